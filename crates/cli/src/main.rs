@@ -1,8 +1,34 @@
 use clap::Parser;
-use legit::objects::{read_object, write_object, Object, ObjectHash, ObjectType};
-use legit::Repository;
+use legit::add::{self, AddOptions};
+use legit::checkout::{self, CheckoutOptions, RestoreOptions};
+use legit::commit_graph::{self, CommitGraphOptions};
+use legit::commits::Commit;
+use legit::diff::{self, Algorithm, DiffOptions, FileDiff};
+use legit::fetch::{self, CloneOptions, FetchOptions};
+use legit::fsck::{self, FsckOptions, IssueKind};
+use legit::gc::{self, GcOptions, RepackOptions, RepackReport};
+use legit::ignore;
+use legit::index::Index;
+use legit::merge::{self, ConflictStyle, FastForward, MergeOptions, MergeOutcome, TreeMerge};
+use legit::merge_base;
+use legit::midx;
+use legit::objects::{read_object, write_object, HashAlgorithm, Object, ObjectHash, ObjectType};
+use legit::promisor::ObjectFilter;
+use legit::push::{self, Lease, PushOptions};
+use legit::rebase::{self, RebaseOptions, RebaseOutcome, RebaseStop};
+use legit::receive_pack::receive_pack;
+use legit::refs::{read_head, resolve_ref, short_name, Head, RefStorage};
+use legit::revision::{self, peel_to_commit, peel_to_tree, rev_parse};
+use legit::sequencer::{self, Action, SequencerOptions, SequencerReport, StopReason};
+use legit::stash::{self, Stash, StashOptions};
+use legit::status::{self, StatusOptions, UntrackedFiles};
+use legit::submodule::{self, UpdateOptions};
+use legit::upload_pack::upload_pack;
+use legit::worktree::{self, Worktree, WorktreeAddOptions};
+use legit::{FormatError, Repository};
 use std::ffi::OsString;
-use std::path::PathBuf;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser, Debug)]
 #[command(name = "legit")]
@@ -21,34 +47,1510 @@ enum Command {
     Init {
         /// The path to the repository
         path: Option<OsString>,
+
+        /// The hash algorithm naming the objects: sha1 or sha256
+        #[arg(long, default_value = "sha1")]
+        object_format: HashAlgorithm,
+
+        /// The storage of the references: files or reftable
+        #[arg(long, default_value = "files")]
+        ref_format: RefStorage,
+    },
+
+    /// Display information about the repository
+    Config,
+
+    /// Provide contents of a repository object
+    CatFile {
+        /// The type of the object (e.g., commit, tree, blob)
+        #[arg(value_enum)]
+        object_type: ObjectType,
+
+        /// The hash of the object
+        hash: String,
+    },
+
+    /// Hash a file
+    HashFile {
+        /// The type of the object (e.g., commit, tree, blob)
+        #[arg(value_enum)]
+        object_type: ObjectType,
+
+        /// The path to the file
+        path: OsString,
+
+        /// If true, the object will be stored in the repository
+        #[arg(long)]
+        store: bool,
+    },
+
+    /// Switch branches or restore working tree files
+    Checkout {
+        /// The branch or commit to check out
+        target: Option<String>,
+
+        /// Create a new branch starting at the target and switch to it
+        #[arg(short = 'b')]
+        new_branch: Option<String>,
+
+        /// Throw away local changes
+        #[arg(short, long)]
+        force: bool,
+
+        /// Detach HEAD at the target even if it is a branch
+        #[arg(long)]
+        detach: bool,
+
+        /// Restore these paths instead of switching branches
+        #[arg(last = true)]
+        paths: Vec<String>,
+    },
+
+    /// Switch branches
+    Switch {
+        /// The branch to switch to, or the start point with --create
+        target: Option<String>,
+
+        /// Create a new branch and switch to it
+        #[arg(short, long)]
+        create: Option<String>,
+
+        /// Throw away local changes
+        #[arg(short, long)]
+        force: bool,
+
+        /// Switch to a commit for inspection, detaching HEAD
+        #[arg(long)]
+        detach: bool,
+    },
+
+    /// Restore working tree files
+    Restore {
+        /// The paths to restore
+        #[arg(required = true)]
+        paths: Vec<String>,
+
+        /// Restore the content from this revision
+        #[arg(short, long)]
+        source: Option<String>,
+
+        /// Restore the index
+        #[arg(short = 'S', long)]
+        staged: bool,
+
+        /// Restore the working tree (the default without --staged)
+        #[arg(short = 'W', long)]
+        worktree: bool,
+    },
+
+    /// Show the working tree status
+    Status {
+        /// Give the output in the short format
+        #[arg(short, long)]
+        short: bool,
+
+        /// Show the branch and tracking info in the short format
+        #[arg(short, long)]
+        branch: bool,
+
+        /// Give the output in a stable format for scripts (v1 or v2)
+        #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "v1")]
+        porcelain: Option<String>,
+
+        /// Terminate entries with NUL instead of LF; implies --porcelain=v1
+        #[arg(short = 'z')]
+        nul: bool,
+
+        /// Show untracked files (no, normal or all)
+        #[arg(
+            short = 'u',
+            long,
+            num_args = 0..=1,
+            default_value = "normal",
+            default_missing_value = "all"
+        )]
+        untracked_files: String,
+
+        /// Show ignored files as well
+        #[arg(long)]
+        ignored: bool,
+
+        /// Do not detect renames
+        #[arg(long)]
+        no_renames: bool,
+    },
+
+    /// Add file contents to the index
+    Add {
+        /// The files or directories to add
+        #[arg(required = true)]
+        paths: Vec<String>,
+
+        /// Allow adding otherwise ignored files
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// Debug gitignore and exclude files
+    CheckIgnore {
+        /// The paths to check
+        #[arg(required = true)]
+        paths: Vec<String>,
+
+        /// Show the matching pattern and where it comes from
+        #[arg(short, long)]
+        verbose: bool,
+
+        /// Also show paths that match no pattern; requires --verbose
+        #[arg(short, long, requires = "verbose")]
+        non_matching: bool,
+
+        /// Do not skip paths that are in the index
+        #[arg(long)]
+        no_index: bool,
+    },
+
+    /// Show changes between commits, the index and the working tree
+    Diff {
+        /// Compare with these commits: none for the index, one for the
+        /// working tree, two (or `a..b`) for each other
+        revisions: Vec<String>,
+
+        /// Compare the index with HEAD or the given commit
+        #[arg(long, visible_alias = "staged")]
+        cached: bool,
+
+        #[command(flatten)]
+        format: DiffFormatArgs,
+
+        /// Limit the diff to these paths
+        #[arg(last = true)]
+        paths: Vec<String>,
+    },
+
+    /// Compare the content and mode of blobs found via two tree objects
+    DiffTree {
+        /// One commit to compare with its parent, or two trees
+        #[arg(required = true, num_args = 1..=2)]
+        trees: Vec<String>,
+
+        /// Recurse into subtrees
+        #[arg(short)]
+        recursive: bool,
+
+        /// Show a root commit as a diff against the empty tree
+        #[arg(long)]
+        root: bool,
+
+        #[command(flatten)]
+        format: DiffFormatArgs,
+
+        /// Limit the diff to these paths
+        #[arg(last = true)]
+        paths: Vec<String>,
+    },
+
+    /// Join another branch into the current one
+    Merge {
+        /// The commit to merge
+        #[arg(required_unless_present_any = ["continue_merge", "abort"])]
+        commit: Option<String>,
+
+        /// Refuse to merge unless HEAD can be fast-forwarded
+        #[arg(long, conflicts_with = "no_ff")]
+        ff_only: bool,
+
+        /// Create a merge commit even when a fast-forward is possible
+        #[arg(long)]
+        no_ff: bool,
+
+        /// The message of the merge commit
+        #[arg(short, long)]
+        message: Option<String>,
+
+        /// How conflicts are shown: merge, diff3 or zdiff3
+        #[arg(long)]
+        conflict: Option<ConflictStyle>,
+
+        /// Create the merge commit once conflicts are resolved
+        #[arg(long = "continue", conflicts_with_all = ["commit", "abort"])]
+        continue_merge: bool,
+
+        /// Abandon the merge and restore the pre-merge state
+        #[arg(long, conflicts_with = "commit")]
+        abort: bool,
+    },
+
+    /// Find as good common ancestors as possible for a merge
+    MergeBase {
+        /// The commits; with --fork-point, a reference and an optional commit
+        #[arg(required = true)]
+        commits: Vec<String>,
+
+        /// Output all merge bases instead of just one
+        #[arg(short, long)]
+        all: bool,
+
+        /// Compute the best common ancestors of all commits, for an octopus merge
+        #[arg(long, conflicts_with_all = ["is_ancestor", "fork_point"])]
+        octopus: bool,
+
+        /// Exit with status 0 if the first commit is an ancestor of the second
+        #[arg(long, conflicts_with = "fork_point")]
+        is_ancestor: bool,
+
+        /// Find where a commit (HEAD by default) forked from a reference,
+        /// using its reflog
+        #[arg(long)]
+        fork_point: bool,
+    },
+
+    /// List commits in reverse chronological order
+    RevList {
+        /// Commits to start from; `^commit` excludes what a commit reaches
+        #[arg(required = true)]
+        commits: Vec<String>,
+
+        /// Only list the commits that change these paths
+        #[arg(last = true)]
+        paths: Vec<String>,
+    },
+
+    /// Apply the changes introduced by existing commits
+    CherryPick {
+        #[command(flatten)]
+        sequencer: SequencerArgs,
+
+        /// Append a line recording which commit was cherry-picked
+        #[arg(short = 'x')]
+        record_origin: bool,
     },
 
-    /// Display information about the repository
-    Config,
+    /// Create commits reverting the changes of existing commits
+    Revert {
+        #[command(flatten)]
+        sequencer: SequencerArgs,
+    },
+
+    /// Reapply commits on top of another base
+    Rebase {
+        /// The upstream to rebase onto; defaults to the branch's upstream
+        #[arg(conflicts_with_all = ["continue_rebase", "skip", "abort"])]
+        upstream: Option<String>,
+
+        /// Switch to this branch before rebasing it
+        branch: Option<String>,
+
+        /// Replay the commits onto this commit instead of the upstream
+        #[arg(long)]
+        onto: Option<String>,
+
+        /// Edit the list of commits to replay before starting
+        #[arg(short, long)]
+        interactive: bool,
+
+        /// Move fixup!, squash! and amend! commits after their targets
+        #[arg(long, overrides_with = "no_autosquash")]
+        autosquash: bool,
+
+        #[arg(long, hide = true)]
+        no_autosquash: bool,
+
+        /// Run a shell command after each replayed commit
+        #[arg(short = 'x', long = "exec")]
+        exec: Vec<String>,
+
+        /// Also move branches that point to replayed commits
+        #[arg(long, overrides_with = "no_update_refs")]
+        update_refs: bool,
+
+        #[arg(long, hide = true)]
+        no_update_refs: bool,
+
+        /// Recreate merge commits instead of flattening them
+        #[arg(short = 'r', long)]
+        rebase_merges: bool,
+
+        /// Resume after resolving conflicts or editing a commit
+        #[arg(long = "continue", conflicts_with_all = ["skip", "abort"])]
+        continue_rebase: bool,
+
+        /// Skip the current commit and resume
+        #[arg(long, conflicts_with = "abort")]
+        skip: bool,
+
+        /// Abandon the rebase and restore the original branch
+        #[arg(long)]
+        abort: bool,
+    },
+
+    /// Stash the changes in a dirty working directory away
+    #[command(args_conflicts_with_subcommands = true)]
+    Stash {
+        #[command(subcommand)]
+        action: Option<StashCommand>,
+
+        #[command(flatten)]
+        push: StashPushArgs,
+    },
+
+    /// Manage the working trees attached to the repository
+    Worktree {
+        #[command(subcommand)]
+        action: WorktreeCommand,
+    },
+
+    /// Initialize, update or inspect submodules
+    Submodule {
+        #[command(subcommand)]
+        action: SubmoduleCommand,
+    },
+
+    /// Clone a repository into a new directory
+    Clone {
+        /// The repository to clone from
+        url: String,
+
+        /// The directory to clone into, named after the repository by default
+        directory: Option<PathBuf>,
+
+        /// Check out this branch instead of the remote's HEAD
+        #[arg(short, long)]
+        branch: Option<String>,
+
+        /// Hardlink the source's objects instead of copying them
+        #[arg(short, long)]
+        local: bool,
+
+        /// Name the remote this instead of origin
+        #[arg(short, long, default_value = "origin")]
+        origin: String,
+
+        /// Do not check out HEAD after cloning
+        #[arg(short, long)]
+        no_checkout: bool,
+
+        /// Only fetch this many commits of history
+        #[arg(long)]
+        depth: Option<u32>,
+
+        /// Only fetch the commits made after a date, e.g. 2024-01-31 or
+        /// 2.weeks.ago
+        #[arg(long, value_parser = parse_date)]
+        shallow_since: Option<i64>,
+
+        /// Leave out objects until they are needed, e.g. blob:none,
+        /// blob:limit=1m or tree:0
+        #[arg(long)]
+        filter: Option<ObjectFilter>,
+    },
+
+    /// Download objects and refs from another repository
+    Fetch {
+        /// The remote or repository URL to fetch from
+        remote: Option<String>,
+
+        /// The refspecs to fetch instead of the remote's configured ones
+        refspecs: Vec<String>,
+
+        /// Do not report the updated references
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// Limit the history to this many commits from each fetched one
+        #[arg(long)]
+        depth: Option<u32>,
+
+        /// Limit the history to the commits made after a date
+        #[arg(long, value_parser = parse_date)]
+        shallow_since: Option<i64>,
+
+        /// Fetch the whole history of a shallow repository
+        #[arg(long)]
+        unshallow: bool,
+
+        /// Leave out objects until they are needed, making the repository
+        /// a partial clone
+        #[arg(long)]
+        filter: Option<ObjectFilter>,
+    },
+
+    /// Update remote refs along with their objects
+    Push {
+        /// The remote or repository URL to push to
+        remote: Option<String>,
+
+        /// The refspecs to push instead of the current branch
+        refspecs: Vec<String>,
+
+        /// Update remote refs even when they are not ancestors of what is pushed
+        #[arg(short, long)]
+        force: bool,
+
+        /// Force only while the remote ref has the expected value
+        #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "")]
+        force_with_lease: Vec<String>,
+
+        /// Update all refs or none of them
+        #[arg(long)]
+        atomic: bool,
+
+        /// Delete the named remote refs
+        #[arg(short, long)]
+        delete: bool,
+
+        /// Pass a string to the remote's hooks
+        #[arg(short = 'o', long = "push-option")]
+        push_options: Vec<String>,
+
+        /// Make the pushed branches track the remote ones
+        #[arg(short = 'u', long)]
+        set_upstream: bool,
+
+        /// Do everything except send the updates
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// Do not report the updated references
+        #[arg(short, long)]
+        quiet: bool,
+    },
+
+    /// Write the commit-graph file that speeds up history walks
+    CommitGraph {
+        #[command(subcommand)]
+        action: CommitGraphCommand,
+    },
+
+    /// Verify the connectivity and validity of the objects in the database
+    Fsck {
+        /// Report every unreachable object, not only the dangling ones
+        #[arg(long)]
+        unreachable: bool,
+
+        /// Do not report dangling objects
+        #[arg(long)]
+        no_dangling: bool,
+
+        /// Print one tab separated `kind type hash id message` line per issue
+        #[arg(long)]
+        porcelain: bool,
+    },
+
+    /// Pack references and objects and remove unreachable objects
+    Gc {
+        /// Prune unreachable objects older than this date, e.g. `now`
+        #[arg(long, require_equals = true, value_name = "DATE")]
+        prune: Option<String>,
+
+        /// Keep every unreachable object
+        #[arg(long, conflicts_with = "prune")]
+        no_prune: bool,
+
+        /// Report what would be done without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Pack the objects of the repository
+    Repack {
+        /// Pack every reachable object into one pack, not only the loose ones
+        #[arg(short, long)]
+        all: bool,
+
+        /// Remove the packs and loose objects the new pack makes redundant
+        #[arg(short, long)]
+        delete: bool,
+
+        /// Write a reachability bitmap for the new pack; needs --all
+        #[arg(short = 'b', long, requires = "all")]
+        write_bitmap_index: bool,
+
+        /// Report what would be done without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Write the multi-pack-index that indexes every pack at once
+    MultiPackIndex {
+        #[command(subcommand)]
+        action: MultiPackIndexCommand,
+    },
+
+    /// Send objects to a fetching client over stdin and stdout
+    UploadPack {
+        /// The repository to serve
+        directory: PathBuf,
+    },
+
+    /// Receive objects from a pushing client over stdin and stdout
+    ReceivePack {
+        /// The repository to update
+        directory: PathBuf,
+    },
+}
+
+/// Arguments shared by cherry-pick and revert
+#[derive(clap::Args, Debug)]
+struct SequencerArgs {
+    /// The commits or `A..B` ranges to apply
+    #[arg(required_unless_present_any = ["continue_sequence", "skip", "abort"])]
+    commits: Vec<String>,
+
+    /// The parent number, starting from 1, to diff merge commits against
+    #[arg(short, long)]
+    mainline: Option<usize>,
+
+    /// Resume after resolving conflicts
+    #[arg(long = "continue", conflicts_with_all = ["commits", "skip", "abort"])]
+    continue_sequence: bool,
+
+    /// Skip the current commit and resume
+    #[arg(long, conflicts_with_all = ["commits", "abort"])]
+    skip: bool,
+
+    /// Abandon the operation and restore the original HEAD
+    #[arg(long, conflicts_with = "commits")]
+    abort: bool,
+}
+
+impl SequencerArgs {
+    /// Start or resume the sequencer and report what it did
+    fn run(self, repo: &Repository, action: Action, record_origin: bool) {
+        let report = if self.continue_sequence {
+            sequencer::sequencer_continue(repo)
+        } else if self.skip {
+            sequencer::sequencer_skip(repo)
+        } else if self.abort {
+            sequencer::sequencer_abort(repo).unwrap_or_else(|e| fail(e));
+            return;
+        } else {
+            let options = SequencerOptions {
+                mainline: self.mainline,
+                record_origin,
+            };
+            match action {
+                Action::Pick => sequencer::cherry_pick(repo, &self.commits, &options),
+                Action::Revert => sequencer::revert(repo, &self.commits, &options),
+            }
+        }
+        .unwrap_or_else(|e| fail(e));
+        report_sequencer(repo, report);
+    }
+}
+
+/// The commit-graph subcommands
+#[derive(clap::Subcommand, Debug)]
+enum CommitGraphCommand {
+    /// Write the commit-graph of the commits reachable from the references
+    Write {
+        /// Add a layer to the commit-graph chain instead of one file
+        #[arg(long)]
+        split: bool,
+
+        /// Compute changed-path Bloom filters
+        #[arg(long)]
+        changed_paths: bool,
+    },
+}
+
+/// The multi-pack-index subcommands
+#[derive(clap::Subcommand, Debug)]
+enum MultiPackIndexCommand {
+    /// Write a multi-pack-index covering every pack
+    Write,
+}
+
+/// The stash subcommands; without one, changes are pushed
+#[derive(clap::Subcommand, Debug)]
+enum StashCommand {
+    /// Save local changes as a new stash and revert them
+    Push(StashPushArgs),
+
+    /// Like push, with the message given as arguments
+    Save {
+        #[command(flatten)]
+        push: StashPushArgs,
+
+        /// The message describing the stash
+        text: Vec<String>,
+    },
+
+    /// List the stashes, newest first
+    List,
+
+    /// Show the changes recorded in a stash
+    Show {
+        /// The stash, `stash@{0}` by default
+        stash: Option<String>,
+
+        #[command(flatten)]
+        format: DiffFormatArgs,
+    },
+
+    /// Apply a stash on top of the working tree
+    Apply(StashApplyArgs),
+
+    /// Apply a stash and remove it from the list
+    Pop(StashApplyArgs),
+
+    /// Remove a stash from the list
+    Drop {
+        /// The stash, `stash@{0}` by default
+        stash: Option<String>,
+
+        /// Do not report the dropped stash
+        #[arg(short, long)]
+        quiet: bool,
+    },
+
+    /// Create a branch where a stash was created and pop the stash there
+    Branch {
+        /// The name of the new branch
+        branch: String,
+
+        /// The stash, `stash@{0}` by default
+        stash: Option<String>,
+    },
+
+    /// Remove every stash
+    Clear,
+}
+
+/// The worktree subcommands
+#[derive(clap::Subcommand, Debug)]
+enum WorktreeCommand {
+    /// Create a worktree at a path and check out a commit in it
+    Add {
+        /// Where to create the worktree
+        path: PathBuf,
+
+        /// The branch or commit to check out; a branch named after the path
+        /// by default
+        commitish: Option<String>,
+
+        /// Create a branch with this name and check it out
+        #[arg(short = 'b', value_name = "NEW_BRANCH")]
+        new_branch: Option<String>,
+
+        /// Detach HEAD in the new worktree
+        #[arg(short, long, conflicts_with = "new_branch")]
+        detach: bool,
+
+        /// Check out a branch even if another worktree has it
+        #[arg(short, long)]
+        force: bool,
+
+        /// Do not check out the files
+        #[arg(long)]
+        no_checkout: bool,
+
+        /// Keep the new worktree locked
+        #[arg(long)]
+        lock: bool,
+
+        /// Why the new worktree is locked
+        #[arg(long, requires = "lock")]
+        reason: Option<String>,
+    },
+
+    /// List the worktrees, the main one first
+    List {
+        /// Print one attribute per line, for scripts
+        #[arg(long)]
+        porcelain: bool,
+    },
+
+    /// Remove a worktree and its files
+    Remove {
+        /// The path of the worktree, or its last component
+        worktree: String,
+
+        /// Remove a worktree with local changes; twice for a locked one
+        #[arg(short, long, action = clap::ArgAction::Count)]
+        force: u8,
+    },
+
+    /// Forget the worktrees whose files are gone
+    Prune {
+        /// Report what would be pruned without removing anything
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// Report every pruned worktree
+        #[arg(short, long)]
+        verbose: bool,
+    },
+
+    /// Keep a worktree from being pruned, moved or removed
+    Lock {
+        /// The path of the worktree, or its last component
+        worktree: String,
+
+        /// Why the worktree is locked
+        #[arg(long)]
+        reason: Option<String>,
+    },
+
+    /// Unlock a worktree
+    Unlock {
+        /// The path of the worktree, or its last component
+        worktree: String,
+    },
+
+    /// Move a worktree to a new path
+    Move {
+        /// The path of the worktree, or its last component
+        worktree: String,
+
+        /// The new path, or a directory to move the worktree into
+        new_path: PathBuf,
+
+        /// Twice to move a locked worktree
+        #[arg(short, long, action = clap::ArgAction::Count)]
+        force: u8,
+    },
+}
+
+impl WorktreeCommand {
+    fn run(self, repo: &Repository, base_path: &Path) {
+        // A worktree is named by a path or by its last component
+        let spec = |worktree: String| match base_path.join(&worktree) {
+            path if path.exists() => path.to_string_lossy().to_string(),
+            _ => worktree,
+        };
+        match self {
+            WorktreeCommand::Add {
+                path,
+                commitish,
+                new_branch,
+                detach,
+                force,
+                no_checkout,
+                lock,
+                reason,
+            } => {
+                let options = WorktreeAddOptions {
+                    new_branch,
+                    detach,
+                    force,
+                    no_checkout,
+                    lock: lock.then(|| reason.unwrap_or_default()),
+                };
+                let path = base_path.join(path);
+                let added = worktree::add_worktree(repo, &path, commitish.as_deref(), &options)
+                    .unwrap_or_else(|e| fail(e));
+                let head = read_head(&added).unwrap_or_else(|e| fail(e));
+                let hash = resolve_ref(&added, "HEAD")
+                    .unwrap_or_else(|e| fail(e))
+                    .unwrap_or_else(|| fail("HEAD of the new worktree is unborn"));
+                let short = &hash.to_hex()[..7];
+                match (&head, &options.new_branch, commitish) {
+                    (Head::Branch(name), None, Some(_)) => {
+                        eprintln!("Preparing worktree (checking out '{}')", short_name(name))
+                    }
+                    (Head::Branch(name), _, _) => {
+                        eprintln!("Preparing worktree (new branch '{}')", short_name(name))
+                    }
+                    (Head::Detached(_), _, _) => {
+                        eprintln!("Preparing worktree (detached HEAD {})", short)
+                    }
+                }
+                let commit = Commit::read(&added, &hash).unwrap_or_else(|e| fail(e));
+                println!("HEAD is now at {} {}", short, commit.summary());
+            }
+            WorktreeCommand::List { porcelain } => {
+                let worktrees = worktree::list_worktrees(repo).unwrap_or_else(|e| fail(e));
+                match porcelain {
+                    true => print!("{}", format_worktrees_porcelain(&worktrees)),
+                    false => print!("{}", format_worktrees(&worktrees)),
+                }
+            }
+            WorktreeCommand::Remove { worktree, force } => {
+                worktree::remove_worktree(repo, &spec(worktree), force).unwrap_or_else(|e| fail(e));
+            }
+            WorktreeCommand::Prune { dry_run, verbose } => {
+                let pruned = worktree::prune_worktrees(repo, dry_run).unwrap_or_else(|e| fail(e));
+                if dry_run || verbose {
+                    for (name, reason) in pruned {
+                        println!("Removing worktrees/{}: {}", name, reason);
+                    }
+                }
+            }
+            WorktreeCommand::Lock { worktree, reason } => {
+                let reason = reason.unwrap_or_default();
+                worktree::lock_worktree(repo, &spec(worktree), &reason).unwrap_or_else(|e| fail(e));
+            }
+            WorktreeCommand::Unlock { worktree } => {
+                worktree::unlock_worktree(repo, &spec(worktree)).unwrap_or_else(|e| fail(e));
+            }
+            WorktreeCommand::Move {
+                worktree,
+                new_path,
+                force,
+            } => {
+                let new_path = base_path.join(new_path);
+                worktree::move_worktree(repo, &spec(worktree), &new_path, force)
+                    .unwrap_or_else(|e| fail(e));
+            }
+        }
+    }
+}
+
+/// The submodule subcommands
+#[derive(clap::Subcommand, Debug)]
+enum SubmoduleCommand {
+    /// Register the submodules of .gitmodules in the config
+    Init {
+        /// Only these submodules
+        paths: Vec<String>,
+    },
+
+    /// Clone the missing submodules and check out the recorded commits
+    Update {
+        /// Initialize the submodules that are not yet
+        #[arg(long)]
+        init: bool,
+
+        /// Also update nested submodules
+        #[arg(long)]
+        recursive: bool,
+
+        /// Only these submodules
+        paths: Vec<String>,
+    },
+
+    /// Show the commit checked out in each submodule
+    Status {
+        /// Also show nested submodules
+        #[arg(long)]
+        recursive: bool,
+
+        /// Only these submodules
+        paths: Vec<String>,
+    },
+
+    /// Copy the URLs of .gitmodules to the config and the submodules
+    Sync {
+        /// Also synchronize nested submodules
+        #[arg(long)]
+        recursive: bool,
+
+        /// Only these submodules
+        paths: Vec<String>,
+    },
+
+    /// Run a shell command in each checked out submodule
+    Foreach {
+        /// Also run it in nested submodules
+        #[arg(long)]
+        recursive: bool,
+
+        /// The command, run by `sh -c`
+        command: String,
+    },
+
+    /// Move the git directories of submodules into the superproject's
+    Absorbgitdirs {
+        /// Only these submodules
+        paths: Vec<String>,
+    },
+}
+
+impl SubmoduleCommand {
+    fn run(self, repo: &Repository) {
+        let modules = submodule::read_gitmodules(repo).unwrap_or_else(|e| fail(e));
+        for name in modules.skipped {
+            eprintln!("warning: ignoring suspicious submodule name: {}", name);
+        }
+        match self {
+            SubmoduleCommand::Init { paths } => init_submodules(repo, &paths),
+            SubmoduleCommand::Update {
+                init,
+                recursive,
+                paths,
+            } => {
+                if init {
+                    init_submodules(repo, &paths);
+                }
+                let options = UpdateOptions { init, recursive };
+                let updated = submodule::update(repo, &paths, &options).unwrap_or_else(|e| fail(e));
+                for (path, hash) in updated {
+                    println!("Submodule path '{}': checked out '{}'", path, hash);
+                }
+            }
+            SubmoduleCommand::Status { recursive, paths } => {
+                let statuses = submodule::submodule_status(repo, &paths, recursive)
+                    .unwrap_or_else(|e| fail(e));
+                for status in statuses {
+                    print!("{}{} {}", status.prefix, status.hash, status.path);
+                    match status.name {
+                        Some(name) => println!(" ({})", name),
+                        None => println!(),
+                    }
+                }
+            }
+            SubmoduleCommand::Sync { recursive, paths } => {
+                let synced = submodule::sync(repo, &paths, recursive).unwrap_or_else(|e| fail(e));
+                for path in synced {
+                    println!("Synchronizing submodule url for '{}'", path);
+                }
+            }
+            SubmoduleCommand::Foreach { recursive, command } => {
+                let mut entering = |path: &str| println!("Entering '{}'", path);
+                submodule::foreach(repo, &command, recursive, &mut entering)
+                    .unwrap_or_else(|e| fail(e));
+            }
+            SubmoduleCommand::Absorbgitdirs { paths } => {
+                let moved = submodule::absorb_git_dirs(repo, &paths).unwrap_or_else(|e| fail(e));
+                let worktree = std::fs::canonicalize(repo.worktree()).unwrap_or_else(|e| fail(e));
+                for (path, gitdir) in moved {
+                    eprintln!(
+                        "Migrating git directory of '{}' from '{}' to '{}'",
+                        path,
+                        worktree.join(&path).join(".git").display(),
+                        gitdir.display()
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Register submodules, reporting each one like `git submodule init`
+fn init_submodules(repo: &Repository, paths: &[String]) {
+    let registered = submodule::init(repo, paths).unwrap_or_else(|e| fail(e));
+    for (submodule, url) in registered {
+        eprintln!(
+            "Submodule '{}' ({}) registered for path '{}'",
+            submodule.name, url, submodule.path
+        );
+    }
+}
 
-    /// Provide contents of a repository object
-    CatFile {
-        /// The type of the object (e.g., commit, tree, blob)
-        #[arg(value_enum)]
-        object_type: ObjectType,
+/// Format worktrees like `git worktree list`: path, commit and branch
+fn format_worktrees(worktrees: &[Worktree]) -> String {
+    let width = worktrees
+        .iter()
+        .map(|worktree| worktree.path.display().to_string().len())
+        .max()
+        .unwrap_or(0);
+    let mut out = String::new();
+    for worktree in worktrees {
+        let path = worktree.path.display().to_string();
+        out.push_str(&format!("{:<width$} ", path, width = width));
+        match worktree.head() {
+            _ if worktree.bare => out.push_str("(bare)"),
+            Some((head, hash)) => {
+                let hash =
+                    hash.map_or("0000000".to_string(), |hash| hash.to_hex()[..7].to_string());
+                match head {
+                    Head::Branch(name) => {
+                        out.push_str(&format!("{} [{}]", hash, short_name(&name)))
+                    }
+                    Head::Detached(_) => out.push_str(&format!("{} (detached HEAD)", hash)),
+                }
+            }
+            None => out.push_str("(error)"),
+        }
+        if worktree.locked.is_some() {
+            out.push_str(" locked");
+        }
+        if worktree.prunable.is_some() {
+            out.push_str(" prunable");
+        }
+        out.push('\n');
+    }
+    out
+}
 
-        /// The hash of the object
-        hash: String,
-    },
+/// Format worktrees like `git worktree list --porcelain`
+fn format_worktrees_porcelain(worktrees: &[Worktree]) -> String {
+    let mut out = String::new();
+    for worktree in worktrees {
+        out.push_str(&format!("worktree {}\n", worktree.path.display()));
+        match worktree.head() {
+            _ if worktree.bare => out.push_str("bare\n"),
+            Some((head, hash)) => {
+                if let Some(hash) = hash {
+                    out.push_str(&format!("HEAD {}\n", hash));
+                }
+                match head {
+                    Head::Branch(name) => out.push_str(&format!("branch {}\n", name)),
+                    Head::Detached(_) => out.push_str("detached\n"),
+                }
+            }
+            None => {}
+        }
+        match worktree.locked.as_deref() {
+            Some("") => out.push_str("locked\n"),
+            Some(reason) => out.push_str(&format!("locked {}\n", reason)),
+            None => {}
+        }
+        if let Some(reason) = &worktree.prunable {
+            out.push_str(&format!("prunable {}\n", reason));
+        }
+        out.push('\n');
+    }
+    out
+}
 
-    /// Hash a file
-    HashFile {
-        /// The type of the object (e.g., commit, tree, blob)
-        #[arg(value_enum)]
-        object_type: ObjectType,
+/// Arguments of stash push and save
+#[derive(clap::Args, Debug)]
+struct StashPushArgs {
+    /// The message describing the stash
+    #[arg(short, long)]
+    message: Option<String>,
 
-        /// The path to the file
-        path: OsString,
+    /// Also stash untracked files
+    #[arg(short = 'u', long)]
+    include_untracked: bool,
 
-        /// If true, the object will be stored in the repository
-        #[arg(long)]
-        store: bool,
-    },
+    /// Also stash untracked and ignored files
+    #[arg(short, long, conflicts_with = "include_untracked")]
+    all: bool,
+
+    /// Keep the changes added to the index
+    #[arg(short, long)]
+    keep_index: bool,
+
+    /// Do not report the saved stash
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+impl StashPushArgs {
+    fn run(self, repo: &Repository, message: Option<String>) {
+        let options = StashOptions {
+            message: message.or(self.message),
+            include_untracked: self.include_untracked,
+            all: self.all,
+            keep_index: self.keep_index,
+        };
+        let stash = stash::stash_push(repo, &options).unwrap_or_else(|e| fail(e));
+        if self.quiet {
+            return;
+        }
+        match stash {
+            Some(hash) => {
+                let commit = Commit::read(repo, &hash).unwrap_or_else(|e| fail(e));
+                println!(
+                    "Saved working directory and index state {}",
+                    commit.summary()
+                );
+            }
+            None => println!("No local changes to save"),
+        }
+    }
+}
+
+/// Arguments of stash apply and pop
+#[derive(clap::Args, Debug)]
+struct StashApplyArgs {
+    /// The stash, `stash@{0}` by default
+    stash: Option<String>,
+
+    /// Also restore the changes that were added to the index
+    #[arg(long)]
+    index: bool,
+
+    /// Do not show the status afterwards
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+impl StashCommand {
+    fn run(self, repo: &Repository) {
+        match self {
+            StashCommand::Push(push) => push.run(repo, None),
+            StashCommand::Save { push, text } => {
+                let message = (!text.is_empty()).then(|| text.join(" "));
+                push.run(repo, message)
+            }
+            StashCommand::List => {
+                let entries = stash::stash_list(repo).unwrap_or_else(|e| fail(e));
+                for (position, entry) in entries.iter().enumerate() {
+                    println!("stash@{{{}}}: {}", position, entry.message);
+                }
+            }
+            StashCommand::Show { stash, format } => {
+                let (base, tree) =
+                    stash::stash_trees(repo, stash.as_deref()).unwrap_or_else(|e| fail(e));
+                let options = format.options(true, Vec::new());
+                let diffs = diff::diff_trees(repo, Some(&base), Some(&tree), &options)
+                    .unwrap_or_else(|e| fail(e));
+                if !format.print(repo, &diffs, &options) {
+                    let stats =
+                        diff::diff_stats(repo, &diffs, &options).unwrap_or_else(|e| fail(e));
+                    print!("{}", diff::format_stat(&stats));
+                }
+            }
+            StashCommand::Apply(args) => {
+                let result = stash::stash_apply(repo, args.stash.as_deref(), args.index)
+                    .unwrap_or_else(|e| fail(e));
+                report_stash_apply(repo, &result, &args, false, None);
+            }
+            StashCommand::Pop(args) => {
+                let (result, dropped) = stash::stash_pop(repo, args.stash.as_deref(), args.index)
+                    .unwrap_or_else(|e| fail(e));
+                report_stash_apply(repo, &result, &args, true, dropped);
+            }
+            StashCommand::Drop { stash, quiet } => {
+                let dropped = stash::stash_drop(repo, stash.as_deref()).unwrap_or_else(|e| fail(e));
+                if !quiet {
+                    println!("Dropped {} ({})", dropped.name, dropped.commit);
+                }
+            }
+            StashCommand::Branch { branch, stash } => {
+                let (result, dropped) = stash::stash_branch(repo, &branch, stash.as_deref())
+                    .unwrap_or_else(|e| fail(e));
+                println!("Switched to a new branch '{}'", branch);
+                let args = StashApplyArgs {
+                    stash,
+                    index: true,
+                    quiet: false,
+                };
+                report_stash_apply(repo, &result, &args, true, dropped);
+            }
+            StashCommand::Clear => stash::stash_clear(repo).unwrap_or_else(|e| fail(e)),
+        }
+    }
+}
+
+/// Print the status after applying a stash and what happened to the stash
+fn report_stash_apply(
+    repo: &Repository,
+    result: &TreeMerge,
+    args: &StashApplyArgs,
+    pop: bool,
+    dropped: Option<Stash>,
+) {
+    result.messages.iter().for_each(|m| println!("{}", m));
+    let conflicted = !result.conflicts.is_empty();
+    if conflicted && args.index {
+        eprintln!("Index was not unstashed.");
+    }
+    if !args.quiet {
+        let status = status::status(repo, &StatusOptions::default()).unwrap_or_else(|e| fail(e));
+        let hints = status::status_hints(repo).unwrap_or_else(|e| fail(e));
+        print!("{}", status::format_long(&status, hints));
+    }
+    if conflicted {
+        if pop {
+            fail("The stash entry is kept in case you need it again.");
+        }
+        std::process::exit(1);
+    }
+    if let Some(stash) = dropped {
+        println!("Dropped {} ({})", stash.name, stash.commit);
+    }
+}
+
+/// Output and comparison options shared by the diff commands
+#[derive(clap::Args, Debug)]
+struct DiffFormatArgs {
+    /// Generate a patch
+    #[arg(short = 'p', long = "patch")]
+    patch: bool,
+
+    /// Number of context lines
+    #[arg(short = 'U', long = "unified", default_value_t = 3)]
+    context: usize,
+
+    /// Show a diffstat instead of a patch
+    #[arg(long)]
+    stat: bool,
+
+    /// Show only the names and status of changed files
+    #[arg(long)]
+    name_status: bool,
+
+    /// Show only the names of changed files
+    #[arg(long)]
+    name_only: bool,
+
+    /// Show changed words instead of lines (only `plain` is supported)
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "plain")]
+    word_diff: Option<String>,
+
+    /// Detect renames, optionally with a similarity threshold like `50%`
+    #[arg(short = 'M', long, num_args = 0..=1, require_equals = true, default_missing_value = "50")]
+    find_renames: Option<String>,
+
+    /// Do not detect renames
+    #[arg(long)]
+    no_renames: bool,
+
+    /// Detect copies as well as renames
+    #[arg(short = 'C', long)]
+    find_copies: bool,
+
+    /// Also use unmodified files as copy sources
+    #[arg(long)]
+    find_copies_harder: bool,
+
+    /// The diff algorithm (myers or histogram)
+    #[arg(long, default_value = "myers")]
+    diff_algorithm: Algorithm,
+
+    /// Use the histogram diff algorithm
+    #[arg(long)]
+    histogram: bool,
+}
+
+impl DiffFormatArgs {
+    /// Build the diff options, with renames detected by default if asked
+    fn options(&self, renames_by_default: bool, paths: Vec<String>) -> DiffOptions {
+        let threshold = |value: &str| {
+            value
+                .trim_end_matches('%')
+                .parse::<u32>()
+                .unwrap_or_else(|_| fail(format!("Invalid similarity: {}", value)))
+        };
+        let renames = match (&self.find_renames, self.no_renames) {
+            (_, true) => None,
+            (Some(value), false) => Some(threshold(value)),
+            (None, false) if renames_by_default || self.find_copies => Some(50),
+            (None, false) => None,
+        };
+        let word_diff = self
+            .word_diff
+            .as_deref()
+            .map(|mode| diff::parse_word_diff_mode(mode).unwrap_or_else(|e| fail(e)))
+            .unwrap_or(false);
+        DiffOptions {
+            algorithm: match self.histogram {
+                true => Algorithm::Histogram,
+                false => self.diff_algorithm,
+            },
+            context: self.context,
+            renames,
+            copies: self.find_copies || self.find_copies_harder,
+            find_copies_harder: self.find_copies_harder,
+            pathspecs: paths.iter().map(|p| checkout::normalize_path(p)).collect(),
+            word_diff,
+        }
+    }
+
+    /// Print the diffs in the requested format, returning false if no
+    /// format was selected
+    fn print(&self, repo: &Repository, diffs: &[FileDiff], options: &DiffOptions) -> bool {
+        use std::io::Write;
+        if self.stat {
+            let stats = diff::diff_stats(repo, diffs, options).unwrap_or_else(|e| fail(e));
+            if !stats.is_empty() {
+                print!("{}", diff::format_stat(&stats));
+            }
+        } else if self.name_status {
+            print!("{}", diff::format_name_status(diffs));
+        } else if self.name_only {
+            print!("{}", diff::format_name_only(diffs));
+        } else if self.patch || self.word_diff.is_some() {
+            let patch = diff::format_patch(repo, diffs, options).unwrap_or_else(|e| fail(e));
+            std::io::stdout()
+                .write_all(&patch)
+                .unwrap_or_else(|e| fail(e));
+        } else {
+            return false;
+        }
+        true
+    }
+}
+
+/// Print an error and exit with a failure status
+fn fail(error: impl std::fmt::Display) -> ! {
+    eprintln!("{}", error);
+    std::process::exit(1);
+}
+
+/// Find the repository containing the given path or exit
+fn find_repo(path: &Path) -> Repository {
+    Repository::find(path).unwrap_or_else(|e| fail_repo(e))
+}
+
+/// Exit on a repository that cannot be opened. One whose format legit does
+/// not support exits with 128, like git does.
+fn fail_repo(error: anyhow::Error) -> ! {
+    match error.downcast_ref::<FormatError>() {
+        Some(format) => {
+            eprintln!("fatal: {}", format);
+            std::process::exit(128);
+        }
+        None => fail(error),
+    }
+}
+
+/// Parse a date given as `@<seconds>`, `YYYY-MM-DD` or like `2.weeks.ago`
+/// into seconds since the epoch
+fn parse_date(value: &str) -> Result<i64, String> {
+    if let Ok(seconds) = value.trim_start_matches('@').parse::<i64>() {
+        return Ok(seconds);
+    }
+    let fields = value
+        .splitn(3, '-')
+        .map(|field| field.parse::<i64>())
+        .collect::<Vec<_>>();
+    if let [Ok(year), Ok(month), Ok(day)] = fields[..] {
+        // Days since the epoch of a proleptic Gregorian date
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        return Ok((era * 146097 + day_of_era - 719468) * 86400);
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    match gc::parse_expiry(value, now) {
+        Ok(Some(time)) => Ok(time),
+        _ => Err(format!("invalid date '{}'", value)),
+    }
+}
+
+/// Resolve a revision to its tree or exit
+fn resolve_tree(repo: &Repository, spec: &str) -> legit::objects::ObjectHash {
+    rev_parse(repo, spec)
+        .and_then(|hash| peel_to_tree(repo, &hash))
+        .unwrap_or_else(|e| fail(e))
+}
+
+/// Return the remote the current branch tracks, or origin
+fn default_remote(repo: &Repository) -> String {
+    let config = repo.config().unwrap_or_else(|e| fail(e));
+    let remote = match read_head(repo).unwrap_or_else(|e| fail(e)) {
+        Head::Branch(name) => {
+            let branch = name.trim_start_matches("refs/heads/");
+            config.get(&format!("branch.{}.remote", branch))
+        }
+        Head::Detached(_) => None,
+    };
+    remote.unwrap_or("origin").to_string()
+}
+
+/// Print what a repack would pack and remove
+fn print_repack_dry_run(report: &RepackReport) {
+    println!("Would pack {} objects", report.packed_objects);
+    for pack in &report.removed_packs {
+        println!("Would remove {}", pack.display());
+    }
+}
+
+/// Print the commits a cherry-pick or revert created and why it stopped
+fn report_sequencer(repo: &Repository, report: SequencerReport) {
+    let branch = match read_head(repo).unwrap_or_else(|e| fail(e)) {
+        Head::Branch(name) => name.trim_start_matches("refs/heads/").to_string(),
+        Head::Detached(_) => "detached HEAD".to_string(),
+    };
+    for hash in &report.created {
+        let commit = Commit::read(repo, hash).unwrap_or_else(|e| fail(e));
+        println!("[{} {}] {}", branch, &hash.to_hex()[..7], commit.summary());
+        let options = DiffOptions::default();
+        let old = commit
+            .parents
+            .first()
+            .map(|p| resolve_tree(repo, &p.to_hex()));
+        let diffs = diff::diff_trees(repo, old.as_ref(), Some(&commit.tree), &options)
+            .unwrap_or_else(|e| fail(e));
+        let stats = diff::diff_stats(repo, &diffs, &options).unwrap_or_else(|e| fail(e));
+        // Like `git commit`, only the totals line of the stat is shown
+        if let Some(totals) = diff::format_stat(&stats).lines().last() {
+            println!("{}", totals);
+        }
+        print!("{}", diff::format_summary(&diffs));
+    }
+
+    let Some(stop) = report.stopped else {
+        return;
+    };
+    let command = stop.action.command();
+    match stop.reason {
+        StopReason::Conflicted(result) => {
+            result.messages.iter().for_each(|m| println!("{}", m));
+            let verb = match stop.action {
+                Action::Pick => "apply",
+                Action::Revert => "revert",
+            };
+            fail(format!(
+                "error: could not {verb} {}... {}\n\
+                 hint: After resolving the conflicts, mark them with\n\
+                 hint: \"legit add/rm <pathspec>\", then run\n\
+                 hint: \"legit {command} --continue\".\n\
+                 hint: You can instead skip this commit with \"legit {command} --skip\".\n\
+                 hint: To abort and get back to the state before \"legit {command}\",\n\
+                 hint: run \"legit {command} --abort\".",
+                &stop.commit.to_hex()[..7],
+                stop.summary,
+            ))
+        }
+        StopReason::Empty => fail(format!(
+            "The previous {command} is now empty, possibly due to conflict resolution.\n\
+             If you wish to commit it anyway, use \"legit {command} --continue\".\n\
+             Otherwise, please use \"legit {command} --skip\"."
+        )),
+    }
+}
+
+/// Print how a rebase ended or why it stopped
+fn report_rebase(outcome: RebaseOutcome) {
+    match outcome {
+        RebaseOutcome::UpToDate { head_name } => {
+            let name = head_name.trim_start_matches("refs/heads/");
+            println!("Current branch {} is up to date.", name);
+        }
+        RebaseOutcome::Rebased {
+            head_name,
+            updated_refs,
+        } => {
+            if !updated_refs.is_empty() {
+                eprintln!("Updated the following refs with --update-refs:");
+                updated_refs.iter().for_each(|name| eprintln!("\t{}", name));
+            }
+            eprintln!("Successfully rebased and updated {}.", head_name);
+        }
+        RebaseOutcome::Stopped(RebaseStop::Conflict {
+            commit,
+            summary,
+            result,
+        }) => {
+            result.messages.iter().for_each(|m| println!("{}", m));
+            let commit = &commit.to_hex()[..7];
+            fail(format!(
+                "error: could not apply {commit}... {summary}\n\
+                 hint: Resolve all conflicts manually, mark them as resolved with\n\
+                 hint: \"legit add/rm <conflicted_files>\", then run \"legit rebase --continue\".\n\
+                 hint: You can instead skip this commit: run \"legit rebase --skip\".\n\
+                 hint: To abort and get back to the state before \"legit rebase\", run \"legit rebase --abort\".\n\
+                 Could not apply {commit}... {summary}"
+            ))
+        }
+        RebaseOutcome::Stopped(RebaseStop::Edit { commit, summary }) => {
+            eprintln!(
+                "Stopped at {}...  {}\n\
+                 You can amend the commit now: stage your changes and run\n\n  \
+                 legit rebase --continue\n",
+                &commit.to_hex()[..7],
+                summary
+            );
+        }
+        RebaseOutcome::Stopped(RebaseStop::Break) => {
+            eprintln!("Stopped; run \"legit rebase --continue\" to resume.");
+        }
+        RebaseOutcome::Stopped(RebaseStop::Exec { command }) => fail(format!(
+            "warning: execution failed: {}\n\
+             You can fix the problem, and then run\n\n  \
+             legit rebase --continue\n",
+            command
+        )),
+    }
+}
+
+/// Print where HEAD ended up after a checkout or switch
+fn report_head(head: &Head) {
+    match head {
+        Head::Branch(_) => println!("Switched to branch '{}'", head.branch().unwrap_or_default()),
+        Head::Detached(hash) => println!("HEAD is now at {}", hash),
+    }
 }
 
 fn main() {
@@ -63,10 +1565,14 @@ fn main() {
     };
 
     match args.command {
-        Command::Init { path } => {
+        Command::Init {
+            path,
+            object_format,
+            ref_format,
+        } => {
             println!("Initializing repository...");
             let path = path.map_or(base_path.clone(), PathBuf::from);
-            let repo = Repository::new(&path);
+            let repo = Repository::new_with_formats(&path, object_format, ref_format);
             match repo {
                 Ok(_) => {
                     println!("Initialized empty git repository in {}", path.display());
@@ -83,20 +1589,18 @@ fn main() {
                 Ok(repo) => {
                     println!("{:#?}", repo.settings());
                 }
-                Err(e) => {
-                    eprintln!("{}", e);
-                    std::process::exit(1);
-                }
+                Err(e) => fail_repo(e),
             }
         }
         Command::CatFile { hash, .. } => {
             let repo = Repository::find(&base_path);
-            let hash = ObjectHash::from_hex(hash.as_str()).unwrap_or_else(|_| {
-                eprintln!("Invalid hash format");
-                std::process::exit(1);
-            });
             match repo {
                 Ok(repo) => {
+                    let hash = ObjectHash::from_hex(hash.as_str(), repo.hash_algorithm())
+                        .unwrap_or_else(|_| {
+                            eprintln!("Invalid hash format");
+                            std::process::exit(1);
+                        });
                     let object = read_object(&repo, &hash);
                     match object {
                         Ok(obj) => {
@@ -124,12 +1628,22 @@ fn main() {
                 eprintln!("Failed to read file {}: {}", path.display(), e);
                 std::process::exit(1);
             });
-            let object = Object::new(object_type, data).unwrap_or_else(|e| {
+            // Outside of a repository, objects are hashed with SHA-1
+            let repo = Repository::find(&base_path);
+            if let Err(e) = &repo {
+                if e.is::<FormatError>() {
+                    fail_repo(repo.unwrap_err());
+                }
+            }
+            let algorithm = repo
+                .as_ref()
+                .map_or(HashAlgorithm::Sha1, |repo| repo.hash_algorithm());
+            let object = Object::new(algorithm, object_type, data).unwrap_or_else(|e| {
                 eprintln!("Failed to create object: {}", e);
                 std::process::exit(1);
             });
             if store {
-                let repo = Repository::find(&base_path).unwrap_or_else(|e| {
+                let repo = repo.unwrap_or_else(|e| {
                     eprintln!("{}", e);
                     std::process::exit(1);
                 });
@@ -142,5 +1656,620 @@ fn main() {
                 println!("Hash of file {}: {}", path.display(), object.hash);
             }
         }
+        Command::Checkout {
+            target,
+            new_branch,
+            force,
+            detach,
+            paths,
+        } => {
+            let repo = find_repo(&base_path);
+            if !paths.is_empty() {
+                // `checkout [<rev>] -- <paths>` restores from the index or the revision
+                let options = RestoreOptions {
+                    staged: target.is_some(),
+                    worktree: true,
+                    source: target,
+                };
+                checkout::restore(&repo, &paths, &options).unwrap_or_else(|e| fail(e));
+                return;
+            }
+            let target = target.unwrap_or_else(|| "HEAD".to_string());
+            let options = CheckoutOptions {
+                force,
+                new_branch,
+                detach,
+            };
+            let head = checkout::checkout(&repo, &target, &options).unwrap_or_else(|e| fail(e));
+            report_head(&head);
+        }
+        Command::Switch {
+            target,
+            create,
+            force,
+            detach,
+        } => {
+            let repo = find_repo(&base_path);
+            let target = match (&target, &create) {
+                (Some(target), _) => target.clone(),
+                (None, Some(_)) => "HEAD".to_string(),
+                (None, None) => fail("Missing branch name"),
+            };
+            let options = CheckoutOptions {
+                force,
+                new_branch: create,
+                detach,
+            };
+            let head = checkout::switch(&repo, &target, &options).unwrap_or_else(|e| fail(e));
+            report_head(&head);
+        }
+        Command::Restore {
+            paths,
+            source,
+            staged,
+            worktree,
+        } => {
+            let repo = find_repo(&base_path);
+            let options = RestoreOptions {
+                source,
+                staged,
+                worktree,
+            };
+            checkout::restore(&repo, &paths, &options).unwrap_or_else(|e| fail(e));
+        }
+        Command::Status {
+            short,
+            branch,
+            porcelain,
+            nul,
+            untracked_files,
+            ignored,
+            no_renames,
+        } => {
+            let repo = find_repo(&base_path);
+            let untracked = match untracked_files.as_str() {
+                "no" => UntrackedFiles::No,
+                "normal" => UntrackedFiles::Normal,
+                "all" => UntrackedFiles::All,
+                other => fail(format!("Invalid untracked files mode: {}", other)),
+            };
+            let options = StatusOptions {
+                untracked,
+                ignored,
+                no_renames,
+            };
+            let status = status::status(&repo, &options).unwrap_or_else(|e| fail(e));
+            let porcelain = porcelain.or_else(|| (nul && !short).then(|| "v1".to_string()));
+            let output = match porcelain.as_deref() {
+                Some("v1") | Some("1") => status::format_short(&status, branch, nul),
+                Some("v2") | Some("2") => status::format_porcelain_v2(&status, branch, nul),
+                Some(other) => fail(format!("Unsupported porcelain format: {}", other)),
+                None if short => status::format_short(&status, branch, nul),
+                None => {
+                    let hints = status::status_hints(&repo).unwrap_or_else(|e| fail(e));
+                    status::format_long(&status, hints)
+                }
+            };
+            print!("{}", output);
+        }
+        Command::Add { paths, force } => {
+            let repo = find_repo(&base_path);
+            add::add(&repo, &paths, &AddOptions { force }).unwrap_or_else(|e| fail(e));
+        }
+        Command::CheckIgnore {
+            paths,
+            verbose,
+            non_matching,
+            no_index,
+        } => {
+            let repo = find_repo(&base_path);
+            let results = ignore::check_ignore(&repo, &paths, no_index).unwrap_or_else(|e| fail(e));
+            let mut any_ignored = false;
+            for (path, pattern) in results {
+                any_ignored |= pattern.as_ref().is_some_and(|p| !p.negated);
+                match pattern {
+                    Some(p) if verbose => println!("{}:{}:{}\t{}", p.source, p.line, p.text, path),
+                    Some(p) if !p.negated => println!("{}", path),
+                    None if non_matching => println!("::\t{}", path),
+                    _ => {}
+                }
+            }
+            if !any_ignored {
+                std::process::exit(1);
+            }
+        }
+        Command::Diff {
+            revisions,
+            cached,
+            format,
+            paths,
+        } => {
+            let repo = find_repo(&base_path);
+            let options = format.options(true, paths);
+            let revisions = match revisions.as_slice() {
+                [range] if range.contains("..") => {
+                    let (from, to) = range.split_once("..").unwrap_or_default();
+                    let side = |s: &str| if s.is_empty() { "HEAD" } else { s }.to_string();
+                    vec![side(from), side(to)]
+                }
+                _ => revisions,
+            };
+            let index = Index::read(&repo).unwrap_or_else(|e| fail(e));
+            let diffs = match (revisions.as_slice(), cached) {
+                ([], false) => diff::diff_index_to_worktree(&repo, &index, &options),
+                ([], true) => {
+                    let tree = checkout::head_tree(&repo).unwrap_or_else(|e| fail(e));
+                    diff::diff_tree_to_index(&repo, tree.as_ref(), &index, &options)
+                }
+                ([rev], true) => {
+                    let tree = resolve_tree(&repo, rev);
+                    diff::diff_tree_to_index(&repo, Some(&tree), &index, &options)
+                }
+                ([rev], false) => {
+                    let tree = resolve_tree(&repo, rev);
+                    diff::diff_tree_to_worktree(&repo, Some(&tree), &index, &options)
+                }
+                ([old, new], false) => {
+                    let (old, new) = (resolve_tree(&repo, old), resolve_tree(&repo, new));
+                    diff::diff_trees(&repo, Some(&old), Some(&new), &options)
+                }
+                _ => fail("Too many revisions"),
+            }
+            .unwrap_or_else(|e| fail(e));
+            let mut format = format;
+            format.patch = true;
+            format.print(&repo, &diffs, &options);
+        }
+        Command::DiffTree {
+            trees,
+            recursive,
+            root,
+            format,
+            paths,
+        } => {
+            let repo = find_repo(&base_path);
+            let options = format.options(false, paths);
+            let (old, new) = match trees.as_slice() {
+                [commit] => {
+                    let hash = rev_parse(&repo, commit)
+                        .and_then(|hash| peel_to_commit(&repo, &hash))
+                        .unwrap_or_else(|e| fail(e));
+                    let parent = Commit::read(&repo, &hash)
+                        .unwrap_or_else(|e| fail(e))
+                        .parents
+                        .first()
+                        .cloned();
+                    if parent.is_none() && !root {
+                        return;
+                    }
+                    println!("{}", hash);
+                    (
+                        parent.map(|p| resolve_tree(&repo, &p.to_hex())),
+                        resolve_tree(&repo, commit),
+                    )
+                }
+                [old, new] => (Some(resolve_tree(&repo, old)), resolve_tree(&repo, new)),
+                _ => unreachable!("clap limits the number of trees"),
+            };
+            // Patches always recurse into subtrees
+            let recursive = recursive || format.patch || format.stat || format.word_diff.is_some();
+            let files = |tree: &legit::objects::ObjectHash| {
+                diff::tree_files(&repo, tree, recursive).unwrap_or_else(|e| fail(e))
+            };
+            let old_files = old.as_ref().map(files).unwrap_or_default();
+            let diffs = diff::diff_files(&repo, &old_files, &files(&new), false, &options)
+                .unwrap_or_else(|e| fail(e));
+            if !format.print(&repo, &diffs, &options) {
+                print!("{}", diff::format_raw(&diffs));
+            }
+        }
+        Command::Merge {
+            commit,
+            ff_only,
+            no_ff,
+            message,
+            conflict,
+            continue_merge,
+            abort,
+        } => {
+            let repo = find_repo(&base_path);
+            if continue_merge {
+                merge::merge_continue(&repo).unwrap_or_else(|e| fail(e));
+                return;
+            }
+            if abort {
+                merge::merge_abort(&repo).unwrap_or_else(|e| fail(e));
+                return;
+            }
+            let commit = commit.expect("clap requires a commit");
+            let options = MergeOptions {
+                fast_forward: match (ff_only, no_ff) {
+                    (true, _) => FastForward::Only,
+                    (_, true) => FastForward::Never,
+                    _ => FastForward::Allow,
+                },
+                style: conflict.unwrap_or_else(|| {
+                    ConflictStyle::from_config(&repo).unwrap_or_else(|e| fail(e))
+                }),
+                message,
+            };
+            let old_head = rev_parse(&repo, "HEAD").ok();
+            let outcome = merge::merge(&repo, &commit, &options).unwrap_or_else(|e| fail(e));
+            let print_stat = |new: &ObjectHash| {
+                let options = DiffOptions::default();
+                let old = old_head.as_ref().map(|h| resolve_tree(&repo, &h.to_hex()));
+                let new = resolve_tree(&repo, &new.to_hex());
+                let diffs = diff::diff_trees(&repo, old.as_ref(), Some(&new), &options)
+                    .unwrap_or_else(|e| fail(e));
+                let stats = diff::diff_stats(&repo, &diffs, &options).unwrap_or_else(|e| fail(e));
+                if !stats.is_empty() {
+                    print!("{}", diff::format_stat(&stats));
+                    print!("{}", diff::format_summary(&diffs));
+                }
+            };
+            match outcome {
+                MergeOutcome::UpToDate => println!("Already up to date."),
+                MergeOutcome::FastForward { from, to } => {
+                    if let Some(from) = from {
+                        println!("Updating {}..{}", &from.to_hex()[..7], &to.to_hex()[..7]);
+                    }
+                    println!("Fast-forward");
+                    print_stat(&to);
+                }
+                MergeOutcome::Merged { commit, result } => {
+                    result.messages.iter().for_each(|m| println!("{}", m));
+                    println!("Merge made by the 'recursive' strategy.");
+                    print_stat(&commit);
+                }
+                MergeOutcome::Conflicted(result) => {
+                    result.messages.iter().for_each(|m| println!("{}", m));
+                    fail("Automatic merge failed; fix conflicts and then commit the result.");
+                }
+            }
+        }
+        Command::CherryPick {
+            sequencer,
+            record_origin,
+        } => sequencer.run(&find_repo(&base_path), Action::Pick, record_origin),
+        Command::Revert { sequencer } => {
+            sequencer.run(&find_repo(&base_path), Action::Revert, false)
+        }
+        Command::Rebase {
+            upstream,
+            branch,
+            onto,
+            interactive,
+            autosquash,
+            no_autosquash,
+            exec,
+            update_refs,
+            no_update_refs,
+            rebase_merges,
+            continue_rebase,
+            skip,
+            abort,
+        } => {
+            let repo = find_repo(&base_path);
+            let outcome = if continue_rebase {
+                rebase::rebase_continue(&repo)
+            } else if skip {
+                rebase::rebase_skip(&repo)
+            } else if abort {
+                rebase::rebase_abort(&repo).unwrap_or_else(|e| fail(e));
+                return;
+            } else {
+                let config = repo.config().unwrap_or_else(|e| fail(e));
+                let config_bool = |key: &str| {
+                    config
+                        .get_bool(key)
+                        .unwrap_or_else(|e| fail(e))
+                        .unwrap_or(false)
+                };
+                let options = RebaseOptions {
+                    upstream,
+                    onto,
+                    branch,
+                    interactive,
+                    autosquash: autosquash
+                        || (interactive && !no_autosquash && config_bool("rebase.autosquash")),
+                    exec,
+                    update_refs: update_refs
+                        || (!no_update_refs && config_bool("rebase.updaterefs")),
+                    rebase_merges,
+                };
+                rebase::rebase(&repo, &options)
+            }
+            .unwrap_or_else(|e| fail(e));
+            report_rebase(outcome);
+        }
+        Command::RevList { commits, paths } => {
+            let repo = find_repo(&base_path);
+            let (mut include, mut exclude) = (Vec::new(), Vec::new());
+            for spec in &commits {
+                let (list, spec) = match spec.strip_prefix('^') {
+                    Some(spec) => (&mut exclude, spec),
+                    None => (&mut include, spec.as_str()),
+                };
+                let hash = rev_parse(&repo, spec)
+                    .and_then(|hash| peel_to_commit(&repo, &hash))
+                    .unwrap_or_else(|e| fail(e));
+                list.push(hash);
+            }
+            let commits = revision::rev_list_paths(&repo, &include, &exclude, &paths)
+                .unwrap_or_else(|e| fail(e));
+            for commit in commits {
+                println!("{}", commit);
+            }
+        }
+        Command::MergeBase {
+            commits,
+            all,
+            octopus,
+            is_ancestor,
+            fork_point,
+        } => {
+            let repo = find_repo(&base_path);
+            let resolve = |spec: &str| {
+                rev_parse(&repo, spec)
+                    .and_then(|hash| peel_to_commit(&repo, &hash))
+                    .unwrap_or_else(|e| fail(e))
+            };
+            if fork_point {
+                let (reference, commit) = match commits.as_slice() {
+                    [reference] => (reference, resolve("HEAD")),
+                    [reference, commit] => (reference, resolve(commit)),
+                    _ => fail("--fork-point takes a reference and at most one commit"),
+                };
+                match merge_base::fork_point(&repo, reference, &commit) {
+                    Ok(Some(hash)) => println!("{}", hash),
+                    Ok(None) => std::process::exit(1),
+                    Err(e) => fail(e),
+                }
+                return;
+            }
+            let hashes = commits.iter().map(|c| resolve(c)).collect::<Vec<_>>();
+            if is_ancestor {
+                let [ancestor, descendant] = hashes.as_slice() else {
+                    fail("--is-ancestor takes exactly two commits");
+                };
+                let found = merge_base::is_ancestor(&repo, ancestor, descendant)
+                    .unwrap_or_else(|e| fail(e));
+                std::process::exit(if found { 0 } else { 1 });
+            }
+            let bases = if octopus {
+                merge_base::octopus_merge_bases(&repo, &hashes)
+            } else if hashes.len() < 2 {
+                fail("merge-base needs at least two commits");
+            } else {
+                merge_base::merge_bases_many(&repo, &hashes[0], &hashes[1..])
+            }
+            .unwrap_or_else(|e| fail(e));
+            if bases.is_empty() {
+                std::process::exit(1);
+            }
+            for base in bases.iter().take(if all { bases.len() } else { 1 }) {
+                println!("{}", base);
+            }
+        }
+        Command::Stash { action, push } => {
+            let repo = find_repo(&base_path);
+            match action {
+                Some(action) => action.run(&repo),
+                None => push.run(&repo, None),
+            }
+        }
+        Command::Worktree { action } => action.run(&find_repo(&base_path), &base_path),
+        Command::Submodule { action } => action.run(&find_repo(&base_path)),
+        Command::Clone {
+            url,
+            directory,
+            branch,
+            local,
+            origin,
+            no_checkout,
+            depth,
+            shallow_since,
+            filter,
+        } => {
+            let directory = directory.unwrap_or_else(|| {
+                let name = url.trim_end_matches('/').trim_end_matches("/.git");
+                let name = name.rsplit('/').next().unwrap_or(name);
+                PathBuf::from(name.strip_suffix(".git").unwrap_or(name))
+            });
+            eprintln!("Cloning into '{}'...", directory.display());
+            let options = CloneOptions {
+                branch,
+                origin,
+                local,
+                no_checkout,
+                progress: std::io::stderr().is_terminal(),
+                depth,
+                shallow_since,
+                filter,
+            };
+            let repo = fetch::clone(&url, &base_path.join(&directory), &options)
+                .unwrap_or_else(|e| fail(e));
+            if resolve_ref(&repo, "HEAD")
+                .unwrap_or_else(|e| fail(e))
+                .is_none()
+            {
+                eprintln!("warning: You appear to have cloned an empty repository.");
+            }
+            eprintln!("done.");
+        }
+        Command::Fetch {
+            remote,
+            refspecs,
+            quiet,
+            depth,
+            shallow_since,
+            unshallow,
+            filter,
+        } => {
+            let repo = find_repo(&base_path);
+            let remote = remote.unwrap_or_else(|| default_remote(&repo));
+            let options = FetchOptions {
+                refspecs,
+                progress: !quiet && std::io::stderr().is_terminal(),
+                depth,
+                shallow_since,
+                unshallow,
+                filter,
+            };
+            let result = fetch::fetch(&repo, &remote, &options).unwrap_or_else(|e| fail(e));
+            if !quiet {
+                eprint!("{}", fetch::format_updates(&result));
+            }
+            if result.updates.iter().any(|update| update.is_rejected()) {
+                fail("error: some local refs could not be updated");
+            }
+        }
+        Command::Push {
+            remote,
+            refspecs,
+            force,
+            force_with_lease,
+            atomic,
+            delete,
+            push_options,
+            set_upstream,
+            dry_run,
+            quiet,
+        } => {
+            let repo = find_repo(&base_path);
+            let remote = remote.unwrap_or_else(|| default_remote(&repo));
+            let options = PushOptions {
+                refspecs,
+                force,
+                force_with_lease: force_with_lease.iter().map(|l| Lease::parse(l)).collect(),
+                atomic,
+                delete,
+                push_options,
+                set_upstream,
+                dry_run,
+                progress: !quiet && std::io::stderr().is_terminal(),
+                quiet,
+            };
+            let result = push::push(&repo, &remote, &options).unwrap_or_else(|e| fail(e));
+            if !quiet || result.updates.iter().any(|update| update.is_rejected()) {
+                eprint!("{}", push::format_push(&result));
+            }
+            for (branch, upstream) in &result.upstreams {
+                println!("branch '{}' set up to track '{}'.", branch, upstream);
+            }
+            if result.updates.iter().any(|update| update.is_rejected()) {
+                fail(format!(
+                    "error: failed to push some refs to '{}'",
+                    result.url
+                ));
+            }
+        }
+        Command::CommitGraph {
+            action:
+                CommitGraphCommand::Write {
+                    split,
+                    changed_paths,
+                },
+        } => {
+            let repo = find_repo(&base_path);
+            let options = CommitGraphOptions {
+                split,
+                changed_paths,
+            };
+            commit_graph::write_commit_graph(&repo, &options).unwrap_or_else(|e| fail(e));
+        }
+        Command::Fsck {
+            unreachable,
+            no_dangling,
+            porcelain,
+        } => {
+            let repo = find_repo(&base_path);
+            let options = FsckOptions {
+                unreachable,
+                no_dangling,
+            };
+            let report = fsck::fsck(&repo, &options).unwrap_or_else(|e| fail(e));
+            for issue in &report.issues {
+                match (porcelain, issue.kind) {
+                    (true, _) => println!("{}", issue.porcelain()),
+                    (false, IssueKind::Error | IssueKind::Warning) => eprintln!("{}", issue),
+                    (false, _) => println!("{}", issue),
+                }
+            }
+            if report.has_errors() {
+                std::process::exit(1);
+            }
+        }
+        Command::Gc {
+            prune,
+            no_prune,
+            dry_run,
+        } => {
+            let repo = find_repo(&base_path);
+            let options = GcOptions {
+                prune_expire: match no_prune {
+                    true => Some("never".to_string()),
+                    false => prune,
+                },
+                dry_run,
+            };
+            let report = gc::gc(&repo, &options).unwrap_or_else(|e| fail(e));
+            if dry_run {
+                println!("Would pack {} refs", report.packed_refs.len());
+                println!(
+                    "Would expire {} reflog entries",
+                    report.expired_reflog_entries
+                );
+                print_repack_dry_run(&report.repack);
+                for hash in &report.pruned_objects {
+                    println!("Would prune {}", hash);
+                }
+            }
+        }
+        Command::Repack {
+            all,
+            delete,
+            write_bitmap_index,
+            dry_run,
+        } => {
+            let repo = find_repo(&base_path);
+            let options = RepackOptions {
+                all,
+                delete,
+                loosen_unreachable: false,
+                write_bitmap: write_bitmap_index,
+                dry_run,
+            };
+            let report = gc::repack(&repo, &options).unwrap_or_else(|e| fail(e));
+            if dry_run {
+                print_repack_dry_run(&report);
+            } else if report.pack.is_none() {
+                println!("Nothing new to pack.");
+            }
+        }
+        Command::MultiPackIndex {
+            action: MultiPackIndexCommand::Write,
+        } => {
+            let repo = find_repo(&base_path);
+            midx::write_midx(&repo).unwrap_or_else(|e| fail(e));
+        }
+        Command::UploadPack { directory } => {
+            let repo = Repository::open(&directory).unwrap_or_else(|e| fail_repo(e));
+            // Clients ask for protocol version 2 through the environment
+            let protocol_v2 = std::env::var("GIT_PROTOCOL")
+                .is_ok_and(|value| value.split(':').any(|field| field == "version=2"));
+            upload_pack(
+                &repo,
+                std::io::stdin().lock(),
+                std::io::stdout().lock(),
+                protocol_v2,
+            )
+            .unwrap_or_else(|e| fail(format!("fatal: {}", e)));
+        }
+        Command::ReceivePack { directory } => {
+            let repo = Repository::open(&directory).unwrap_or_else(|e| fail_repo(e));
+            receive_pack(&repo, std::io::stdin().lock(), std::io::stdout().lock())
+                .unwrap_or_else(|e| fail(format!("fatal: {}", e)));
+        }
     }
 }
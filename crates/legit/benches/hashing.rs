@@ -0,0 +1,37 @@
+//! Compares naming objects with collision-detecting SHA-1 against plain
+//! SHA-1, as used for file checksums. Run with `cargo bench --bench hashing`.
+
+use legit::objects::HashAlgorithm;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const TOTAL: usize = 64 << 20;
+
+fn measure(name: &str, size: usize, hash: impl Fn(&[u8])) {
+    let data: Vec<u8> = (0..size).map(|i| (i * 31 % 251) as u8).collect();
+    let rounds = TOTAL / size;
+    let mut best = Duration::MAX;
+    for _ in 0..5 {
+        let start = Instant::now();
+        for _ in 0..rounds {
+            hash(black_box(&data));
+        }
+        best = best.min(start.elapsed());
+    }
+    let mib_per_sec = (rounds * size) as f64 / best.as_secs_f64() / (1 << 20) as f64;
+    println!("{name:<24} {size:>9} bytes {mib_per_sec:>9.1} MiB/s");
+}
+
+fn main() {
+    for size in [64, 4 << 10, 1 << 20] {
+        measure("sha1", size, |data| {
+            black_box(HashAlgorithm::Sha1.digest(data));
+        });
+        measure("sha1 collision-checked", size, |data| {
+            black_box(HashAlgorithm::Sha1.hash_object(data).unwrap());
+        });
+        measure("sha256", size, |data| {
+            black_box(HashAlgorithm::Sha256.digest(data));
+        });
+    }
+}
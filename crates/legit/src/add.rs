@@ -0,0 +1,213 @@
+use crate::checkout::{normalize_path, path_matches, store_file};
+use crate::ignore::IgnoreRules;
+use crate::index::{Index, IndexEntry};
+use crate::submodule::submodule_head;
+use crate::tree::EntryMode;
+use crate::Repository;
+use anyhow::{bail, Result};
+use std::fs;
+
+/// AddOptions controls how `add` stages files
+#[derive(Debug, Clone, Default)]
+pub struct AddOptions {
+    /// Allow adding otherwise ignored files
+    pub force: bool,
+}
+
+/// Stage the files matching the pathspecs, returning the staged paths
+///
+/// Directories are added recursively, skipping untracked ignored files.
+/// Tracked files that were deleted from the working tree are removed from
+/// the index. Naming an ignored file explicitly is an error unless
+/// `force` is set, but the other paths are still staged.
+pub fn add(repo: &Repository, pathspecs: &[String], options: &AddOptions) -> Result<Vec<String>> {
+    let mut index = Index::read(repo)?;
+    let rules = IgnoreRules::load(repo)?;
+    let mut staged = Vec::new();
+    let mut ignored = Vec::new();
+
+    for pathspec in pathspecs {
+        let pathspec = normalize_path(pathspec);
+        let mut matched = false;
+
+        // Tracked files that no longer exist are staged as deletions
+        let deleted = index
+            .entries
+            .iter()
+            .filter(|e| path_matches(&pathspec, &e.path))
+            .map(|e| e.path.clone())
+            .collect::<Vec<_>>();
+        for path in deleted {
+            matched = true;
+            if fs::symlink_metadata(repo.worktree().join(&path)).is_err() {
+                index.remove(&path);
+                staged.push(path);
+            }
+        }
+
+        let root = match pathspec.as_str() {
+            "." => "",
+            path => path,
+        };
+        let full_path = repo.worktree().join(root);
+        let Ok(metadata) = fs::symlink_metadata(&full_path) else {
+            if !matched {
+                bail!("pathspec '{}' did not match any files", pathspec);
+            }
+            continue;
+        };
+
+        if !metadata.is_dir() {
+            if !options.force && !index.contains(root) && rules.is_ignored(root, false) {
+                ignored.push(root.to_string());
+                continue;
+            }
+            stage_file(repo, &mut index, root, &mut staged)?;
+            continue;
+        }
+
+        // A submodule, or a repository named explicitly, is staged as a
+        // gitlink
+        if index.contains(root) || (!root.is_empty() && full_path.join(".git").exists()) {
+            stage_file(repo, &mut index, root, &mut staged)?;
+            continue;
+        }
+        let mut files = Vec::new();
+        collect_files(repo, &index, &rules, options, root, &mut files)?;
+        for path in files {
+            stage_file(repo, &mut index, &path, &mut staged)?;
+        }
+    }
+
+    index.write(repo)?;
+    if !ignored.is_empty() {
+        bail!(
+            "The following paths are ignored by one of your .gitignore files:\n{}\n\
+             hint: Use -f if you really want to add them.",
+            ignored.join("\n")
+        );
+    }
+    Ok(staged)
+}
+
+/// Store a working tree file and update its index entry if it changed
+///
+/// A directory is a submodule, staged at the commit checked out in it.
+fn stage_file(
+    repo: &Repository,
+    index: &mut Index,
+    path: &str,
+    staged: &mut Vec<String>,
+) -> Result<()> {
+    let metadata = fs::symlink_metadata(repo.worktree().join(path))?;
+    let entry = index.get(path);
+    let (mode, hash) = if metadata.is_dir() {
+        match submodule_head(repo, path)? {
+            Some(hash) => (EntryMode::Gitlink, hash),
+            None => return Ok(()),
+        }
+    } else if entry.is_some_and(|e| e.stat_matches(&metadata)) {
+        return Ok(());
+    } else {
+        store_file(repo, path, entry)?
+    };
+    // A conflicted path has no stage 0 entry, so it is always staged
+    let changed = entry.is_none_or(|e| e.hash != hash || e.mode != mode.bits());
+    index.add(IndexEntry::new(path, mode, hash, Some(&metadata)))?;
+    if changed {
+        staged.push(path.to_string());
+    }
+    Ok(())
+}
+
+/// List the files of a directory that should be staged
+fn collect_files(
+    repo: &Repository,
+    index: &Index,
+    rules: &IgnoreRules,
+    options: &AddOptions,
+    dir: &str,
+    files: &mut Vec<String>,
+) -> Result<()> {
+    let mut entries = fs::read_dir(repo.worktree().join(dir))?
+        .map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+
+    for name in entries {
+        if name == ".git" {
+            continue;
+        }
+        let path = match dir {
+            "" => name,
+            _ => format!("{}/{}", dir, name),
+        };
+        let is_dir = fs::symlink_metadata(repo.worktree().join(&path))?.is_dir();
+        let tracked = index.contains(&path);
+        if is_dir {
+            let has_tracked = index
+                .entries
+                .iter()
+                .any(|e| path_matches(&path, &e.path) && e.path != path);
+            if !has_tracked && !options.force && rules.is_ignored(&path, true) {
+                continue;
+            }
+            if tracked {
+                // Submodules are staged at their checked out commit
+                files.push(path);
+                continue;
+            }
+            if repo.worktree().join(&path).join(".git").exists() {
+                // Nested repositories are left alone
+                continue;
+            }
+            collect_files(repo, index, rules, options, &path, files)?;
+        } else if tracked || options.force || !rules.is_ignored(&path, false) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{commit_files, init_repo};
+
+    #[test]
+    fn test_add_skips_ignored_files() {
+        let (dir, repo) = init_repo();
+        fs::write(dir.path().join(".gitignore"), "*.log\nbuild/\n").unwrap();
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        fs::write(dir.path().join("debug.log"), "log").unwrap();
+        fs::create_dir_all(dir.path().join("build")).unwrap();
+        fs::write(dir.path().join("build/out"), "out").unwrap();
+
+        let staged = add(&repo, &[".".to_string()], &AddOptions::default()).unwrap();
+        assert_eq!(staged, vec![".gitignore", "a.txt"]);
+
+        let error = add(&repo, &["debug.log".to_string()], &AddOptions::default()).unwrap_err();
+        assert!(error.to_string().contains("ignored by one of your"));
+
+        let options = AddOptions { force: true };
+        let staged = add(&repo, &["debug.log".to_string()], &options).unwrap();
+        assert_eq!(staged, vec!["debug.log"]);
+    }
+
+    #[test]
+    fn test_add_stages_modifications_and_deletions() {
+        let (dir, repo) = init_repo();
+        commit_files(&repo, &[("a.txt", "a"), ("b.txt", "b")], "initial");
+        let mut index =
+            Index::from_tree(&repo, &crate::checkout::head_tree(&repo).unwrap().unwrap()).unwrap();
+        index.write(&repo).unwrap();
+
+        fs::write(dir.path().join("a.txt"), "changed").unwrap();
+        let staged = add(&repo, &[".".to_string()], &AddOptions::default()).unwrap();
+        assert_eq!(staged, vec!["b.txt", "a.txt"]);
+
+        index = Index::read(&repo).unwrap();
+        assert!(!index.contains("b.txt"));
+        assert!(add(&repo, &["missing".to_string()], &AddOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,624 @@
+use crate::commits::Commit;
+use crate::objects::{object_exists, read_object, ObjectHash, ObjectType};
+use crate::pack::{packs, Pack};
+use crate::pack_writer::ObjectList;
+use crate::refs::write_atomic;
+use crate::revision::{rev_list, tag_target};
+use crate::tree::{EntryMode, Tree};
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+
+const SIGNATURE: &[u8; 4] = b"BITM";
+
+/// Every object reachable from a bitmapped commit is in the pack
+const OPT_FULL_DAG: u16 = 0x1;
+
+/// Commits are picked for a bitmap about this far apart
+const COMMIT_INTERVAL: usize = 100;
+
+/// Bitmap is a set of objects of a pack, by their position in pack order
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bitmap {
+    words: Vec<u64>,
+}
+
+impl Bitmap {
+    pub fn set(&mut self, bit: usize) {
+        if self.words.len() <= bit / 64 {
+            self.words.resize(bit / 64 + 1, 0);
+        }
+        self.words[bit / 64] |= 1 << (bit % 64);
+    }
+
+    pub fn get(&self, bit: usize) -> bool {
+        self.words
+            .get(bit / 64)
+            .is_some_and(|word| word & (1 << (bit % 64)) != 0)
+    }
+
+    /// Add the bits of another bitmap
+    pub fn or(&mut self, other: &Bitmap) {
+        if self.words.len() < other.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+
+    fn xor(&mut self, other: &Bitmap) {
+        if self.words.len() < other.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word ^= other;
+        }
+    }
+
+    /// Iterate over the set bits in increasing order
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, &word)| {
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| i * 64 + bit)
+        })
+    }
+
+    /// Decode an EWAH compressed bitmap, returning it with the number of
+    /// bytes read
+    pub fn read_ewah(data: &[u8]) -> Result<(Bitmap, usize)> {
+        let word = |i: usize| -> Result<u64> {
+            let bytes = data.get(i..i + 8).context("EWAH bitmap is truncated")?;
+            Ok(u64::from_be_bytes(bytes.try_into()?))
+        };
+        let size = |i: usize| -> Result<usize> {
+            let bytes = data.get(i..i + 4).context("EWAH bitmap is truncated")?;
+            Ok(u32::from_be_bytes(bytes.try_into()?) as usize)
+        };
+        let count = size(4)?;
+        let mut words = Vec::new();
+        let mut i = 0;
+        while i < count {
+            let marker = word(8 + i * 8)?;
+            i += 1;
+            let fill = match marker & 1 {
+                0 => 0,
+                _ => u64::MAX,
+            };
+            let run = ((marker >> 1) & 0xffff_ffff) as usize;
+            let literals = (marker >> 33) as usize;
+            words.resize(words.len() + run, fill);
+            for _ in 0..literals {
+                if i >= count {
+                    bail!("EWAH bitmap has more literal words than stored");
+                }
+                words.push(word(8 + i * 8)?);
+                i += 1;
+            }
+        }
+        Ok((Bitmap { words }, 8 + count * 8 + 4))
+    }
+
+    /// Encode the bitmap with EWAH compression
+    pub fn write_ewah(&self) -> Vec<u8> {
+        let mut words = self.words.as_slice();
+        while let Some((0, rest)) = words.split_last() {
+            words = rest;
+        }
+        let mut encoded: Vec<u64> = Vec::new();
+        let mut last_marker;
+        let mut i = 0;
+        loop {
+            let fill = words.get(i).copied().filter(|&w| w == 0 || w == u64::MAX);
+            let run = match fill {
+                Some(fill) => words[i..]
+                    .iter()
+                    .take(0xffff_ffff)
+                    .take_while(|&&w| w == fill)
+                    .count(),
+                None => 0,
+            };
+            i += run;
+            let literals = words[i..]
+                .iter()
+                .take(0x7fff_ffff)
+                .take_while(|&&w| w != 0 && w != u64::MAX)
+                .count();
+            last_marker = encoded.len();
+            let running_bit = (fill == Some(u64::MAX)) as u64;
+            encoded.push(running_bit | (run as u64) << 1 | (literals as u64) << 33);
+            encoded.extend(&words[i..i + literals]);
+            i += literals;
+            if i >= words.len() {
+                break;
+            }
+        }
+        let mut data = Vec::new();
+        data.extend(((words.len() * 64) as u32).to_be_bytes());
+        data.extend((encoded.len() as u32).to_be_bytes());
+        for word in encoded {
+            data.extend(word.to_be_bytes());
+        }
+        data.extend((last_marker as u32).to_be_bytes());
+        data
+    }
+}
+
+/// PackBitmap is the `.bitmap` file of a pack: for some commits, the set of
+/// objects they reach, and the type of each object of the pack
+///
+/// Bits are numbered in pack order, by the offsets of the objects.
+#[derive(Debug)]
+pub struct PackBitmap {
+    pack: Pack,
+    /// Index position of the object at each pack position
+    index_positions: Vec<u32>,
+    /// Pack position of the object at each index position
+    pack_positions: Vec<u32>,
+    commits: Bitmap,
+    trees: Bitmap,
+    blobs: Bitmap,
+    tags: Bitmap,
+    reachable: HashMap<ObjectHash, Bitmap>,
+}
+
+impl PackBitmap {
+    /// Load the bitmap of the first pack of the repository that has one
+    pub fn open(repo: &Repository) -> Result<Option<PackBitmap>> {
+        for pack in packs(repo)? {
+            let path = pack.path().with_extension("bitmap");
+            if path.exists() {
+                let data = fs::read(&path)?;
+                let bitmap = PackBitmap::parse(pack, &data)
+                    .with_context(|| format!("Invalid bitmap {}", path.display()))?;
+                return Ok(Some(bitmap));
+            }
+        }
+        Ok(None)
+    }
+
+    fn new(pack: Pack) -> PackBitmap {
+        let index = pack.index();
+        let mut index_positions = (0..index.len() as u32).collect::<Vec<_>>();
+        index_positions.sort_by_key(|&position| index.offset(position as usize));
+        let mut pack_positions = vec![0; index.len()];
+        for (pack_position, &index_position) in index_positions.iter().enumerate() {
+            pack_positions[index_position as usize] = pack_position as u32;
+        }
+        PackBitmap {
+            pack,
+            index_positions,
+            pack_positions,
+            commits: Bitmap::default(),
+            trees: Bitmap::default(),
+            blobs: Bitmap::default(),
+            tags: Bitmap::default(),
+            reachable: HashMap::new(),
+        }
+    }
+
+    fn parse(pack: Pack, data: &[u8]) -> Result<PackBitmap> {
+        // The bitmap names its pack by the pack's checksum
+        let pack_checksum = pack.index().pack_checksum().as_bytes().to_vec();
+        let hash_size = pack_checksum.len();
+        if data.len() < 12 + 2 * hash_size || &data[..4] != SIGNATURE {
+            bail!("Not a bitmap file");
+        }
+        let version = u16::from_be_bytes(data[4..6].try_into()?);
+        let options = u16::from_be_bytes(data[6..8].try_into()?);
+        if version != 1 {
+            bail!("Unsupported bitmap version {}", version);
+        }
+        if options & OPT_FULL_DAG == 0 {
+            bail!("Bitmaps without the full DAG option are not supported");
+        }
+        let count = u32::from_be_bytes(data[8..12].try_into()?) as usize;
+        if data[12..12 + hash_size] != pack_checksum {
+            bail!("Bitmap does not match its pack");
+        }
+        let mut bitmap = PackBitmap::new(pack);
+        let mut offset = 12 + hash_size;
+        let read = |offset: &mut usize| -> Result<Bitmap> {
+            let (bitmap, size) = Bitmap::read_ewah(&data[*offset..])?;
+            *offset += size;
+            Ok(bitmap)
+        };
+        bitmap.commits = read(&mut offset)?;
+        bitmap.trees = read(&mut offset)?;
+        bitmap.blobs = read(&mut offset)?;
+        bitmap.tags = read(&mut offset)?;
+
+        let mut entries: Vec<(ObjectHash, Bitmap)> = Vec::with_capacity(count);
+        for i in 0..count {
+            let header = data
+                .get(offset..offset + 6)
+                .context("Bitmap entry is truncated")?;
+            let position = u32::from_be_bytes(header[..4].try_into()?) as usize;
+            let xor_offset = header[4] as usize;
+            offset += 6;
+            let mut reachable = read(&mut offset)?;
+            if xor_offset > i {
+                bail!("Bitmap entry {} has an invalid XOR offset", i);
+            }
+            if xor_offset > 0 {
+                reachable.xor(&entries[i - xor_offset].1);
+            }
+            if position >= bitmap.pack.index().len() {
+                bail!("Bitmap entry {} is out of range", i);
+            }
+            entries.push((bitmap.pack.index().hash(position), reachable));
+        }
+        // A name-hash cache or lookup table may follow; both are only speedups
+        bitmap.reachable = entries.into_iter().collect();
+        Ok(bitmap)
+    }
+
+    /// Return the pack position of an object of the pack
+    fn position(&self, hash: &ObjectHash) -> Option<usize> {
+        let index_position = self.pack.index().position(hash)?;
+        Some(self.pack_positions[index_position] as usize)
+    }
+
+    /// Return the object at a pack position
+    pub fn hash(&self, position: usize) -> ObjectHash {
+        self.pack
+            .index()
+            .hash(self.index_positions[position] as usize)
+    }
+
+    /// Number of commits with a bitmap
+    pub fn len(&self) -> usize {
+        self.reachable.len()
+    }
+
+    /// Return true if no commit has a bitmap
+    pub fn is_empty(&self) -> bool {
+        self.reachable.is_empty()
+    }
+
+    /// Return the pack the bitmap describes
+    pub fn pack(&self) -> &Pack {
+        &self.pack
+    }
+
+    /// Compute the set of objects reachable from `tips`, without walking
+    /// below the objects of `stop`
+    ///
+    /// Commits with a bitmap are not walked at all. Objects outside of the
+    /// pack cannot be in a bitmap; they are added to `extra` with their
+    /// path unless `seen` already has them.
+    pub fn reach(
+        &self,
+        repo: &Repository,
+        tips: &[ObjectHash],
+        stop: &Bitmap,
+        extra: &mut Vec<(ObjectHash, String)>,
+        seen: &mut HashSet<ObjectHash>,
+    ) -> Result<Bitmap> {
+        let mut bits = Bitmap::default();
+        let mut stack = tips
+            .iter()
+            .map(|hash| (hash.clone(), String::new()))
+            .collect::<Vec<_>>();
+        while let Some((hash, path)) = stack.pop() {
+            let position = self.position(&hash);
+            match position {
+                Some(position) => {
+                    if bits.get(position) || stop.get(position) {
+                        continue;
+                    }
+                    if let Some(reachable) = self.reachable.get(&hash) {
+                        bits.or(reachable);
+                        continue;
+                    }
+                    bits.set(position);
+                    if self.blobs.get(position) {
+                        continue;
+                    }
+                }
+                None => {
+                    if !seen.insert(hash.clone()) {
+                        continue;
+                    }
+                    extra.push((hash.clone(), path.clone()));
+                }
+            }
+            let object = read_object(repo, &hash)?;
+            match object.object_type {
+                ObjectType::Commit => {
+                    let commit = Commit::parse(&object.data, repo.hash_algorithm())?;
+                    stack.extend(commit.parents.into_iter().map(|p| (p, String::new())));
+                    stack.push((commit.tree, String::new()));
+                }
+                ObjectType::Tree => {
+                    for entry in Tree::parse(&object.data, object.hash.algorithm())?.entries {
+                        if entry.mode != EntryMode::Gitlink {
+                            let path = match path.is_empty() {
+                                true => entry.name,
+                                false => format!("{}/{}", path, entry.name),
+                            };
+                            stack.push((entry.hash, path));
+                        }
+                    }
+                }
+                ObjectType::Tag => stack.push((
+                    tag_target(&object.data, repo.hash_algorithm())?,
+                    String::new(),
+                )),
+                ObjectType::Blob => {}
+            }
+        }
+        Ok(bits)
+    }
+}
+
+/// List the objects reachable from `include` but not from `exclude` with
+/// the bitmap of a pack, or `None` if no pack has one
+///
+/// Unlike `list_objects`, everything `exclude` reaches is left out, and
+/// objects found through a bitmap have no path.
+pub fn bitmap_objects(
+    repo: &Repository,
+    include: &[ObjectHash],
+    exclude: &[ObjectHash],
+) -> Result<Option<ObjectList>> {
+    let Some(bitmap) = PackBitmap::open(repo)? else {
+        return Ok(None);
+    };
+    let exclude = exclude
+        .iter()
+        .filter(|hash| object_exists(repo, hash))
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut seen = HashSet::new();
+    let mut have_extra = Vec::new();
+    let haves = bitmap.reach(
+        repo,
+        &exclude,
+        &Bitmap::default(),
+        &mut have_extra,
+        &mut seen,
+    )?;
+    let mut list = ObjectList::default();
+    let wants = bitmap.reach(repo, include, &haves, &mut list.objects, &mut seen)?;
+    // Commits first, then trees, blobs and tags, each in pack order
+    for types in [&bitmap.commits, &bitmap.trees, &bitmap.blobs, &bitmap.tags] {
+        for position in wants.ones().filter(|&p| types.get(p) && !haves.get(p)) {
+            list.objects.push((bitmap.hash(position), String::new()));
+        }
+    }
+    Ok(Some(list))
+}
+
+/// Write a `.bitmap` for a pack that holds every object reachable from
+/// `tips`, like `git repack -a -b`
+///
+/// The tips and about one commit in a hundred get a bitmap.
+pub fn write_bitmap(repo: &Repository, pack: &Pack, tips: &[ObjectHash]) -> Result<PathBuf> {
+    let mut bitmap = PackBitmap::new(pack.clone());
+    let index = pack.index();
+    for position in 0..index.len() {
+        let hash = index.hash(position);
+        let object = pack
+            .read_object(repo, &hash)?
+            .context("Object is not in its pack")?;
+        let types = match object.object_type {
+            ObjectType::Commit => &mut bitmap.commits,
+            ObjectType::Tree => &mut bitmap.trees,
+            ObjectType::Blob => &mut bitmap.blobs,
+            ObjectType::Tag => &mut bitmap.tags,
+        };
+        types.set(bitmap.pack_positions[position] as usize);
+    }
+
+    let commits = rev_list(repo, tips, &[])?;
+    let tips = tips.iter().collect::<HashSet<_>>();
+    let selected = commits
+        .iter()
+        .enumerate()
+        .filter(|(i, hash)| i % COMMIT_INTERVAL == 0 || tips.contains(hash))
+        .map(|(_, hash)| hash.clone())
+        .collect::<Vec<_>>();
+    let mut entries = Vec::new();
+    // Oldest first, so newer commits reuse the bitmaps of older ones
+    for hash in selected.into_iter().rev() {
+        let mut extra = Vec::new();
+        let reachable = bitmap.reach(
+            repo,
+            std::slice::from_ref(&hash),
+            &Bitmap::default(),
+            &mut extra,
+            &mut HashSet::new(),
+        )?;
+        if let Some((missing, _)) = extra.first() {
+            bail!(
+                "Object {} reachable from {} is not in the pack",
+                missing,
+                hash
+            );
+        }
+        let position = index.position(&hash).context("Commit is not in the pack")?;
+        entries.push((position as u32, reachable.clone()));
+        bitmap.reachable.insert(hash, reachable);
+    }
+
+    let mut data = Vec::new();
+    data.extend(SIGNATURE);
+    data.extend(1u16.to_be_bytes());
+    data.extend(OPT_FULL_DAG.to_be_bytes());
+    data.extend((entries.len() as u32).to_be_bytes());
+    data.extend(index.pack_checksum().as_bytes());
+    for types in [&bitmap.commits, &bitmap.trees, &bitmap.blobs, &bitmap.tags] {
+        data.extend(types.write_ewah());
+    }
+    for (position, reachable) in entries {
+        data.extend(position.to_be_bytes());
+        data.extend([0, 0]);
+        data.extend(reachable.write_ewah());
+    }
+    let checksum = repo.hash_algorithm().digest(&data);
+    data.extend(checksum.as_bytes());
+    let path = pack.path().with_extension("bitmap");
+    write_atomic(&path, &data)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pack::store_pack;
+    use crate::pack_writer::{list_objects, write_pack};
+    use crate::test_utils::{commit_files, init_repo};
+
+    #[test]
+    fn test_ewah_round_trip() {
+        let mut bitmap = Bitmap::default();
+        for bit in [0, 3, 64, 700, 701] {
+            bitmap.set(bit);
+        }
+        for bit in 1000..1300 {
+            bitmap.set(bit);
+        }
+        let data = bitmap.write_ewah();
+        let (decoded, size) = Bitmap::read_ewah(&data).unwrap();
+        assert_eq!(size, data.len());
+        assert_eq!(
+            decoded.ones().collect::<Vec<_>>(),
+            bitmap.ones().collect::<Vec<_>>()
+        );
+        let (empty, _) = Bitmap::read_ewah(&Bitmap::default().write_ewah()).unwrap();
+        assert_eq!(empty.ones().count(), 0);
+    }
+
+    #[test]
+    fn test_bitmap_objects() {
+        let (_dir, repo) = init_repo();
+        let first = commit_files(&repo, &[("a.txt", "one\n")], "first");
+        let second = commit_files(&repo, &[("a.txt", "two\n"), ("b.txt", "b\n")], "second");
+        let (_dir, server) = init_repo();
+        let list = list_objects(&repo, std::slice::from_ref(&second), &[]).unwrap();
+        let pack = store_pack(&server, &write_pack(&repo, &list, false).unwrap())
+            .unwrap()
+            .unwrap();
+        write_bitmap(&server, &pack, std::slice::from_ref(&second)).unwrap();
+
+        let bitmap = PackBitmap::open(&server).unwrap().unwrap();
+        assert_eq!(bitmap.len(), 1);
+        let objects = |include: &ObjectHash, exclude: &[ObjectHash]| {
+            let list = bitmap_objects(&server, std::slice::from_ref(include), exclude)
+                .unwrap()
+                .unwrap();
+            list.objects
+                .into_iter()
+                .map(|(hash, _)| hash)
+                .collect::<HashSet<_>>()
+        };
+        assert_eq!(objects(&second, &[]).len(), 7);
+        // Only the second commit, its tree and its two new blobs are new
+        let new = objects(&second, std::slice::from_ref(&first));
+        assert_eq!(new.len(), 4);
+        assert!(new.contains(&second));
+        assert!(!new.contains(&first));
+    }
+
+    #[test]
+    fn test_ewah_run_length_words() {
+        let mut bitmap = Bitmap::default();
+        bitmap.set(1);
+        for bit in 128..320 {
+            bitmap.set(bit);
+        }
+        bitmap.set(64 * 10 + 5);
+        bitmap.set(64 * 11 + 7);
+        let data = bitmap.write_ewah();
+        let words = data[8..data.len() - 4]
+            .chunks(8)
+            .map(|w| u64::from_be_bytes(w.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        // A marker has a single fill, so the zero word and the three words
+        // of ones are two runs; the last run of zeros comes with the two
+        // literal words after it
+        assert_eq!(
+            words,
+            [
+                1 << 33,
+                0b10,
+                1 << 1,
+                3 << 1 | 1,
+                5 << 1 | 2 << 33,
+                1 << 5,
+                1 << 7
+            ]
+        );
+        assert_eq!(u32::from_be_bytes(data[..4].try_into().unwrap()), 12 * 64);
+        assert_eq!(
+            u32::from_be_bytes(data[data.len() - 4..].try_into().unwrap()),
+            4,
+            "position of the last marker"
+        );
+        let (decoded, size) = Bitmap::read_ewah(&data).unwrap();
+        assert_eq!(size, data.len());
+        assert_eq!(decoded, bitmap);
+
+        let ones = Bitmap {
+            words: [u64::MAX; 3].into_iter().chain([0, 0, 9]).collect(),
+        };
+        let (decoded, _) = Bitmap::read_ewah(&ones.write_ewah()).unwrap();
+        assert_eq!(decoded, ones);
+
+        // A marker announcing more literal words than there are
+        let mut truncated = Vec::new();
+        truncated.extend(64u32.to_be_bytes());
+        truncated.extend(1u32.to_be_bytes());
+        truncated.extend((2u64 << 33).to_be_bytes());
+        truncated.extend(0u32.to_be_bytes());
+        assert!(Bitmap::read_ewah(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_bitmap_objects_of_older_commits() {
+        let (_dir, repo) = init_repo();
+        let mut commits = Vec::new();
+        for i in 0..5 {
+            let name = format!("{}.txt", i);
+            commits.push(commit_files(
+                &repo,
+                &[(&name, "same\n"), ("a.txt", &i.to_string())],
+                "commit",
+            ));
+        }
+        let tip = commits.last().unwrap().clone();
+        let (_dir, server) = init_repo();
+        let list = list_objects(&repo, std::slice::from_ref(&tip), &[]).unwrap();
+        let pack = store_pack(&server, &write_pack(&repo, &list, false).unwrap())
+            .unwrap()
+            .unwrap();
+        write_bitmap(&server, &pack, std::slice::from_ref(&tip)).unwrap();
+        let bitmap = PackBitmap::open(&server).unwrap().unwrap();
+        assert!(!bitmap.reachable.contains_key(&commits[3]));
+
+        // Commits without a bitmap of their own are walked down to the
+        // bitmapped ones and give what a plain walk gives
+        let hashes = |list: ObjectList| {
+            list.objects
+                .into_iter()
+                .map(|(hash, _)| hash)
+                .collect::<HashSet<_>>()
+        };
+        for include in 1..4 {
+            for exclude in [None, Some(0), Some(include - 1)] {
+                let exclude = exclude
+                    .map(|i| commits[i].clone())
+                    .into_iter()
+                    .collect::<Vec<_>>();
+                let include = std::slice::from_ref(&commits[include]);
+                let found = bitmap_objects(&server, include, &exclude).unwrap().unwrap();
+                let expected = list_objects(&server, include, &exclude).unwrap();
+                assert_eq!(hashes(found), hashes(expected));
+            }
+        }
+    }
+}
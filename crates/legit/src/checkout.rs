@@ -0,0 +1,696 @@
+use crate::commits::Commit;
+use crate::index::{Index, IndexEntry};
+use crate::objects::{read_object, store_object, Object, ObjectHash, ObjectType};
+use crate::refs::{read_head, resolve_ref, set_head, update_ref, Head};
+use crate::revision::{peel_to_tree, rev_parse};
+use crate::tree::{flatten_tree, verify_path, EntryMode};
+use crate::worktree::checked_out_elsewhere;
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, Metadata};
+use std::path::Path;
+
+/// A flattened tree: every file path with its mode and blob hash
+pub type FileMap = BTreeMap<String, (EntryMode, ObjectHash)>;
+
+/// CheckoutOptions controls how `checkout` and `switch` move HEAD
+#[derive(Debug, Default, Clone)]
+pub struct CheckoutOptions {
+    /// Discard local changes instead of refusing to overwrite them
+    pub force: bool,
+    /// Create a branch with this name at the target and switch to it
+    pub new_branch: Option<String>,
+    /// Detach HEAD even if the target is a branch
+    pub detach: bool,
+}
+
+/// RestoreOptions selects the source and destinations of `restore`
+#[derive(Debug, Default, Clone)]
+pub struct RestoreOptions {
+    /// Revision to restore from; defaults to the index for the working tree
+    /// and to HEAD for the index
+    pub source: Option<String>,
+    /// Restore the index
+    pub staged: bool,
+    /// Restore the working tree
+    pub worktree: bool,
+}
+
+/// Switch to a branch or detach HEAD at any other revision, like `git checkout`
+pub fn checkout(repo: &Repository, target: &str, options: &CheckoutOptions) -> Result<Head> {
+    move_head(repo, target, options, true)
+}
+
+/// Switch to a branch, like `git switch`
+///
+/// Unlike `checkout`, a revision that is not a branch is refused unless
+/// `detach` is set.
+pub fn switch(repo: &Repository, target: &str, options: &CheckoutOptions) -> Result<Head> {
+    move_head(repo, target, options, options.detach)
+}
+
+fn move_head(
+    repo: &Repository,
+    target: &str,
+    options: &CheckoutOptions,
+    allow_detach: bool,
+) -> Result<Head> {
+    let branch_ref = format!("refs/heads/{}", target);
+    let is_branch = options.new_branch.is_none()
+        && !options.detach
+        && resolve_ref(repo, &branch_ref)?.is_some();
+
+    let commit = rev_parse(repo, target)?;
+    let head = if let Some(name) = &options.new_branch {
+        let new_ref = format!("refs/heads/{}", name);
+        if resolve_ref(repo, &new_ref)?.is_some() {
+            bail!("A branch named '{}' already exists", name);
+        }
+        Head::Branch(new_ref)
+    } else if is_branch {
+        if let Some(path) = checked_out_elsewhere(repo, &branch_ref)? {
+            bail!(
+                "'{}' is already checked out at '{}'",
+                target,
+                path.display()
+            );
+        }
+        Head::Branch(branch_ref)
+    } else if allow_detach {
+        Head::Detached(commit.clone())
+    } else {
+        bail!("A branch is expected, got '{}'", target);
+    };
+
+    let tree = peel_to_tree(repo, &commit)?;
+    checkout_tree(repo, &tree, options.force)?;
+
+    if let (Some(_), Head::Branch(name)) = (&options.new_branch, &head) {
+        update_ref(repo, name, &commit)?;
+    }
+    set_head(repo, &head)?;
+    Ok(head)
+}
+
+/// Return the tree of the commit HEAD points to, if there is one
+pub fn head_tree(repo: &Repository) -> Result<Option<ObjectHash>> {
+    match resolve_ref(repo, "HEAD")? {
+        Some(commit) => Ok(Some(Commit::read(repo, &commit)?.tree)),
+        None => Ok(None),
+    }
+}
+
+/// Update the index and working tree from the HEAD tree to `target`
+///
+/// This is a two-tree merge: only paths that differ between the HEAD tree and
+/// the target are touched, so local changes to other paths are carried over.
+/// Unless `force` is set, the update is refused if it would overwrite local
+/// modifications or untracked files. With `force`, the index and working tree
+/// are reset to the target.
+pub fn checkout_tree(repo: &Repository, target: &ObjectHash, force: bool) -> Result<()> {
+    let current = match head_tree(repo)? {
+        Some(tree) => flatten_tree(repo, &tree)?,
+        None => FileMap::new(),
+    };
+    let target = flatten_tree(repo, target)?;
+    let mut index = Index::read(repo)?;
+
+    let mut paths = current
+        .keys()
+        .chain(target.keys())
+        .filter(|path| current.get(*path) != target.get(*path))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+
+    if force {
+        paths.extend(index.entries.iter().map(|e| e.path.clone()));
+        paths.extend(target.keys().cloned());
+    } else {
+        let conflicts = overwritten_paths(repo, &index, &paths, &current, &target);
+        if !conflicts.is_empty() {
+            bail!(
+                "Your local changes to the following files would be overwritten by checkout:\n\t{}\nPlease commit your changes or stash them before you switch branches.",
+                conflicts.join("\n\t")
+            );
+        }
+    }
+
+    apply_changes(repo, &mut index, &paths, &target)?;
+    index.write(repo)
+}
+
+/// Write the target version of every path to the index and working tree,
+/// removing paths missing from the target
+pub(crate) fn apply_changes(
+    repo: &Repository,
+    index: &mut Index,
+    paths: &BTreeSet<String>,
+    target: &FileMap,
+) -> Result<()> {
+    // Refuse unsafe paths before touching anything
+    for path in paths.iter().filter(|path| target.contains_key(*path)) {
+        verify_path(path)?;
+    }
+    // Remove files first so that a file can be replaced by a directory
+    for path in paths.iter().filter(|path| !target.contains_key(*path)) {
+        index.remove(path);
+        remove_file(repo, path)?;
+    }
+    for path in paths {
+        if let Some((mode, hash)) = target.get(path) {
+            let metadata = write_file(repo, path, *mode, hash)?;
+            index.add(IndexEntry::new(path, *mode, hash.clone(), Some(&metadata)))?;
+        }
+    }
+    Ok(())
+}
+
+/// Return the paths whose local changes would be lost by moving them from
+/// their `current` to their `target` version
+pub(crate) fn overwritten_paths(
+    repo: &Repository,
+    index: &Index,
+    paths: &BTreeSet<String>,
+    current: &FileMap,
+    target: &FileMap,
+) -> Vec<String> {
+    paths
+        .iter()
+        .filter(|path| !is_safe_to_update(repo, index, path, current.get(*path), target.get(*path)))
+        .cloned()
+        .collect()
+}
+
+/// Check that switching a path from `current` to `target` loses no local work
+fn is_safe_to_update(
+    repo: &Repository,
+    index: &Index,
+    path: &str,
+    current: Option<&(EntryMode, ObjectHash)>,
+    target: Option<&(EntryMode, ObjectHash)>,
+) -> bool {
+    if index.get_stage(path, 1).is_some() || index.get_stage(path, 2).is_some() {
+        return false;
+    }
+    let staged = index
+        .get(path)
+        .map(|e| (e.entry_mode().ok(), e.hash.clone()));
+    let as_staged = |entry: Option<&(EntryMode, ObjectHash)>| {
+        entry.map(|(mode, hash)| (Some(*mode), hash.clone()))
+    };
+    // The staged version must be either the HEAD or the target version
+    if staged != as_staged(current) && staged != as_staged(target) {
+        return false;
+    }
+
+    let file = repo.worktree().join(path);
+    let worktree = match fs::symlink_metadata(&file) {
+        // Never replace a directory that may hold untracked files
+        Ok(metadata) if metadata.is_dir() => return false,
+        Ok(_) => hash_file(repo, path, index.get(path)).ok(),
+        Err(_) => None,
+    };
+    match (index.get(path), worktree) {
+        // An untracked file is only safe to overwrite with identical content
+        (None, Some((_, hash))) => target.is_some_and(|(_, target_hash)| *target_hash == hash),
+        (None, None) => true,
+        // A tracked file must not have unstaged changes
+        (Some(entry), Some((_, hash))) => entry.hash == hash,
+        // A deleted tracked file is fine to remove or recreate
+        (Some(_), None) => true,
+    }
+}
+
+/// Restore paths in the index and/or working tree, like `git restore`
+///
+/// A path names a file or a directory; every path must match something in
+/// the source.
+pub fn restore(repo: &Repository, paths: &[String], options: &RestoreOptions) -> Result<()> {
+    let mut index = Index::read(repo)?;
+    let worktree = options.worktree || !options.staged;
+
+    let source = match &options.source {
+        Some(rev) => Some(flatten_tree(
+            repo,
+            &peel_to_tree(repo, &rev_parse(repo, rev)?)?,
+        )?),
+        None if options.staged => match head_tree(repo)? {
+            Some(tree) => Some(flatten_tree(repo, &tree)?),
+            None => Some(FileMap::new()),
+        },
+        None => None,
+    };
+    // Without an explicit source the working tree is restored from the index
+    let source = match source {
+        Some(files) => files,
+        None => {
+            if let Some(path) = index
+                .conflicted_paths()
+                .into_iter()
+                .find(|path| paths.iter().any(|p| path_matches(p, path)))
+            {
+                bail!("Path '{}' is unmerged", path);
+            }
+            index
+                .entries
+                .iter()
+                .map(|e| Ok((e.path.clone(), (e.entry_mode()?, e.hash.clone()))))
+                .collect::<Result<FileMap>>()?
+        }
+    };
+
+    for pathspec in paths {
+        let pathspec = normalize_path(pathspec);
+        let in_source = source.keys().filter(|p| path_matches(&pathspec, p));
+        let in_index = index
+            .entries
+            .iter()
+            .map(|e| &e.path)
+            .filter(|p| path_matches(&pathspec, p));
+        let matched = in_source.chain(in_index).cloned().collect::<BTreeSet<_>>();
+        if matched.is_empty() {
+            bail!(
+                "Pathspec '{}' did not match any file(s) known to legit",
+                pathspec
+            );
+        }
+
+        for path in matched {
+            let entry = source.get(&path);
+            if options.staged {
+                match entry {
+                    Some((mode, hash)) => {
+                        let mut new_entry = IndexEntry::new(&path, *mode, hash.clone(), None);
+                        if let Some(old) = index.get(&path).filter(|old| old.hash == *hash) {
+                            new_entry = old.clone();
+                        }
+                        index.add(new_entry)?;
+                    }
+                    None => {
+                        index.remove(&path);
+                    }
+                }
+            }
+            if worktree {
+                match entry {
+                    Some((mode, hash)) => {
+                        let metadata = write_file(repo, &path, *mode, hash)?;
+                        if options.staged || options.source.is_none() {
+                            if let Some(e) = index.entries.iter_mut().find(|e| e.path == path) {
+                                if e.hash == *hash {
+                                    e.update_stat(&metadata);
+                                }
+                            }
+                        }
+                    }
+                    None => remove_file(repo, &path)?,
+                }
+            }
+        }
+    }
+    index.write(repo)
+}
+
+/// Return true if `path` is `pathspec` or lies in the directory it names
+pub fn path_matches(pathspec: &str, path: &str) -> bool {
+    pathspec.is_empty()
+        || pathspec == "."
+        || path == pathspec
+        || path
+            .strip_prefix(pathspec)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Normalize a user supplied path relative to the worktree root
+pub fn normalize_path(path: &str) -> String {
+    let path = path.trim_start_matches("./").trim_end_matches('/');
+    if path.is_empty() {
+        ".".to_string()
+    } else {
+        path.to_string()
+    }
+}
+
+/// Hash a working tree file as a blob without storing it
+///
+/// The mode is derived from the file, honoring `core.filemode` and
+/// `core.symlinks`: when they are off, the mode of the index entry is kept.
+pub fn hash_file(
+    repo: &Repository,
+    path: &str,
+    entry: Option<&IndexEntry>,
+) -> Result<(EntryMode, ObjectHash)> {
+    let (mode, data) = read_file(repo, path, entry)?;
+    Ok((
+        mode,
+        Object::new(repo.hash_algorithm(), ObjectType::Blob, data)?.hash,
+    ))
+}
+
+/// Hash a working tree file and store it as a blob
+pub fn store_file(
+    repo: &Repository,
+    path: &str,
+    entry: Option<&IndexEntry>,
+) -> Result<(EntryMode, ObjectHash)> {
+    let (mode, data) = read_file(repo, path, entry)?;
+    let hash = store_object(
+        &Object::new(repo.hash_algorithm(), ObjectType::Blob, data)?,
+        repo,
+    )?;
+    Ok((mode, hash))
+}
+
+fn read_file(
+    repo: &Repository,
+    path: &str,
+    entry: Option<&IndexEntry>,
+) -> Result<(EntryMode, Vec<u8>)> {
+    let file = repo.worktree().join(path);
+    let metadata = fs::symlink_metadata(&file)
+        .with_context(|| format!("Failed to read metadata of {}", file.display()))?;
+    let indexed_mode = entry.and_then(|e| e.entry_mode().ok());
+    let settings = &repo.settings().core;
+
+    if metadata.file_type().is_symlink() {
+        let target = fs::read_link(&file)?;
+        let data = target.to_string_lossy().into_owned().into_bytes();
+        return Ok((EntryMode::Symlink, data));
+    }
+    let data = fs::read(&file).with_context(|| format!("Failed to read {}", file.display()))?;
+    let mode = match indexed_mode {
+        Some(EntryMode::Symlink) if !settings.symlinks => EntryMode::Symlink,
+        Some(mode) if !settings.filemode => mode,
+        _ if is_executable(&metadata) && settings.filemode => EntryMode::BlobExecutable,
+        _ => EntryMode::Blob,
+    };
+    Ok((mode, data))
+}
+
+#[cfg(unix)]
+pub(crate) fn is_executable(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_executable(_metadata: &Metadata) -> bool {
+    false
+}
+
+/// Write a blob to the working tree, replacing whatever is at the path
+///
+/// Symlinks are created as links when `core.symlinks` is on and as plain
+/// files holding the link target otherwise. The executable bit is only set
+/// when `core.filemode` is on.
+pub fn write_file(
+    repo: &Repository,
+    path: &str,
+    mode: EntryMode,
+    hash: &ObjectHash,
+) -> Result<Metadata> {
+    // Paths come from trees and indexes that may have been crafted to
+    // write outside the working tree
+    verify_path(path)?;
+    let file = repo.worktree().join(path);
+    if mode == EntryMode::Gitlink {
+        // Submodules are checked out separately; only make sure the directory exists
+        fs::create_dir_all(&file)?;
+        return Ok(fs::symlink_metadata(&file)?);
+    }
+
+    let object = read_object(repo, hash)?;
+    if let Some(parent) = file.parent() {
+        remove_blocking_files(repo.worktree(), parent)?;
+        fs::create_dir_all(parent)?;
+    }
+    if let Ok(metadata) = fs::symlink_metadata(&file) {
+        if metadata.is_dir() {
+            fs::remove_dir_all(&file)?;
+        } else {
+            fs::remove_file(&file)?;
+        }
+    }
+
+    let settings = &repo.settings().core;
+    if mode == EntryMode::Symlink && settings.symlinks {
+        create_symlink(&object.data, &file)?;
+    } else {
+        fs::write(&file, &object.data)
+            .with_context(|| format!("Failed to write {}", file.display()))?;
+        if settings.filemode {
+            set_executable(&file, mode == EntryMode::BlobExecutable)?;
+        }
+    }
+    Ok(fs::symlink_metadata(&file)?)
+}
+
+/// Remove files standing where a directory of `dir` needs to be created
+fn remove_blocking_files(worktree: &Path, dir: &Path) -> Result<()> {
+    let mut current = dir;
+    while current != worktree && current.starts_with(worktree) {
+        if let Ok(metadata) = fs::symlink_metadata(current) {
+            if !metadata.is_dir() {
+                fs::remove_file(current)?;
+            }
+        }
+        current = match current.parent() {
+            Some(parent) => parent,
+            None => break,
+        };
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &[u8], file: &Path) -> Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let target = std::ffi::OsStr::from_bytes(target);
+    std::os::unix::fs::symlink(target, file)
+        .with_context(|| format!("Failed to create symlink {}", file.display()))
+}
+
+#[cfg(not(unix))]
+fn create_symlink(target: &[u8], file: &Path) -> Result<()> {
+    fs::write(file, target).with_context(|| format!("Failed to write {}", file.display()))
+}
+
+#[cfg(unix)]
+fn set_executable(file: &Path, executable: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = if executable { 0o755 } else { 0o644 };
+    fs::set_permissions(file, fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_file: &Path, _executable: bool) -> Result<()> {
+    Ok(())
+}
+
+/// Remove a file from the working tree and prune the directories it leaves empty
+pub fn remove_file(repo: &Repository, path: &str) -> Result<()> {
+    let file = repo.worktree().join(path);
+    match fs::symlink_metadata(&file) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&file)?,
+        Ok(_) => fs::remove_file(&file)?,
+        Err(_) => return Ok(()),
+    }
+    let mut dir = file.parent();
+    while let Some(current) = dir {
+        if current == repo.worktree() || fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+    Ok(())
+}
+
+/// Return the branch HEAD is on, or `None` when detached
+pub fn current_branch(repo: &Repository) -> Result<Option<String>> {
+    Ok(read_head(repo)?.branch().map(str::to_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{commit_files, init_repo};
+
+    fn setup() -> (tempfile::TempDir, Repository, ObjectHash, ObjectHash) {
+        let (dir, repo) = init_repo();
+        let first = commit_files(&repo, &[("a.txt", "one"), ("keep.txt", "keep")], "first");
+        let second = commit_files(
+            &repo,
+            &[
+                ("a.txt", "two"),
+                ("keep.txt", "keep"),
+                ("dir/new.txt", "new"),
+            ],
+            "second",
+        );
+        let tree = Commit::read(&repo, &second).unwrap().tree;
+        checkout_tree(&repo, &tree, true).unwrap();
+        (dir, repo, first, second)
+    }
+
+    fn read(repo: &Repository, path: &str) -> String {
+        fs::read_to_string(repo.worktree().join(path)).unwrap()
+    }
+
+    #[test]
+    fn test_checkout_detaches_head() {
+        let (_dir, repo, first, _) = setup();
+        let head = checkout(&repo, &first.to_hex(), &CheckoutOptions::default()).unwrap();
+        assert_eq!(head, Head::Detached(first));
+        assert_eq!(read(&repo, "a.txt"), "one");
+        assert!(!repo.worktree().join("dir").exists());
+        assert!(Index::read(&repo).unwrap().get("dir/new.txt").is_none());
+    }
+
+    #[test]
+    fn test_checkout_branch_and_create() {
+        let (_dir, repo, first, _) = setup();
+        let options = CheckoutOptions {
+            new_branch: Some("feature".to_string()),
+            ..Default::default()
+        };
+        let head = checkout(&repo, "HEAD~1", &options).unwrap();
+        assert_eq!(head, Head::Branch("refs/heads/feature".to_string()));
+        assert_eq!(
+            resolve_ref(&repo, "refs/heads/feature").unwrap(),
+            Some(first)
+        );
+
+        checkout(&repo, "master", &CheckoutOptions::default()).unwrap();
+        assert_eq!(current_branch(&repo).unwrap().as_deref(), Some("master"));
+        assert_eq!(read(&repo, "a.txt"), "two");
+    }
+
+    #[test]
+    fn test_switch_requires_branch() {
+        let (_dir, repo, _, _) = setup();
+        let result = switch(&repo, "HEAD~1", &CheckoutOptions::default());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("A branch is expected"));
+    }
+
+    #[test]
+    fn test_checkout_refuses_to_clobber_local_changes() {
+        let (_dir, repo, first, _) = setup();
+        fs::write(repo.worktree().join("a.txt"), "local").unwrap();
+        let result = checkout(&repo, &first.to_hex(), &CheckoutOptions::default());
+        assert!(result.unwrap_err().to_string().contains("a.txt"));
+        assert_eq!(read(&repo, "a.txt"), "local");
+
+        let options = CheckoutOptions {
+            force: true,
+            ..Default::default()
+        };
+        checkout(&repo, &first.to_hex(), &options).unwrap();
+        assert_eq!(read(&repo, "a.txt"), "one");
+    }
+
+    #[test]
+    fn test_checkout_carries_unrelated_changes() {
+        let (_dir, repo, first, _) = setup();
+        fs::write(repo.worktree().join("keep.txt"), "local").unwrap();
+        checkout(&repo, &first.to_hex(), &CheckoutOptions::default()).unwrap();
+        assert_eq!(read(&repo, "keep.txt"), "local");
+    }
+
+    #[test]
+    fn test_checkout_refuses_to_clobber_untracked_file() {
+        let (_dir, repo, first, second) = setup();
+        checkout(&repo, &first.to_hex(), &CheckoutOptions::default()).unwrap();
+        fs::create_dir_all(repo.worktree().join("dir")).unwrap();
+        fs::write(repo.worktree().join("dir/new.txt"), "untracked").unwrap();
+        let result = checkout(&repo, &second.to_hex(), &CheckoutOptions::default());
+        assert!(result.unwrap_err().to_string().contains("dir/new.txt"));
+    }
+
+    #[test]
+    fn test_restore_worktree_and_staged() {
+        let (_dir, repo, first, _) = setup();
+        fs::write(repo.worktree().join("a.txt"), "local").unwrap();
+        restore(&repo, &["a.txt".to_string()], &RestoreOptions::default()).unwrap();
+        assert_eq!(read(&repo, "a.txt"), "two");
+
+        let options = RestoreOptions {
+            source: Some(first.to_hex()),
+            staged: true,
+            worktree: true,
+        };
+        restore(&repo, &["dir".to_string(), "a.txt".to_string()], &options).unwrap();
+        assert_eq!(read(&repo, "a.txt"), "one");
+        assert!(!repo.worktree().join("dir/new.txt").exists());
+        let index = Index::read(&repo).unwrap();
+        assert!(index.get("dir/new.txt").is_none());
+
+        let result = restore(&repo, &["missing".to_string()], &RestoreOptions::default());
+        assert!(result.unwrap_err().to_string().contains("did not match"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_file_modes() {
+        use std::os::unix::fs::PermissionsExt;
+        let (_dir, repo) = init_repo();
+        let hash = crate::test_utils::write_blob(&repo, b"target.txt");
+        write_file(&repo, "link", EntryMode::Symlink, &hash).unwrap();
+        let metadata = fs::symlink_metadata(repo.worktree().join("link")).unwrap();
+        assert!(metadata.file_type().is_symlink());
+        assert_eq!(
+            hash_file(&repo, "link", None).unwrap(),
+            (EntryMode::Symlink, hash.clone())
+        );
+
+        // core.filemode is off by default, so no executable bit is set
+        write_file(&repo, "run.sh", EntryMode::BlobExecutable, &hash).unwrap();
+        let metadata = fs::metadata(repo.worktree().join("run.sh")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o111, 0);
+    }
+
+    #[test]
+    fn test_checkout_refuses_unsafe_paths() {
+        use crate::pack_writer::list_objects;
+        use crate::tree::{Tree, TreeEntry};
+        let (dir, repo) = init_repo();
+        let blob = crate::test_utils::write_blob(&repo, b"evil");
+        let entry = |mode, name: &str, hash: &ObjectHash| TreeEntry {
+            mode,
+            name: name.to_string(),
+            hash: hash.clone(),
+        };
+        let inner = Tree {
+            entries: vec![entry(EntryMode::Blob, "evil", &blob)],
+        };
+        let inner = inner.write(&repo).unwrap();
+        for name in ["..", ".git", ".GIT", ".git. ", "git~1"] {
+            let tree = Tree {
+                entries: vec![
+                    entry(EntryMode::Tree, name, &inner),
+                    entry(EntryMode::Blob, "a.txt", &blob),
+                ],
+            };
+            let tree = tree.write(&repo).unwrap();
+            let error = checkout_tree(&repo, &tree, true).unwrap_err();
+            assert!(
+                format!("{:#}", error).contains("invalid path"),
+                "{:#}",
+                error
+            );
+            assert!(!dir.path().join("a.txt").exists());
+            assert!(!dir.path().join("../evil").exists());
+            assert!(!repo.gitdir().join("evil").exists());
+            assert!(Index::from_tree(&repo, &tree).is_err());
+            // The history itself can still be walked, e.g. by gc or fsck
+            let objects = list_objects(&repo, std::slice::from_ref(&tree), &[]).unwrap();
+            assert_eq!(objects.objects.len(), 3);
+        }
+        assert!(write_file(&repo, "../evil", EntryMode::Blob, &blob).is_err());
+        assert!(!dir.path().join("../evil").exists());
+    }
+}
@@ -0,0 +1,915 @@
+use crate::commits::{Commit, Signature};
+use crate::objects::{HashAlgorithm, ObjectHash};
+use crate::refs::{list_refs, resolve_ref, write_atomic};
+use crate::revision::peel_to_commit;
+use crate::shallow::is_shallow;
+use crate::tree::Tree;
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::PathBuf;
+
+const SIGNATURE: &[u8; 4] = b"CGPH";
+
+/// Size of a commit's entry in the commit data chunk, after its tree hash
+const COMMIT_DATA_SIZE: usize = 16;
+
+/// Parent position of a commit without that parent
+const PARENT_NONE: u32 = 0x7000_0000;
+
+/// Marks a second parent that indexes the extra edge list, and the last
+/// parent of an octopus merge in that list
+const EXTRA_EDGE: u32 = 0x8000_0000;
+
+/// Marks a generation offset stored in the overflow chunk
+const OFFSET_OVERFLOW: u32 = 0x8000_0000;
+
+/// Topological levels are capped to fit in 30 bits
+const GENERATION_V1_MAX: u32 = 0x3fff_ffff;
+
+/// Changed-path filter parameters git writes
+const BLOOM_VERSION: u32 = 1;
+const BLOOM_HASHES: u32 = 7;
+const BLOOM_BITS_PER_ENTRY: u32 = 10;
+const BLOOM_SEEDS: [u32; 2] = [0x293a_e76f, 0x7e64_6e2c];
+
+/// Commits changing more paths than this get a filter that matches anything
+const MAX_CHANGED_PATHS: usize = 512;
+
+/// A layer is merged into a new one at most this many times its size
+const SPLIT_SIZE_MULTIPLE: usize = 2;
+
+/// GraphCommit is what the commit-graph stores about a commit
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphCommit {
+    pub tree: ObjectHash,
+    pub parents: Vec<ObjectHash>,
+    pub commit_time: i64,
+    /// One more than the highest level of the parents; root commits are 1
+    pub topo_level: u32,
+    /// The corrected commit date when the graph has generation data v2,
+    /// the topological level otherwise
+    pub generation: u64,
+}
+
+/// Layer is one commit-graph file; a split graph is a chain of them
+#[derive(Debug)]
+struct Layer {
+    /// The trailing checksum, which names the files of a chain
+    checksum: ObjectHash,
+    data: Vec<u8>,
+    chunks: HashMap<[u8; 4], (usize, usize)>,
+    /// Number of commits in the layers below this one
+    base: u32,
+    count: u32,
+    algorithm: HashAlgorithm,
+}
+
+impl Layer {
+    fn parse(data: Vec<u8>, base: u32, algorithm: HashAlgorithm) -> Result<Layer> {
+        let hash_size = algorithm.size();
+        if data.len() < 8 + hash_size || &data[..4] != SIGNATURE {
+            bail!("Not a commit-graph file");
+        }
+        if data[4] != 1 || data[5] != algorithm.id() {
+            bail!(
+                "Unsupported commit-graph version {} or hash {}",
+                data[4],
+                data[5]
+            );
+        }
+        let chunk_count = data[6] as usize;
+        let mut chunks = HashMap::new();
+        let table = |i: usize| -> Result<([u8; 4], usize)> {
+            let entry = data
+                .get(8 + i * 12..8 + (i + 1) * 12)
+                .context("Commit-graph chunk table is truncated")?;
+            let offset = u64::from_be_bytes(entry[4..].try_into()?) as usize;
+            Ok((entry[..4].try_into()?, offset))
+        };
+        for i in 0..chunk_count {
+            let (id, start) = table(i)?;
+            let (_, end) = table(i + 1)?;
+            if start > end || end > data.len() - hash_size {
+                bail!(
+                    "Commit-graph chunk {} is out of range",
+                    String::from_utf8_lossy(&id)
+                );
+            }
+            chunks.insert(id, (start, end));
+        }
+        for id in [b"OIDF", b"OIDL", b"CDAT"] {
+            if !chunks.contains_key(id) {
+                bail!(
+                    "Commit-graph is missing the {} chunk",
+                    String::from_utf8_lossy(id)
+                );
+            }
+        }
+        let checksum = ObjectHash::from_bytes(&data[data.len() - hash_size..], algorithm)?;
+        let mut layer = Layer {
+            checksum,
+            data,
+            chunks,
+            base,
+            count: 0,
+            algorithm,
+        };
+        layer.count = layer
+            .u32_at(b"OIDF", 255)
+            .context("Commit-graph fanout is truncated")?;
+        if layer.chunk(b"OIDL").unwrap_or_default().len() != layer.count as usize * hash_size
+            || layer.chunk(b"CDAT").unwrap_or_default().len()
+                != layer.count as usize * (hash_size + COMMIT_DATA_SIZE)
+        {
+            bail!(
+                "Commit-graph chunks do not match its {} commits",
+                layer.count
+            );
+        }
+        Ok(layer)
+    }
+
+    fn chunk(&self, id: &[u8; 4]) -> Option<&[u8]> {
+        let (start, end) = self.chunks.get(id)?;
+        Some(&self.data[*start..*end])
+    }
+
+    fn u32_at(&self, id: &[u8; 4], index: usize) -> Option<u32> {
+        let bytes = self.chunk(id)?.get(index * 4..index * 4 + 4)?;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?))
+    }
+
+    fn hash(&self, index: usize) -> &[u8] {
+        let names = self.chunk(b"OIDL").expect("checked on parse");
+        let size = self.algorithm.size();
+        &names[index * size..(index + 1) * size]
+    }
+
+    fn find(&self, hash: &ObjectHash) -> Option<usize> {
+        let first = hash.as_bytes()[0] as usize;
+        let start = match first {
+            0 => 0,
+            _ => self.u32_at(b"OIDF", first - 1)? as usize,
+        };
+        let end = (self.u32_at(b"OIDF", first)? as usize).min(self.count as usize);
+        let (mut low, mut high) = (start, end);
+        while low < high {
+            let middle = (low + high) / 2;
+            match self.hash(middle).cmp(hash.as_bytes()) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Some(middle),
+            }
+        }
+        None
+    }
+
+    /// Return the changed-path filter of a commit with the number of hashes
+    /// per key, if the layer has filters
+    fn bloom_filter(&self, index: usize) -> Option<(&[u8], u32)> {
+        let data = self.chunk(b"BDAT")?;
+        let (header, filters) = data.split_at_checked(12)?;
+        let hashes = u32::from_be_bytes(header[4..8].try_into().ok()?);
+        let end = self.u32_at(b"BIDX", index)? as usize;
+        let start = match index {
+            0 => 0,
+            _ => self.u32_at(b"BIDX", index - 1)? as usize,
+        };
+        Some((filters.get(start..end)?, hashes))
+    }
+}
+
+/// CommitGraph is the `objects/info/commit-graph` file, or the chain of
+/// files of a split commit-graph, read from the repository
+///
+/// Commits are numbered from the base of the chain up; each layer only
+/// refers to commits of its own and lower layers.
+#[derive(Debug)]
+pub struct CommitGraph {
+    layers: Vec<Layer>,
+}
+
+impl CommitGraph {
+    /// Load the commit-graph of a repository, if it has one and
+    /// `core.commitGraph` is not disabled
+    ///
+    /// As in git, a shallow repository ignores it: its parents are those of
+    /// the full history.
+    pub fn open(repo: &Repository) -> Result<Option<CommitGraph>> {
+        if repo.config()?.get_bool("core.commitGraph")? == Some(false) || is_shallow(repo) {
+            return Ok(None);
+        }
+        let info = repo.commondir().join("objects").join("info");
+        let single = info.join("commit-graph");
+        if single.exists() {
+            let layer = Layer::parse(fs::read(&single)?, 0, repo.hash_algorithm())
+                .with_context(|| format!("Invalid commit-graph {}", single.display()))?;
+            return Ok(Some(CommitGraph {
+                layers: vec![layer],
+            }));
+        }
+        let graph = CommitGraph::open_chain(repo)?;
+        Ok((!graph.layers.is_empty()).then_some(graph))
+    }
+
+    /// Load the layers listed in `commit-graph-chain`, base first
+    fn open_chain(repo: &Repository) -> Result<CommitGraph> {
+        let mut graph = CommitGraph { layers: Vec::new() };
+        let Ok(chain) = fs::read_to_string(chain_path(repo)) else {
+            return Ok(graph);
+        };
+        for name in chain.lines().filter(|line| !line.is_empty()) {
+            let path = layer_path(repo, &ObjectHash::from_hex(name, repo.hash_algorithm())?);
+            let layer = Layer::parse(fs::read(&path)?, graph.len() as u32, repo.hash_algorithm())
+                .with_context(|| format!("Invalid commit-graph {}", path.display()))?;
+            let bases = layer.chunk(b"BASE").unwrap_or_default();
+            let expected = graph
+                .layers
+                .iter()
+                .flat_map(|l| l.checksum.as_bytes().to_vec())
+                .collect::<Vec<_>>();
+            if bases != expected {
+                bail!("Commit-graph chain does not match {}", path.display());
+            }
+            graph.layers.push(layer);
+        }
+        Ok(graph)
+    }
+
+    /// Number of commits in the graph
+    pub fn len(&self) -> usize {
+        self.layers
+            .last()
+            .map_or(0, |l| (l.base + l.count) as usize)
+    }
+
+    /// Return true if the graph holds no commits
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return true if the graph has a commit
+    pub fn contains(&self, hash: &ObjectHash) -> bool {
+        self.position(hash).is_some()
+    }
+
+    fn position(&self, hash: &ObjectHash) -> Option<u32> {
+        self.layers
+            .iter()
+            .find_map(|layer| Some(layer.base + layer.find(hash)? as u32))
+    }
+
+    fn layer(&self, position: u32) -> Result<(&Layer, usize)> {
+        let layer = self
+            .layers
+            .iter()
+            .find(|l| position >= l.base && position < l.base + l.count)
+            .with_context(|| format!("Commit-graph position {} is out of range", position))?;
+        Ok((layer, (position - layer.base) as usize))
+    }
+
+    fn hash_at(&self, position: u32) -> Result<ObjectHash> {
+        let (layer, index) = self.layer(position)?;
+        ObjectHash::from_bytes(layer.hash(index), layer.algorithm)
+    }
+
+    /// Look up a commit in the graph
+    pub fn lookup(&self, hash: &ObjectHash) -> Result<Option<GraphCommit>> {
+        self.position(hash)
+            .map(|position| self.commit_at(position))
+            .transpose()
+    }
+
+    fn commit_at(&self, position: u32) -> Result<GraphCommit> {
+        let (layer, index) = self.layer(position)?;
+        let cdat = layer.chunk(b"CDAT").expect("checked on parse");
+        let hash_size = layer.algorithm.size();
+        let size = hash_size + COMMIT_DATA_SIZE;
+        let entry = &cdat[index * size..(index + 1) * size];
+        let word = |i: usize| u32::from_be_bytes(entry[i..i + 4].try_into().unwrap());
+        let mut parents = Vec::new();
+        if word(hash_size) != PARENT_NONE {
+            parents.push(self.hash_at(word(hash_size))?);
+        }
+        match word(hash_size + 4) {
+            PARENT_NONE => {}
+            second if second & EXTRA_EDGE != 0 => {
+                let mut edge = (second & !EXTRA_EDGE) as usize;
+                loop {
+                    let parent = layer
+                        .u32_at(b"EDGE", edge)
+                        .context("Commit-graph extra edge is out of range")?;
+                    parents.push(self.hash_at(parent & !EXTRA_EDGE)?);
+                    if parent & EXTRA_EDGE != 0 {
+                        break;
+                    }
+                    edge += 1;
+                }
+            }
+            second => parents.push(self.hash_at(second)?),
+        }
+        let topo_level = word(hash_size + 8) >> 2;
+        let commit_time = ((word(hash_size + 8) as i64 & 3) << 32) | word(hash_size + 12) as i64;
+        // Generation data is only used when every layer has it
+        let generation = match self.layers.iter().all(|l| l.chunk(b"GDA2").is_some()) {
+            true => {
+                let offset = layer
+                    .u32_at(b"GDA2", index)
+                    .context("Generation data is truncated")?;
+                let offset = match offset & OFFSET_OVERFLOW {
+                    0 => offset as u64,
+                    _ => {
+                        let i = (offset & !OFFSET_OVERFLOW) as usize * 8;
+                        let bytes = layer
+                            .chunk(b"GDO2")
+                            .and_then(|c| c.get(i..i + 8))
+                            .context("Generation data overflow is out of range")?;
+                        u64::from_be_bytes(bytes.try_into()?)
+                    }
+                };
+                commit_time as u64 + offset
+            }
+            false => topo_level as u64,
+        };
+        Ok(GraphCommit {
+            tree: ObjectHash::from_bytes(&entry[..hash_size], layer.algorithm)?,
+            parents,
+            commit_time,
+            topo_level,
+            generation,
+        })
+    }
+
+    /// Ask the changed-path filter whether a commit may change `path`
+    /// compared to its first parent
+    ///
+    /// Returns `Some(false)` when it certainly does not, `Some(true)` when it
+    /// may, and `None` when the graph has no filter for the commit.
+    pub fn maybe_changed(&self, hash: &ObjectHash, path: &str) -> Option<bool> {
+        let (layer, index) = self.layer(self.position(hash)?).ok()?;
+        let (filter, hashes) = layer.bloom_filter(index)?;
+        if filter.is_empty() {
+            return None;
+        }
+        let path = path.trim_end_matches('/');
+        let mut key = path;
+        // The leading directories of a changed path are in the filter too
+        loop {
+            let found = bloom_positions(key, filter.len() * 8, hashes)
+                .all(|bit| filter[bit / 8] & (1 << (bit % 8)) != 0);
+            if !found {
+                return Some(false);
+            }
+            match key.rfind('/') {
+                Some(slash) => key = &key[..slash],
+                None => return Some(true),
+            }
+        }
+    }
+}
+
+/// CommitGraphOptions controls how `write_commit_graph` writes the graph
+#[derive(Debug, Clone, Default)]
+pub struct CommitGraphOptions {
+    /// Add a layer to the commit-graph chain instead of rewriting one file
+    pub split: bool,
+    /// Compute changed-path Bloom filters
+    pub changed_paths: bool,
+}
+
+/// CommitGraphReport describes the file `write_commit_graph` wrote
+#[derive(Debug, Default)]
+pub struct CommitGraphReport {
+    /// Commits in the new file, none if the graph was up to date
+    pub commits: usize,
+    pub path: Option<PathBuf>,
+    /// Layers in the chain once written, 1 for a single file
+    pub layers: usize,
+}
+
+/// A commit to write with the values computed for it
+struct NewCommit {
+    hash: ObjectHash,
+    tree: ObjectHash,
+    parents: Vec<ObjectHash>,
+    commit_time: i64,
+    topo_level: u32,
+    corrected_date: u64,
+}
+
+/// Write the commit-graph of the commits reachable from the references,
+/// like `git commit-graph write --reachable`
+///
+/// With `split`, only the commits missing from the chain are written to a
+/// new layer, which absorbs the layers above it that are not at least
+/// twice its size. A shallow repository gets no commit-graph.
+pub fn write_commit_graph(
+    repo: &Repository,
+    options: &CommitGraphOptions,
+) -> Result<CommitGraphReport> {
+    if is_shallow(repo) {
+        return Ok(CommitGraphReport::default());
+    }
+    let mut tips = Vec::new();
+    tips.extend(resolve_ref(repo, "HEAD")?);
+    tips.extend(list_refs(repo, "refs/")?.into_values());
+    let tips = tips
+        .iter()
+        .filter_map(|hash| peel_to_commit(repo, hash).ok())
+        .collect::<Vec<_>>();
+
+    let mut existing = match options.split {
+        true => CommitGraph::open_chain(repo)?,
+        false => CommitGraph { layers: Vec::new() },
+    };
+    let mut commits = HashMap::new();
+    let mut stack = tips;
+    while let Some(hash) = stack.pop() {
+        if commits.contains_key(&hash) || existing.contains(&hash) {
+            continue;
+        }
+        let commit = Commit::read(repo, &hash)?;
+        let time = Signature::parse(&commit.committer)
+            .map(|s| s.time)
+            .unwrap_or_default();
+        stack.extend(commit.parents.iter().cloned());
+        commits.insert(hash, (commit.tree, commit.parents, time));
+    }
+    if commits.is_empty() && options.split {
+        return Ok(CommitGraphReport {
+            layers: existing.layers.len(),
+            ..Default::default()
+        });
+    }
+
+    let mut merged = Vec::new();
+    while let Some(top) = existing.layers.last() {
+        if top.count as usize > SPLIT_SIZE_MULTIPLE * commits.len() {
+            break;
+        }
+        for position in top.base..top.base + top.count {
+            let commit = existing.commit_at(position)?;
+            let value = (commit.tree, commit.parents, commit.commit_time);
+            commits.insert(existing.hash_at(position)?, value);
+        }
+        merged.push(existing.layers.pop().expect("layer exists").checksum);
+    }
+
+    let mut sorted = compute_generations(&existing, commits)?;
+    sorted.sort_by(|a, b| a.hash.cmp(&b.hash));
+    let data = serialize(repo, &existing, &sorted, options.changed_paths)?;
+    let algorithm = repo.hash_algorithm();
+    let checksum = ObjectHash::from_bytes(&data[data.len() - algorithm.size()..], algorithm)?;
+
+    let info = repo.commondir().join("objects").join("info");
+    let single = info.join("commit-graph");
+    let path = match options.split {
+        true => {
+            let path = layer_path(repo, &checksum);
+            write_atomic(&path, &data)?;
+            let mut chain = existing
+                .layers
+                .iter()
+                .map(|l| format!("{}\n", l.checksum))
+                .collect::<String>();
+            chain.push_str(&format!("{}\n", checksum));
+            write_atomic(&chain_path(repo), chain.as_bytes())?;
+            for layer in &merged {
+                let old = layer_path(repo, layer);
+                if old != path {
+                    fs::remove_file(old).ok();
+                }
+            }
+            if single.exists() {
+                fs::remove_file(&single)?;
+            }
+            path
+        }
+        false => {
+            write_atomic(&single, &data)?;
+            fs::remove_dir_all(info.join("commit-graphs")).ok();
+            single
+        }
+    };
+    Ok(CommitGraphReport {
+        commits: sorted.len(),
+        path: Some(path),
+        layers: existing.layers.len() + 1,
+    })
+}
+
+/// Compute the topological levels and corrected commit dates of the new
+/// commits, parents first
+fn compute_generations(
+    base: &CommitGraph,
+    commits: HashMap<ObjectHash, (ObjectHash, Vec<ObjectHash>, i64)>,
+) -> Result<Vec<NewCommit>> {
+    let mut done: HashMap<ObjectHash, (u32, u64)> = HashMap::new();
+    let mut result = Vec::new();
+    let mut hashes = commits.keys().cloned().collect::<Vec<_>>();
+    hashes.sort();
+    for start in hashes {
+        let mut stack = vec![start];
+        while let Some(hash) = stack.last().cloned() {
+            if done.contains_key(&hash) {
+                stack.pop();
+                continue;
+            }
+            let (_, parents, time) = &commits[&hash];
+            let (parents, time) = (parents.clone(), *time);
+            let mut level = 0;
+            let mut corrected = time.max(0) as u64;
+            let mut pending = false;
+            for parent in &parents {
+                if let Some((parent_level, parent_date)) = done.get(parent) {
+                    level = level.max(*parent_level);
+                    corrected = corrected.max(parent_date + 1);
+                } else if let Some(commit) = base.lookup(parent)? {
+                    level = level.max(commit.topo_level);
+                    corrected = corrected.max(commit.generation + 1);
+                } else if commits.contains_key(parent) {
+                    stack.push(parent.clone());
+                    pending = true;
+                } else {
+                    bail!(
+                        "Commit {} has a parent {} that is not in the graph",
+                        hash,
+                        parent
+                    );
+                }
+            }
+            if pending {
+                continue;
+            }
+            let level = (level + 1).min(GENERATION_V1_MAX);
+            done.insert(hash.clone(), (level, corrected));
+            stack.pop();
+            let (tree, parents, time) = &commits[&hash];
+            result.push(NewCommit {
+                tree: tree.clone(),
+                parents: parents.clone(),
+                commit_time: *time,
+                hash,
+                topo_level: level,
+                corrected_date: corrected,
+            });
+        }
+    }
+    Ok(result)
+}
+
+/// Serialize a commit-graph file on top of the `base` layers
+fn serialize(
+    repo: &Repository,
+    base: &CommitGraph,
+    commits: &[NewCommit],
+    changed_paths: bool,
+) -> Result<Vec<u8>> {
+    let base_count = base.len() as u32;
+    let positions = commits
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (c.hash.clone(), base_count + i as u32))
+        .collect::<HashMap<_, _>>();
+    let position = |hash: &ObjectHash| -> Result<u32> {
+        positions
+            .get(hash)
+            .copied()
+            .or_else(|| base.position(hash))
+            .with_context(|| format!("Commit {} is not in the graph", hash))
+    };
+
+    let mut chunks: Vec<([u8; 4], Vec<u8>)> = Vec::new();
+    let mut fanout = vec![0u32; 256];
+    for commit in commits {
+        fanout[commit.hash.as_bytes()[0] as usize] += 1;
+    }
+    let mut oidf = Vec::new();
+    let mut total = 0;
+    for count in fanout {
+        total += count;
+        oidf.extend(total.to_be_bytes());
+    }
+    chunks.push((*b"OIDF", oidf));
+    let oidl = commits
+        .iter()
+        .flat_map(|c| c.hash.as_bytes().to_vec())
+        .collect();
+    chunks.push((*b"OIDL", oidl));
+
+    let mut cdat = Vec::new();
+    let mut edges = Vec::new();
+    for commit in commits {
+        cdat.extend(commit.tree.as_bytes());
+        let parent1 = match commit.parents.first() {
+            Some(parent) => position(parent)?,
+            None => PARENT_NONE,
+        };
+        let parent2 = match commit.parents.len() {
+            0 | 1 => PARENT_NONE,
+            2 => position(&commit.parents[1])?,
+            _ => {
+                let start = (edges.len() / 4) as u32 | EXTRA_EDGE;
+                let last = commit.parents.len() - 1;
+                for (i, parent) in commit.parents.iter().enumerate().skip(1) {
+                    let mut value = position(parent)?;
+                    if i == last {
+                        value |= EXTRA_EDGE;
+                    }
+                    edges.extend(value.to_be_bytes());
+                }
+                start
+            }
+        };
+        let time = commit.commit_time.max(0) as u64;
+        cdat.extend(parent1.to_be_bytes());
+        cdat.extend(parent2.to_be_bytes());
+        cdat.extend(((commit.topo_level << 2) | (time >> 32) as u32 & 3).to_be_bytes());
+        cdat.extend((time as u32).to_be_bytes());
+    }
+    chunks.push((*b"CDAT", cdat));
+
+    // Generation data is left out when a lower layer lacks it
+    if base.layers.iter().all(|l| l.chunk(b"GDA2").is_some()) {
+        let mut gda2 = Vec::new();
+        let mut gdo2 = Vec::new();
+        for commit in commits {
+            let offset = commit.corrected_date - commit.commit_time.max(0) as u64;
+            match offset > (OFFSET_OVERFLOW - 1) as u64 {
+                true => {
+                    gda2.extend(((gdo2.len() / 8) as u32 | OFFSET_OVERFLOW).to_be_bytes());
+                    gdo2.extend(offset.to_be_bytes());
+                }
+                false => gda2.extend((offset as u32).to_be_bytes()),
+            }
+        }
+        chunks.push((*b"GDA2", gda2));
+        if !gdo2.is_empty() {
+            chunks.push((*b"GDO2", gdo2));
+        }
+    }
+    if !edges.is_empty() {
+        chunks.push((*b"EDGE", edges));
+    }
+
+    if changed_paths {
+        let mut bidx = Vec::new();
+        let mut bdat = Vec::new();
+        for value in [BLOOM_VERSION, BLOOM_HASHES, BLOOM_BITS_PER_ENTRY] {
+            bdat.extend(value.to_be_bytes());
+        }
+        for commit in commits {
+            let parent_tree = match commit.parents.first() {
+                Some(parent) => Some(match base.lookup(parent)? {
+                    Some(parent) => parent.tree,
+                    None => Commit::read(repo, parent)?.tree,
+                }),
+                None => None,
+            };
+            let mut paths = Vec::new();
+            diff_paths(
+                repo,
+                parent_tree.as_ref(),
+                Some(&commit.tree),
+                "",
+                &mut paths,
+            )?;
+            bdat.extend(bloom_filter(&paths));
+            bidx.extend(((bdat.len() - 12) as u32).to_be_bytes());
+        }
+        chunks.push((*b"BIDX", bidx));
+        chunks.push((*b"BDAT", bdat));
+    }
+    if !base.layers.is_empty() {
+        let bases = base
+            .layers
+            .iter()
+            .flat_map(|l| l.checksum.as_bytes().to_vec())
+            .collect();
+        chunks.push((*b"BASE", bases));
+    }
+
+    let mut data = Vec::new();
+    data.extend(SIGNATURE);
+    data.extend([
+        1,
+        repo.hash_algorithm().id(),
+        chunks.len() as u8,
+        base.layers.len() as u8,
+    ]);
+    let mut offset = (8 + (chunks.len() + 1) * 12) as u64;
+    for (id, chunk) in &chunks {
+        data.extend(id);
+        data.extend(offset.to_be_bytes());
+        offset += chunk.len() as u64;
+    }
+    data.extend([0; 4]);
+    data.extend(offset.to_be_bytes());
+    for (_, chunk) in &chunks {
+        data.extend(chunk);
+    }
+    let checksum = repo.hash_algorithm().digest(&data);
+    data.extend(checksum.as_bytes());
+    Ok(data)
+}
+
+/// Collect the paths of the files that differ between two trees
+fn diff_paths(
+    repo: &Repository,
+    old: Option<&ObjectHash>,
+    new: Option<&ObjectHash>,
+    prefix: &str,
+    paths: &mut Vec<String>,
+) -> Result<()> {
+    let read = |tree: Option<&ObjectHash>| -> Result<BTreeSet<_>> {
+        Ok(match tree {
+            Some(tree) => Tree::read(repo, tree)?
+                .entries
+                .into_iter()
+                .map(|e| (e.name, e.mode.is_tree(), e.mode.bits(), e.hash))
+                .collect(),
+            None => BTreeSet::new(),
+        })
+    };
+    let (old, new) = (read(old)?, read(new)?);
+    let names = old
+        .iter()
+        .chain(&new)
+        .map(|(name, ..)| name.clone())
+        .collect::<BTreeSet<_>>();
+    let find = |entries: &BTreeSet<(String, bool, u32, ObjectHash)>, name: &str| {
+        entries
+            .iter()
+            .find(|(n, ..)| n == name)
+            .map(|(_, is_tree, mode, hash)| (*is_tree, *mode, hash.clone()))
+    };
+    for name in names {
+        let path = format!("{}{}", prefix, name);
+        let (old, new) = (find(&old, &name), find(&new, &name));
+        if old == new {
+            continue;
+        }
+        let subtree = |entry: &Option<(bool, u32, ObjectHash)>| match entry {
+            Some((true, _, hash)) => Some(hash.clone()),
+            _ => None,
+        };
+        let (old_tree, new_tree) = (subtree(&old), subtree(&new));
+        if old_tree.is_some() || new_tree.is_some() {
+            let prefix = format!("{}/", path);
+            diff_paths(repo, old_tree.as_ref(), new_tree.as_ref(), &prefix, paths)?;
+        }
+        let is_file =
+            |entry: &Option<(bool, u32, ObjectHash)>| entry.as_ref().is_some_and(|e| !e.0);
+        if is_file(&old) || is_file(&new) {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Build the changed-path filter of a commit from the files it changes
+fn bloom_filter(paths: &[String]) -> Vec<u8> {
+    if paths.len() > MAX_CHANGED_PATHS {
+        return vec![0xff];
+    }
+    let mut keys = BTreeSet::new();
+    for path in paths {
+        let mut key = path.as_str();
+        keys.insert(key);
+        while let Some(slash) = key.rfind('/') {
+            key = &key[..slash];
+            keys.insert(key);
+        }
+    }
+    let len = (keys.len() * BLOOM_BITS_PER_ENTRY as usize)
+        .div_ceil(8)
+        .max(1);
+    let mut filter = vec![0u8; len];
+    for key in keys {
+        for bit in bloom_positions(key, len * 8, BLOOM_HASHES) {
+            filter[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+    filter
+}
+
+/// Bits of a filter of `bits` bits that a key sets
+fn bloom_positions(key: &str, bits: usize, hashes: u32) -> impl Iterator<Item = usize> {
+    let first = murmur3(BLOOM_SEEDS[0], key.as_bytes());
+    let step = murmur3(BLOOM_SEEDS[1], key.as_bytes());
+    (0..hashes).map(move |i| (first.wrapping_add(i.wrapping_mul(step)) as usize) % bits)
+}
+
+/// The 32-bit murmur3 hash of version 1 filters, which reads bytes as
+/// signed characters like git does
+fn murmur3(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let byte = |b: u8| b as i8 as i32 as u32;
+    let mut hash = seed;
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k =
+            byte(chunk[0]) | byte(chunk[1]) << 8 | byte(chunk[2]) << 16 | byte(chunk[3]) << 24;
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        hash ^= k;
+        hash = hash
+            .rotate_left(13)
+            .wrapping_mul(5)
+            .wrapping_add(0xe654_6b64);
+    }
+    if !tail.is_empty() {
+        let mut k = 0u32;
+        for (i, b) in tail.iter().enumerate().rev() {
+            k ^= byte(*b) << (8 * i);
+        }
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        hash ^= k;
+    }
+    hash ^= data.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^ (hash >> 16)
+}
+
+fn chain_path(repo: &Repository) -> PathBuf {
+    let dir = repo
+        .commondir()
+        .join("objects")
+        .join("info")
+        .join("commit-graphs");
+    dir.join("commit-graph-chain")
+}
+
+fn layer_path(repo: &Repository, checksum: &ObjectHash) -> PathBuf {
+    let dir = repo
+        .commondir()
+        .join("objects")
+        .join("info")
+        .join("commit-graphs");
+    dir.join(format!("graph-{}.graph", checksum))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::refs::update_ref;
+    use crate::test_utils::{commit_files, init_repo, write_commit};
+
+    #[test]
+    fn test_murmur3() {
+        assert_eq!(murmur3(0, b""), 0);
+        assert_eq!(murmur3(0, b"Hello world!"), 0x627b_0c2c);
+        assert_eq!(
+            murmur3(0, b"The quick brown fox jumps over the lazy dog"),
+            0x2e4f_f723
+        );
+    }
+
+    #[test]
+    fn test_write_and_read_split_graph() {
+        let (_dir, repo) = init_repo();
+        let first = commit_files(&repo, &[("a/x.txt", "1\n"), ("b.txt", "1\n")], "first");
+        let second = commit_files(&repo, &[("a/x.txt", "2\n"), ("b.txt", "1\n")], "second");
+        let options = CommitGraphOptions {
+            split: true,
+            changed_paths: true,
+        };
+        let report = write_commit_graph(&repo, &options).unwrap();
+        assert_eq!((report.commits, report.layers), (2, 1));
+
+        // The layer of two commits is merged into the new one of five
+        let side = write_commit(&repo, &[("c.txt", "1\n")], &[], "side");
+        let parents = [second.clone(), first.clone(), side.clone()];
+        let merge = write_commit(&repo, &[("a/x.txt", "2\n")], &parents, "merge");
+        update_ref(&repo, "refs/heads/master", &merge).unwrap();
+        for _ in 0..3 {
+            commit_files(&repo, &[("a/x.txt", "2\n"), ("d.txt", "1\n")], "more");
+        }
+        let report = write_commit_graph(&repo, &options).unwrap();
+        assert_eq!((report.commits, report.layers), (7, 1));
+        let last = commit_files(&repo, &[("e.txt", "1\n")], "last");
+        let report = write_commit_graph(&repo, &options).unwrap();
+        assert_eq!((report.commits, report.layers), (1, 2));
+
+        let graph = CommitGraph::open(&repo).unwrap().unwrap();
+        assert_eq!(graph.len(), 8);
+        assert_eq!(graph.lookup(&last).unwrap().unwrap().topo_level, 7);
+        let stored = graph.lookup(&merge).unwrap().unwrap();
+        let commit = Commit::read(&repo, &merge).unwrap();
+        assert_eq!(stored.parents, commit.parents);
+        assert_eq!(stored.tree, commit.tree);
+        assert_eq!(stored.topo_level, 3);
+        assert_eq!(graph.lookup(&first).unwrap().unwrap().topo_level, 1);
+
+        assert_eq!(graph.maybe_changed(&second, "a/x.txt"), Some(true));
+        assert_eq!(graph.maybe_changed(&second, "a"), Some(true));
+        assert_eq!(graph.maybe_changed(&second, "b.txt"), Some(false));
+        assert_eq!(graph.maybe_changed(&first, "b.txt"), Some(true));
+    }
+}
@@ -1,78 +1,328 @@
-// use itertools::Itertools;
-// use std::collections::HashMap;
-// use anyhow::Result;
-// pub struct Commit {
-//     pub tree: String,
-//     pub parents: Vec<String>,
-//     pub author: String,
-//     pub committer: String,
-//     pub gpgsig: Option<String>,
-//     pub message: String,
-// }
-//
-// /// Parse a list of key value pairs from a list of strings
-// ///
-// /// Lines is a list of string, each line contain a key space value
-// /// if the next line is empty, it means the value is multi-line
-// /// and we need to append the next line to the value until we find a new line
-// pub fn parse_key_values(lines: &[&str]) -> Result<HashMap<String, String>> {
-//     let mut map = HashMap::new();
-//     let mut remaining = lines;
-//     while !remaining.is_empty() {
-//         remaining = parse_key_value(remaining, &mut map)?;
-//     }
-//     Ok(map)
-// }
-//
-// /// Parse a single key value pair and return the remaining lines
-// ///
-// /// Lines is a list of string, each line contain a key space value
-// /// if the next line is empty, it means the value is multi-line
-// /// and we need to append the next line to the value until we find a new line
-// fn parse_key_value<'a>(
-//     lines: &'a [&str],
-//     map: &mut HashMap<String, String>,
-// ) -> Result<&'a [&'a str]> {
-//     let mut lines_consumed = 1;
-//     let (key, value) = lines
-//         .iter()
-//         .take(1)
-//         .map(|line| line.split_whitespace().collect_tuple::<(&str, &str)>())
-//         .collect_tuple::<(&str, &str)>()
-//         .ok_or(anyhow::anyhow!("No key value pair found"))?;
-//
-//     let mut value_str = value.to_string();
-//
-//     // TODO: Fix this logic - it's not correctly handling multiline values
-//     let mut remaining = &lines[1..];
-//     while !remaining.is_empty() && remaining[0].is_empty() {
-//         if remaining.len() > 1 {
-//             value_str.push_str(&remaining[1]);
-//             lines_consumed += 2;
-//             remaining = &remaining[2..];
-//         } else {
-//             break;
-//         }
-//     }
-//
-//     map.insert(key.to_string(), value_str);
-//     Ok(&lines[lines_consumed..])
-// }
-//
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//
-//     #[test]
-//     fn test_parse_key_values() {
-//         let text = "
-// key value
-//
-// line2
-// ";
-//         let lines = text.lines().map(|s| s.to_string()).collect::<Vec<_>>();
-//         let map = parse_key_values(&lines).unwrap();
-//         assert_eq!(map.get("key"), Some(&"value".to_string()));
-//         assert_eq!(map.get("line2"), Some(&"line2".to_string()));
-//     }
-// }
+use crate::gitconfig::GitConfig;
+use crate::objects::{read_object, store_object, HashAlgorithm, Object, ObjectHash, ObjectType};
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::fmt::Display;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Commit represents a git commit object
+#[derive(Debug, Clone, PartialEq)]
+pub struct Commit {
+    pub tree: ObjectHash,
+    pub parents: Vec<ObjectHash>,
+    pub author: String,
+    pub committer: String,
+    pub gpgsig: Option<String>,
+    /// Headers legit does not interpret (e.g. `encoding`, `mergetag`), kept
+    /// in order so that re-serializing a commit reproduces the same object.
+    pub extra_headers: Vec<(String, String)>,
+    pub message: String,
+}
+
+impl Commit {
+    /// Create a commit authored and committed by the current user
+    pub fn new(
+        repo: &Repository,
+        tree: ObjectHash,
+        parents: Vec<ObjectHash>,
+        message: &str,
+    ) -> Result<Commit> {
+        Ok(Commit {
+            tree,
+            parents,
+            author: Signature::identity(repo, "AUTHOR")?.to_string(),
+            committer: Signature::identity(repo, "COMMITTER")?.to_string(),
+            gpgsig: None,
+            extra_headers: Vec::new(),
+            message: message.to_string(),
+        })
+    }
+
+    /// Parse the data of a commit object, whose hashes are of `algorithm`
+    pub fn parse(data: &[u8], algorithm: HashAlgorithm) -> Result<Commit> {
+        let text = std::str::from_utf8(data).context("Commit is not valid UTF-8")?;
+        let (headers, message) = split_headers(text);
+        let headers = parse_key_values(headers)?;
+
+        let mut tree = None;
+        let mut parents = Vec::new();
+        let mut author = None;
+        let mut committer = None;
+        let mut gpgsig = None;
+        let mut extra_headers = Vec::new();
+        for (key, value) in headers {
+            match key.as_str() {
+                "tree" => {
+                    tree =
+                        Some(ObjectHash::from_hex(&value, algorithm).context("Invalid tree hash")?)
+                }
+                "parent" => parents
+                    .push(ObjectHash::from_hex(&value, algorithm).context("Invalid parent hash")?),
+                "author" => author = Some(value),
+                "committer" => committer = Some(value),
+                "gpgsig" => gpgsig = Some(value),
+                _ => extra_headers.push((key, value)),
+            }
+        }
+
+        Ok(Commit {
+            tree: tree.ok_or_else(|| anyhow::anyhow!("Commit is missing a tree"))?,
+            parents,
+            author: author.ok_or_else(|| anyhow::anyhow!("Commit is missing an author"))?,
+            committer: committer.ok_or_else(|| anyhow::anyhow!("Commit is missing a committer"))?,
+            gpgsig,
+            extra_headers,
+            message: message.to_string(),
+        })
+    }
+
+    /// Serialize the commit into the data of a commit object
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut headers = vec![("tree".to_string(), self.tree.to_hex())];
+        for parent in &self.parents {
+            headers.push(("parent".to_string(), parent.to_hex()));
+        }
+        headers.push(("author".to_string(), self.author.clone()));
+        headers.push(("committer".to_string(), self.committer.clone()));
+        if let Some(gpgsig) = &self.gpgsig {
+            headers.push(("gpgsig".to_string(), gpgsig.clone()));
+        }
+        headers.extend(self.extra_headers.iter().cloned());
+        format_key_values(&headers, &self.message).into_bytes()
+    }
+
+    /// Read and parse a commit from the repository
+    pub fn read(repo: &Repository, hash: &ObjectHash) -> Result<Commit> {
+        let object = read_object(repo, hash)?;
+        if object.object_type != ObjectType::Commit {
+            bail!("Object {} is a {}, not a commit", hash, object.object_type);
+        }
+        Commit::parse(&object.data, repo.hash_algorithm())
+            .with_context(|| format!("Failed to parse commit {}", hash))
+    }
+
+    /// Write the commit to the repository and return its hash
+    pub fn write(&self, repo: &Repository) -> Result<ObjectHash> {
+        let object = Object::new(repo.hash_algorithm(), ObjectType::Commit, self.serialize())?;
+        store_object(&object, repo)
+    }
+
+    /// Return the first line of the commit message
+    pub fn summary(&self) -> &str {
+        self.message.lines().next().unwrap_or_default()
+    }
+}
+
+/// Signature is the identity and timestamp found in author and committer lines
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    /// Seconds since the unix epoch
+    pub time: i64,
+    /// Timezone offset in git's `+HHMM` format
+    pub offset: String,
+}
+
+impl Signature {
+    /// Create a signature for the current time
+    pub fn now(name: &str, email: &str) -> Signature {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        Signature {
+            name: name.to_string(),
+            email: email.to_string(),
+            time,
+            offset: "+0000".to_string(),
+        }
+    }
+
+    /// Return the identity of the current user for the `AUTHOR` or `COMMITTER` role
+    ///
+    /// The `GIT_<role>_NAME`, `GIT_<role>_EMAIL` and `GIT_<role>_DATE`
+    /// environment variables take precedence over `user.name` and
+    /// `user.email` from the repository and global config. Dates use git's
+    /// raw `<seconds> <offset>` format, optionally prefixed by `@`.
+    pub fn identity(repo: &Repository, role: &str) -> Result<Signature> {
+        let config = repo.config()?;
+        let global = GitConfig::global()?;
+        let lookup = |variable: &str, key: &str| {
+            std::env::var(format!("GIT_{}_{}", role, variable))
+                .ok()
+                .or_else(|| {
+                    config
+                        .get(key)
+                        .or_else(|| global.get(key))
+                        .map(str::to_string)
+                })
+        };
+        let (Some(name), Some(email)) =
+            (lookup("NAME", "user.name"), lookup("EMAIL", "user.email"))
+        else {
+            bail!(
+                "{} identity unknown: set user.name and user.email in your config",
+                role.to_lowercase()
+            );
+        };
+
+        let mut signature = Signature::now(&name, &email);
+        if let Ok(date) = std::env::var(format!("GIT_{}_DATE", role)) {
+            let date = Signature::parse(&format!("<> {}", date.trim_start_matches('@')))
+                .with_context(|| format!("Invalid date in GIT_{}_DATE", role))?;
+            signature.time = date.time;
+            signature.offset = date.offset;
+        }
+        Ok(signature)
+    }
+
+    /// Parse a signature in the form `Name <email> 1234567890 +0000`
+    pub fn parse(line: &str) -> Result<Signature> {
+        let (name, rest) = line
+            .split_once('<')
+            .ok_or_else(|| anyhow::anyhow!("Invalid signature: missing email"))?;
+        let (email, rest) = rest
+            .split_once('>')
+            .ok_or_else(|| anyhow::anyhow!("Invalid signature: unterminated email"))?;
+        let mut parts = rest.split_whitespace();
+        let time = parts
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Invalid signature: missing timestamp"))?
+            .parse::<i64>()
+            .context("Invalid signature timestamp")?;
+        let offset = parts.next().unwrap_or("+0000").to_string();
+        Ok(Signature {
+            name: name.trim().to_string(),
+            email: email.to_string(),
+            time,
+            offset,
+        })
+    }
+}
+
+impl Display for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} <{}> {} {}",
+            self.name, self.email, self.time, self.offset
+        )
+    }
+}
+
+/// Split an object made of headers and a message at the first blank line
+pub(crate) fn split_headers(text: &str) -> (&str, &str) {
+    match text.split_once("\n\n") {
+        Some((headers, message)) => (headers, message),
+        None => (text.trim_end_matches('\n'), ""),
+    }
+}
+
+/// Parse a list of key value pairs from the header section of an object
+///
+/// Each line contains a key, a space and a value. Lines starting with a space
+/// continue the value of the previous line, which is how git stores
+/// multi-line values such as `gpgsig`. The order of the pairs is kept since
+/// keys like `parent` can repeat.
+pub fn parse_key_values(text: &str) -> Result<Vec<(String, String)>> {
+    let mut pairs: Vec<(String, String)> = Vec::new();
+    for line in text.lines() {
+        if let Some(continuation) = line.strip_prefix(' ') {
+            let (_, value) = pairs
+                .last_mut()
+                .ok_or_else(|| anyhow::anyhow!("Continuation line without a key"))?;
+            value.push('\n');
+            value.push_str(continuation);
+            continue;
+        }
+        let (key, value) = line
+            .split_once(' ')
+            .ok_or_else(|| anyhow::anyhow!("No key value pair found in line: {}", line))?;
+        pairs.push((key.to_string(), value.to_string()));
+    }
+    Ok(pairs)
+}
+
+/// Format key value pairs and a message the way `parse_key_values` reads them
+pub(crate) fn format_key_values(pairs: &[(String, String)], message: &str) -> String {
+    let mut out = String::new();
+    for (key, value) in pairs {
+        out.push_str(key);
+        out.push(' ');
+        out.push_str(&value.replace('\n', "\n "));
+        out.push('\n');
+    }
+    out.push('\n');
+    out.push_str(message);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMIT: &str = "tree 29ff16c9c14e2652b22f8b78bb08a5a07930c147
+parent 206941306e8a8af65b66eaaaea388a7ae24d49a0
+author Thibault Polge <thibault@thb.lt> 1527025023 +0200
+committer Thibault Polge <thibault@thb.lt> 1527025044 +0200
+gpgsig -----BEGIN PGP SIGNATURE-----
+\x20
+ iQIzBAABCAAdFiEExwXquOM8bWb4Q2zVGxM2FxoLkGQFAlsEjZQACgkQGxM2FxoL
+ -----END PGP SIGNATURE-----
+
+Create first draft
+";
+
+    #[test]
+    fn test_parse_key_values() {
+        let pairs = parse_key_values("key value\nother multi\n line\nkey again").unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                ("key".to_string(), "value".to_string()),
+                ("other".to_string(), "multi\nline".to_string()),
+                ("key".to_string(), "again".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_commit() {
+        let commit = Commit::parse(COMMIT.as_bytes(), HashAlgorithm::Sha1).unwrap();
+        assert_eq!(
+            commit.tree.to_hex(),
+            "29ff16c9c14e2652b22f8b78bb08a5a07930c147"
+        );
+        assert_eq!(commit.parents.len(), 1);
+        assert!(commit
+            .gpgsig
+            .unwrap()
+            .ends_with("-----END PGP SIGNATURE-----"));
+        assert_eq!(commit.message, "Create first draft\n");
+    }
+
+    #[test]
+    fn test_commit_roundtrip() {
+        let commit = Commit::parse(COMMIT.as_bytes(), HashAlgorithm::Sha1).unwrap();
+        assert_eq!(commit.serialize(), COMMIT.as_bytes());
+    }
+
+    #[test]
+    fn test_parse_commit_missing_tree() {
+        let result = Commit::parse(
+            b"author a <a> 0 +0000\ncommitter a <a> 0 +0000\n\nmsg",
+            HashAlgorithm::Sha1,
+        );
+        assert!(result.unwrap_err().to_string().contains("missing a tree"));
+    }
+
+    #[test]
+    fn test_signature_roundtrip() {
+        let line = "Thibault Polge <thibault@thb.lt> 1527025023 +0200";
+        let signature = Signature::parse(line).unwrap();
+        assert_eq!(signature.name, "Thibault Polge");
+        assert_eq!(signature.time, 1527025023);
+        assert_eq!(signature.to_string(), line);
+    }
+}
@@ -0,0 +1,324 @@
+use crate::gitconfig::GitConfig;
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Credential is a username and password for a remote, in the form git
+/// credential helpers exchange them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Credential {
+    pub protocol: String,
+    /// The host, with its port when it is not the default one
+    pub host: String,
+    pub path: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Credential {
+    /// Return true if both the username and the password are known
+    pub fn is_complete(&self) -> bool {
+        self.username.is_some() && self.password.is_some()
+    }
+
+    /// Return the URL the credential is for, as shown in prompts
+    pub fn url(&self) -> String {
+        format!("{}://{}", self.protocol, self.host)
+    }
+
+    /// Format the credential as helper input: `key=value` lines and a blank line
+    fn to_input(&self) -> String {
+        let mut input = format!("protocol={}\nhost={}\n", self.protocol, self.host);
+        let fields = [
+            ("path", &self.path),
+            ("username", &self.username),
+            ("password", &self.password),
+        ];
+        for (key, value) in fields {
+            if let Some(value) = value {
+                input.push_str(&format!("{}={}\n", key, value));
+            }
+        }
+        input.push('\n');
+        input
+    }
+
+    /// Take the username and password a helper answered with. Returns true
+    /// if the helper asked to stop looking for credentials with `quit`.
+    fn update(&mut self, output: &str) -> bool {
+        let mut quit = false;
+        for line in output.lines() {
+            match line.split_once('=') {
+                Some(("username", value)) => self.username = Some(value.to_string()),
+                Some(("password", value)) => self.password = Some(value.to_string()),
+                Some(("quit", value)) => {
+                    quit = matches!(value, "1" | "true" | "yes" | "on");
+                }
+                _ => {}
+            }
+        }
+        quit
+    }
+}
+
+/// CredentialHelpers asks the `credential.helper` programs and then
+/// `GIT_ASKPASS` for credentials, like `git credential`
+#[derive(Debug, Clone, Default)]
+pub struct CredentialHelpers {
+    helpers: Vec<String>,
+    askpass: Option<String>,
+}
+
+impl CredentialHelpers {
+    /// Read the helpers from the global and repository configs
+    ///
+    /// An empty `credential.helper` value clears the helpers listed before it.
+    pub fn load(repo: &Repository) -> Result<CredentialHelpers> {
+        let global = GitConfig::global()?;
+        let config = repo.config()?;
+        let mut helpers = Vec::new();
+        for helper in global
+            .get_all("credential.helper")
+            .into_iter()
+            .chain(config.get_all("credential.helper"))
+        {
+            match helper.is_empty() {
+                true => helpers.clear(),
+                false => helpers.push(helper.to_string()),
+            }
+        }
+        let askpass = std::env::var("GIT_ASKPASS")
+            .ok()
+            .or_else(|| config.get("core.askPass").map(str::to_string))
+            .or_else(|| global.get("core.askPass").map(str::to_string))
+            .or_else(|| std::env::var("SSH_ASKPASS").ok())
+            .filter(|askpass| !askpass.is_empty());
+        Ok(CredentialHelpers { helpers, askpass })
+    }
+
+    /// Complete a credential from the helpers, stopping at the first one
+    /// that knows both username and password, then from the askpass program
+    ///
+    /// A helper that answers nothing, or fails, leaves the credential to the
+    /// next one; one that answers `quit=1` ends the search with an error.
+    pub fn fill(&self, credential: &mut Credential) -> Result<()> {
+        for helper in &self.helpers {
+            if credential.is_complete() {
+                break;
+            }
+            let output = run_helper(helper, "get", &credential.to_input())?;
+            if credential.update(&output) {
+                bail!("credential helper '{}' told us to quit", helper);
+            }
+        }
+        if credential.username.is_none() {
+            let prompt = format!("Username for '{}': ", credential.url());
+            credential.username = Some(self.ask(&prompt, credential)?);
+        }
+        if credential.password.is_none() {
+            let url = format!(
+                "{}://{}@{}",
+                credential.protocol,
+                credential.username.as_deref().unwrap_or_default(),
+                credential.host
+            );
+            let prompt = format!("Password for '{}': ", url);
+            credential.password = Some(self.ask(&prompt, credential)?);
+        }
+        Ok(())
+    }
+
+    /// Tell the helpers a credential worked so they can store it
+    pub fn approve(&self, credential: &Credential) -> Result<()> {
+        self.notify("store", credential)
+    }
+
+    /// Tell the helpers a credential was refused so they can forget it
+    pub fn reject(&self, credential: &Credential) -> Result<()> {
+        self.notify("erase", credential)
+    }
+
+    fn notify(&self, action: &str, credential: &Credential) -> Result<()> {
+        if !credential.is_complete() {
+            return Ok(());
+        }
+        for helper in &self.helpers {
+            run_helper(helper, action, &credential.to_input())?;
+        }
+        Ok(())
+    }
+
+    fn ask(&self, prompt: &str, credential: &Credential) -> Result<String> {
+        let Some(askpass) = &self.askpass else {
+            bail!(
+                "could not read {} for '{}': terminal prompts disabled",
+                prompt.split(' ').next().unwrap_or_default(),
+                credential.url()
+            );
+        };
+        let output = Command::new(askpass)
+            .arg(prompt)
+            .stderr(Stdio::inherit())
+            .output()
+            .with_context(|| format!("Failed to run askpass program '{}'", askpass))?;
+        if !output.status.success() {
+            bail!("could not read from askpass program '{}'", askpass);
+        }
+        let answer = String::from_utf8(output.stdout).context("Invalid askpass answer")?;
+        Ok(answer.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+/// Run a credential helper: `!command` is a shell snippet, an absolute path
+/// a program, and any other name `git credential-<name>`
+fn run_helper(helper: &str, action: &str, input: &str) -> Result<String> {
+    let command = match helper.strip_prefix('!') {
+        Some(command) => command.to_string(),
+        None if helper.starts_with('/') => helper.to_string(),
+        None => format!("git credential-{}", helper),
+    };
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} {}", command, action))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run credential helper '{}'", helper))?;
+    // A helper may exit without reading its input
+    let _ = child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(input.as_bytes());
+    let output = child.wait_with_output()?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::init_repo;
+
+    #[test]
+    fn test_fill_from_helpers() {
+        let (dir, repo) = init_repo();
+        let log = dir.path().join("stored");
+        let mut config = repo.config().unwrap();
+        config
+            .add("credential.helper", "!f() { echo username=ignored; }; f")
+            .unwrap();
+        config.add("credential.helper", "").unwrap();
+        let helper = format!(
+            "!f() {{ test $1 = get && echo password=secret; test $1 = store && cat > {}; }}; f",
+            log.display()
+        );
+        config.add("credential.helper", &helper).unwrap();
+        repo.write_config(&config).unwrap();
+
+        let helpers = CredentialHelpers::load(&repo).unwrap();
+        let mut credential = Credential {
+            protocol: "https".to_string(),
+            host: "example.com".to_string(),
+            username: Some("alice".to_string()),
+            ..Credential::default()
+        };
+        helpers.fill(&mut credential).unwrap();
+        assert_eq!(credential.username.as_deref(), Some("alice"));
+        assert_eq!(credential.password.as_deref(), Some("secret"));
+
+        helpers.approve(&credential).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(log).unwrap(),
+            "protocol=https\nhost=example.com\nusername=alice\npassword=secret\n\n"
+        );
+    }
+
+    fn set_helpers(repo: &Repository, helpers: &[&str]) {
+        let mut config = repo.config().unwrap();
+        for helper in helpers {
+            config.add("credential.helper", helper).unwrap();
+        }
+        repo.write_config(&config).unwrap();
+    }
+
+    fn example() -> Credential {
+        Credential {
+            protocol: "https".to_string(),
+            host: "example.com:8443".to_string(),
+            ..Credential::default()
+        }
+    }
+
+    #[test]
+    fn test_fill_falls_through_helpers() {
+        let (_dir, repo) = init_repo();
+        set_helpers(
+            &repo,
+            &[
+                "!f() { :; }; f",
+                "!f() { echo nonsense; exit 1; }; f",
+                "!f() { test $1 = get && echo username=bob; }; f",
+                "!f() { grep -q username=bob && echo password=pw; }; f",
+                "!f() { echo password=late; }; f",
+            ],
+        );
+        let helpers = CredentialHelpers::load(&repo).unwrap();
+        let mut credential = example();
+        helpers.fill(&mut credential).unwrap();
+        assert_eq!(credential.username.as_deref(), Some("bob"));
+        assert_eq!(credential.password.as_deref(), Some("pw"));
+
+        // Nothing is left to ask once every helper answered nothing
+        let helpers = CredentialHelpers {
+            helpers: vec!["!f() { :; }; f".to_string()],
+            askpass: None,
+        };
+        let mut credential = example();
+        let error = helpers.fill(&mut credential).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "could not read Username for 'https://example.com:8443': terminal prompts disabled"
+        );
+    }
+
+    #[test]
+    fn test_fill_stops_at_quit() {
+        let (dir, repo) = init_repo();
+        let asked = dir.path().join("asked");
+        let later = format!("!f() {{ touch {}; }}; f", asked.display());
+        set_helpers(
+            &repo,
+            &["!f() { echo username=bob; echo quit=1; }; f", &later],
+        );
+        let helpers = CredentialHelpers::load(&repo).unwrap();
+        let mut credential = example();
+        let error = helpers.fill(&mut credential).unwrap_err();
+        assert!(error.to_string().contains("told us to quit"), "{}", error);
+        assert!(!asked.exists());
+
+        let mut credential = example();
+        assert!(!credential.update("quit=0\nusername=bob\n"));
+        assert_eq!(credential.username.as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn test_reject_erases_complete_credentials() {
+        let (dir, repo) = init_repo();
+        let log = dir.path().join("log");
+        let helper = format!("!f() {{ echo $1 >> {0}; cat >> {0}; }}; f", log.display());
+        set_helpers(&repo, &[&helper]);
+        let helpers = CredentialHelpers::load(&repo).unwrap();
+        let mut credential = example();
+        credential.username = Some("bob".to_string());
+        helpers.reject(&credential).unwrap();
+        assert!(!log.exists(), "an incomplete credential is not erased");
+
+        credential.password = Some("pw".to_string());
+        helpers.reject(&credential).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "erase\nprotocol=https\nhost=example.com:8443\nusername=bob\npassword=pw\n\n"
+        );
+    }
+}
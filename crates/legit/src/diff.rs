@@ -0,0 +1,1986 @@
+use crate::checkout::{hash_file, path_matches, FileMap};
+use crate::index::Index;
+use crate::objects::{read_object, HashAlgorithm, ObjectHash};
+use crate::status::quote_path;
+use crate::submodule::submodule_head;
+use crate::tree::{flatten_tree, EntryMode, Tree};
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use strum::EnumString;
+
+/// Number of bytes inspected when deciding whether content is binary
+const BINARY_CHECK_BYTES: usize = 8000;
+
+/// Lines appearing more often than this are not used as histogram anchors
+const MAX_CHAIN_LENGTH: usize = 64;
+
+/// Maximum length of the function name shown in hunk headers
+const MAX_FUNCNAME_LENGTH: usize = 80;
+
+/// Length of the abbreviated hashes of the `index` line
+const ABBREV: usize = 7;
+
+/// Algorithm is the line diff algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, EnumString, strum::Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum Algorithm {
+    /// The classic O(ND) algorithm, in its linear space variant
+    #[default]
+    Myers,
+    /// Anchor on the rarest common lines first, falling back to Myers
+    Histogram,
+}
+
+/// LineOp is one step of a line diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineOp {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Split content into lines, keeping their terminating newlines
+pub fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+    data.split_inclusive(|&b| b == b'\n').collect()
+}
+
+/// Return true if the content looks binary, which git decides by looking
+/// for a NUL byte at its start
+pub fn is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(BINARY_CHECK_BYTES)].contains(&0)
+}
+
+/// Compute the steps transforming the old lines into the new ones
+///
+/// Ambiguous changes are placed where git would put them, using its indent
+/// heuristic.
+pub fn diff_lines(old: &[&[u8]], new: &[&[u8]], algorithm: Algorithm) -> Vec<LineOp> {
+    // Intern lines so the algorithms compare integers
+    let mut ids = HashMap::new();
+    let mut intern = |line: &[u8]| -> u32 {
+        let next = ids.len() as u32;
+        *ids.entry(line.to_vec()).or_insert(next)
+    };
+    let a = old.iter().map(|l| intern(l)).collect::<Vec<_>>();
+    let b = new.iter().map(|l| intern(l)).collect::<Vec<_>>();
+
+    let mut changed_a = vec![false; a.len()];
+    let mut changed_b = vec![false; b.len()];
+    let mut sides = Sides {
+        a: &a,
+        b: &b,
+        changed_a: &mut changed_a,
+        changed_b: &mut changed_b,
+    };
+    match algorithm {
+        Algorithm::Myers => sides.myers(0, a.len(), 0, b.len()),
+        Algorithm::Histogram => sides.histogram(0, a.len(), 0, b.len()),
+    }
+
+    compact(
+        old,
+        &a,
+        &mut changed_a,
+        &Changes {
+            lines: &b,
+            changed: &mut changed_b.clone(),
+        },
+    );
+    compact(
+        new,
+        &b,
+        &mut changed_b,
+        &Changes {
+            lines: &a,
+            changed: &mut changed_a.clone(),
+        },
+    );
+
+    let mut ops = Vec::with_capacity(a.len().max(b.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && changed_a[i] {
+            ops.push(LineOp::Delete);
+            i += 1;
+        } else if j < b.len() && changed_b[j] {
+            ops.push(LineOp::Insert);
+            j += 1;
+        } else {
+            ops.push(LineOp::Equal);
+            i += 1;
+            j += 1;
+        }
+    }
+    ops
+}
+
+/// Sides holds the interned lines of both files and which of them changed
+struct Sides<'a> {
+    a: &'a [u32],
+    b: &'a [u32],
+    changed_a: &'a mut [bool],
+    changed_b: &'a mut [bool],
+}
+
+impl Sides<'_> {
+    /// Shrink a range by its common prefix and suffix, marking what is left
+    /// if one side is empty. Returns `None` when nothing is left to diff.
+    fn trim(
+        &mut self,
+        mut alo: usize,
+        mut ahi: usize,
+        mut blo: usize,
+        mut bhi: usize,
+    ) -> Option<(usize, usize, usize, usize)> {
+        while alo < ahi && blo < bhi && self.a[alo] == self.b[blo] {
+            alo += 1;
+            blo += 1;
+        }
+        while alo < ahi && blo < bhi && self.a[ahi - 1] == self.b[bhi - 1] {
+            ahi -= 1;
+            bhi -= 1;
+        }
+        if alo == ahi || blo == bhi {
+            self.changed_a[alo..ahi].fill(true);
+            self.changed_b[blo..bhi].fill(true);
+            return None;
+        }
+        Some((alo, ahi, blo, bhi))
+    }
+
+    /// Myers' divide and conquer diff, splitting on the middle snake
+    fn myers(&mut self, alo: usize, ahi: usize, blo: usize, bhi: usize) {
+        let Some((alo, ahi, blo, bhi)) = self.trim(alo, ahi, blo, bhi) else {
+            return;
+        };
+        // The middle snake holds a single edit next to a diagonal, which the
+        // trimming of the recursive call resolves
+        let (x0, y0, x1, y1) = self.middle_snake(alo, ahi, blo, bhi);
+        self.myers(alo, x0, blo, y0);
+        self.myers(x0, x1, y0, y1);
+        self.myers(x1, ahi, y1, bhi);
+    }
+
+    /// Find the middle snake of the shortest edit script of a box, returning
+    /// its start and end points including the edit leading to it
+    fn middle_snake(
+        &self,
+        left: usize,
+        right: usize,
+        top: usize,
+        bottom: usize,
+    ) -> (usize, usize, usize, usize) {
+        let (left, right, top, bottom) =
+            (left as isize, right as isize, top as isize, bottom as isize);
+        let width = right - left;
+        let height = bottom - top;
+        let delta = width - height;
+        let max = (width + height + 1) / 2;
+        let offset = max + 1;
+        let size = (2 * offset + 1) as usize;
+        let mut forward = vec![0isize; size];
+        let mut backward = vec![0isize; size];
+        forward[(offset + 1) as usize] = left;
+        backward[(offset + 1) as usize] = bottom;
+        let a = |x: isize| self.a[x as usize];
+        let b = |y: isize| self.b[y as usize];
+
+        for d in 0..=max {
+            let mut k = d;
+            while k >= -d {
+                let c = k - delta;
+                let (mut x, px);
+                if k == -d
+                    || (k != d
+                        && forward[(offset + k - 1) as usize] < forward[(offset + k + 1) as usize])
+                {
+                    px = forward[(offset + k + 1) as usize];
+                    x = px;
+                } else {
+                    px = forward[(offset + k - 1) as usize];
+                    x = px + 1;
+                }
+                let mut y = top + (x - left) - k;
+                let py = if d == 0 || x != px { y } else { y - 1 };
+                while x < right && y < bottom && a(x) == b(y) {
+                    x += 1;
+                    y += 1;
+                }
+                forward[(offset + k) as usize] = x;
+                if delta % 2 != 0 && c > -d && c < d && y >= backward[(offset + c) as usize] {
+                    return (px as usize, py as usize, x as usize, y as usize);
+                }
+                k -= 2;
+            }
+
+            let mut c = d;
+            while c >= -d {
+                let k = c + delta;
+                let (mut y, py);
+                if c == -d
+                    || (c != d
+                        && backward[(offset + c - 1) as usize]
+                            > backward[(offset + c + 1) as usize])
+                {
+                    py = backward[(offset + c + 1) as usize];
+                    y = py;
+                } else {
+                    py = backward[(offset + c - 1) as usize];
+                    y = py - 1;
+                }
+                let mut x = left + (y - top) + k;
+                let px = if d == 0 || y != py { x } else { x + 1 };
+                while x > left && y > top && a(x - 1) == b(y - 1) {
+                    x -= 1;
+                    y -= 1;
+                }
+                backward[(offset + c) as usize] = y;
+                if delta % 2 == 0 && k >= -d && k <= d && x <= forward[(offset + k) as usize] {
+                    return (x as usize, y as usize, px as usize, py as usize);
+                }
+                c -= 2;
+            }
+        }
+        unreachable!("the middle snake is always found within (width + height) / 2 steps")
+    }
+
+    /// Histogram diff: split on the longest common region containing the
+    /// rarest lines and recurse on both sides of it
+    fn histogram(&mut self, alo: usize, ahi: usize, blo: usize, bhi: usize) {
+        let Some((alo, ahi, blo, bhi)) = self.trim(alo, ahi, blo, bhi) else {
+            return;
+        };
+        let mut occurrences = HashMap::<u32, Vec<usize>>::new();
+        for i in alo..ahi {
+            occurrences.entry(self.a[i]).or_default().push(i);
+        }
+
+        let mut best: Option<(usize, usize, usize, usize)> = None;
+        let mut best_count = MAX_CHAIN_LENGTH + 1;
+        let mut any_common = false;
+        let mut j = blo;
+        while j < bhi {
+            let Some(positions) = occurrences.get(&self.b[j]) else {
+                j += 1;
+                continue;
+            };
+            any_common = true;
+            if positions.len() > best_count {
+                j += 1;
+                continue;
+            }
+            let mut next = j + 1;
+            for &i in positions {
+                let (mut as_, mut bs) = (i, j);
+                while as_ > alo && bs > blo && self.a[as_ - 1] == self.b[bs - 1] {
+                    as_ -= 1;
+                    bs -= 1;
+                }
+                let (mut ae, mut be) = (i + 1, j + 1);
+                while ae < ahi && be < bhi && self.a[ae] == self.b[be] {
+                    ae += 1;
+                    be += 1;
+                }
+                let count = (as_..ae)
+                    .map(|k| occurrences[&self.a[k]].len())
+                    .min()
+                    .unwrap_or(usize::MAX);
+                let longer = best.is_some_and(|(s, e, _, _)| ae - as_ > e - s);
+                if count < best_count || (count == best_count && longer) {
+                    best = Some((as_, ae, bs, be));
+                    best_count = count;
+                }
+                next = next.max(be);
+            }
+            j = next;
+        }
+
+        match best {
+            Some((as_, ae, bs, be)) => {
+                self.histogram(alo, as_, blo, bs);
+                self.histogram(ae, ahi, be, bhi);
+            }
+            None if any_common => self.myers(alo, ahi, blo, bhi),
+            None => {
+                self.changed_a[alo..ahi].fill(true);
+                self.changed_b[blo..bhi].fill(true);
+            }
+        }
+    }
+}
+
+/// Maximum indent considered by the indent heuristic
+const MAX_INDENT: i32 = 200;
+
+/// Maximum number of blank lines the indent heuristic looks through
+const MAX_BLANKS: i32 = 20;
+
+/// Maximum distance a group is slid by the indent heuristic
+const INDENT_HEURISTIC_MAX_SLIDING: usize = 100;
+
+/// A run of changed lines `start..end` in one file
+#[derive(Debug, Clone, Copy)]
+struct Group {
+    start: usize,
+    end: usize,
+}
+
+/// Changed lines of one file, with the helpers git uses to move groups
+struct Changes<'a> {
+    lines: &'a [u32],
+    changed: &'a mut [bool],
+}
+
+impl Changes<'_> {
+    fn is_changed(&self, i: usize) -> bool {
+        i < self.changed.len() && self.changed[i]
+    }
+
+    fn first_group(&self) -> Group {
+        let mut end = 0;
+        while self.is_changed(end) {
+            end += 1;
+        }
+        Group { start: 0, end }
+    }
+
+    fn next_group(&self, g: &mut Group) -> bool {
+        if g.end == self.changed.len() {
+            return false;
+        }
+        g.start = g.end + 1;
+        g.end = g.start;
+        while self.is_changed(g.end) {
+            g.end += 1;
+        }
+        true
+    }
+
+    fn previous_group(&self, g: &mut Group) -> bool {
+        if g.start == 0 {
+            return false;
+        }
+        g.end = g.start - 1;
+        g.start = g.end;
+        while g.start > 0 && self.changed[g.start - 1] {
+            g.start -= 1;
+        }
+        true
+    }
+
+    fn slide_down(&mut self, g: &mut Group) -> bool {
+        if g.end < self.lines.len() && self.lines[g.start] == self.lines[g.end] {
+            self.changed[g.start] = false;
+            self.changed[g.end] = true;
+            g.start += 1;
+            g.end += 1;
+            while self.is_changed(g.end) {
+                g.end += 1;
+            }
+            return true;
+        }
+        false
+    }
+
+    fn slide_up(&mut self, g: &mut Group) -> bool {
+        if g.start > 0 && self.lines[g.start - 1] == self.lines[g.end - 1] {
+            g.start -= 1;
+            g.end -= 1;
+            self.changed[g.start] = true;
+            self.changed[g.end] = false;
+            while g.start > 0 && self.changed[g.start - 1] {
+                g.start -= 1;
+            }
+            return true;
+        }
+        false
+    }
+}
+
+/// Shift groups of changes to their most readable position, as git's
+/// `xdl_change_compact` does
+///
+/// Every group is slid as far down as possible, merging with neighbours.
+/// It is then moved back up to line up with a change in the other file, or
+/// failing that to the position the indent heuristic likes best.
+fn compact(raw: &[&[u8]], lines: &[u32], changed: &mut [bool], other: &Changes) {
+    let mut file = Changes { lines, changed };
+    let mut g = file.first_group();
+    let mut go = other.first_group();
+    loop {
+        if g.end != g.start {
+            let mut earliest_end;
+            let mut end_matching_other;
+            let mut group_size;
+            loop {
+                group_size = g.end - g.start;
+                end_matching_other = None;
+                while file.slide_up(&mut g) {
+                    other.previous_group(&mut go);
+                }
+                earliest_end = g.end;
+                if go.end > go.start {
+                    end_matching_other = Some(g.end);
+                }
+                while file.slide_down(&mut g) {
+                    other.next_group(&mut go);
+                    if go.end > go.start {
+                        end_matching_other = Some(g.end);
+                    }
+                }
+                if group_size == g.end - g.start {
+                    break;
+                }
+            }
+
+            if g.end == earliest_end {
+                // The group cannot move
+            } else if end_matching_other.is_some() {
+                while go.end == go.start {
+                    file.slide_up(&mut g);
+                    other.previous_group(&mut go);
+                }
+            } else {
+                let mut shift = earliest_end
+                    .max(g.end.saturating_sub(group_size + 1))
+                    .max(g.end.saturating_sub(INDENT_HEURISTIC_MAX_SLIDING));
+                let mut best: Option<(usize, SplitScore)> = None;
+                while shift <= g.end {
+                    let mut score = SplitScore::default();
+                    score.add(&measure_split(raw, shift));
+                    score.add(&measure_split(raw, shift - group_size));
+                    if best.as_ref().is_none_or(|(_, b)| score.cmp(b) <= 0) {
+                        best = Some((shift, score));
+                    }
+                    shift += 1;
+                }
+                let best_shift = best.map_or(g.end, |(shift, _)| shift);
+                while g.end > best_shift {
+                    file.slide_up(&mut g);
+                    other.previous_group(&mut go);
+                }
+            }
+        }
+        if !file.next_group(&mut g) {
+            break;
+        }
+        other.next_group(&mut go);
+    }
+}
+
+/// Indentation of a line with tabs every 8 columns, `None` if it is blank
+fn indent(line: &[u8]) -> Option<i32> {
+    let mut indent = 0;
+    for &c in line {
+        if !c.is_ascii_whitespace() && c != 0x0b {
+            return Some(indent);
+        }
+        match c {
+            b' ' => indent += 1,
+            b'\t' => indent += 8 - indent % 8,
+            _ => {}
+        }
+        if indent >= MAX_INDENT {
+            return Some(MAX_INDENT);
+        }
+    }
+    None
+}
+
+/// SplitMeasurement describes the lines around a split point
+#[derive(Debug, Default)]
+struct SplitMeasurement {
+    end_of_file: bool,
+    indent: Option<i32>,
+    pre_blank: i32,
+    pre_indent: Option<i32>,
+    post_blank: i32,
+    post_indent: Option<i32>,
+}
+
+fn measure_split(lines: &[&[u8]], split: usize) -> SplitMeasurement {
+    let mut m = SplitMeasurement {
+        end_of_file: split >= lines.len(),
+        indent: lines.get(split).and_then(|l| indent(l)),
+        ..Default::default()
+    };
+    for line in lines[..split.min(lines.len())].iter().rev() {
+        m.pre_indent = indent(line);
+        if m.pre_indent.is_some() {
+            break;
+        }
+        m.pre_blank += 1;
+        if m.pre_blank == MAX_BLANKS {
+            m.pre_indent = Some(0);
+            break;
+        }
+    }
+    for line in lines.iter().skip(split + 1) {
+        m.post_indent = indent(line);
+        if m.post_indent.is_some() {
+            break;
+        }
+        m.post_blank += 1;
+        if m.post_blank == MAX_BLANKS {
+            m.post_indent = Some(0);
+            break;
+        }
+    }
+    m
+}
+
+/// SplitScore rates how natural a split looks, lower being better
+#[derive(Debug, Default, Clone, Copy)]
+struct SplitScore {
+    effective_indent: i32,
+    penalty: i32,
+}
+
+impl SplitScore {
+    fn add(&mut self, m: &SplitMeasurement) {
+        if m.pre_indent.is_none() && m.pre_blank == 0 {
+            self.penalty += 1;
+        }
+        if m.end_of_file {
+            self.penalty += 21;
+        }
+        let post_blank = match m.indent {
+            None => 1 + m.post_blank,
+            Some(_) => 0,
+        };
+        let total_blank = m.pre_blank + post_blank;
+        self.penalty += -30 * total_blank + 6 * post_blank;
+
+        let indent = m.indent.or(m.post_indent);
+        let any_blanks = total_blank != 0;
+        self.effective_indent += indent.unwrap_or(-1);
+        let (Some(indent), Some(pre_indent)) = (indent, m.pre_indent) else {
+            return;
+        };
+        if indent > pre_indent {
+            self.penalty += if any_blanks { 10 } else { -4 };
+        } else if indent < pre_indent {
+            let outdent = m.post_indent.is_some_and(|post| post > indent);
+            self.penalty += match (outdent, any_blanks) {
+                (true, true) => 17,
+                (true, false) => 24,
+                (false, true) => 17,
+                (false, false) => 23,
+            };
+        }
+    }
+
+    fn cmp(&self, other: &SplitScore) -> i32 {
+        let indents = (self.effective_indent > other.effective_indent) as i32
+            - (self.effective_indent < other.effective_indent) as i32;
+        60 * indents + (self.penalty - other.penalty)
+    }
+}
+
+/// Count the inserted and deleted lines of a diff
+pub fn count_lines(ops: &[LineOp]) -> (usize, usize) {
+    ops.iter().fold((0, 0), |(added, deleted), op| match op {
+        LineOp::Insert => (added + 1, deleted),
+        LineOp::Delete => (added, deleted + 1),
+        LineOp::Equal => (added, deleted),
+    })
+}
+
+/// Estimate how similar two contents are, from 0 to 100
+///
+/// Like git, content is cut into chunks at newlines or every 64 bytes and
+/// the score is the number of bytes found in common chunks relative to the
+/// larger content.
+pub fn similarity(old: &[u8], new: &[u8]) -> u32 {
+    let max = old.len().max(new.len());
+    if max == 0 {
+        return 100;
+    }
+    let old_chunks = chunk_sizes(old);
+    let new_chunks = chunk_sizes(new);
+    let common = new_chunks
+        .iter()
+        .map(|(hash, size)| old_chunks.get(hash).map_or(0, |old| (*old).min(*size)))
+        .sum::<usize>();
+    (common * 100 / max) as u32
+}
+
+fn chunk_sizes(data: &[u8]) -> HashMap<u64, usize> {
+    let mut sizes = HashMap::new();
+    let mut start = 0;
+    for (i, &b) in data.iter().enumerate() {
+        if b == b'\n' || i + 1 - start == 64 || i + 1 == data.len() {
+            let mut hasher = DefaultHasher::new();
+            data[start..=i].hash(&mut hasher);
+            *sizes.entry(hasher.finish()).or_insert(0) += i + 1 - start;
+            start = i + 1;
+        }
+    }
+    sizes
+}
+
+/// ChangeKind is the kind of change made to a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Deleted,
+    Modified,
+    Renamed,
+    Copied,
+    TypeChanged,
+    Unmerged,
+}
+
+impl ChangeKind {
+    /// Return the letter used by `--name-status` and the raw format
+    pub fn as_char(&self) -> char {
+        match self {
+            ChangeKind::Added => 'A',
+            ChangeKind::Deleted => 'D',
+            ChangeKind::Modified => 'M',
+            ChangeKind::Renamed => 'R',
+            ChangeKind::Copied => 'C',
+            ChangeKind::TypeChanged => 'T',
+            ChangeKind::Unmerged => 'U',
+        }
+    }
+}
+
+/// DiffEntry is one side of a file diff
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffEntry {
+    pub path: String,
+    pub mode: EntryMode,
+    pub hash: ObjectHash,
+    /// The content lives in the working tree rather than the object store
+    pub worktree: bool,
+}
+
+/// FileDiff describes how a single file changed
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileDiff {
+    pub kind: ChangeKind,
+    pub old: Option<DiffEntry>,
+    pub new: Option<DiffEntry>,
+    /// Similarity percentage of renames and copies
+    pub score: Option<u32>,
+}
+
+impl FileDiff {
+    /// The path the change is reported under
+    pub fn path(&self) -> &str {
+        match (&self.new, &self.old) {
+            (Some(entry), _) | (None, Some(entry)) => &entry.path,
+            (None, None) => "",
+        }
+    }
+}
+
+/// DiffOptions controls how files are compared and printed
+#[derive(Debug, Clone)]
+pub struct DiffOptions {
+    pub algorithm: Algorithm,
+    /// Number of context lines around changes
+    pub context: usize,
+    /// Minimum similarity for rename detection, `None` to disable it
+    pub renames: Option<u32>,
+    /// Also detect copies from modified files
+    pub copies: bool,
+    /// Consider unmodified files as copy sources too
+    pub find_copies_harder: bool,
+    /// Only compare paths matching one of these
+    pub pathspecs: Vec<String>,
+    /// Show changed words inline instead of changed lines
+    pub word_diff: bool,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions {
+            algorithm: Algorithm::Myers,
+            context: 3,
+            renames: Some(50),
+            copies: false,
+            find_copies_harder: false,
+            pathspecs: Vec::new(),
+            word_diff: false,
+        }
+    }
+}
+
+/// Files of the stage 0 entries of the index
+pub fn index_files(index: &Index) -> Result<FileMap> {
+    index
+        .entries
+        .iter()
+        .filter(|e| e.stage == 0)
+        .map(|e| Ok((e.path.clone(), (e.entry_mode()?, e.hash.clone()))))
+        .collect()
+}
+
+/// Files of the working tree tracked by the index, hashing only those
+/// whose stat data does not match the index
+pub fn worktree_files(repo: &Repository, index: &Index) -> Result<FileMap> {
+    let mut files = FileMap::new();
+    for entry in index.entries.iter().filter(|e| e.stage == 0) {
+        let Ok(metadata) = fs::symlink_metadata(repo.worktree().join(&entry.path)) else {
+            continue;
+        };
+        let mode = entry.entry_mode()?;
+        if mode == EntryMode::Gitlink {
+            // A submodule is at the commit checked out in it
+            let hash = submodule_head(repo, &entry.path)?.unwrap_or_else(|| entry.hash.clone());
+            files.insert(entry.path.clone(), (mode, hash));
+        } else if entry.stat_matches(&metadata) {
+            files.insert(entry.path.clone(), (mode, entry.hash.clone()));
+        } else if !metadata.is_dir() {
+            files.insert(
+                entry.path.clone(),
+                hash_file(repo, &entry.path, Some(entry))?,
+            );
+        }
+    }
+    Ok(files)
+}
+
+/// Entries of a tree, recursing into subtrees or listing them as entries
+pub fn tree_files(repo: &Repository, tree: &ObjectHash, recursive: bool) -> Result<FileMap> {
+    if recursive {
+        return flatten_tree(repo, tree);
+    }
+    Ok(Tree::read(repo, tree)?
+        .entries
+        .into_iter()
+        .map(|e| (e.name, (e.mode, e.hash)))
+        .collect())
+}
+
+/// Compare two trees, either of which may be missing
+pub fn diff_trees(
+    repo: &Repository,
+    old: Option<&ObjectHash>,
+    new: Option<&ObjectHash>,
+    options: &DiffOptions,
+) -> Result<Vec<FileDiff>> {
+    let old = old
+        .map(|t| flatten_tree(repo, t))
+        .transpose()?
+        .unwrap_or_default();
+    let new = new
+        .map(|t| flatten_tree(repo, t))
+        .transpose()?
+        .unwrap_or_default();
+    diff_files(repo, &old, &new, false, options)
+}
+
+/// Compare a tree with the index, as `diff --cached` does
+pub fn diff_tree_to_index(
+    repo: &Repository,
+    tree: Option<&ObjectHash>,
+    index: &Index,
+    options: &DiffOptions,
+) -> Result<Vec<FileDiff>> {
+    let old = tree
+        .map(|t| flatten_tree(repo, t))
+        .transpose()?
+        .unwrap_or_default();
+    let mut diffs = diff_files(repo, &old, &index_files(index)?, false, options)?;
+    add_unmerged(repo, index, options, &mut diffs);
+    Ok(diffs)
+}
+
+/// Compare the index with the working tree, as a plain `diff` does
+pub fn diff_index_to_worktree(
+    repo: &Repository,
+    index: &Index,
+    options: &DiffOptions,
+) -> Result<Vec<FileDiff>> {
+    let old = index_files(index)?;
+    let new = worktree_files(repo, index)?;
+    let mut diffs = diff_files(repo, &old, &new, true, options)?;
+    add_unmerged(repo, index, options, &mut diffs);
+    Ok(diffs)
+}
+
+/// Compare a tree with the tracked files of the working tree
+pub fn diff_tree_to_worktree(
+    repo: &Repository,
+    tree: Option<&ObjectHash>,
+    index: &Index,
+    options: &DiffOptions,
+) -> Result<Vec<FileDiff>> {
+    let old = tree
+        .map(|t| flatten_tree(repo, t))
+        .transpose()?
+        .unwrap_or_default();
+    let new = worktree_files(repo, index)?;
+    diff_files(repo, &old, &new, true, options)
+}
+
+/// Report conflicted paths, which cannot be compared
+fn add_unmerged(
+    repo: &Repository,
+    index: &Index,
+    options: &DiffOptions,
+    diffs: &mut Vec<FileDiff>,
+) {
+    for path in index.conflicted_paths() {
+        if !matches_pathspecs(options, path) {
+            continue;
+        }
+        diffs.retain(|d| d.path() != path);
+        diffs.push(FileDiff {
+            kind: ChangeKind::Unmerged,
+            old: None,
+            new: Some(DiffEntry {
+                path: path.to_string(),
+                mode: EntryMode::Blob,
+                hash: repo.hash_algorithm().null_hash(),
+                worktree: true,
+            }),
+            score: None,
+        });
+    }
+    diffs.sort_by(|a, b| a.path().cmp(b.path()));
+}
+
+fn matches_pathspecs(options: &DiffOptions, path: &str) -> bool {
+    options.pathspecs.is_empty() || options.pathspecs.iter().any(|p| path_matches(p, path))
+}
+
+/// Files that can only change into each other by deleting and re-adding
+fn file_type(mode: EntryMode) -> u8 {
+    match mode {
+        EntryMode::Blob | EntryMode::BlobExecutable => 0,
+        EntryMode::Symlink => 1,
+        EntryMode::Gitlink => 2,
+        EntryMode::Tree => 3,
+    }
+}
+
+/// Compare two sets of files and detect renames and copies
+pub fn diff_files(
+    repo: &Repository,
+    old: &FileMap,
+    new: &FileMap,
+    new_in_worktree: bool,
+    options: &DiffOptions,
+) -> Result<Vec<FileDiff>> {
+    let entry = |path: &str, (mode, hash): &(EntryMode, ObjectHash), worktree: bool| DiffEntry {
+        path: path.to_string(),
+        mode: *mode,
+        hash: hash.clone(),
+        worktree,
+    };
+    let paths = old.keys().chain(new.keys()).collect::<BTreeSet<_>>();
+    let mut diffs = Vec::new();
+    for path in paths {
+        if !matches_pathspecs(options, path) {
+            continue;
+        }
+        let (old_file, new_file) = (old.get(path), new.get(path));
+        let kind = match (old_file, new_file) {
+            (Some(o), Some(n)) if o == n => continue,
+            (Some(o), Some(n)) if file_type(o.0) != file_type(n.0) => ChangeKind::TypeChanged,
+            (Some(_), Some(_)) => ChangeKind::Modified,
+            (Some(_), None) => ChangeKind::Deleted,
+            (None, Some(_)) => ChangeKind::Added,
+            (None, None) => continue,
+        };
+        diffs.push(FileDiff {
+            kind,
+            old: old_file.map(|f| entry(path, f, false)),
+            new: new_file.map(|f| entry(path, f, new_in_worktree)),
+            score: None,
+        });
+    }
+
+    if let Some(threshold) = options.renames {
+        let sources = match options.find_copies_harder {
+            true => old
+                .iter()
+                .filter(|(path, _)| matches_pathspecs(options, path))
+                .map(|(path, file)| entry(path, file, false))
+                .collect(),
+            false => Vec::new(),
+        };
+        detect_renames(repo, &mut diffs, sources, threshold, options.copies)?;
+    }
+    Ok(diffs)
+}
+
+/// Read the content of one side of a diff
+pub fn read_content(repo: &Repository, entry: &DiffEntry) -> Result<Vec<u8>> {
+    if entry.mode == EntryMode::Gitlink {
+        return Ok(format!("Subproject commit {}\n", entry.hash).into_bytes());
+    }
+    if entry.worktree {
+        let path = repo.worktree().join(&entry.path);
+        if fs::symlink_metadata(&path)?.file_type().is_symlink() {
+            let target = fs::read_link(&path)?;
+            return Ok(target.to_string_lossy().into_owned().into_bytes());
+        }
+        return fs::read(&path).with_context(|| format!("Failed to read {}", path.display()));
+    }
+    Ok(read_object(repo, &entry.hash)?.data)
+}
+
+/// Pair deleted (and with `copies` modified) files with added files of
+/// similar content
+///
+/// Exact matches are paired first, then the remaining candidates in order of
+/// decreasing similarity. A deleted file used as the source of several
+/// files is a rename once and a copy for the others.
+fn detect_renames(
+    repo: &Repository,
+    diffs: &mut Vec<FileDiff>,
+    extra_sources: Vec<DiffEntry>,
+    threshold: u32,
+    copies: bool,
+) -> Result<()> {
+    let is_file = |e: &DiffEntry| file_type(e.mode) <= 1;
+    let mut sources = diffs
+        .iter()
+        .filter(|d| d.kind == ChangeKind::Deleted || (copies && d.kind == ChangeKind::Modified))
+        .filter_map(|d| d.old.clone())
+        .filter(is_file)
+        .collect::<Vec<_>>();
+    for source in extra_sources {
+        if is_file(&source) && !sources.iter().any(|s| s.path == source.path) {
+            sources.push(source);
+        }
+    }
+    let targets = diffs
+        .iter()
+        .enumerate()
+        .filter(|(_, d)| d.kind == ChangeKind::Added)
+        .filter_map(|(i, d)| Some((i, d.new.clone()?)))
+        .filter(|(_, e)| is_file(e))
+        .collect::<Vec<_>>();
+    if sources.is_empty() || targets.is_empty() {
+        return Ok(());
+    }
+    let deleted = diffs
+        .iter()
+        .filter(|d| d.kind == ChangeKind::Deleted)
+        .filter_map(|d| Some(d.old.as_ref()?.path.clone()))
+        .collect::<BTreeSet<_>>();
+
+    let target_entries = targets.iter().map(|(_, e)| e.clone()).collect::<Vec<_>>();
+    let candidates = rename_candidates(repo, &sources, &target_entries, threshold)?;
+
+    let mut paired = vec![false; targets.len()];
+    let mut used = vec![false; sources.len()];
+    let mut renamed_sources = BTreeSet::new();
+    for (score, s, t) in candidates {
+        if paired[t] {
+            continue;
+        }
+        let source = &sources[s];
+        let kind = if deleted.contains(&source.path) && !used[s] {
+            renamed_sources.insert(source.path.clone());
+            ChangeKind::Renamed
+        } else if copies {
+            ChangeKind::Copied
+        } else {
+            continue;
+        };
+        paired[t] = true;
+        used[s] = true;
+        let diff = &mut diffs[targets[t].0];
+        diff.kind = kind;
+        diff.old = Some(source.clone());
+        diff.score = Some(score);
+    }
+
+    diffs.retain(|d| {
+        d.kind != ChangeKind::Deleted
+            || !d
+                .old
+                .as_ref()
+                .is_some_and(|o| renamed_sources.contains(&o.path))
+    });
+    diffs.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(())
+}
+
+/// Score every source and target pair at least `threshold` similar,
+/// returning `(score, source, target)` with the best candidates first
+///
+/// Exact matches score 100; among equal scores files keeping their base
+/// name are preferred.
+pub fn rename_candidates(
+    repo: &Repository,
+    sources: &[DiffEntry],
+    targets: &[DiffEntry],
+    threshold: u32,
+) -> Result<Vec<(u32, usize, usize)>> {
+    let target_contents = targets
+        .iter()
+        .map(|e| read_content(repo, e))
+        .collect::<Result<Vec<_>>>()?;
+    let mut candidates = Vec::new();
+    for (s, source) in sources.iter().enumerate() {
+        let content = read_content(repo, source)?;
+        for (t, target) in targets.iter().enumerate() {
+            let score = match source.hash == target.hash {
+                true => 100,
+                false => {
+                    let other = &target_contents[t];
+                    let (small, large) = match content.len() < other.len() {
+                        true => (content.len(), other.len()),
+                        false => (other.len(), content.len()),
+                    };
+                    // Skip pairs whose size difference alone rules them out
+                    if (small * 100) < (large * threshold as usize) {
+                        continue;
+                    }
+                    similarity(&content, other)
+                }
+            };
+            if score >= threshold {
+                let same_name = source.path.rsplit('/').next() == target.path.rsplit('/').next();
+                candidates.push((score, same_name, s, t));
+            }
+        }
+    }
+    candidates.sort_by(|x, y| {
+        (y.0, y.1)
+            .cmp(&(x.0, x.1))
+            .then((x.3, x.2).cmp(&(y.3, y.2)))
+    });
+    Ok(candidates
+        .into_iter()
+        .map(|(score, _, s, t)| (score, s, t))
+        .collect())
+}
+
+/// Quote a path with its `a/` or `b/` prefix as in patch headers
+fn prefixed(prefix: &str, path: &str) -> String {
+    quote_path(&format!("{}{}", prefix, path))
+}
+
+fn abbrev(hash: &ObjectHash) -> String {
+    hash.to_hex()[..ABBREV].to_string()
+}
+
+/// Format a hunk range the way unified diffs do
+fn hunk_range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, count),
+    }
+}
+
+/// Find the function name for a hunk: the closest line before it starting
+/// with a letter, `_` or `$`
+fn function_name(lines: &[&[u8]], before: usize) -> Option<String> {
+    lines[..before.min(lines.len())]
+        .iter()
+        .rev()
+        .find(|line| {
+            line.first()
+                .is_some_and(|&c| c.is_ascii_alphabetic() || c == b'_' || c == b'$')
+        })
+        .map(|line| {
+            let line = &line[..line.len().min(MAX_FUNCNAME_LENGTH)];
+            String::from_utf8_lossy(line).trim_end().to_string()
+        })
+}
+
+/// Group changes into hunks, returning ranges of operation indices
+fn hunk_ranges(ops: &[LineOp], context: usize) -> Vec<(usize, usize)> {
+    let changes = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| **op != LineOp::Equal)
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    let mut ranges = Vec::new();
+    let mut iter = changes.into_iter().peekable();
+    while let Some(first) = iter.next() {
+        let mut last = first;
+        while let Some(&next) = iter.peek() {
+            if next - last - 1 > 2 * context {
+                break;
+            }
+            last = next;
+            iter.next();
+        }
+        ranges.push((
+            first.saturating_sub(context),
+            (last + 1 + context).min(ops.len()),
+        ));
+    }
+    ranges
+}
+
+/// Write the hunks of a text diff in unified format
+pub fn write_hunks(
+    old: &[u8],
+    new: &[u8],
+    options: &DiffOptions,
+    out: &mut Vec<u8>,
+) -> (usize, usize) {
+    let old_lines = split_lines(old);
+    let new_lines = split_lines(new);
+    let ops = diff_lines(&old_lines, &new_lines, options.algorithm);
+
+    let mut positions = Vec::with_capacity(ops.len() + 1);
+    let (mut ai, mut bi) = (0, 0);
+    for op in &ops {
+        positions.push((ai, bi));
+        match op {
+            LineOp::Equal => {
+                ai += 1;
+                bi += 1;
+            }
+            LineOp::Delete => ai += 1,
+            LineOp::Insert => bi += 1,
+        }
+    }
+    positions.push((ai, bi));
+
+    for (start, end) in hunk_ranges(&ops, options.context) {
+        let (old_start, new_start) = positions[start];
+        let (old_end, new_end) = positions[end];
+        let mut header = format!(
+            "@@ -{} +{} @@",
+            hunk_range(old_start, old_end - old_start),
+            hunk_range(new_start, new_end - new_start)
+        );
+        if let Some(name) = function_name(&old_lines, old_start) {
+            header.push(' ');
+            header.push_str(&name);
+        }
+        out.extend_from_slice(header.as_bytes());
+        out.push(b'\n');
+
+        if options.word_diff {
+            write_word_diff(
+                &old_lines[old_start..old_end],
+                &new_lines[new_start..new_end],
+                options.algorithm,
+                out,
+            );
+            continue;
+        }
+        for (op, &(a, b)) in ops[start..end].iter().zip(&positions[start..end]) {
+            let (prefix, line) = match op {
+                LineOp::Equal => (b' ', old_lines[a]),
+                LineOp::Delete => (b'-', old_lines[a]),
+                LineOp::Insert => (b'+', new_lines[b]),
+            };
+            out.push(prefix);
+            out.extend_from_slice(line);
+            if !line.ends_with(b"\n") {
+                out.extend_from_slice(b"\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    count_lines(&ops)
+}
+
+/// Split text into words, each with the whitespace preceding it; trailing
+/// whitespace is dropped
+fn split_words(text: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut words = Vec::new();
+    let mut i = 0;
+    while i < text.len() {
+        let space_start = i;
+        while i < text.len() && text[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        let word_start = i;
+        while i < text.len() && !text[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if word_start < i {
+            words.push((&text[space_start..word_start], &text[word_start..i]));
+        }
+    }
+    words
+}
+
+/// Write the words of a hunk, marking removed words with `[-...-]` and
+/// added ones with `{+...+}` like `--word-diff=plain`
+fn write_word_diff(old: &[&[u8]], new: &[&[u8]], algorithm: Algorithm, out: &mut Vec<u8>) {
+    let old_text = old.concat();
+    let new_text = new.concat();
+    let old_words = split_words(&old_text);
+    let new_words = split_words(&new_text);
+    let ops = diff_lines(
+        &old_words.iter().map(|(_, w)| *w).collect::<Vec<_>>(),
+        &new_words.iter().map(|(_, w)| *w).collect::<Vec<_>>(),
+        algorithm,
+    );
+
+    let (mut a, mut b) = (0, 0);
+    let mut i = 0;
+    while i < ops.len() {
+        if ops[i] == LineOp::Equal {
+            out.extend_from_slice(new_words[b].0);
+            out.extend_from_slice(new_words[b].1);
+            a += 1;
+            b += 1;
+            i += 1;
+            continue;
+        }
+        let mut deleted = Vec::new();
+        let mut inserted = Vec::new();
+        while i < ops.len() && ops[i] != LineOp::Equal {
+            if ops[i] == LineOp::Delete {
+                deleted.push(old_words[a]);
+                a += 1;
+            } else {
+                inserted.push(new_words[b]);
+                b += 1;
+            }
+            i += 1;
+        }
+        let leading = inserted
+            .first()
+            .or(deleted.first())
+            .map_or(&b""[..], |w| w.0);
+        out.extend_from_slice(leading);
+        for (marker, words) in [(("[-", "-]"), &deleted), (("{+", "+}"), &inserted)] {
+            let Some(((_, first), rest)) = words.split_first() else {
+                continue;
+            };
+            out.extend_from_slice(marker.0.as_bytes());
+            out.extend_from_slice(first);
+            for (space, word) in rest {
+                // Markers never span lines
+                if space.contains(&b'\n') {
+                    out.extend_from_slice(marker.1.as_bytes());
+                    out.extend_from_slice(space);
+                    out.extend_from_slice(marker.0.as_bytes());
+                } else {
+                    out.extend_from_slice(space);
+                }
+                out.extend_from_slice(word);
+            }
+            out.extend_from_slice(marker.1.as_bytes());
+        }
+    }
+    if !out.ends_with(b"\n") {
+        out.push(b'\n');
+    }
+}
+
+/// Format diffs as a patch like `git diff`
+pub fn format_patch(
+    repo: &Repository,
+    diffs: &[FileDiff],
+    options: &DiffOptions,
+) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    for diff in diffs {
+        match diff.kind {
+            ChangeKind::Unmerged => {
+                writeln_bytes(&mut out, &format!("* Unmerged path {}", diff.path()));
+            }
+            // A type change is shown as a deletion followed by an addition
+            ChangeKind::TypeChanged => {
+                let deleted = FileDiff {
+                    kind: ChangeKind::Deleted,
+                    new: None,
+                    ..diff.clone()
+                };
+                let added = FileDiff {
+                    kind: ChangeKind::Added,
+                    old: None,
+                    ..diff.clone()
+                };
+                write_file_patch(repo, &deleted, options, &mut out)?;
+                write_file_patch(repo, &added, options, &mut out)?;
+            }
+            _ => write_file_patch(repo, diff, options, &mut out)?,
+        }
+    }
+    Ok(out)
+}
+
+fn writeln_bytes(out: &mut Vec<u8>, line: &str) {
+    out.extend_from_slice(line.as_bytes());
+    out.push(b'\n');
+}
+
+fn write_file_patch(
+    repo: &Repository,
+    diff: &FileDiff,
+    options: &DiffOptions,
+    out: &mut Vec<u8>,
+) -> Result<()> {
+    let old_path = diff.old.as_ref().map_or(diff.path(), |e| &e.path);
+    let new_path = diff.new.as_ref().map_or(diff.path(), |e| &e.path);
+    writeln_bytes(
+        out,
+        &format!(
+            "diff --git {} {}",
+            prefixed("a/", old_path),
+            prefixed("b/", new_path)
+        ),
+    );
+
+    let old_mode = diff.old.as_ref().map(|e| e.mode);
+    let new_mode = diff.new.as_ref().map(|e| e.mode);
+    match (old_mode, new_mode) {
+        (None, Some(mode)) => writeln_bytes(out, &format!("new file mode {}", mode.as_octal())),
+        (Some(mode), None) => writeln_bytes(out, &format!("deleted file mode {}", mode.as_octal())),
+        (Some(old), Some(new)) if old != new => {
+            writeln_bytes(out, &format!("old mode {}", old.as_octal()));
+            writeln_bytes(out, &format!("new mode {}", new.as_octal()));
+        }
+        _ => {}
+    }
+    if let Some(score) = diff.score {
+        let verb = match diff.kind {
+            ChangeKind::Copied => "copy",
+            _ => "rename",
+        };
+        writeln_bytes(out, &format!("similarity index {}%", score));
+        writeln_bytes(out, &format!("{} from {}", verb, quote_path(old_path)));
+        writeln_bytes(out, &format!("{} to {}", verb, quote_path(new_path)));
+    }
+
+    let old_hash = diff
+        .old
+        .as_ref()
+        .map(|e| e.hash.clone())
+        .unwrap_or_default();
+    let new_hash = diff
+        .new
+        .as_ref()
+        .map(|e| e.hash.clone())
+        .unwrap_or_default();
+    if old_hash == new_hash && diff.old.is_some() && diff.new.is_some() {
+        return Ok(());
+    }
+    let mut index_line = format!("index {}..{}", abbrev(&old_hash), abbrev(&new_hash));
+    if let (Some(old), Some(new)) = (old_mode, new_mode) {
+        if old == new {
+            let _ = write!(index_line, " {}", old.as_octal());
+        }
+    }
+    writeln_bytes(out, &index_line);
+
+    let old_content = diff
+        .old
+        .as_ref()
+        .map(|e| read_content(repo, e))
+        .transpose()?;
+    let new_content = diff
+        .new
+        .as_ref()
+        .map(|e| read_content(repo, e))
+        .transpose()?;
+    let old_name = match diff.old {
+        Some(_) => prefixed("a/", old_path),
+        None => "/dev/null".to_string(),
+    };
+    let new_name = match diff.new {
+        Some(_) => prefixed("b/", new_path),
+        None => "/dev/null".to_string(),
+    };
+    let old_content = old_content.unwrap_or_default();
+    let new_content = new_content.unwrap_or_default();
+    if is_binary(&old_content) || is_binary(&new_content) {
+        writeln_bytes(
+            out,
+            &format!("Binary files {} and {} differ", old_name, new_name),
+        );
+        return Ok(());
+    }
+    let mut hunks = Vec::new();
+    write_hunks(&old_content, &new_content, options, &mut hunks);
+    if !hunks.is_empty() {
+        writeln_bytes(out, &format!("--- {}", old_name));
+        writeln_bytes(out, &format!("+++ {}", new_name));
+        out.extend_from_slice(&hunks);
+    }
+    Ok(())
+}
+
+/// FileStat is the size of the change of one file for `--stat`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileStat {
+    pub name: String,
+    pub added: usize,
+    pub deleted: usize,
+    /// Binary files report their old and new sizes instead of lines
+    pub binary: bool,
+}
+
+/// Count the changed lines of every file
+pub fn diff_stats(
+    repo: &Repository,
+    diffs: &[FileDiff],
+    options: &DiffOptions,
+) -> Result<Vec<FileStat>> {
+    let mut stats = Vec::new();
+    for diff in diffs {
+        let name = match (&diff.old, &diff.new) {
+            (Some(old), Some(new)) if old.path != new.path => rename_name(&old.path, &new.path),
+            _ => diff.path().to_string(),
+        };
+        if diff.kind == ChangeKind::Unmerged {
+            stats.push(FileStat {
+                name,
+                added: 0,
+                deleted: 0,
+                binary: false,
+            });
+            continue;
+        }
+        let old = diff
+            .old
+            .as_ref()
+            .map(|e| read_content(repo, e))
+            .transpose()?;
+        let new = diff
+            .new
+            .as_ref()
+            .map(|e| read_content(repo, e))
+            .transpose()?;
+        let (old, new) = (old.unwrap_or_default(), new.unwrap_or_default());
+        let stat = if is_binary(&old) || is_binary(&new) {
+            FileStat {
+                name,
+                added: new.len(),
+                deleted: old.len(),
+                binary: true,
+            }
+        } else {
+            let ops = diff_lines(&split_lines(&old), &split_lines(&new), options.algorithm);
+            let (added, deleted) = count_lines(&ops);
+            FileStat {
+                name,
+                added,
+                deleted,
+                binary: false,
+            }
+        };
+        stats.push(stat);
+    }
+    Ok(stats)
+}
+
+/// Shorten a rename to `common/{old => new}/suffix` as git does
+fn rename_name(old: &str, new: &str) -> String {
+    let (a, b) = (old.as_bytes(), new.as_bytes());
+    let mut prefix = 0;
+    for (i, (x, y)) in a.iter().zip(b).enumerate() {
+        if x != y {
+            break;
+        }
+        if *x == b'/' {
+            prefix = i + 1;
+        }
+    }
+    // The suffix may reach into the prefix's trailing slash, never further
+    let limit = prefix.saturating_sub(1);
+    let mut suffix = 0;
+    let (mut i, mut j) = (a.len(), b.len());
+    while i > limit && j > limit && a[i - 1] == b[j - 1] {
+        i -= 1;
+        j -= 1;
+        if a[i] == b'/' {
+            suffix = a.len() - i;
+        }
+    }
+    if prefix + suffix == 0 {
+        return format!("{} => {}", old, new);
+    }
+    let a_mid = &old[prefix..a.len().saturating_sub(suffix).max(prefix)];
+    let b_mid = &new[prefix..b.len().saturating_sub(suffix).max(prefix)];
+    format!(
+        "{}{{{} => {}}}{}",
+        &old[..prefix],
+        a_mid,
+        b_mid,
+        &old[a.len() - suffix..]
+    )
+}
+
+/// Format the `--stat` summary, scaling the graph to 80 columns
+pub fn format_stat(stats: &[FileStat]) -> String {
+    let width = 80;
+    let max_len = stats
+        .iter()
+        .map(|s| s.name.chars().count())
+        .max()
+        .unwrap_or(0);
+    let max_change = stats
+        .iter()
+        .filter(|s| !s.binary)
+        .map(|s| s.added + s.deleted)
+        .max()
+        .unwrap_or(0);
+    let bin_width = stats
+        .iter()
+        .filter(|s| s.binary)
+        .map(|s| 14 + s.added.to_string().len() + s.deleted.to_string().len())
+        .max()
+        .unwrap_or(0);
+    let mut number_width = if bin_width > 0 { 3 } else { 0 };
+    number_width = number_width.max(max_change.to_string().len());
+
+    let mut graph_width = if max_change + 4 > bin_width {
+        max_change
+    } else {
+        bin_width - 4
+    };
+    let mut name_width = max_len;
+    if name_width + number_width + 6 + graph_width > width {
+        let limit = (width * 3 / 8).saturating_sub(number_width + 6);
+        if graph_width > limit {
+            graph_width = limit.max(6);
+        }
+        if name_width > width - number_width - 6 - graph_width {
+            name_width = width - number_width - 6 - graph_width;
+        } else {
+            graph_width = width - number_width - 6 - name_width;
+        }
+    }
+
+    let scale = |n: usize| match n {
+        0 => 0,
+        _ => 1 + n * (graph_width - 1) / max_change,
+    };
+    let mut out = String::new();
+    let (mut insertions, mut deletions) = (0, 0);
+    for stat in stats {
+        let len = stat.name.chars().count();
+        let name = if len > name_width {
+            let skip = len - name_width + 3;
+            let tail = stat.name.chars().skip(skip).collect::<String>();
+            // Prefer cutting at a directory boundary
+            match tail.find('/') {
+                Some(slash) => format!("...{}", &tail[slash..]),
+                None => format!("...{}", tail),
+            }
+        } else {
+            stat.name.clone()
+        };
+        let padding = name_width.saturating_sub(name.chars().count());
+        let _ = write!(out, " {}{} |", name, " ".repeat(padding));
+        if stat.binary {
+            let _ = write!(out, " {:>width$}", "Bin", width = number_width);
+            if stat.added != 0 || stat.deleted != 0 {
+                let _ = write!(out, " {} -> {} bytes", stat.deleted, stat.added);
+            }
+            out.push('\n');
+            continue;
+        }
+        insertions += stat.added;
+        deletions += stat.deleted;
+        let (mut added, mut deleted) = (stat.added, stat.deleted);
+        if graph_width <= max_change {
+            let mut total = scale(added + deleted);
+            if total < 2 && added > 0 && deleted > 0 {
+                total = 2;
+            }
+            if added < deleted {
+                added = scale(added);
+                deleted = total - added;
+            } else {
+                deleted = scale(deleted);
+                added = total - deleted;
+            }
+        }
+        let total = stat.added + stat.deleted;
+        let _ = writeln!(
+            out,
+            " {:>width$}{}{}{}",
+            total,
+            if total > 0 { " " } else { "" },
+            "+".repeat(added),
+            "-".repeat(deleted),
+            width = number_width
+        );
+    }
+    out.push_str(&stat_summary(stats.len(), insertions, deletions));
+    out.push('\n');
+    out
+}
+
+/// The closing line of `--stat`, e.g. ` 1 file changed, 2 insertions(+)`
+pub fn stat_summary(files: usize, insertions: usize, deletions: usize) -> String {
+    if files == 0 {
+        return " 0 files changed".to_string();
+    }
+    let plural = |n: usize, one: &str, many: &str| match n {
+        1 => one.to_string(),
+        _ => many.to_string(),
+    };
+    let mut summary = format!(" {} {} changed", files, plural(files, "file", "files"));
+    if insertions > 0 || deletions == 0 {
+        let _ = write!(
+            summary,
+            ", {} {}(+)",
+            insertions,
+            plural(insertions, "insertion", "insertions")
+        );
+    }
+    if deletions > 0 || insertions == 0 {
+        let _ = write!(
+            summary,
+            ", {} {}(-)",
+            deletions,
+            plural(deletions, "deletion", "deletions")
+        );
+    }
+    summary
+}
+
+/// Format the creations, deletions, renames and mode changes as `--summary` does
+pub fn format_summary(diffs: &[FileDiff]) -> String {
+    let mut out = String::new();
+    for diff in diffs {
+        match (diff.kind, &diff.old, &diff.new) {
+            (ChangeKind::Added, _, Some(new)) => {
+                let _ = writeln!(out, " create mode {} {}", new.mode.as_octal(), new.path);
+            }
+            (ChangeKind::Deleted, Some(old), _) => {
+                let _ = writeln!(out, " delete mode {} {}", old.mode.as_octal(), old.path);
+            }
+            (ChangeKind::Renamed | ChangeKind::Copied, Some(old), Some(new)) => {
+                let verb = match diff.kind {
+                    ChangeKind::Renamed => "rename",
+                    _ => "copy",
+                };
+                let _ = writeln!(
+                    out,
+                    " {} {} ({}%)",
+                    verb,
+                    rename_name(&old.path, &new.path),
+                    diff.score.unwrap_or_default()
+                );
+            }
+            (_, Some(old), Some(new)) if old.mode != new.mode => {
+                let _ = writeln!(
+                    out,
+                    " mode change {} => {} {}",
+                    old.mode.as_octal(),
+                    new.mode.as_octal(),
+                    new.path
+                );
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+/// Format diffs as `--name-status` does
+pub fn format_name_status(diffs: &[FileDiff]) -> String {
+    let mut out = String::new();
+    for diff in diffs {
+        let status = diff.kind.as_char();
+        match (diff.kind, &diff.old) {
+            (ChangeKind::Renamed | ChangeKind::Copied, Some(old)) => {
+                let _ = writeln!(
+                    out,
+                    "{}{:03}\t{}\t{}",
+                    status,
+                    diff.score.unwrap_or_default(),
+                    quote_path(&old.path),
+                    quote_path(diff.path())
+                );
+            }
+            _ => {
+                let _ = writeln!(out, "{}\t{}", status, quote_path(diff.path()));
+            }
+        }
+    }
+    out
+}
+
+/// Format diffs as `--name-only` does
+pub fn format_name_only(diffs: &[FileDiff]) -> String {
+    diffs
+        .iter()
+        .map(|d| format!("{}\n", quote_path(d.path())))
+        .collect()
+}
+
+/// Format diffs in the raw format of `diff-tree`
+pub fn format_raw(diffs: &[FileDiff]) -> String {
+    let mut out = String::new();
+    for diff in diffs {
+        let algorithm = [&diff.old, &diff.new]
+            .into_iter()
+            .flatten()
+            .next()
+            .map_or(HashAlgorithm::Sha1, |e| e.hash.algorithm());
+        let mode = |e: &Option<DiffEntry>| e.as_ref().map_or(0, |e| e.mode.bits());
+        let hash = |e: &Option<DiffEntry>| match e {
+            Some(e) if !e.worktree => e.hash.to_hex(),
+            _ => algorithm.null_hash().to_hex(),
+        };
+        let _ = write!(
+            out,
+            ":{:06o} {:06o} {} {} {}",
+            mode(&diff.old),
+            mode(&diff.new),
+            hash(&diff.old),
+            hash(&diff.new),
+            diff.kind.as_char()
+        );
+        match (diff.kind, &diff.old) {
+            (ChangeKind::Renamed | ChangeKind::Copied, Some(old)) => {
+                let _ = writeln!(
+                    out,
+                    "{:03}\t{}\t{}",
+                    diff.score.unwrap_or_default(),
+                    quote_path(&old.path),
+                    quote_path(diff.path())
+                );
+            }
+            _ => {
+                let _ = writeln!(out, "\t{}", quote_path(diff.path()));
+            }
+        }
+    }
+    out
+}
+
+/// Parse a `--word-diff` mode, of which only `plain` is supported
+pub fn parse_word_diff_mode(mode: &str) -> Result<bool> {
+    match mode {
+        "plain" => Ok(true),
+        "none" => Ok(false),
+        other => bail!("Unsupported word diff mode: {}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::IndexEntry;
+    use crate::test_utils::{init_repo, write_blob, write_tree};
+
+    fn patch(old: &str, new: &str, options: &DiffOptions) -> String {
+        let mut out = Vec::new();
+        write_hunks(old.as_bytes(), new.as_bytes(), options, &mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_diff_lines_algorithms() {
+        let old = split_lines(b"a\nb\nc\nd\ne\n");
+        let new = split_lines(b"a\nc\nd\nx\ne\n");
+        for algorithm in [Algorithm::Myers, Algorithm::Histogram] {
+            let ops = diff_lines(&old, &new, algorithm);
+            assert_eq!(
+                ops,
+                vec![
+                    LineOp::Equal,
+                    LineOp::Delete,
+                    LineOp::Equal,
+                    LineOp::Equal,
+                    LineOp::Insert,
+                    LineOp::Equal
+                ],
+                "{}",
+                algorithm
+            );
+        }
+    }
+
+    #[test]
+    fn test_diff_lines_random_edits() {
+        // Both algorithms must produce a valid edit script
+        let old = (0..200)
+            .map(|i| format!("{}\n", i % 17))
+            .collect::<Vec<_>>();
+        let new = (0..180)
+            .map(|i| format!("{}\n", (i * 7) % 13))
+            .collect::<Vec<_>>();
+        let old = old.iter().map(|l| l.as_bytes()).collect::<Vec<_>>();
+        let new = new.iter().map(|l| l.as_bytes()).collect::<Vec<_>>();
+        for algorithm in [Algorithm::Myers, Algorithm::Histogram] {
+            let ops = diff_lines(&old, &new, algorithm);
+            let (mut a, mut b) = (0, 0);
+            for op in ops {
+                match op {
+                    LineOp::Equal => {
+                        assert_eq!(old[a], new[b]);
+                        a += 1;
+                        b += 1;
+                    }
+                    LineOp::Delete => a += 1,
+                    LineOp::Insert => b += 1,
+                }
+            }
+            assert_eq!((a, b), (old.len(), new.len()));
+        }
+    }
+
+    #[test]
+    fn test_unified_hunks() {
+        let old = "fn main() {\n1\n2\n3\n4\n5\n6\n7\n8\n9\n}\n";
+        let new = "fn main() {\n1\n2\n3\n4\nfive\n6\n7\n8\n9\n}\n";
+        assert_eq!(
+            patch(old, new, &DiffOptions::default()),
+            "@@ -3,7 +3,7 @@ fn main() {\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n"
+        );
+        let options = DiffOptions {
+            context: 0,
+            ..Default::default()
+        };
+        assert_eq!(
+            patch("a\nb", "a\n", &options),
+            "@@ -2 +1,0 @@ a\n-b\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn test_slide_insertions_down() {
+        let ops = diff_lines(
+            &split_lines(b"a\n}\n"),
+            &split_lines(b"a\n}\nb\n}\n"),
+            Algorithm::Myers,
+        );
+        assert_eq!(
+            ops,
+            vec![LineOp::Equal, LineOp::Equal, LineOp::Insert, LineOp::Insert]
+        );
+    }
+
+    #[test]
+    fn test_word_diff() {
+        let options = DiffOptions {
+            word_diff: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            patch("the quick fox\n", "the slow fox\n", &options),
+            "@@ -1 +1 @@\nthe [-quick-]{+slow+} fox\n"
+        );
+    }
+
+    #[test]
+    fn test_similarity_and_binary() {
+        let text = (0..20).map(|i| format!("line {}\n", i)).collect::<String>();
+        let edited = text.replace("line 3\n", "changed\n");
+        assert_eq!(similarity(text.as_bytes(), text.as_bytes()), 100);
+        assert!(similarity(text.as_bytes(), edited.as_bytes()) >= 90);
+        assert!(similarity(b"abc\n", b"xyz\n") < 50);
+        assert!(is_binary(b"a\0b"));
+        assert!(!is_binary(b"text\n"));
+    }
+
+    #[test]
+    fn test_diff_trees_with_renames() {
+        let (_dir, repo) = init_repo();
+        let body = (0..20).map(|i| format!("line {}\n", i)).collect::<String>();
+        let edited = body.replace("line 3\n", "changed\n");
+        let old = write_tree(
+            &repo,
+            &[("a.txt", &body), ("keep.txt", "keep"), ("gone", "x")],
+        );
+        let new = write_tree(&repo, &[("b.txt", &edited), ("keep.txt", "kept")]);
+
+        let diffs = diff_trees(&repo, Some(&old), Some(&new), &DiffOptions::default()).unwrap();
+        assert_eq!(
+            format_name_status(&diffs),
+            "R094\ta.txt\tb.txt\nD\tgone\nM\tkeep.txt\n"
+        );
+
+        let no_renames = DiffOptions {
+            renames: None,
+            ..Default::default()
+        };
+        let diffs = diff_trees(&repo, Some(&old), Some(&new), &no_renames).unwrap();
+        assert_eq!(
+            format_name_status(&diffs),
+            "D\ta.txt\nA\tb.txt\nD\tgone\nM\tkeep.txt\n"
+        );
+    }
+
+    #[test]
+    fn test_copy_detection() {
+        let (_dir, repo) = init_repo();
+        let body = (0..20).map(|i| format!("line {}\n", i)).collect::<String>();
+        let old = write_tree(&repo, &[("a.txt", &body)]);
+        let new = write_tree(&repo, &[("a.txt", &body), ("copy.txt", &body)]);
+        let options = DiffOptions {
+            copies: true,
+            find_copies_harder: true,
+            ..Default::default()
+        };
+        let diffs = diff_trees(&repo, Some(&old), Some(&new), &options).unwrap();
+        assert_eq!(format_name_status(&diffs), "C100\ta.txt\tcopy.txt\n");
+    }
+
+    #[test]
+    fn test_format_patch() {
+        let (_dir, repo) = init_repo();
+        let old = write_tree(&repo, &[("a.txt", "one\ntwo\n")]);
+        let new = write_tree(&repo, &[("a.txt", "one\n2\n"), ("bin", "\0\x01")]);
+        let diffs = diff_trees(&repo, Some(&old), Some(&new), &DiffOptions::default()).unwrap();
+        let patch =
+            String::from_utf8(format_patch(&repo, &diffs, &DiffOptions::default()).unwrap())
+                .unwrap();
+        let expected = format!(
+            "diff --git a/a.txt b/a.txt\nindex {}..{} 100644\n--- a/a.txt\n+++ b/a.txt\n\
+             @@ -1,2 +1,2 @@\n one\n-two\n+2\n\
+             diff --git a/bin b/bin\nnew file mode 100644\nindex 0000000..{}\n\
+             Binary files /dev/null and b/bin differ\n",
+            &write_blob(&repo, b"one\ntwo\n").to_hex()[..7],
+            &write_blob(&repo, b"one\n2\n").to_hex()[..7],
+            &write_blob(&repo, b"\0\x01").to_hex()[..7],
+        );
+        assert_eq!(patch, expected);
+    }
+
+    #[test]
+    fn test_index_to_worktree() {
+        let (dir, repo) = init_repo();
+        let hash = write_blob(&repo, b"old\n");
+        let mut index = Index::default();
+        index
+            .add(IndexEntry::new("a.txt", EntryMode::Blob, hash, None))
+            .unwrap();
+        fs::write(dir.path().join("a.txt"), "new\n").unwrap();
+        let diffs = diff_index_to_worktree(&repo, &index, &DiffOptions::default()).unwrap();
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].kind, ChangeKind::Modified);
+        let patch = format_patch(&repo, &diffs, &DiffOptions::default()).unwrap();
+        assert!(String::from_utf8(patch).unwrap().ends_with("-old\n+new\n"));
+    }
+
+    #[test]
+    fn test_stat() {
+        let stats = vec![
+            FileStat {
+                name: "src/{a.rs => b.rs}".to_string(),
+                added: 3,
+                deleted: 1,
+                binary: false,
+            },
+            FileStat {
+                name: "img.png".to_string(),
+                added: 20,
+                deleted: 10,
+                binary: true,
+            },
+        ];
+        assert_eq!(
+            format_stat(&stats),
+            " src/{a.rs => b.rs} |   4 +++-\n img.png            | Bin 10 -> 20 bytes\n \
+             2 files changed, 3 insertions(+), 1 deletion(-)\n"
+        );
+        assert_eq!(rename_name("src/a.rs", "src/b.rs"), "src/{a.rs => b.rs}");
+        assert_eq!(rename_name("a/x/f", "b/x/f"), "{a => b}/x/f");
+        assert_eq!(rename_name("a", "b"), "a => b");
+    }
+}
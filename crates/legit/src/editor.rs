@@ -0,0 +1,81 @@
+use crate::gitconfig::GitConfig;
+use crate::merge::strip_comments;
+use crate::refs::write_atomic;
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::process::Command;
+
+/// Return the editor used for commit messages, like `git var GIT_EDITOR`
+///
+/// `GIT_EDITOR` takes precedence over `core.editor`, then `VISUAL` and
+/// `EDITOR`, falling back to `vi`.
+pub fn editor(repo: &Repository) -> Result<String> {
+    if let Ok(editor) = std::env::var("GIT_EDITOR") {
+        return Ok(editor);
+    }
+    if let Some(editor) = config_value(repo, "core.editor")? {
+        return Ok(editor);
+    }
+    Ok(std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string()))
+}
+
+/// Return the editor used for rebase todo lists, like `git var GIT_SEQUENCE_EDITOR`
+pub fn sequence_editor(repo: &Repository) -> Result<String> {
+    if let Ok(editor) = std::env::var("GIT_SEQUENCE_EDITOR") {
+        return Ok(editor);
+    }
+    match config_value(repo, "sequence.editor")? {
+        Some(editor) => Ok(editor),
+        None => editor(repo),
+    }
+}
+
+/// Run `editor` on a file from the root of the working tree
+///
+/// The editor is a shell command, so it may carry its own arguments. `:` is
+/// treated as an editor that leaves the file unchanged.
+pub fn launch(repo: &Repository, editor: &str, path: &Path) -> Result<()> {
+    if editor == ":" {
+        return Ok(());
+    }
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(editor)
+        .arg(path)
+        .current_dir(repo.worktree())
+        .status()
+        .with_context(|| format!("Failed to run editor '{}'", editor))?;
+    if !status.success() {
+        bail!("There was a problem with the editor '{}'.", editor);
+    }
+    Ok(())
+}
+
+/// Let the user edit a commit message in `.git/COMMIT_EDITMSG` and return
+/// it without its comment lines
+pub fn edit_message(repo: &Repository, message: &str) -> Result<String> {
+    let path = repo.gitdir().join("COMMIT_EDITMSG");
+    let text = format!(
+        "{}\n# Please enter the commit message for your changes. Lines starting\n# with '#' will be ignored, and an empty message aborts the commit.\n",
+        message.trim_end()
+    );
+    write_atomic(&path, text.as_bytes())?;
+    launch(repo, &editor(repo)?, &path)?;
+    let message = strip_comments(&std::fs::read_to_string(&path)?);
+    if message.is_empty() {
+        bail!("Aborting commit due to empty commit message.");
+    }
+    Ok(message)
+}
+
+fn config_value(repo: &Repository, key: &str) -> Result<Option<String>> {
+    let value = repo.config()?.get(key).map(str::to_string);
+    match value {
+        Some(value) => Ok(Some(value)),
+        None => Ok(GitConfig::global()?.get(key).map(str::to_string)),
+    }
+}
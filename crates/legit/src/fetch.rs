@@ -0,0 +1,718 @@
+use crate::checkout::checkout_tree;
+use crate::commits::Commit;
+use crate::merge_base::is_ancestor;
+use crate::objects::{object_exists, ObjectHash};
+use crate::promisor::{self, promisor_scope, ObjectFilter};
+use crate::refs::{
+    list_refs, read_head, resolve_ref, set_head, short_name, update_ref, update_symbolic_ref,
+    write_atomic, Head,
+};
+use crate::remote::{RefSpec, Remote};
+use crate::revision::peel_to_commit;
+use crate::shallow::{is_shallow, Deepen, INFINITE_DEPTH};
+use crate::transport::{self, FetchScope, LocalTransport, RemoteRef, Transport, Url};
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::Path;
+
+/// Width of the summary column of fetch output, e.g. `[new branch]`
+const SUMMARY_WIDTH: usize = 17;
+
+/// Minimum width of the remote reference column of fetch output
+const REF_WIDTH: usize = 10;
+
+/// UpdateStatus is what happened to a local reference during a fetch
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpdateStatus {
+    /// The reference did not exist
+    New,
+    /// The reference was moved to a descendant of its old value
+    FastForward { old: ObjectHash },
+    /// The reference was moved to a commit that does not descend from it
+    Forced { old: ObjectHash },
+    /// The reference already had the fetched value
+    UpToDate,
+    /// The update was refused because it is not a fast-forward
+    Rejected { old: ObjectHash },
+    /// The update was refused because the tag already exists
+    TagExists { old: ObjectHash },
+}
+
+/// RefUpdate is a fetched remote reference and the local one it updated
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefUpdate {
+    pub remote_name: String,
+    pub local_name: String,
+    pub hash: ObjectHash,
+    pub status: UpdateStatus,
+}
+
+impl RefUpdate {
+    /// Return true if the update was refused
+    pub fn is_rejected(&self) -> bool {
+        matches!(
+            self.status,
+            UpdateStatus::Rejected { .. } | UpdateStatus::TagExists { .. }
+        )
+    }
+}
+
+/// FetchResult lists what a fetch did
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FetchResult {
+    pub url: String,
+    pub updates: Vec<RefUpdate>,
+    /// The references the remote advertised
+    pub remote_refs: Vec<RemoteRef>,
+}
+
+/// FetchOptions controls what `fetch` asks for and how it reports it
+#[derive(Debug, Clone, Default)]
+pub struct FetchOptions {
+    /// Refspecs replacing the remote's configured ones
+    pub refspecs: Vec<String>,
+    /// Show the progress messages of the remote on stderr
+    pub progress: bool,
+    /// Limit the history to this many commits from each fetched one
+    pub depth: Option<u32>,
+    /// Limit the history to the commits made at or after this time
+    pub shallow_since: Option<i64>,
+    /// Fetch the whole history of a shallow repository
+    pub unshallow: bool,
+    /// Leave out the objects this filter excludes, making the repository a
+    /// partial clone of the remote
+    pub filter: Option<ObjectFilter>,
+}
+
+impl FetchOptions {
+    /// Return how the fetch is narrowed: by the options, and for a promisor
+    /// remote by its filter
+    fn scope(&self, repo: &Repository, remote: &Remote) -> Result<FetchScope> {
+        let deepen = match (self.depth, self.shallow_since, self.unshallow) {
+            (None, None, false) => None,
+            (Some(depth), None, false) => Some(Deepen::Depth(depth)),
+            (None, Some(since), false) => Some(Deepen::Since(since)),
+            (None, None, true) if is_shallow(repo) => Some(Deepen::Depth(INFINITE_DEPTH)),
+            (None, None, true) => bail!("--unshallow on a complete repository does not make sense"),
+            _ => bail!("--depth, --shallow-since and --unshallow cannot be used together"),
+        };
+        if let (Some(filter), true) = (&self.filter, remote.is_configured()) {
+            promisor::register(repo, &remote.name, filter)?;
+        }
+        let mut scope = match &self.filter {
+            Some(filter) => FetchScope {
+                filter: Some(*filter),
+                promisor: true,
+                ..FetchScope::default()
+            },
+            None => promisor_scope(repo, &remote.name)?.unwrap_or_default(),
+        };
+        scope.deepen = deepen;
+        Ok(scope)
+    }
+}
+
+/// Fetch from a configured remote or a repository URL, like `git fetch`
+pub fn fetch(repo: &Repository, remote: &str, options: &FetchOptions) -> Result<FetchResult> {
+    let remote = Remote::resolve(repo, remote)?;
+    let scope = options.scope(repo, &remote)?;
+    let mut transport = transport::open(repo, &remote.url)?;
+    transport.set_progress(options.progress);
+    let refspecs = match options.refspecs.is_empty() {
+        true => remote.fetch.clone(),
+        false => options
+            .refspecs
+            .iter()
+            .map(|spec| RefSpec::parse(spec))
+            .collect::<Result<Vec<_>>>()?,
+    };
+    fetch_with(repo, &remote, transport.as_mut(), &refspecs, &scope)
+}
+
+/// Fetch the references matching `refspecs` through a transport, narrowed
+/// by `scope`
+///
+/// Tags pointing to fetched commits are fetched as well. Every fetched
+/// reference is recorded in `FETCH_HEAD`, with those a `pull` would merge
+/// listed first.
+pub fn fetch_with(
+    repo: &Repository,
+    remote: &Remote,
+    transport: &mut dyn Transport,
+    refspecs: &[RefSpec],
+    scope: &FetchScope,
+) -> Result<FetchResult> {
+    if *scope != FetchScope::default() {
+        transport.set_scope(scope)?;
+    }
+    let remote_refs = transport.list_refs()?;
+    if let Some(remote_ref) = remote_refs
+        .iter()
+        .find(|r| r.hash.algorithm() != repo.hash_algorithm())
+    {
+        bail!(
+            "The remote repository uses {} object names but this one uses {}",
+            remote_ref.hash.algorithm(),
+            repo.hash_algorithm()
+        );
+    }
+    let mut fetched = Vec::new();
+    let mut updates = Vec::new();
+    // Without refspecs, the remote's HEAD is fetched into FETCH_HEAD only
+    let default_spec = [RefSpec::parse("HEAD")?];
+    let specs = match refspecs.is_empty() {
+        true => &default_spec[..],
+        false => refspecs,
+    };
+    for spec in specs {
+        let mut matched = false;
+        for remote_ref in remote_refs.iter().filter(|r| matches_ref(spec, &r.name)) {
+            matched = true;
+            fetched.push((remote_ref.clone(), false));
+            if let Some(local_name) = spec
+                .map(&remote_ref.name)
+                .or_else(|| short_map(spec, remote_ref))
+            {
+                updates.push((spec.force, remote_ref.clone(), local_name));
+            }
+        }
+        if !matched && !spec.is_pattern() {
+            bail!("couldn't find remote ref {}", spec.src);
+        }
+    }
+    // With the configured refspecs only the branch the current branch tracks
+    // is merged, otherwise everything requested is; these are listed first
+    let merge = merge_ref(repo, remote)?;
+    let configured = remote.is_configured() && refspecs == remote.fetch.as_slice();
+    let for_merge = |r: &RemoteRef| !configured || merge.as_deref() == Some(r.name.as_str());
+    fetched.sort_by_key(|(r, _)| !for_merge(r));
+    for (remote_ref, merged) in fetched.iter_mut() {
+        *merged = for_merge(remote_ref);
+    }
+    updates.sort_by_key(|(_, r, _)| !for_merge(r));
+
+    // Deepening may need the history of commits we already have
+    let haves = local_tips(repo)?;
+    let mut wants = fetched
+        .iter()
+        .map(|(r, _)| r.hash.clone())
+        .filter(|hash| scope.deepen.is_some() || !object_exists(repo, hash))
+        .collect::<Vec<_>>();
+    wants.sort();
+    wants.dedup();
+    if !wants.is_empty() {
+        transport.fetch(repo, &wants, &haves)?;
+    }
+
+    // Follow the tags that point into what we now have
+    let tags = remote_refs
+        .iter()
+        .filter(|r| r.name.starts_with("refs/tags/") && !r.name.ends_with("^{}"))
+        .filter(|r| !updates.iter().any(|(_, u, _)| u.name == r.name))
+        .filter(|r| resolve_ref(repo, &r.name).ok().flatten().is_none())
+        .filter(|r| object_exists(repo, r.peeled.as_ref().unwrap_or(&r.hash)))
+        .cloned()
+        .collect::<Vec<_>>();
+    let wants = tags
+        .iter()
+        .map(|r| r.hash.clone())
+        .filter(|hash| !object_exists(repo, hash))
+        .collect::<Vec<_>>();
+    if !wants.is_empty() {
+        // The tags point into history we have, which is not to be cut
+        if scope.deepen.is_some() {
+            transport.set_scope(&FetchScope {
+                deepen: None,
+                ..scope.clone()
+            })?;
+        }
+        transport.fetch(repo, &wants, &local_tips(repo)?)?;
+    }
+    for tag in tags {
+        updates.push((false, tag.clone(), tag.name.clone()));
+        fetched.push((tag, false));
+    }
+
+    let mut result = Vec::new();
+    for (force, remote_ref, local_name) in updates {
+        let status = update_local_ref(repo, &local_name, &remote_ref.hash, force)?;
+        result.push(RefUpdate {
+            remote_name: remote_ref.name,
+            local_name,
+            hash: remote_ref.hash,
+            status,
+        });
+    }
+    write_fetch_head(repo, remote, &fetched)?;
+    Ok(FetchResult {
+        url: remote.url.clone(),
+        updates: result,
+        remote_refs,
+    })
+}
+
+/// Match a refspec source against a remote reference, accepting short
+/// names like `main` for `refs/heads/main`
+fn matches_ref(spec: &RefSpec, name: &str) -> bool {
+    if spec.matches(name) {
+        return true;
+    }
+    !spec.is_pattern()
+        && ["refs/", "refs/tags/", "refs/heads/"]
+            .iter()
+            .any(|prefix| format!("{}{}", prefix, spec.src) == name)
+}
+
+/// Map a refspec with a short source and destination, e.g. `main:tmp`
+fn short_map(spec: &RefSpec, remote_ref: &RemoteRef) -> Option<String> {
+    let dst = spec.dst.as_ref()?;
+    if spec.is_pattern() || !matches_ref(spec, &remote_ref.name) {
+        return None;
+    }
+    match dst.starts_with("refs/") {
+        true => Some(dst.clone()),
+        false => Some(format!("refs/heads/{}", dst)),
+    }
+}
+
+/// Return the commits our references point to, to tell the remote what we have
+fn local_tips(repo: &Repository) -> Result<Vec<ObjectHash>> {
+    let mut refs = list_refs(repo, "refs/")?.into_values().collect::<Vec<_>>();
+    refs.extend(resolve_ref(repo, "HEAD")?);
+    // Tags of trees and blobs have no history to negotiate
+    let mut tips = refs
+        .iter()
+        .filter_map(|hash| peel_to_commit(repo, hash).ok())
+        .collect::<Vec<_>>();
+    tips.sort();
+    tips.dedup();
+    Ok(tips)
+}
+
+/// Point a local reference to a fetched value, refusing non-fast-forward
+/// updates unless forced
+fn update_local_ref(
+    repo: &Repository,
+    name: &str,
+    hash: &ObjectHash,
+    force: bool,
+) -> Result<UpdateStatus> {
+    let status = match resolve_ref(repo, name)? {
+        None => UpdateStatus::New,
+        Some(old) if old == *hash => return Ok(UpdateStatus::UpToDate),
+        Some(old) if name.starts_with("refs/tags/") && !force => {
+            return Ok(UpdateStatus::TagExists { old })
+        }
+        Some(old) if is_commit_ancestor(repo, &old, hash) => UpdateStatus::FastForward { old },
+        Some(old) if force => UpdateStatus::Forced { old },
+        Some(old) => return Ok(UpdateStatus::Rejected { old }),
+    };
+    update_ref(repo, name, hash)?;
+    Ok(status)
+}
+
+fn is_commit_ancestor(repo: &Repository, old: &ObjectHash, new: &ObjectHash) -> bool {
+    let commits = Commit::read(repo, old).is_ok() && Commit::read(repo, new).is_ok();
+    commits && is_ancestor(repo, old, new).unwrap_or(false)
+}
+
+/// Return the remote reference a `pull` would merge: the branch the current
+/// branch tracks on this remote
+fn merge_ref(repo: &Repository, remote: &Remote) -> Result<Option<String>> {
+    let Head::Branch(name) = read_head(repo)? else {
+        return Ok(None);
+    };
+    let config = repo.config()?;
+    let branch = short_name(&name);
+    if config.get(&format!("branch.{}.remote", branch)) != Some(remote.name.as_str()) {
+        return Ok(None);
+    }
+    Ok(config
+        .get(&format!("branch.{}.merge", branch))
+        .map(str::to_string))
+}
+
+/// Record the fetched references in `FETCH_HEAD`, each with whether a
+/// `pull` would merge it
+fn write_fetch_head(
+    repo: &Repository,
+    remote: &Remote,
+    fetched: &[(RemoteRef, bool)],
+) -> Result<()> {
+    let mut lines = String::new();
+    for (remote_ref, for_merge) in fetched {
+        let description = match remote_ref.name.as_str() {
+            "HEAD" => remote.url.clone(),
+            name => match (
+                name.strip_prefix("refs/heads/"),
+                name.strip_prefix("refs/tags/"),
+            ) {
+                (Some(branch), _) => format!("branch '{}' of {}", branch, remote.url),
+                (_, Some(tag)) => format!("tag '{}' of {}", tag, remote.url),
+                _ => format!("'{}' of {}", name, remote.url),
+            },
+        };
+        let marker = if *for_merge { "" } else { "not-for-merge" };
+        lines.push_str(&format!(
+            "{}\t{}\t{}\n",
+            remote_ref.hash, marker, description
+        ));
+    }
+    write_atomic(&repo.gitdir().join("FETCH_HEAD"), lines.as_bytes())
+}
+
+/// Format the updates of a fetch the way git reports them
+///
+/// References that were already up to date are left out.
+pub fn format_updates(result: &FetchResult) -> String {
+    let shown = result
+        .updates
+        .iter()
+        .filter(|u| u.status != UpdateStatus::UpToDate)
+        .collect::<Vec<_>>();
+    if shown.is_empty() {
+        return String::new();
+    }
+    let width = shown
+        .iter()
+        .map(|u| short_name(&u.remote_name).len())
+        .fold(REF_WIDTH, usize::max);
+    let mut out = format!("From {}\n", result.url);
+    for update in shown {
+        let abbrev = |hash: &ObjectHash| hash.to_hex()[..7].to_string();
+        let kind = match update.local_name.split('/').nth(1) {
+            Some("tags") => "tag",
+            Some("heads") | Some("remotes") => "branch",
+            _ => "ref",
+        };
+        let (flag, summary, suffix) = match &update.status {
+            UpdateStatus::New => ('*', format!("[new {}]", kind), ""),
+            UpdateStatus::FastForward { old } => (
+                ' ',
+                format!("{}..{}", abbrev(old), abbrev(&update.hash)),
+                "",
+            ),
+            UpdateStatus::Forced { old } => (
+                '+',
+                format!("{}...{}", abbrev(old), abbrev(&update.hash)),
+                "  (forced update)",
+            ),
+            UpdateStatus::Rejected { .. } => {
+                ('!', "[rejected]".to_string(), "  (non-fast-forward)")
+            }
+            UpdateStatus::TagExists { .. } => (
+                '!',
+                "[rejected]".to_string(),
+                "  (would clobber existing tag)",
+            ),
+            UpdateStatus::UpToDate => continue,
+        };
+        out.push_str(&format!(
+            " {} {:<summary_width$} {:<width$} -> {}{}\n",
+            flag,
+            summary,
+            short_name(&update.remote_name),
+            short_name(&update.local_name),
+            suffix,
+            summary_width = SUMMARY_WIDTH,
+        ));
+    }
+    out
+}
+
+/// CloneOptions controls how `clone` sets up the new repository
+#[derive(Debug, Clone)]
+pub struct CloneOptions {
+    /// The branch to check out instead of the remote's HEAD
+    pub branch: Option<String>,
+    /// The name of the remote
+    pub origin: String,
+    /// Hardlink the source's object files instead of copying what is needed
+    pub local: bool,
+    /// Do not check out HEAD after cloning
+    pub no_checkout: bool,
+    /// Show the progress messages of the remote on stderr
+    pub progress: bool,
+    /// Only fetch this many commits of history
+    pub depth: Option<u32>,
+    /// Only fetch the commits made at or after this time
+    pub shallow_since: Option<i64>,
+    /// Leave out the objects this filter excludes, for the remote to send
+    /// when they are needed
+    pub filter: Option<ObjectFilter>,
+}
+
+impl Default for CloneOptions {
+    fn default() -> Self {
+        CloneOptions {
+            branch: None,
+            origin: "origin".to_string(),
+            local: false,
+            no_checkout: false,
+            progress: false,
+            depth: None,
+            shallow_since: None,
+            filter: None,
+        }
+    }
+}
+
+/// Clone a repository into a new directory, like `git clone`
+///
+/// Every branch of the remote becomes a remote-tracking branch and the
+/// remote's HEAD branch, or `options.branch`, is checked out.
+pub fn clone(url: &str, path: &Path, options: &CloneOptions) -> Result<Repository> {
+    if path.exists() && fs::read_dir(path)?.next().is_some() {
+        bail!(
+            "destination path '{}' already exists and is not an empty directory.",
+            path.display()
+        );
+    }
+    // A plain path is recorded absolute and read directly
+    let source = match Url::parse(url)? {
+        Url::Local(source) => Some(
+            fs::canonicalize(&source)
+                .with_context(|| format!("repository '{}' does not exist", url))?,
+        ),
+        _ => None,
+    };
+    let url = match &source {
+        Some(source) => source.to_string_lossy().into_owned(),
+        None => url.to_string(),
+    };
+
+    let existed = path.exists();
+    let repo = Repository::new(path)?;
+    match clone_into(repo, &url, source.as_deref(), options) {
+        Ok(repo) => Ok(repo),
+        Err(error) => {
+            // Leave nothing of a failed clone behind
+            let _ = fs::remove_dir_all(path);
+            if existed {
+                let _ = fs::create_dir(path);
+            }
+            Err(error)
+        }
+    }
+}
+
+/// Fetch into a freshly created repository and check out its HEAD branch
+///
+/// A partial clone is registered before fetching, so the repository is
+/// reopened to pick up its promisor remote.
+fn clone_into(
+    repo: Repository,
+    url: &str,
+    source: Option<&Path>,
+    options: &CloneOptions,
+) -> Result<Repository> {
+    let remote = Remote::add(&repo, &options.origin, url)?;
+    let fetch_options = FetchOptions {
+        depth: options.depth,
+        shallow_since: options.shallow_since,
+        filter: options.filter,
+        ..FetchOptions::default()
+    };
+    let scope = fetch_options.scope(&repo, &remote)?;
+    let repo = match options.filter {
+        Some(_) => Repository::open(repo.worktree())?,
+        None => repo,
+    };
+    clone_fetch(&repo, &remote, source, options, &scope)?;
+    Ok(repo)
+}
+
+/// Fetch the branches of the remote of a clone and check out its HEAD
+/// branch
+fn clone_fetch(
+    repo: &Repository,
+    remote: &Remote,
+    source: Option<&Path>,
+    options: &CloneOptions,
+    scope: &FetchScope,
+) -> Result<()> {
+    let mut transport = transport::open(repo, &remote.url)?;
+    transport.set_progress(options.progress);
+    // Linking everything would defeat a shallow or partial clone
+    if let (true, Some(source), true) = (options.local, source, *scope == FetchScope::default()) {
+        LocalTransport::open(source)?.link_objects(repo)?;
+    }
+    let result = fetch_with(repo, remote, transport.as_mut(), &remote.fetch, scope)?;
+
+    let remote_head = result.remote_refs.iter().find(|r| r.name == "HEAD");
+    let head_branch = remote_head.and_then(|head| head.symref_target.clone());
+    if let Some(target) = &head_branch {
+        let tracking = format!("refs/remotes/{}/{}", options.origin, short_name(target));
+        if resolve_ref(repo, &tracking)?.is_some() {
+            let name = format!("refs/remotes/{}/HEAD", options.origin);
+            update_symbolic_ref(repo, &name, &tracking)?;
+        }
+    }
+
+    let branch = match &options.branch {
+        Some(branch) => Some(format!("refs/heads/{}", branch)),
+        None => head_branch,
+    };
+    let target = match &branch {
+        Some(name) => result.remote_refs.iter().find(|r| r.name == *name),
+        None => remote_head,
+    };
+    let commit = match (target, &options.branch) {
+        (Some(target), _) => target.hash.clone(),
+        (None, Some(branch)) => {
+            bail!(
+                "Remote branch {} not found in upstream {}",
+                branch,
+                options.origin
+            )
+        }
+        // An empty repository: HEAD points to the remote's unborn branch
+        (None, None) => {
+            if let Some(name) = &branch {
+                set_head(repo, &Head::Branch(name.clone()))?;
+            }
+            return Ok(());
+        }
+    };
+
+    match &branch {
+        Some(name) => {
+            update_ref(repo, name, &commit)?;
+            let short = short_name(name);
+            let mut config = repo.config()?;
+            config.set(&format!("branch.{}.remote", short), &options.origin)?;
+            config.set(&format!("branch.{}.merge", short), name)?;
+            repo.write_config(&config)?;
+            set_head(repo, &Head::Branch(name.clone()))?;
+        }
+        None => set_head(repo, &Head::Detached(commit.clone()))?,
+    }
+    if !options.no_checkout {
+        checkout_tree(repo, &Commit::read(repo, &commit)?.tree, true)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::read_object;
+    use crate::refs::read_ref;
+    use crate::shallow::read_shallow;
+    use crate::test_utils::{commit_files, init_repo, write_commit};
+    use std::collections::HashSet;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_clone_checks_out_remote_head() {
+        let (source_dir, source) = init_repo();
+        commit_files(&source, &[("a.txt", "one\n")], "first");
+        let head = commit_files(&source, &[("a.txt", "two\n")], "second");
+        update_ref(&source, "refs/heads/topic", &head).unwrap();
+
+        let target = TempDir::new().unwrap();
+        let path = target.path().join("clone");
+        let url = source_dir.path().to_str().unwrap();
+        let repo = clone(url, &path, &CloneOptions::default()).unwrap();
+
+        assert_eq!(fs::read_to_string(path.join("a.txt")).unwrap(), "two\n");
+        assert_eq!(
+            read_head(&repo).unwrap(),
+            Head::Branch("refs/heads/master".to_string())
+        );
+        assert_eq!(
+            resolve_ref(&repo, "refs/remotes/origin/topic").unwrap(),
+            Some(head.clone())
+        );
+        assert!(matches!(
+            read_ref(&repo, "refs/remotes/origin/HEAD").unwrap(),
+            Some(crate::refs::RefValue::Symbolic(target)) if target == "refs/remotes/origin/master"
+        ));
+        let config = repo.config().unwrap();
+        assert_eq!(config.get("branch.master.remote"), Some("origin"));
+        assert_eq!(config.get("branch.master.merge"), Some("refs/heads/master"));
+        assert!(read_object(&repo, &Commit::read(&repo, &head).unwrap().parents[0]).is_ok());
+
+        assert!(clone(url, &path, &CloneOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_fetch_updates_tracking_refs() {
+        let (source_dir, source) = init_repo();
+        let first = commit_files(&source, &[("a.txt", "one\n")], "first");
+        let target = TempDir::new().unwrap();
+        let path = target.path().join("clone");
+        let options = CloneOptions {
+            local: true,
+            ..CloneOptions::default()
+        };
+        let repo = clone(source_dir.path().to_str().unwrap(), &path, &options).unwrap();
+
+        let second = commit_files(&source, &[("a.txt", "two\n")], "second");
+        update_ref(&source, "refs/tags/v1", &second).unwrap();
+        let result = fetch(&repo, "origin", &FetchOptions::default()).unwrap();
+        assert_eq!(
+            result.updates[0].status,
+            UpdateStatus::FastForward { old: first.clone() }
+        );
+        assert_eq!(
+            resolve_ref(&repo, "refs/tags/v1").unwrap(),
+            Some(second.clone())
+        );
+        let fetch_head = fs::read_to_string(repo.gitdir().join("FETCH_HEAD")).unwrap();
+        assert!(fetch_head.starts_with(&format!("{}\t\tbranch 'master' of ", second)));
+        assert!(format_updates(&result).contains(" * [new tag]         v1         -> v1\n"));
+
+        // A rewritten branch is only taken with a forcing refspec
+        let rewritten = write_commit(&source, &[("a.txt", "three\n")], &[first], "other");
+        update_ref(&source, "refs/heads/master", &rewritten).unwrap();
+        let options = FetchOptions {
+            refspecs: vec!["refs/heads/master:refs/remotes/origin/master".to_string()],
+            ..FetchOptions::default()
+        };
+        let result = fetch(&repo, "origin", &options).unwrap();
+        assert!(result.updates[0].is_rejected());
+        let result = fetch(&repo, "origin", &FetchOptions::default()).unwrap();
+        assert_eq!(
+            result.updates[0].status,
+            UpdateStatus::Forced { old: second }
+        );
+        assert_eq!(
+            resolve_ref(&repo, "refs/remotes/origin/master").unwrap(),
+            Some(rewritten)
+        );
+    }
+
+    #[test]
+    fn test_shallow_clone_and_unshallow() {
+        let (source_dir, source) = init_repo();
+        let first = commit_files(&source, &[("a.txt", "one\n")], "first");
+        let second = commit_files(&source, &[("a.txt", "two\n")], "second");
+        let third = commit_files(&source, &[("a.txt", "three\n")], "third");
+
+        let target = TempDir::new().unwrap();
+        let path = target.path().join("clone");
+        let options = CloneOptions {
+            depth: Some(2),
+            ..CloneOptions::default()
+        };
+        let repo = clone(source_dir.path().to_str().unwrap(), &path, &options).unwrap();
+        assert_eq!(fs::read_to_string(path.join("a.txt")).unwrap(), "three\n");
+        assert_eq!(
+            read_shallow(&repo).unwrap(),
+            HashSet::from([second.clone()])
+        );
+        assert!(read_object(&repo, &third).is_ok());
+        assert!(!object_exists(&repo, &first));
+
+        let options = FetchOptions {
+            unshallow: true,
+            ..FetchOptions::default()
+        };
+        fetch(&repo, "origin", &options).unwrap();
+        assert!(!is_shallow(&repo));
+        assert!(read_object(&repo, &first).is_ok());
+        assert!(fetch(&repo, "origin", &options).is_err());
+    }
+}
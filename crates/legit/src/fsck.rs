@@ -0,0 +1,930 @@
+use crate::gc::{reflog_names, GITLINK_MODE};
+use crate::index::Index;
+use crate::objects::{loose_objects, read_object, HashAlgorithm, Object, ObjectHash, ObjectType};
+use crate::pack::packs;
+use crate::promisor::promisor_packs;
+use crate::refs::{list_refs, read_reflog, resolve_ref};
+use crate::shallow::read_shallow;
+use crate::tree::is_dot_git;
+use crate::worktree::other_worktrees;
+use crate::Repository;
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+use std::fs;
+use std::str::FromStr;
+
+/// Kind of problem `fsck` reports, from the most to the least serious
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, strum::Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum IssueKind {
+    Error,
+    Warning,
+    Missing,
+    Dangling,
+    Unreachable,
+}
+
+/// FsckIssue is one finding of `fsck`
+///
+/// `id` is a camelCase message id like git's (`badTreeSha1`, `hashMismatch`),
+/// empty for missing, dangling and unreachable objects.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FsckIssue {
+    pub kind: IssueKind,
+    /// Type of the object, if it could be determined
+    pub object_type: Option<ObjectType>,
+    pub hash: ObjectHash,
+    pub id: String,
+    pub message: String,
+}
+
+impl FsckIssue {
+    /// Format the issue as a tab separated `kind type hash id message` line
+    pub fn porcelain(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}",
+            self.kind,
+            self.type_name(),
+            self.hash,
+            self.id,
+            self.message
+        )
+    }
+
+    fn type_name(&self) -> String {
+        match &self.object_type {
+            Some(object_type) => object_type.to_string(),
+            None => "object".to_string(),
+        }
+    }
+}
+
+impl Display for FsckIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            IssueKind::Error | IssueKind::Warning => write!(
+                f,
+                "{} in {} {}: {}: {}",
+                self.kind,
+                self.type_name(),
+                self.hash,
+                self.id,
+                self.message
+            ),
+            _ => write!(f, "{} {} {}", self.kind, self.type_name(), self.hash),
+        }
+    }
+}
+
+/// FsckOptions selects what `fsck` reports besides errors
+#[derive(Debug, Clone, Default)]
+pub struct FsckOptions {
+    /// Report every unreachable object rather than only the dangling ones
+    pub unreachable: bool,
+    /// Do not report dangling objects
+    pub no_dangling: bool,
+}
+
+/// FsckReport lists the issues `fsck` found, errors first
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub checked_objects: usize,
+    pub issues: Vec<FsckIssue>,
+}
+
+impl FsckReport {
+    /// Return true if the repository is corrupt: an object is broken or a
+    /// reachable one is missing
+    pub fn has_errors(&self) -> bool {
+        self.issues
+            .iter()
+            .any(|issue| matches!(issue.kind, IssueKind::Error | IssueKind::Missing))
+    }
+}
+
+/// A problem found in an object's content, before it has a kind
+type Problem = (IssueKind, &'static str, String);
+
+/// Verify the object database, like `git fsck`
+///
+/// Every loose and packed object is re-hashed and its syntax checked, then
+/// the objects are walked from the references, reflogs and index to find the
+/// missing ones and those nothing reaches.
+pub fn fsck(repo: &Repository, options: &FsckOptions) -> Result<FsckReport> {
+    let mut report = FsckReport::default();
+    let mut types = HashMap::new();
+    let mut links = HashMap::new();
+    for (hash, _) in loose_objects(repo)? {
+        let object = read_object(repo, &hash);
+        check(&mut report, &mut types, &mut links, hash, object);
+    }
+    for pack in packs(repo)? {
+        if let Some(message) = check_pack_checksum(pack.path(), repo.hash_algorithm())? {
+            let name = pack
+                .path()
+                .file_stem()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default();
+            let hash =
+                ObjectHash::from_hex(name.trim_start_matches("pack-"), repo.hash_algorithm())
+                    .unwrap_or_default();
+            report.issues.push(issue(
+                IssueKind::Error,
+                None,
+                &hash,
+                "badPackChecksum",
+                message,
+            ));
+        }
+        for hash in pack.index().hashes() {
+            if types.contains_key(&hash) {
+                continue;
+            }
+            let object = pack.read_object(repo, &hash).and_then(|object| {
+                object.ok_or_else(|| anyhow::anyhow!("Object is not in its pack"))
+            });
+            check(&mut report, &mut types, &mut links, hash, object);
+        }
+    }
+
+    for (hash, object_links) in &links {
+        for (target, expected) in object_links {
+            match types.get(target) {
+                Some(actual) if actual != expected => {
+                    let message = format!("{} is a {}, not a {}", target, actual, expected);
+                    let object_type = types.get(hash).cloned();
+                    report.issues.push(issue(
+                        IssueKind::Error,
+                        object_type,
+                        hash,
+                        "wrongObjectType",
+                        message,
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut missing = BTreeMap::new();
+    let mut reachable = HashSet::new();
+    let mut stack = Vec::new();
+    for (name, hash) in roots(repo)? {
+        match types.contains_key(&hash) {
+            true => stack.push(hash),
+            false => {
+                let message = format!("{}: invalid sha1 pointer", name);
+                report.issues.push(issue(
+                    IssueKind::Error,
+                    None,
+                    &hash,
+                    "badRefPointer",
+                    message,
+                ));
+            }
+        }
+    }
+    // The parents of shallow commits and what promisor objects refer to
+    // may be missing
+    let shallow = read_shallow(repo)?;
+    let promisor = promisor_packs(repo)?;
+    while let Some(hash) = stack.pop() {
+        if !reachable.insert(hash.clone()) {
+            continue;
+        }
+        for (target, expected) in links.get(&hash).into_iter().flatten() {
+            match types.contains_key(target) {
+                true => stack.push(target.clone()),
+                false if *expected == ObjectType::Commit && shallow.contains(&hash) => {}
+                false if promisor.iter().any(|pack| pack.contains(&hash)) => {}
+                false => {
+                    missing.insert(target.clone(), expected.clone());
+                }
+            }
+        }
+    }
+    for (hash, object_type) in missing {
+        report.issues.push(issue(
+            IssueKind::Missing,
+            Some(object_type),
+            &hash,
+            "",
+            String::new(),
+        ));
+    }
+
+    let mut unreachable = types
+        .keys()
+        .filter(|hash| !reachable.contains(*hash))
+        .collect::<Vec<_>>();
+    unreachable.sort();
+    let referenced = unreachable
+        .iter()
+        .flat_map(|hash| links.get(*hash).into_iter().flatten())
+        .map(|(target, _)| target)
+        .collect::<HashSet<_>>();
+    for hash in unreachable {
+        let kind = match (options.unreachable, referenced.contains(hash)) {
+            (true, _) => IssueKind::Unreachable,
+            (false, false) if !options.no_dangling => IssueKind::Dangling,
+            _ => continue,
+        };
+        report.issues.push(issue(
+            kind,
+            types.get(hash).cloned(),
+            hash,
+            "",
+            String::new(),
+        ));
+    }
+    report.issues.sort_by_key(|issue| issue.kind);
+    Ok(report)
+}
+
+/// Re-hash and check the syntax of an object read from the database
+fn check(
+    report: &mut FsckReport,
+    types: &mut HashMap<ObjectHash, ObjectType>,
+    links: &mut HashMap<ObjectHash, Vec<(ObjectHash, ObjectType)>>,
+    hash: ObjectHash,
+    object: Result<Object>,
+) {
+    report.checked_objects += 1;
+    let object = match object {
+        Ok(object) => object,
+        Err(e) => {
+            let message = format!("{:#}", e);
+            report
+                .issues
+                .push(issue(IssueKind::Error, None, &hash, "badObject", message));
+            return;
+        }
+    };
+    if object.hash != hash {
+        let message = format!("hash mismatch, content hashes to {}", object.hash);
+        let object_type = Some(object.object_type);
+        report.issues.push(issue(
+            IssueKind::Error,
+            object_type,
+            &hash,
+            "hashMismatch",
+            message,
+        ));
+        return;
+    }
+    let (object_links, problems) = check_object(&object);
+    for (kind, id, message) in problems {
+        let object_type = Some(object.object_type.clone());
+        report
+            .issues
+            .push(issue(kind, object_type, &hash, id, message));
+    }
+    types.insert(hash.clone(), object.object_type);
+    links.insert(hash, object_links);
+}
+
+fn issue(
+    kind: IssueKind,
+    object_type: Option<ObjectType>,
+    hash: &ObjectHash,
+    id: &str,
+    message: String,
+) -> FsckIssue {
+    FsckIssue {
+        kind,
+        object_type,
+        hash: hash.clone(),
+        id: id.to_string(),
+        message,
+    }
+}
+
+/// Collect the objects the repository needs, with where they come from
+///
+/// As in git, `ORIG_HEAD` and the other special files are not roots.
+fn roots(repo: &Repository) -> Result<Vec<(String, ObjectHash)>> {
+    let mut roots = Vec::new();
+    if let Ok(Some(hash)) = resolve_ref(repo, "HEAD") {
+        roots.push(("HEAD".to_string(), hash));
+    }
+    roots.extend(list_refs(repo, "refs/")?);
+    for name in reflog_names(repo)? {
+        for entry in read_reflog(repo, &name)? {
+            for hash in [entry.old, entry.new] {
+                if !hash.is_null() {
+                    roots.push((format!("{}@{{reflog}}", name), hash));
+                }
+            }
+        }
+    }
+    if let Ok(index) = Index::read(repo) {
+        for entry in index.entries {
+            if entry.mode != GITLINK_MODE {
+                roots.push((format!("index entry {}", entry.path), entry.hash));
+            }
+        }
+    }
+    // The HEAD and index of the other worktrees, named like git does
+    for worktree in other_worktrees(repo)? {
+        let (Ok(other), Some(name)) = (worktree.open(), &worktree.name) else {
+            continue;
+        };
+        if let Ok(Some(hash)) = resolve_ref(&other, "HEAD") {
+            roots.push((format!("worktrees/{}/HEAD", name), hash));
+        }
+        if let Ok(index) = Index::read(&other) {
+            for entry in index.entries {
+                if entry.mode != GITLINK_MODE {
+                    let id = format!("worktrees/{}: index entry {}", name, entry.path);
+                    roots.push((id, entry.hash));
+                }
+            }
+        }
+    }
+    Ok(roots)
+}
+
+/// Compare a pack's trailer with the checksum of its content
+fn check_pack_checksum(path: &std::path::Path, algorithm: HashAlgorithm) -> Result<Option<String>> {
+    let data = fs::read(path)?;
+    if data.len() < algorithm.size() {
+        return Ok(Some("pack is truncated".to_string()));
+    }
+    let (content, trailer) = data.split_at(data.len() - algorithm.size());
+    match algorithm.digest(content).as_bytes() == trailer {
+        true => Ok(None),
+        false => Ok(Some(format!("{} has a bad checksum", path.display()))),
+    }
+}
+
+/// Check the syntax of an object and return the objects it refers to
+fn check_object(object: &Object) -> (Vec<(ObjectHash, ObjectType)>, Vec<Problem>) {
+    let mut links = Vec::new();
+    let mut problems = Vec::new();
+    match object.object_type {
+        ObjectType::Blob => {}
+        ObjectType::Tree => check_tree(
+            &object.data,
+            object.hash.algorithm(),
+            &mut links,
+            &mut problems,
+        ),
+        ObjectType::Commit => {
+            if let Err(problem) = check_commit(&object.data, object.hash.algorithm(), &mut links) {
+                problems.push(problem);
+            }
+        }
+        ObjectType::Tag => {
+            if let Err(problem) = check_tag(
+                &object.data,
+                object.hash.algorithm(),
+                &mut links,
+                &mut problems,
+            ) {
+                problems.push(problem);
+            }
+        }
+    }
+    (links, problems)
+}
+
+fn error(id: &'static str, message: &str) -> Problem {
+    (IssueKind::Error, id, message.to_string())
+}
+
+/// Split the header lines of a commit or tag from its message
+fn header_lines(data: &[u8]) -> Vec<&[u8]> {
+    let end = data
+        .windows(2)
+        .position(|w| w == b"\n\n")
+        .map_or(data.len(), |i| i + 1);
+    data[..end]
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+/// Take a `<key> <hex hash>` header line, whose hash must be of `algorithm`
+fn hash_header(
+    line: Option<&&[u8]>,
+    key: &str,
+    algorithm: HashAlgorithm,
+) -> Option<Result<ObjectHash, ()>> {
+    let value = line?.strip_prefix(key.as_bytes())?.strip_prefix(b" ")?;
+    Some(
+        std::str::from_utf8(value)
+            .ok()
+            .filter(|hex| hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')))
+            .and_then(|hex| ObjectHash::from_hex(hex, algorithm).ok())
+            .ok_or(()),
+    )
+}
+
+fn check_commit(
+    data: &[u8],
+    algorithm: HashAlgorithm,
+    links: &mut Vec<(ObjectHash, ObjectType)>,
+) -> Result<(), Problem> {
+    let lines = header_lines(data);
+    let mut lines = lines.iter().peekable();
+    match hash_header(lines.next(), "tree", algorithm) {
+        None => {
+            return Err(error(
+                "missingTree",
+                "invalid format - expected 'tree' line",
+            ))
+        }
+        Some(Err(())) => {
+            return Err(error(
+                "badTreeSha1",
+                "invalid 'tree' line format - bad sha1",
+            ))
+        }
+        Some(Ok(tree)) => links.push((tree, ObjectType::Tree)),
+    }
+    while lines
+        .peek()
+        .is_some_and(|line| line.starts_with(b"parent "))
+    {
+        match hash_header(lines.next(), "parent", algorithm) {
+            Some(Ok(parent)) => links.push((parent, ObjectType::Commit)),
+            _ => {
+                return Err(error(
+                    "badParentSha1",
+                    "invalid 'parent' line format - bad sha1",
+                ))
+            }
+        }
+    }
+    for (key, missing) in [
+        ("author", "missingAuthor"),
+        ("committer", "missingCommitter"),
+    ] {
+        let ident = lines
+            .next()
+            .and_then(|line| line.strip_prefix(key.as_bytes()))
+            .and_then(|rest| rest.strip_prefix(b" "))
+            .ok_or_else(|| {
+                error(
+                    missing,
+                    &format!("invalid format - expected '{}' line", key),
+                )
+            })?;
+        check_ident(ident)?;
+    }
+    Ok(())
+}
+
+fn check_tag(
+    data: &[u8],
+    algorithm: HashAlgorithm,
+    links: &mut Vec<(ObjectHash, ObjectType)>,
+    problems: &mut Vec<Problem>,
+) -> Result<(), Problem> {
+    let lines = header_lines(data);
+    let mut lines = lines.iter();
+    let object = match hash_header(lines.next(), "object", algorithm) {
+        None => {
+            return Err(error(
+                "missingObject",
+                "invalid format - expected 'object' line",
+            ))
+        }
+        Some(Err(())) => {
+            return Err(error(
+                "badObjectSha1",
+                "invalid 'object' line format - bad sha1",
+            ))
+        }
+        Some(Ok(object)) => object,
+    };
+    let object_type = lines
+        .next()
+        .and_then(|line| line.strip_prefix(b"type "))
+        .ok_or_else(|| error("missingTypeEntry", "invalid format - expected 'type' line"))?;
+    let object_type = std::str::from_utf8(object_type)
+        .ok()
+        .and_then(|name| ObjectType::from_str(name).ok())
+        .ok_or_else(|| error("badType", "invalid 'type' value"))?;
+    links.push((object, object_type));
+    if !lines.next().is_some_and(|line| line.starts_with(b"tag ")) {
+        return Err(error(
+            "missingTagEntry",
+            "invalid format - expected 'tag' line",
+        ));
+    }
+    match lines.next().and_then(|line| line.strip_prefix(b"tagger ")) {
+        Some(ident) => check_ident(ident)?,
+        None => problems.push((
+            IssueKind::Warning,
+            "missingTaggerEntry",
+            "invalid format - expected 'tagger' line".to_string(),
+        )),
+    }
+    Ok(())
+}
+
+/// Check an identity of the form `Name <email> 1234567890 +0000`
+fn check_ident(ident: &[u8]) -> Result<(), Problem> {
+    let open = ident.iter().position(|&b| b == b'<').ok_or_else(|| {
+        error(
+            "missingEmail",
+            "invalid author/committer line - missing email",
+        )
+    })?;
+    let close = ident[open..]
+        .iter()
+        .position(|&b| b == b'>')
+        .ok_or_else(|| error("badEmail", "invalid author/committer line - bad email"))?;
+    let rest = &ident[open + close + 1..];
+    let rest = rest.strip_prefix(b" ").ok_or_else(|| {
+        error(
+            "missingSpaceBeforeDate",
+            "invalid author/committer line - missing space before date",
+        )
+    })?;
+    let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+    if digits == 0 {
+        return Err(error("badDate", "invalid author/committer line - bad date"));
+    }
+    match rest[digits..].strip_prefix(b" ") {
+        Some([b'+' | b'-', zone @ ..])
+            if zone.len() == 4 && zone.iter().all(u8::is_ascii_digit) =>
+        {
+            Ok(())
+        }
+        _ => Err(error(
+            "badTimezone",
+            "invalid author/committer line - bad time zone",
+        )),
+    }
+}
+
+fn check_tree(
+    data: &[u8],
+    algorithm: HashAlgorithm,
+    links: &mut Vec<(ObjectHash, ObjectType)>,
+    problems: &mut Vec<Problem>,
+) {
+    let size = algorithm.size();
+    let mut rest = data;
+    let mut previous: Option<(Vec<u8>, bool)> = None;
+    let mut warned = HashSet::new();
+    let mut warn = |problems: &mut Vec<Problem>, id: &'static str, message: &str| {
+        if warned.insert(id) {
+            problems.push((IssueKind::Warning, id, message.to_string()));
+        }
+    };
+    while !rest.is_empty() {
+        let (Some(space), Some(nul)) = (
+            rest.iter().position(|&b| b == b' '),
+            rest.iter().position(|&b| b == 0),
+        ) else {
+            problems.push(error("badTree", "cannot be parsed as a tree"));
+            return;
+        };
+        if space > nul || rest.len() < nul + 1 + size {
+            problems.push(error("badTree", "cannot be parsed as a tree"));
+            return;
+        }
+        let (mode, name) = (&rest[..space], &rest[space + 1..nul]);
+        let hash =
+            ObjectHash::from_bytes(&rest[nul + 1..nul + 1 + size], algorithm).expect("hash size");
+        rest = &rest[nul + 1 + size..];
+
+        let is_tree = mode == b"40000";
+        let object_type = match mode {
+            b"40000" => Some(ObjectType::Tree),
+            b"100644" | b"100755" | b"120000" => Some(ObjectType::Blob),
+            b"160000" => None,
+            b"040000" => {
+                warn(
+                    problems,
+                    "zeroPaddedFilemode",
+                    "contains zero-padded file modes",
+                );
+                Some(ObjectType::Tree)
+            }
+            b"100664" | b"100640" | b"100600" => {
+                warn(problems, "badFilemode", "contains bad file modes");
+                Some(ObjectType::Blob)
+            }
+            _ => {
+                problems.push(error("badFilemode", "contains bad file modes"));
+                None
+            }
+        };
+        links.extend(object_type.map(|object_type| (hash, object_type)));
+
+        match name {
+            b"" => warn(problems, "emptyName", "contains empty pathname"),
+            b"." => warn(problems, "hasDot", "contains '.'"),
+            b".." => warn(problems, "hasDotdot", "contains '..'"),
+            _ if is_dot_git(&String::from_utf8_lossy(name)) => {
+                problems.push(error("hasDotgit", "contains '.git'"))
+            }
+            _ if name.contains(&b'/') => warn(problems, "fullPathname", "contains full pathnames"),
+            _ => {}
+        }
+
+        if let Some((last, last_is_tree)) = &previous {
+            if last.as_slice() == name {
+                problems.push(error("duplicateEntries", "contains duplicate file entries"));
+            } else if sort_key(last, *last_is_tree) > sort_key(name, is_tree) {
+                problems.push(error("treeNotSorted", "not properly sorted"));
+            }
+        }
+        previous = Some((name.to_vec(), is_tree));
+    }
+}
+
+/// Trees sort as if their names ended with a slash
+fn sort_key(name: &[u8], is_tree: bool) -> Vec<u8> {
+    let mut key = name.to_vec();
+    if is_tree {
+        key.push(b'/');
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::write_object;
+    use crate::pack::store_pack;
+    use crate::pack_writer::{write_pack, ObjectList};
+    use crate::refs::update_ref;
+    use crate::test_utils::{commit_files, init_repo, write_blob};
+
+    /// Write a tree listing `entries` as given, without sorting them
+    fn raw_tree(repo: &Repository, entries: &[(&str, &str, &ObjectHash)]) -> ObjectHash {
+        let mut tree = Vec::new();
+        for (mode, name, hash) in entries {
+            tree.extend(format!("{} {}\0", mode, name).as_bytes());
+            tree.extend(hash.as_bytes());
+        }
+        let tree = Object::new(HashAlgorithm::Sha1, ObjectType::Tree, tree).unwrap();
+        write_object(&tree, repo).unwrap()
+    }
+
+    fn issue_lines(report: &FsckReport) -> Vec<String> {
+        report.issues.iter().map(|i| i.porcelain()).collect()
+    }
+
+    #[test]
+    fn test_fsck_reports_corruption() {
+        let (_dir, repo) = init_repo();
+        let base = commit_files(&repo, &[("a.txt", "one\n")], "first");
+        let report = fsck(&repo, &FsckOptions::default()).unwrap();
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+        assert_eq!(report.checked_objects, 3);
+
+        // A commit nothing points at, whose tree lists an absent blob
+        // out of order
+        let mut tree = Vec::new();
+        for name in ["b", "a"] {
+            tree.extend(format!("100644 {}\0", name).as_bytes());
+            tree.extend(HashAlgorithm::Sha1.digest(name.as_bytes()).as_bytes());
+        }
+        let tree = write_object(
+            &Object::new(HashAlgorithm::Sha1, ObjectType::Tree, tree).unwrap(),
+            &repo,
+        )
+        .unwrap();
+        let commit = format!(
+            "tree {}\nparent {}\nauthor A <a@x> 1700000000 +0000\n\nbad\n",
+            tree, base
+        );
+        let commit =
+            Object::new(HashAlgorithm::Sha1, ObjectType::Commit, commit.into_bytes()).unwrap();
+        let dangling = write_object(&commit, &repo).unwrap();
+
+        // A loose object whose content does not match its name
+        let blob = Object::new(HashAlgorithm::Sha1, ObjectType::Blob, b"two\n".to_vec()).unwrap();
+        let corrupt =
+            Object::new(HashAlgorithm::Sha1, ObjectType::Blob, b"three\n".to_vec()).unwrap();
+        write_object(&corrupt, &repo).unwrap();
+        fs::create_dir_all(blob.file_path(&repo).parent().unwrap()).unwrap();
+        fs::rename(corrupt.file_path(&repo), blob.file_path(&repo)).unwrap();
+
+        let report = fsck(&repo, &FsckOptions::default()).unwrap();
+        let lines = report
+            .issues
+            .iter()
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>();
+        assert!(report.has_errors());
+        assert!(lines.contains(&format!(
+            "error in blob {}: hashMismatch: hash mismatch, content hashes to {}",
+            blob.hash, corrupt.hash
+        )));
+        assert!(lines.contains(&format!(
+            "error in tree {}: treeNotSorted: not properly sorted",
+            tree
+        )));
+        assert!(lines.contains(&format!(
+            "error in commit {}: missingCommitter: invalid format - expected 'committer' line",
+            dangling
+        )));
+        assert!(lines.contains(&format!("dangling commit {}", dangling)));
+        assert!(!lines
+            .iter()
+            .any(|line| line.contains(&tree.to_hex()) && line.starts_with("dangling")));
+
+        // Once the commit is referenced, the blobs it needs are missing
+        update_ref(&repo, "refs/heads/broken", &dangling).unwrap();
+        let options = FsckOptions {
+            unreachable: true,
+            ..Default::default()
+        };
+        let report = fsck(&repo, &options).unwrap();
+        let missing = report
+            .issues
+            .iter()
+            .filter(|i| i.kind == IssueKind::Missing)
+            .map(|i| i.porcelain())
+            .collect::<Vec<_>>();
+        assert_eq!(missing.len(), 2);
+        assert!(missing.contains(&format!(
+            "missing\tblob\t{}\t\t",
+            HashAlgorithm::Sha1.digest("a".as_bytes())
+        )));
+        assert!(!report
+            .issues
+            .iter()
+            .any(|i| i.kind == IssueKind::Unreachable));
+    }
+
+    #[test]
+    fn test_fsck_rehashes_packed_objects() {
+        let (_dir, repo) = init_repo();
+        let blob = write_blob(&repo, b"packed\n");
+        let list = ObjectList {
+            objects: vec![(blob.clone(), "a.txt".to_string())],
+            bases: Default::default(),
+        };
+        let (_dir, server) = init_repo();
+        let pack = store_pack(&server, &write_pack(&repo, &list, false).unwrap())
+            .unwrap()
+            .unwrap();
+        let report = fsck(&server, &FsckOptions::default()).unwrap();
+        assert_eq!(report.checked_objects, 1);
+        assert!(!report.has_errors(), "{:?}", report.issues);
+
+        // Name the packed blob differently in a copy of the index, keeping
+        // the first byte so that the fan-out table still holds
+        let idx_path = pack.path().with_extension("idx");
+        let mut idx = fs::read(&idx_path).unwrap();
+        let name = 8 + 256 * 4;
+        idx[name + 19] ^= 0xff;
+        let renamed = ObjectHash::from_bytes(&idx[name..name + 20], HashAlgorithm::Sha1).unwrap();
+        let copy = idx_path.with_file_name(format!("pack-{}.idx", "1".repeat(40)));
+        fs::write(&copy, &idx).unwrap();
+        fs::rename(pack.path(), copy.with_extension("pack")).unwrap();
+        fs::remove_file(&idx_path).unwrap();
+
+        let report = fsck(&server, &FsckOptions::default()).unwrap();
+        assert!(report.has_errors());
+        assert!(issue_lines(&report).contains(&format!(
+            "error\tblob\t{}\thashMismatch\thash mismatch, content hashes to {}",
+            renamed, blob
+        )));
+    }
+
+    #[test]
+    fn test_fsck_checks_tree_entries() {
+        let (_dir, repo) = init_repo();
+        let blob = write_blob(&repo, b"one\n");
+        let sorted = raw_tree(&repo, &[("100644", "a", &blob), ("100644", "b", &blob)]);
+        let unsorted = raw_tree(&repo, &[("100644", "b", &blob), ("100644", "a", &blob)]);
+        let duplicate = raw_tree(&repo, &[("100644", "a", &blob), ("100644", "a", &blob)]);
+        // A directory sorts as if its name ended with a slash, after "a.txt"
+        let subtree = raw_tree(
+            &repo,
+            &[("100644", "a.txt", &blob), ("40000", "a", &sorted)],
+        );
+        let dotgit = raw_tree(&repo, &[("40000", ".GIT", &sorted)]);
+
+        let report = fsck(&repo, &FsckOptions::default()).unwrap();
+        let errors = report
+            .issues
+            .iter()
+            .filter(|i| i.kind == IssueKind::Error)
+            .map(|i| (i.hash.clone(), i.id.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(errors.len(), 3, "{:?}", report.issues);
+        assert!(errors.contains(&(unsorted, "treeNotSorted")));
+        assert!(errors.contains(&(duplicate, "duplicateEntries")));
+        assert!(errors.contains(&(dotgit.clone(), "hasDotgit")));
+        assert!(!errors
+            .iter()
+            .any(|(hash, _)| *hash == sorted || *hash == subtree));
+        assert!(report
+            .issues
+            .iter()
+            .any(|i| i.to_string()
+                == format!("error in tree {}: hasDotgit: contains '.git'", dotgit)));
+    }
+
+    #[test]
+    fn test_fsck_missing_and_dangling_objects() {
+        let (_dir, repo) = init_repo();
+        let commit = commit_files(&repo, &[("a.txt", "one\n")], "first");
+        let absent = HashAlgorithm::Sha1.digest("absent".as_bytes());
+        let lonely = write_blob(&repo, b"lonely\n");
+        let listed = write_blob(&repo, b"listed\n");
+        let unreachable = raw_tree(&repo, &[("100644", "a", &listed)]);
+        let broken = raw_tree(&repo, &[("100644", "a", &absent)]);
+        update_ref(&repo, "refs/heads/broken", &broken).unwrap();
+
+        // Only the unreachable objects nothing else refers to are dangling
+        let report = fsck(&repo, &FsckOptions::default()).unwrap();
+        assert!(report.has_errors());
+        let mut lines = issue_lines(&report);
+        lines.sort();
+        let mut expected = vec![
+            format!("missing\tblob\t{}\t\t", absent),
+            format!("dangling\ttree\t{}\t\t", unreachable),
+            format!("dangling\tblob\t{}\t\t", lonely),
+        ];
+        expected.sort();
+        assert_eq!(lines, expected);
+        assert!(!lines.iter().any(|line| line.contains(&listed.to_hex())));
+        assert!(!lines.iter().any(|line| line.contains(&commit.to_hex())));
+
+        let options = FsckOptions {
+            no_dangling: true,
+            ..Default::default()
+        };
+        let report = fsck(&repo, &options).unwrap();
+        assert_eq!(
+            issue_lines(&report),
+            [format!("missing\tblob\t{}\t\t", absent)]
+        );
+
+        let options = FsckOptions {
+            unreachable: true,
+            ..Default::default()
+        };
+        let report = fsck(&repo, &options).unwrap();
+        let unreachable_objects = report
+            .issues
+            .iter()
+            .filter(|i| i.kind == IssueKind::Unreachable)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(unreachable_objects.len(), 3);
+        assert!(unreachable_objects.contains(&format!("unreachable blob {}", listed)));
+        assert!(unreachable_objects.contains(&format!("unreachable tree {}", unreachable)));
+    }
+
+    #[test]
+    fn test_fsck_porcelain_format() {
+        let blob = HashAlgorithm::Sha1.digest("blob".as_bytes());
+        let error = issue(
+            IssueKind::Error,
+            Some(ObjectType::Tree),
+            &blob,
+            "duplicateEntries",
+            "contains duplicate file entries".to_string(),
+        );
+        assert_eq!(
+            error.porcelain(),
+            format!(
+                "error\ttree\t{}\tduplicateEntries\tcontains duplicate file entries",
+                blob
+            )
+        );
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "error in tree {}: duplicateEntries: contains duplicate file entries",
+                blob
+            )
+        );
+        let unknown = issue(
+            IssueKind::Error,
+            None,
+            &blob,
+            "badObject",
+            "cannot inflate".to_string(),
+        );
+        assert_eq!(
+            unknown.porcelain(),
+            format!("error\tobject\t{}\tbadObject\tcannot inflate", blob)
+        );
+        let dangling = issue(
+            IssueKind::Dangling,
+            Some(ObjectType::Blob),
+            &blob,
+            "",
+            String::new(),
+        );
+        assert_eq!(
+            dangling.porcelain(),
+            format!("dangling\tblob\t{}\t\t", blob)
+        );
+        assert_eq!(dangling.to_string(), format!("dangling blob {}", blob));
+    }
+}
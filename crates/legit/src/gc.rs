@@ -0,0 +1,622 @@
+use crate::bitmap::write_bitmap;
+use crate::commits::Signature;
+use crate::index::Index;
+use crate::midx::write_midx;
+use crate::objects::{loose_objects, object_exists, read_object, store_object, ObjectHash};
+use crate::pack::{packs, store_pack, Pack};
+use crate::pack_writer::{list_objects_with, write_pack, ListOptions, ObjectList};
+use crate::promisor::promisor_packs;
+use crate::refs::{
+    is_per_worktree, list_refs, read_packed_refs, read_ref, read_reflog, ref_root, ref_roots,
+    resolve_ref, write_packed_refs, write_reflog, RefStorage, RefValue,
+};
+use crate::reftable;
+use crate::revision::{ancestors, peel_to_commit};
+use crate::shallow::{is_shallow, Grafts};
+use crate::worktree::{other_worktrees, Worktree};
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Default of `gc.pruneExpire`: how long unreachable objects are kept
+const PRUNE_EXPIRE: &str = "2.weeks.ago";
+
+/// Default of `gc.reflogExpire`
+const REFLOG_EXPIRE: &str = "90.days.ago";
+
+/// Default of `gc.reflogExpireUnreachable`, for entries that are no longer
+/// reachable from the reference
+const REFLOG_EXPIRE_UNREACHABLE: &str = "30.days.ago";
+
+/// Files of the git directory besides `HEAD` whose commits must be kept
+const SPECIAL_REFS: [&str; 6] = [
+    "ORIG_HEAD",
+    "MERGE_HEAD",
+    "CHERRY_PICK_HEAD",
+    "REVERT_HEAD",
+    "REBASE_HEAD",
+    "AUTO_MERGE",
+];
+
+/// Mode of submodule entries in the index, whose commits live elsewhere
+pub(crate) const GITLINK_MODE: u32 = 0o160000;
+
+/// RepackOptions controls how objects are packed
+#[derive(Debug, Clone, Default)]
+pub struct RepackOptions {
+    /// Pack every reachable object into one pack, not only the loose ones
+    pub all: bool,
+    /// Remove the packs and loose objects made redundant by the new pack
+    pub delete: bool,
+    /// Write the unreachable objects of removed packs as loose objects, so
+    /// that pruning can give them a grace period
+    pub loosen_unreachable: bool,
+    /// Write a reachability bitmap for the new pack; only with `all`, and
+    /// not in shallow or partial clones, whose packs lack objects
+    pub write_bitmap: bool,
+    pub dry_run: bool,
+}
+
+/// RepackReport lists what a repack did, or would do on a dry run
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RepackReport {
+    pub packed_objects: usize,
+    /// The written pack, `None` when there was nothing to pack
+    pub pack: Option<PathBuf>,
+    pub removed_packs: Vec<PathBuf>,
+    pub removed_loose_objects: usize,
+    pub loosened_objects: usize,
+}
+
+/// GcOptions controls a garbage collection
+#[derive(Debug, Clone, Default)]
+pub struct GcOptions {
+    /// How old unreachable loose objects must be to be pruned, overriding
+    /// `gc.pruneExpire`, e.g. `now` or `never`
+    pub prune_expire: Option<String>,
+    pub dry_run: bool,
+}
+
+/// GcReport lists what a garbage collection did, or would do on a dry run
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GcReport {
+    pub packed_refs: Vec<String>,
+    pub expired_reflog_entries: usize,
+    pub repack: RepackReport,
+    pub pruned_objects: Vec<ObjectHash>,
+}
+
+/// Clean up a repository like `git gc`: pack references, expire old reflog
+/// entries, pack every reachable object and prune old unreachable ones
+pub fn gc(repo: &Repository, options: &GcOptions) -> Result<GcReport> {
+    let config = repo.config()?;
+    let now = now();
+    let setting = |key: &str, default: &str| config.get(key).unwrap_or(default).to_string();
+    let prune_expire = match &options.prune_expire {
+        Some(expire) => expire.clone(),
+        None => setting("gc.pruneExpire", PRUNE_EXPIRE),
+    };
+    let prune_cutoff = parse_expiry(&prune_expire, now)?;
+    let reflog_cutoff = parse_expiry(&setting("gc.reflogExpire", REFLOG_EXPIRE), now)?;
+    let unreachable_cutoff = parse_expiry(
+        &setting("gc.reflogExpireUnreachable", REFLOG_EXPIRE_UNREACHABLE),
+        now,
+    )?;
+
+    let packed_refs = pack_refs(repo, options.dry_run)?;
+    let expired_reflog_entries =
+        expire_reflogs(repo, reflog_cutoff, unreachable_cutoff, options.dry_run)?;
+    // Objects of a precious-objects repository are packed but never deleted
+    let precious = repo.settings().extensions.preciousobjects;
+    let repack_options = RepackOptions {
+        all: true,
+        delete: !precious,
+        // Pruning everything right away needs no loose copies
+        loosen_unreachable: prune_cutoff != Some(now),
+        write_bitmap: false,
+        dry_run: options.dry_run,
+    };
+    let repack = repack(repo, &repack_options)?;
+    let pruned_objects = match prune_cutoff {
+        Some(cutoff) if !precious => prune(repo, cutoff, options.dry_run)?,
+        _ => Vec::new(),
+    };
+    Ok(GcReport {
+        packed_refs,
+        expired_reflog_entries,
+        repack,
+        pruned_objects,
+    })
+}
+
+/// Pack objects like `git repack`: the loose reachable objects into a new
+/// pack, or with `all` every reachable object into a single pack
+pub fn repack(repo: &Repository, options: &RepackOptions) -> Result<RepackReport> {
+    if options.delete && repo.settings().extensions.preciousobjects {
+        bail!("Cannot delete packs in a precious-objects repo");
+    }
+    let mut list = reachable_objects(repo)?;
+    let old_packs = packs(repo)?;
+    if !options.all {
+        list.objects
+            .retain(|(hash, _)| !old_packs.iter().any(|pack| pack.contains(hash)));
+    }
+    let mut report = RepackReport {
+        packed_objects: list.objects.len(),
+        ..RepackReport::default()
+    };
+    let reachable = list
+        .objects
+        .iter()
+        .map(|(hash, _)| hash.clone())
+        .collect::<HashSet<_>>();
+    let redundant = match (options.all, options.delete) {
+        (true, true) => old_packs
+            .into_iter()
+            .filter(|pack| !pack.path().with_extension("keep").exists())
+            .filter(|pack| !pack.path().with_extension("promisor").exists())
+            .collect(),
+        _ => Vec::new(),
+    };
+    if options.dry_run {
+        report.removed_packs = redundant.iter().map(|p| p.path().to_path_buf()).collect();
+        return Ok(report);
+    }
+
+    let new_pack = match list.objects.is_empty() {
+        true => None,
+        false => store_pack(repo, &write_pack(repo, &list, false)?)?,
+    };
+    report.pack = new_pack.as_ref().map(|pack| pack.path().to_path_buf());
+    let complete = !is_shallow(repo) && promisor_packs(repo)?.is_empty();
+    if let (Some(pack), true, true, true) = (&new_pack, options.all, options.write_bitmap, complete)
+    {
+        write_bitmap(repo, pack, &bitmap_tips(repo)?)?;
+    }
+    if !options.delete {
+        refresh_midx(repo)?;
+        return Ok(report);
+    }
+    for pack in redundant {
+        if Some(pack.path()) == report.pack.as_deref() {
+            continue;
+        }
+        if options.loosen_unreachable {
+            report.loosened_objects += loosen_unreachable(repo, &pack, &reachable)?;
+        }
+        for extension in ["pack", "idx", "bitmap", "rev"] {
+            let path = pack.path().with_extension(extension);
+            if path.exists() {
+                fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove {}", path.display()))?;
+            }
+        }
+        report.removed_packs.push(pack.path().to_path_buf());
+    }
+
+    // Like `git prune-packed`, drop the loose copies of packed objects
+    let packs = packs(repo)?;
+    for (hash, path) in loose_objects(repo)? {
+        if packs.iter().any(|pack| pack.contains(&hash)) {
+            fs::remove_file(&path)?;
+            report.removed_loose_objects += 1;
+        }
+    }
+    remove_empty_fanout_dirs(repo)?;
+    refresh_midx(repo)?;
+    Ok(report)
+}
+
+/// The commits the references point to, which get a bitmap
+fn bitmap_tips(repo: &Repository) -> Result<Vec<ObjectHash>> {
+    let mut tips = Vec::new();
+    tips.extend(resolve_ref(repo, "HEAD")?);
+    tips.extend(list_refs(repo, "refs/")?.into_values());
+    let mut seen = HashSet::new();
+    Ok(tips
+        .iter()
+        .filter_map(|hash| peel_to_commit(repo, hash).ok())
+        .filter(|hash| seen.insert(hash.clone()))
+        .collect())
+}
+
+/// Rewrite the multi-pack-index, if there is one, for the current packs
+fn refresh_midx(repo: &Repository) -> Result<()> {
+    if repo
+        .commondir()
+        .join("objects/pack/multi-pack-index")
+        .exists()
+    {
+        write_midx(repo)?;
+    }
+    Ok(())
+}
+
+/// List the objects to keep: those reachable from the references, their
+/// reflogs, the special heads and the index of every worktree
+///
+/// The objects of promisor packs are left out, and not walked into: their
+/// packs are kept as they are, and what they refer to may be missing.
+pub fn reachable_objects(repo: &Repository) -> Result<ObjectList> {
+    let mut roots = list_refs(repo, "refs/")?.into_values().collect::<Vec<_>>();
+    // Reflogs and the index may mention objects that are long gone
+    let mut extra = Vec::new();
+    // Every worktree has its own HEAD, special heads, reflogs and index
+    let others = other_worktrees(repo)?
+        .iter()
+        .map(Worktree::open)
+        .collect::<Result<Vec<_>>>()?;
+    for (i, worktree) in std::iter::once(repo).chain(&others).enumerate() {
+        roots.extend(resolve_ref(worktree, "HEAD")?);
+        for name in SPECIAL_REFS {
+            roots.extend(resolve_ref(worktree, name).ok().flatten());
+        }
+        for name in reflog_names(worktree)? {
+            if i > 0 && !is_per_worktree(&name) {
+                continue;
+            }
+            for entry in read_reflog(worktree, &name)? {
+                extra.push(entry.old);
+                extra.push(entry.new);
+            }
+        }
+        extra.extend(
+            Index::read(worktree)?
+                .entries
+                .into_iter()
+                .filter(|entry| entry.mode != GITLINK_MODE)
+                .map(|entry| entry.hash),
+        );
+    }
+    roots.extend(
+        extra
+            .into_iter()
+            .filter(|hash| !hash.is_null() && object_exists(repo, hash)),
+    );
+    let mut seen = HashSet::new();
+    roots.retain(|hash| seen.insert(hash.clone()));
+    let options = ListOptions {
+        grafts: Grafts::of(repo)?,
+        exclude_promisor: true,
+        ..ListOptions::default()
+    };
+    list_objects_with(repo, &roots, &[], &options)
+}
+
+/// Move every loose reference into `packed-refs`, like `git pack-refs --all`
+///
+/// Symbolic references stay loose. Returns the names of the packed ones.
+/// With reftables, every table is merged into one instead.
+pub fn pack_refs(repo: &Repository, dry_run: bool) -> Result<Vec<String>> {
+    if repo.ref_storage() == RefStorage::Reftable {
+        return reftable::compact(repo, dry_run);
+    }
+    let mut loose = Vec::new();
+    let mut stack = vec![repo.commondir().join("refs")];
+    while let Some(dir) = stack.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_dir() {
+                stack.push(path);
+                continue;
+            }
+            let name = ref_name(repo, &path);
+            // Per-worktree references are never packed
+            if is_per_worktree(&name) {
+                continue;
+            }
+            if let Some(RefValue::Direct(hash)) = read_ref(repo, &name)? {
+                loose.push((name, hash, path));
+            }
+        }
+    }
+    loose.sort();
+    if dry_run || loose.is_empty() {
+        return Ok(loose.into_iter().map(|(name, _, _)| name).collect());
+    }
+    let mut packed = read_packed_refs(repo)?;
+    for (name, hash, _) in &loose {
+        packed.insert(name.clone(), hash.clone());
+    }
+    write_packed_refs(repo, &packed)?;
+    let mut names = Vec::new();
+    for (name, _, path) in loose {
+        fs::remove_file(&path)?;
+        // Empty directories are left behind, except for the standard ones
+        let mut dir = path.parent();
+        while let Some(parent) = dir {
+            let relative = parent.strip_prefix(repo.commondir())?;
+            let standard = ["refs", "refs/heads", "refs/tags"].map(Path::new);
+            if standard.contains(&relative) || fs::remove_dir(parent).is_err() {
+                break;
+            }
+            dir = parent.parent();
+        }
+        names.push(name);
+    }
+    Ok(names)
+}
+
+/// Drop reflog entries older than `cutoff`, and those older than
+/// `unreachable_cutoff` that are no longer reachable from the reference,
+/// like `git reflog expire --all`
+///
+/// A cutoff of `None` keeps every entry. Returns how many entries went.
+pub fn expire_reflogs(
+    repo: &Repository,
+    cutoff: Option<i64>,
+    unreachable_cutoff: Option<i64>,
+    dry_run: bool,
+) -> Result<usize> {
+    let mut expired = 0;
+    for name in reflog_names(repo)? {
+        let entries = read_reflog(repo, &name)?;
+        let reachable = match resolve_ref(repo, &name)? {
+            Some(tip) => match peel_to_commit(repo, &tip) {
+                Ok(commit) => ancestors(repo, &commit)?,
+                Err(_) => HashSet::from([tip]),
+            },
+            None => HashSet::new(),
+        };
+        let before = entries.len();
+        let kept = entries
+            .into_iter()
+            .filter(|entry| {
+                let time = Signature::parse(&entry.committer).map_or(0, |s| s.time);
+                let old = cutoff.is_some_and(|cutoff| time <= cutoff);
+                let unreachable = unreachable_cutoff.is_some_and(|cutoff| time <= cutoff)
+                    && !reachable.contains(&entry.new);
+                !old && !unreachable
+            })
+            .collect::<Vec<_>>();
+        if kept.len() < before {
+            expired += before - kept.len();
+            if !dry_run {
+                write_reflog(repo, &name, &kept)?;
+            }
+        }
+    }
+    Ok(expired)
+}
+
+/// Remove the unreachable loose objects not modified since `cutoff`, like
+/// `git prune --expire`, and return them
+pub fn prune(repo: &Repository, cutoff: i64, dry_run: bool) -> Result<Vec<ObjectHash>> {
+    if repo.settings().extensions.preciousobjects {
+        bail!("Cannot prune in a precious-objects repo");
+    }
+    let reachable = reachable_objects(repo)?
+        .objects
+        .into_iter()
+        .map(|(hash, _)| hash)
+        .collect::<HashSet<_>>();
+    let mut pruned = Vec::new();
+    for (hash, path) in loose_objects(repo)? {
+        if reachable.contains(&hash) || modified_time(&path)? > cutoff {
+            continue;
+        }
+        if !dry_run {
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+        pruned.push(hash);
+    }
+    if !dry_run {
+        remove_empty_fanout_dirs(repo)?;
+    }
+    Ok(pruned)
+}
+
+/// Parse an expiry date such as `2.weeks.ago`, `now` or `never` into the
+/// time at or before which things expire; `None` means never
+pub fn parse_expiry(value: &str, now: i64) -> Result<Option<i64>> {
+    let value = value.trim();
+    match value {
+        "never" | "false" => return Ok(None),
+        "now" | "all" => return Ok(Some(now)),
+        _ => {}
+    }
+    let words = value
+        .split(['.', ' '])
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+    let [count, unit, "ago"] = words[..] else {
+        bail!("Invalid expiry date '{}'", value);
+    };
+    let count = count
+        .parse::<i64>()
+        .with_context(|| format!("Invalid expiry date '{}'", value))?;
+    let seconds = match unit.trim_end_matches('s') {
+        "second" => 1,
+        "minute" => 60,
+        "hour" => 60 * 60,
+        "day" => 24 * 60 * 60,
+        "week" => 7 * 24 * 60 * 60,
+        "month" => 30 * 24 * 60 * 60,
+        "year" => 365 * 24 * 60 * 60,
+        _ => bail!("Invalid expiry date '{}'", value),
+    };
+    Ok(Some(now - count * seconds))
+}
+
+/// Write the objects of a pack that are not reachable as loose objects,
+/// dated like the pack so that they keep its age
+fn loosen_unreachable(
+    repo: &Repository,
+    pack: &Pack,
+    reachable: &HashSet<ObjectHash>,
+) -> Result<usize> {
+    let modified = fs::metadata(pack.path())?.modified()?;
+    let mut loosened = 0;
+    for hash in pack.index().hashes() {
+        if reachable.contains(&hash) {
+            continue;
+        }
+        let (dir, file) = hash.as_path_parts();
+        let path = repo.commondir().join("objects").join(dir).join(file);
+        if path.exists() {
+            continue;
+        }
+        store_object(&read_object(repo, &hash)?, repo)?;
+        fs::File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(modified)?;
+        loosened += 1;
+    }
+    Ok(loosened)
+}
+
+fn remove_empty_fanout_dirs(repo: &Repository) -> Result<()> {
+    for entry in fs::read_dir(repo.commondir().join("objects"))? {
+        let dir = entry?.path();
+        let is_fanout = dir
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.len() == 2 && n.bytes().all(|b| b.is_ascii_hexdigit()));
+        if is_fanout && fs::read_dir(&dir)?.next().is_none() {
+            fs::remove_dir(&dir)?;
+        }
+    }
+    Ok(())
+}
+
+/// List the references that have a reflog, `HEAD` included
+pub(crate) fn reflog_names(repo: &Repository) -> Result<Vec<String>> {
+    if repo.ref_storage() == RefStorage::Reftable {
+        return reftable::reflog_names(repo);
+    }
+    let mut names = Vec::new();
+    for root in ref_roots(repo) {
+        let logs = root.join("logs");
+        let mut stack = vec![logs.clone()];
+        while let Some(dir) = stack.pop() {
+            let Ok(entries) = fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries {
+                let path = entry?.path();
+                if path.is_dir() {
+                    stack.push(path);
+                    continue;
+                }
+                let name = path
+                    .strip_prefix(&logs)?
+                    .to_string_lossy()
+                    .replace('\\', "/");
+                if ref_root(repo, &name) == root {
+                    names.push(name);
+                }
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+fn ref_name(repo: &Repository, path: &Path) -> String {
+    path.strip_prefix(repo.commondir())
+        .unwrap_or(path)
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+
+fn modified_time(path: &Path) -> Result<i64> {
+    let modified = fs::metadata(path)?.modified()?;
+    Ok(modified
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64))
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commits::Commit;
+    use crate::test_utils::{commit_files, init_repo, write_blob};
+
+    #[test]
+    fn test_gc() {
+        let (_dir, repo) = init_repo();
+        commit_files(&repo, &[("a.txt", "one\n")], "first");
+        let head = commit_files(&repo, &[("a.txt", "two\n")], "second");
+        let garbage = write_blob(&repo, b"unreachable\n");
+
+        let dry_run = GcOptions {
+            dry_run: true,
+            ..GcOptions::default()
+        };
+        let report = gc(&repo, &dry_run).unwrap();
+        assert_eq!(report.packed_refs, ["refs/heads/master"]);
+        assert_eq!(report.repack.packed_objects, 6);
+        assert!(report.pruned_objects.is_empty());
+        assert!(packs(&repo).unwrap().is_empty());
+
+        let options = GcOptions {
+            prune_expire: Some("now".to_string()),
+            ..GcOptions::default()
+        };
+        let report = gc(&repo, &options).unwrap();
+        assert_eq!(report.repack.removed_loose_objects, 6);
+        assert_eq!(report.pruned_objects, std::slice::from_ref(&garbage));
+        assert!(!object_exists(&repo, &garbage));
+        assert_eq!(packs(&repo).unwrap()[0].index().len(), 6);
+        assert!(!repo.gitdir().join("refs/heads/master").exists());
+        assert_eq!(resolve_ref(&repo, "HEAD").unwrap(), Some(head.clone()));
+        assert_eq!(
+            Commit::read(&repo, &head).unwrap().parents.len(),
+            1,
+            "packed history stays readable"
+        );
+    }
+
+    #[test]
+    fn test_gc_keeps_precious_objects() {
+        let (dir, repo) = init_repo();
+        commit_files(&repo, &[("a.txt", "one\n")], "first");
+        let garbage = write_blob(&repo, b"unreachable\n");
+        let mut config = repo.config().unwrap();
+        config.set("extensions.preciousObjects", "true").unwrap();
+        repo.write_config(&config).unwrap();
+        let repo = Repository::open(dir.path()).unwrap();
+
+        let options = GcOptions {
+            prune_expire: Some("now".to_string()),
+            ..GcOptions::default()
+        };
+        let report = gc(&repo, &options).unwrap();
+        assert_eq!(report.repack.packed_objects, 3);
+        assert!(report.pruned_objects.is_empty());
+        assert!(object_exists(&repo, &garbage));
+        assert!(prune(&repo, now(), false).is_err());
+        let repack_options = RepackOptions {
+            all: true,
+            delete: true,
+            ..RepackOptions::default()
+        };
+        assert!(repack(&repo, &repack_options).is_err());
+    }
+
+    #[test]
+    fn test_parse_expiry() {
+        assert_eq!(parse_expiry("never", 1000).unwrap(), None);
+        assert_eq!(parse_expiry("now", 1000).unwrap(), Some(1000));
+        assert_eq!(
+            parse_expiry("2.weeks.ago", 2_000_000).unwrap(),
+            Some(790_400)
+        );
+        assert_eq!(parse_expiry("1 hour ago", 7200).unwrap(), Some(3600));
+        assert!(parse_expiry("yesterday", 0).is_err());
+    }
+}
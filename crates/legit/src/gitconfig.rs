@@ -0,0 +1,524 @@
+use crate::refs::write_atomic;
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Line is a single line of a git config file
+#[derive(Debug, Clone, PartialEq)]
+enum Line {
+    /// A `[section]` or `[section "subsection"]` header
+    Section {
+        name: String,
+        subsection: Option<String>,
+    },
+    /// A `key = value` entry belonging to the last section above it. The raw
+    /// text is kept so that untouched entries are written back verbatim.
+    Entry {
+        section: String,
+        subsection: Option<String>,
+        key: String,
+        value: String,
+        raw: Option<String>,
+    },
+    /// Comments and blank lines
+    Other(String),
+}
+
+/// GitConfig gives raw access to a git config file
+///
+/// `Settings` deserializes the well-known `core` options; GitConfig covers the
+/// rest: subsections (`[branch "master"]`), multi-valued keys (`remote.*.fetch`)
+/// and editing the file while keeping its comments and layout.
+///
+/// Keys are written as `section.subsection.name`. Section and key names are
+/// case-insensitive while subsections are case-sensitive, as in git.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GitConfig {
+    lines: Vec<Line>,
+}
+
+/// Split a `section.subsection.key` name into its normalized parts
+fn split_key(key: &str) -> Result<(String, Option<String>, String)> {
+    let (section, rest) = key
+        .split_once('.')
+        .ok_or_else(|| anyhow::anyhow!("Invalid config key: {}", key))?;
+    let (subsection, name) = match rest.rsplit_once('.') {
+        Some((subsection, name)) => (Some(subsection.to_string()), name),
+        None => (None, rest),
+    };
+    if section.is_empty() || name.is_empty() {
+        bail!("Invalid config key: {}", key);
+    }
+    Ok((section.to_lowercase(), subsection, name.to_lowercase()))
+}
+
+impl GitConfig {
+    /// Read a config file, returning an empty config if it does not exist
+    pub fn read(path: &Path) -> Result<GitConfig> {
+        if !path.exists() {
+            return Ok(GitConfig::default());
+        }
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+        GitConfig::parse(&text).with_context(|| format!("Invalid config {}", path.display()))
+    }
+
+    /// Parse the text of a config file
+    pub fn parse(text: &str) -> Result<GitConfig> {
+        let mut lines = Vec::new();
+        let mut section = String::new();
+        let mut subsection = None;
+        let mut raw_lines = text.lines();
+        while let Some(line) = raw_lines.next() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+                lines.push(Line::Other(line.to_string()));
+                continue;
+            }
+            if let Some(header) = trimmed.strip_prefix('[') {
+                let (header, _) = header
+                    .split_once(']')
+                    .ok_or_else(|| anyhow::anyhow!("Unterminated section header: {}", line))?;
+                (section, subsection) = parse_section_header(header)?;
+                lines.push(Line::Section {
+                    name: section.clone(),
+                    subsection: subsection.clone(),
+                });
+                continue;
+            }
+            if section.is_empty() {
+                bail!("Config entry outside of a section: {}", line);
+            }
+
+            // Values ending in a backslash continue on the next line
+            let mut entry = trimmed.to_string();
+            let mut raw = line.to_string();
+            while entry.ends_with('\\') && !entry.ends_with("\\\\") {
+                entry.pop();
+                let Some(next) = raw_lines.next() else { break };
+                entry.push_str(next);
+                raw.push('\n');
+                raw.push_str(next);
+            }
+
+            let (key, value) = match entry.split_once('=') {
+                Some((key, value)) => (key.trim(), parse_value(value)?),
+                // A key without a value is a boolean set to true
+                None => (entry.trim(), "true".to_string()),
+            };
+            lines.push(Line::Entry {
+                section: section.clone(),
+                subsection: subsection.clone(),
+                key: key.to_lowercase(),
+                value,
+                raw: Some(raw),
+            });
+        }
+        Ok(GitConfig { lines })
+    }
+
+    fn entries(&self, key: &str) -> impl Iterator<Item = (usize, &str)> {
+        let parts = split_key(key).ok();
+        self.lines
+            .iter()
+            .enumerate()
+            .filter_map(move |(i, line)| match (line, &parts) {
+                (
+                    Line::Entry {
+                        section,
+                        subsection,
+                        key,
+                        value,
+                        ..
+                    },
+                    Some((s, sub, k)),
+                ) if section == s && subsection == sub && key == k => Some((i, value.as_str())),
+                _ => None,
+            })
+    }
+
+    /// Return the value of a key; the last one wins if it is set multiple times
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries(key).last().map(|(_, value)| value)
+    }
+
+    /// Return every value of a multi-valued key in file order
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        self.entries(key).map(|(_, value)| value).collect()
+    }
+
+    /// Return the value of a key interpreted as a git boolean
+    pub fn get_bool(&self, key: &str) -> Result<Option<bool>> {
+        self.get(key).map(parse_bool).transpose()
+    }
+
+    /// Return the value of a key interpreted as an integer with an optional
+    /// `k`, `m` or `g` suffix
+    pub fn get_int(&self, key: &str) -> Result<Option<i64>> {
+        self.get(key).map(parse_int).transpose()
+    }
+
+    /// Set a key, replacing its last value or adding it to its section
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match self.entries(key).last().map(|(i, _)| i) {
+            Some(i) => {
+                if let Line::Entry { value: v, raw, .. } = &mut self.lines[i] {
+                    *v = value.to_string();
+                    *raw = None;
+                }
+                Ok(())
+            }
+            None => self.add(key, value),
+        }
+    }
+
+    /// Add a value to a key without replacing existing ones
+    pub fn add(&mut self, key: &str, value: &str) -> Result<()> {
+        let (section, subsection, name) = split_key(key)?;
+        let entry = Line::Entry {
+            section: section.clone(),
+            subsection: subsection.clone(),
+            key: name,
+            value: value.to_string(),
+            raw: None,
+        };
+        // Insert after the last line of the last matching section
+        let mut position = None;
+        let mut in_section = false;
+        for (i, line) in self.lines.iter().enumerate() {
+            match line {
+                Line::Section {
+                    name,
+                    subsection: sub,
+                } => in_section = *name == section && *sub == subsection,
+                Line::Entry { .. } if in_section => position = Some(i + 1),
+                _ => {}
+            }
+            if in_section && position.is_none() {
+                position = Some(i + 1);
+            }
+        }
+        match position {
+            Some(i) => self.lines.insert(i, entry),
+            None => {
+                self.lines.push(Line::Section {
+                    name: section,
+                    subsection,
+                });
+                self.lines.push(entry);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove every value of a key, returning true if anything was removed
+    pub fn unset_all(&mut self, key: &str) -> bool {
+        let indices = self.entries(key).map(|(i, _)| i).collect::<Vec<_>>();
+        for i in indices.iter().rev() {
+            self.lines.remove(*i);
+        }
+        !indices.is_empty()
+    }
+
+    /// Remove a section and all of its entries
+    pub fn remove_section(&mut self, section: &str, subsection: Option<&str>) -> bool {
+        let section = section.to_lowercase();
+        let subsection = subsection.map(str::to_string);
+        let len = self.lines.len();
+        self.lines.retain(|line| match line {
+            Line::Section {
+                name,
+                subsection: sub,
+            }
+            | Line::Entry {
+                section: name,
+                subsection: sub,
+                ..
+            } => !(*name == section && *sub == subsection),
+            Line::Other(_) => true,
+        });
+        self.lines.len() != len
+    }
+
+    /// List the subsections of a section, e.g. the remote names of `remote`
+    pub fn subsections(&self, section: &str) -> Vec<String> {
+        let section = section.to_lowercase();
+        let mut names = Vec::new();
+        for line in &self.lines {
+            if let Line::Section {
+                name,
+                subsection: Some(sub),
+            } = line
+            {
+                if *name == section && !names.contains(sub) {
+                    names.push(sub.clone());
+                }
+            }
+        }
+        names
+    }
+
+    /// List the lowercased key names set directly in a section, without
+    /// the ones of its subsections
+    pub fn keys(&self, section: &str) -> Vec<String> {
+        let section = section.to_lowercase();
+        let mut keys = Vec::new();
+        for line in &self.lines {
+            if let Line::Entry {
+                section: s,
+                subsection: None,
+                key,
+                ..
+            } = line
+            {
+                if *s == section && !keys.contains(key) {
+                    keys.push(key.clone());
+                }
+            }
+        }
+        keys
+    }
+
+    /// Read the user's global config from `$XDG_CONFIG_HOME/git/config` and
+    /// `~/.gitconfig`, the latter taking precedence
+    pub fn global() -> Result<GitConfig> {
+        let mut config = GitConfig::default();
+        let xdg = xdg_config_home().map(|dir| dir.join("git").join("config"));
+        let home = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".gitconfig"));
+        for path in [xdg, home].into_iter().flatten() {
+            config.lines.extend(GitConfig::read(&path)?.lines);
+        }
+        Ok(config)
+    }
+
+    /// Write the config file atomically
+    pub fn write(&self, path: &Path) -> Result<()> {
+        write_atomic(path, self.to_string().as_bytes())
+    }
+}
+
+impl std::fmt::Display for GitConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for line in &self.lines {
+            match line {
+                Line::Section {
+                    name,
+                    subsection: None,
+                } => writeln!(f, "[{}]", name)?,
+                Line::Section {
+                    name,
+                    subsection: Some(sub),
+                } => writeln!(
+                    f,
+                    "[{} \"{}\"]",
+                    name,
+                    sub.replace('\\', "\\\\").replace('"', "\\\"")
+                )?,
+                Line::Entry { raw: Some(raw), .. } | Line::Other(raw) => writeln!(f, "{}", raw)?,
+                Line::Entry { key, value, .. } => {
+                    writeln!(f, "\t{} = {}", key, format_value(value))?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse the inside of a section header, e.g. `remote "origin"`
+fn parse_section_header(header: &str) -> Result<(String, Option<String>)> {
+    let header = header.trim();
+    match header.split_once(char::is_whitespace) {
+        Some((name, subsection)) => {
+            let subsection = subsection.trim();
+            let quoted = subsection
+                .strip_prefix('"')
+                .and_then(|s| s.strip_suffix('"'))
+                .ok_or_else(|| anyhow::anyhow!("Invalid subsection: {}", subsection))?;
+            let mut value = String::new();
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    _ => value.push(c),
+                }
+            }
+            Ok((name.to_lowercase(), Some(value)))
+        }
+        // The deprecated `[section.subsection]` syntax
+        None => match header.split_once('.') {
+            Some((name, subsection)) => Ok((name.to_lowercase(), Some(subsection.to_lowercase()))),
+            None => Ok((header.to_lowercase(), None)),
+        },
+    }
+}
+
+/// Parse a value: strip comments and surrounding whitespace, handle quotes
+/// and escape sequences
+fn parse_value(raw: &str) -> Result<String> {
+    let mut value = String::new();
+    let mut quoted = false;
+    // Whitespace outside quotes is only kept between words
+    let mut pending_space = String::new();
+    let mut chars = raw.trim().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                value.push_str(&pending_space);
+                pending_space.clear();
+            }
+            '\\' => {
+                value.push_str(&pending_space);
+                pending_space.clear();
+                match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('b') => {
+                        value.pop();
+                    }
+                    Some(c @ ('\\' | '"')) => value.push(c),
+                    other => bail!("Invalid escape sequence: \\{}", other.unwrap_or(' ')),
+                }
+            }
+            '#' | ';' if !quoted => break,
+            c if c.is_whitespace() && !quoted => pending_space.push(c),
+            c => {
+                value.push_str(&pending_space);
+                pending_space.clear();
+                value.push(c);
+            }
+        }
+    }
+    if quoted {
+        bail!("Unterminated quoted value: {}", raw);
+    }
+    Ok(value)
+}
+
+/// Quote a value if it would not survive `parse_value` unchanged
+fn format_value(value: &str) -> String {
+    let needs_quotes = value.starts_with(char::is_whitespace)
+        || value.ends_with(char::is_whitespace)
+        || value.contains(['#', ';']);
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+    if needs_quotes {
+        format!("\"{}\"", escaped)
+    } else {
+        escaped
+    }
+}
+
+/// Expand a leading `~/` to the home directory
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Return `$XDG_CONFIG_HOME`, defaulting to `~/.config`
+pub fn xdg_config_home() -> Option<PathBuf> {
+    match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")),
+    }
+}
+
+/// Parse a git boolean (`true`/`yes`/`on`/`1` and their opposites)
+pub fn parse_bool(value: &str) -> Result<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" | "" => Ok(false),
+        _ => bail!("Invalid boolean value: {}", value),
+    }
+}
+
+/// Parse a git integer with an optional `k`, `m` or `g` suffix
+pub fn parse_int(value: &str) -> Result<i64> {
+    let value = value.trim().to_lowercase();
+    let (number, factor) = match value.chars().last() {
+        Some('k') => (&value[..value.len() - 1], 1024),
+        Some('m') => (&value[..value.len() - 1], 1024 * 1024),
+        Some('g') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value.as_str(), 1),
+    };
+    Ok(number
+        .parse::<i64>()
+        .with_context(|| format!("Invalid integer value: {}", value))?
+        * factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"# a comment
+[core]
+	repositoryformatversion = 0
+	bare
+[remote "origin"]
+	url = /tmp/origin ; trailing comment
+	fetch = +refs/heads/*:refs/remotes/origin/*
+	fetch = +refs/tags/*:refs/tags/*
+[Branch "Main"]
+	remote = origin
+	merge = "refs/heads/main"
+"#;
+
+    #[test]
+    fn test_get() {
+        let config = GitConfig::parse(CONFIG).unwrap();
+        assert_eq!(config.get("core.repositoryformatversion"), Some("0"));
+        assert_eq!(config.keys("Core"), ["repositoryformatversion", "bare"]);
+        assert_eq!(config.get_bool("core.bare").unwrap(), Some(true));
+        assert_eq!(config.get("remote.origin.url"), Some("/tmp/origin"));
+        assert_eq!(config.get_all("remote.origin.fetch").len(), 2);
+        assert_eq!(config.get("branch.Main.merge"), Some("refs/heads/main"));
+        assert_eq!(config.get("BRANCH.Main.Remote"), Some("origin"));
+        assert_eq!(config.get("branch.main.remote"), None);
+        assert_eq!(config.subsections("remote"), vec!["origin"]);
+    }
+
+    #[test]
+    fn test_roundtrip_keeps_layout() {
+        let config = GitConfig::parse(CONFIG).unwrap();
+        let text = config.to_string();
+        assert!(text.contains("# a comment"));
+        assert!(text.contains("url = /tmp/origin ; trailing comment"));
+        assert_eq!(GitConfig::parse(&text).unwrap(), config);
+    }
+
+    #[test]
+    fn test_set_add_and_unset() {
+        let mut config = GitConfig::parse(CONFIG).unwrap();
+        config.set("remote.origin.url", "/new path").unwrap();
+        config.set("user.name", "Some One").unwrap();
+        config
+            .add("remote.origin.fetch", "+refs/notes/*:refs/notes/*")
+            .unwrap();
+        let config = GitConfig::parse(&config.to_string()).unwrap();
+        assert_eq!(config.get("remote.origin.url"), Some("/new path"));
+        assert_eq!(config.get("user.name"), Some("Some One"));
+        assert_eq!(config.get_all("remote.origin.fetch").len(), 3);
+
+        let mut config = config;
+        assert!(config.unset_all("remote.origin.fetch"));
+        assert!(config.get("remote.origin.fetch").is_none());
+        assert!(config.remove_section("remote", Some("origin")));
+        assert!(config.subsections("remote").is_empty());
+    }
+
+    #[test]
+    fn test_values() {
+        let config =
+            GitConfig::parse("[a]\n\tb = \" x \" # c\n\tc = one\\\n two\n\td = 2k\n").unwrap();
+        assert_eq!(config.get("a.b"), Some(" x "));
+        assert_eq!(config.get("a.c"), Some("one two"));
+        assert_eq!(config.get_int("a.d").unwrap(), Some(2048));
+        assert!(GitConfig::parse("[a]\n\tb = \"open\n").is_err());
+    }
+}
@@ -0,0 +1,657 @@
+use crate::checkout::normalize_path;
+use crate::gitconfig::{expand_home, xdg_config_home, GitConfig};
+use crate::index::Index;
+use crate::Repository;
+use anyhow::{Context, Result};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Result of matching a pattern, following git's wildmatch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Matched,
+    NoMatch,
+    /// Give up completely: the rest of the text cannot match
+    AbortAll,
+    /// Give up until the enclosing `**`, which can consume a `/`
+    AbortToStarStar,
+}
+
+/// Return the byte at `i`, or 0 past the end, mimicking a C string
+fn at(s: &[u8], i: usize) -> u8 {
+    s.get(i).copied().unwrap_or(0)
+}
+
+/// Match a path against a pattern with git's wildmatch semantics
+///
+/// `*` and `?` do not match `/`, `**` matches across directories when it
+/// forms a whole path component, `[...]` supports ranges, negation with `!`
+/// or `^` and POSIX classes such as `[:alpha:]`, and `\` escapes the next
+/// character.
+pub fn wildmatch(pattern: &str, text: &str) -> bool {
+    dowild(pattern.as_bytes(), text.as_bytes()) == Outcome::Matched
+}
+
+fn dowild(p: &[u8], text: &[u8]) -> Outcome {
+    let mut pi = 0;
+    let mut ti = 0;
+    while pi < p.len() {
+        let mut p_ch = p[pi];
+        let mut t_ch = at(text, ti);
+        if t_ch == 0 && p_ch != b'*' {
+            return Outcome::AbortAll;
+        }
+        match p_ch {
+            b'?' => {
+                if t_ch == b'/' {
+                    return Outcome::NoMatch;
+                }
+            }
+            b'*' => {
+                pi += 1;
+                let match_slash = if at(p, pi) == b'*' {
+                    let first_star = pi - 1;
+                    while at(p, pi) == b'*' {
+                        pi += 1;
+                    }
+                    let starts_component = first_star == 0 || p[first_star - 1] == b'/';
+                    let ends_component = pi == p.len()
+                        || at(p, pi) == b'/'
+                        || (at(p, pi) == b'\\' && at(p, pi + 1) == b'/');
+                    if starts_component && ends_component {
+                        // "**/" may match no directory at all
+                        if at(p, pi) == b'/'
+                            && dowild(&p[pi + 1..], &text[ti..]) == Outcome::Matched
+                        {
+                            return Outcome::Matched;
+                        }
+                        true
+                    } else {
+                        false
+                    }
+                } else {
+                    false
+                };
+
+                if pi == p.len() {
+                    // A trailing "**" matches everything, "*" only within a component
+                    if !match_slash && text[ti..].contains(&b'/') {
+                        return Outcome::NoMatch;
+                    }
+                    return Outcome::Matched;
+                }
+                if !match_slash && p[pi] == b'/' {
+                    // A single star followed by a slash matches the next component
+                    match text[ti..].iter().position(|&b| b == b'/') {
+                        Some(slash) => {
+                            ti += slash;
+                            pi += 1;
+                            ti += 1;
+                            continue;
+                        }
+                        None => return Outcome::NoMatch,
+                    }
+                }
+                loop {
+                    if t_ch == 0 {
+                        break;
+                    }
+                    // Skip ahead to the next occurrence of a literal
+                    if !is_glob_special(p[pi]) {
+                        let literal = p[pi];
+                        while ti < text.len() && (match_slash || text[ti] != b'/') {
+                            if text[ti] == literal {
+                                break;
+                            }
+                            ti += 1;
+                        }
+                        t_ch = at(text, ti);
+                        if t_ch != literal {
+                            return Outcome::NoMatch;
+                        }
+                    }
+                    let matched = dowild(&p[pi..], &text[ti..]);
+                    if matched != Outcome::NoMatch {
+                        if !match_slash || matched != Outcome::AbortToStarStar {
+                            return matched;
+                        }
+                    } else if !match_slash && t_ch == b'/' {
+                        return Outcome::AbortToStarStar;
+                    }
+                    ti += 1;
+                    t_ch = at(text, ti);
+                }
+                return Outcome::AbortAll;
+            }
+            b'[' => {
+                pi += 1;
+                p_ch = at(p, pi);
+                if p_ch == b'^' {
+                    p_ch = b'!';
+                }
+                let negated = p_ch == b'!';
+                if negated {
+                    pi += 1;
+                    p_ch = at(p, pi);
+                }
+                let mut prev_ch = 0u8;
+                let mut matched = false;
+                loop {
+                    if p_ch == 0 {
+                        return Outcome::AbortAll;
+                    }
+                    if p_ch == b'\\' {
+                        pi += 1;
+                        p_ch = at(p, pi);
+                        if p_ch == 0 {
+                            return Outcome::AbortAll;
+                        }
+                        if t_ch == p_ch {
+                            matched = true;
+                        }
+                    } else if p_ch == b'-'
+                        && prev_ch != 0
+                        && at(p, pi + 1) != 0
+                        && at(p, pi + 1) != b']'
+                    {
+                        pi += 1;
+                        p_ch = at(p, pi);
+                        if p_ch == b'\\' {
+                            pi += 1;
+                            p_ch = at(p, pi);
+                            if p_ch == 0 {
+                                return Outcome::AbortAll;
+                            }
+                        }
+                        if t_ch <= p_ch && t_ch >= prev_ch {
+                            matched = true;
+                        }
+                        p_ch = 0;
+                    } else if p_ch == b'[' && at(p, pi + 1) == b':' {
+                        let start = pi + 2;
+                        let Some(len) = p[start..].iter().position(|&b| b == b']') else {
+                            return Outcome::AbortAll;
+                        };
+                        let end = start + len;
+                        if len == 0 || p[end - 1] != b':' {
+                            // Not a "[:class:]": treat the '[' literally
+                            if t_ch == b'[' {
+                                matched = true;
+                            }
+                        } else {
+                            match char_class(&p[start..end - 1], t_ch) {
+                                Some(true) => matched = true,
+                                Some(false) => {}
+                                None => return Outcome::AbortAll,
+                            }
+                            pi = end;
+                            p_ch = 0;
+                        }
+                    } else if t_ch == p_ch {
+                        matched = true;
+                    }
+                    prev_ch = p_ch;
+                    pi += 1;
+                    p_ch = at(p, pi);
+                    if p_ch == b']' {
+                        break;
+                    }
+                }
+                if matched == negated || t_ch == b'/' {
+                    return Outcome::NoMatch;
+                }
+            }
+            _ => {
+                if p_ch == b'\\' {
+                    pi += 1;
+                    p_ch = at(p, pi);
+                }
+                if t_ch != p_ch {
+                    return Outcome::NoMatch;
+                }
+            }
+        }
+        pi += 1;
+        ti += 1;
+    }
+    if ti < text.len() {
+        Outcome::NoMatch
+    } else {
+        Outcome::Matched
+    }
+}
+
+fn is_glob_special(c: u8) -> bool {
+    matches!(c, b'*' | b'?' | b'[' | b'\\')
+}
+
+/// Match a character against a POSIX class name, `None` if the name is unknown
+fn char_class(name: &[u8], c: u8) -> Option<bool> {
+    Some(match name {
+        b"alnum" => c.is_ascii_alphanumeric(),
+        b"alpha" => c.is_ascii_alphabetic(),
+        b"blank" => c == b' ' || c == b'\t',
+        b"cntrl" => c.is_ascii_control(),
+        b"digit" => c.is_ascii_digit(),
+        b"graph" => c.is_ascii_graphic(),
+        b"lower" => c.is_ascii_lowercase(),
+        b"print" => c.is_ascii_graphic() || c == b' ',
+        b"punct" => c.is_ascii_punctuation(),
+        b"space" => c.is_ascii_whitespace(),
+        b"upper" => c.is_ascii_uppercase(),
+        b"xdigit" => c.is_ascii_hexdigit(),
+        _ => return None,
+    })
+}
+
+/// Pattern is a single line of an ignore file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    /// The pattern as written in the file, used for `check-ignore -v`
+    pub text: String,
+    /// The file the pattern comes from, as displayed by `check-ignore -v`
+    pub source: String,
+    /// The 1-based line number in the source file
+    pub line: usize,
+    /// Directory of the `.gitignore` relative to the worktree, `""` for the root
+    base: String,
+    glob: String,
+    pub negated: bool,
+    dir_only: bool,
+    /// Patterns without a slash match the file name at any depth
+    basename_only: bool,
+}
+
+impl Pattern {
+    /// Parse a line of an ignore file, returning `None` for blanks and comments
+    pub fn parse(line: &str, source: &str, number: usize, base: &str) -> Option<Pattern> {
+        let line = line.trim_end_matches(['\n', '\r']);
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let text = trim_trailing_spaces(line);
+        let mut glob = text.as_str();
+        let negated = glob.starts_with('!');
+        if negated {
+            glob = &glob[1..];
+        }
+        let dir_only = glob.ends_with('/') && glob.len() > 1;
+        if dir_only {
+            glob = &glob[..glob.len() - 1];
+        }
+        if glob.is_empty() {
+            return None;
+        }
+        let basename_only = !glob.contains('/');
+        let glob = glob.strip_prefix('/').unwrap_or(glob);
+        Some(Pattern {
+            text: text.clone(),
+            source: source.to_string(),
+            line: number,
+            base: base.to_string(),
+            glob: glob.to_string(),
+            negated,
+            dir_only,
+            basename_only,
+        })
+    }
+
+    /// Return true if the pattern matches a path relative to the worktree
+    pub fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let relative = match self.base.as_str() {
+            "" => path,
+            base => match path.strip_prefix(base).and_then(|p| p.strip_prefix('/')) {
+                Some(relative) => relative,
+                None => return false,
+            },
+        };
+        if self.basename_only {
+            let name = relative.rsplit('/').next().unwrap_or(relative);
+            wildmatch(&self.glob, name)
+        } else {
+            wildmatch(&self.glob, relative)
+        }
+    }
+}
+
+/// Remove trailing spaces unless they are escaped with a backslash
+fn trim_trailing_spaces(line: &str) -> String {
+    let mut end = line.len();
+    while end > 0 && line.as_bytes()[end - 1] == b' ' {
+        if end >= 2 && line.as_bytes()[end - 2] == b'\\' {
+            break;
+        }
+        end -= 1;
+    }
+    line[..end].to_string()
+}
+
+/// Read the patterns of an ignore file, returning none if it does not exist
+fn read_patterns(path: &Path, source: &str, base: &str) -> Result<Vec<Pattern>> {
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let content = String::from_utf8_lossy(&content);
+    Ok(content
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| Pattern::parse(line, source, i + 1, base))
+        .collect())
+}
+
+/// IgnoreRules decides which paths are ignored, like git's exclude machinery
+///
+/// Patterns come from, in decreasing order of precedence: the `.gitignore`
+/// files from the directory of the path up to the root, `.git/info/exclude`
+/// and the file named by `core.excludesFile` (by default
+/// `$XDG_CONFIG_HOME/git/ignore`). Within one file the last matching pattern
+/// wins. Nested `.gitignore` files are read lazily.
+pub struct IgnoreRules {
+    worktree: PathBuf,
+    /// `.gitignore` patterns keyed by directory relative to the worktree
+    per_directory: RefCell<HashMap<String, Vec<Pattern>>>,
+    exclude: Vec<Pattern>,
+    excludes_file: Vec<Pattern>,
+}
+
+impl IgnoreRules {
+    /// Load the ignore rules of a repository
+    pub fn load(repo: &Repository) -> Result<IgnoreRules> {
+        let exclude = read_patterns(
+            &repo.commondir().join("info").join("exclude"),
+            ".git/info/exclude",
+            "",
+        )?;
+        let excludes_file = match excludes_file_path(repo)? {
+            Some(path) => read_patterns(&path, &path.to_string_lossy(), "")?,
+            None => Vec::new(),
+        };
+        Ok(IgnoreRules {
+            worktree: repo.worktree().to_owned(),
+            per_directory: RefCell::new(HashMap::new()),
+            exclude,
+            excludes_file,
+        })
+    }
+
+    /// Create rules from the patterns of a single root `.gitignore`, mostly
+    /// useful for tests
+    pub fn from_patterns(worktree: &Path, patterns: &str) -> IgnoreRules {
+        let patterns = patterns
+            .lines()
+            .enumerate()
+            .filter_map(|(i, line)| Pattern::parse(line, ".gitignore", i + 1, ""))
+            .collect();
+        let mut per_directory = HashMap::new();
+        per_directory.insert(String::new(), patterns);
+        IgnoreRules {
+            worktree: worktree.to_owned(),
+            per_directory: RefCell::new(per_directory),
+            exclude: Vec::new(),
+            excludes_file: Vec::new(),
+        }
+    }
+
+    /// Return the `.gitignore` patterns of a directory, reading them once
+    fn directory_patterns(&self, dir: &str) -> Result<Vec<Pattern>> {
+        if let Some(patterns) = self.per_directory.borrow().get(dir) {
+            return Ok(patterns.clone());
+        }
+        let (file, source) = match dir {
+            "" => (self.worktree.join(".gitignore"), ".gitignore".to_string()),
+            _ => (
+                self.worktree.join(dir).join(".gitignore"),
+                format!("{}/.gitignore", dir),
+            ),
+        };
+        let patterns = read_patterns(&file, &source, dir)?;
+        self.per_directory
+            .borrow_mut()
+            .insert(dir.to_string(), patterns.clone());
+        Ok(patterns)
+    }
+
+    /// Return the pattern deciding whether a path is ignored, if any
+    ///
+    /// The pattern may be a negated one, in which case the path is explicitly
+    /// not ignored. Leading directories are not considered; see `matching`.
+    pub fn last_match(&self, path: &str, is_dir: bool) -> Result<Option<Pattern>> {
+        let mut dirs = Vec::new();
+        let mut dir = path;
+        while let Some((parent, _)) = dir.rsplit_once('/') {
+            dirs.push(parent);
+            dir = parent;
+        }
+        dirs.push("");
+
+        for dir in dirs {
+            let patterns = self.directory_patterns(dir)?;
+            if let Some(pattern) = patterns.iter().rev().find(|p| p.matches(path, is_dir)) {
+                return Ok(Some(pattern.clone()));
+            }
+        }
+        for patterns in [&self.exclude, &self.excludes_file] {
+            if let Some(pattern) = patterns.iter().rev().find(|p| p.matches(path, is_dir)) {
+                return Ok(Some(pattern.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Return the pattern that makes a path ignored, also considering its
+    /// leading directories: a file inside an ignored directory is ignored and
+    /// cannot be re-included.
+    pub fn matching(&self, path: &str, is_dir: bool) -> Result<Option<Pattern>> {
+        let mut prefix = String::new();
+        let components = path.split('/').collect::<Vec<_>>();
+        for component in &components[..components.len() - 1] {
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(component);
+            if let Some(pattern) = self.last_match(&prefix, true)? {
+                if !pattern.negated {
+                    return Ok(Some(pattern));
+                }
+            }
+        }
+        self.last_match(path, is_dir)
+    }
+
+    /// Return true if a path is ignored
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        matches!(self.matching(path, is_dir), Ok(Some(pattern)) if !pattern.negated)
+    }
+}
+
+/// Find the pattern deciding each path, as reported by `check-ignore`
+///
+/// Paths in the index are never ignored and get no pattern unless
+/// `no_index` is set. Whether a path is a directory is taken from the
+/// working tree.
+pub fn check_ignore(
+    repo: &Repository,
+    paths: &[String],
+    no_index: bool,
+) -> Result<Vec<(String, Option<Pattern>)>> {
+    let rules = IgnoreRules::load(repo)?;
+    let index = match no_index {
+        true => None,
+        false => Some(Index::read(repo)?),
+    };
+    let mut results = Vec::new();
+    for path in paths {
+        let normalized = normalize_path(path);
+        let tracked = index.as_ref().is_some_and(|i| i.contains(&normalized));
+        let pattern = match tracked {
+            true => None,
+            false => {
+                let is_dir = repo.worktree().join(&normalized).is_dir();
+                rules.matching(&normalized, is_dir)?
+            }
+        };
+        results.push((path.clone(), pattern));
+    }
+    Ok(results)
+}
+
+/// Locate the global excludes file from `core.excludesFile`, falling back to
+/// `$XDG_CONFIG_HOME/git/ignore`
+fn excludes_file_path(repo: &Repository) -> Result<Option<PathBuf>> {
+    let configured = repo
+        .config()?
+        .get("core.excludesfile")
+        .map(str::to_string)
+        .or_else(|| {
+            GitConfig::global()
+                .ok()?
+                .get("core.excludesfile")
+                .map(str::to_string)
+        });
+    if let Some(path) = configured {
+        return Ok(Some(expand_home(&path)));
+    }
+    Ok(xdg_config_home().map(|dir| dir.join("git").join("ignore")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::init_repo;
+
+    #[test]
+    fn test_wildmatch() {
+        let cases = [
+            ("foo", "foo", true),
+            ("foo", "bar", false),
+            ("*.o", "main.o", true),
+            ("*.o", "dir/main.o", false),
+            ("?ar", "bar", true),
+            ("?ar", "/ar", false),
+            ("a/*/c", "a/b/c", true),
+            ("a/*/c", "a/b/x/c", false),
+            ("a/**/c", "a/c", true),
+            ("a/**/c", "a/b/x/c", true),
+            ("**/foo", "x/y/foo", true),
+            ("**/foo", "foo", true),
+            ("foo/**", "foo/a/b", true),
+            ("foo/**", "foo", false),
+            ("a**b", "a/b", false),
+            ("[a-c]at", "bat", true),
+            ("[!a-c]at", "bat", false),
+            ("[^a-c]at", "rat", true),
+            ("[[:digit:]]x", "5x", true),
+            ("[[:digit:]]x", "ax", false),
+            ("\\*lit", "*lit", true),
+            ("\\*lit", "xlit", false),
+            ("[]]", "]", true),
+            ("[\\]]", "]", true),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(
+                wildmatch(pattern, text),
+                expected,
+                "{} against {}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_pattern_parse() {
+        assert!(Pattern::parse("# comment", ".gitignore", 1, "").is_none());
+        assert!(Pattern::parse("   ", ".gitignore", 1, "").is_none());
+        let pattern = Pattern::parse("!build/  ", ".gitignore", 2, "").unwrap();
+        assert!(pattern.negated);
+        assert!(pattern.dir_only);
+        assert_eq!(pattern.text, "!build/");
+        let escaped = Pattern::parse("\\#file\\ ", ".gitignore", 3, "").unwrap();
+        assert!(escaped.matches("#file ", false));
+    }
+
+    #[test]
+    fn test_pattern_matching() {
+        let rules = IgnoreRules::from_patterns(
+            Path::new("/nonexistent"),
+            "*.log\n!keep.log\nbuild/\n/root.txt\ndocs/*.html\n",
+        );
+        assert!(rules.is_ignored("debug.log", false));
+        assert!(rules.is_ignored("deep/dir/debug.log", false));
+        assert!(!rules.is_ignored("keep.log", false));
+        assert!(rules.is_ignored("build", true));
+        assert!(!rules.is_ignored("build", false));
+        assert!(rules.is_ignored("src/build/out.bin", false));
+        assert!(rules.is_ignored("root.txt", false));
+        assert!(!rules.is_ignored("sub/root.txt", false));
+        assert!(rules.is_ignored("docs/index.html", false));
+        assert!(!rules.is_ignored("docs/api/index.html", false));
+    }
+
+    #[test]
+    fn test_cannot_reinclude_in_ignored_directory() {
+        let rules = IgnoreRules::from_patterns(Path::new("/nonexistent"), "out/\n!out/keep\n");
+        assert!(rules.is_ignored("out/keep", false));
+        assert_eq!(
+            rules.matching("out/keep", false).unwrap().unwrap().text,
+            "out/"
+        );
+    }
+
+    #[test]
+    fn test_nested_gitignore_and_exclude() {
+        let (dir, repo) = init_repo();
+        fs::write(dir.path().join(".gitignore"), "*.tmp\n").unwrap();
+        fs::create_dir_all(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/.gitignore"), "!keep.tmp\nlocal\n").unwrap();
+        fs::create_dir_all(dir.path().join(".git/info")).unwrap();
+        fs::write(dir.path().join(".git/info/exclude"), "secret\n").unwrap();
+
+        let rules = IgnoreRules::load(&repo).unwrap();
+        assert!(rules.is_ignored("a.tmp", false));
+        assert!(!rules.is_ignored("sub/keep.tmp", false));
+        assert!(rules.is_ignored("sub/other.tmp", false));
+        assert!(rules.is_ignored("sub/local", false));
+        assert!(!rules.is_ignored("local", false));
+
+        let pattern = rules.matching("x/secret", false).unwrap().unwrap();
+        assert_eq!(pattern.source, ".git/info/exclude");
+        assert_eq!(pattern.line, 1);
+        let pattern = rules.matching("sub/keep.tmp", false).unwrap().unwrap();
+        assert_eq!(
+            (pattern.source.as_str(), pattern.line),
+            ("sub/.gitignore", 1)
+        );
+    }
+
+    #[test]
+    fn test_check_ignore_skips_tracked_paths() {
+        let (dir, repo) = init_repo();
+        fs::write(dir.path().join(".gitignore"), "*.o\n").unwrap();
+        fs::write(dir.path().join("tracked.o"), "x").unwrap();
+        let hash = crate::test_utils::write_blob(&repo, b"x");
+        let mut index = Index::default();
+        index
+            .add(crate::index::IndexEntry::new(
+                "tracked.o",
+                crate::tree::EntryMode::Blob,
+                hash,
+                None,
+            ))
+            .unwrap();
+        index.write(&repo).unwrap();
+
+        let paths = ["tracked.o".to_string(), "new.o".to_string()];
+        let results = check_ignore(&repo, &paths, false).unwrap();
+        assert!(results[0].1.is_none());
+        assert_eq!(results[1].1.as_ref().unwrap().text, "*.o");
+        let results = check_ignore(&repo, &paths, true).unwrap();
+        assert!(results[0].1.is_some());
+    }
+}
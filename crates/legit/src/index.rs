@@ -0,0 +1,371 @@
+use crate::objects::{HashAlgorithm, ObjectHash};
+use crate::refs::write_atomic;
+use crate::tree::{build_tree, flatten_tree, verify_path, EntryMode};
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::fs::{self, Metadata};
+
+/// Signature at the start of every index file
+const SIGNATURE: &[u8; 4] = b"DIRC";
+/// Size of the stat data at the start of an index entry, before the hash
+const ENTRY_STAT_SIZE: usize = 40;
+/// Flag bit marking an entry that uses the version 3 extended flags
+const EXTENDED_FLAG: u16 = 0x4000;
+
+/// IndexEntry is a single file tracked by the index (the staging area)
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct IndexEntry {
+    pub ctime: (u32, u32),
+    pub mtime: (u32, u32),
+    pub dev: u32,
+    pub ino: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+    pub hash: ObjectHash,
+    /// Merge stage: 0 for normal entries, 1-3 for the base, ours and theirs
+    /// versions of a conflicted path
+    pub stage: u8,
+    pub path: String,
+}
+
+impl IndexEntry {
+    /// Create an entry for a path, filling the stat data from its metadata
+    pub fn new(path: &str, mode: EntryMode, hash: ObjectHash, metadata: Option<&Metadata>) -> Self {
+        let mut entry = IndexEntry {
+            mode: mode.bits(),
+            hash,
+            path: path.to_string(),
+            ..Default::default()
+        };
+        if let Some(metadata) = metadata {
+            entry.update_stat(metadata);
+        }
+        entry
+    }
+
+    /// Refresh the cached stat data from the file metadata
+    pub fn update_stat(&mut self, metadata: &Metadata) {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            self.ctime = (metadata.ctime() as u32, metadata.ctime_nsec() as u32);
+            self.mtime = (metadata.mtime() as u32, metadata.mtime_nsec() as u32);
+            self.dev = metadata.dev() as u32;
+            self.ino = metadata.ino() as u32;
+            self.uid = metadata.uid();
+            self.gid = metadata.gid();
+        }
+        self.size = metadata.len() as u32;
+    }
+
+    /// Return true if the file metadata matches the cached stat data, which
+    /// means the file can be assumed unchanged without hashing it
+    pub fn stat_matches(&self, metadata: &Metadata) -> bool {
+        let mut other = self.clone();
+        other.update_stat(metadata);
+        other.mtime == self.mtime && other.size == self.size && other.ino == self.ino
+    }
+
+    /// Return the tree entry mode of the file
+    pub fn entry_mode(&self) -> Result<EntryMode> {
+        EntryMode::from_bits(self.mode)
+    }
+}
+
+/// Index is git's staging area stored in `.git/index`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Index {
+    /// Entries sorted by path and stage
+    pub entries: Vec<IndexEntry>,
+}
+
+impl Index {
+    /// Read the index of a repository, returning an empty index if there is none
+    pub fn read(repo: &Repository) -> Result<Index> {
+        let path = repo.gitdir().join("index");
+        if !path.exists() {
+            return Ok(Index::default());
+        }
+        let data = fs::read(&path).context("Failed to read index")?;
+        Index::parse(&data, repo.hash_algorithm())
+    }
+
+    /// Parse the binary index format (versions 2 and 3) of a repository
+    /// using `algorithm`
+    pub fn parse(data: &[u8], algorithm: HashAlgorithm) -> Result<Index> {
+        let size = algorithm.size();
+        if data.len() < 12 + size {
+            bail!("Invalid index: file too short");
+        }
+        let (content, checksum) = data.split_at(data.len() - size);
+        if algorithm.digest(content).as_bytes() != checksum {
+            bail!("Invalid index: checksum mismatch");
+        }
+        if &content[..4] != SIGNATURE {
+            bail!("Invalid index: bad signature");
+        }
+        let version = read_u32(content, 4);
+        if version != 2 && version != 3 {
+            bail!("Unsupported index version: {}", version);
+        }
+        let count = read_u32(content, 8) as usize;
+
+        let mut entries = Vec::with_capacity(count);
+        let mut offset = 12;
+        // The stat data, the hash and the flags come before the path
+        let header_size = ENTRY_STAT_SIZE + size + 2;
+        for _ in 0..count {
+            if offset + header_size > content.len() {
+                bail!("Invalid index: truncated entry");
+            }
+            let field = |i: usize| read_u32(content, offset + i * 4);
+            let hash_start = offset + ENTRY_STAT_SIZE;
+            let flags =
+                u16::from_be_bytes([content[hash_start + size], content[hash_start + size + 1]]);
+            let mut path_start = offset + header_size;
+            if flags & EXTENDED_FLAG != 0 {
+                path_start += 2;
+            }
+            let path_len = content[path_start..]
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid index: unterminated path"))?;
+            let path = String::from_utf8(content[path_start..path_start + path_len].to_vec())
+                .context("Invalid index: path is not UTF-8")?;
+            verify_path(&path).context("Invalid index")?;
+
+            entries.push(IndexEntry {
+                ctime: (field(0), field(1)),
+                mtime: (field(2), field(3)),
+                dev: field(4),
+                ino: field(5),
+                mode: field(6),
+                uid: field(7),
+                gid: field(8),
+                size: field(9),
+                hash: ObjectHash::from_bytes(&content[hash_start..hash_start + size], algorithm)?,
+                stage: ((flags >> 12) & 0x3) as u8,
+                path,
+            });
+
+            // Entries are padded with 1 to 8 NUL bytes to a multiple of 8
+            let entry_len = path_start - offset + path_len;
+            offset += (entry_len + 8) & !7;
+        }
+        // Extensions (cached trees, resolve undo, ...) are optional caches and
+        // are dropped; they are rebuilt by git when needed.
+        Ok(Index { entries })
+    }
+
+    /// Serialize the index in version 2 format, with a checksum made with
+    /// `algorithm`
+    pub fn serialize(&self, algorithm: HashAlgorithm) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(SIGNATURE);
+        data.extend_from_slice(&2u32.to_be_bytes());
+        data.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        for entry in &self.entries {
+            let start = data.len();
+            for value in [
+                entry.ctime.0,
+                entry.ctime.1,
+                entry.mtime.0,
+                entry.mtime.1,
+                entry.dev,
+                entry.ino,
+                entry.mode,
+                entry.uid,
+                entry.gid,
+                entry.size,
+            ] {
+                data.extend_from_slice(&value.to_be_bytes());
+            }
+            data.extend_from_slice(entry.hash.as_bytes());
+            let flags = ((entry.stage as u16 & 0x3) << 12) | entry.path.len().min(0xfff) as u16;
+            data.extend_from_slice(&flags.to_be_bytes());
+            data.extend_from_slice(entry.path.as_bytes());
+            let entry_len = data.len() - start;
+            data.resize(start + ((entry_len + 8) & !7), 0);
+        }
+        let checksum = algorithm.digest(&data);
+        data.extend_from_slice(checksum.as_bytes());
+        data
+    }
+
+    /// Write the index to `.git/index`
+    pub fn write(&self, repo: &Repository) -> Result<()> {
+        write_atomic(
+            &repo.gitdir().join("index"),
+            &self.serialize(repo.hash_algorithm()),
+        )
+    }
+
+    /// Build an index holding every file of a tree, without stat data
+    pub fn from_tree(repo: &Repository, tree: &ObjectHash) -> Result<Index> {
+        let entries = flatten_tree(repo, tree)?
+            .into_iter()
+            .map(|(path, (mode, hash))| {
+                verify_path(&path)?;
+                Ok(IndexEntry::new(&path, mode, hash, None))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Index { entries })
+    }
+
+    /// Write the tree objects for the index and return the root tree hash
+    pub fn write_tree(&self, repo: &Repository) -> Result<ObjectHash> {
+        if self.has_conflicts() {
+            bail!("Cannot write a tree from an index with unresolved conflicts");
+        }
+        let files = self
+            .entries
+            .iter()
+            .map(|e| Ok((e.path.as_str(), e.entry_mode()?, &e.hash)))
+            .collect::<Result<Vec<_>>>()?;
+        build_tree(repo, files)
+    }
+
+    fn position(&self, path: &str, stage: u8) -> std::result::Result<usize, usize> {
+        self.entries
+            .binary_search_by(|e| (e.path.as_str(), e.stage).cmp(&(path, stage)))
+    }
+
+    /// Return the stage 0 entry of a path
+    pub fn get(&self, path: &str) -> Option<&IndexEntry> {
+        self.get_stage(path, 0)
+    }
+
+    /// Return the entry of a path at the given merge stage
+    pub fn get_stage(&self, path: &str, stage: u8) -> Option<&IndexEntry> {
+        self.position(path, stage).ok().map(|i| &self.entries[i])
+    }
+
+    /// Return true if a path has an entry at any stage
+    pub fn contains(&self, path: &str) -> bool {
+        let i = self.entries.partition_point(|e| e.path.as_str() < path);
+        self.entries.get(i).is_some_and(|e| e.path == path)
+    }
+
+    /// Insert or replace an entry, clearing any conflict stages of its path
+    /// when adding a stage 0 entry. Paths that are unsafe to check out are
+    /// refused.
+    pub fn add(&mut self, entry: IndexEntry) -> Result<()> {
+        verify_path(&entry.path)?;
+        if entry.stage == 0 {
+            self.entries
+                .retain(|e| e.path != entry.path || e.stage == 0);
+        }
+        match self.position(&entry.path, entry.stage) {
+            Ok(i) => self.entries[i] = entry,
+            Err(i) => self.entries.insert(i, entry),
+        }
+        Ok(())
+    }
+
+    /// Remove every stage of a path, returning true if it was present
+    pub fn remove(&mut self, path: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|e| e.path != path);
+        self.entries.len() != len
+    }
+
+    /// Return true if any path has conflict stages
+    pub fn has_conflicts(&self) -> bool {
+        self.entries.iter().any(|e| e.stage != 0)
+    }
+
+    /// Return the paths with conflict stages, each listed once
+    pub fn conflicted_paths(&self) -> Vec<&str> {
+        let mut paths = self
+            .entries
+            .iter()
+            .filter(|e| e.stage != 0)
+            .map(|e| e.path.as_str())
+            .collect::<Vec<_>>();
+        paths.dedup();
+        paths
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{commit_files, init_repo};
+
+    fn entry(path: &str, stage: u8) -> IndexEntry {
+        IndexEntry {
+            path: path.to_string(),
+            stage,
+            mode: EntryMode::Blob.bits(),
+            hash: HashAlgorithm::Sha1.digest(path.as_bytes()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_index_roundtrip() {
+        let mut index = Index::default();
+        index.add(entry("b.txt", 0)).unwrap();
+        index.add(entry("a/long/path/name.txt", 0)).unwrap();
+        let parsed =
+            Index::parse(&index.serialize(HashAlgorithm::Sha1), HashAlgorithm::Sha1).unwrap();
+        assert_eq!(parsed, index);
+        assert_eq!(parsed.entries[0].path, "a/long/path/name.txt");
+    }
+
+    #[test]
+    fn test_index_checksum_mismatch() {
+        let mut data = Index::default().serialize(HashAlgorithm::Sha1);
+        data[5] ^= 1;
+        let result = Index::parse(&data, HashAlgorithm::Sha1);
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("checksum mismatch"));
+    }
+
+    #[test]
+    fn test_index_conflicts() {
+        let mut index = Index::default();
+        index.add(entry("a.txt", 1)).unwrap();
+        index.add(entry("a.txt", 2)).unwrap();
+        index.add(entry("a.txt", 3)).unwrap();
+        assert!(index.has_conflicts());
+        assert_eq!(index.conflicted_paths(), vec!["a.txt"]);
+
+        index.add(entry("a.txt", 0)).unwrap();
+        assert!(!index.has_conflicts());
+        assert_eq!(index.entries.len(), 1);
+    }
+
+    #[test]
+    fn test_index_tree_roundtrip() {
+        let (_dir, repo) = init_repo();
+        let commit = commit_files(&repo, &[("a.txt", "a"), ("dir/b.txt", "b")], "first");
+        let tree = crate::commits::Commit::read(&repo, &commit).unwrap().tree;
+        let index = Index::from_tree(&repo, &tree).unwrap();
+        assert_eq!(index.entries.len(), 2);
+        assert_eq!(index.write_tree(&repo).unwrap(), tree);
+    }
+
+    #[test]
+    fn test_read_git_index() {
+        let (dir, repo) = init_repo();
+        let mut index = Index::default();
+        index.add(entry("x", 0)).unwrap();
+        index.write(&repo).unwrap();
+        assert!(dir.path().join(".git/index").exists());
+        assert_eq!(Index::read(&repo).unwrap(), index);
+    }
+}
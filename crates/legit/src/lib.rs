@@ -1,6 +1,46 @@
+pub mod add;
+pub mod bitmap;
+pub mod checkout;
+pub mod commit_graph;
 pub mod commits;
+pub mod credential;
+pub mod diff;
+pub mod editor;
+pub mod fetch;
+pub mod fsck;
+pub mod gc;
+pub mod gitconfig;
+pub mod http;
+pub mod ignore;
+pub mod index;
+pub mod merge;
+pub mod merge_base;
+pub mod midx;
 pub mod objects;
+pub mod pack;
+pub mod pack_writer;
+pub mod pktline;
+pub mod promisor;
+pub mod protocol;
+pub mod push;
+pub mod rebase;
+pub mod receive_pack;
+pub mod refs;
+pub mod reftable;
+pub mod remote;
 mod repository;
+pub mod revision;
+pub mod sequencer;
 mod settings;
+pub mod shallow;
+pub mod stash;
+pub mod status;
+pub mod submodule;
+#[cfg(test)]
+mod test_utils;
+pub mod transport;
+pub mod tree;
+pub mod upload_pack;
+pub mod worktree;
 
-pub use repository::Repository;
+pub use repository::{FormatError, Repository};
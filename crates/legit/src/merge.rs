@@ -0,0 +1,1177 @@
+use crate::checkout::{apply_changes, head_tree, overwritten_paths, FileMap};
+use crate::commits::{Commit, Signature};
+use crate::diff::{diff_lines, is_binary, split_lines, Algorithm, LineOp};
+use crate::gitconfig::GitConfig;
+use crate::index::{Index, IndexEntry};
+use crate::merge_base::merge_bases;
+use crate::objects::{read_object, store_object, Object, ObjectHash, ObjectType};
+use crate::refs::{read_head, resolve_ref, update_head, write_atomic, Head};
+use crate::revision::{peel_to_commit, rev_parse};
+use crate::tree::{build_tree, flatten_tree, EntryMode};
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeSet;
+use std::fs;
+use std::ops::Range;
+use strum::{Display, EnumString};
+
+/// Length of the `<<<<<<<`, `|||||||`, `=======` and `>>>>>>>` markers
+const MARKER_SIZE: usize = 7;
+const MERGE_HEAD: &str = "MERGE_HEAD";
+const MERGE_MSG: &str = "MERGE_MSG";
+const MERGE_MODE: &str = "MERGE_MODE";
+
+/// ConflictStyle selects how conflicting hunks are written, like `merge.conflictStyle`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum ConflictStyle {
+    /// Show our and their side of each conflict
+    #[default]
+    Merge,
+    /// Also show the base version, without shrinking the conflict
+    Diff3,
+    /// Show the base version, moving lines common to both sides out of the conflict
+    ZDiff3,
+}
+
+impl ConflictStyle {
+    /// Read `merge.conflictStyle` from the repository and global config
+    pub fn from_config(repo: &Repository) -> Result<ConflictStyle> {
+        let config = repo.config()?;
+        let global = GitConfig::global()?;
+        match config
+            .get("merge.conflictstyle")
+            .or_else(|| global.get("merge.conflictstyle"))
+        {
+            Some(value) => value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Unknown conflict style '{}'", value)),
+            None => Ok(ConflictStyle::default()),
+        }
+    }
+}
+
+/// ConflictLabels name the sides of a merge in conflict markers
+#[derive(Debug, Clone)]
+pub struct ConflictLabels {
+    pub ours: String,
+    pub base: String,
+    pub theirs: String,
+}
+
+/// ConflictKind is the reason a path could not be merged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// Both sides changed the same lines, or the file could not be merged
+    Content,
+    /// Both sides added the path with different content
+    AddAdd,
+    /// One side deleted the path while the other modified it
+    ModifyDelete,
+    /// One side has a file where the other has a directory
+    FileDirectory,
+}
+
+/// MergeConflict is a path with unmerged versions, recorded as index stages
+/// 1 (base), 2 (ours) and 3 (theirs)
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeConflict {
+    pub path: String,
+    pub kind: ConflictKind,
+    pub base: Option<(EntryMode, ObjectHash)>,
+    pub ours: Option<(EntryMode, ObjectHash)>,
+    pub theirs: Option<(EntryMode, ObjectHash)>,
+}
+
+/// TreeMerge is the result of merging two trees
+#[derive(Debug, Clone, Default)]
+pub struct TreeMerge {
+    /// The merged files; conflicted files hold their conflict markers
+    pub files: FileMap,
+    pub conflicts: Vec<MergeConflict>,
+    /// Progress and conflict messages in the order git prints them
+    pub messages: Vec<String>,
+}
+
+impl TreeMerge {
+    /// Write the merged tree, conflict markers included
+    pub fn write_tree(&self, repo: &Repository) -> Result<ObjectHash> {
+        build_tree(
+            repo,
+            self.files
+                .iter()
+                .map(|(path, (mode, hash))| (path.as_str(), *mode, hash)),
+        )
+    }
+}
+
+/// FastForward selects whether `merge` may just move the branch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FastForward {
+    /// Fast-forward when possible, merge otherwise
+    #[default]
+    Allow,
+    /// Refuse to merge when a fast-forward is not possible
+    Only,
+    /// Always create a merge commit
+    Never,
+}
+
+/// MergeOptions controls how `merge` combines a commit into HEAD
+#[derive(Debug, Clone, Default)]
+pub struct MergeOptions {
+    pub fast_forward: FastForward,
+    pub style: ConflictStyle,
+    /// Message of the merge commit instead of the generated one
+    pub message: Option<String>,
+}
+
+/// MergeOutcome is what `merge` did
+#[derive(Debug)]
+pub enum MergeOutcome {
+    /// The commit is already part of HEAD
+    UpToDate,
+    /// HEAD was moved forward to the commit
+    FastForward {
+        from: Option<ObjectHash>,
+        to: ObjectHash,
+    },
+    /// A merge commit was created
+    Merged {
+        commit: ObjectHash,
+        result: TreeMerge,
+    },
+    /// The merge stopped with conflicts left in the index and working tree
+    Conflicted(TreeMerge),
+}
+
+/// Merge a commit into HEAD, like `git merge`
+///
+/// When the merge conflicts, the index holds the conflict stages, the
+/// working tree holds the conflict markers and `MERGE_HEAD` and `MERGE_MSG`
+/// are written so that `merge_continue` can create the merge commit once the
+/// conflicts are resolved.
+pub fn merge(repo: &Repository, spec: &str, options: &MergeOptions) -> Result<MergeOutcome> {
+    if repo.gitdir().join(MERGE_HEAD).exists() {
+        bail!("You have not concluded your merge (MERGE_HEAD exists).\nPlease, commit your changes before you merge.");
+    }
+    let theirs = peel_to_commit(repo, &rev_parse(repo, spec)?)
+        .with_context(|| format!("{} - not something we can merge", spec))?;
+    let head = resolve_ref(repo, "HEAD")?;
+    let mut index = Index::read(repo)?;
+    if index.has_conflicts() {
+        bail!("Merging is not possible because you have unmerged files.");
+    }
+
+    let current = match head_tree(repo)? {
+        Some(tree) => flatten_tree(repo, &tree)?,
+        None => FileMap::new(),
+    };
+    let Some(ours) = head else {
+        // Merging into an unborn branch just points it at the commit
+        if options.fast_forward == FastForward::Never {
+            bail!("Non-fast-forward commit does not make sense into an empty head");
+        }
+        let target = flatten_tree(repo, &Commit::read(repo, &theirs)?.tree)?;
+        update_files(repo, &mut index, &current, &target, "merge")?;
+        update_head(repo, &theirs)?;
+        return Ok(MergeOutcome::FastForward {
+            from: None,
+            to: theirs,
+        });
+    };
+
+    let bases = merge_bases(repo, &ours, &theirs)?;
+    if bases.contains(&theirs) {
+        return Ok(MergeOutcome::UpToDate);
+    }
+    check_index_matches_head(&index, &current, "merge")?;
+    write_atomic(
+        &repo.gitdir().join("ORIG_HEAD"),
+        format!("{}\n", ours).as_bytes(),
+    )?;
+
+    if bases.contains(&ours) && options.fast_forward != FastForward::Never {
+        let target = flatten_tree(repo, &Commit::read(repo, &theirs)?.tree)?;
+        update_files(repo, &mut index, &current, &target, "merge")?;
+        update_head(repo, &theirs)?;
+        return Ok(MergeOutcome::FastForward {
+            from: Some(ours),
+            to: theirs,
+        });
+    }
+    if options.fast_forward == FastForward::Only {
+        bail!("Not possible to fast-forward, aborting.");
+    }
+
+    let base_tree = virtual_base(repo, &bases, options.style)?;
+    let labels = ConflictLabels {
+        ours: "HEAD".to_string(),
+        base: match bases.as_slice() {
+            [base] => base.to_hex()[..7].to_string(),
+            _ => "merged common ancestors".to_string(),
+        },
+        theirs: spec.to_string(),
+    };
+    let result = merge_trees(
+        repo,
+        base_tree.as_ref(),
+        &Commit::read(repo, &ours)?.tree,
+        &Commit::read(repo, &theirs)?.tree,
+        &labels,
+        options.style,
+    )?;
+    update_files(repo, &mut index, &current, &result.files, "merge")?;
+
+    let message = match &options.message {
+        Some(message) => format!("{}\n", message.trim_end()),
+        None => merge_message(repo, spec)?,
+    };
+    if !result.conflicts.is_empty() {
+        record_conflicts(&mut index, &result.conflicts)?;
+        index.write(repo)?;
+
+        let mut merge_msg = format!("{}\n# Conflicts:\n", message);
+        for conflict in &result.conflicts {
+            merge_msg.push_str(&format!("#\t{}\n", conflict.path));
+        }
+        let gitdir = repo.gitdir();
+        write_atomic(&gitdir.join(MERGE_HEAD), format!("{}\n", theirs).as_bytes())?;
+        write_atomic(&gitdir.join(MERGE_MSG), merge_msg.as_bytes())?;
+        let mode = match options.fast_forward {
+            FastForward::Never => "no-ff",
+            _ => "",
+        };
+        write_atomic(&gitdir.join(MERGE_MODE), mode.as_bytes())?;
+        return Ok(MergeOutcome::Conflicted(result));
+    }
+
+    index.write(repo)?;
+    let tree = index.write_tree(repo)?;
+    let commit = Commit::new(repo, tree, vec![ours, theirs], &message)?.write(repo)?;
+    update_head(repo, &commit)?;
+    Ok(MergeOutcome::Merged { commit, result })
+}
+
+/// Create the merge commit of a merge that stopped with conflicts, once they
+/// have been resolved in the index
+pub fn merge_continue(repo: &Repository) -> Result<ObjectHash> {
+    let gitdir = repo.gitdir();
+    let Ok(merge_head) = fs::read_to_string(gitdir.join(MERGE_HEAD)) else {
+        bail!("There is no merge in progress (MERGE_HEAD missing).");
+    };
+    let index = Index::read(repo)?;
+    if index.has_conflicts() {
+        bail!("Committing is not possible because you have unmerged files.");
+    }
+
+    let mut parents = resolve_ref(repo, "HEAD")?.into_iter().collect::<Vec<_>>();
+    for line in merge_head.lines().filter(|l| !l.is_empty()) {
+        parents
+            .push(ObjectHash::from_hex(line, repo.hash_algorithm()).context("Invalid MERGE_HEAD")?);
+    }
+    let message = fs::read_to_string(gitdir.join(MERGE_MSG)).unwrap_or_default();
+    let message = strip_comments(&message);
+    if message.is_empty() {
+        bail!("Aborting commit due to empty commit message.");
+    }
+
+    let tree = index.write_tree(repo)?;
+    let commit = Commit::new(repo, tree, parents, &message)?.write(repo)?;
+    update_head(repo, &commit)?;
+    remove_merge_state(repo)?;
+    Ok(commit)
+}
+
+/// Abandon a merge that stopped with conflicts, resetting the paths it
+/// touched to HEAD
+pub fn merge_abort(repo: &Repository) -> Result<()> {
+    if !repo.gitdir().join(MERGE_HEAD).exists() {
+        bail!("There is no merge to abort (MERGE_HEAD missing).");
+    }
+    let head = match head_tree(repo)? {
+        Some(tree) => flatten_tree(repo, &tree)?,
+        None => FileMap::new(),
+    };
+    reset_merge(repo, &mut Index::read(repo)?, &head)?;
+    remove_merge_state(repo)
+}
+
+/// Reset the index and working tree to `target`, like `git reset --merge`
+///
+/// Only paths whose index entry differs from the target, including
+/// conflicted paths, are reset, so unstaged changes to other files survive.
+pub(crate) fn reset_merge(repo: &Repository, index: &mut Index, target: &FileMap) -> Result<()> {
+    let paths = index
+        .entries
+        .iter()
+        .filter(|e| {
+            e.stage != 0
+                || target
+                    .get(&e.path)
+                    .is_none_or(|(mode, hash)| *hash != e.hash || mode.bits() != e.mode)
+        })
+        .map(|e| e.path.clone())
+        .chain(target.keys().filter(|p| !index.contains(p)).cloned())
+        .collect::<BTreeSet<_>>();
+    apply_changes(repo, index, &paths, target)?;
+    index.write(repo)
+}
+
+/// Replace the entries of conflicted paths with their base, ours and theirs
+/// versions at stages 1, 2 and 3
+pub(crate) fn record_conflicts(index: &mut Index, conflicts: &[MergeConflict]) -> Result<()> {
+    for conflict in conflicts {
+        index.remove(&conflict.path);
+        let stages = [&conflict.base, &conflict.ours, &conflict.theirs];
+        for (stage, entry) in (1..).zip(stages) {
+            if let Some((mode, hash)) = entry {
+                let mut entry = IndexEntry::new(&conflict.path, *mode, hash.clone(), None);
+                entry.stage = stage;
+                index.add(entry)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Return true if a merge stopped with conflicts and was not concluded
+pub fn merge_in_progress(repo: &Repository) -> bool {
+    repo.gitdir().join(MERGE_HEAD).exists()
+}
+
+fn remove_merge_state(repo: &Repository) -> Result<()> {
+    for name in [MERGE_HEAD, MERGE_MSG, MERGE_MODE] {
+        let path = repo.gitdir().join(name);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Drop the `#` comment lines and the surrounding blank lines of a message
+pub(crate) fn strip_comments(message: &str) -> String {
+    let lines = message
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<Vec<_>>();
+    let text = lines.join("\n");
+    let text = text.trim_matches('\n');
+    match text.is_empty() {
+        true => String::new(),
+        false => format!("{}\n", text.trim_end()),
+    }
+}
+
+/// Build the default message of a merge commit, e.g. `Merge branch 'topic'`
+fn merge_message(repo: &Repository, spec: &str) -> Result<String> {
+    let kind = if resolve_ref(repo, &format!("refs/heads/{}", spec))?.is_some() {
+        "branch"
+    } else if resolve_ref(repo, &format!("refs/tags/{}", spec))?.is_some() {
+        "tag"
+    } else if resolve_ref(repo, &format!("refs/remotes/{}", spec))?.is_some() {
+        "remote-tracking branch"
+    } else {
+        "commit"
+    };
+    let mut message = format!("Merge {} '{}'", kind, spec);
+    if let Head::Branch(name) = read_head(repo)? {
+        let branch = name.strip_prefix("refs/heads/").unwrap_or(&name);
+        if branch != "master" && branch != "main" {
+            message.push_str(&format!(" into {}", branch));
+        }
+    }
+    message.push('\n');
+    Ok(message)
+}
+
+/// Refuse to run `action` when the index has changes that are not committed
+pub(crate) fn check_index_matches_head(index: &Index, head: &FileMap, action: &str) -> Result<()> {
+    let staged = index
+        .entries
+        .iter()
+        .filter(|e| head.get(&e.path).is_none_or(|(_, hash)| *hash != e.hash))
+        .map(|e| e.path.as_str())
+        .chain(
+            head.keys()
+                .filter(|p| !index.contains(p))
+                .map(String::as_str),
+        )
+        .collect::<BTreeSet<_>>();
+    if !staged.is_empty() {
+        bail!(overwritten_error(staged.into_iter(), action));
+    }
+    Ok(())
+}
+
+fn overwritten_error<'a>(paths: impl Iterator<Item = &'a str>, action: &str) -> String {
+    format!(
+        "Your local changes to the following files would be overwritten by {action}:\n\t{}\nPlease commit your changes or stash them before you {action}.\nAborting",
+        paths.collect::<Vec<_>>().join("\n\t")
+    )
+}
+
+/// Move the index and working tree from the `current` files to the `target`
+/// files, refusing to overwrite local changes
+pub(crate) fn update_files(
+    repo: &Repository,
+    index: &mut Index,
+    current: &FileMap,
+    target: &FileMap,
+    action: &str,
+) -> Result<()> {
+    let paths = current
+        .keys()
+        .chain(target.keys())
+        .filter(|path| current.get(*path) != target.get(*path))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    let overwritten = overwritten_paths(repo, index, &paths, current, target);
+    if !overwritten.is_empty() {
+        bail!(overwritten_error(
+            overwritten.iter().map(String::as_str),
+            action
+        ));
+    }
+    apply_changes(repo, index, &paths, target)?;
+    index.write(repo)
+}
+
+/// Return the tree to use as the base of a merge
+///
+/// Several merge bases are merged together into a virtual base, recursively,
+/// as git's recursive strategy does. Conflicts in the virtual base are kept
+/// with their markers.
+pub(crate) fn virtual_base(
+    repo: &Repository,
+    bases: &[ObjectHash],
+    style: ConflictStyle,
+) -> Result<Option<ObjectHash>> {
+    let Some((first, rest)) = bases.split_first() else {
+        return Ok(None);
+    };
+    let mut current = first.clone();
+    for next in rest {
+        let inner = merge_bases(repo, &current, next)?;
+        let base_tree = virtual_base(repo, &inner, style)?;
+        let labels = ConflictLabels {
+            ours: "Temporary merge branch 1".to_string(),
+            base: "merged common ancestors".to_string(),
+            theirs: "Temporary merge branch 2".to_string(),
+        };
+        let merged = merge_trees(
+            repo,
+            base_tree.as_ref(),
+            &Commit::read(repo, &current)?.tree,
+            &Commit::read(repo, next)?.tree,
+            &labels,
+            style,
+        )?;
+        let signature = Signature::now("legit", "legit@localhost").to_string();
+        let commit = Commit {
+            tree: merged.write_tree(repo)?,
+            parents: vec![current, next.clone()],
+            author: signature.clone(),
+            committer: signature,
+            gpgsig: None,
+            extra_headers: Vec::new(),
+            message: "merged common ancestors\n".to_string(),
+        };
+        current = commit.write(repo)?;
+    }
+    Ok(Some(Commit::read(repo, &current)?.tree))
+}
+
+/// Three-way merge two trees against their common base
+///
+/// Paths changed on only one side take that side's version. Files changed
+/// on both sides are merged line by line; the result keeps conflict markers
+/// where the changes overlap.
+pub fn merge_trees(
+    repo: &Repository,
+    base: Option<&ObjectHash>,
+    ours: &ObjectHash,
+    theirs: &ObjectHash,
+    labels: &ConflictLabels,
+    style: ConflictStyle,
+) -> Result<TreeMerge> {
+    let base = match base {
+        Some(tree) => flatten_tree(repo, tree)?,
+        None => FileMap::new(),
+    };
+    let ours = flatten_tree(repo, ours)?;
+    let theirs = flatten_tree(repo, theirs)?;
+    let paths = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .cloned()
+        .collect::<BTreeSet<_>>();
+
+    let mut result = TreeMerge::default();
+    for path in paths {
+        let (b, o, t) = (base.get(&path), ours.get(&path), theirs.get(&path));
+        if o == t || t == b {
+            if let Some(entry) = o {
+                result.files.insert(path, entry.clone());
+            }
+            continue;
+        }
+        if o == b {
+            if let Some(entry) = t {
+                result.files.insert(path, entry.clone());
+            }
+            continue;
+        }
+
+        let conflict = |kind| MergeConflict {
+            path: path.clone(),
+            kind,
+            base: b.cloned(),
+            ours: o.cloned(),
+            theirs: t.cloned(),
+        };
+        match (o, t) {
+            (Some(o), Some(t)) => {
+                let kind = match b {
+                    Some(_) => ConflictKind::Content,
+                    None => ConflictKind::AddAdd,
+                };
+                let (entry, clean) = merge_file(repo, &path, b, o, t, labels, style, &mut result)?;
+                result.files.insert(path.clone(), entry);
+                if !clean {
+                    let label = match kind {
+                        ConflictKind::AddAdd => "add/add",
+                        _ => "content",
+                    };
+                    result
+                        .messages
+                        .push(format!("CONFLICT ({}): Merge conflict in {}", label, path));
+                    result.conflicts.push(conflict(kind));
+                }
+            }
+            (Some(kept), None) | (None, Some(kept)) => {
+                let (deleted_in, modified_in) = match o {
+                    Some(_) => (&labels.theirs, &labels.ours),
+                    None => (&labels.ours, &labels.theirs),
+                };
+                result.messages.push(format!(
+                    "CONFLICT (modify/delete): {path} deleted in {deleted_in} and modified in {modified_in}.  Version {modified_in} of {path} left in tree."
+                ));
+                result.files.insert(path.clone(), kept.clone());
+                result.conflicts.push(conflict(ConflictKind::ModifyDelete));
+            }
+            (None, None) => unreachable!("a path deleted on both sides is unchanged"),
+        }
+    }
+    resolve_directory_conflicts(&ours, labels, &mut result);
+    Ok(result)
+}
+
+/// Move files out of the way of directories with the same name, renaming
+/// them to `path~side`
+fn resolve_directory_conflicts(ours: &FileMap, labels: &ConflictLabels, result: &mut TreeMerge) {
+    let blocked = result
+        .files
+        .keys()
+        .filter(|path| {
+            let prefix = format!("{}/", path);
+            result
+                .files
+                .range(prefix.clone()..)
+                .next()
+                .is_some_and(|(next, _)| next.starts_with(&prefix))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for path in blocked {
+        let entry = result.files.remove(&path).expect("blocked path is present");
+        let from_ours = ours.get(&path) == Some(&entry);
+        let side = if from_ours {
+            &labels.ours
+        } else {
+            &labels.theirs
+        };
+        let new_path = format!("{}~{}", path, side.replace('/', "_"));
+        result.messages.push(format!(
+            "CONFLICT (file/directory): directory in the way of {} from {}; moving it to {} instead.",
+            path, side, new_path
+        ));
+        result.conflicts.retain(|c| c.path != path);
+        result.conflicts.push(MergeConflict {
+            path: new_path.clone(),
+            kind: ConflictKind::FileDirectory,
+            base: None,
+            ours: from_ours.then(|| entry.clone()),
+            theirs: (!from_ours).then(|| entry.clone()),
+        });
+        result.files.insert(new_path, entry);
+    }
+    result.conflicts.sort_by(|a, b| a.path.cmp(&b.path));
+}
+
+/// Merge one file changed on both sides, storing the merged blob
+///
+/// Returns the merged entry and whether the merge was clean. Files that
+/// cannot be merged line by line keep our version.
+#[allow(clippy::too_many_arguments)]
+fn merge_file(
+    repo: &Repository,
+    path: &str,
+    base: Option<&(EntryMode, ObjectHash)>,
+    ours: &(EntryMode, ObjectHash),
+    theirs: &(EntryMode, ObjectHash),
+    labels: &ConflictLabels,
+    style: ConflictStyle,
+    result: &mut TreeMerge,
+) -> Result<((EntryMode, ObjectHash), bool)> {
+    // A mode change on one side wins; changes on both sides conflict
+    let (mode, mode_clean) = match base {
+        Some((mode, _)) if *mode == ours.0 => (theirs.0, true),
+        Some((mode, _)) if *mode == theirs.0 => (ours.0, true),
+        _ => (ours.0, ours.0 == theirs.0),
+    };
+    let is_file = |mode: EntryMode| matches!(mode, EntryMode::Blob | EntryMode::BlobExecutable);
+    if !is_file(ours.0) || !is_file(theirs.0) || base.is_some_and(|(mode, _)| !is_file(*mode)) {
+        return Ok((ours.clone(), false));
+    }
+    if ours.1 == theirs.1 {
+        return Ok(((mode, ours.1.clone()), mode_clean));
+    }
+
+    result.messages.push(format!("Auto-merging {}", path));
+    let base_data = match base {
+        Some((_, hash)) => read_object(repo, hash)?.data,
+        None => Vec::new(),
+    };
+    let ours_data = read_object(repo, &ours.1)?.data;
+    let theirs_data = read_object(repo, &theirs.1)?.data;
+    if [&base_data, &ours_data, &theirs_data]
+        .iter()
+        .any(|d| is_binary(d))
+    {
+        result.messages.push(format!(
+            "warning: Cannot merge binary files: {} ({} vs. {})",
+            path, labels.ours, labels.theirs
+        ));
+        return Ok((ours.clone(), false));
+    }
+
+    let (merged, conflicts) = merge_content(&base_data, &ours_data, &theirs_data, labels, style);
+    let hash = store_object(
+        &Object::new(repo.hash_algorithm(), ObjectType::Blob, merged)?,
+        repo,
+    )?;
+    Ok(((mode, hash), conflicts == 0 && mode_clean))
+}
+
+/// A run of base lines replaced by other lines on one side
+#[derive(Debug)]
+struct Hunk {
+    base: Range<usize>,
+    side: Range<usize>,
+}
+
+/// Collect the changed regions of a line diff
+fn hunks(ops: &[LineOp]) -> Vec<Hunk> {
+    let mut hunks = Vec::new();
+    let mut current: Option<Hunk> = None;
+    let (mut base, mut side) = (0, 0);
+    for op in ops {
+        if *op == LineOp::Equal {
+            hunks.extend(current.take());
+            base += 1;
+            side += 1;
+            continue;
+        }
+        let hunk = current.get_or_insert(Hunk {
+            base: base..base,
+            side: side..side,
+        });
+        if *op == LineOp::Delete {
+            base += 1;
+            hunk.base.end = base;
+        } else {
+            side += 1;
+            hunk.side.end = side;
+        }
+    }
+    hunks.extend(current);
+    hunks
+}
+
+/// Section is a part of a merged file
+#[derive(Debug, PartialEq)]
+enum Section<'a> {
+    /// Lines unchanged on both sides
+    Clean(Vec<&'a [u8]>),
+    /// Lines changed by one side, or identically by both
+    Resolved(Vec<&'a [u8]>),
+    Conflict {
+        ours: Vec<&'a [u8]>,
+        base: Vec<&'a [u8]>,
+        theirs: Vec<&'a [u8]>,
+    },
+}
+
+/// Three-way merge the lines of a file
+///
+/// Changes from both sides that overlap or touch are conflicts unless they
+/// are identical.
+fn merge_lines<'a>(base: &[&'a [u8]], ours: &[&'a [u8]], theirs: &[&'a [u8]]) -> Vec<Section<'a>> {
+    let ours_hunks = hunks(&diff_lines(base, ours, Algorithm::Myers));
+    let theirs_hunks = hunks(&diff_lines(base, theirs, Algorithm::Myers));
+
+    let mut sections = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let mut position = 0;
+    // How far each side's line numbers have drifted from the base
+    let (mut ours_delta, mut theirs_delta) = (0isize, 0isize);
+    let shift = |line: usize, delta: isize| (line as isize + delta) as usize;
+
+    while i < ours_hunks.len() || j < theirs_hunks.len() {
+        let start = match (ours_hunks.get(i), theirs_hunks.get(j)) {
+            (Some(o), Some(t)) => o.base.start.min(t.base.start),
+            (Some(o), None) => o.base.start,
+            (None, Some(t)) => t.base.start,
+            (None, None) => unreachable!(),
+        };
+        let (ours_start, theirs_start) = (shift(start, ours_delta), shift(start, theirs_delta));
+        let (first_ours, first_theirs) = (i, j);
+
+        // Grow the region while hunks of either side overlap or touch it
+        let mut end = start;
+        loop {
+            let mut grew = false;
+            while let Some(hunk) = ours_hunks.get(i).filter(|h| h.base.start <= end) {
+                end = end.max(hunk.base.end);
+                ours_delta += hunk.side.len() as isize - hunk.base.len() as isize;
+                i += 1;
+                grew = true;
+            }
+            while let Some(hunk) = theirs_hunks.get(j).filter(|h| h.base.start <= end) {
+                end = end.max(hunk.base.end);
+                theirs_delta += hunk.side.len() as isize - hunk.base.len() as isize;
+                j += 1;
+                grew = true;
+            }
+            if !grew {
+                break;
+            }
+        }
+
+        push_clean(&mut sections, &base[position..start]);
+        let ours_lines = &ours[ours_start..shift(end, ours_delta)];
+        let theirs_lines = &theirs[theirs_start..shift(end, theirs_delta)];
+        if i == first_ours {
+            sections.push(Section::Resolved(theirs_lines.to_vec()));
+        } else if j == first_theirs || ours_lines == theirs_lines {
+            sections.push(Section::Resolved(ours_lines.to_vec()));
+        } else {
+            sections.push(Section::Conflict {
+                ours: ours_lines.to_vec(),
+                base: base[start..end].to_vec(),
+                theirs: theirs_lines.to_vec(),
+            });
+        }
+        position = end;
+    }
+    push_clean(&mut sections, &base[position..]);
+    sections
+}
+
+fn push_clean<'a>(sections: &mut Vec<Section<'a>>, lines: &[&'a [u8]]) {
+    if lines.is_empty() {
+        return;
+    }
+    if let Some(Section::Clean(clean)) = sections.last_mut() {
+        clean.extend_from_slice(lines);
+    } else {
+        sections.push(Section::Clean(lines.to_vec()));
+    }
+}
+
+/// Shrink conflicts to the lines where the sides really differ
+///
+/// With the `merge` style each conflict is split along a diff of our and
+/// their side. With `zdiff3` only the lines common to the start and end of
+/// both sides are moved out, so the base stays meaningful.
+fn refine_conflicts(sections: Vec<Section<'_>>, style: ConflictStyle) -> Vec<Section<'_>> {
+    let mut refined = Vec::new();
+    for section in sections {
+        let Section::Conflict { ours, base, theirs } = section else {
+            match section {
+                Section::Clean(lines) => push_clean(&mut refined, &lines),
+                _ => refined.push(section),
+            }
+            continue;
+        };
+        match style {
+            ConflictStyle::Diff3 => refined.push(Section::Conflict { ours, base, theirs }),
+            ConflictStyle::ZDiff3 => {
+                let prefix = ours.iter().zip(&theirs).take_while(|(o, t)| o == t).count();
+                let suffix = ours[prefix..]
+                    .iter()
+                    .rev()
+                    .zip(theirs[prefix..].iter().rev())
+                    .take_while(|(o, t)| o == t)
+                    .count();
+                push_clean(&mut refined, &ours[..prefix]);
+                refined.push(Section::Conflict {
+                    ours: ours[prefix..ours.len() - suffix].to_vec(),
+                    base,
+                    theirs: theirs[prefix..theirs.len() - suffix].to_vec(),
+                });
+                push_clean(&mut refined, &ours[ours.len() - suffix..]);
+            }
+            ConflictStyle::Merge => {
+                let ops = diff_lines(&ours, &theirs, Algorithm::Myers);
+                let (mut o, mut t) = (0, 0);
+                for hunk in hunks(&ops) {
+                    push_clean(&mut refined, &ours[o..hunk.base.start]);
+                    refined.push(Section::Conflict {
+                        ours: ours[hunk.base.clone()].to_vec(),
+                        base: Vec::new(),
+                        theirs: theirs[hunk.side.clone()].to_vec(),
+                    });
+                    o = hunk.base.end;
+                    t = hunk.side.end;
+                }
+                debug_assert_eq!(ours.len() - o, theirs.len() - t);
+                push_clean(&mut refined, &ours[o..]);
+            }
+        }
+    }
+    match style {
+        ConflictStyle::Merge => join_close_conflicts(refined),
+        _ => refined,
+    }
+}
+
+/// Join conflicts separated by at most three unchanged lines, as git does
+/// to avoid a cascade of tiny conflicts
+fn join_close_conflicts(sections: Vec<Section<'_>>) -> Vec<Section<'_>> {
+    let mut joined: Vec<Section> = Vec::new();
+    let mut sections = sections.into_iter().peekable();
+    while let Some(section) = sections.next() {
+        let joinable = matches!(&section, Section::Clean(gap) if gap.len() <= 3)
+            && matches!(joined.last(), Some(Section::Conflict { .. }))
+            && matches!(sections.peek(), Some(Section::Conflict { .. }));
+        if !joinable {
+            joined.push(section);
+            continue;
+        }
+        let (
+            Section::Clean(gap),
+            Some(Section::Conflict {
+                ours: next_ours,
+                theirs: next_theirs,
+                ..
+            }),
+            Some(Section::Conflict { ours, theirs, .. }),
+        ) = (section, sections.next(), joined.last_mut())
+        else {
+            unreachable!("checked above");
+        };
+        ours.extend(gap.iter().chain(&next_ours));
+        theirs.extend(gap.iter().chain(&next_theirs));
+    }
+    joined
+}
+
+/// Three-way merge the content of a file, returning the merged content and
+/// the number of conflicts written with markers
+pub fn merge_content(
+    base: &[u8],
+    ours: &[u8],
+    theirs: &[u8],
+    labels: &ConflictLabels,
+    style: ConflictStyle,
+) -> (Vec<u8>, usize) {
+    let (base, ours, theirs) = (split_lines(base), split_lines(ours), split_lines(theirs));
+    let sections = refine_conflicts(merge_lines(&base, &ours, &theirs), style);
+
+    let mut out = Vec::new();
+    let mut conflicts = 0;
+    let marker = |out: &mut Vec<u8>, c: u8, label: &str| {
+        out.extend(std::iter::repeat_n(c, MARKER_SIZE));
+        if !label.is_empty() {
+            out.push(b' ');
+            out.extend_from_slice(label.as_bytes());
+        }
+        out.push(b'\n');
+    };
+    // Lines before a marker must end with a newline
+    let lines = |out: &mut Vec<u8>, lines: &[&[u8]]| {
+        for line in lines {
+            out.extend_from_slice(line);
+        }
+        if lines.last().is_some_and(|line| !line.ends_with(b"\n")) {
+            out.push(b'\n');
+        }
+    };
+
+    for section in sections {
+        match section {
+            Section::Clean(clean) | Section::Resolved(clean) => {
+                clean.iter().for_each(|line| out.extend_from_slice(line))
+            }
+            Section::Conflict { ours, base, theirs } => {
+                conflicts += 1;
+                marker(&mut out, b'<', &labels.ours);
+                lines(&mut out, &ours);
+                if style != ConflictStyle::Merge {
+                    marker(&mut out, b'|', &labels.base);
+                    lines(&mut out, &base);
+                }
+                marker(&mut out, b'=', "");
+                lines(&mut out, &theirs);
+                marker(&mut out, b'>', &labels.theirs);
+            }
+        }
+    }
+    (out, conflicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkout::checkout_tree;
+    use crate::refs::update_ref;
+    use crate::test_utils::{commit_files, init_repo, set_identity, write_commit};
+
+    fn labels() -> ConflictLabels {
+        ConflictLabels {
+            ours: "ours".to_string(),
+            base: "base".to_string(),
+            theirs: "theirs".to_string(),
+        }
+    }
+
+    fn merge_text(base: &str, ours: &str, theirs: &str, style: ConflictStyle) -> (String, usize) {
+        let (merged, conflicts) = merge_content(
+            base.as_bytes(),
+            ours.as_bytes(),
+            theirs.as_bytes(),
+            &labels(),
+            style,
+        );
+        (String::from_utf8(merged).unwrap(), conflicts)
+    }
+
+    #[test]
+    fn test_merge_content_clean() {
+        let base = "a\nb\nc\nd\ne\n";
+        let (merged, conflicts) = merge_text(
+            base,
+            "A\nb\nc\nd\ne\n",
+            "a\nb\nc\nd\nE\n",
+            ConflictStyle::Merge,
+        );
+        assert_eq!(merged, "A\nb\nc\nd\nE\n");
+        assert_eq!(conflicts, 0);
+
+        // The same change on both sides is not a conflict
+        let (merged, conflicts) = merge_text(
+            base,
+            "a\nX\nc\nd\ne\n",
+            "a\nX\nc\nd\ne\n",
+            ConflictStyle::Merge,
+        );
+        assert_eq!(merged, "a\nX\nc\nd\ne\n");
+        assert_eq!(conflicts, 0);
+    }
+
+    #[test]
+    fn test_merge_content_conflict_styles() {
+        let base = "a\nb\nc\n";
+        let ours = "a\nx\nsame\nc\n";
+        let theirs = "a\ny\nsame\nc\n";
+
+        let (merged, conflicts) = merge_text(base, ours, theirs, ConflictStyle::Merge);
+        assert_eq!(conflicts, 1);
+        assert_eq!(
+            merged,
+            "a\n<<<<<<< ours\nx\n=======\ny\n>>>>>>> theirs\nsame\nc\n"
+        );
+
+        let (merged, _) = merge_text(base, ours, theirs, ConflictStyle::Diff3);
+        assert_eq!(
+            merged,
+            "a\n<<<<<<< ours\nx\nsame\n||||||| base\nb\n=======\ny\nsame\n>>>>>>> theirs\nc\n"
+        );
+
+        let (merged, _) = merge_text(base, ours, theirs, ConflictStyle::ZDiff3);
+        assert_eq!(
+            merged,
+            "a\n<<<<<<< ours\nx\n||||||| base\nb\n=======\ny\n>>>>>>> theirs\nsame\nc\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_content_missing_newline() {
+        let (merged, conflicts) = merge_text("a", "b", "c", ConflictStyle::Merge);
+        assert_eq!(conflicts, 1);
+        assert_eq!(merged, "<<<<<<< ours\nb\n=======\nc\n>>>>>>> theirs\n");
+    }
+
+    #[test]
+    fn test_merge_trees() {
+        let (_dir, repo) = init_repo();
+        let base = write_commit(
+            &repo,
+            &[("same", "s\n"), ("both", "1\n2\n3\n"), ("gone", "g\n")],
+            &[],
+            "base",
+        );
+        let ours = write_commit(
+            &repo,
+            &[
+                ("same", "s\n"),
+                ("both", "one\n2\n3\n"),
+                ("gone", "changed\n"),
+                ("new", "o\n"),
+            ],
+            std::slice::from_ref(&base),
+            "ours",
+        );
+        let theirs = write_commit(
+            &repo,
+            &[("same", "s\n"), ("both", "1\n2\nthree\n"), ("new", "t\n")],
+            std::slice::from_ref(&base),
+            "theirs",
+        );
+        let tree = |hash: &ObjectHash| Commit::read(&repo, hash).unwrap().tree;
+        let result = merge_trees(
+            &repo,
+            Some(&tree(&base)),
+            &tree(&ours),
+            &tree(&theirs),
+            &labels(),
+            ConflictStyle::Merge,
+        )
+        .unwrap();
+
+        let content = |path: &str| {
+            let (_, hash) = &result.files[path];
+            String::from_utf8(read_object(&repo, hash).unwrap().data).unwrap()
+        };
+        assert_eq!(content("both"), "one\n2\nthree\n");
+        assert_eq!(content("gone"), "changed\n");
+        assert_eq!(
+            content("new"),
+            "<<<<<<< ours\no\n=======\nt\n>>>>>>> theirs\n"
+        );
+        let kinds = result
+            .conflicts
+            .iter()
+            .map(|c| (c.path.as_str(), c.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                ("gone", ConflictKind::ModifyDelete),
+                ("new", ConflictKind::AddAdd)
+            ]
+        );
+    }
+
+    fn setup_branches(ours: &str, theirs: &str) -> (tempfile::TempDir, Repository, ObjectHash) {
+        let (dir, repo) = init_repo();
+        set_identity(&repo);
+
+        let base = commit_files(&repo, &[("file", "a\nb\nc\n"), ("other", "o\n")], "base");
+        let topic = write_commit(
+            &repo,
+            &[("file", theirs), ("other", "o\n")],
+            &[base],
+            "topic",
+        );
+        update_ref(&repo, "refs/heads/topic", &topic).unwrap();
+        let head = commit_files(&repo, &[("file", ours), ("other", "o\n")], "ours");
+        checkout_tree(&repo, &Commit::read(&repo, &head).unwrap().tree, true).unwrap();
+        (dir, repo, topic)
+    }
+
+    #[test]
+    fn test_merge_creates_merge_commit() {
+        let (_dir, repo, topic) = setup_branches("A\nb\nc\n", "a\nb\nC\n");
+        let outcome = merge(&repo, "topic", &MergeOptions::default()).unwrap();
+        let MergeOutcome::Merged { commit, .. } = outcome else {
+            panic!("expected a merge commit, got {:?}", outcome);
+        };
+        let commit = Commit::read(&repo, &commit).unwrap();
+        assert_eq!(commit.parents[1], topic);
+        assert_eq!(commit.message, "Merge branch 'topic'\n");
+        let content = fs::read_to_string(repo.worktree().join("file")).unwrap();
+        assert_eq!(content, "A\nb\nC\n");
+
+        let outcome = merge(&repo, "topic", &MergeOptions::default()).unwrap();
+        assert!(matches!(outcome, MergeOutcome::UpToDate));
+    }
+
+    #[test]
+    fn test_merge_conflict_and_continue() {
+        let (_dir, repo, topic) = setup_branches("ours\nb\nc\n", "theirs\nb\nc\n");
+        let options = MergeOptions {
+            fast_forward: FastForward::Only,
+            ..Default::default()
+        };
+        assert!(merge(&repo, "topic", &options).is_err());
+
+        let outcome = merge(&repo, "topic", &MergeOptions::default()).unwrap();
+        assert!(matches!(outcome, MergeOutcome::Conflicted(_)));
+        assert!(merge_in_progress(&repo));
+        let index = Index::read(&repo).unwrap();
+        assert_eq!(index.conflicted_paths(), vec!["file"]);
+        assert!(index.get_stage("file", 3).is_some());
+        let content = fs::read_to_string(repo.worktree().join("file")).unwrap();
+        assert!(content.starts_with("<<<<<<< HEAD\nours\n=======\ntheirs\n>>>>>>> topic\n"));
+        assert!(merge_continue(&repo).is_err());
+
+        fs::write(repo.worktree().join("file"), "resolved\n").unwrap();
+        crate::add::add(&repo, &["file".to_string()], &Default::default()).unwrap();
+        let commit = merge_continue(&repo).unwrap();
+        let commit = Commit::read(&repo, &commit).unwrap();
+        assert_eq!(commit.parents[1], topic);
+        assert_eq!(commit.message, "Merge branch 'topic'\n");
+        assert!(!merge_in_progress(&repo));
+    }
+
+    #[test]
+    fn test_merge_abort_restores_head() {
+        let (_dir, repo, _) = setup_branches("ours\nb\nc\n", "theirs\nb\nc\n");
+        merge(&repo, "topic", &MergeOptions::default()).unwrap();
+        merge_abort(&repo).unwrap();
+        let content = fs::read_to_string(repo.worktree().join("file")).unwrap();
+        assert_eq!(content, "ours\nb\nc\n");
+        assert!(!Index::read(&repo).unwrap().has_conflicts());
+        assert!(!merge_in_progress(&repo));
+    }
+
+    #[test]
+    fn test_merge_fast_forward() {
+        let (_dir, repo) = init_repo();
+        let base = commit_files(&repo, &[("file", "a\n")], "base");
+        checkout_tree(&repo, &Commit::read(&repo, &base).unwrap().tree, true).unwrap();
+        let next = write_commit(
+            &repo,
+            &[("file", "b\n")],
+            std::slice::from_ref(&base),
+            "next",
+        );
+        update_ref(&repo, "refs/heads/topic", &next).unwrap();
+
+        let options = MergeOptions {
+            fast_forward: FastForward::Only,
+            ..Default::default()
+        };
+        let outcome = merge(&repo, "topic", &options).unwrap();
+        assert!(matches!(outcome, MergeOutcome::FastForward { to, .. } if to == next));
+        assert_eq!(resolve_ref(&repo, "HEAD").unwrap(), Some(next));
+        let content = fs::read_to_string(repo.worktree().join("file")).unwrap();
+        assert_eq!(content, "b\n");
+    }
+}
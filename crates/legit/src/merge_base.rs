@@ -0,0 +1,430 @@
+use crate::commit_graph;
+use crate::commits::{Commit, Signature};
+use crate::objects::{object_exists, ObjectHash};
+use crate::refs::{read_reflog, resolve_ref};
+use crate::revision::expand_ref_name;
+use crate::shallow::{graft_parents, read_shallow};
+use crate::Repository;
+use anyhow::{bail, Result};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// Reachable from the first commit
+const PARENT1: u8 = 1;
+/// Reachable from one of the other commits
+const PARENT2: u8 = 2;
+/// Reachable from a common ancestor, so no longer a candidate
+const STALE: u8 = 4;
+
+/// The generation of commits missing from the commit-graph, which sort
+/// above every commit it holds
+const GENERATION_INFINITY: u32 = u32::MAX;
+
+/// CommitNode is what history walks need to know about a commit
+struct CommitNode {
+    parents: Vec<ObjectHash>,
+    date: i64,
+    /// One more than the highest generation of the parents, as the
+    /// commit-graph file records it; root commits are generation 1
+    generation: Option<u32>,
+}
+
+/// CommitGraph caches the parents, commit dates and generation numbers
+/// needed to walk history
+///
+/// A commit can only reach commits of a lower generation, which lets walks
+/// stop early instead of going all the way to the root commits. Only the
+/// commit-graph file provides generations; without it walks go by commit
+/// date, as computing generations would itself walk to the roots.
+struct CommitGraph<'a> {
+    repo: &'a Repository,
+    commits: HashMap<ObjectHash, CommitNode>,
+    /// The repository's commit-graph file, which has generation numbers
+    /// ready for the commits it holds
+    file: Option<commit_graph::CommitGraph>,
+    /// The shallow commits, whose parents the repository lacks
+    shallow: HashSet<ObjectHash>,
+}
+
+impl<'a> CommitGraph<'a> {
+    fn new(repo: &'a Repository) -> Self {
+        CommitGraph {
+            repo,
+            commits: HashMap::new(),
+            // A broken commit-graph only makes walks slower
+            file: commit_graph::CommitGraph::open(repo).ok().flatten(),
+            shallow: read_shallow(repo).unwrap_or_default(),
+        }
+    }
+
+    fn load(&mut self, hash: &ObjectHash) -> Result<&mut CommitNode> {
+        let stored = match (&self.file, self.commits.contains_key(hash)) {
+            (Some(file), false) => file.lookup(hash)?,
+            _ => None,
+        };
+        if let Some(commit) = stored {
+            let node = CommitNode {
+                parents: commit.parents,
+                date: commit.commit_time,
+                generation: Some(commit.topo_level),
+            };
+            self.commits.insert(hash.clone(), node);
+        }
+        if !self.commits.contains_key(hash) {
+            let commit = Commit::read(self.repo, hash)?;
+            let date = Signature::parse(&commit.committer)
+                .map(|s| s.time)
+                .unwrap_or_default();
+            let node = CommitNode {
+                parents: graft_parents(&self.shallow, hash, commit.parents),
+                date,
+                generation: None,
+            };
+            self.commits.insert(hash.clone(), node);
+        }
+        Ok(self.commits.get_mut(hash).expect("commit was just loaded"))
+    }
+
+    fn parents(&mut self, hash: &ObjectHash) -> Result<Vec<ObjectHash>> {
+        Ok(self.load(hash)?.parents.clone())
+    }
+
+    fn date(&mut self, hash: &ObjectHash) -> Result<i64> {
+        Ok(self.load(hash)?.date)
+    }
+
+    /// Return the generation number of a commit from the commit-graph, or
+    /// `GENERATION_INFINITY` if it is not there
+    fn generation(&mut self, hash: &ObjectHash) -> Result<u32> {
+        Ok(self.load(hash)?.generation.unwrap_or(GENERATION_INFINITY))
+    }
+
+    /// Walk down from `one` and `others`, highest generation first, marking
+    /// which side reaches each commit
+    ///
+    /// Returns the commits reached from both sides that were not already
+    /// known to be below another common commit, along with the flags.
+    /// Commits below `min_generation` are not walked.
+    fn paint_down_to_common(
+        &mut self,
+        one: &ObjectHash,
+        others: &[ObjectHash],
+        min_generation: u32,
+    ) -> Result<(Vec<ObjectHash>, HashMap<ObjectHash, u8>)> {
+        let mut flags: HashMap<ObjectHash, u8> = HashMap::new();
+        // Ties are broken by commit date, then by insertion order
+        let mut queue = BinaryHeap::new();
+        let mut sequence = 0usize;
+        let mut push = |graph: &mut Self, queue: &mut BinaryHeap<_>, hash: ObjectHash| {
+            let key = (
+                graph.generation(&hash)?,
+                graph.date(&hash)?,
+                Reverse(sequence),
+            );
+            queue.push((key, hash));
+            sequence += 1;
+            anyhow::Ok(())
+        };
+        flags.insert(one.clone(), PARENT1);
+        push(self, &mut queue, one.clone())?;
+        for other in others {
+            *flags.entry(other.clone()).or_default() |= PARENT2;
+            push(self, &mut queue, other.clone())?;
+        }
+
+        let mut result = Vec::new();
+        while queue
+            .iter()
+            .any(|(_, hash)| flags.get(hash).is_some_and(|f| f & STALE == 0))
+        {
+            let Some(((generation, _, _), hash)) = queue.pop() else {
+                break;
+            };
+            if generation < min_generation {
+                break;
+            }
+            let mut flag = flags[&hash] & (PARENT1 | PARENT2 | STALE);
+            if flag & (PARENT1 | PARENT2) == PARENT1 | PARENT2 {
+                if flag & STALE == 0 && !result.contains(&hash) {
+                    result.push(hash.clone());
+                }
+                flag |= STALE;
+            }
+            for parent in self.parents(&hash)? {
+                let current = flags.entry(parent.clone()).or_default();
+                if *current & flag == flag {
+                    continue;
+                }
+                *current |= flag;
+                push(self, &mut queue, parent)?;
+            }
+        }
+        Ok((result, flags))
+    }
+
+    /// Drop the commits reachable from another commit of the list
+    fn remove_redundant(&mut self, commits: Vec<ObjectHash>) -> Result<Vec<ObjectHash>> {
+        let mut min_generation = u32::MAX;
+        for commit in &commits {
+            min_generation = min_generation.min(self.generation(commit)?);
+        }
+        let mut redundant = vec![false; commits.len()];
+        for i in 0..commits.len() {
+            if redundant[i] {
+                continue;
+            }
+            let others = (0..commits.len())
+                .filter(|&j| j != i && !redundant[j])
+                .map(|j| commits[j].clone())
+                .collect::<Vec<_>>();
+            if others.is_empty() {
+                break;
+            }
+            let (_, flags) = self.paint_down_to_common(&commits[i], &others, min_generation)?;
+            let flag = |hash: &ObjectHash| flags.get(hash).copied().unwrap_or_default();
+            for (j, commit) in commits.iter().enumerate() {
+                if j != i && flag(commit) & PARENT1 != 0 {
+                    redundant[j] = true;
+                }
+            }
+            if flag(&commits[i]) & PARENT2 != 0 {
+                redundant[i] = true;
+            }
+        }
+        Ok(commits
+            .into_iter()
+            .zip(redundant)
+            .filter(|(_, redundant)| !redundant)
+            .map(|(commit, _)| commit)
+            .collect())
+    }
+
+    /// Return true if `ancestor` can be reached from `descendant`
+    fn reaches(&mut self, descendant: &ObjectHash, ancestor: &ObjectHash) -> Result<bool> {
+        let min_generation = self.generation(ancestor)?;
+        let mut seen = HashSet::new();
+        let mut stack = vec![descendant.clone()];
+        while let Some(hash) = stack.pop() {
+            if hash == *ancestor {
+                return Ok(true);
+            }
+            // Nothing below the ancestor's generation can lead to it
+            let generation = self.generation(&hash)?;
+            let below = generation != GENERATION_INFINITY && generation <= min_generation;
+            if below || !seen.insert(hash.clone()) {
+                continue;
+            }
+            stack.extend(self.parents(&hash)?);
+        }
+        Ok(false)
+    }
+}
+
+/// Return the best common ancestors of two commits, like `git merge-base --all`
+///
+/// A common ancestor is best when it is not an ancestor of another common
+/// ancestor. Criss-cross histories can have several.
+pub fn merge_bases(
+    repo: &Repository,
+    one: &ObjectHash,
+    two: &ObjectHash,
+) -> Result<Vec<ObjectHash>> {
+    merge_bases_many(repo, one, std::slice::from_ref(two))
+}
+
+/// Return the best common ancestors of `one` and a hypothetical merge of
+/// all of `others`, newest first
+pub fn merge_bases_many(
+    repo: &Repository,
+    one: &ObjectHash,
+    others: &[ObjectHash],
+) -> Result<Vec<ObjectHash>> {
+    if others.contains(one) {
+        return Ok(vec![one.clone()]);
+    }
+    let mut graph = CommitGraph::new(repo);
+    let (common, _) = graph.paint_down_to_common(one, others, 0)?;
+    let mut bases = match common.len() {
+        0 | 1 => common,
+        _ => graph.remove_redundant(common)?,
+    };
+    let mut dates = HashMap::new();
+    for base in &bases {
+        dates.insert(base.clone(), graph.date(base)?);
+    }
+    bases.sort_by_key(|base| Reverse(dates[base]));
+    Ok(bases)
+}
+
+/// Return the common ancestors of all commits, for merging them at once
+///
+/// The bases are found pairwise, each new commit against the bases so far,
+/// and then reduced to those not reachable from one another, in the order
+/// `git merge-base --octopus` gives them.
+pub fn octopus_merge_bases(repo: &Repository, commits: &[ObjectHash]) -> Result<Vec<ObjectHash>> {
+    let Some((first, rest)) = commits.split_first() else {
+        return Ok(Vec::new());
+    };
+    let mut bases = vec![first.clone()];
+    for next in rest {
+        let mut merged = Vec::new();
+        for base in &bases {
+            for found in merge_bases(repo, next, base)? {
+                if !merged.contains(&found) {
+                    merged.push(found);
+                }
+            }
+        }
+        bases = merged;
+    }
+    match bases.len() {
+        0 | 1 => Ok(bases),
+        _ => CommitGraph::new(repo).remove_redundant(bases),
+    }
+}
+
+/// Return true if `ancestor` is reachable from `descendant`
+///
+/// A commit is its own ancestor.
+pub fn is_ancestor(
+    repo: &Repository,
+    ancestor: &ObjectHash,
+    descendant: &ObjectHash,
+) -> Result<bool> {
+    CommitGraph::new(repo).reaches(descendant, ancestor)
+}
+
+/// Find where `commit` forked from the history of a reference, like
+/// `git merge-base --fork-point`
+///
+/// Every value the reference had according to its reflog is a candidate,
+/// so a branch that was rewritten since `commit` forked from it is handled.
+/// Returns `None` when the fork point is not one of those values.
+pub fn fork_point(
+    repo: &Repository,
+    reference: &str,
+    commit: &ObjectHash,
+) -> Result<Option<ObjectHash>> {
+    let Some(name) = expand_ref_name(repo, reference)? else {
+        bail!("Not a valid reference: {}", reference);
+    };
+    let reflog = read_reflog(repo, &name)?;
+    let mut candidates = Vec::new();
+    let values = reflog
+        .first()
+        .map(|entry| entry.old.clone())
+        .into_iter()
+        .chain(reflog.into_iter().map(|entry| entry.new));
+    for value in values {
+        if object_exists(repo, &value) && !candidates.contains(&value) {
+            candidates.push(value);
+        }
+    }
+    if candidates.is_empty() {
+        candidates.extend(resolve_ref(repo, &name)?);
+    }
+
+    match merge_bases_many(repo, commit, &candidates)?.as_slice() {
+        [base] if candidates.contains(base) => Ok(Some(base.clone())),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::refs::update_ref;
+    use crate::test_utils::{init_repo, write_commit};
+    use std::fs;
+
+    #[test]
+    fn test_merge_base_of_branches() {
+        let (_dir, repo) = init_repo();
+        let root = write_commit(&repo, &[("a", "1")], &[], "root");
+        let base = write_commit(&repo, &[("a", "2")], std::slice::from_ref(&root), "base");
+        let left = write_commit(&repo, &[("a", "3")], std::slice::from_ref(&base), "left");
+        let right = write_commit(&repo, &[("a", "4")], std::slice::from_ref(&base), "right");
+
+        assert_eq!(
+            merge_bases(&repo, &left, &right).unwrap(),
+            vec![base.clone()]
+        );
+        assert_eq!(
+            merge_bases(&repo, &left, &base).unwrap(),
+            vec![base.clone()]
+        );
+        assert!(is_ancestor(&repo, &root, &right).unwrap());
+        assert!(is_ancestor(&repo, &right, &right).unwrap());
+        assert!(!is_ancestor(&repo, &left, &right).unwrap());
+        assert!(!is_ancestor(&repo, &right, &root).unwrap());
+
+        let other = write_commit(&repo, &[("b", "1")], &[], "unrelated");
+        assert!(merge_bases(&repo, &left, &other).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_criss_cross_merge_bases() {
+        let (_dir, repo) = init_repo();
+        let root = write_commit(&repo, &[("a", "1")], &[], "root");
+        let a = write_commit(&repo, &[("a", "2")], std::slice::from_ref(&root), "a");
+        let b = write_commit(&repo, &[("a", "3")], std::slice::from_ref(&root), "b");
+        let left = write_commit(&repo, &[("a", "4")], &[a.clone(), b.clone()], "left");
+        let right = write_commit(&repo, &[("a", "5")], &[b.clone(), a.clone()], "right");
+
+        let mut bases = merge_bases(&repo, &left, &right).unwrap();
+        bases.sort_by_key(|h| h.to_hex());
+        let mut expected = vec![a, b];
+        expected.sort_by_key(|h| h.to_hex());
+        assert_eq!(bases, expected);
+    }
+
+    #[test]
+    fn test_octopus_merge_bases() {
+        let (_dir, repo) = init_repo();
+        let root = write_commit(&repo, &[("a", "1")], &[], "root");
+        let base = write_commit(&repo, &[("a", "2")], std::slice::from_ref(&root), "base");
+        let one = write_commit(&repo, &[("a", "3")], std::slice::from_ref(&base), "one");
+        let two = write_commit(&repo, &[("a", "4")], std::slice::from_ref(&base), "two");
+        let three = write_commit(&repo, &[("a", "5")], std::slice::from_ref(&root), "three");
+
+        let bases = octopus_merge_bases(&repo, &[one.clone(), two.clone()]).unwrap();
+        assert_eq!(bases, vec![base]);
+        let bases = octopus_merge_bases(&repo, &[one, two, three]).unwrap();
+        assert_eq!(bases, vec![root.clone()]);
+
+        // Pairwise bases reachable from another base are dropped
+        let p = write_commit(&repo, &[("a", "6")], std::slice::from_ref(&root), "p");
+        let q = write_commit(&repo, &[("a", "7")], std::slice::from_ref(&root), "q");
+        let left = write_commit(&repo, &[("a", "8")], &[p.clone(), q.clone()], "left");
+        let right = write_commit(&repo, &[("a", "9")], &[q.clone(), p.clone()], "right");
+        let tip = write_commit(&repo, &[("a", "10")], std::slice::from_ref(&p), "tip");
+        let bases = octopus_merge_bases(&repo, &[left, right, tip]).unwrap();
+        assert_eq!(bases, vec![p]);
+    }
+
+    #[test]
+    fn test_fork_point_uses_reflog() {
+        let (_dir, repo) = init_repo();
+        let root = write_commit(&repo, &[("a", "1")], &[], "root");
+        let old_tip = write_commit(&repo, &[("a", "2")], std::slice::from_ref(&root), "old");
+        let topic = write_commit(
+            &repo,
+            &[("a", "3")],
+            std::slice::from_ref(&old_tip),
+            "topic",
+        );
+        // The upstream branch was rewritten after topic forked from it
+        let new_tip = write_commit(&repo, &[("a", "4")], std::slice::from_ref(&root), "new");
+        update_ref(&repo, "refs/heads/main", &new_tip).unwrap();
+        assert_eq!(fork_point(&repo, "main", &topic).unwrap(), None);
+
+        let log = format!(
+            "{zero} {old_tip} A <a@x> 1 +0000\tbranch: Created\n{old_tip} {new_tip} A <a@x> 2 +0000\tpush\n",
+            zero = "0".repeat(40)
+        );
+        let log_path = repo.gitdir().join("logs/refs/heads/main");
+        fs::create_dir_all(log_path.parent().unwrap()).unwrap();
+        fs::write(log_path, log).unwrap();
+        assert_eq!(fork_point(&repo, "main", &topic).unwrap(), Some(old_tip));
+    }
+}
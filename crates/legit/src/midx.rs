@@ -0,0 +1,401 @@
+use crate::objects::{HashAlgorithm, ObjectHash};
+use crate::pack::packs;
+use crate::refs::write_atomic;
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+
+const SIGNATURE: &[u8; 4] = b"MIDX";
+
+/// Marks an offset stored in the table of 64-bit offsets
+const LARGE_OFFSET: u32 = 0x8000_0000;
+
+/// MultiPackIndex is `objects/pack/multi-pack-index`: one sorted index of
+/// the objects of many packs, so a lookup is a single binary search
+#[derive(Debug)]
+pub struct MultiPackIndex {
+    dir: PathBuf,
+    data: Vec<u8>,
+    chunks: HashMap<[u8; 4], (usize, usize)>,
+    /// The index files of the packs, sorted by name
+    pack_names: Vec<String>,
+    count: usize,
+    hash_size: usize,
+}
+
+/// The parsed multi-pack-index with the modification time and size it was
+/// read at; unlike pack names, its path does not change with its content
+type CacheEntry = (SystemTime, u64, Arc<MultiPackIndex>);
+
+fn midx_cache() -> &'static Mutex<HashMap<PathBuf, CacheEntry>> {
+    static CACHE: OnceLock<Mutex<HashMap<PathBuf, CacheEntry>>> = OnceLock::new();
+    CACHE.get_or_init(Default::default)
+}
+
+impl MultiPackIndex {
+    /// Load the multi-pack-index of a repository, if it has one
+    pub fn open(repo: &Repository) -> Result<Option<Arc<MultiPackIndex>>> {
+        let dir = repo.commondir().join("objects").join("pack");
+        let path = dir.join("multi-pack-index");
+        let Ok(metadata) = fs::metadata(&path) else {
+            return Ok(None);
+        };
+        let stamp = (metadata.modified()?, metadata.len());
+        if let Some((modified, len, midx)) = midx_cache().lock().unwrap().get(&path) {
+            if (*modified, *len) == stamp {
+                return Ok(Some(midx.clone()));
+            }
+        }
+        let midx = Arc::new(
+            MultiPackIndex::parse(dir, fs::read(&path)?, repo.hash_algorithm())
+                .with_context(|| format!("Invalid multi-pack-index {}", path.display()))?,
+        );
+        midx_cache()
+            .lock()
+            .unwrap()
+            .insert(path, (stamp.0, stamp.1, midx.clone()));
+        Ok(Some(midx))
+    }
+
+    fn parse(dir: PathBuf, data: Vec<u8>, algorithm: HashAlgorithm) -> Result<MultiPackIndex> {
+        let hash_size = algorithm.size();
+        if data.len() < 12 + hash_size || &data[..4] != SIGNATURE {
+            bail!("Not a multi-pack-index file");
+        }
+        if data[4] != 1 || data[5] != algorithm.id() {
+            bail!(
+                "Unsupported multi-pack-index version {} or hash {}",
+                data[4],
+                data[5]
+            );
+        }
+        let chunk_count = data[6] as usize;
+        let pack_count = u32::from_be_bytes(data[8..12].try_into()?) as usize;
+        let mut chunks = HashMap::new();
+        let table = |i: usize| -> Result<([u8; 4], usize)> {
+            let entry = data
+                .get(12 + i * 12..12 + (i + 1) * 12)
+                .context("Multi-pack-index chunk table is truncated")?;
+            let offset = u64::from_be_bytes(entry[4..].try_into()?) as usize;
+            Ok((entry[..4].try_into()?, offset))
+        };
+        for i in 0..chunk_count {
+            let (id, start) = table(i)?;
+            let (_, end) = table(i + 1)?;
+            if start > end || end > data.len() - hash_size {
+                bail!("Multi-pack-index chunk is out of range");
+            }
+            chunks.insert(id, (start, end));
+        }
+        let mut midx = MultiPackIndex {
+            dir,
+            data,
+            chunks,
+            pack_names: Vec::new(),
+            count: 0,
+            hash_size,
+        };
+        for id in [b"PNAM", b"OIDF", b"OIDL", b"OOFF"] {
+            if midx.chunk(id).is_none() {
+                bail!("Missing the {} chunk", String::from_utf8_lossy(id));
+            }
+        }
+        midx.pack_names = midx
+            .chunk(b"PNAM")
+            .unwrap_or_default()
+            .split(|&b| b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect();
+        if midx.pack_names.len() != pack_count {
+            bail!("Expected {} pack names", pack_count);
+        }
+        midx.count = midx.u32_at(b"OIDF", 255).context("Fanout is truncated")? as usize;
+        if midx.chunk(b"OIDL").unwrap_or_default().len() != midx.count * hash_size
+            || midx.chunk(b"OOFF").unwrap_or_default().len() != midx.count * 8
+        {
+            bail!("Chunks do not match the {} objects", midx.count);
+        }
+        Ok(midx)
+    }
+
+    fn chunk(&self, id: &[u8; 4]) -> Option<&[u8]> {
+        let (start, end) = self.chunks.get(id)?;
+        Some(&self.data[*start..*end])
+    }
+
+    fn u32_at(&self, id: &[u8; 4], index: usize) -> Option<u32> {
+        let bytes = self.chunk(id)?.get(index * 4..index * 4 + 4)?;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?))
+    }
+
+    /// Number of objects in the index
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Return true if the index holds no objects
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Names of the pack index files the multi-pack-index covers
+    pub fn pack_names(&self) -> &[String] {
+        &self.pack_names
+    }
+
+    /// Return the path of the index file of a pack, by its number
+    pub fn pack_path(&self, pack: usize) -> PathBuf {
+        self.dir.join(&self.pack_names[pack])
+    }
+
+    /// Find which pack holds an object, and at which offset
+    pub fn find(&self, hash: &ObjectHash) -> Option<(usize, u64)> {
+        let names = self.chunk(b"OIDL")?;
+        let first = hash.as_bytes()[0] as usize;
+        let start = match first {
+            0 => 0,
+            _ => self.u32_at(b"OIDF", first - 1)? as usize,
+        };
+        let end = (self.u32_at(b"OIDF", first)? as usize).min(self.count);
+        let (mut low, mut high) = (start, end);
+        while low < high {
+            let middle = (low + high) / 2;
+            let name = &names[middle * self.hash_size..(middle + 1) * self.hash_size];
+            match name.cmp(hash.as_bytes()) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return self.location(middle),
+            }
+        }
+        None
+    }
+
+    fn location(&self, index: usize) -> Option<(usize, u64)> {
+        let pack = self.u32_at(b"OOFF", index * 2)? as usize;
+        let offset = self.u32_at(b"OOFF", index * 2 + 1)?;
+        let offset = match offset & LARGE_OFFSET {
+            0 => offset as u64,
+            _ => {
+                let i = (offset & !LARGE_OFFSET) as usize * 8;
+                u64::from_be_bytes(self.chunk(b"LOFF")?.get(i..i + 8)?.try_into().ok()?)
+            }
+        };
+        (pack < self.pack_names.len()).then_some((pack, offset))
+    }
+}
+
+/// Write a multi-pack-index covering every pack of the repository, like
+/// `git multi-pack-index write`
+///
+/// An object in several packs is taken from the most recently modified one.
+/// Returns the number of objects indexed, or `None` without packs.
+pub fn write_midx(repo: &Repository) -> Result<Option<usize>> {
+    let dir = repo.commondir().join("objects").join("pack");
+    let path = dir.join("multi-pack-index");
+    let mut packs = packs(repo)?;
+    if packs.is_empty() {
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        return Ok(None);
+    }
+    let name = |path: &Path| path.with_extension("idx").file_name().map(|n| n.to_owned());
+    packs.sort_by_key(|pack| name(pack.path()));
+    let mut chosen: HashMap<ObjectHash, (SystemTime, usize, u64)> = HashMap::new();
+    for (number, pack) in packs.iter().enumerate() {
+        let modified = fs::metadata(pack.path())?.modified()?;
+        let index = pack.index();
+        for position in 0..index.len() {
+            let entry = (modified, number, index.offset(position));
+            chosen
+                .entry(index.hash(position))
+                .and_modify(|current| {
+                    if modified > current.0 {
+                        *current = entry;
+                    }
+                })
+                .or_insert(entry);
+        }
+    }
+    let mut objects = chosen.into_iter().collect::<Vec<_>>();
+    objects.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut pnam = Vec::new();
+    for pack in &packs {
+        let name = name(pack.path()).context("Pack without a file name")?;
+        pnam.extend(name.to_string_lossy().as_bytes());
+        pnam.push(0);
+    }
+    pnam.resize(pnam.len().div_ceil(4) * 4, 0);
+    let mut fanout = [0u32; 256];
+    for (hash, _) in &objects {
+        fanout[hash.as_bytes()[0] as usize] += 1;
+    }
+    let mut oidf = Vec::new();
+    let mut total = 0;
+    for count in fanout {
+        total += count;
+        oidf.extend(total.to_be_bytes());
+    }
+    let mut oidl = Vec::new();
+    let mut ooff = Vec::new();
+    let mut loff = Vec::new();
+    for (hash, (_, pack, offset)) in &objects {
+        oidl.extend(hash.as_bytes());
+        ooff.extend((*pack as u32).to_be_bytes());
+        match *offset < LARGE_OFFSET as u64 {
+            true => ooff.extend((*offset as u32).to_be_bytes()),
+            false => {
+                ooff.extend(((loff.len() / 8) as u32 | LARGE_OFFSET).to_be_bytes());
+                loff.extend(offset.to_be_bytes());
+            }
+        }
+    }
+    let mut chunks = vec![
+        (*b"PNAM", pnam),
+        (*b"OIDF", oidf),
+        (*b"OIDL", oidl),
+        (*b"OOFF", ooff),
+    ];
+    if !loff.is_empty() {
+        chunks.push((*b"LOFF", loff));
+    }
+
+    let mut data = Vec::new();
+    data.extend(SIGNATURE);
+    data.extend([1, repo.hash_algorithm().id(), chunks.len() as u8, 0]);
+    data.extend((packs.len() as u32).to_be_bytes());
+    let mut offset = (12 + (chunks.len() + 1) * 12) as u64;
+    for (id, chunk) in &chunks {
+        data.extend(id);
+        data.extend(offset.to_be_bytes());
+        offset += chunk.len() as u64;
+    }
+    data.extend([0; 4]);
+    data.extend(offset.to_be_bytes());
+    for (_, chunk) in &chunks {
+        data.extend(chunk);
+    }
+    let checksum = repo.hash_algorithm().digest(&data);
+    data.extend(checksum.as_bytes());
+    write_atomic(&path, &data)?;
+    Ok(Some(objects.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::read_object;
+    use crate::pack::{read_packed_object, store_pack};
+    use crate::pack_writer::{list_objects, write_pack};
+    use crate::test_utils::{commit_files, init_repo};
+    use std::time::Duration;
+
+    #[test]
+    fn test_write_and_read_midx() {
+        let (_dir, repo) = init_repo();
+        let first = commit_files(&repo, &[("a.txt", "one\n")], "first");
+        let second = commit_files(&repo, &[("a.txt", "two\n")], "second");
+        let (_dir, server) = init_repo();
+        let old = list_objects(&repo, std::slice::from_ref(&first), &[]).unwrap();
+        store_pack(&server, &write_pack(&repo, &old, false).unwrap()).unwrap();
+        let new = list_objects(&repo, std::slice::from_ref(&second), &[]).unwrap();
+        store_pack(&server, &write_pack(&repo, &new, false).unwrap()).unwrap();
+
+        assert_eq!(write_midx(&server).unwrap(), Some(6));
+        let midx = MultiPackIndex::open(&server).unwrap().unwrap();
+        assert_eq!(midx.pack_names().len(), 2);
+        assert!(midx.pack_names().iter().all(|n| n.ends_with(".idx")));
+        for (hash, _) in old.objects.iter().chain(&new.objects) {
+            let (pack, _) = midx.find(hash).unwrap();
+            assert!(midx.pack_path(pack).exists());
+            let object = read_packed_object(&server, hash).unwrap().unwrap();
+            assert_eq!(object.data, read_object(&repo, hash).unwrap().data);
+        }
+        assert_eq!(
+            midx.find(&HashAlgorithm::Sha1.digest("missing".as_bytes())),
+            None
+        );
+    }
+
+    #[test]
+    fn test_midx_across_packs() {
+        let (_dir, repo) = init_repo();
+        let first = commit_files(&repo, &[("a.txt", "one\n")], "first");
+        let second = commit_files(&repo, &[("b.txt", "two\n")], "second");
+        let third = commit_files(&repo, &[("c.txt", "three\n")], "third");
+        let (_dir, server) = init_repo();
+        let mut lists = Vec::new();
+        let mut packs = Vec::new();
+        // The last pack holds everything again, so each object of the
+        // first two is in two packs
+        for (include, exclude) in [
+            (&first, vec![]),
+            (&second, vec![first.clone()]),
+            (&third, vec![]),
+        ] {
+            let list = list_objects(&repo, std::slice::from_ref(include), &exclude).unwrap();
+            let pack = store_pack(&server, &write_pack(&repo, &list, false).unwrap())
+                .unwrap()
+                .unwrap();
+            lists.push(list);
+            packs.push(pack.path().with_extension("idx"));
+        }
+        let touch = |pack: &PathBuf, seconds: u64| {
+            let time = SystemTime::UNIX_EPOCH + Duration::from_secs(seconds);
+            fs::File::options()
+                .write(true)
+                .open(pack.with_extension("pack"))
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+        };
+        let pack_of = |midx: &MultiPackIndex, hash: &ObjectHash| {
+            let (pack, offset) = midx.find(hash).unwrap();
+            (midx.pack_path(pack), offset)
+        };
+
+        // The most recently modified pack is preferred for duplicates
+        touch(&packs[0], 3_000_000_000);
+        touch(&packs[1], 2_000_000_000);
+        touch(&packs[2], 1_000_000_000);
+        assert_eq!(write_midx(&server).unwrap(), Some(9));
+        let midx = MultiPackIndex::open(&server).unwrap().unwrap();
+        assert_eq!(midx.pack_names().len(), 3);
+        for (number, list) in lists.iter().enumerate().take(2) {
+            for (hash, _) in &list.objects {
+                let (path, offset) = pack_of(&midx, hash);
+                assert_eq!(path, packs[number]);
+                let index = crate::pack::Pack::open(&path, HashAlgorithm::Sha1).unwrap();
+                assert_eq!(index.index().find(hash), Some(offset));
+                let object = read_packed_object(&server, hash).unwrap().unwrap();
+                assert_eq!(object.data, read_object(&repo, hash).unwrap().data);
+            }
+        }
+        let only_third = lists[2]
+            .objects
+            .iter()
+            .filter(|(hash, _)| {
+                !lists[..2]
+                    .iter()
+                    .any(|l| l.objects.iter().any(|(h, _)| h == hash))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(only_third.len(), 3);
+        for (hash, _) in only_third {
+            assert_eq!(pack_of(&midx, hash).0, packs[2]);
+        }
+
+        touch(&packs[2], 4_000_000_000);
+        write_midx(&server).unwrap();
+        let midx = MultiPackIndex::open(&server).unwrap().unwrap();
+        for (hash, _) in &lists[2].objects {
+            assert_eq!(pack_of(&midx, hash).0, packs[2]);
+        }
+    }
+}
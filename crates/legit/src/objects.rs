@@ -1,12 +1,11 @@
 use crate::Repository;
 use anyhow::{bail, Context, Result};
-use digest::generic_array::typenum::U20;
-use digest::generic_array::GenericArray;
 use flate2::read::ZlibDecoder;
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
-use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 use std::fmt::{Display, Write};
 use std::fs::File;
 use std::io::{Read, Write as _};
@@ -32,15 +31,11 @@ pub struct Object {
 }
 
 impl Object {
-    /// Create a new Git object
-    pub fn new(object_type: ObjectType, data: Vec<u8>) -> Result<Self> {
-        let object_data = format!(
-            "{} {}\0{}",
-            object_type,
-            data.len(),
-            String::from_utf8_lossy(&data)
-        );
-        let hash = ObjectHash::try_from(object_data.as_str()).context("Failed to hash object")?;
+    /// Create a new Git object, named by hashing it with `algorithm`
+    pub fn new(algorithm: HashAlgorithm, object_type: ObjectType, data: Vec<u8>) -> Result<Self> {
+        let mut object_data = format!("{} {}\0", object_type, data.len()).into_bytes();
+        object_data.extend_from_slice(&data);
+        let hash = algorithm.hash_object(&object_data)?;
         Ok(Object {
             object_type,
             data,
@@ -51,7 +46,7 @@ impl Object {
     /// Return the file path of the object in the repository
     pub fn file_path(&self, repo: &Repository) -> PathBuf {
         let (dir, file) = self.hash.as_path_parts();
-        repo.gitdir().join("objects").join(dir).join(file)
+        repo.commondir().join("objects").join(dir).join(file)
     }
 
     /// Return the header of the object
@@ -60,42 +55,161 @@ impl Object {
     }
 }
 
-/// A newtype for a Git hash which guarantees that the hash is exactly 20 bytes long.
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct ObjectHash(GenericArray<u8, U20>);
+/// Size of the largest raw hash, a SHA-256 one
+const MAX_HASH_SIZE: usize = 32;
+
+/// HashAlgorithm is the hash function naming the objects of a repository,
+/// chosen by `extensions.objectFormat`
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Default,
+    EnumString,
+    strum::Display,
+    Serialize,
+    Deserialize,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// Size of a raw hash in bytes
+    pub fn size(self) -> usize {
+        match self {
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+        }
+    }
+
+    /// Length of a hash written in hexadecimal
+    pub fn hex_len(self) -> usize {
+        self.size() * 2
+    }
+
+    /// Number identifying the algorithm in the header of commit-graph and
+    /// multi-pack-index files
+    pub fn id(self) -> u8 {
+        match self {
+            HashAlgorithm::Sha1 => 1,
+            HashAlgorithm::Sha256 => 2,
+        }
+    }
+
+    /// Hash data into an object name. SHA-1 runs with collision
+    /// detection, as git's sha1dc does, so that data carrying a
+    /// SHAttered-style attack is refused instead of getting a name
+    /// shared with some other object.
+    pub fn hash_object(self, data: &[u8]) -> Result<ObjectHash> {
+        if self == HashAlgorithm::Sha256 {
+            return Ok(self.digest(data));
+        }
+        let result = sha1_checked::Sha1::try_digest(data);
+        if result.has_collision() {
+            bail!(
+                "SHA-1 appears to be part of a collision attack: {}",
+                hex::encode(result.hash())
+            );
+        }
+        let mut bytes = [0u8; MAX_HASH_SIZE];
+        bytes[..20].copy_from_slice(result.hash());
+        Ok(ObjectHash {
+            bytes,
+            algorithm: self,
+        })
+    }
+
+    /// Hash data without collision detection, for the checksums of packs,
+    /// indexes and other files, which only guard against corruption
+    pub fn digest(self, data: &[u8]) -> ObjectHash {
+        let mut bytes = [0u8; MAX_HASH_SIZE];
+        match self {
+            HashAlgorithm::Sha1 => bytes[..20].copy_from_slice(&Sha1::digest(data)),
+            HashAlgorithm::Sha256 => bytes.copy_from_slice(&Sha256::digest(data)),
+        }
+        ObjectHash {
+            bytes,
+            algorithm: self,
+        }
+    }
+
+    /// The all-zero hash git uses to mean "no object"
+    pub fn null_hash(self) -> ObjectHash {
+        ObjectHash {
+            bytes: [0u8; MAX_HASH_SIZE],
+            algorithm: self,
+        }
+    }
+}
+
+/// A Git object hash, either a 20 byte SHA-1 or a 32 byte SHA-256 one
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ObjectHash {
+    bytes: [u8; MAX_HASH_SIZE],
+    algorithm: HashAlgorithm,
+}
 
 impl ObjectHash {
-    /// Convert a hexadecimal string representation of a hash into an ObjectHash.
-    pub fn from_hex(hex: &str) -> Result<Self> {
-        if hex.len() != 40 {
+    /// Convert a hexadecimal string representation of a hash made with
+    /// `algorithm` into an ObjectHash.
+    pub fn from_hex(hex: &str, algorithm: HashAlgorithm) -> Result<Self> {
+        if hex.len() != algorithm.hex_len() {
             bail!(
-                "Invalid hash length: expected 40 characters, got {}",
+                "Invalid hash length: expected {} characters for {}, got {}",
+                algorithm.hex_len(),
+                algorithm,
                 hex.len()
             );
         }
-        let bytes = hex
-            .as_bytes()
-            .chunks(2)
-            .map(|chunk| {
-                let byte_str = std::str::from_utf8(chunk).unwrap();
-                u8::from_str_radix(byte_str, 16).unwrap()
-            })
-            .collect::<Vec<u8>>();
-        if bytes.len() != 20 {
+        let bytes = hex::decode(hex).context("Invalid hash: not a hexadecimal string")?;
+        ObjectHash::from_bytes(&bytes, algorithm)
+    }
+
+    /// Create an ObjectHash from the raw bytes of a hash made with
+    /// `algorithm`.
+    pub fn from_bytes(bytes: &[u8], algorithm: HashAlgorithm) -> Result<Self> {
+        if bytes.len() != algorithm.size() {
             bail!(
-                "Invalid hash length: expected 20 bytes, got {}",
+                "Invalid hash length: expected {} bytes for {}, got {}",
+                algorithm.size(),
+                algorithm,
                 bytes.len()
             );
         }
-        let mut array = GenericArray::<u8, U20>::default();
-        array.copy_from_slice(&bytes);
-        Ok(ObjectHash(array))
+        let mut hash = algorithm.null_hash();
+        hash.bytes[..bytes.len()].copy_from_slice(bytes);
+        Ok(hash)
+    }
+
+    /// Return the algorithm the hash was made with.
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Return the raw bytes of the hash.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.algorithm.size()]
     }
 
-    /// Convert the hash to a hexadecimal string representation.
+    /// Return true for the all-zero hash.
+    pub fn is_null(&self) -> bool {
+        self.as_bytes().iter().all(|&b| b == 0)
+    }
+
+    /// Convert the hash to a lowercase hexadecimal string representation.
     pub fn to_hex(&self) -> String {
-        self.0.iter().fold(String::new(), |mut output, b| {
-            let _ = write!(output, "{b:02X}");
+        self.as_bytes().iter().fold(String::new(), |mut output, b| {
+            let _ = write!(output, "{b:02x}");
             output
         })
     }
@@ -110,29 +224,10 @@ impl ObjectHash {
     }
 }
 
-impl TryFrom<&[u8]> for ObjectHash {
-    type Error = anyhow::Error;
-
-    /// Create a ObjectHash. Uses SHA-1 to hash the input data.
-    fn try_from(slice: &[u8]) -> Result<Self> {
-        let mut hasher = Sha1::new();
-        hasher.update(slice);
-        let result = hasher.finalize();
-        if result.len() != 20 {
-            bail!("SHA-1 digest should be 20 bytes, got {}", result.len());
-        }
-        let mut bytes = GenericArray::<u8, U20>::default();
-        bytes.copy_from_slice(&result);
-        Ok(ObjectHash(bytes))
-    }
-}
-
-impl TryFrom<&str> for ObjectHash {
-    type Error = anyhow::Error;
-
-    /// Create a ObjectHash from a string. Uses SHA-1 to hash the input data.
-    fn try_from(s: &str) -> Result<Self> {
-        ObjectHash::try_from(s.as_bytes())
+impl Default for ObjectHash {
+    /// The all-zero SHA-1 hash
+    fn default() -> Self {
+        HashAlgorithm::Sha1.null_hash()
     }
 }
 
@@ -148,11 +243,22 @@ impl Display for ObjectHash {
 /// is the first two characters of the hash (as a string) and the file is the rest.
 /// The object file is stored compressed (zlib); after decompression, its header
 /// is expected to have the form "type size\0". This function parses the header,
-/// validates the size, and returns an `Object`.
+/// validates the size, and returns an `Object`. Objects without a loose file
+/// are looked up in the repository's packs, then fetched from the promisor
+/// remote of a partial clone.
 pub fn read_object(repo: &Repository, hash: &ObjectHash) -> Result<Object> {
     let (dir, file) = hash.as_path_parts();
-    let object_path: PathBuf = repo.gitdir().join("objects").join(dir).join(file);
+    let object_path: PathBuf = repo.commondir().join("objects").join(dir).join(file);
     if !object_path.exists() {
+        if let Some(object) = crate::pack::read_packed_object(repo, hash)? {
+            return Ok(object);
+        }
+        // A partial clone asks its promisor remote for what it lacks
+        if crate::promisor::fetch_missing(repo, hash)? {
+            if let Some(object) = crate::pack::read_packed_object(repo, hash)? {
+                return Ok(object);
+            }
+        }
         bail!("Object not found at {}", object_path.display());
     }
 
@@ -164,10 +270,14 @@ pub fn read_object(repo: &Repository, hash: &ObjectHash) -> Result<Object> {
         .read_to_end(&mut buffer)
         .context("Failed to decompress object data")?;
 
+    // Only the first NUL ends the header: tree data contains NUL bytes too
     let (header, data) = buffer
-        .split(|&b| b == 0)
-        .collect_tuple()
-        .map(|(header, data)| (String::from_utf8_lossy(header).into_owned(), data.to_vec()))
+        .iter()
+        .position(|&b| b == 0)
+        .map(|nul| {
+            let header = String::from_utf8_lossy(&buffer[..nul]).into_owned();
+            (header, buffer[nul + 1..].to_vec())
+        })
         .ok_or_else(|| anyhow::anyhow!("Invalid object header: missing null terminator"))?;
 
     let (object_type, size) = header
@@ -187,7 +297,7 @@ pub fn read_object(repo: &Repository, hash: &ObjectHash) -> Result<Object> {
         );
     }
 
-    Object::new(object_type, data)
+    Object::new(hash.algorithm(), object_type, data)
 }
 
 /// Writes a Git object to the repository.
@@ -223,6 +333,56 @@ pub fn write_object(obj: &Object, repo: &Repository) -> Result<ObjectHash> {
     Ok(obj.hash.clone())
 }
 
+/// Returns true if an object with the given hash is stored in the repository,
+/// either loose or in a pack.
+pub fn object_exists(repo: &Repository, hash: &ObjectHash) -> bool {
+    let (dir, file) = hash.as_path_parts();
+    repo.commondir()
+        .join("objects")
+        .join(dir)
+        .join(file)
+        .exists()
+        || crate::pack::is_packed(repo, hash)
+}
+
+/// Writes a Git object unless it is already stored in the repository.
+///
+/// Unlike `write_object`, storing an object that already exists is not an
+/// error: objects are content addressed, so the existing file is identical.
+pub fn store_object(obj: &Object, repo: &Repository) -> Result<ObjectHash> {
+    if object_exists(repo, &obj.hash) {
+        return Ok(obj.hash.clone());
+    }
+    write_object(obj, repo)
+}
+
+/// List the loose objects with their files
+pub fn loose_objects(repo: &Repository) -> Result<Vec<(ObjectHash, PathBuf)>> {
+    let mut objects = Vec::new();
+    for entry in std::fs::read_dir(repo.commondir().join("objects"))? {
+        let dir = entry?.path();
+        let Some(prefix) = dir.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if prefix.len() != 2 || !dir.is_dir() {
+            continue;
+        }
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let Some(rest) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if let Ok(hash) =
+                ObjectHash::from_hex(&format!("{}{}", prefix, rest), repo.hash_algorithm())
+            {
+                objects.push((hash, path));
+            }
+        }
+    }
+    objects.sort();
+    Ok(objects)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -230,16 +390,25 @@ mod tests {
     use tempfile::TempDir;
 
     #[test]
-    fn test_git_hash_from_str() {
-        let hash_str = "1234567890abcdef1234";
-        let hash = ObjectHash::try_from(hash_str).unwrap();
-        assert_eq!(hash.0.len(), 20);
+    fn test_hash_length_follows_algorithm() {
+        let sha1 = "1234567890abcdef1234".repeat(2);
+        let sha256 = "1234567890abcdef".repeat(4);
+        let hash = ObjectHash::from_hex(&sha1, HashAlgorithm::Sha1).unwrap();
+        assert_eq!(hash.as_bytes().len(), 20);
+        let hash = ObjectHash::from_hex(&sha256, HashAlgorithm::Sha256).unwrap();
+        assert_eq!(hash.as_bytes().len(), 32);
+        assert!(ObjectHash::from_hex(&sha256, HashAlgorithm::Sha1).is_err());
+        assert!(ObjectHash::from_hex(&sha1, HashAlgorithm::Sha256).is_err());
+
+        assert!(ObjectHash::from_bytes(&[7; 20], HashAlgorithm::Sha1).is_ok());
+        assert!(ObjectHash::from_bytes(&[7; 32], HashAlgorithm::Sha256).is_ok());
+        assert!(ObjectHash::from_bytes(&[7; 32], HashAlgorithm::Sha1).is_err());
+        assert!(ObjectHash::from_bytes(&[7; 20], HashAlgorithm::Sha256).is_err());
     }
 
     #[test]
     fn test_git_hash_as_path_parts() {
-        let hash_str = "1234567890abcdef1234";
-        let hash = ObjectHash::try_from(hash_str).unwrap();
+        let hash = HashAlgorithm::Sha1.digest(b"1234567890abcdef1234");
         let (dir, file) = hash.as_path_parts();
         assert_eq!(dir.len(), 2);
         assert_eq!(file.len(), 38);
@@ -248,7 +417,7 @@ mod tests {
     #[test]
     fn test_read_object() {
         let tempdir = TempDir::new().unwrap();
-        let object = Object::new(ObjectType::Blob, b"test".to_vec()).unwrap();
+        let object = Object::new(HashAlgorithm::Sha1, ObjectType::Blob, b"test".to_vec()).unwrap();
         let repo = Repository::new(tempdir.path()).unwrap();
         write_object(&object, &repo).unwrap();
         let result = read_object(&repo, &object.hash);
@@ -258,8 +427,14 @@ mod tests {
     #[test]
     fn test_read_object_object_doesnt_exist() {
         let tempdir = TempDir::new().unwrap();
-        let object_written = Object::new(ObjectType::Blob, b"test".to_vec()).unwrap();
-        let object_not_written = Object::new(ObjectType::Blob, b"other data".to_vec()).unwrap();
+        let object_written =
+            Object::new(HashAlgorithm::Sha1, ObjectType::Blob, b"test".to_vec()).unwrap();
+        let object_not_written = Object::new(
+            HashAlgorithm::Sha1,
+            ObjectType::Blob,
+            b"other data".to_vec(),
+        )
+        .unwrap();
         let repo = Repository::new(tempdir.path()).unwrap();
         write_object(&object_written, &repo).unwrap();
         let result = read_object(&repo, &object_not_written.hash);
@@ -272,7 +447,7 @@ mod tests {
     #[test]
     fn test_read_object_not_encoded() {
         let tempdir = TempDir::new().unwrap();
-        let object = Object::new(ObjectType::Blob, b"test".to_vec()).unwrap();
+        let object = Object::new(HashAlgorithm::Sha1, ObjectType::Blob, b"test".to_vec()).unwrap();
         let repo = Repository::new(tempdir.path()).unwrap();
         let object_path = object.file_path(&repo);
         std::fs::create_dir_all(object_path.parent().unwrap()).unwrap();
@@ -326,7 +501,7 @@ mod tests {
         ];
 
         let tempdir = TempDir::new().unwrap();
-        let object = Object::new(ObjectType::Blob, b"test".to_vec()).unwrap();
+        let object = Object::new(HashAlgorithm::Sha1, ObjectType::Blob, b"test".to_vec()).unwrap();
         let repo = Repository::new(tempdir.path()).unwrap();
         let object_path = object.file_path(&repo);
         std::fs::create_dir_all(object_path.parent().unwrap()).unwrap();
@@ -350,7 +525,7 @@ mod tests {
     #[test]
     fn test_write_object() {
         let tempdir = TempDir::new().unwrap();
-        let object = Object::new(ObjectType::Blob, b"test".to_vec()).unwrap();
+        let object = Object::new(HashAlgorithm::Sha1, ObjectType::Blob, b"test".to_vec()).unwrap();
         let repo = Repository::new(tempdir.path()).unwrap();
         let result = write_object(&object, &repo);
         let object_path = object.file_path(&repo);
@@ -358,10 +533,80 @@ mod tests {
         assert!(object_path.exists());
     }
 
+    #[test]
+    fn test_git_hash_hex_roundtrip() {
+        let hash = HashAlgorithm::Sha1.digest(b"hello");
+        let hex = hash.to_hex();
+        assert_eq!(hex, hex.to_lowercase());
+        assert_eq!(
+            ObjectHash::from_hex(&hex, HashAlgorithm::Sha1).unwrap(),
+            hash
+        );
+        assert!(ObjectHash::from_hex(&"z".repeat(40), HashAlgorithm::Sha1).is_err());
+    }
+
+    #[test]
+    fn test_object_hash_matches_git() {
+        // `git hash-object` of a file containing "test" without a newline.
+        let object = Object::new(HashAlgorithm::Sha1, ObjectType::Blob, b"test".to_vec()).unwrap();
+        assert_eq!(
+            object.hash.to_hex(),
+            "30d74d258442c7c65512eafab474568dd706c430"
+        );
+        // The same in a repository using `--object-format=sha256`
+        let object =
+            Object::new(HashAlgorithm::Sha256, ObjectType::Blob, b"test".to_vec()).unwrap();
+        assert_eq!(
+            object.hash.to_hex(),
+            "aa19560d465e7d43915547490a1f6b73eb55702e3d12cb82fb577df60bad4928"
+        );
+        assert_eq!(object.hash.algorithm(), HashAlgorithm::Sha256);
+        assert_eq!(
+            ObjectHash::from_hex(&object.hash.to_hex(), HashAlgorithm::Sha256).unwrap(),
+            object.hash
+        );
+    }
+
+    #[test]
+    fn test_hash_object_detects_collision() {
+        // The first 320 bytes of shattered-1.pdf, which share their SHA-1
+        // with those of shattered-2.pdf.
+        let shattered = hex::decode(concat!(
+            "255044462d312e330a25e2e3cfd30a0a0a312030206f626a0a3c3c2f57696474",
+            "682032203020522f4865696768742033203020522f547970652034203020522f",
+            "537562747970652035203020522f46696c7465722036203020522f436f6c6f72",
+            "53706163652037203020522f4c656e6774682038203020522f42697473506572",
+            "436f6d706f6e656e7420383e3e0a73747265616d0affd8fffe00245348412d31",
+            "20697320646561642121212121852fec092339759c39b1a1c63c4c97e1fffe01",
+            "7346dc9166b67e118f029ab621b2560ff9ca67cca8c7f85ba84c79030c2b3de2",
+            "18f86db3a90901d5df45c14f26fedfb3dc38e96ac22fe7bd728f0e45bce046d2",
+            "3c570feb141398bb552ef5a0a82be331fea48037b8b5d71f0e332edf93ac3500",
+            "eb4ddc0decc1a864790c782c76215660dd309791d06bd0af3f98cda4bc4629b1",
+        ))
+        .unwrap();
+        assert_eq!(
+            HashAlgorithm::Sha1.digest(&shattered).to_hex(),
+            "f92d74e3874587aaf443d1db961d4e26dde13e9c"
+        );
+        let err = HashAlgorithm::Sha1.hash_object(&shattered).unwrap_err();
+        assert!(err.to_string().contains("collision attack"));
+        assert!(HashAlgorithm::Sha256.hash_object(&shattered).is_ok());
+    }
+
+    #[test]
+    fn test_store_object_is_idempotent() {
+        let tempdir = TempDir::new().unwrap();
+        let object = Object::new(HashAlgorithm::Sha1, ObjectType::Blob, b"test".to_vec()).unwrap();
+        let repo = Repository::new(tempdir.path()).unwrap();
+        store_object(&object, &repo).unwrap();
+        assert!(object_exists(&repo, &object.hash));
+        assert!(store_object(&object, &repo).is_ok());
+    }
+
     #[test]
     fn test_write_object_object_already_exist() {
         let tempdir = TempDir::new().unwrap();
-        let object = Object::new(ObjectType::Blob, b"test".to_vec()).unwrap();
+        let object = Object::new(HashAlgorithm::Sha1, ObjectType::Blob, b"test".to_vec()).unwrap();
         let repo = Repository::new(tempdir.path()).unwrap();
         write_object(&object, &repo).unwrap();
         let result = write_object(&object, &repo);
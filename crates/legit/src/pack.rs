@@ -0,0 +1,1000 @@
+use crate::midx::MultiPackIndex;
+use crate::objects::{HashAlgorithm, Object, ObjectHash, ObjectType};
+use crate::pack_writer::{deflate, entry_header, type_number};
+use crate::refs::write_atomic;
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use flate2::read::ZlibDecoder;
+use flate2::{Crc, Decompress, FlushDecompress, Status};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Magic number at the start of a version 2 pack index
+const IDX_MAGIC: [u8; 4] = [0xff, b't', b'O', b'c'];
+
+/// Size of the 256 entry fan-out table of a pack index
+const FANOUT_SIZE: usize = 256 * 4;
+
+/// Deltas nested deeper than this are considered corrupt
+const MAX_DELTA_DEPTH: usize = 10_000;
+
+/// Sizes read from a pack are untrusted, so buffers start no larger than this
+const MAX_PREALLOCATION: usize = 1 << 20;
+
+/// PackIndex is a parsed `.idx` file: the sorted hashes of a pack's objects
+/// with their offsets in the `.pack` file
+#[derive(Debug)]
+pub struct PackIndex {
+    fanout: [u32; 256],
+    hashes: Vec<u8>,
+    offsets: Vec<u64>,
+    pack_checksum: ObjectHash,
+    algorithm: HashAlgorithm,
+}
+
+impl PackIndex {
+    /// Parse a version 2 pack index of a repository using `algorithm`
+    pub fn parse(data: &[u8], algorithm: HashAlgorithm) -> Result<PackIndex> {
+        let hash_size = algorithm.size();
+        if data.len() < 8 + FANOUT_SIZE || data[..4] != IDX_MAGIC {
+            bail!("Unsupported pack index: only version 2 is supported");
+        }
+        let version = u32::from_be_bytes(data[4..8].try_into()?);
+        if version != 2 {
+            bail!("Unsupported pack index version {}", version);
+        }
+        let mut fanout = [0u32; 256];
+        for (i, entry) in fanout.iter_mut().enumerate() {
+            let start = 8 + i * 4;
+            *entry = u32::from_be_bytes(data[start..start + 4].try_into()?);
+        }
+        let count = fanout[255] as usize;
+        let names = 8 + FANOUT_SIZE;
+        let offsets_start = names + count * hash_size + count * 4;
+        let large_start = offsets_start + count * 4;
+        if data.len() < large_start + 2 * hash_size {
+            bail!("Pack index is truncated");
+        }
+
+        let mut offsets = Vec::with_capacity(count);
+        for i in 0..count {
+            let start = offsets_start + i * 4;
+            let offset = u32::from_be_bytes(data[start..start + 4].try_into()?);
+            // The high bit marks an index into the table of 64-bit offsets
+            let offset = match offset & 0x8000_0000 {
+                0 => offset as u64,
+                _ => {
+                    let start = large_start + (offset & 0x7fff_ffff) as usize * 8;
+                    let bytes = data
+                        .get(start..start + 8)
+                        .context("Pack index has an invalid large offset")?;
+                    u64::from_be_bytes(bytes.try_into()?)
+                }
+            };
+            offsets.push(offset);
+        }
+        let trailer = data.len() - 2 * hash_size;
+        Ok(PackIndex {
+            fanout,
+            hashes: data[names..names + count * hash_size].to_vec(),
+            offsets,
+            pack_checksum: ObjectHash::from_bytes(&data[trailer..trailer + hash_size], algorithm)?,
+            algorithm,
+        })
+    }
+
+    /// Number of objects in the pack
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Return true if the pack holds no objects
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Return the hash of the object at a position in the index
+    pub fn hash(&self, position: usize) -> ObjectHash {
+        let size = self.algorithm.size();
+        let start = position * size;
+        ObjectHash::from_bytes(&self.hashes[start..start + size], self.algorithm)
+            .expect("index hashes have the right size")
+    }
+
+    /// Iterate over the hashes of the pack's objects in sorted order
+    pub fn hashes(&self) -> impl Iterator<Item = ObjectHash> + '_ {
+        (0..self.len()).map(|i| self.hash(i))
+    }
+
+    /// Return the offset of the object at a position in the index
+    pub fn offset(&self, position: usize) -> u64 {
+        self.offsets[position]
+    }
+
+    /// Return the checksum of the pack the index describes
+    pub fn pack_checksum(&self) -> &ObjectHash {
+        &self.pack_checksum
+    }
+
+    /// Find the offset of an object in the pack
+    pub fn find(&self, hash: &ObjectHash) -> Option<u64> {
+        self.position(hash).map(|position| self.offsets[position])
+    }
+
+    /// Find the position of an object in the index
+    pub fn position(&self, hash: &ObjectHash) -> Option<usize> {
+        let first = hash.as_bytes()[0] as usize;
+        let start = match first {
+            0 => 0,
+            _ => self.fanout[first - 1] as usize,
+        };
+        let end = self.fanout[first] as usize;
+        let size = self.algorithm.size();
+        let (mut low, mut high) = (start, end);
+        while low < high {
+            let middle = (low + high) / 2;
+            let name = &self.hashes[middle * size..(middle + 1) * size];
+            match name.cmp(hash.as_bytes()) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Some(middle),
+            }
+        }
+        None
+    }
+}
+
+/// Pack is a `.pack` file with its index
+#[derive(Debug, Clone)]
+pub struct Pack {
+    path: PathBuf,
+    index: Arc<PackIndex>,
+}
+
+/// Parsed indexes by path; pack names are derived from their content, so a
+/// path always refers to the same pack
+fn index_cache() -> &'static Mutex<HashMap<PathBuf, Arc<PackIndex>>> {
+    static CACHE: OnceLock<Mutex<HashMap<PathBuf, Arc<PackIndex>>>> = OnceLock::new();
+    CACHE.get_or_init(Default::default)
+}
+
+impl Pack {
+    /// Open the pack whose index is at `idx_path`, in a repository using
+    /// `algorithm`
+    pub fn open(idx_path: &Path, algorithm: HashAlgorithm) -> Result<Pack> {
+        let cached = index_cache().lock().unwrap().get(idx_path).cloned();
+        let index = match cached {
+            Some(index) => index,
+            None => {
+                let data = fs::read(idx_path)
+                    .with_context(|| format!("Failed to read {}", idx_path.display()))?;
+                let index = Arc::new(
+                    PackIndex::parse(&data, algorithm)
+                        .with_context(|| format!("Invalid pack index {}", idx_path.display()))?,
+                );
+                index_cache()
+                    .lock()
+                    .unwrap()
+                    .insert(idx_path.to_path_buf(), index.clone());
+                index
+            }
+        };
+        Ok(Pack {
+            path: idx_path.with_extension("pack"),
+            index,
+        })
+    }
+
+    /// Return the path of the `.pack` file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return the index of the pack
+    pub fn index(&self) -> &PackIndex {
+        &self.index
+    }
+
+    /// Return true if the pack holds an object
+    pub fn contains(&self, hash: &ObjectHash) -> bool {
+        self.index.find(hash).is_some()
+    }
+
+    /// Read an object from the pack, resolving deltas
+    ///
+    /// Bases of `REF_DELTA` objects that are not in this pack are looked up
+    /// in the rest of the repository.
+    pub fn read_object(&self, repo: &Repository, hash: &ObjectHash) -> Result<Option<Object>> {
+        let Some(offset) = self.index.find(hash) else {
+            return Ok(None);
+        };
+        let mut file = BufReader::new(
+            File::open(&self.path)
+                .with_context(|| format!("Failed to open {}", self.path.display()))?,
+        );
+        let (object_type, data) = self
+            .read_at(repo, &mut file, offset, 0)
+            .with_context(|| format!("Failed to read {} from {}", hash, self.path.display()))?;
+        Ok(Some(Object::new(hash.algorithm(), object_type, data)?))
+    }
+
+    /// Read the object at an offset of the pack, as found in a
+    /// multi-pack-index
+    pub fn read_object_at(&self, repo: &Repository, offset: u64) -> Result<Object> {
+        let mut file = BufReader::new(
+            File::open(&self.path)
+                .with_context(|| format!("Failed to open {}", self.path.display()))?,
+        );
+        let (object_type, data) = self.read_at(repo, &mut file, offset, 0).with_context(|| {
+            format!(
+                "Failed to read offset {} of {}",
+                offset,
+                self.path.display()
+            )
+        })?;
+        Object::new(repo.hash_algorithm(), object_type, data)
+    }
+
+    fn read_at(
+        &self,
+        repo: &Repository,
+        file: &mut BufReader<File>,
+        offset: u64,
+        depth: usize,
+    ) -> Result<(ObjectType, Vec<u8>)> {
+        if depth > MAX_DELTA_DEPTH {
+            bail!("Delta chain is too deep");
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        let (kind, size) = read_entry_header(file)?;
+        match kind {
+            6 => {
+                let distance = read_offset_delta(file)?;
+                let delta = inflate(file, size)?;
+                let base_offset = offset
+                    .checked_sub(distance)
+                    .context("Delta base offset is out of range")?;
+                let (object_type, base) = self.read_at(repo, file, base_offset, depth + 1)?;
+                Ok((object_type, apply_delta(&base, &delta)?))
+            }
+            7 => {
+                let mut base_hash = vec![0u8; self.index.algorithm.size()];
+                file.read_exact(&mut base_hash)?;
+                let delta = inflate(file, size)?;
+                let base_hash = ObjectHash::from_bytes(&base_hash, self.index.algorithm)?;
+                let (object_type, base) = match self.index.find(&base_hash) {
+                    Some(base_offset) => self.read_at(repo, file, base_offset, depth + 1)?,
+                    None => {
+                        let base = crate::objects::read_object(repo, &base_hash)?;
+                        (base.object_type, base.data)
+                    }
+                };
+                Ok((object_type, apply_delta(&base, &delta)?))
+            }
+            kind => Ok((object_type(kind)?, inflate(file, size)?)),
+        }
+    }
+}
+
+/// List the packs of a repository
+pub fn packs(repo: &Repository) -> Result<Vec<Pack>> {
+    let dir = repo.commondir().join("objects").join("pack");
+    let Ok(entries) = fs::read_dir(&dir) else {
+        return Ok(Vec::new());
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "idx") && path.with_extension("pack").exists() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    paths
+        .iter()
+        .map(|path| Pack::open(path, repo.hash_algorithm()))
+        .collect()
+}
+
+/// Find and read an object stored in any pack of the repository
+pub fn read_packed_object(repo: &Repository, hash: &ObjectHash) -> Result<Option<Object>> {
+    if let Some(midx) = MultiPackIndex::open(repo)? {
+        if let Some((pack, offset)) = midx.find(hash) {
+            match Pack::open(&midx.pack_path(pack), repo.hash_algorithm()) {
+                Ok(pack) if pack.path().exists() => {
+                    return pack.read_object_at(repo, offset).map(Some)
+                }
+                _ => {}
+            }
+        }
+    }
+    for pack in packs(repo)? {
+        if let Some(object) = pack.read_object(repo, hash)? {
+            return Ok(Some(object));
+        }
+    }
+    Ok(None)
+}
+
+/// Return true if any pack of the repository holds an object
+pub fn is_packed(repo: &Repository, hash: &ObjectHash) -> bool {
+    if let Ok(Some(midx)) = MultiPackIndex::open(repo) {
+        if midx.find(hash).is_some() {
+            return true;
+        }
+    }
+    packs(repo).is_ok_and(|packs| packs.iter().any(|pack| pack.contains(hash)))
+}
+
+/// Map the type number of a pack entry to an object type
+pub(crate) fn object_type(kind: u8) -> Result<ObjectType> {
+    Ok(match kind {
+        1 => ObjectType::Commit,
+        2 => ObjectType::Tree,
+        3 => ObjectType::Blob,
+        4 => ObjectType::Tag,
+        _ => bail!("Invalid pack object type {}", kind),
+    })
+}
+
+/// Read the type and inflated size of a pack entry
+pub(crate) fn read_entry_header(reader: &mut impl Read) -> Result<(u8, usize)> {
+    let mut byte = read_byte(reader)?;
+    let kind = (byte >> 4) & 0x7;
+    let mut size = (byte & 0x0f) as usize;
+    let mut shift = 4;
+    while byte & 0x80 != 0 {
+        byte = read_byte(reader)?;
+        size |= varint_bits(byte, shift).context("Pack entry size overflows")?;
+        shift += 7;
+    }
+    Ok((kind, size))
+}
+
+/// Move the low seven bits of a varint byte into place, or return None if
+/// any of them would be shifted out
+fn varint_bits(byte: u8, shift: u32) -> Option<usize> {
+    let bits = (byte & 0x7f) as usize;
+    bits.checked_shl(shift)
+        .filter(|shifted| shifted >> shift == bits)
+}
+
+/// Read the distance back to the base of an `OFS_DELTA` entry
+pub(crate) fn read_offset_delta(reader: &mut impl Read) -> Result<u64> {
+    let mut byte = read_byte(reader)?;
+    let mut distance = (byte & 0x7f) as u64;
+    while byte & 0x80 != 0 {
+        byte = read_byte(reader)?;
+        distance = distance
+            .checked_add(1)
+            .and_then(|distance| distance.checked_mul(1 << 7))
+            .context("Delta base offset overflows")?
+            | (byte & 0x7f) as u64;
+    }
+    Ok(distance)
+}
+
+fn read_byte(reader: &mut impl Read) -> Result<u8> {
+    let mut byte = [0u8];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+/// Inflate a zlib stream expected to hold `size` bytes
+///
+/// The decoder stops at the end of the stream, so the reader is left
+/// somewhere after it; callers seek before reading the next entry. The
+/// size comes from the pack, so it only bounds the output rather than
+/// being allocated up front.
+fn inflate(reader: &mut impl Read, size: usize) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(size.min(MAX_PREALLOCATION));
+    ZlibDecoder::new(reader)
+        .take((size as u64).saturating_add(1))
+        .read_to_end(&mut data)?;
+    if data.len() != size {
+        bail!(
+            "Pack entry size mismatch: expected {} bytes, got {}",
+            size,
+            data.len()
+        );
+    }
+    Ok(data)
+}
+
+/// Read a little-endian base-128 size from the start of a delta
+fn delta_size(delta: &[u8], position: &mut usize) -> Result<usize> {
+    let mut size = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *delta.get(*position).context("Delta is truncated")?;
+        *position += 1;
+        size |= varint_bits(byte, shift).context("Delta size overflows")?;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+/// Rebuild an object from its base and a delta of copy and insert instructions
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut position = 0;
+    if delta_size(delta, &mut position)? != base.len() {
+        bail!("Delta base size mismatch");
+    }
+    let size = delta_size(delta, &mut position)?;
+    let mut result = Vec::with_capacity(size.min(MAX_PREALLOCATION));
+    while position < delta.len() {
+        let instruction = delta[position];
+        position += 1;
+        if instruction & 0x80 != 0 {
+            // Copy: the low seven bits select which offset and size bytes follow
+            let mut next = || -> Result<usize> {
+                let byte = *delta.get(position).context("Delta is truncated")?;
+                position += 1;
+                Ok(byte as usize)
+            };
+            let (mut offset, mut length) = (0, 0);
+            for i in 0..4 {
+                if instruction & (1 << i) != 0 {
+                    offset |= next()? << (8 * i);
+                }
+            }
+            for i in 0..3 {
+                if instruction & (0x10 << i) != 0 {
+                    length |= next()? << (8 * i);
+                }
+            }
+            if length == 0 {
+                length = 0x10000;
+            }
+            let chunk = base
+                .get(offset..offset + length)
+                .context("Delta copies outside of its base")?;
+            result.extend_from_slice(chunk);
+        } else if instruction != 0 {
+            let length = instruction as usize;
+            let chunk = delta
+                .get(position..position + length)
+                .context("Delta is truncated")?;
+            result.extend_from_slice(chunk);
+            position += length;
+        } else {
+            bail!("Invalid delta instruction");
+        }
+    }
+    if result.len() != size {
+        bail!("Delta result size mismatch");
+    }
+    Ok(result)
+}
+
+/// Where an entry of a received pack gets its base from
+enum DeltaBase {
+    None,
+    Offset(u64),
+    Hash(ObjectHash),
+}
+
+/// An entry of a received pack before its deltas are resolved
+struct RawEntry {
+    offset: u64,
+    kind: u8,
+    base: DeltaBase,
+    data: Vec<u8>,
+    crc: u32,
+}
+
+/// Store a pack received from a remote and write its index
+///
+/// Every delta must have its base in the pack or the repository. A thin
+/// pack, whose deltas refer to objects of the repository, is completed with
+/// those objects so that it stands on its own, like
+/// `git index-pack --fix-thin`. Returns `None` for a pack holding no
+/// objects, which is not stored.
+pub fn store_pack(repo: &Repository, data: &[u8]) -> Result<Option<Pack>> {
+    let algorithm = repo.hash_algorithm();
+    let hash_size = algorithm.size();
+    if data.len() < 12 + hash_size || &data[..4] != b"PACK" {
+        bail!("Invalid pack: missing header");
+    }
+    let version = u32::from_be_bytes(data[4..8].try_into()?);
+    if version != 2 && version != 3 {
+        bail!("Unsupported pack version {}", version);
+    }
+    let (content, checksum) = data.split_at(data.len() - hash_size);
+    if algorithm.digest(content).as_bytes() != checksum {
+        bail!("Invalid pack: checksum mismatch");
+    }
+    let count = u32::from_be_bytes(data[8..12].try_into()?) as usize;
+    if count == 0 {
+        return Ok(None);
+    }
+
+    let entries = parse_entries(content, count, algorithm)?;
+    let (hashes, external_bases) = resolve_entries(repo, &entries)?;
+    let mut objects = hashes
+        .into_iter()
+        .zip(&entries)
+        .map(|(hash, entry)| (hash, entry.crc, entry.offset))
+        .collect::<Vec<_>>();
+    let data = match external_bases.is_empty() {
+        true => data.to_vec(),
+        false => complete_thin_pack(repo, content, &external_bases, &mut objects)?,
+    };
+    let checksum = &data[data.len() - hash_size..];
+    objects.sort();
+    let index = write_index(&objects, checksum, algorithm);
+
+    let dir = repo.commondir().join("objects").join("pack");
+    fs::create_dir_all(&dir)?;
+    let name = format!("pack-{}", hex::encode(checksum));
+    write_atomic(&dir.join(format!("{}.pack", name)), &data)?;
+    let idx_path = dir.join(format!("{}.idx", name));
+    write_atomic(&idx_path, &index)?;
+    Pack::open(&idx_path, algorithm).map(Some)
+}
+
+/// Append the objects a thin pack's deltas are based on to its content,
+/// adding them to the pack's objects, and return the completed pack
+fn complete_thin_pack(
+    repo: &Repository,
+    content: &[u8],
+    bases: &[ObjectHash],
+    objects: &mut Vec<(ObjectHash, u32, u64)>,
+) -> Result<Vec<u8>> {
+    let mut pack = content.to_vec();
+    for hash in bases {
+        let object = crate::objects::read_object(repo, hash)?;
+        let offset = pack.len();
+        pack.extend(entry_header(
+            type_number(&object.object_type),
+            object.data.len(),
+        ));
+        pack.extend(deflate(&object.data)?);
+        let mut crc = Crc::new();
+        crc.update(&pack[offset..]);
+        objects.push((hash.clone(), crc.sum(), offset as u64));
+    }
+    let count = u32::from_be_bytes(pack[8..12].try_into()?) + bases.len() as u32;
+    pack[8..12].copy_from_slice(&count.to_be_bytes());
+    let checksum = repo.hash_algorithm().digest(&pack);
+    pack.extend(checksum.as_bytes());
+    Ok(pack)
+}
+
+/// Read a pack from a stream, stopping right after its checksum so that
+/// whatever follows it stays in the stream
+///
+/// Bases of `REF_DELTA` entries and the checksum are hashes of `algorithm`.
+pub fn read_pack(reader: &mut impl BufRead, algorithm: HashAlgorithm) -> Result<Vec<u8>> {
+    let mut pack = vec![0u8; 12];
+    reader
+        .read_exact(&mut pack)
+        .context("The pack is truncated")?;
+    if &pack[..4] != b"PACK" {
+        bail!("Invalid pack: missing header");
+    }
+    let count = u32::from_be_bytes(pack[8..12].try_into()?);
+    let mut byte = [0u8; 1];
+    let mut output = Vec::new();
+    for _ in 0..count {
+        let start = pack.len();
+        loop {
+            reader
+                .read_exact(&mut byte)
+                .context("The pack is truncated")?;
+            pack.push(byte[0]);
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        match pack[start] >> 4 & 0x07 {
+            6 => loop {
+                reader
+                    .read_exact(&mut byte)
+                    .context("The pack is truncated")?;
+                pack.push(byte[0]);
+                if byte[0] & 0x80 == 0 {
+                    break;
+                }
+            },
+            7 => {
+                let mut base = vec![0u8; algorithm.size()];
+                reader
+                    .read_exact(&mut base)
+                    .context("The pack is truncated")?;
+                pack.extend(base);
+            }
+            _ => {}
+        }
+        // Only the compressed bytes the entry uses are taken from the stream
+        let mut decompress = Decompress::new(true);
+        loop {
+            let input = reader.fill_buf()?;
+            if input.is_empty() {
+                bail!("The pack is truncated");
+            }
+            let before = decompress.total_in();
+            output.clear();
+            output.reserve(64 * 1024);
+            let status = decompress.decompress_vec(input, &mut output, FlushDecompress::None)?;
+            let used = (decompress.total_in() - before) as usize;
+            pack.extend_from_slice(&input[..used]);
+            reader.consume(used);
+            if status == Status::StreamEnd {
+                break;
+            }
+        }
+    }
+    let mut checksum = vec![0u8; algorithm.size()];
+    reader
+        .read_exact(&mut checksum)
+        .context("The pack is truncated")?;
+    pack.extend(checksum);
+    Ok(pack)
+}
+
+/// Split the content of a pack into its entries, inflating them
+fn parse_entries(content: &[u8], count: usize, algorithm: HashAlgorithm) -> Result<Vec<RawEntry>> {
+    let mut entries = Vec::with_capacity(count);
+    let mut position = 12;
+    for _ in 0..count {
+        let offset = position;
+        let mut reader = content.get(position..).context("Pack is truncated")?;
+        let (kind, size) = read_entry_header(&mut reader)?;
+        let base = match kind {
+            6 => {
+                let distance = read_offset_delta(&mut reader)?;
+                let base = (offset as u64)
+                    .checked_sub(distance)
+                    .context("Delta base offset is out of range")?;
+                DeltaBase::Offset(base)
+            }
+            7 => {
+                let hash = reader
+                    .get(..algorithm.size())
+                    .context("Pack is truncated")?;
+                reader = &reader[algorithm.size()..];
+                DeltaBase::Hash(ObjectHash::from_bytes(hash, algorithm)?)
+            }
+            kind => {
+                object_type(kind)?;
+                DeltaBase::None
+            }
+        };
+        position = content.len() - reader.len();
+
+        let mut decompress = Decompress::new(true);
+        // Room for one extra byte, so an entry longer than announced is caught
+        let mut data = Vec::with_capacity(size.min(MAX_PREALLOCATION) + 1);
+        loop {
+            let input = &content[position + decompress.total_in() as usize..];
+            match decompress.decompress_vec(input, &mut data, FlushDecompress::Finish)? {
+                Status::StreamEnd => break,
+                _ if data.len() == data.capacity() && data.len() <= size => {
+                    data.reserve((size - data.len()).min(MAX_PREALLOCATION) + 1)
+                }
+                _ => bail!("Pack entry at offset {} is corrupt", offset),
+            }
+        }
+        if data.len() != size {
+            bail!("Pack entry at offset {} is corrupt", offset);
+        }
+        position += decompress.total_in() as usize;
+
+        let mut crc = Crc::new();
+        crc.update(&content[offset..position]);
+        entries.push(RawEntry {
+            offset: offset as u64,
+            kind,
+            base,
+            data,
+            crc: crc.sum(),
+        });
+    }
+    if position != content.len() {
+        bail!("Pack has trailing garbage");
+    }
+    Ok(entries)
+}
+
+/// Resolve the deltas of a received pack and return the hash of each entry,
+/// with the bases that were read from the repository
+///
+/// Bases missing from the pack are read from the repository, so that a
+/// thin pack can be indexed once the objects it builds on are present.
+fn resolve_entries(
+    repo: &Repository,
+    entries: &[RawEntry],
+) -> Result<(Vec<ObjectHash>, Vec<ObjectHash>)> {
+    let by_offset = entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| (entry.offset, i))
+        .collect::<HashMap<_, _>>();
+    let mut resolved: Vec<Option<(ObjectType, Vec<u8>)>> = vec![None; entries.len()];
+    let mut hashes: Vec<Option<ObjectHash>> = vec![None; entries.len()];
+    let mut by_hash: HashMap<ObjectHash, usize> = HashMap::new();
+    let mut use_repo = false;
+    let mut external_bases = Vec::new();
+    loop {
+        let mut progress = false;
+        let mut pending = false;
+        for (i, entry) in entries.iter().enumerate() {
+            if resolved[i].is_some() {
+                continue;
+            }
+            let object = match &entry.base {
+                DeltaBase::None => Some((object_type(entry.kind)?, entry.data.clone())),
+                DeltaBase::Offset(offset) => {
+                    let base = by_offset
+                        .get(offset)
+                        .context("Delta base is not an entry of the pack")?;
+                    match &resolved[*base] {
+                        Some((object_type, base)) => {
+                            Some((object_type.clone(), apply_delta(base, &entry.data)?))
+                        }
+                        None => None,
+                    }
+                }
+                DeltaBase::Hash(hash) => match by_hash.get(hash) {
+                    Some(&base) => {
+                        let (object_type, base) = resolved[base]
+                            .as_ref()
+                            .expect("hashed entries are resolved");
+                        Some((object_type.clone(), apply_delta(base, &entry.data)?))
+                    }
+                    None if use_repo => {
+                        let base = crate::objects::read_object(repo, hash)
+                            .with_context(|| format!("Delta base {} is missing", hash))?;
+                        if !external_bases.contains(hash) {
+                            external_bases.push(hash.clone());
+                        }
+                        Some((base.object_type, apply_delta(&base.data, &entry.data)?))
+                    }
+                    None => None,
+                },
+            };
+            match object {
+                Some((object_type, data)) => {
+                    let hash =
+                        Object::new(repo.hash_algorithm(), object_type.clone(), data.clone())?.hash;
+                    by_hash.insert(hash.clone(), i);
+                    hashes[i] = Some(hash);
+                    resolved[i] = Some((object_type, data));
+                    progress = true;
+                }
+                None => pending = true,
+            }
+        }
+        if !pending {
+            break;
+        }
+        if !progress {
+            if use_repo {
+                bail!("Pack has unresolvable deltas");
+            }
+            use_repo = true;
+        }
+    }
+    Ok((hashes.into_iter().flatten().collect(), external_bases))
+}
+
+/// Build a version 2 index from the sorted hashes, CRCs and offsets of a
+/// pack's entries
+fn write_index(
+    objects: &[(ObjectHash, u32, u64)],
+    pack_checksum: &[u8],
+    algorithm: HashAlgorithm,
+) -> Vec<u8> {
+    let mut index = IDX_MAGIC.to_vec();
+    index.extend(2u32.to_be_bytes());
+    let mut count = 0;
+    for byte in 0..256 {
+        while count < objects.len() && objects[count].0.as_bytes()[0] as usize <= byte {
+            count += 1;
+        }
+        index.extend((count as u32).to_be_bytes());
+    }
+    for (hash, _, _) in objects {
+        index.extend(hash.as_bytes());
+    }
+    for (_, crc, _) in objects {
+        index.extend(crc.to_be_bytes());
+    }
+    // Offsets that do not fit in 31 bits go to a table of 64-bit offsets
+    let mut large = Vec::new();
+    for (_, _, offset) in objects {
+        match u32::try_from(*offset) {
+            Ok(offset) if offset & 0x8000_0000 == 0 => index.extend(offset.to_be_bytes()),
+            _ => {
+                index.extend((0x8000_0000 | large.len() as u32).to_be_bytes());
+                large.push(*offset);
+            }
+        }
+    }
+    for offset in large {
+        index.extend(offset.to_be_bytes());
+    }
+    index.extend(pack_checksum);
+    let checksum = algorithm.digest(&index);
+    index.extend(checksum.as_bytes());
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::read_object;
+    use crate::test_utils::init_repo;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn entry(kind: u8, data: &[u8]) -> Vec<u8> {
+        let mut size = data.len();
+        let mut bytes = vec![(kind << 4) | (size & 0x0f) as u8];
+        size >>= 4;
+        while size > 0 {
+            *bytes.last_mut().unwrap() |= 0x80;
+            bytes.push((size & 0x7f) as u8);
+            size >>= 7;
+        }
+        bytes
+    }
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Write a pack holding a blob and a delta against it, with its index
+    fn write_pack(repo: &Repository, base: &[u8], delta: &[u8], result: &[u8]) -> Vec<u8> {
+        let mut pack = b"PACK\0\0\0\x02\0\0\0\x02".to_vec();
+        let base_offset = pack.len() as u64;
+        pack.extend(entry(3, base));
+        pack.extend(deflate(base));
+        let delta_offset = pack.len() as u64;
+        pack.extend(entry(6, delta));
+        pack.push((delta_offset - base_offset) as u8);
+        pack.extend(deflate(delta));
+        let checksum = HashAlgorithm::Sha1.digest(&pack);
+        pack.extend(checksum.as_bytes());
+
+        let mut objects = [
+            (
+                Object::new(HashAlgorithm::Sha1, ObjectType::Blob, base.to_vec())
+                    .unwrap()
+                    .hash,
+                base_offset,
+            ),
+            (
+                Object::new(HashAlgorithm::Sha1, ObjectType::Blob, result.to_vec())
+                    .unwrap()
+                    .hash,
+                delta_offset,
+            ),
+        ];
+        objects.sort();
+        let mut idx = IDX_MAGIC.to_vec();
+        idx.extend(2u32.to_be_bytes());
+        for byte in 0..256 {
+            let count = objects
+                .iter()
+                .filter(|(h, _)| h.as_bytes()[0] as usize <= byte);
+            idx.extend((count.count() as u32).to_be_bytes());
+        }
+        for (hash, _) in &objects {
+            idx.extend(hash.as_bytes());
+        }
+        idx.extend([0u8; 8]);
+        for (_, offset) in &objects {
+            idx.extend((*offset as u32).to_be_bytes());
+        }
+        idx.extend([0u8; 40]);
+
+        let dir = repo.commondir().join("objects/pack");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("pack-test.pack"), &pack).unwrap();
+        fs::write(dir.join("pack-test.idx"), idx).unwrap();
+        pack
+    }
+
+    #[test]
+    fn test_apply_delta() {
+        let base = b"hello world\n";
+        // Copy "hello ", insert "there", copy "\n"
+        let delta = b"\x0c\x0c\x90\x06\x05there\x91\x0b\x01";
+        assert_eq!(apply_delta(base, delta).unwrap(), b"hello there\n");
+        assert!(apply_delta(b"short", delta).is_err());
+        assert!(apply_delta(base, b"\x0c\x01\x91\x20\x01").is_err());
+    }
+
+    #[test]
+    fn test_rejects_bad_varints() {
+        // Truncated: the continuation bit promises another byte
+        assert!(read_entry_header(&mut &b"\x9f"[..]).is_err());
+        assert!(read_offset_delta(&mut &b"\x80"[..]).is_err());
+        assert!(apply_delta(b"", b"\x80").is_err());
+        // Overlong: more bits than a size or offset can hold
+        let overlong = [&b"\x9f"[..], &[0xff; 10], b"\x01"].concat();
+        assert!(read_entry_header(&mut overlong.as_slice()).is_err());
+        assert!(read_offset_delta(&mut &overlong[1..]).is_err());
+        assert!(apply_delta(b"", &overlong[1..]).is_err());
+
+        // A header announcing a huge object is not allocated up front
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"hello").unwrap();
+        let stream = encoder.finish().unwrap();
+        let error = inflate(&mut stream.as_slice(), usize::MAX >> 1).unwrap_err();
+        assert!(error.to_string().contains("size mismatch"));
+        let content = [
+            &b"PACK\0\0\0\x02\0\0\0\x01"[..],
+            b"\xbf\xff\xff\xff\x7f",
+            &stream,
+        ]
+        .concat();
+        assert!(parse_entries(&content, 1, HashAlgorithm::Sha1).is_err());
+    }
+
+    #[test]
+    fn test_read_packed_objects() {
+        let (_dir, repo) = init_repo();
+        let delta = b"\x0c\x0c\x90\x06\x05there\x91\x0b\x01";
+        write_pack(&repo, b"hello world\n", delta, b"hello there\n");
+
+        let pack = packs(&repo).unwrap().remove(0);
+        assert_eq!(pack.index().len(), 2);
+        let hash = Object::new(
+            HashAlgorithm::Sha1,
+            ObjectType::Blob,
+            b"hello there\n".to_vec(),
+        )
+        .unwrap()
+        .hash;
+        assert!(pack.contains(&hash));
+        let object = read_object(&repo, &hash).unwrap();
+        assert_eq!(object.data, b"hello there\n");
+        assert_eq!(object.hash, hash);
+
+        let missing = Object::new(HashAlgorithm::Sha1, ObjectType::Blob, b"other".to_vec())
+            .unwrap()
+            .hash;
+        assert!(!is_packed(&repo, &missing));
+        assert!(read_packed_object(&repo, &missing).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_store_pack() {
+        let (_dir, source) = init_repo();
+        let delta = b"\x0c\x0c\x90\x06\x05there\x91\x0b\x01";
+        let data = write_pack(&source, b"hello world\n", delta, b"hello there\n");
+
+        let (_dir, repo) = init_repo();
+        let pack = store_pack(&repo, &data).unwrap().unwrap();
+        let expected = packs(&source).unwrap().remove(0);
+        assert_eq!(
+            pack.index().hashes().collect::<Vec<_>>(),
+            expected.index().hashes().collect::<Vec<_>>()
+        );
+        let hash = pack.index().hash(0);
+        assert_eq!(
+            read_object(&repo, &hash).unwrap().data,
+            read_object(&source, &hash).unwrap().data
+        );
+
+        let stream = [&data[..], b"0000"].concat();
+        let mut reader = &stream[..];
+        assert_eq!(read_pack(&mut reader, HashAlgorithm::Sha1).unwrap(), data);
+        assert_eq!(reader, b"0000");
+
+        let mut corrupt = data.clone();
+        corrupt[20] ^= 1;
+        assert!(store_pack(&repo, &corrupt).is_err());
+    }
+}
@@ -0,0 +1,521 @@
+use crate::commits::Commit;
+use crate::objects::{object_exists, read_object, ObjectHash, ObjectType};
+use crate::pack::Pack;
+use crate::promisor::{promisor_packs, ObjectFilter};
+use crate::revision::{rev_list_grafted, tag_target};
+use crate::shallow::{graft_parents, Grafts};
+use crate::tree::{EntryMode, Tree};
+use crate::Repository;
+use anyhow::Result;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+/// Length of the blocks of a delta base that matches are looked up by
+const BLOCK_SIZE: usize = 16;
+
+/// Longest copy a single delta instruction holds
+const MAX_COPY: usize = 0xffff;
+
+/// Deltas are only kept when at most this fraction of the object's size
+const MAX_DELTA_RATIO: usize = 2;
+
+/// Deltas are not chained deeper than this
+const MAX_DEPTH: usize = 10;
+
+/// Objects smaller than this are not worth a delta
+const MIN_DELTA_SIZE: usize = 64;
+
+/// ObjectList is the objects to send to a remote, in pack order, with the
+/// blobs the remote has that may serve as delta bases
+#[derive(Debug, Default)]
+pub struct ObjectList {
+    /// Each object with the path it was found at, empty for commits and tags
+    pub objects: Vec<(ObjectHash, String)>,
+    /// The blobs of the remote's trees by path
+    pub bases: HashMap<String, ObjectHash>,
+}
+
+/// ListOptions narrows what `list_objects_with` lists
+#[derive(Debug, Clone, Default)]
+pub struct ListOptions {
+    /// The commits whose parents are not walked
+    pub grafts: Grafts,
+    /// Leave out the trees and blobs the filter excludes, except those
+    /// listed by name
+    pub filter: Option<ObjectFilter>,
+    /// Neither list nor walk into the objects of promisor packs, which stay
+    /// where they are
+    pub exclude_promisor: bool,
+}
+
+/// List the objects reachable from `include` but not from `exclude`, like
+/// `git rev-list --objects include ^exclude`
+///
+/// Only the trees of the excluded commits bordering the included ones are
+/// walked, so an object from older history may be listed again. The
+/// history of a shallow repository ends at its shallow commits.
+pub fn list_objects(
+    repo: &Repository,
+    include: &[ObjectHash],
+    exclude: &[ObjectHash],
+) -> Result<ObjectList> {
+    let options = ListOptions {
+        grafts: Grafts::of(repo)?,
+        ..ListOptions::default()
+    };
+    list_objects_with(repo, include, exclude, &options)
+}
+
+/// List the objects of `list_objects`, narrowed by `options`
+pub fn list_objects_with(
+    repo: &Repository,
+    include: &[ObjectHash],
+    exclude: &[ObjectHash],
+    options: &ListOptions,
+) -> Result<ObjectList> {
+    let promisor = match options.exclude_promisor {
+        true => promisor_packs(repo)?,
+        false => Vec::new(),
+    };
+    let mut walk = TreeWalk {
+        repo,
+        filter: options.filter,
+        promisor: &promisor,
+        seen: HashSet::new(),
+    };
+    let mut list = ObjectList::default();
+    let mut commits = Vec::new();
+    let mut trees = Vec::new();
+    for hash in include {
+        let mut hash = hash.clone();
+        loop {
+            let object = read_object(repo, &hash)?;
+            match object.object_type {
+                ObjectType::Tag => {
+                    if walk.seen.insert(hash.clone()) {
+                        list.objects.push((hash.clone(), String::new()));
+                    }
+                    hash = tag_target(&object.data, repo.hash_algorithm())?;
+                    continue;
+                }
+                ObjectType::Commit => commits.push(hash),
+                ObjectType::Tree => trees.push(hash),
+                ObjectType::Blob => {
+                    if walk.seen.insert(hash.clone()) {
+                        list.objects.push((hash, String::new()));
+                    }
+                }
+            }
+            break;
+        }
+    }
+
+    // The remote has the excluded commits and the parents of the new ones
+    let exclude = exclude
+        .iter()
+        .filter(|hash| object_exists(repo, hash))
+        .filter_map(|hash| crate::revision::peel_to_commit(repo, hash).ok())
+        .collect::<Vec<_>>();
+    let new_commits = rev_list_grafted(repo, &commits, &exclude, &options.grafts)?;
+    let new_set = new_commits.iter().collect::<HashSet<_>>();
+    let mut boundary = exclude.iter().cloned().collect::<HashSet<_>>();
+    let mut commit_trees = Vec::new();
+    for hash in &new_commits {
+        let commit = Commit::read(repo, hash)?;
+        let parents = graft_parents(&options.grafts.include, hash, commit.parents);
+        boundary.extend(parents.into_iter().filter(|p| !new_set.contains(p)));
+        commit_trees.push(commit.tree);
+    }
+    for hash in &boundary {
+        if let Ok(commit) = Commit::read(repo, hash) {
+            walk.mark_uninteresting(&commit.tree, "", &mut list.bases)?;
+        }
+    }
+
+    for hash in new_commits {
+        if !walk.is_promisor(&hash) && walk.seen.insert(hash.clone()) {
+            list.objects.push((hash, String::new()));
+        }
+    }
+    for tree in commit_trees {
+        walk.add_tree(&tree, "", 0, false, &mut list.objects)?;
+    }
+    for tree in trees {
+        walk.add_tree(&tree, "", 0, true, &mut list.objects)?;
+    }
+    Ok(list)
+}
+
+/// TreeWalk lists the trees and blobs to send, skipping those already seen
+struct TreeWalk<'a> {
+    repo: &'a Repository,
+    filter: Option<ObjectFilter>,
+    promisor: &'a [Pack],
+    seen: HashSet<ObjectHash>,
+}
+
+impl TreeWalk<'_> {
+    fn is_promisor(&self, hash: &ObjectHash) -> bool {
+        self.promisor.iter().any(|pack| pack.contains(hash))
+    }
+
+    /// Mark a tree the remote has as seen, remembering its blobs by path
+    ///
+    /// Trees a partial clone lacks are skipped: they only help to send less.
+    fn mark_uninteresting(
+        &mut self,
+        hash: &ObjectHash,
+        path: &str,
+        bases: &mut HashMap<String, ObjectHash>,
+    ) -> Result<()> {
+        if !object_exists(self.repo, hash) || !self.seen.insert(hash.clone()) {
+            return Ok(());
+        }
+        for entry in Tree::read(self.repo, hash)?.entries {
+            let path = join(path, &entry.name);
+            match entry.mode {
+                EntryMode::Tree => self.mark_uninteresting(&entry.hash, &path, bases)?,
+                EntryMode::Gitlink => {}
+                _ => {
+                    self.seen.insert(entry.hash.clone());
+                    bases.insert(path, entry.hash);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a tree `depth` levels below a root tree and everything below it
+    /// that was not seen yet and the filter keeps; a tree asked for by
+    /// `name` is kept whatever the filter
+    fn add_tree(
+        &mut self,
+        hash: &ObjectHash,
+        path: &str,
+        depth: u64,
+        named: bool,
+        objects: &mut Vec<(ObjectHash, String)>,
+    ) -> Result<()> {
+        if self.filter.is_some_and(|f| !named && !f.keeps_tree(depth))
+            || self.is_promisor(hash)
+            || !self.seen.insert(hash.clone())
+        {
+            return Ok(());
+        }
+        objects.push((hash.clone(), path.to_string()));
+        for entry in Tree::read(self.repo, hash)?.entries {
+            let path = join(path, &entry.name);
+            match entry.mode {
+                EntryMode::Tree => self.add_tree(&entry.hash, &path, depth + 1, false, objects)?,
+                // Submodule commits live in another repository
+                EntryMode::Gitlink => {}
+                _ => {
+                    if self.seen.contains(&entry.hash) || self.is_promisor(&entry.hash) {
+                        continue;
+                    }
+                    if let Some(filter) = self.filter {
+                        let size = match filter {
+                            ObjectFilter::BlobLimit(_) => {
+                                read_object(self.repo, &entry.hash)?.data.len()
+                            }
+                            _ => 0,
+                        };
+                        if !filter.keeps_blob(depth + 1, size) {
+                            continue;
+                        }
+                    }
+                    self.seen.insert(entry.hash.clone());
+                    objects.push((entry.hash, path));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn join(path: &str, name: &str) -> String {
+    match path.is_empty() {
+        true => name.to_string(),
+        false => format!("{}/{}", path, name),
+    }
+}
+
+/// DeltaBase is the last version of a path written to a pack, or one the
+/// receiving side has when `offset` is `None`
+struct DeltaBase {
+    hash: ObjectHash,
+    data: Vec<u8>,
+    offset: Option<u64>,
+    depth: usize,
+}
+
+/// Write a pack of the listed objects
+///
+/// Blobs are stored as deltas against the previous version at the same
+/// path. When `thin` is set that version may be one of `list.bases`, which
+/// the pack then refers to without holding it.
+pub fn write_pack(repo: &Repository, list: &ObjectList, thin: bool) -> Result<Vec<u8>> {
+    let mut pack = b"PACK".to_vec();
+    pack.extend(2u32.to_be_bytes());
+    pack.extend((list.objects.len() as u32).to_be_bytes());
+    let mut previous: HashMap<&str, DeltaBase> = HashMap::new();
+    for (hash, path) in &list.objects {
+        let object = read_object(repo, hash)?;
+        let offset = pack.len() as u64;
+        let mut entry = None;
+        if object.object_type == ObjectType::Blob && object.data.len() >= MIN_DELTA_SIZE {
+            let base = match previous.get(path.as_str()) {
+                Some(base) => Some(base),
+                None if thin => list.bases.get(path).and_then(|base| {
+                    let data = read_object(repo, base).ok()?.data;
+                    let base = DeltaBase {
+                        hash: base.clone(),
+                        data,
+                        offset: None,
+                        depth: 0,
+                    };
+                    previous.insert(path, base);
+                    previous.get(path.as_str())
+                }),
+                None => None,
+            };
+            if let Some(base) = base.filter(|base| base.depth < MAX_DEPTH) {
+                let delta = create_delta(&base.data, &object.data);
+                if delta.len() * MAX_DELTA_RATIO <= object.data.len() {
+                    entry = Some((base.hash.clone(), base.offset, delta, base.depth + 1));
+                }
+            }
+        }
+        let depth = match entry {
+            Some((base_hash, base_offset, delta, depth)) => {
+                match base_offset {
+                    Some(base_offset) => {
+                        pack.extend(entry_header(6, delta.len()));
+                        pack.extend(offset_encoding(offset - base_offset));
+                    }
+                    None => {
+                        pack.extend(entry_header(7, delta.len()));
+                        pack.extend(base_hash.as_bytes());
+                    }
+                }
+                pack.extend(deflate(&delta)?);
+                depth
+            }
+            None => {
+                pack.extend(entry_header(
+                    type_number(&object.object_type),
+                    object.data.len(),
+                ));
+                pack.extend(deflate(&object.data)?);
+                0
+            }
+        };
+        if object.object_type == ObjectType::Blob {
+            let base = DeltaBase {
+                hash: hash.clone(),
+                data: object.data,
+                offset: Some(offset),
+                depth,
+            };
+            previous.insert(path, base);
+        }
+    }
+    let checksum = repo.hash_algorithm().digest(&pack);
+    pack.extend(checksum.as_bytes());
+    Ok(pack)
+}
+
+pub(crate) fn type_number(object_type: &ObjectType) -> u8 {
+    match object_type {
+        ObjectType::Commit => 1,
+        ObjectType::Tree => 2,
+        ObjectType::Blob => 3,
+        ObjectType::Tag => 4,
+    }
+}
+
+/// Encode the type and size of a pack entry
+pub(crate) fn entry_header(kind: u8, size: usize) -> Vec<u8> {
+    let mut header = vec![(kind << 4) | (size & 0x0f) as u8];
+    let mut size = size >> 4;
+    while size > 0 {
+        *header.last_mut().expect("header is not empty") |= 0x80;
+        header.push((size & 0x7f) as u8);
+        size >>= 7;
+    }
+    header
+}
+
+/// Encode the distance back to the base of an `OFS_DELTA` entry
+pub(crate) fn offset_encoding(distance: u64) -> Vec<u8> {
+    let mut bytes = vec![(distance & 0x7f) as u8];
+    let mut distance = distance >> 7;
+    while distance > 0 {
+        distance -= 1;
+        bytes.push(0x80 | (distance & 0x7f) as u8);
+        distance >>= 7;
+    }
+    bytes.reverse();
+    bytes
+}
+
+pub(crate) fn deflate(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Encode a size as the little-endian base-128 number deltas start with
+fn delta_size(size: usize, out: &mut Vec<u8>) {
+    let mut size = size;
+    loop {
+        let byte = (size & 0x7f) as u8;
+        size >>= 7;
+        match size {
+            0 => return out.push(byte),
+            _ => out.push(byte | 0x80),
+        }
+    }
+}
+
+/// Compute a delta turning `base` into `target`, the inverse of
+/// `pack::apply_delta`
+///
+/// Blocks of the target found in the base become copies, found by looking
+/// up every aligned block of the base; the rest is inserted.
+pub fn create_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut index: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for offset in (0..base.len().saturating_sub(BLOCK_SIZE - 1)).step_by(BLOCK_SIZE) {
+        index
+            .entry(&base[offset..offset + BLOCK_SIZE])
+            .or_default()
+            .push(offset);
+    }
+
+    let mut delta = Vec::new();
+    delta_size(base.len(), &mut delta);
+    delta_size(target.len(), &mut delta);
+    let mut insert_from = 0;
+    let mut position = 0;
+    while position + BLOCK_SIZE <= target.len() {
+        let candidates = index.get(&target[position..position + BLOCK_SIZE]);
+        let best = candidates
+            .into_iter()
+            .flatten()
+            .map(|&offset| {
+                let length = base[offset..]
+                    .iter()
+                    .zip(&target[position..])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                (length, offset)
+            })
+            .max();
+        let Some((length, offset)) = best else {
+            position += 1;
+            continue;
+        };
+        // Grow the match backwards over bytes not yet emitted
+        let back = base[..offset]
+            .iter()
+            .rev()
+            .zip(target[insert_from..position].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        push_insert(&target[insert_from..position - back], &mut delta);
+        push_copy(offset - back, length + back, &mut delta);
+        position += length;
+        insert_from = position;
+    }
+    push_insert(&target[insert_from..], &mut delta);
+    delta
+}
+
+fn push_insert(data: &[u8], delta: &mut Vec<u8>) {
+    for chunk in data.chunks(0x7f) {
+        delta.push(chunk.len() as u8);
+        delta.extend_from_slice(chunk);
+    }
+}
+
+fn push_copy(offset: usize, length: usize, delta: &mut Vec<u8>) {
+    let mut done = 0;
+    while done < length {
+        let size = (length - done).min(MAX_COPY);
+        let start = offset + done;
+        let mut instruction = 0x80u8;
+        let mut arguments = Vec::new();
+        for i in 0..4 {
+            let byte = (start >> (8 * i)) as u8;
+            if byte != 0 {
+                instruction |= 1 << i;
+                arguments.push(byte);
+            }
+        }
+        for i in 0..2 {
+            let byte = (size >> (8 * i)) as u8;
+            if byte != 0 {
+                instruction |= 0x10 << i;
+                arguments.push(byte);
+            }
+        }
+        delta.push(instruction);
+        delta.extend(arguments);
+        done += size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pack::{apply_delta, store_pack};
+    use crate::test_utils::{commit_files, init_repo};
+
+    #[test]
+    fn test_create_delta() {
+        let base = (0..2000).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+        let mut target = base[..700].to_vec();
+        target.extend(b"something new in the middle");
+        target.extend(&base[650..]);
+        let delta = create_delta(&base, &target);
+        assert!(delta.len() < 100);
+        assert_eq!(apply_delta(&base, &delta).unwrap(), target);
+        assert_eq!(
+            apply_delta(b"", &create_delta(b"", b"abc")).unwrap(),
+            b"abc"
+        );
+    }
+
+    #[test]
+    fn test_thin_pack_round_trip() {
+        let (_dir, repo) = init_repo();
+        let text = (0..100)
+            .map(|i| format!("line {}\n", i))
+            .collect::<String>();
+        let first = commit_files(&repo, &[("a.txt", &text)], "first");
+        let second = commit_files(&repo, &[("a.txt", &format!("{}more\n", text))], "second");
+        let list = list_objects(
+            &repo,
+            std::slice::from_ref(&second),
+            std::slice::from_ref(&first),
+        )
+        .unwrap();
+        assert_eq!(list.objects.len(), 3);
+        let pack = write_pack(&repo, &list, true).unwrap();
+
+        // The receiving side has the first commit only
+        let (_dir, remote) = init_repo();
+        let objects = list_objects(&repo, &[first], &[]).unwrap();
+        let base = write_pack(&repo, &objects, false).unwrap();
+        store_pack(&remote, &base).unwrap();
+        // The delta's base is added to the stored pack
+        let stored = store_pack(&remote, &pack).unwrap().unwrap();
+        assert_eq!(stored.index().len(), 4);
+        let commit = Commit::read(&remote, &second).unwrap();
+        let tree = Tree::read(&remote, &commit.tree).unwrap();
+        let blob = read_object(&remote, &tree.entries[0].hash).unwrap();
+        assert!(blob.data.ends_with(b"more\n"));
+    }
+}
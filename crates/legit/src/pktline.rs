@@ -0,0 +1,144 @@
+use anyhow::{bail, Context, Result};
+use std::io::{Read, Write};
+
+/// Largest payload a single pkt-line can carry
+pub const MAX_DATA_LEN: usize = 65516;
+
+/// Packet is one pkt-line: a length-prefixed payload or a special packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    Data(Vec<u8>),
+    /// `0000`, the end of a message
+    Flush,
+    /// `0001`, the end of a section of a protocol v2 message
+    Delim,
+    /// `0002`, the end of a response to a stateless request
+    ResponseEnd,
+}
+
+/// PktReader reads pkt-lines from a stream
+pub struct PktReader<R> {
+    reader: R,
+}
+
+impl<R: Read> PktReader<R> {
+    pub fn new(reader: R) -> PktReader<R> {
+        PktReader { reader }
+    }
+
+    /// Read the next packet
+    pub fn read_packet(&mut self) -> Result<Packet> {
+        let mut length = [0u8; 4];
+        self.reader
+            .read_exact(&mut length)
+            .context("The remote end hung up unexpectedly")?;
+        let length = std::str::from_utf8(&length)
+            .ok()
+            .and_then(|length| usize::from_str_radix(length, 16).ok())
+            .with_context(|| format!("Invalid pkt-line length {:?}", length))?;
+        match length {
+            0 => Ok(Packet::Flush),
+            1 => Ok(Packet::Delim),
+            2 => Ok(Packet::ResponseEnd),
+            3 => bail!("Invalid pkt-line length 3"),
+            _ => {
+                let mut data = vec![0u8; length - 4];
+                self.reader
+                    .read_exact(&mut data)
+                    .context("The remote end hung up unexpectedly")?;
+                Ok(Packet::Data(data))
+            }
+        }
+    }
+
+    /// Read a text line without its trailing newline, or `None` at the end
+    /// of a message or section
+    pub fn read_line(&mut self) -> Result<Option<String>> {
+        match self.read_packet()? {
+            Packet::Data(data) => {
+                let line = String::from_utf8(data).context("Invalid pkt-line text")?;
+                let line = line.strip_suffix('\n').unwrap_or(&line).to_string();
+                if let Some(message) = line.strip_prefix("ERR ") {
+                    bail!("remote error: {}", message);
+                }
+                Ok(Some(line))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Return a mutable reference to the underlying stream
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Return the underlying stream
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Encode a payload as a pkt-line
+pub fn encode(data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() > MAX_DATA_LEN {
+        bail!("pkt-line payload of {} bytes is too long", data.len());
+    }
+    let mut packet = format!("{:04x}", data.len() + 4).into_bytes();
+    packet.extend_from_slice(data);
+    Ok(packet)
+}
+
+/// Write a payload as a pkt-line
+pub fn write_packet(writer: &mut impl Write, data: &[u8]) -> Result<()> {
+    writer.write_all(&encode(data)?)?;
+    Ok(())
+}
+
+/// Write a text line as a pkt-line, adding its newline
+pub fn write_line(writer: &mut impl Write, line: &str) -> Result<()> {
+    write_packet(writer, format!("{}\n", line).as_bytes())
+}
+
+/// Write a flush packet
+pub fn write_flush(writer: &mut impl Write) -> Result<()> {
+    writer.write_all(b"0000")?;
+    Ok(())
+}
+
+/// Write a delimiter packet
+pub fn write_delim(writer: &mut impl Write) -> Result<()> {
+    writer.write_all(b"0001")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut buffer = Vec::new();
+        write_line(&mut buffer, "command=ls-refs").unwrap();
+        write_delim(&mut buffer).unwrap();
+        write_packet(&mut buffer, b"\x01pack").unwrap();
+        write_flush(&mut buffer).unwrap();
+        assert_eq!(&buffer[..4], b"0014");
+
+        let mut reader = PktReader::new(buffer.as_slice());
+        assert_eq!(
+            reader.read_line().unwrap().as_deref(),
+            Some("command=ls-refs")
+        );
+        assert_eq!(reader.read_packet().unwrap(), Packet::Delim);
+        assert_eq!(
+            reader.read_packet().unwrap(),
+            Packet::Data(b"\x01pack".to_vec())
+        );
+        assert_eq!(reader.read_line().unwrap(), None);
+        assert!(reader.read_packet().is_err());
+
+        let mut reader = PktReader::new(&b"000eERR denied"[..]);
+        assert!(reader.read_line().is_err());
+        assert!(encode(&[0; MAX_DATA_LEN + 1]).is_err());
+    }
+}
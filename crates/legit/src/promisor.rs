@@ -0,0 +1,256 @@
+use crate::objects::ObjectHash;
+use crate::pack::{packs, Pack};
+use crate::remote::Remote;
+use crate::transport::{self, FetchScope};
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::cell::Cell;
+use std::fmt::Display;
+use std::fs;
+use std::str::FromStr;
+
+thread_local! {
+    /// Set while fetching missing objects, so that the objects a fetch
+    /// itself misses are not fetched in turn
+    static FETCHING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// ObjectFilter is a partial clone filter, leaving objects out of a fetch
+/// for the remote to send later
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectFilter {
+    /// `blob:none`: no blobs
+    BlobNone,
+    /// `blob:limit=<n>`: no blobs of `n` bytes or more
+    BlobLimit(u64),
+    /// `tree:<depth>`: no trees or blobs `depth` levels or more below the
+    /// root trees, which are at depth 0
+    TreeDepth(u64),
+}
+
+impl ObjectFilter {
+    /// Return true if the filter keeps a tree `depth` levels below a root
+    pub fn keeps_tree(&self, depth: u64) -> bool {
+        match self {
+            ObjectFilter::TreeDepth(limit) => depth < *limit,
+            _ => true,
+        }
+    }
+
+    /// Return true if the filter keeps a blob of `size` bytes `depth`
+    /// levels below a root
+    pub fn keeps_blob(&self, depth: u64, size: usize) -> bool {
+        match self {
+            ObjectFilter::BlobNone => false,
+            ObjectFilter::BlobLimit(limit) => (size as u64) < *limit,
+            ObjectFilter::TreeDepth(limit) => depth < *limit,
+        }
+    }
+}
+
+impl FromStr for ObjectFilter {
+    type Err = anyhow::Error;
+
+    /// Parse a filter spec; limits take a `k`, `m` or `g` suffix
+    fn from_str(spec: &str) -> Result<ObjectFilter> {
+        let number = |value: &str| -> Result<u64> {
+            let (digits, unit) = match value.char_indices().last() {
+                Some((i, c)) if c.is_ascii_alphabetic() => (&value[..i], c),
+                _ => (value, 'b'),
+            };
+            let unit = match unit.to_ascii_lowercase() {
+                'b' => 1,
+                'k' => 1 << 10,
+                'm' => 1 << 20,
+                'g' => 1 << 30,
+                _ => bail!("invalid filter-spec '{}'", spec),
+            };
+            let count = digits
+                .parse::<u64>()
+                .with_context(|| format!("invalid filter-spec '{}'", spec))?;
+            Ok(count * unit)
+        };
+        if spec == "blob:none" {
+            return Ok(ObjectFilter::BlobNone);
+        }
+        if let Some(limit) = spec.strip_prefix("blob:limit=") {
+            return Ok(ObjectFilter::BlobLimit(number(limit)?));
+        }
+        if let Some(depth) = spec.strip_prefix("tree:") {
+            return Ok(ObjectFilter::TreeDepth(number(depth)?));
+        }
+        bail!("invalid filter-spec '{}'", spec)
+    }
+}
+
+impl Display for ObjectFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjectFilter::BlobNone => write!(f, "blob:none"),
+            ObjectFilter::BlobLimit(limit) => write!(f, "blob:limit={}", limit),
+            ObjectFilter::TreeDepth(depth) => write!(f, "tree:{}", depth),
+        }
+    }
+}
+
+/// Make a repository a partial clone of `remote`, whose later fetches use
+/// `filter` and which is asked for the objects the repository misses
+pub fn register(repo: &Repository, remote: &str, filter: &ObjectFilter) -> Result<()> {
+    let mut config = repo.config()?;
+    config.set(&format!("remote.{}.promisor", remote), "true")?;
+    config.set(
+        &format!("remote.{}.partialclonefilter", remote),
+        &filter.to_string(),
+    )?;
+    if config.get("extensions.partialclone").is_none() {
+        config.set("core.repositoryformatversion", "1")?;
+        config.set("extensions.partialclone", remote)?;
+    }
+    repo.write_config(&config)
+}
+
+/// Return the fetch scope of a promisor remote, `None` for other remotes
+pub fn promisor_scope(repo: &Repository, remote: &str) -> Result<Option<FetchScope>> {
+    let config = repo.config()?;
+    let promisor = config.get_bool(&format!("remote.{}.promisor", remote))? == Some(true)
+        || repo.settings().extensions.partialclone.as_deref() == Some(remote);
+    if !promisor {
+        return Ok(None);
+    }
+    let filter = config
+        .get(&format!("remote.{}.partialclonefilter", remote))
+        .map(ObjectFilter::from_str)
+        .transpose()?;
+    Ok(Some(FetchScope {
+        filter,
+        promisor: true,
+        ..FetchScope::default()
+    }))
+}
+
+/// Mark a pack as fetched from a promisor remote, which promises to send
+/// the objects it refers to but lacks
+pub fn mark_promisor(pack: &Pack) -> Result<()> {
+    let path = pack.path().with_extension("promisor");
+    fs::write(&path, "").with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// List the packs fetched from a promisor remote
+pub fn promisor_packs(repo: &Repository) -> Result<Vec<Pack>> {
+    Ok(packs(repo)?
+        .into_iter()
+        .filter(|pack| pack.path().with_extension("promisor").exists())
+        .collect())
+}
+
+/// Fetch an object a partial clone lacks from its promisor remote
+///
+/// Returns false, fetching nothing, when the repository is not a partial
+/// clone or this is already a fetch of missing objects. As in git, the
+/// fetch leaves out blobs, so that a missing tree does not bring every file
+/// with it.
+pub fn fetch_missing(repo: &Repository, hash: &ObjectHash) -> Result<bool> {
+    let Some(name) = &repo.settings().extensions.partialclone else {
+        return Ok(false);
+    };
+    if FETCHING.with(Cell::get) {
+        return Ok(false);
+    }
+    FETCHING.with(|fetching| fetching.set(true));
+    let result = (|| {
+        let remote = Remote::resolve(repo, name)?;
+        let mut transport = transport::open(repo, &remote.url)?;
+        transport.set_scope(&FetchScope {
+            filter: Some(ObjectFilter::BlobNone),
+            promisor: true,
+            ..FetchScope::default()
+        })?;
+        transport.fetch(repo, std::slice::from_ref(hash), &[])
+    })();
+    FETCHING.with(|fetching| fetching.set(false));
+    result.with_context(|| format!("Failed to fetch {} from promisor remote {}", hash, name))?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::{clone, CloneOptions};
+    use crate::objects::{object_exists, read_object};
+    use crate::test_utils::{commit_files, init_repo};
+    use crate::tree::Tree;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_filter() {
+        assert_eq!(
+            "blob:none".parse::<ObjectFilter>().unwrap(),
+            ObjectFilter::BlobNone
+        );
+        assert_eq!(
+            "blob:limit=2k".parse::<ObjectFilter>().unwrap(),
+            ObjectFilter::BlobLimit(2048)
+        );
+        assert_eq!(ObjectFilter::BlobLimit(2048).to_string(), "blob:limit=2048");
+        assert_eq!(
+            "tree:0".parse::<ObjectFilter>().unwrap(),
+            ObjectFilter::TreeDepth(0)
+        );
+        assert!("sparse:oid=x".parse::<ObjectFilter>().is_err());
+        assert!("blob:limit=1x".parse::<ObjectFilter>().is_err());
+    }
+
+    #[test]
+    fn test_partial_clone_fetches_missing_objects() {
+        let (source_dir, source) = init_repo();
+        commit_files(&source, &[("a.txt", "one\n")], "first");
+        let head = commit_files(
+            &source,
+            &[("a.txt", "two\n"), ("dir/b.txt", "b\n")],
+            "second",
+        );
+
+        let target = TempDir::new().unwrap();
+        let path = target.path().join("clone");
+        let options = CloneOptions {
+            filter: Some(ObjectFilter::BlobNone),
+            no_checkout: true,
+            ..CloneOptions::default()
+        };
+        let repo = clone(source_dir.path().to_str().unwrap(), &path, &options).unwrap();
+        assert_eq!(
+            repo.settings().extensions.partialclone.as_deref(),
+            Some("origin")
+        );
+        assert_eq!(promisor_packs(&repo).unwrap().len(), 1);
+
+        let tree = Tree::read(
+            &repo,
+            &crate::commits::Commit::read(&repo, &head).unwrap().tree,
+        )
+        .unwrap();
+        let blob = &tree
+            .entries
+            .iter()
+            .find(|e| e.name == "a.txt")
+            .unwrap()
+            .hash;
+        assert!(!object_exists(&repo, blob));
+        assert_eq!(read_object(&repo, blob).unwrap().data, b"two\n");
+        assert!(object_exists(&repo, blob));
+
+        // Without its promisor remote, a missing object stays missing
+        let dir = tree.entries.iter().find(|e| e.name == "dir").unwrap();
+        let dir = Tree::read(&repo, &dir.hash).unwrap();
+        let other = &dir.entries[0].hash;
+        fs::rename(source_dir.path(), target.path().join("gone")).unwrap();
+        let error = read_object(&repo, other).unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("from promisor remote origin"),
+            "{:#}",
+            error
+        );
+        assert!(!object_exists(&repo, other));
+        assert_eq!(read_object(&repo, blob).unwrap().data, b"two\n");
+    }
+}
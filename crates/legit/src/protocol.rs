@@ -0,0 +1,600 @@
+use crate::objects::{HashAlgorithm, ObjectHash};
+use crate::pack::store_pack;
+use crate::pktline::{write_delim, write_flush, write_line, Packet, PktReader};
+use crate::promisor::{mark_promisor, ObjectFilter};
+use crate::revision::rev_list;
+use crate::shallow::{read_shallow, update_shallow, Deepen};
+use crate::transport::{check_not_option, FetchScope, RemoteRef, Transport, Url};
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+/// Most commits we tell the server we have; anything older it will send again
+const MAX_HAVES: usize = 256;
+
+/// Capabilities is the capability advertisement of a protocol v2 server,
+/// e.g. `fetch=shallow filter`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Capabilities {
+    lines: Vec<String>,
+}
+
+impl Capabilities {
+    /// Read the advertisement a server sends when a connection opens
+    pub fn read(reader: &mut PktReader<impl Read>) -> Result<Capabilities> {
+        match reader.read_line()? {
+            Some(version) if version == "version 2" => {}
+            Some(_) => bail!("The remote does not speak protocol version 2"),
+            None => bail!("The remote sent an empty capability advertisement"),
+        }
+        let mut lines = Vec::new();
+        while let Some(line) = reader.read_line()? {
+            lines.push(line);
+        }
+        Ok(Capabilities { lines })
+    }
+
+    /// Return the value of a capability, empty when it has none
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.lines
+            .iter()
+            .find_map(|line| match line.split_once('=') {
+                Some((key, value)) if key == name => Some(value),
+                None if line == name => Some(""),
+                _ => None,
+            })
+    }
+
+    /// The algorithm the server names objects with, SHA-1 unless it
+    /// advertises an `object-format`
+    pub fn hash_algorithm(&self) -> Result<HashAlgorithm> {
+        match self.get("object-format") {
+            Some(format) => format
+                .parse()
+                .with_context(|| format!("The remote uses an unknown object format '{}'", format)),
+            None => Ok(HashAlgorithm::Sha1),
+        }
+    }
+
+    /// Return true if a command supports a feature, e.g. `fetch` and `filter`
+    pub fn supports(&self, command: &str, feature: &str) -> bool {
+        self.get(command)
+            .is_some_and(|features| features.split(' ').any(|f| f == feature))
+    }
+
+    /// Write the start of a command request, up to its arguments
+    fn write_command(&self, out: &mut Vec<u8>, command: &str) -> Result<()> {
+        if self.get(command).is_none() {
+            bail!("The remote does not support the {} command", command);
+        }
+        write_line(out, &format!("command={}", command))?;
+        if self.get("agent").is_some() {
+            write_line(out, &format!("agent=legit/{}", env!("CARGO_PKG_VERSION")))?;
+        }
+        // Ask for the server's own format: fetching checks it matches ours
+        if let Some(format) = self.get("object-format") {
+            write_line(out, &format!("object-format={}", format))?;
+        }
+        write_delim(out)
+    }
+}
+
+/// FetchRequest is what a `fetch` command asks the server for
+#[derive(Debug, Clone, Default)]
+pub struct FetchRequest {
+    pub wants: Vec<ObjectHash>,
+    pub haves: Vec<ObjectHash>,
+    /// The commits the fetching repository is shallow at
+    pub shallow: Vec<ObjectHash>,
+    /// How much history to fetch, all of it when `None`
+    pub deepen: Option<Deepen>,
+    /// Leave out the objects a filter such as `blob:none` excludes
+    pub filter: Option<ObjectFilter>,
+    pub progress: bool,
+}
+
+/// FetchResponse is the pack a server sent with its shallow boundary
+#[derive(Debug, Clone, Default)]
+pub struct FetchResponse {
+    pub pack: Vec<u8>,
+    /// Commits whose parents were left out
+    pub shallow: Vec<ObjectHash>,
+    /// Commits that are no longer shallow
+    pub unshallow: Vec<ObjectHash>,
+}
+
+/// Build an `ls-refs` request listing every reference with its peeled
+/// value and symref target
+pub fn ls_refs_request(capabilities: &Capabilities) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    capabilities.write_command(&mut out, "ls-refs")?;
+    write_line(&mut out, "peel")?;
+    write_line(&mut out, "symrefs")?;
+    write_flush(&mut out)?;
+    Ok(out)
+}
+
+/// Parse the response to an `ls-refs` request
+///
+/// Unborn references carry no object and are left out.
+pub fn read_ls_refs(
+    reader: &mut PktReader<impl Read>,
+    algorithm: HashAlgorithm,
+) -> Result<Vec<RemoteRef>> {
+    let mut refs = Vec::new();
+    while let Some(line) = reader.read_line()? {
+        let mut fields = line.split(' ');
+        let (Some(hash), Some(name)) = (fields.next(), fields.next()) else {
+            bail!("Invalid ls-refs line: {}", line);
+        };
+        if hash == "unborn" {
+            continue;
+        }
+        let mut remote_ref = RemoteRef {
+            name: name.to_string(),
+            hash: ObjectHash::from_hex(hash, algorithm)?,
+            symref_target: None,
+            peeled: None,
+        };
+        for attribute in fields {
+            if let Some(target) = attribute.strip_prefix("symref-target:") {
+                remote_ref.symref_target = Some(target.to_string());
+            } else if let Some(peeled) = attribute.strip_prefix("peeled:") {
+                remote_ref.peeled = Some(ObjectHash::from_hex(peeled, algorithm)?);
+            }
+        }
+        refs.push(remote_ref);
+    }
+    Ok(refs)
+}
+
+/// Build a `fetch` request that ends negotiation right away with `done`
+pub fn fetch_request(capabilities: &Capabilities, request: &FetchRequest) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    capabilities.write_command(&mut out, "fetch")?;
+    write_line(&mut out, "ofs-delta")?;
+    write_line(&mut out, "include-tag")?;
+    if !request.progress {
+        write_line(&mut out, "no-progress")?;
+    }
+    if (!request.shallow.is_empty() || request.deepen.is_some())
+        && !capabilities.supports("fetch", "shallow")
+    {
+        bail!("Server does not support shallow clients");
+    }
+    for hash in &request.shallow {
+        write_line(&mut out, &format!("shallow {}", hash))?;
+    }
+    match request.deepen {
+        Some(Deepen::Depth(depth)) => write_line(&mut out, &format!("deepen {}", depth))?,
+        Some(Deepen::Since(time)) => write_line(&mut out, &format!("deepen-since {}", time))?,
+        None => {}
+    }
+    if let Some(filter) = &request.filter {
+        if !capabilities.supports("fetch", "filter") {
+            bail!("Server does not support filters");
+        }
+        write_line(&mut out, &format!("filter {}", filter))?;
+    }
+    for want in &request.wants {
+        write_line(&mut out, &format!("want {}", want))?;
+    }
+    for have in &request.haves {
+        write_line(&mut out, &format!("have {}", have))?;
+    }
+    write_line(&mut out, "done")?;
+    write_flush(&mut out)?;
+    Ok(out)
+}
+
+/// Parse the response to a `fetch` request, demultiplexing the pack from
+/// the progress messages sent alongside it
+pub fn read_fetch_response(
+    reader: &mut PktReader<impl Read>,
+    algorithm: HashAlgorithm,
+    progress: bool,
+) -> Result<FetchResponse> {
+    let mut response = FetchResponse::default();
+    loop {
+        let Some(section) = reader.read_line()? else {
+            bail!("The remote sent no pack");
+        };
+        if section == "packfile" {
+            break;
+        }
+        loop {
+            let line = match reader.read_packet()? {
+                Packet::Data(data) => String::from_utf8(data)?,
+                Packet::Delim => break,
+                _ => bail!("The remote sent no pack"),
+            };
+            let line = line.trim_end();
+            match (section.as_str(), line.split_once(' ')) {
+                ("shallow-info", Some(("shallow", hash))) => response
+                    .shallow
+                    .push(ObjectHash::from_hex(hash, algorithm)?),
+                ("shallow-info", Some(("unshallow", hash))) => response
+                    .unshallow
+                    .push(ObjectHash::from_hex(hash, algorithm)?),
+                _ => {}
+            }
+        }
+    }
+
+    let mut stderr = std::io::stderr();
+    loop {
+        let data = match reader.read_packet()? {
+            Packet::Data(data) => data,
+            _ => break,
+        };
+        match data.split_first() {
+            Some((1, pack)) => response.pack.extend_from_slice(pack),
+            Some((2, message)) if progress => {
+                stderr.write_all(message)?;
+            }
+            Some((2, _)) => {}
+            Some((3, message)) => {
+                bail!(
+                    "remote error: {}",
+                    String::from_utf8_lossy(message).trim_end()
+                )
+            }
+            _ => bail!("Invalid side-band packet"),
+        }
+    }
+    Ok(response)
+}
+
+/// Build the request of a fetch through a transport: `haves` are offered
+/// with their recent history, and a shallow repository tells where its
+/// history ends
+pub fn fetch_request_for(
+    repo: &Repository,
+    wants: &[ObjectHash],
+    haves: &[ObjectHash],
+    scope: &FetchScope,
+    progress: bool,
+) -> Result<FetchRequest> {
+    let mut shallow = read_shallow(repo)?.into_iter().collect::<Vec<_>>();
+    shallow.sort();
+    Ok(FetchRequest {
+        wants: wants.to_vec(),
+        haves: negotiation_haves(repo, haves)?,
+        shallow,
+        deepen: scope.deepen,
+        filter: scope.filter,
+        progress,
+    })
+}
+
+/// Store the pack of a fetch and move the shallow boundary as the server
+/// said; a pack from a promisor remote is marked as such
+pub fn store_response(repo: &Repository, response: &FetchResponse, promisor: bool) -> Result<()> {
+    if let (Some(pack), true) = (store_pack(repo, &response.pack)?, promisor) {
+        mark_promisor(&pack)?;
+    }
+    update_shallow(repo, &response.shallow, &response.unshallow)
+}
+
+/// Return the commits to offer as `have` lines: the tips and their recent
+/// history
+pub fn negotiation_haves(repo: &Repository, tips: &[ObjectHash]) -> Result<Vec<ObjectHash>> {
+    let mut haves = rev_list(repo, tips, &[])?;
+    haves.truncate(MAX_HAVES);
+    Ok(haves)
+}
+
+/// Connection is a pipe to a git service such as `git-upload-pack`, run
+/// locally or at the other end of `ssh`
+pub struct Connection {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    reader: PktReader<ChildStdout>,
+}
+
+impl Connection {
+    /// Spawn a command serving a git service, asking it to speak protocol
+    /// version 2 if `protocol_v2` is set
+    pub fn spawn(mut command: Command, protocol_v2: bool) -> Result<Connection> {
+        if protocol_v2 {
+            command.env("GIT_PROTOCOL", "version=2");
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run {:?}", command.get_program()))?;
+        let stdin = child.stdin.take();
+        let reader = PktReader::new(child.stdout.take().expect("stdout is piped"));
+        Ok(Connection {
+            child,
+            stdin,
+            reader,
+        })
+    }
+
+    /// Run a service such as `git-receive-pack` on a local repository, or on
+    /// a host over ssh
+    ///
+    /// The ssh program comes from `GIT_SSH_COMMAND` or `GIT_SSH`, as in git.
+    pub fn open(url: &Url, service: &str, protocol_v2: bool) -> Result<Connection> {
+        if let Url::Local(path) | Url::File(path) = url {
+            check_not_option("pathname", &path.to_string_lossy())?;
+            let mut command = Command::new(service);
+            command.arg(path);
+            return Connection::spawn(command, protocol_v2);
+        }
+        let (mut command, openssh) = match std::env::var("GIT_SSH_COMMAND") {
+            Ok(ssh) => {
+                let mut command = Command::new("sh");
+                command.args(["-c", &format!("{} \"$@\"", ssh), "ssh"]);
+                (command, true)
+            }
+            Err(_) => match std::env::var_os("GIT_SSH") {
+                Some(ssh) => {
+                    // Like git, only a program named ssh is taken for OpenSSH
+                    let openssh = Path::new(&ssh).file_stem() == Some("ssh".as_ref());
+                    (Command::new(ssh), openssh)
+                }
+                None => (Command::new("ssh"), true),
+            },
+        };
+        command.args(ssh_args(url, service, openssh, protocol_v2)?);
+        Connection::spawn(command, protocol_v2)
+    }
+
+    /// Return the reader of the service's output
+    pub fn reader(&mut self) -> &mut PktReader<ChildStdout> {
+        &mut self.reader
+    }
+
+    /// Send data to the service
+    pub fn send(&mut self, data: &[u8]) -> Result<()> {
+        let stdin = self.stdin.as_mut().context("The connection is closed")?;
+        stdin.write_all(data)?;
+        stdin.flush()?;
+        Ok(())
+    }
+
+    /// Close the input of the service, telling it we are done
+    pub fn close_input(&mut self) {
+        drop(self.stdin.take());
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.close_input();
+        let _ = self.child.wait();
+    }
+}
+
+/// Build the arguments running `service` over ssh; `openssh` enables the
+/// options only OpenSSH understands
+///
+/// The URL is checked again here, as the destination must never be taken
+/// for an option.
+fn ssh_args(url: &Url, service: &str, openssh: bool, protocol_v2: bool) -> Result<Vec<String>> {
+    let Url::Ssh {
+        user,
+        host,
+        port,
+        path,
+    } = url
+    else {
+        bail!("{:?} cannot be reached through {}", url, service);
+    };
+    check_not_option("hostname", host)?;
+    if let Some(user) = user {
+        check_not_option("username", user)?;
+    }
+    check_not_option("pathname", path)?;
+    let mut args = Vec::new();
+    if openssh && protocol_v2 {
+        args.extend(["-o".to_string(), "SendEnv=GIT_PROTOCOL".to_string()]);
+    }
+    if let Some(port) = port {
+        args.extend(["-p".to_string(), port.to_string()]);
+    }
+    if openssh {
+        args.push("--".to_string());
+    }
+    args.push(match user {
+        Some(user) => format!("{}@{}", user, host),
+        None => host.to_string(),
+    });
+    let quoted = format!("'{}'", path.replace('\'', "'\\''"));
+    args.push(format!("{} {}", service, quoted));
+    Ok(args)
+}
+
+/// ProcessTransport talks protocol v2 to `git-upload-pack` over a
+/// `Connection`
+pub struct ProcessTransport {
+    connection: Connection,
+    capabilities: Capabilities,
+    progress: bool,
+    scope: FetchScope,
+}
+
+impl ProcessTransport {
+    /// Connect to the `git-upload-pack` of a `file://` or ssh URL and read
+    /// its capabilities
+    pub fn open(url: &Url) -> Result<ProcessTransport> {
+        let mut connection = Connection::open(url, "git-upload-pack", true)?;
+        let capabilities = Capabilities::read(connection.reader())?;
+        Ok(ProcessTransport {
+            connection,
+            capabilities,
+            progress: false,
+            scope: FetchScope::default(),
+        })
+    }
+
+    /// Return what the server advertised
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Fetch a pack and store it in the repository
+    pub fn fetch_pack(
+        &mut self,
+        repo: &Repository,
+        request: &FetchRequest,
+    ) -> Result<FetchResponse> {
+        self.connection
+            .send(&fetch_request(&self.capabilities, request)?)?;
+        let algorithm = self.capabilities.hash_algorithm()?;
+        let response = read_fetch_response(self.connection.reader(), algorithm, request.progress)?;
+        store_response(repo, &response, self.scope.promisor)?;
+        Ok(response)
+    }
+}
+
+impl Transport for ProcessTransport {
+    fn list_refs(&mut self) -> Result<Vec<RemoteRef>> {
+        self.connection
+            .send(&ls_refs_request(&self.capabilities)?)?;
+        let algorithm = self.capabilities.hash_algorithm()?;
+        read_ls_refs(self.connection.reader(), algorithm)
+    }
+
+    fn fetch(
+        &mut self,
+        repo: &Repository,
+        wants: &[ObjectHash],
+        haves: &[ObjectHash],
+    ) -> Result<()> {
+        let request = fetch_request_for(repo, wants, haves, &self.scope, self.progress)?;
+        self.fetch_pack(repo, &request)?;
+        Ok(())
+    }
+
+    fn set_progress(&mut self, progress: bool) {
+        self.progress = progress;
+    }
+
+    fn set_scope(&mut self, scope: &FetchScope) -> Result<()> {
+        self.scope = scope.clone();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::{fetch, FetchOptions};
+    use crate::objects::object_exists;
+    use crate::refs::resolve_ref;
+    use crate::test_utils::{commit_files, init_repo, require_program};
+
+    #[test]
+    fn test_ssh_args() {
+        let url = Url::parse("ssh://git@example.com:2222/srv/it's.git").unwrap();
+        assert_eq!(
+            ssh_args(&url, "git-upload-pack", true, true).unwrap(),
+            [
+                "-o",
+                "SendEnv=GIT_PROTOCOL",
+                "-p",
+                "2222",
+                "--",
+                "git@example.com",
+                "git-upload-pack '/srv/it'\\''s.git'"
+            ]
+        );
+        let url = Url::parse("example.com:repo").unwrap();
+        assert_eq!(
+            ssh_args(&url, "git-upload-pack", false, true).unwrap(),
+            ["example.com", "git-upload-pack 'repo'"]
+        );
+
+        // URLs built without parsing are checked as well
+        let url = Url::Ssh {
+            user: None,
+            host: "-oProxyCommand=touch pwnedfile".to_string(),
+            port: None,
+            path: "repo".to_string(),
+        };
+        assert!(ssh_args(&url, "git-upload-pack", false, false).is_err());
+    }
+
+    #[test]
+    fn test_parse_responses() {
+        let advertisement = b"000eversion 2\n0015agent=git/2.39.5\n0013ls-refs=unborn\n0020fetch=shallow wait-for-done\n0000";
+        let mut reader = PktReader::new(&advertisement[..]);
+        let capabilities = Capabilities::read(&mut reader).unwrap();
+        assert!(capabilities.supports("fetch", "shallow"));
+        assert!(!capabilities.supports("fetch", "filter"));
+        assert_eq!(capabilities.get("ls-refs"), Some("unborn"));
+
+        let hash = "1".repeat(40);
+        let peeled = "2".repeat(40);
+        let mut response = Vec::new();
+        write_line(
+            &mut response,
+            &format!("{} HEAD symref-target:refs/heads/main", hash),
+        )
+        .unwrap();
+        write_line(
+            &mut response,
+            &format!("{} refs/tags/v1 peeled:{}", hash, peeled),
+        )
+        .unwrap();
+        write_line(&mut response, "unborn refs/heads/empty").unwrap();
+        write_flush(&mut response).unwrap();
+        let refs = read_ls_refs(
+            &mut PktReader::new(response.as_slice()),
+            HashAlgorithm::Sha1,
+        )
+        .unwrap();
+        assert_eq!(refs.len(), 2);
+        assert_eq!(refs[0].symref_target.as_deref(), Some("refs/heads/main"));
+        assert_eq!(
+            refs[1].peeled,
+            Some(ObjectHash::from_hex(&peeled, HashAlgorithm::Sha1).unwrap())
+        );
+
+        let mut response = Vec::new();
+        write_line(&mut response, "shallow-info").unwrap();
+        write_line(&mut response, &format!("shallow {}", hash)).unwrap();
+        write_delim(&mut response).unwrap();
+        write_line(&mut response, "packfile").unwrap();
+        crate::pktline::write_packet(&mut response, b"\x02counting\r").unwrap();
+        crate::pktline::write_packet(&mut response, b"\x01PACK").unwrap();
+        write_flush(&mut response).unwrap();
+        let response = read_fetch_response(
+            &mut PktReader::new(response.as_slice()),
+            HashAlgorithm::Sha1,
+            false,
+        )
+        .unwrap();
+        assert_eq!(response.pack, b"PACK");
+        assert_eq!(response.shallow.len(), 1);
+    }
+
+    #[test]
+    fn test_fetch_from_upload_pack() {
+        require_program("git-upload-pack");
+        let (source_dir, source) = init_repo();
+        commit_files(&source, &[("a.txt", "one\n")], "first");
+        let head = commit_files(&source, &[("a.txt", "two\n"), ("b.txt", "b\n")], "second");
+
+        let (_dir, repo) = init_repo();
+        let url = format!("file://{}", source_dir.path().display());
+        let options = FetchOptions {
+            refspecs: vec!["refs/heads/master:refs/remotes/origin/master".to_string()],
+            ..FetchOptions::default()
+        };
+        fetch(&repo, &url, &options).unwrap();
+        assert_eq!(
+            resolve_ref(&repo, "refs/remotes/origin/master").unwrap(),
+            Some(head.clone())
+        );
+        let commit = crate::commits::Commit::read(&repo, &head).unwrap();
+        assert!(object_exists(&repo, &commit.tree));
+        assert!(object_exists(&repo, &commit.parents[0]));
+    }
+}
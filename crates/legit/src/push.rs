@@ -0,0 +1,831 @@
+use crate::merge_base::is_ancestor;
+use crate::objects::{object_exists, HashAlgorithm, ObjectHash};
+use crate::pack_writer::{list_objects, write_pack};
+use crate::pktline::{encode, write_flush, write_line, Packet, PktReader};
+use crate::protocol::Connection;
+use crate::refs::{delete_ref, list_refs, read_head, resolve_ref, short_name, update_ref, Head};
+use crate::remote::{RefSpec, Remote};
+use crate::revision::{expand_ref_name, peel_to_commit, rev_parse};
+use crate::transport::Url;
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Read, Write};
+
+/// Width of the summary column of push output, e.g. `[new branch]`
+const SUMMARY_WIDTH: usize = 17;
+
+/// Lease is a `--force-with-lease` condition: a reference may only be
+/// overwritten while the remote still has the value we expect
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Lease {
+    /// The remote reference the lease is for; `None` covers every pushed one
+    pub refname: Option<String>,
+    /// The expected value; `None` takes the remote-tracking reference's
+    pub expect: Option<String>,
+}
+
+impl Lease {
+    /// Parse the value of `--force-with-lease`: empty, `<ref>` or
+    /// `<ref>:<expect>`
+    pub fn parse(value: &str) -> Lease {
+        match value.split_once(':') {
+            _ if value.is_empty() => Lease::default(),
+            Some((refname, expect)) => Lease {
+                refname: Some(refname.to_string()),
+                expect: Some(expect.to_string()),
+            },
+            None => Lease {
+                refname: Some(value.to_string()),
+                expect: None,
+            },
+        }
+    }
+}
+
+/// PushOptions controls what a push sends
+#[derive(Debug, Clone, Default)]
+pub struct PushOptions {
+    /// `[+]<src>:<dst>` specs; empty pushes the current branch upstream
+    pub refspecs: Vec<String>,
+    pub force: bool,
+    pub force_with_lease: Vec<Lease>,
+    /// Update every reference or none of them
+    pub atomic: bool,
+    /// Delete the references named by `refspecs`
+    pub delete: bool,
+    /// Strings passed to the remote's hooks
+    pub push_options: Vec<String>,
+    /// Make each pushed branch track the reference it was pushed to
+    pub set_upstream: bool,
+    /// Decide what to update without sending anything
+    pub dry_run: bool,
+    pub progress: bool,
+    /// Hide the messages of the remote's hooks
+    pub quiet: bool,
+}
+
+/// PushStatus is what happened to a remote reference during a push
+#[derive(Debug, Clone, PartialEq)]
+pub enum PushStatus {
+    /// The reference was created
+    New,
+    /// The reference was deleted
+    Deleted,
+    /// The reference was moved to a descendant of its old value
+    FastForward,
+    /// The reference was overwritten
+    Forced,
+    /// The reference already had the pushed value
+    UpToDate,
+    /// The update was refused before sending, e.g. `non-fast-forward`
+    Rejected(String),
+    /// The remote refused the update, e.g. `hook declined`
+    RemoteRejected(String),
+    /// The update was fine but another one of an atomic push was refused
+    AtomicFailed,
+}
+
+/// PushUpdate is a remote reference a push updates
+#[derive(Debug, Clone, PartialEq)]
+pub struct PushUpdate {
+    /// What was pushed as given, or its full reference name; `None` deletes
+    pub src: Option<String>,
+    pub dst: String,
+    pub old: Option<ObjectHash>,
+    pub new: Option<ObjectHash>,
+    pub status: PushStatus,
+}
+
+impl PushUpdate {
+    /// Return true if the remote reference was not updated as asked
+    pub fn is_rejected(&self) -> bool {
+        matches!(
+            self.status,
+            PushStatus::Rejected(_) | PushStatus::RemoteRejected(_) | PushStatus::AtomicFailed
+        )
+    }
+
+    fn is_pending(&self) -> bool {
+        !self.is_rejected() && self.status != PushStatus::UpToDate
+    }
+}
+
+/// PushResult lists what a push did
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PushResult {
+    pub url: String,
+    pub updates: Vec<PushUpdate>,
+    /// Branches that now track a remote one, with the tracking reference
+    pub upstreams: Vec<(String, String)>,
+}
+
+/// Push references to a remote through `git-receive-pack`, like `git push`
+///
+/// The remote is told which references to update, then sent a thin pack of
+/// the objects it lacks. Remote-tracking references of the updated ones
+/// follow their new values.
+pub fn push(repo: &Repository, remote: &str, options: &PushOptions) -> Result<PushResult> {
+    let remote = Remote::resolve(repo, remote)?;
+    let url = Url::parse(&remote.url)?;
+    let mut connection = Connection::open(&url, "git-receive-pack", false)?;
+    let (remote_refs, capabilities) = read_advertisement(connection.reader())?;
+
+    let specs = push_specs(repo, &remote, options)?;
+    let mut updates = Vec::new();
+    for spec in specs {
+        updates.extend(resolve_spec(repo, &spec, options, &remote_refs)?);
+    }
+    for update in &mut updates {
+        update.old = remote_refs.get(&update.dst).cloned();
+        update.status = check_update(repo, &remote, update, options)?;
+    }
+    if options.atomic && updates.iter().any(PushUpdate::is_rejected) {
+        for update in updates.iter_mut().filter(|u| u.is_pending()) {
+            update.status = PushStatus::AtomicFailed;
+        }
+    }
+
+    let mut result = PushResult {
+        url: remote.url.clone(),
+        updates,
+        upstreams: Vec::new(),
+    };
+    if options.dry_run {
+        return Ok(result);
+    }
+    match result.updates.iter().any(PushUpdate::is_pending) {
+        true => send_updates(
+            repo,
+            &mut connection,
+            &mut result.updates,
+            &capabilities,
+            &remote_refs,
+            options,
+        )?,
+        // An empty list of commands ends the session
+        false => connection.send(b"0000")?,
+    }
+    drop(connection);
+
+    for update in result.updates.iter().filter(|u| u.is_pending()) {
+        let Some(tracking) = tracking_ref(&remote, &update.dst) else {
+            continue;
+        };
+        match &update.new {
+            Some(new) => update_ref(repo, &tracking, new)?,
+            None => {
+                let _ = delete_ref(repo, &tracking);
+            }
+        }
+    }
+    if options.set_upstream {
+        result.upstreams = set_upstreams(repo, &remote, &result.updates)?;
+    }
+    Ok(result)
+}
+
+/// Read the references and capabilities `git-receive-pack` advertises
+///
+/// An empty repository advertises its capabilities on a
+/// `capabilities^{}` line instead of a reference.
+fn read_advertisement(
+    reader: &mut PktReader<impl Read>,
+) -> Result<(BTreeMap<String, ObjectHash>, HashSet<String>)> {
+    let mut refs = BTreeMap::new();
+    let mut capabilities = HashSet::new();
+    let mut algorithm = HashAlgorithm::Sha1;
+    let mut first = true;
+    while let Some(line) = reader.read_line()? {
+        let line = match line.split_once('\0') {
+            Some((line, caps)) if first => {
+                capabilities.extend(caps.split(' ').map(str::to_string));
+                if let Some(format) = caps
+                    .split(' ')
+                    .find_map(|c| c.strip_prefix("object-format="))
+                {
+                    algorithm = format.parse().with_context(|| {
+                        format!("The remote uses an unknown object format '{}'", format)
+                    })?;
+                }
+                line.to_string()
+            }
+            _ => line,
+        };
+        first = false;
+        let Some((hash, name)) = line.split_once(' ') else {
+            bail!("Invalid reference advertisement '{}'", line);
+        };
+        if name == "capabilities^{}" || name == ".have" {
+            continue;
+        }
+        refs.insert(name.to_string(), ObjectHash::from_hex(hash, algorithm)?);
+    }
+    Ok((refs, capabilities))
+}
+
+/// The specs to push: those given, or the current branch to its upstream
+fn push_specs(repo: &Repository, remote: &Remote, options: &PushOptions) -> Result<Vec<String>> {
+    if !options.refspecs.is_empty() {
+        return Ok(options.refspecs.clone());
+    }
+    if options.delete {
+        bail!("--delete doesn't make sense without any refs");
+    }
+    let Head::Branch(name) = read_head(repo)? else {
+        bail!("You are not currently on a branch.");
+    };
+    let branch = short_name(&name);
+    let config = repo.config()?;
+    let merge = config.get(&format!("branch.{}.merge", branch));
+    let upstream_remote = config.get(&format!("branch.{}.remote", branch));
+    match merge {
+        Some(merge) if upstream_remote == Some(remote.name.as_str()) => {
+            Ok(vec![format!("{}:{}", name, merge)])
+        }
+        _ if options.set_upstream || upstream_remote.is_some() => Ok(vec![name.clone()]),
+        _ => bail!(
+            "The current branch {} has no upstream branch.\n\
+             To push the current branch and set the remote as upstream, use\n\n    \
+             legit push --set-upstream {} {}\n",
+            branch,
+            remote.name,
+            branch
+        ),
+    }
+}
+
+/// Turn a push spec into updates of remote references
+///
+/// A pattern such as `refs/heads/*:refs/heads/*` pushes every matching
+/// local reference.
+fn resolve_spec(
+    repo: &Repository,
+    spec: &str,
+    options: &PushOptions,
+    remote_refs: &BTreeMap<String, ObjectHash>,
+) -> Result<Vec<PushUpdate>> {
+    let (force, spec) = match spec.strip_prefix('+') {
+        Some(spec) => (true, spec),
+        None => (options.force, spec),
+    };
+    let status = match force {
+        true => PushStatus::Forced,
+        false => PushStatus::FastForward,
+    };
+    let (src, dst) = match spec.split_once(':') {
+        _ if options.delete => ("", spec),
+        Some((src, dst)) => (src, dst),
+        None => (spec, spec),
+    };
+
+    if src.contains('*') {
+        let pattern = RefSpec::parse(spec)?;
+        return list_refs(repo, "refs/")?
+            .into_iter()
+            .filter_map(|(name, hash)| {
+                let dst = pattern.map(&name)?;
+                Some(PushUpdate {
+                    src: Some(name),
+                    dst,
+                    old: None,
+                    new: Some(hash),
+                    status: status.clone(),
+                })
+            })
+            .map(Ok)
+            .collect();
+    }
+
+    if src.is_empty() {
+        let dst = match dst.starts_with("refs/") {
+            true => dst.to_string(),
+            false => ["refs/heads/", "refs/tags/"]
+                .iter()
+                .map(|prefix| format!("{}{}", prefix, dst))
+                .find(|name| remote_refs.contains_key(name))
+                .unwrap_or_else(|| format!("refs/heads/{}", dst)),
+        };
+        return Ok(vec![PushUpdate {
+            src: None,
+            dst,
+            old: None,
+            new: None,
+            status,
+        }]);
+    }
+
+    let new = rev_parse(repo, src)
+        .ok()
+        .with_context(|| format!("src refspec {} does not match any", src))?;
+    let full_src = expand_ref_name(repo, src)?;
+    let dst = if dst.starts_with("refs/") {
+        dst.to_string()
+    } else if let Some(name) = ["refs/heads/", "refs/tags/"]
+        .iter()
+        .map(|prefix| format!("{}{}", prefix, dst))
+        .find(|name| remote_refs.contains_key(name))
+    {
+        name
+    } else {
+        match full_src.as_deref().and_then(|name| name.rsplit_once('/')) {
+            Some((prefix, _)) if prefix == "refs/heads" || prefix == "refs/tags" => {
+                format!("{}/{}", prefix, dst)
+            }
+            _ if full_src.as_deref() == Some("HEAD") => format!("refs/heads/{}", dst),
+            _ => bail!(
+                "The destination you provided is not a full refname (i.e.,\n\
+                 starting with \"refs/\"). Unable to push '{}' to '{}'",
+                src,
+                dst
+            ),
+        }
+    };
+    let src = match full_src {
+        Some(name) if name != "HEAD" || src == "HEAD" => name,
+        _ => src.to_string(),
+    };
+    Ok(vec![PushUpdate {
+        src: Some(src),
+        dst,
+        old: None,
+        new: Some(new),
+        status,
+    }])
+}
+
+/// Decide whether an update may be sent, given the remote's current value
+///
+/// On entry `update.status` is `Forced` when forcing was asked for.
+fn check_update(
+    repo: &Repository,
+    remote: &Remote,
+    update: &PushUpdate,
+    options: &PushOptions,
+) -> Result<PushStatus> {
+    let mut force = update.status == PushStatus::Forced;
+    let rejected = |reason: &str| Ok(PushStatus::Rejected(reason.to_string()));
+    if update.old == update.new {
+        return match update.new {
+            Some(_) => Ok(PushStatus::UpToDate),
+            None => rejected("remote ref does not exist"),
+        };
+    }
+    let lease = options
+        .force_with_lease
+        .iter()
+        .find(|lease| match &lease.refname {
+            Some(name) => name == &update.dst || short_name(&update.dst) == name,
+            None => true,
+        });
+    if let Some(lease) = lease {
+        let expected = match &lease.expect {
+            Some(expect) => Some(rev_parse(repo, expect)?),
+            None => match tracking_ref(remote, &update.dst) {
+                Some(tracking) => resolve_ref(repo, &tracking)?,
+                None => bail!(
+                    "cannot force-with-lease {}: no remote-tracking reference",
+                    update.dst
+                ),
+            },
+        };
+        if expected != update.old {
+            return rejected("stale info");
+        }
+        force = true;
+    }
+
+    let (Some(old), Some(new)) = (&update.old, &update.new) else {
+        return Ok(match update.new {
+            Some(_) => PushStatus::New,
+            None => PushStatus::Deleted,
+        });
+    };
+    if force {
+        return Ok(PushStatus::Forced);
+    }
+    if update.dst.starts_with("refs/tags/") {
+        return rejected("already exists");
+    }
+    if !object_exists(repo, old) {
+        return rejected("fetch first");
+    }
+    let fast_forward = match (peel_to_commit(repo, old), peel_to_commit(repo, new)) {
+        (Ok(old), Ok(new)) => is_ancestor(repo, &old, &new)?,
+        _ => false,
+    };
+    match fast_forward {
+        true => Ok(PushStatus::FastForward),
+        false => rejected("non-fast-forward"),
+    }
+}
+
+/// Send the pending updates and their objects, then read which ones the
+/// remote accepted
+fn send_updates(
+    repo: &Repository,
+    connection: &mut Connection,
+    updates: &mut [PushUpdate],
+    capabilities: &HashSet<String>,
+    remote_refs: &BTreeMap<String, ObjectHash>,
+    options: &PushOptions,
+) -> Result<()> {
+    let supports = |name: &str| capabilities.contains(name);
+    let mut wanted = vec!["report-status"];
+    if supports("side-band-64k") {
+        wanted.push("side-band-64k");
+    }
+    if options.atomic {
+        if !supports("atomic") {
+            bail!("the receiving end does not support --atomic push");
+        }
+        wanted.push("atomic");
+    }
+    if !options.push_options.is_empty() {
+        if !supports("push-options") {
+            bail!("the receiving end does not support push options");
+        }
+        wanted.push("push-options");
+    }
+    if !options.progress && supports("quiet") {
+        wanted.push("quiet");
+    }
+    let agent = format!("agent=legit/{}", env!("CARGO_PKG_VERSION"));
+    wanted.push(&agent);
+    let sideband = supports("side-band-64k");
+
+    let zero = repo.hash_algorithm().null_hash().to_hex();
+    let hex = |hash: &Option<ObjectHash>| hash.as_ref().map_or(zero.clone(), |h| h.to_hex());
+    let mut request = Vec::new();
+    for (i, update) in updates.iter().filter(|u| u.is_pending()).enumerate() {
+        let mut line = format!("{} {} {}", hex(&update.old), hex(&update.new), update.dst);
+        if i == 0 {
+            line = format!("{}\0{}", line, wanted.join(" "));
+        }
+        request.extend(encode(line.as_bytes())?);
+    }
+    write_flush(&mut request)?;
+    if !options.push_options.is_empty() {
+        for option in &options.push_options {
+            write_line(&mut request, option)?;
+        }
+        write_flush(&mut request)?;
+    }
+    let include = updates
+        .iter()
+        .filter(|u| u.is_pending())
+        .filter_map(|u| u.new.clone())
+        .collect::<Vec<_>>();
+    if !include.is_empty() {
+        let exclude = remote_refs.values().cloned().collect::<Vec<_>>();
+        let objects = list_objects(repo, &include, &exclude)?;
+        request.extend(write_pack(repo, &objects, true)?);
+    }
+    connection.send(&request)?;
+    connection.close_input();
+
+    let report = read_report(connection.reader(), sideband, options.quiet)?;
+    let mut lines = report.iter();
+    match lines.next().map(String::as_str) {
+        Some("unpack ok") => {}
+        Some(line) => {
+            let reason = line.strip_prefix("unpack ").unwrap_or(line);
+            for update in updates.iter_mut().filter(|u| u.is_pending()) {
+                update.status = PushStatus::RemoteRejected("unpacker error".to_string());
+            }
+            bail!("remote unpack failed: {}", reason);
+        }
+        None => bail!("the remote end hung up without reporting status"),
+    }
+    for line in lines {
+        let (name, reason) = match line.strip_prefix("ok ") {
+            Some(name) => (name, None),
+            None => match line.strip_prefix("ng ").and_then(|l| l.split_once(' ')) {
+                Some((name, reason)) => (name, Some(reason)),
+                None => bail!("Invalid status report '{}'", line),
+            },
+        };
+        let Some(reason) = reason else { continue };
+        for update in updates
+            .iter_mut()
+            .filter(|u| u.dst == name && u.is_pending())
+        {
+            update.status = PushStatus::RemoteRejected(reason.to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Read the remote's status report, demultiplexing it from its messages
+/// when side-band is used
+fn read_report(
+    reader: &mut PktReader<impl Read>,
+    sideband: bool,
+    quiet: bool,
+) -> Result<Vec<String>> {
+    let mut data = Vec::new();
+    if sideband {
+        let mut stderr = std::io::stderr();
+        while let Packet::Data(packet) = reader.read_packet()? {
+            match packet.split_first() {
+                Some((1, report)) => data.extend_from_slice(report),
+                Some((2, message)) if !quiet => {
+                    for line in String::from_utf8_lossy(message).split_inclusive(['\n', '\r']) {
+                        write!(stderr, "remote: {}", line)?;
+                    }
+                }
+                Some((2, _)) => {}
+                Some((3, message)) => bail!(
+                    "remote error: {}",
+                    String::from_utf8_lossy(message).trim_end()
+                ),
+                _ => bail!("Invalid side-band packet"),
+            }
+        }
+    } else {
+        while let Packet::Data(packet) = reader.read_packet()? {
+            data.extend(encode(&packet)?);
+        }
+    }
+    let mut lines = Vec::new();
+    if data.is_empty() {
+        return Ok(lines);
+    }
+    let mut report = PktReader::new(data.as_slice());
+    while let Some(line) = report.read_line()? {
+        lines.push(line);
+    }
+    Ok(lines)
+}
+
+/// The remote-tracking reference of a remote reference, if the remote's
+/// fetch refspecs map it to one
+fn tracking_ref(remote: &Remote, name: &str) -> Option<String> {
+    remote.fetch.iter().find_map(|spec| spec.map(name))
+}
+
+/// Make each pushed branch track the branch it was pushed to
+fn set_upstreams(
+    repo: &Repository,
+    remote: &Remote,
+    updates: &[PushUpdate],
+) -> Result<Vec<(String, String)>> {
+    let mut config = repo.config()?;
+    let mut upstreams = Vec::new();
+    for update in updates
+        .iter()
+        .filter(|u| !u.is_rejected() && u.new.is_some())
+    {
+        let Some(branch) = update
+            .src
+            .as_deref()
+            .and_then(|s| s.strip_prefix("refs/heads/"))
+        else {
+            continue;
+        };
+        if !update.dst.starts_with("refs/heads/") {
+            continue;
+        }
+        config.set(&format!("branch.{}.remote", branch), &remote.name)?;
+        config.set(&format!("branch.{}.merge", branch), &update.dst)?;
+        let upstream = match tracking_ref(remote, &update.dst) {
+            Some(tracking) => short_name(&tracking).to_string(),
+            None => format!("{} {}", short_name(&update.dst), remote.name),
+        };
+        upstreams.push((branch.to_string(), upstream));
+    }
+    repo.write_config(&config)?;
+    Ok(upstreams)
+}
+
+/// Format the result of a push as `git push` does
+pub fn format_push(result: &PushResult) -> String {
+    let shown = result
+        .updates
+        .iter()
+        .filter(|u| u.status != PushStatus::UpToDate)
+        .collect::<Vec<_>>();
+    if shown.is_empty() {
+        return "Everything up-to-date\n".to_string();
+    }
+    let mut out = format!("To {}\n", result.url);
+    let abbrev = |hash: &Option<ObjectHash>| match hash {
+        Some(hash) => hash.to_hex()[..7].to_string(),
+        None => String::new(),
+    };
+    for update in shown {
+        let kind = match update.dst.split('/').nth(1) {
+            Some("tags") => "tag",
+            Some("heads") => "branch",
+            _ => "reference",
+        };
+        let (flag, summary, reason) = match &update.status {
+            PushStatus::New => ('*', format!("[new {}]", kind), None),
+            PushStatus::Deleted => ('-', "[deleted]".to_string(), None),
+            PushStatus::FastForward => (
+                ' ',
+                format!("{}..{}", abbrev(&update.old), abbrev(&update.new)),
+                None,
+            ),
+            PushStatus::Forced => (
+                '+',
+                format!("{}...{}", abbrev(&update.old), abbrev(&update.new)),
+                Some("forced update"),
+            ),
+            PushStatus::Rejected(reason) => ('!', "[rejected]".to_string(), Some(reason.as_str())),
+            PushStatus::RemoteRejected(reason) => {
+                ('!', "[remote rejected]".to_string(), Some(reason.as_str()))
+            }
+            PushStatus::AtomicFailed => ('!', "[rejected]".to_string(), Some("atomic push failed")),
+            PushStatus::UpToDate => continue,
+        };
+        let refs = match &update.src {
+            Some(src) => format!("{} -> {}", short_name(src), short_name(&update.dst)),
+            None => short_name(&update.dst).to_string(),
+        };
+        out.push_str(&format!(
+            " {} {:<width$} {}",
+            flag,
+            summary,
+            refs,
+            width = SUMMARY_WIDTH
+        ));
+        if let Some(reason) = reason {
+            out.push_str(&format!(" ({})", reason));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commits::Commit;
+    use crate::test_utils::{commit_files, init_repo, require_program};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_push_to_receive_pack() {
+        require_program("git-receive-pack");
+        let (remote_dir, remote) = init_repo();
+        let base = commit_files(&remote, &[("a.txt", "one\n")], "first");
+        update_ref(&remote, "refs/heads/topic", &base).unwrap();
+
+        let (_dir, repo) = init_repo();
+        let url = remote_dir.path().display().to_string();
+        Remote::add(&repo, "origin", &url).unwrap();
+        crate::fetch::fetch(&repo, "origin", &Default::default()).unwrap();
+        update_ref(&repo, "refs/heads/topic", &base).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set("branch.topic.remote", "origin").unwrap();
+        config
+            .set("branch.topic.merge", "refs/heads/topic")
+            .unwrap();
+        repo.write_config(&config).unwrap();
+        crate::refs::update_symbolic_ref(&repo, "HEAD", "refs/heads/topic").unwrap();
+        let head = commit_files(&repo, &[("a.txt", "one\n"), ("b.txt", "b\n")], "second");
+
+        let result = push(&repo, "origin", &PushOptions::default()).unwrap();
+        assert_eq!(result.updates[0].status, PushStatus::FastForward);
+        assert_eq!(
+            resolve_ref(&remote, "refs/heads/topic").unwrap(),
+            Some(head.clone())
+        );
+        assert!(object_exists(
+            &remote,
+            &Commit::read(&repo, &head).unwrap().tree
+        ));
+        assert_eq!(
+            resolve_ref(&repo, "refs/remotes/origin/topic").unwrap(),
+            Some(head.clone())
+        );
+
+        // Rewinding needs force; deleting and creating need nothing
+        update_ref(&repo, "refs/heads/topic", &base).unwrap();
+        let rejected = push(&repo, "origin", &PushOptions::default()).unwrap();
+        assert_eq!(
+            rejected.updates[0].status,
+            PushStatus::Rejected("non-fast-forward".to_string())
+        );
+        assert!(format_push(&rejected)
+            .ends_with(" ! [rejected]        topic -> topic (non-fast-forward)\n"));
+        assert_eq!(
+            resolve_ref(&remote, "refs/heads/topic").unwrap(),
+            Some(head.clone())
+        );
+        let options = PushOptions {
+            refspecs: vec!["topic:other".to_string(), ":topic".to_string()],
+            atomic: true,
+            ..PushOptions::default()
+        };
+        let result = push(&repo, "origin", &options).unwrap();
+        assert_eq!(
+            format_push(&result),
+            format!(
+                "To {}\n * [new branch]      topic -> other\n - [deleted]         topic\n",
+                url
+            )
+        );
+        assert_eq!(resolve_ref(&remote, "refs/heads/topic").unwrap(), None);
+        assert_eq!(
+            resolve_ref(&repo, "refs/remotes/origin/topic").unwrap(),
+            None
+        );
+        assert_eq!(
+            resolve_ref(&remote, "refs/heads/other").unwrap(),
+            Some(base)
+        );
+    }
+
+    /// Make a remote with a `topic` branch and a repository that fetched it
+    fn cloned_remote() -> (TempDir, Repository, TempDir, Repository, ObjectHash) {
+        require_program("git-receive-pack");
+        let (remote_dir, remote) = init_repo();
+        let base = commit_files(&remote, &[("a.txt", "one\n")], "first");
+        update_ref(&remote, "refs/heads/topic", &base).unwrap();
+
+        let (dir, repo) = init_repo();
+        let url = remote_dir.path().display().to_string();
+        Remote::add(&repo, "origin", &url).unwrap();
+        crate::fetch::fetch(&repo, "origin", &Default::default()).unwrap();
+        (remote_dir, remote, dir, repo, base)
+    }
+
+    #[test]
+    fn test_push_with_lease() {
+        let (_remote_dir, remote, _dir, repo, _) = cloned_remote();
+        let ours = commit_files(&repo, &[("a.txt", "ours\n")], "ours");
+        // Someone else moved the branch since we fetched it
+        let theirs = commit_files(&remote, &[("a.txt", "theirs\n")], "theirs");
+        update_ref(&remote, "refs/heads/topic", &theirs).unwrap();
+
+        let mut options = PushOptions {
+            refspecs: vec![format!("{}:refs/heads/topic", ours)],
+            force_with_lease: vec![Lease::parse("topic")],
+            ..PushOptions::default()
+        };
+        let result = push(&repo, "origin", &options).unwrap();
+        assert_eq!(
+            result.updates[0].status,
+            PushStatus::Rejected("stale info".to_string())
+        );
+        assert_eq!(
+            resolve_ref(&remote, "refs/heads/topic").unwrap(),
+            Some(theirs.clone())
+        );
+
+        // A lease naming the value the remote has lets the push through
+        options.force_with_lease = vec![Lease::parse(&format!("topic:{}", theirs))];
+        let result = push(&repo, "origin", &options).unwrap();
+        assert_eq!(result.updates[0].status, PushStatus::Forced);
+        assert_eq!(
+            resolve_ref(&remote, "refs/heads/topic").unwrap(),
+            Some(ours.clone())
+        );
+    }
+
+    #[test]
+    fn test_push_delete_and_push_options() {
+        let (remote_dir, remote, _dir, repo, base) = cloned_remote();
+        let mut config = remote.config().unwrap();
+        config.set("receive.advertisePushOptions", "true").unwrap();
+        remote.write_config(&config).unwrap();
+        let hook = remote.gitdir().join("hooks/pre-receive");
+        std::fs::create_dir_all(hook.parent().unwrap()).unwrap();
+        std::fs::write(
+            &hook,
+            "#!/bin/sh\necho \"$GIT_PUSH_OPTION_COUNT $GIT_PUSH_OPTION_0 $GIT_PUSH_OPTION_1\" > options.txt\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&hook, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+
+        let options = PushOptions {
+            refspecs: vec!["refs/remotes/origin/master:refs/heads/copy".to_string()],
+            push_options: vec!["ci.skip".to_string(), "reviewer=me".to_string()],
+            ..PushOptions::default()
+        };
+        let result = push(&repo, "origin", &options).unwrap();
+        assert_eq!(result.updates[0].status, PushStatus::New);
+        let seen = std::fs::read_to_string(remote.gitdir().join("options.txt")).unwrap();
+        assert_eq!(seen, "2 ci.skip reviewer=me\n");
+
+        let options = PushOptions {
+            refspecs: vec!["topic".to_string()],
+            delete: true,
+            ..PushOptions::default()
+        };
+        let result = push(&repo, "origin", &options).unwrap();
+        assert_eq!(result.updates[0].status, PushStatus::Deleted);
+        assert_eq!(
+            format_push(&result),
+            format!(
+                "To {}\n - [deleted]         topic\n",
+                remote_dir.path().display()
+            )
+        );
+        assert_eq!(resolve_ref(&remote, "refs/heads/topic").unwrap(), None);
+        assert_eq!(resolve_ref(&remote, "refs/heads/copy").unwrap(), Some(base));
+    }
+}
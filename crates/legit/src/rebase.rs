@@ -0,0 +1,1610 @@
+use crate::checkout::{self, checkout_tree, FileMap};
+use crate::commits::{Commit, Signature};
+use crate::editor;
+use crate::index::Index;
+use crate::merge::{
+    merge_in_progress, merge_trees, record_conflicts, reset_merge, strip_comments, update_files,
+    virtual_base, ConflictLabels, ConflictStyle, TreeMerge,
+};
+use crate::merge_base::merge_bases;
+use crate::objects::ObjectHash;
+use crate::refs::{
+    delete_ref, list_refs, read_head, resolve_ref, set_head, update_head, update_ref, write_atomic,
+    Head,
+};
+use crate::revision::{peel_to_commit, rev_list, rev_parse};
+use crate::sequencer::{apply_commit, sequencer_in_progress, Action};
+use crate::status::{self, upstream_of, StatusOptions, UntrackedFiles};
+use crate::tree::flatten_tree;
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command as Process;
+
+const REBASE_DIR: &str = "rebase-merge";
+const TODO: &str = "git-rebase-todo";
+const REBASE_HEAD: &str = "REBASE_HEAD";
+const MERGE_HEAD: &str = "MERGE_HEAD";
+const MERGE_MSG: &str = "MERGE_MSG";
+/// Files describing the step a rebase stopped at
+const STOP_FILES: [&str; 5] = [
+    "message",
+    "author-script",
+    "amend",
+    "stopped-sha",
+    "current-fixups",
+];
+
+const TODO_HELP: &str = "
+# Commands:
+# p, pick <commit> = use commit
+# r, reword <commit> = use commit, but edit the commit message
+# e, edit <commit> = use commit, but stop for amending
+# s, squash <commit> = use commit, but meld into previous commit
+# f, fixup [-C | -c] <commit> = like \"squash\" but keep only the previous
+#                    commit's log message, unless -C is used, in which case
+#                    keep only this commit's message; -c is same as -C but
+#                    opens the editor
+# x, exec <command> = run command (the rest of the line) using shell
+# b, break = stop here (continue rebase later with 'legit rebase --continue')
+# d, drop <commit> = remove commit
+# l, label <label> = label current HEAD with a name
+# t, reset <label> = reset HEAD to a label
+# m, merge [-C <commit> | -c <commit>] <label> [# <oneline>]
+#         create a merge commit using the original merge commit's
+#         message (or the oneline, if no original merge commit was
+#         specified); use -c <commit> to reword the commit message
+# u, update-ref <ref> = track a placeholder for the <ref> to be updated
+#                       to this position in the new commits. The <ref> is
+#                       updated at the end of the rebase
+#
+# These lines can be re-ordered; they are executed from top to bottom.
+#
+# If you remove a line here THAT COMMIT WILL BE LOST.
+#
+# However, if you remove everything, the rebase will be aborted.
+#
+";
+
+/// Command is a line of a rebase todo list
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Pick(ObjectHash),
+    /// Pick the commit and edit its message
+    Reword(ObjectHash),
+    /// Pick the commit and stop so that it can be amended
+    Edit(ObjectHash),
+    /// Meld the commit into the previous one, combining their messages
+    Squash(ObjectHash),
+    /// Meld the commit into the previous one, keeping the previous message
+    /// unless `replace_message` (`-C`, or `-c` which also opens the editor)
+    Fixup {
+        commit: ObjectHash,
+        replace_message: bool,
+        edit: bool,
+    },
+    /// Run a shell command
+    Exec(String),
+    /// Stop and let the user continue the rebase later
+    Break,
+    Drop(ObjectHash),
+    /// Name the current HEAD, as `refs/rewritten/<label>`
+    Label(String),
+    /// Move HEAD to a label or revision
+    Reset(String),
+    /// Merge a label or revision into HEAD, reusing the message of the
+    /// original merge commit when there is one
+    Merge {
+        commit: Option<ObjectHash>,
+        edit: bool,
+        label: String,
+    },
+    /// Move a reference to the current HEAD once the rebase finishes
+    UpdateRef(String),
+}
+
+impl Command {
+    fn is_fixup(&self) -> bool {
+        matches!(self, Command::Squash(_) | Command::Fixup { .. })
+    }
+}
+
+/// RebaseOptions selects what `rebase` replays and how
+#[derive(Debug, Clone, Default)]
+pub struct RebaseOptions {
+    /// The commits reachable from the upstream are not replayed; defaults to
+    /// the upstream of the current branch
+    pub upstream: Option<String>,
+    /// Where to replay the commits, the upstream by default
+    pub onto: Option<String>,
+    /// Switch to this branch before rebasing it
+    pub branch: Option<String>,
+    /// Let the user edit the todo list before it runs
+    pub interactive: bool,
+    /// Move `fixup!`, `squash!` and `amend!` commits after their target
+    pub autosquash: bool,
+    /// Shell commands to run after each replayed commit
+    pub exec: Vec<String>,
+    /// Also move branches pointing at replayed commits
+    pub update_refs: bool,
+    /// Recreate merge commits instead of flattening the history
+    pub rebase_merges: bool,
+}
+
+/// RebaseStop tells why a rebase stopped before the end of its todo list
+#[derive(Debug)]
+pub enum RebaseStop {
+    /// A commit did not apply cleanly; the conflicts are left in the index
+    Conflict {
+        commit: ObjectHash,
+        summary: String,
+        result: TreeMerge,
+    },
+    /// An `edit` line stopped so that the commit can be amended
+    Edit { commit: ObjectHash, summary: String },
+    /// A `break` line
+    Break,
+    /// An `exec` command failed
+    Exec { command: String },
+}
+
+/// RebaseOutcome is the result of running or resuming a rebase
+#[derive(Debug)]
+pub enum RebaseOutcome {
+    /// There was nothing to replay
+    UpToDate {
+        head_name: String,
+    },
+    /// The rebase finished; `updated_refs` lists the refs moved by `update-ref`
+    Rebased {
+        head_name: String,
+        updated_refs: Vec<String>,
+    },
+    Stopped(RebaseStop),
+}
+
+/// State is what `.git/rebase-merge` records about the rebase as a whole
+struct State {
+    /// The branch being rebased, or `detached HEAD`
+    head_name: String,
+    onto: ObjectHash,
+    orig_head: ObjectHash,
+}
+
+/// Replay the commits of the current branch on top of another commit, like
+/// `git rebase`
+///
+/// The state is kept in `.git/rebase-merge` in git's format, so a rebase that
+/// stops can be resumed by either legit or git.
+pub fn rebase(repo: &Repository, options: &RebaseOptions) -> Result<RebaseOutcome> {
+    if rebase_in_progress(repo) {
+        bail!("It seems that there is already a rebase-merge directory, and\nI wonder if you are in the middle of another rebase.  If that is the\ncase, please try\n\tlegit rebase (--continue | --abort | --skip)");
+    }
+    if merge_in_progress(repo) || sequencer_in_progress(repo) {
+        bail!("cannot rebase: a merge, cherry-pick or revert is in progress");
+    }
+    if let Some(branch) = &options.branch {
+        checkout::switch(repo, branch, &Default::default())?;
+    }
+    let head_name = match read_head(repo)? {
+        Head::Branch(name) => name,
+        Head::Detached(_) => "detached HEAD".to_string(),
+    };
+    let Some(head) = resolve_ref(repo, "HEAD")? else {
+        bail!("cannot rebase: the current branch has no commits");
+    };
+    check_clean(repo)?;
+
+    let resolve = |spec: &str| {
+        peel_to_commit(repo, &rev_parse(repo, spec)?)
+            .with_context(|| format!("invalid upstream '{}'", spec))
+    };
+    let upstream = match &options.upstream {
+        Some(spec) => resolve(spec)?,
+        None => {
+            let branch = head_name.strip_prefix("refs/heads/").unwrap_or_default();
+            match upstream_of(repo, branch)? {
+                Some((name, _)) => resolve(&name)?,
+                None => bail!("There is no tracking information for the current branch.\nPlease specify which branch you want to rebase against."),
+            }
+        }
+    };
+    let onto = match &options.onto {
+        Some(spec) => resolve(spec)?,
+        None => upstream.clone(),
+    };
+
+    let forced = options.interactive || !options.exec.is_empty() || options.rebase_merges;
+    if !forced && is_up_to_date(repo, &onto, &upstream, &head)? {
+        return Ok(RebaseOutcome::UpToDate { head_name });
+    }
+
+    let mut commits = rev_list(repo, std::slice::from_ref(&head), &[upstream])?;
+    commits.reverse();
+    let mut todo = match options.rebase_merges {
+        true => merges_script(repo, &commits)?,
+        false => {
+            let mut picks = Vec::new();
+            for hash in commits {
+                if Commit::read(repo, &hash)?.parents.len() <= 1 {
+                    picks.push(Command::Pick(hash));
+                }
+            }
+            if options.autosquash {
+                autosquash(repo, picks)?
+            } else {
+                picks
+            }
+        }
+    };
+    if options.update_refs {
+        add_update_refs(repo, &head_name, &mut todo)?;
+    }
+    add_exec(&mut todo, &options.exec);
+
+    let dir = rebase_dir(repo);
+    fs::create_dir_all(&dir)?;
+    let state = State {
+        head_name,
+        onto: onto.clone(),
+        orig_head: head,
+    };
+    write_state(repo, &state)?;
+    let lines = todo
+        .iter()
+        .map(|command| format_command(repo, command, false))
+        .collect::<Result<Vec<_>>>()?;
+    write_todo(repo, &lines)?;
+    if options.interactive {
+        write_atomic(&dir.join("interactive"), b"")?;
+        let lines = match edit_todo(repo, &state, &todo) {
+            Ok(lines) => lines,
+            Err(e) => {
+                remove_state(repo)?;
+                return Err(e);
+            }
+        };
+        write_todo(repo, &lines)?;
+    }
+    write_atomic(
+        &dir.join(format!("{}.backup", TODO)),
+        fs::read(dir.join(TODO))?.as_slice(),
+    )?;
+    write_atomic(&dir.join("done"), b"")?;
+    init_update_refs(repo)?;
+
+    let onto_tree = Commit::read(repo, &onto)?.tree;
+    checkout_tree(repo, &onto_tree, false)?;
+    set_head(repo, &Head::Detached(onto))?;
+    run(repo, &state)
+}
+
+/// Return true if a rebase stopped and was not concluded
+pub fn rebase_in_progress(repo: &Repository) -> bool {
+    rebase_dir(repo).is_dir()
+}
+
+/// Commit the resolved step the rebase stopped at and run the rest of the
+/// todo list
+///
+/// When the rebase stopped at an `edit` line, staged changes are folded into
+/// the commit being edited.
+pub fn rebase_continue(repo: &Repository) -> Result<RebaseOutcome> {
+    let state = read_state(repo)?;
+    let dir = rebase_dir(repo);
+    let gitdir = repo.gitdir();
+    let index = Index::read(repo)?;
+    if index.has_conflicts() {
+        bail!("You must edit all merge conflicts and then\nmark them as resolved using legit add");
+    }
+    let head = resolve_ref(repo, "HEAD")?.context("HEAD does not point to a commit")?;
+    let head_commit = Commit::read(repo, &head)?;
+    let tree = index.write_tree(repo)?;
+    let staged = tree != head_commit.tree;
+    let last = read_lines(&dir.join("done"))?
+        .last()
+        .map(|line| parse_command(repo, line))
+        .transpose()?
+        .flatten();
+
+    let message = fs::read_to_string(dir.join("message"))
+        .ok()
+        .map(|m| strip_comments(&m));
+    if let Ok(amend) = fs::read_to_string(dir.join("amend")) {
+        if staged {
+            if amend.trim() != head.to_hex() {
+                bail!("You have uncommitted changes in your working tree. Please, commit them\nfirst and then run 'legit rebase --continue' again.");
+            }
+            let message = message.unwrap_or_else(|| head_commit.message.clone());
+            let mut commit = Commit::new(repo, tree, head_commit.parents.clone(), &message)?;
+            commit.author = head_commit.author.clone();
+            update_head(repo, &commit.write(repo)?)?;
+        }
+        if last.as_ref().is_some_and(Command::is_fixup) {
+            let todo = read_lines(&dir.join(TODO))?;
+            finish_fixups(repo, &todo)?;
+        }
+    } else if staged || gitdir.join(MERGE_HEAD).exists() {
+        let message = message
+            .or_else(|| fs::read_to_string(gitdir.join(MERGE_MSG)).ok())
+            .map(|m| strip_comments(&m))
+            .unwrap_or_default();
+        if message.is_empty() {
+            bail!("Aborting commit due to empty commit message.");
+        }
+        let mut parents = vec![head];
+        if let Ok(merge_head) = fs::read_to_string(gitdir.join(MERGE_HEAD)) {
+            parents.push(
+                ObjectHash::from_hex(merge_head.trim(), repo.hash_algorithm())
+                    .context("Invalid MERGE_HEAD")?,
+            );
+        }
+        let mut commit = Commit::new(repo, tree, parents, &message)?;
+        if let Some(author) = read_author_script(repo)? {
+            commit.author = author;
+        }
+        update_head(repo, &commit.write(repo)?)?;
+        if matches!(
+            last,
+            Some(Command::Reword(_)) | Some(Command::Merge { edit: true, .. })
+        ) {
+            reword_head(repo)?;
+        }
+    }
+    remove_stop_files(repo)?;
+    run(repo, &state)
+}
+
+/// Drop the step the rebase stopped at and run the rest of the todo list
+pub fn rebase_skip(repo: &Repository) -> Result<RebaseOutcome> {
+    let state = read_state(repo)?;
+    let head = match checkout::head_tree(repo)? {
+        Some(tree) => flatten_tree(repo, &tree)?,
+        None => FileMap::new(),
+    };
+    reset_merge(repo, &mut Index::read(repo)?, &head)?;
+    remove_stop_files(repo)?;
+    run(repo, &state)
+}
+
+/// Abandon the rebase, restoring the branch, index and working tree to
+/// their state before it started
+pub fn rebase_abort(repo: &Repository) -> Result<()> {
+    let state = read_state(repo)?;
+    let target = flatten_tree(repo, &Commit::read(repo, &state.orig_head)?.tree)?;
+    reset_merge(repo, &mut Index::read(repo)?, &target)?;
+    match state.head_name.starts_with("refs/") {
+        true => set_head(repo, &Head::Branch(state.head_name.clone()))?,
+        false => set_head(repo, &Head::Detached(state.orig_head.clone()))?,
+    }
+    remove_stop_files(repo)?;
+    remove_state(repo)
+}
+
+/// Return true when replaying would recreate the same commits: the branch
+/// already sits on top of `onto` and `upstream` forks at `onto`
+fn is_up_to_date(
+    repo: &Repository,
+    onto: &ObjectHash,
+    upstream: &ObjectHash,
+    head: &ObjectHash,
+) -> Result<bool> {
+    if merge_bases(repo, onto, head)?.first() != Some(onto) {
+        return Ok(false);
+    }
+    Ok(upstream == onto || merge_bases(repo, upstream, head)?.first() == Some(onto))
+}
+
+fn check_clean(repo: &Repository) -> Result<()> {
+    let options = StatusOptions {
+        untracked: UntrackedFiles::No,
+        ..Default::default()
+    };
+    let status = status::status(repo, &options)?;
+    if !status.conflicts.is_empty() {
+        bail!("cannot rebase: You have unmerged files.");
+    }
+    if status
+        .changes
+        .iter()
+        .any(|c| c.unstaged != status::StatusCode::Unmodified)
+    {
+        bail!("cannot rebase: You have unstaged changes.\nPlease commit or stash them.");
+    }
+    if !status.changes.is_empty() {
+        bail!(
+            "cannot rebase: Your index contains uncommitted changes.\nPlease commit or stash them."
+        );
+    }
+    Ok(())
+}
+
+/// Run the todo list until it is empty or a step stops the rebase
+fn run(repo: &Repository, state: &State) -> Result<RebaseOutcome> {
+    let dir = rebase_dir(repo);
+    let mut todo = read_lines(&dir.join(TODO))?;
+    let mut done = read_lines(&dir.join("done"))?;
+    while !todo.is_empty() {
+        let line = todo.remove(0);
+        done.push(line.clone());
+        write_todo(repo, &todo)?;
+        write_atomic(&dir.join("done"), lines_text(&done).as_bytes())?;
+        write_atomic(&dir.join("msgnum"), format!("{}\n", done.len()).as_bytes())?;
+        write_atomic(
+            &dir.join("end"),
+            format!("{}\n", done.len() + todo.len()).as_bytes(),
+        )?;
+
+        let Some(command) = parse_command(repo, &line)? else {
+            continue;
+        };
+        if !command.is_fixup() {
+            remove_file(&dir.join("current-fixups"))?;
+        }
+        if let Some(stop) = execute(repo, &command, &todo)? {
+            return Ok(RebaseOutcome::Stopped(stop));
+        }
+    }
+    finish(repo, state)
+}
+
+/// Run one todo command, returning why the rebase has to stop, if it does
+fn execute(repo: &Repository, command: &Command, todo: &[String]) -> Result<Option<RebaseStop>> {
+    let dir = rebase_dir(repo);
+    let head = resolve_ref(repo, "HEAD")?.context("HEAD does not point to a commit")?;
+    match command {
+        Command::Pick(hash) | Command::Reword(hash) | Command::Edit(hash) => {
+            let picked = Commit::read(repo, hash)?;
+            if picked.parents.first() == Some(&head) {
+                // The commit already sits on HEAD; keep it as it is
+                move_head(repo, hash)?;
+            } else {
+                let result = apply_commit(repo, Action::Pick, hash, &picked, None, "rebase")?;
+                if !result.conflicts.is_empty() {
+                    return conflict_stop(repo, hash, &picked, result);
+                }
+                let tree = result.write_tree(repo)?;
+                let was_empty = match picked.parents.first() {
+                    Some(parent) => Commit::read(repo, parent)?.tree == picked.tree,
+                    None => false,
+                };
+                if tree == Commit::read(repo, &head)?.tree && !was_empty {
+                    // Drop commits whose changes are already upstream
+                    return Ok(None);
+                }
+                let mut commit = Commit::new(repo, tree, vec![head], &picked.message)?;
+                commit.author = picked.author.clone();
+                update_head(repo, &commit.write(repo)?)?;
+            }
+            match command {
+                Command::Reword(_) => reword_head(repo)?,
+                Command::Edit(_) => {
+                    let new_head = resolve_ref(repo, "HEAD")?.context("HEAD is missing")?;
+                    write_stop_files(repo, hash, &picked)?;
+                    write_atomic(&dir.join("amend"), format!("{}\n", new_head).as_bytes())?;
+                    return Ok(Some(RebaseStop::Edit {
+                        commit: hash.clone(),
+                        summary: picked.summary().to_string(),
+                    }));
+                }
+                _ => {}
+            }
+        }
+        Command::Squash(hash) | Command::Fixup { commit: hash, .. } => {
+            let picked = Commit::read(repo, hash)?;
+            let head_commit = Commit::read(repo, &head)?;
+            let message = match command {
+                Command::Squash(_) => {
+                    // Like git, leave out the subject of `squash!` commits
+                    let body = match ["squash!", "fixup!", "amend!"]
+                        .iter()
+                        .any(|prefix| picked.message.starts_with(prefix))
+                    {
+                        true => picked.message.split_once('\n').map_or("", |(_, b)| b),
+                        false => &picked.message,
+                    };
+                    match body.trim() {
+                        "" => head_commit.message.clone(),
+                        body => format!("{}\n\n{}\n", head_commit.message.trim_end(), body),
+                    }
+                }
+                Command::Fixup {
+                    replace_message: true,
+                    ..
+                } => match picked.message.strip_prefix("amend! ") {
+                    // The body of an `amend!` commit is the replacement message
+                    Some(rest) => rest
+                        .split_once('\n')
+                        .map_or("", |(_, body)| body)
+                        .trim_start_matches('\n')
+                        .to_string(),
+                    None => picked.message.clone(),
+                },
+                _ => head_commit.message.clone(),
+            };
+            // Like git, list the melded commits as `fixup <hash>` or `squash <hash>`
+            let mut fixups = fs::read_to_string(dir.join("current-fixups")).unwrap_or_default();
+            if !fixups.is_empty() {
+                fixups.push('\n');
+            }
+            let kind = match command {
+                Command::Squash(_) => "squash",
+                _ => "fixup",
+            };
+            fixups.push_str(&format!("{} {}", kind, hash));
+            write_atomic(&dir.join("current-fixups"), fixups.as_bytes())?;
+
+            let result = apply_commit(repo, Action::Pick, hash, &picked, None, "rebase")?;
+            if !result.conflicts.is_empty() {
+                let stop = conflict_stop(repo, hash, &picked, result)?;
+                write_atomic(&dir.join("message"), message.as_bytes())?;
+                write_atomic(&dir.join("amend"), format!("{}\n", head).as_bytes())?;
+                return Ok(stop);
+            }
+            let mut commit = Commit::new(
+                repo,
+                result.write_tree(repo)?,
+                head_commit.parents.clone(),
+                &message,
+            )?;
+            commit.author = head_commit.author.clone();
+            update_head(repo, &commit.write(repo)?)?;
+            if let Command::Fixup { edit: true, .. } = command {
+                reword_head(repo)?;
+            }
+            finish_fixups(repo, todo)?;
+        }
+        Command::Exec(command) => {
+            let status = Process::new("sh")
+                .arg("-c")
+                .arg(command)
+                .current_dir(repo.worktree())
+                .status()
+                .with_context(|| format!("Failed to run '{}'", command))?;
+            if !status.success() {
+                return Ok(Some(RebaseStop::Exec {
+                    command: command.clone(),
+                }));
+            }
+        }
+        Command::Break => return Ok(Some(RebaseStop::Break)),
+        Command::Drop(_) => {}
+        Command::Label(label) => {
+            update_ref(repo, &format!("refs/rewritten/{}", label), &head)?;
+        }
+        Command::Reset(label) => {
+            let target = resolve_label(repo, label)?;
+            move_head(repo, &target)?;
+        }
+        Command::Merge {
+            commit,
+            edit,
+            label,
+        } => return merge(repo, &head, commit.as_ref(), *edit, label),
+        Command::UpdateRef(name) => {
+            let mut refs = read_update_refs(repo)?;
+            match refs.iter_mut().find(|(n, _, _)| n == name) {
+                Some(entry) => entry.2 = Some(head),
+                None => {
+                    let old = resolve_ref(repo, name)?;
+                    refs.push((name.clone(), old, Some(head)));
+                }
+            }
+            write_update_refs(repo, &refs)?;
+        }
+    }
+    Ok(None)
+}
+
+/// Recreate a merge of `label` into HEAD
+fn merge(
+    repo: &Repository,
+    head: &ObjectHash,
+    original: Option<&ObjectHash>,
+    edit: bool,
+    label: &str,
+) -> Result<Option<RebaseStop>> {
+    let other = resolve_label(repo, label)?;
+    let original = original.map(|hash| Ok::<_, anyhow::Error>((hash, Commit::read(repo, hash)?)));
+    let original = original.transpose()?;
+    if let Some((hash, commit)) = &original {
+        if !edit && commit.parents == [head.clone(), other.clone()] {
+            // Nothing was rewritten below the merge; keep it
+            move_head(repo, hash)?;
+            return Ok(None);
+        }
+    }
+
+    let message = match &original {
+        Some((_, commit)) => commit.message.clone(),
+        None => format!("Merge branch '{}'\n", label),
+    };
+    let style = ConflictStyle::from_config(repo)?;
+    let bases = merge_bases(repo, head, &other)?;
+    let base_tree = virtual_base(repo, &bases, style)?;
+    let labels = ConflictLabels {
+        ours: "HEAD".to_string(),
+        base: "merged common ancestors".to_string(),
+        theirs: label.to_string(),
+    };
+    let head_tree = Commit::read(repo, head)?.tree;
+    let result = merge_trees(
+        repo,
+        base_tree.as_ref(),
+        &head_tree,
+        &Commit::read(repo, &other)?.tree,
+        &labels,
+        style,
+    )?;
+    let mut index = Index::read(repo)?;
+    let current = flatten_tree(repo, &head_tree)?;
+    update_files(repo, &mut index, &current, &result.files, "rebase")?;
+
+    if !result.conflicts.is_empty() {
+        record_conflicts(&mut index, &result.conflicts)?;
+        index.write(repo)?;
+        let dir = rebase_dir(repo);
+        let gitdir = repo.gitdir();
+        write_atomic(&gitdir.join(MERGE_HEAD), format!("{}\n", other).as_bytes())?;
+        write_atomic(&gitdir.join(MERGE_MSG), message.as_bytes())?;
+        write_atomic(&dir.join("message"), message.as_bytes())?;
+        if let Some((hash, commit)) = &original {
+            write_atomic(&gitdir.join(REBASE_HEAD), format!("{}\n", hash).as_bytes())?;
+            write_author_script(repo, &commit.author)?;
+        }
+        let (commit, summary) = match &original {
+            Some((hash, commit)) => ((*hash).clone(), commit.summary().to_string()),
+            None => (other, format!("Merge branch '{}'", label)),
+        };
+        return Ok(Some(RebaseStop::Conflict {
+            commit,
+            summary,
+            result,
+        }));
+    }
+
+    let mut commit = Commit::new(
+        repo,
+        result.write_tree(repo)?,
+        vec![head.clone(), other],
+        &message,
+    )?;
+    if let Some((_, original)) = &original {
+        commit.author = original.author.clone();
+    }
+    update_head(repo, &commit.write(repo)?)?;
+    if edit {
+        reword_head(repo)?;
+    }
+    Ok(None)
+}
+
+/// Record the state of a step that stopped with conflicts
+fn conflict_stop(
+    repo: &Repository,
+    hash: &ObjectHash,
+    picked: &Commit,
+    result: TreeMerge,
+) -> Result<Option<RebaseStop>> {
+    write_stop_files(repo, hash, picked)?;
+    let mut merge_msg = format!("{}\n# Conflicts:\n", picked.message);
+    for conflict in &result.conflicts {
+        merge_msg.push_str(&format!("#\t{}\n", conflict.path));
+    }
+    write_atomic(&repo.gitdir().join(MERGE_MSG), merge_msg.as_bytes())?;
+    Ok(Some(RebaseStop::Conflict {
+        commit: hash.clone(),
+        summary: picked.summary().to_string(),
+        result,
+    }))
+}
+
+fn write_stop_files(repo: &Repository, hash: &ObjectHash, picked: &Commit) -> Result<()> {
+    let dir = rebase_dir(repo);
+    write_atomic(
+        &repo.gitdir().join(REBASE_HEAD),
+        format!("{}\n", hash).as_bytes(),
+    )?;
+    write_atomic(&dir.join("stopped-sha"), format!("{}\n", hash).as_bytes())?;
+    write_atomic(&dir.join("message"), picked.message.as_bytes())?;
+    write_author_script(repo, &picked.author)
+}
+
+/// Open the editor on the combined message once a chain of squash and
+/// fixup commands ends, if one of them asked for it
+fn finish_fixups(repo: &Repository, todo: &[String]) -> Result<()> {
+    let next_is_fixup = match todo.first() {
+        Some(line) => parse_command(repo, line)?.is_some_and(|c| c.is_fixup()),
+        None => false,
+    };
+    if next_is_fixup {
+        return Ok(());
+    }
+    let path = rebase_dir(repo).join("current-fixups");
+    let fixups = fs::read_to_string(&path).unwrap_or_default();
+    if fixups.lines().any(|line| line.starts_with("squash")) {
+        reword_head(repo)?;
+    }
+    remove_file(&path)
+}
+
+/// Let the user edit the message of the HEAD commit and amend it
+fn reword_head(repo: &Repository) -> Result<()> {
+    let head = resolve_ref(repo, "HEAD")?.context("HEAD does not point to a commit")?;
+    let mut commit = Commit::read(repo, &head)?;
+    let message = editor::edit_message(repo, &commit.message)?;
+    if message != commit.message {
+        commit.message = message;
+        commit.committer = Signature::identity(repo, "COMMITTER")?.to_string();
+        update_head(repo, &commit.write(repo)?)?;
+    }
+    Ok(())
+}
+
+/// Move HEAD, the index and the working tree to a commit
+fn move_head(repo: &Repository, target: &ObjectHash) -> Result<()> {
+    let current = match checkout::head_tree(repo)? {
+        Some(tree) => flatten_tree(repo, &tree)?,
+        None => FileMap::new(),
+    };
+    let files = flatten_tree(repo, &Commit::read(repo, target)?.tree)?;
+    update_files(repo, &mut Index::read(repo)?, &current, &files, "rebase")?;
+    update_head(repo, target)
+}
+
+fn resolve_label(repo: &Repository, label: &str) -> Result<ObjectHash> {
+    if let Some(hash) = resolve_ref(repo, &format!("refs/rewritten/{}", label))? {
+        return Ok(hash);
+    }
+    peel_to_commit(repo, &rev_parse(repo, label)?)
+        .with_context(|| format!("could not resolve '{}'", label))
+}
+
+/// Point the rebased branch at the result and move the refs recorded by
+/// `update-ref` lines
+fn finish(repo: &Repository, state: &State) -> Result<RebaseOutcome> {
+    let head = resolve_ref(repo, "HEAD")?.context("HEAD does not point to a commit")?;
+    if state.head_name.starts_with("refs/") {
+        update_ref(repo, &state.head_name, &head)?;
+        set_head(repo, &Head::Branch(state.head_name.clone()))?;
+    }
+    write_atomic(
+        &repo.gitdir().join("ORIG_HEAD"),
+        format!("{}\n", state.orig_head).as_bytes(),
+    )?;
+
+    let mut updated_refs = Vec::new();
+    for (name, _, new) in read_update_refs(repo)? {
+        if let Some(new) = new {
+            update_ref(repo, &name, &new)?;
+            updated_refs.push(name);
+        }
+    }
+    remove_stop_files(repo)?;
+    remove_state(repo)?;
+    Ok(RebaseOutcome::Rebased {
+        head_name: state.head_name.clone(),
+        updated_refs,
+    })
+}
+
+/// Open the sequence editor on the todo list and return the edited lines,
+/// with abbreviated hashes expanded
+fn edit_todo(repo: &Repository, state: &State, todo: &[Command]) -> Result<Vec<String>> {
+    let mut text = String::new();
+    for command in todo {
+        text.push_str(&format_command(repo, command, true)?);
+        text.push('\n');
+    }
+    let onto = &state.onto.to_hex()[..7];
+    text.push_str(&format!(
+        "\n# Rebase {}..{} onto {} ({} command{})\n#",
+        onto,
+        &state.orig_head.to_hex()[..7],
+        onto,
+        todo.len(),
+        if todo.len() == 1 { "" } else { "s" }
+    ));
+    text.push_str(TODO_HELP);
+
+    let path = rebase_dir(repo).join(TODO);
+    write_atomic(&path, text.as_bytes())?;
+    editor::launch(repo, &editor::sequence_editor(repo)?, &path)?;
+    let mut lines = Vec::new();
+    for line in read_lines(&path)? {
+        if let Some(command) = parse_command(repo, &line)
+            .with_context(|| format!("invalid line in the todo list: {}", line))?
+        {
+            lines.push(format_command(repo, &command, false)?);
+        }
+    }
+    if lines.is_empty() {
+        bail!("Nothing to do");
+    }
+    Ok(lines)
+}
+
+/// Parse a todo line, returning `None` for `noop`
+fn parse_command(repo: &Repository, line: &str) -> Result<Option<Command>> {
+    let resolve = |spec: &str| -> Result<ObjectHash> {
+        peel_to_commit(repo, &rev_parse(repo, spec)?)
+            .with_context(|| format!("could not parse '{}'", spec))
+    };
+    let (word, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let rest = rest.trim();
+    let first = || match rest.split_whitespace().next() {
+        Some(word) => Ok(word),
+        None => bail!("missing argument for {}", word),
+    };
+    let command = match word {
+        "p" | "pick" => Command::Pick(resolve(first()?)?),
+        "r" | "reword" => Command::Reword(resolve(first()?)?),
+        "e" | "edit" => Command::Edit(resolve(first()?)?),
+        "s" | "squash" => Command::Squash(resolve(first()?)?),
+        "d" | "drop" => Command::Drop(resolve(first()?)?),
+        "f" | "fixup" => {
+            let mut words = rest.split_whitespace();
+            let (replace_message, edit, commit) = match words.next() {
+                Some("-C") => (true, false, words.next()),
+                Some("-c") => (true, true, words.next()),
+                other => (false, false, other),
+            };
+            let Some(commit) = commit else {
+                bail!("missing argument for fixup");
+            };
+            Command::Fixup {
+                commit: resolve(commit)?,
+                replace_message,
+                edit,
+            }
+        }
+        "x" | "exec" if !rest.is_empty() => Command::Exec(rest.to_string()),
+        "b" | "break" => Command::Break,
+        "l" | "label" => Command::Label(first()?.to_string()),
+        "t" | "reset" => Command::Reset(first()?.to_string()),
+        "m" | "merge" => {
+            let rest = rest.split(" # ").next().unwrap_or_default();
+            let mut words = rest.split_whitespace();
+            let (commit, edit, label) = match words.next() {
+                Some("-C") => (words.next(), false, words.next()),
+                Some("-c") => (words.next(), true, words.next()),
+                other => (None, false, other),
+            };
+            let Some(label) = label else {
+                bail!("missing label for merge");
+            };
+            Command::Merge {
+                commit: commit.map(resolve).transpose()?,
+                edit,
+                label: label.to_string(),
+            }
+        }
+        "u" | "update-ref" => Command::UpdateRef(first()?.to_string()),
+        "noop" => return Ok(None),
+        _ => bail!("invalid command '{}'", word),
+    };
+    Ok(Some(command))
+}
+
+/// Format a todo line, with the commit summary as a reminder
+fn format_command(repo: &Repository, command: &Command, abbreviate: bool) -> Result<String> {
+    let name = |hash: &ObjectHash| -> String {
+        match abbreviate {
+            true => hash.to_hex()[..7].to_string(),
+            false => hash.to_hex(),
+        }
+    };
+    let summary = |hash: &ObjectHash| -> Result<String> {
+        Ok(Commit::read(repo, hash)?.summary().to_string())
+    };
+    Ok(match command {
+        Command::Pick(hash) => format!("pick {} {}", name(hash), summary(hash)?),
+        Command::Reword(hash) => format!("reword {} {}", name(hash), summary(hash)?),
+        Command::Edit(hash) => format!("edit {} {}", name(hash), summary(hash)?),
+        Command::Squash(hash) => format!("squash {} {}", name(hash), summary(hash)?),
+        Command::Drop(hash) => format!("drop {} {}", name(hash), summary(hash)?),
+        Command::Fixup {
+            commit,
+            replace_message,
+            edit,
+        } => {
+            let flag = match (replace_message, edit) {
+                (_, true) => "-c ",
+                (true, false) => "-C ",
+                _ => "",
+            };
+            format!("fixup {}{} {}", flag, name(commit), summary(commit)?)
+        }
+        Command::Exec(command) => format!("exec {}", command),
+        Command::Break => "break".to_string(),
+        Command::Label(label) => format!("label {}", label),
+        Command::Reset(label) => format!("reset {}", label),
+        Command::Merge {
+            commit: Some(commit),
+            edit,
+            label,
+        } => format!(
+            "merge {} {} {} # {}",
+            if *edit { "-c" } else { "-C" },
+            name(commit),
+            label,
+            summary(commit)?
+        ),
+        Command::Merge {
+            commit: None,
+            label,
+            ..
+        } => format!("merge {}", label),
+        Command::UpdateRef(name) => format!("update-ref {}", name),
+    })
+}
+
+/// Move `fixup!`, `squash!` and `amend!` commits right after the commit
+/// they name, by subject or hash prefix
+fn autosquash(repo: &Repository, picks: Vec<Command>) -> Result<Vec<Command>> {
+    let mut subjects = Vec::new();
+    for command in &picks {
+        if let Command::Pick(hash) = command {
+            subjects.push((
+                hash.clone(),
+                Commit::read(repo, hash)?.summary().to_string(),
+            ));
+        }
+    }
+
+    let mut fixups: HashMap<usize, Vec<Command>> = HashMap::new();
+    let mut moved = HashSet::new();
+    for (i, (hash, subject)) in subjects.iter().enumerate() {
+        let (kind, mut target) = if let Some(t) = subject.strip_prefix("fixup! ") {
+            ("fixup", t)
+        } else if let Some(t) = subject.strip_prefix("squash! ") {
+            ("squash", t)
+        } else if let Some(t) = subject.strip_prefix("amend! ") {
+            ("amend", t)
+        } else {
+            continue;
+        };
+        while let Some(t) = ["fixup! ", "squash! ", "amend! "]
+            .iter()
+            .find_map(|prefix| target.strip_prefix(prefix))
+        {
+            target = t;
+        }
+        let found = subjects[..i]
+            .iter()
+            .position(|(_, s)| s == target)
+            .or_else(|| {
+                subjects[..i].iter().position(|(h, _)| {
+                    !target.contains(' ') && target.len() >= 4 && h.to_hex().starts_with(target)
+                })
+            })
+            .or_else(|| {
+                subjects[..i]
+                    .iter()
+                    .position(|(_, s)| s.starts_with(target))
+            });
+        let Some(found) = found else {
+            continue;
+        };
+        // Chain onto the commit the target itself was squashed into
+        let mut root = found;
+        while let Some((&owner, _)) = fixups.iter().find(|(_, list)| {
+            list.iter().any(|c| match c {
+                Command::Squash(h) | Command::Fixup { commit: h, .. } => *h == subjects[root].0,
+                _ => false,
+            })
+        }) {
+            root = owner;
+        }
+        let command = match kind {
+            "squash" => Command::Squash(hash.clone()),
+            kind => Command::Fixup {
+                commit: hash.clone(),
+                replace_message: kind == "amend",
+                edit: false,
+            },
+        };
+        fixups.entry(root).or_default().push(command);
+        moved.insert(i);
+    }
+
+    let mut todo = Vec::new();
+    for (i, command) in picks.into_iter().enumerate() {
+        if moved.contains(&i) {
+            continue;
+        }
+        todo.push(command);
+        todo.extend(fixups.remove(&i).unwrap_or_default());
+    }
+    Ok(todo)
+}
+
+/// Add an `update-ref` line after the last commit of every other local
+/// branch pointing into the rebased commits
+fn add_update_refs(repo: &Repository, head_name: &str, todo: &mut Vec<Command>) -> Result<()> {
+    let mut branches: HashMap<ObjectHash, Vec<String>> = HashMap::new();
+    for (name, hash) in list_refs(repo, "refs/heads/")? {
+        if name != head_name {
+            branches.entry(hash).or_default().push(name);
+        }
+    }
+    let mut result = Vec::new();
+    let mut pending = Vec::new();
+    for command in todo.drain(..) {
+        if !command.is_fixup() {
+            result.append(&mut pending);
+        }
+        if let Command::Pick(hash) | Command::Squash(hash) | Command::Fixup { commit: hash, .. } =
+            &command
+        {
+            if !command.is_fixup() {
+                pending.clear();
+            }
+            for name in branches.get(hash).into_iter().flatten() {
+                pending.push(Command::UpdateRef(name.clone()));
+            }
+        }
+        result.push(command);
+    }
+    result.append(&mut pending);
+    *todo = result;
+    Ok(())
+}
+
+/// Add the `exec` commands after every picked or merged commit, after any
+/// squash or fixup commands melding into it
+fn add_exec(todo: &mut Vec<Command>, commands: &[String]) {
+    if commands.is_empty() {
+        return;
+    }
+    let execs = commands.iter().map(|c| Command::Exec(c.clone()));
+    let mut result = Vec::new();
+    let mut pending = false;
+    for command in todo.drain(..) {
+        if pending && !command.is_fixup() {
+            result.extend(execs.clone());
+            pending = false;
+        }
+        pending |= matches!(
+            command,
+            Command::Pick(_) | Command::Reword(_) | Command::Edit(_) | Command::Merge { .. }
+        );
+        result.push(command);
+    }
+    if pending {
+        result.extend(execs);
+    }
+    *todo = result;
+}
+
+/// Build a todo list recreating the topology of the commits, merges
+/// included, with `label`, `reset` and `merge` commands
+///
+/// Side branches are listed before the branch they are merged into.
+/// Parents outside of the rebased commits become `onto`.
+fn merges_script(repo: &Repository, commits: &[ObjectHash]) -> Result<Vec<Command>> {
+    let set = commits.iter().cloned().collect::<HashSet<_>>();
+    let mut parents = HashMap::new();
+    for hash in commits {
+        parents.insert(hash.clone(), Commit::read(repo, hash)?.parents);
+    }
+
+    // Post-order walk from the tip, visiting merged branches first
+    let mut order = Vec::new();
+    let mut visited = HashSet::new();
+    let mut stack = commits
+        .last()
+        .map(|tip| vec![(tip.clone(), false)])
+        .unwrap_or_default();
+    while let Some((hash, expanded)) = stack.pop() {
+        if expanded {
+            order.push(hash);
+            continue;
+        }
+        if !visited.insert(hash.clone()) {
+            continue;
+        }
+        stack.push((hash.clone(), true));
+        let list = &parents[&hash];
+        for parent in list.iter().take(1).chain(list.iter().skip(1).rev()) {
+            if set.contains(parent) && !visited.contains(parent) {
+                stack.push((parent.clone(), false));
+            }
+        }
+    }
+
+    // Name every commit a later reset or merge refers to
+    let mut labels: HashMap<ObjectHash, String> = HashMap::new();
+    let mut used = HashSet::from(["onto".to_string()]);
+    let mut unique = |base: String| {
+        let mut name = base.clone();
+        let mut n = 2;
+        while !used.insert(name.clone()) {
+            name = format!("{}-{}", base, n);
+            n += 1;
+        }
+        name
+    };
+    let mut position: Option<ObjectHash> = None;
+    for hash in &order {
+        let list = &parents[hash];
+        let wanted = list.first().filter(|p| set.contains(*p)).cloned();
+        if wanted != position {
+            if let Some(wanted) = &wanted {
+                if !labels.contains_key(wanted) {
+                    labels.insert(wanted.clone(), unique("branch-point".to_string()));
+                }
+            }
+        }
+        for other in list.iter().skip(1).filter(|p| set.contains(*p)) {
+            if !labels.contains_key(other) {
+                let subject = Commit::read(repo, hash)?.summary().to_string();
+                labels.insert(other.clone(), unique(merge_label(&subject)));
+            }
+        }
+        position = Some(hash.clone());
+    }
+
+    let mut todo = vec![
+        Command::Label("onto".to_string()),
+        Command::Reset("onto".to_string()),
+    ];
+    let mut position: Option<ObjectHash> = None;
+    for hash in &order {
+        let list = &parents[hash];
+        let wanted = list.first().filter(|p| set.contains(*p)).cloned();
+        if wanted != position {
+            let label = match &wanted {
+                Some(wanted) => labels[wanted].clone(),
+                None => "onto".to_string(),
+            };
+            todo.push(Command::Reset(label));
+        }
+        if list.len() > 1 {
+            let others = list[1..]
+                .iter()
+                .map(|p| labels.get(p).cloned().unwrap_or_else(|| p.to_hex()))
+                .collect::<Vec<_>>();
+            todo.push(Command::Merge {
+                commit: Some(hash.clone()),
+                edit: false,
+                label: others.join(" "),
+            });
+        } else {
+            todo.push(Command::Pick(hash.clone()));
+        }
+        if let Some(label) = labels.get(hash) {
+            todo.push(Command::Label(label.clone()));
+        }
+        position = Some(hash.clone());
+    }
+    Ok(todo)
+}
+
+/// Derive a label from the subject of a merge, e.g. `topic` for
+/// `Merge branch 'topic' into master`
+fn merge_label(subject: &str) -> String {
+    let name = subject
+        .split('\'')
+        .nth(1)
+        .filter(|_| subject.starts_with("Merge "))
+        .unwrap_or(subject);
+    name.chars()
+        .map(|c| match c.is_ascii_alphanumeric() || "-_./".contains(c) {
+            true => c,
+            false => '-',
+        })
+        .collect()
+}
+
+fn rebase_dir(repo: &Repository) -> PathBuf {
+    repo.gitdir().join(REBASE_DIR)
+}
+
+fn write_state(repo: &Repository, state: &State) -> Result<()> {
+    let dir = rebase_dir(repo);
+    write_atomic(
+        &dir.join("head-name"),
+        format!("{}\n", state.head_name).as_bytes(),
+    )?;
+    write_atomic(&dir.join("onto"), format!("{}\n", state.onto).as_bytes())?;
+    write_atomic(
+        &dir.join("orig-head"),
+        format!("{}\n", state.orig_head).as_bytes(),
+    )
+}
+
+fn read_state(repo: &Repository) -> Result<State> {
+    let dir = rebase_dir(repo);
+    if !dir.is_dir() {
+        bail!("No rebase in progress?");
+    }
+    let read = |name: &str| -> Result<String> {
+        let text = fs::read_to_string(dir.join(name))
+            .with_context(|| format!("Failed to read {}/{}", REBASE_DIR, name))?;
+        Ok(text.trim().to_string())
+    };
+    let algorithm = repo.hash_algorithm();
+    Ok(State {
+        head_name: read("head-name")?,
+        onto: ObjectHash::from_hex(&read("onto")?, algorithm).context("Invalid onto")?,
+        orig_head: ObjectHash::from_hex(&read("orig-head")?, algorithm)
+            .context("Invalid orig-head")?,
+    })
+}
+
+fn remove_state(repo: &Repository) -> Result<()> {
+    for name in list_refs(repo, "refs/rewritten/")?.keys() {
+        delete_ref(repo, name)?;
+    }
+    let dir = rebase_dir(repo);
+    if dir.exists() {
+        fs::remove_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+fn remove_stop_files(repo: &Repository) -> Result<()> {
+    let gitdir = repo.gitdir();
+    for name in [REBASE_HEAD, MERGE_HEAD, MERGE_MSG] {
+        remove_file(&gitdir.join(name))?;
+    }
+    for name in STOP_FILES {
+        remove_file(&rebase_dir(repo).join(name))?;
+    }
+    Ok(())
+}
+
+fn remove_file(path: &std::path::Path) -> Result<()> {
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Read the todo lines of a file, skipping comments and blank lines
+fn read_lines(path: &std::path::Path) -> Result<Vec<String>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+fn lines_text(lines: &[String]) -> String {
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+fn write_todo(repo: &Repository, lines: &[String]) -> Result<()> {
+    write_atomic(&rebase_dir(repo).join(TODO), lines_text(lines).as_bytes())
+}
+
+/// Write the author of a stopped commit as the shell assignments git uses
+fn write_author_script(repo: &Repository, author: &str) -> Result<()> {
+    let signature = Signature::parse(author)?;
+    let quote = |value: &str| format!("'{}'", value.replace('\'', "'\\''"));
+    let text = format!(
+        "GIT_AUTHOR_NAME={}\nGIT_AUTHOR_EMAIL={}\nGIT_AUTHOR_DATE={}\n",
+        quote(&signature.name),
+        quote(&signature.email),
+        quote(&format!("@{} {}", signature.time, signature.offset))
+    );
+    write_atomic(&rebase_dir(repo).join("author-script"), text.as_bytes())
+}
+
+fn read_author_script(repo: &Repository) -> Result<Option<String>> {
+    let Ok(text) = fs::read_to_string(rebase_dir(repo).join("author-script")) else {
+        return Ok(None);
+    };
+    let mut values = HashMap::new();
+    for line in text.lines() {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = value
+            .strip_prefix('\'')
+            .and_then(|v| v.strip_suffix('\''))
+            .unwrap_or(value)
+            .replace("'\\''", "'");
+        values.insert(key.to_string(), value);
+    }
+    let (Some(name), Some(email), Some(date)) = (
+        values.get("GIT_AUTHOR_NAME"),
+        values.get("GIT_AUTHOR_EMAIL"),
+        values.get("GIT_AUTHOR_DATE"),
+    ) else {
+        bail!("Invalid author-script");
+    };
+    Ok(Some(format!(
+        "{} <{}> {}",
+        name,
+        email,
+        date.trim_start_matches('@')
+    )))
+}
+
+/// Record the branches listed by `update-ref` lines with their current value
+fn init_update_refs(repo: &Repository) -> Result<()> {
+    let mut refs = Vec::new();
+    for line in read_lines(&rebase_dir(repo).join(TODO))? {
+        if let Some(Command::UpdateRef(name)) = parse_command(repo, &line)? {
+            refs.push((name.clone(), resolve_ref(repo, &name)?, None));
+        }
+    }
+    match refs.is_empty() {
+        true => Ok(()),
+        false => write_update_refs(repo, &refs),
+    }
+}
+
+type UpdateRefs = Vec<(String, Option<ObjectHash>, Option<ObjectHash>)>;
+
+/// Read `update-refs`, made of the ref name, its old value and its new value
+/// on three lines, with a null hash when a value is missing
+fn read_update_refs(repo: &Repository) -> Result<UpdateRefs> {
+    let text = fs::read_to_string(rebase_dir(repo).join("update-refs")).unwrap_or_default();
+    let lines = text.lines().collect::<Vec<_>>();
+    let hash = |text: &str| match ObjectHash::from_hex(text, repo.hash_algorithm()) {
+        Ok(hash) if !hash.is_null() => Some(hash),
+        _ => None,
+    };
+    Ok(lines
+        .chunks(3)
+        .filter(|chunk| chunk.len() == 3)
+        .map(|chunk| (chunk[0].to_string(), hash(chunk[1]), hash(chunk[2])))
+        .collect())
+}
+
+fn write_update_refs(repo: &Repository, refs: &UpdateRefs) -> Result<()> {
+    let null = repo.hash_algorithm().null_hash().to_hex();
+    let mut text = String::new();
+    for (name, old, new) in refs {
+        let old = old.as_ref().map_or(null.clone(), |h| h.to_hex());
+        let new = new.as_ref().map_or(null.clone(), |h| h.to_hex());
+        text.push_str(&format!("{}\n{}\n{}\n", name, old, new));
+    }
+    write_atomic(&rebase_dir(repo).join("update-refs"), text.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{commit_files, init_repo, set_identity, write_commit};
+    use tempfile::TempDir;
+
+    /// Build `master: base - main` and `topic: base - one - two`, with topic
+    /// checked out
+    fn setup(main: &str, change: &str) -> (TempDir, Repository) {
+        let (dir, repo) = init_repo();
+        set_identity(&repo);
+        let base = commit_files(&repo, &[("file", "a\nb\nc\n")], "base");
+        let one = write_commit(
+            &repo,
+            &[("file", change)],
+            std::slice::from_ref(&base),
+            "one",
+        );
+        let two = write_commit(
+            &repo,
+            &[("file", change), ("new", "n\n")],
+            std::slice::from_ref(&one),
+            "two",
+        );
+        update_ref(&repo, "refs/heads/topic", &two).unwrap();
+        let head = commit_files(&repo, &[("file", main)], "main");
+        checkout_tree(&repo, &Commit::read(&repo, &head).unwrap().tree, true).unwrap();
+        checkout::switch(&repo, "topic", &Default::default()).unwrap();
+        (dir, repo)
+    }
+
+    fn options(upstream: &str) -> RebaseOptions {
+        RebaseOptions {
+            upstream: Some(upstream.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn head_commit(repo: &Repository) -> Commit {
+        Commit::read(repo, &resolve_ref(repo, "HEAD").unwrap().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_rebase_replays_commits() {
+        let (_dir, repo) = setup("a\nb\nC\n", "A\nb\nc\n");
+        let outcome = rebase(&repo, &options("master")).unwrap();
+        assert!(
+            matches!(outcome, RebaseOutcome::Rebased { ref head_name, .. } if head_name == "refs/heads/topic")
+        );
+
+        let head = head_commit(&repo);
+        assert_eq!(head.message, "two\n");
+        let one = Commit::read(&repo, &head.parents[0]).unwrap();
+        assert_eq!(one.message, "one\n");
+        assert_eq!(
+            Some(one.parents[0].clone()),
+            resolve_ref(&repo, "refs/heads/master").unwrap()
+        );
+        assert_eq!(
+            read_head(&repo).unwrap(),
+            Head::Branch("refs/heads/topic".to_string())
+        );
+        let content = fs::read_to_string(repo.worktree().join("file")).unwrap();
+        assert_eq!(content, "A\nb\nC\n");
+        assert!(!rebase_in_progress(&repo));
+
+        let outcome = rebase(&repo, &options("master")).unwrap();
+        assert!(matches!(outcome, RebaseOutcome::UpToDate { .. }));
+    }
+
+    #[test]
+    fn test_rebase_conflict_continue() {
+        let (_dir, repo) = setup("main\nb\nc\n", "one\nb\nc\n");
+        let outcome = rebase(&repo, &options("master")).unwrap();
+        let RebaseOutcome::Stopped(RebaseStop::Conflict { summary, .. }) = outcome else {
+            panic!("expected a conflict, got {:?}", outcome);
+        };
+        assert_eq!(summary, "one");
+        assert!(rebase_in_progress(&repo));
+        assert!(repo.gitdir().join(REBASE_HEAD).exists());
+        assert_eq!(
+            read_lines(&rebase_dir(&repo).join("done")).unwrap().len(),
+            1
+        );
+        assert!(fs::read_to_string(rebase_dir(&repo).join("author-script"))
+            .unwrap()
+            .starts_with("GIT_AUTHOR_NAME='Test User'\n"));
+        assert!(rebase_continue(&repo).is_err());
+
+        fs::write(repo.worktree().join("file"), "resolved\nb\nc\n").unwrap();
+        crate::add::add(&repo, &["file".to_string()], &Default::default()).unwrap();
+        let outcome = rebase_continue(&repo).unwrap();
+        assert!(matches!(outcome, RebaseOutcome::Rebased { .. }));
+        let head = head_commit(&repo);
+        assert_eq!(head.message, "two\n");
+        let one = Commit::read(&repo, &head.parents[0]).unwrap();
+        assert_eq!(one.author, crate::test_utils::SIGNATURE);
+        assert!(!rebase_in_progress(&repo));
+        assert!(!repo.gitdir().join(REBASE_HEAD).exists());
+    }
+
+    #[test]
+    fn test_rebase_abort() {
+        let (_dir, repo) = setup("main\nb\nc\n", "one\nb\nc\n");
+        let before = resolve_ref(&repo, "HEAD").unwrap();
+        rebase(&repo, &options("master")).unwrap();
+        rebase_abort(&repo).unwrap();
+        assert_eq!(resolve_ref(&repo, "HEAD").unwrap(), before);
+        assert_eq!(
+            read_head(&repo).unwrap(),
+            Head::Branch("refs/heads/topic".to_string())
+        );
+        let content = fs::read_to_string(repo.worktree().join("file")).unwrap();
+        assert_eq!(content, "one\nb\nc\n");
+        assert!(!Index::read(&repo).unwrap().has_conflicts());
+        assert!(!rebase_in_progress(&repo));
+    }
+
+    #[test]
+    fn test_rebase_interactive_squash_and_edit() {
+        let (_dir, repo) = setup("a\nb\nC\n", "A\nb\nc\n");
+        let mut config = repo.config().unwrap();
+        config
+            .set(
+                "sequence.editor",
+                "sed -i -e '1s/^pick/edit/' -e '2s/^pick/squash/'",
+            )
+            .unwrap();
+        config.set("core.editor", "true").unwrap();
+        repo.write_config(&config).unwrap();
+
+        let options = RebaseOptions {
+            interactive: true,
+            ..options("master")
+        };
+        let outcome = rebase(&repo, &options).unwrap();
+        assert!(matches!(
+            outcome,
+            RebaseOutcome::Stopped(RebaseStop::Edit { .. })
+        ));
+        fs::write(repo.worktree().join("extra"), "x\n").unwrap();
+        crate::add::add(&repo, &["extra".to_string()], &Default::default()).unwrap();
+
+        let outcome = rebase_continue(&repo).unwrap();
+        assert!(matches!(outcome, RebaseOutcome::Rebased { .. }));
+        let head = head_commit(&repo);
+        assert_eq!(head.message, "one\n\ntwo\n");
+        let files = flatten_tree(&repo, &head.tree).unwrap();
+        assert!(files.contains_key("extra") && files.contains_key("new"));
+        assert_eq!(
+            head.parents,
+            resolve_ref(&repo, "refs/heads/master")
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_autosquash_and_exec() {
+        let (_dir, repo) = init_repo();
+        let a = commit_files(&repo, &[("f", "1")], "A");
+        let b = commit_files(&repo, &[("f", "2")], "B");
+        let fix = commit_files(&repo, &[("f", "3")], "fixup! A");
+        let amend = commit_files(&repo, &[("f", "4")], "amend! fixup! A");
+        let squash = commit_files(
+            &repo,
+            &[("f", "5")],
+            &format!("squash! {}", &b.to_hex()[..7]),
+        );
+        let picks = [&a, &b, &fix, &amend, &squash]
+            .iter()
+            .map(|h| Command::Pick((*h).clone()))
+            .collect();
+        let mut todo = autosquash(&repo, picks).unwrap();
+        add_exec(&mut todo, &["make".to_string()]);
+        let lines = todo
+            .iter()
+            .map(|c| format_command(&repo, c, true).unwrap())
+            .collect::<Vec<_>>();
+        let short = |h: &ObjectHash| h.to_hex()[..7].to_string();
+        assert_eq!(
+            lines,
+            vec![
+                format!("pick {} A", short(&a)),
+                format!("fixup {} fixup! A", short(&fix)),
+                format!("fixup -C {} amend! fixup! A", short(&amend)),
+                "exec make".to_string(),
+                format!("pick {} B", short(&b)),
+                format!("squash {} squash! {}", short(&squash), short(&b)),
+                "exec make".to_string(),
+            ]
+        );
+        for line in &lines {
+            let command = parse_command(&repo, line).unwrap().unwrap();
+            assert_eq!(format_command(&repo, &command, true).unwrap(), *line);
+        }
+    }
+
+    #[test]
+    fn test_merges_script() {
+        let (_dir, repo) = init_repo();
+        let a = write_commit(&repo, &[("f", "a")], &[], "A");
+        let s = write_commit(&repo, &[("f", "s")], std::slice::from_ref(&a), "S");
+        let b = write_commit(&repo, &[("f", "b")], std::slice::from_ref(&a), "B");
+        let m = write_commit(
+            &repo,
+            &[("f", "m")],
+            &[b.clone(), s.clone()],
+            "Merge branch 'side'",
+        );
+        let todo = merges_script(&repo, &[a.clone(), s.clone(), b.clone(), m.clone()]).unwrap();
+        let label = |l: &str| Command::Label(l.to_string());
+        let reset = |l: &str| Command::Reset(l.to_string());
+        assert_eq!(
+            todo,
+            vec![
+                label("onto"),
+                reset("onto"),
+                Command::Pick(a),
+                label("branch-point"),
+                Command::Pick(s),
+                label("side"),
+                reset("branch-point"),
+                Command::Pick(b),
+                Command::Merge {
+                    commit: Some(m),
+                    edit: false,
+                    label: "side".to_string()
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,475 @@
+use crate::checkout::is_executable;
+use crate::merge_base::is_ancestor;
+use crate::objects::{object_exists, ObjectHash};
+use crate::pack::{read_pack, store_pack};
+use crate::pack_writer::list_objects;
+use crate::pktline::{encode, write_flush, write_line, PktReader};
+use crate::refs::{
+    check_ref_name, delete_ref, list_refs, read_head, resolve_ref, update_ref, Head,
+};
+use crate::revision::peel_to_commit;
+use crate::Repository;
+use anyhow::{bail, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Command, Stdio};
+
+/// RefCommand is a reference update a client asked for, with the reason it
+/// was refused once it is
+#[derive(Debug, Clone)]
+struct RefCommand {
+    old: Option<ObjectHash>,
+    new: Option<ObjectHash>,
+    name: String,
+    error: Option<String>,
+}
+
+/// Accept a push over a pair of streams, like `git receive-pack`
+///
+/// The `pre-receive`, `update` and `post-receive` hooks of the repository
+/// run as in git, with their output relayed to the client.
+pub fn receive_pack(repo: &Repository, input: impl Read, mut output: impl Write) -> Result<()> {
+    let output = &mut output;
+    let mut reader = PktReader::new(BufReader::new(input));
+    let capabilities = format!(
+        "report-status delete-refs side-band-64k quiet atomic ofs-delta push-options \
+         object-format={} agent=legit/{}",
+        repo.hash_algorithm(),
+        env!("CARGO_PKG_VERSION")
+    );
+    let null = repo.hash_algorithm().null_hash();
+    let refs = list_refs(repo, "refs/")?;
+    if refs.is_empty() {
+        let line = format!("{} capabilities^{{}}\0{}", null, capabilities);
+        write_line(output, &line)?;
+    }
+    for (i, (name, hash)) in refs.iter().enumerate() {
+        match i {
+            0 => write_line(output, &format!("{} {}\0{}", hash, name, capabilities))?,
+            _ => write_line(output, &format!("{} {}", hash, name))?,
+        }
+    }
+    write_flush(output)?;
+    output.flush()?;
+
+    if reader.get_mut().fill_buf()?.is_empty() {
+        return Ok(());
+    }
+    let mut commands = Vec::new();
+    let mut requested = Vec::new();
+    let parse = |hex: &str| match hex.bytes().all(|b| b == b'0') {
+        true => Ok(None),
+        false => ObjectHash::from_hex(hex, repo.hash_algorithm()).map(Some),
+    };
+    while let Some(line) = reader.read_line()? {
+        let line = match line.split_once('\0') {
+            Some((line, features)) => {
+                requested = features.split(' ').map(str::to_string).collect();
+                line.to_string()
+            }
+            None => line,
+        };
+        let mut parts = line.splitn(3, ' ');
+        let (Some(old), Some(new), Some(name)) = (parts.next(), parts.next(), parts.next()) else {
+            bail!("protocol error: expected old/new/ref, got '{}'", line);
+        };
+        commands.push(RefCommand {
+            old: parse(old)?,
+            new: parse(new)?,
+            name: name.to_string(),
+            error: None,
+        });
+    }
+    if commands.is_empty() {
+        return Ok(());
+    }
+    let has = |name: &str| requested.iter().any(|r| r == name);
+    let sideband = has("side-band-64k");
+    let mut push_options = Vec::new();
+    if has("push-options") {
+        while let Some(line) = reader.read_line()? {
+            push_options.push(line);
+        }
+    }
+
+    let mut unpack_status = Ok(());
+    if commands.iter().any(|c| c.new.is_some()) {
+        unpack_status = read_pack(reader.get_mut(), repo.hash_algorithm())
+            .and_then(|pack| store_pack(repo, &pack))
+            .map(|_| ());
+    }
+    match &unpack_status {
+        Ok(()) => {
+            let hooks = Hooks {
+                repo,
+                push_options: &push_options,
+                sideband,
+            };
+            update_refs(repo, &mut commands, has("atomic"), &hooks, output)?;
+        }
+        Err(_) => {
+            for command in &mut commands {
+                command.error = Some("unpacker error".to_string());
+            }
+        }
+    }
+
+    if has("report-status") {
+        let mut report = Vec::new();
+        match &unpack_status {
+            Ok(()) => write_line(&mut report, "unpack ok")?,
+            Err(e) => write_line(&mut report, &format!("unpack {}", e))?,
+        }
+        for command in &commands {
+            match &command.error {
+                None => write_line(&mut report, &format!("ok {}", command.name))?,
+                Some(error) => write_line(&mut report, &format!("ng {} {}", command.name, error))?,
+            }
+        }
+        write_flush(&mut report)?;
+        match sideband {
+            true => send_band(output, 1, &report)?,
+            false => output.write_all(&report)?,
+        }
+    }
+    if sideband {
+        write_flush(output)?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// Check each command, run the hooks that may refuse them and apply the
+/// accepted ones
+fn update_refs(
+    repo: &Repository,
+    commands: &mut [RefCommand],
+    atomic: bool,
+    hooks: &Hooks,
+    output: &mut impl Write,
+) -> Result<()> {
+    let config = repo.config()?;
+    let deny = |name: &str| matches!(config.get_bool(name), Ok(Some(true)));
+    let null = repo.hash_algorithm().null_hash();
+    let current_branch = match read_head(repo)? {
+        Head::Branch(name) if !repo.is_bare() => Some(name),
+        _ => None,
+    };
+    let deny_current = !matches!(
+        config.get("receive.denyCurrentBranch"),
+        Some("ignore" | "warn" | "false" | "updateInstead")
+    );
+    let known = list_refs(repo, "refs/")?.into_values().collect::<Vec<_>>();
+    for command in commands.iter_mut() {
+        command.error = check_command(repo, command, &known, &deny)
+            .err()
+            .map(|e| e.to_string());
+        if command.error.is_none() && deny_current && current_branch.as_ref() == Some(&command.name)
+        {
+            command.error = Some("branch is currently checked out".to_string());
+        }
+    }
+
+    let input = commands
+        .iter()
+        .filter(|c| c.error.is_none())
+        .map(|c| format!("{} {} {}\n", hex(&c.old, &null), hex(&c.new, &null), c.name))
+        .collect::<String>();
+    if !input.is_empty() && !hooks.run("pre-receive", &[], &input, output)? {
+        for command in commands.iter_mut().filter(|c| c.error.is_none()) {
+            command.error = Some("pre-receive hook declined".to_string());
+        }
+    }
+    for command in commands.iter_mut().filter(|c| c.error.is_none()) {
+        let args = [
+            command.name.clone(),
+            hex(&command.old, &null),
+            hex(&command.new, &null),
+        ];
+        if !hooks.run("update", &args, "", output)? {
+            command.error = Some("hook declined".to_string());
+        }
+    }
+    if atomic && commands.iter().any(|c| c.error.is_some()) {
+        for command in commands.iter_mut().filter(|c| c.error.is_none()) {
+            command.error = Some("atomic transaction failed".to_string());
+        }
+    }
+
+    let mut updated = String::new();
+    for command in commands.iter_mut().filter(|c| c.error.is_none()) {
+        let result = match &command.new {
+            Some(new) => update_ref(repo, &command.name, new),
+            None => delete_ref(repo, &command.name),
+        };
+        match result {
+            Ok(()) => updated.push_str(&format!(
+                "{} {} {}\n",
+                hex(&command.old, &null),
+                hex(&command.new, &null),
+                command.name
+            )),
+            Err(_) => command.error = Some("failed to update ref".to_string()),
+        }
+    }
+    if !updated.is_empty() {
+        hooks.run("post-receive", &[], &updated, output)?;
+    }
+    Ok(())
+}
+
+/// Refuse a command the repository cannot take: a bad name, a stale old
+/// value, missing objects or an update `receive.deny*` forbids
+///
+/// `known` are the references before the push, whose history is complete.
+fn check_command(
+    repo: &Repository,
+    command: &RefCommand,
+    known: &[ObjectHash],
+    deny: &impl Fn(&str) -> bool,
+) -> Result<()> {
+    if !command.name.starts_with("refs/") || check_ref_name(&command.name).is_err() {
+        bail!("funny refname");
+    }
+    if resolve_ref(repo, &command.name)? != command.old {
+        bail!("failed to lock");
+    }
+    let (Some(old), Some(new)) = (&command.old, &command.new) else {
+        return match &command.new {
+            None if deny("receive.denyDeletes") => bail!("deletion prohibited"),
+            Some(new) if !is_connected(repo, new, known) => bail!("missing necessary objects"),
+            _ => Ok(()),
+        };
+    };
+    if !is_connected(repo, new, known) {
+        bail!("missing necessary objects");
+    }
+    if deny("receive.denyNonFastForwards") && command.name.starts_with("refs/heads/") {
+        let fast_forward = match (peel_to_commit(repo, old), peel_to_commit(repo, new)) {
+            (Ok(old), Ok(new)) => is_ancestor(repo, &old, &new)?,
+            _ => false,
+        };
+        if !fast_forward {
+            bail!("non-fast-forward");
+        }
+    }
+    Ok(())
+}
+
+/// Return true if every object reachable from `tip` is in the repository,
+/// walking until the history of the `known` references, like git's
+/// connectivity check after receiving a pack
+fn is_connected(repo: &Repository, tip: &ObjectHash, known: &[ObjectHash]) -> bool {
+    // Reading a missing commit or tree fails the walk; blobs are only listed
+    match list_objects(repo, std::slice::from_ref(tip), known) {
+        Ok(list) => list
+            .objects
+            .iter()
+            .all(|(hash, _)| object_exists(repo, hash)),
+        Err(_) => false,
+    }
+}
+
+/// Hooks runs the repository's `hooks/` programs on behalf of a push
+struct Hooks<'a> {
+    repo: &'a Repository,
+    push_options: &'a [String],
+    /// Relay the hooks' output on side-band 2 rather than to stderr
+    sideband: bool,
+}
+
+impl Hooks<'_> {
+    /// Run a hook if it exists, feeding it `input`, and return false if it
+    /// failed
+    fn run(
+        &self,
+        name: &str,
+        args: &[String],
+        input: &str,
+        output: &mut impl Write,
+    ) -> Result<bool> {
+        let path = self.repo.commondir().join("hooks").join(name);
+        match path.metadata() {
+            Ok(metadata) if is_executable(&metadata) => {}
+            _ => return Ok(true),
+        }
+        let mut command = Command::new(&path);
+        command
+            .args(args)
+            .current_dir(self.repo.gitdir())
+            .env("GIT_DIR", ".")
+            .env("GIT_PUSH_OPTION_COUNT", self.push_options.len().to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        for (i, option) in self.push_options.iter().enumerate() {
+            command.env(format!("GIT_PUSH_OPTION_{}", i), option);
+        }
+        let mut child = command.spawn()?;
+        // A hook may exit without reading its input
+        let _ = child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(input.as_bytes());
+        let result = child.wait_with_output()?;
+        let mut messages = result.stdout;
+        messages.extend(result.stderr);
+        if let (false, Some(refname)) = (result.status.success(), args.first()) {
+            messages.extend(format!("error: hook declined to update {}\n", refname).as_bytes());
+        }
+        match self.sideband {
+            true => send_band(output, 2, &messages)?,
+            false => std::io::stderr().write_all(&messages)?,
+        }
+        Ok(result.status.success())
+    }
+}
+
+/// Send data on a side-band channel, split into packets that fit
+fn send_band(output: &mut impl Write, band: u8, data: &[u8]) -> Result<()> {
+    for chunk in data.chunks(crate::pktline::MAX_DATA_LEN - 1) {
+        output.write_all(&encode(&[&[band][..], chunk].concat())?)?;
+    }
+    Ok(())
+}
+
+fn hex(hash: &Option<ObjectHash>, null: &ObjectHash) -> String {
+    hash.as_ref().unwrap_or(null).to_hex()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pack_writer::{write_pack, ObjectList};
+    use crate::pktline::Packet;
+    use crate::test_utils::{commit_files, init_repo};
+
+    /// Push `tip` over `old` to `refs/heads/topic` of `server` with a pack
+    /// of `list`, returning the status report
+    fn push(
+        server: &Repository,
+        repo: &Repository,
+        old: Option<&ObjectHash>,
+        tip: &ObjectHash,
+        list: &ObjectList,
+    ) -> Vec<String> {
+        let mut input = Vec::new();
+        let old = old.map_or("0".repeat(40), |old| old.to_hex());
+        let line = format!("{} {} refs/heads/topic\0report-status", old, tip);
+        input.extend(encode(line.as_bytes()).unwrap());
+        write_flush(&mut input).unwrap();
+        input.extend(write_pack(repo, list, false).unwrap());
+        let mut output = Vec::new();
+        receive_pack(server, &input[..], &mut output).unwrap();
+        let mut reader = PktReader::new(&output[..]);
+        while reader.read_line().unwrap().is_some() {}
+        let mut report = Vec::new();
+        while let Some(line) = reader.read_line().unwrap() {
+            report.push(line);
+        }
+        report
+    }
+
+    #[test]
+    fn test_receive_refuses_incomplete_pack() {
+        let (_dir, repo) = init_repo();
+        let base = commit_files(&repo, &[("a.txt", "one\n")], "first");
+        let next = commit_files(&repo, &[("a.txt", "two\n")], "second");
+        let (_dir, server) = init_repo();
+
+        // The commit alone, without its tree
+        let mut list = list_objects(&repo, std::slice::from_ref(&base), &[]).unwrap();
+        list.objects.retain(|(hash, _)| *hash == base);
+        let report = push(&server, &repo, None, &base, &list);
+        assert_eq!(
+            report,
+            ["unpack ok", "ng refs/heads/topic missing necessary objects"]
+        );
+        assert_eq!(resolve_ref(&server, "refs/heads/topic").unwrap(), None);
+
+        // The full history is accepted, then a commit with a missing blob is not
+        let list = list_objects(&repo, std::slice::from_ref(&base), &[]).unwrap();
+        assert_eq!(
+            push(&server, &repo, None, &base, &list)[1],
+            "ok refs/heads/topic"
+        );
+        let mut list = list_objects(
+            &repo,
+            std::slice::from_ref(&next),
+            std::slice::from_ref(&base),
+        )
+        .unwrap();
+        list.objects.retain(|(_, path)| path != "a.txt");
+        let report = push(&server, &repo, Some(&base), &next, &list);
+        assert_eq!(report[1], "ng refs/heads/topic missing necessary objects");
+    }
+
+    #[test]
+    fn test_receive_refuses_incomplete_history() {
+        let (_dir, repo) = init_repo();
+        let base = commit_files(&repo, &[("a.txt", "one\n")], "first");
+        let next = commit_files(&repo, &[("a.txt", "two\n")], "second");
+        let (_dir, server) = init_repo();
+
+        // Everything of the new commit, but not the parent it builds on
+        let list = list_objects(
+            &repo,
+            std::slice::from_ref(&next),
+            std::slice::from_ref(&base),
+        )
+        .unwrap();
+        let report = push(&server, &repo, None, &next, &list);
+        assert_eq!(
+            report,
+            ["unpack ok", "ng refs/heads/topic missing necessary objects"]
+        );
+        assert_eq!(resolve_ref(&server, "refs/heads/topic").unwrap(), None);
+
+        let list = list_objects(&repo, std::slice::from_ref(&next), &[]).unwrap();
+        assert_eq!(
+            push(&server, &repo, None, &next, &list)[1],
+            "ok refs/heads/topic"
+        );
+    }
+
+    #[test]
+    fn test_receive_push() {
+        let (_dir, repo) = init_repo();
+        let base = commit_files(&repo, &[("a.txt", "one\n")], "first");
+        let (_dir, server) = init_repo();
+        let list = list_objects(&repo, std::slice::from_ref(&base), &[]).unwrap();
+
+        let mut input = Vec::new();
+        let zero = "0".repeat(40);
+        let line = format!("{} {} refs/heads/topic\0report-status", zero, base);
+        input.extend(encode(line.as_bytes()).unwrap());
+        let line = format!("{} {} refs/heads/master", zero, base);
+        input.extend(encode(line.as_bytes()).unwrap());
+        write_flush(&mut input).unwrap();
+        input.extend(write_pack(&repo, &list, false).unwrap());
+        let mut output = Vec::new();
+        receive_pack(&server, &input[..], &mut output).unwrap();
+
+        let mut reader = PktReader::new(&output[..]);
+        let advertisement = reader.read_line().unwrap().unwrap();
+        assert!(advertisement.starts_with(&format!("{} capabilities^{{}}\0", zero)));
+        assert_eq!(reader.read_packet().unwrap(), Packet::Flush);
+        let mut report = Vec::new();
+        while let Some(line) = reader.read_line().unwrap() {
+            report.push(line);
+        }
+        assert_eq!(
+            report,
+            [
+                "unpack ok",
+                "ok refs/heads/topic",
+                "ng refs/heads/master branch is currently checked out"
+            ]
+        );
+        assert_eq!(
+            resolve_ref(&server, "refs/heads/topic").unwrap(),
+            Some(base)
+        );
+        assert_eq!(resolve_ref(&server, "refs/heads/master").unwrap(), None);
+    }
+}
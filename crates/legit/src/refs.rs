@@ -0,0 +1,555 @@
+use crate::objects::{HashAlgorithm, ObjectHash};
+use crate::reftable;
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use strum::EnumString;
+
+/// Maximum number of symbolic references followed before giving up
+const MAX_SYMREF_DEPTH: usize = 5;
+
+/// Head is what `HEAD` points to: a branch or a detached commit
+#[derive(Debug, Clone, PartialEq)]
+pub enum Head {
+    /// HEAD is a symbolic reference to a branch, e.g. `refs/heads/master`
+    Branch(String),
+    /// HEAD points directly to a commit
+    Detached(ObjectHash),
+}
+
+impl Head {
+    /// Return the short branch name if HEAD is on a branch
+    pub fn branch(&self) -> Option<&str> {
+        match self {
+            Head::Branch(name) => Some(short_name(name)),
+            Head::Detached(_) => None,
+        }
+    }
+}
+
+/// RefValue is the raw content of a reference file
+#[derive(Debug, Clone, PartialEq)]
+pub enum RefValue {
+    Symbolic(String),
+    Direct(ObjectHash),
+}
+
+/// RefStorage is the backend storing the references, chosen by
+/// `extensions.refStorage`
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, EnumString, strum::Display, Deserialize, Serialize,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum RefStorage {
+    /// Loose files under `refs/` and the `packed-refs` file
+    #[default]
+    Files,
+    /// A stack of reftables under `reftable/`
+    Reftable,
+}
+
+/// Return true if a reference lives in the reftable stack of the
+/// repository. Only `HEAD` and the references under `refs/` do; special
+/// files such as `MERGE_HEAD` stay in the git directory.
+fn in_reftable(repo: &Repository, name: &str) -> bool {
+    repo.ref_storage() == RefStorage::Reftable && (name == "HEAD" || name.starts_with("refs/"))
+}
+
+/// Return true if each worktree has its own version of a reference: HEAD,
+/// special files such as `MERGE_HEAD`, and the references under
+/// `refs/bisect/`, `refs/worktree/` and `refs/rewritten/`
+pub fn is_per_worktree(name: &str) -> bool {
+    !name.starts_with("refs/")
+        || ["refs/bisect/", "refs/worktree/", "refs/rewritten/"]
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+}
+
+/// Return the directory storing a reference: the git directory of the
+/// worktree for per-worktree references, the common directory otherwise
+pub(crate) fn ref_root<'a>(repo: &'a Repository, name: &str) -> &'a Path {
+    match is_per_worktree(name) {
+        true => repo.gitdir(),
+        false => repo.commondir(),
+    }
+}
+
+/// Return the directories storing references, the common one first; in
+/// the main worktree, both are the same
+pub(crate) fn ref_roots(repo: &Repository) -> Vec<&Path> {
+    let mut roots = vec![repo.commondir()];
+    if repo.is_linked_worktree() {
+        roots.push(repo.gitdir());
+    }
+    roots
+}
+
+/// Return the path of a loose reference inside the git directory
+fn ref_path(repo: &Repository, name: &str) -> PathBuf {
+    ref_root(repo, name).join(name)
+}
+
+/// Check that a reference name is safe to use as a path
+pub fn check_ref_name(name: &str) -> Result<()> {
+    let invalid = name.is_empty()
+        || name.starts_with('/')
+        || name.ends_with('/')
+        || name.ends_with('.')
+        || name.ends_with(".lock")
+        || name.contains("..")
+        || name.contains("//")
+        || name.contains("@{")
+        || name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c));
+    if invalid {
+        bail!("Invalid reference name: {}", name);
+    }
+    Ok(())
+}
+
+/// Parse the content of a loose reference file
+fn parse_ref(content: &str, algorithm: HashAlgorithm) -> Result<RefValue> {
+    let content = content.trim();
+    match content.strip_prefix("ref:") {
+        Some(target) => Ok(RefValue::Symbolic(target.trim().to_string())),
+        None => Ok(RefValue::Direct(ObjectHash::from_hex(content, algorithm)?)),
+    }
+}
+
+/// Read the `packed-refs` file into a map of reference names to hashes
+pub fn read_packed_refs(repo: &Repository) -> Result<BTreeMap<String, ObjectHash>> {
+    let path = repo.commondir().join("packed-refs");
+    let mut refs = BTreeMap::new();
+    if !path.exists() {
+        return Ok(refs);
+    }
+    let content = fs::read_to_string(&path).context("Failed to read packed-refs")?;
+    for line in content.lines() {
+        // Comments hold the file traits and `^` lines hold peeled tag targets
+        if line.starts_with('#') || line.starts_with('^') || line.is_empty() {
+            continue;
+        }
+        let (hash, name) = line
+            .split_once(' ')
+            .ok_or_else(|| anyhow::anyhow!("Invalid packed-refs line: {}", line))?;
+        refs.insert(
+            name.to_string(),
+            ObjectHash::from_hex(hash, repo.hash_algorithm())?,
+        );
+    }
+    Ok(refs)
+}
+
+/// Write the `packed-refs` file from a map of reference names to hashes
+///
+/// Annotated tags are followed by a `^` line holding the object they peel
+/// to, as git writes them.
+pub fn write_packed_refs(repo: &Repository, refs: &BTreeMap<String, ObjectHash>) -> Result<()> {
+    let mut content = String::from("# pack-refs with: peeled fully-peeled sorted \n");
+    for (name, hash) in refs {
+        content.push_str(&format!("{} {}\n", hash, name));
+        if !name.starts_with("refs/tags/") {
+            continue;
+        }
+        if let Ok(peeled) = crate::revision::peel_tags(repo, hash) {
+            if peeled != *hash {
+                content.push_str(&format!("^{}\n", peeled));
+            }
+        }
+    }
+    write_atomic(&repo.commondir().join("packed-refs"), content.as_bytes())
+}
+
+/// Read a reference without following symbolic references
+pub fn read_ref(repo: &Repository, name: &str) -> Result<Option<RefValue>> {
+    if in_reftable(repo, name) {
+        return reftable::read_ref(repo, name);
+    }
+    let path = ref_path(repo, name);
+    if path.is_file() {
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read reference {}", name))?;
+        return parse_ref(&content, repo.hash_algorithm())
+            .with_context(|| format!("Invalid reference {}", name))
+            .map(Some);
+    }
+    Ok(read_packed_refs(repo)?.remove(name).map(RefValue::Direct))
+}
+
+/// Resolve a reference to the hash it ultimately points to
+///
+/// Returns `None` if the reference, or the branch a symbolic reference points
+/// to, does not exist yet (e.g. `HEAD` in a repository without commits).
+pub fn resolve_ref(repo: &Repository, name: &str) -> Result<Option<ObjectHash>> {
+    let mut name = name.to_string();
+    for _ in 0..MAX_SYMREF_DEPTH {
+        match read_ref(repo, &name)? {
+            Some(RefValue::Direct(hash)) => return Ok(Some(hash)),
+            Some(RefValue::Symbolic(target)) => name = target,
+            None => return Ok(None),
+        }
+    }
+    bail!("Too many levels of symbolic references at {}", name)
+}
+
+/// Create or update a reference to point to the given hash
+pub fn update_ref(repo: &Repository, name: &str, hash: &ObjectHash) -> Result<()> {
+    check_ref_name(name)?;
+    if in_reftable(repo, name) {
+        return reftable::write_ref(repo, name, Some(RefValue::Direct(hash.clone())), None);
+    }
+    write_atomic(&ref_path(repo, name), format!("{}\n", hash).as_bytes())
+        .with_context(|| format!("Failed to update reference {}", name))
+}
+
+/// Update a reference and add an entry to its reflog
+///
+/// With reftables both go into one table, so readers never see the new
+/// value without its reflog entry.
+pub fn update_ref_with_reflog(
+    repo: &Repository,
+    name: &str,
+    hash: &ObjectHash,
+    entry: &ReflogEntry,
+) -> Result<()> {
+    check_ref_name(name)?;
+    if in_reftable(repo, name) {
+        let value = Some(RefValue::Direct(hash.clone()));
+        return reftable::write_ref(repo, name, value, Some(entry));
+    }
+    update_ref(repo, name, hash)?;
+    append_reflog(repo, name, entry)
+}
+
+/// Make a reference a symbolic reference to another one
+pub fn update_symbolic_ref(repo: &Repository, name: &str, target: &str) -> Result<()> {
+    check_ref_name(target)?;
+    if in_reftable(repo, name) {
+        return reftable::write_ref(
+            repo,
+            name,
+            Some(RefValue::Symbolic(target.to_string())),
+            None,
+        );
+    }
+    write_atomic(
+        &ref_path(repo, name),
+        format!("ref: {}\n", target).as_bytes(),
+    )
+    .with_context(|| format!("Failed to update reference {}", name))
+}
+
+/// Delete a reference from both the loose and packed storage
+pub fn delete_ref(repo: &Repository, name: &str) -> Result<()> {
+    if in_reftable(repo, name) {
+        if reftable::read_ref(repo, name)?.is_none() {
+            bail!("Reference not found: {}", name);
+        }
+        return reftable::write_ref(repo, name, None, None);
+    }
+    let path = ref_path(repo, name);
+    let mut found = false;
+    if path.is_file() {
+        fs::remove_file(&path).with_context(|| format!("Failed to delete reference {}", name))?;
+        found = true;
+    }
+    let mut packed = read_packed_refs(repo)?;
+    if packed.remove(name).is_some() {
+        write_packed_refs(repo, &packed)?;
+        found = true;
+    }
+    if !found {
+        bail!("Reference not found: {}", name);
+    }
+    Ok(())
+}
+
+/// List every reference under a prefix (e.g. `refs/heads/`) with its hash
+pub fn list_refs(repo: &Repository, prefix: &str) -> Result<BTreeMap<String, ObjectHash>> {
+    if repo.ref_storage() == RefStorage::Reftable {
+        let mut refs = BTreeMap::new();
+        for (name, value) in reftable::list_refs(repo, prefix)? {
+            let hash = match value {
+                _ if !name.starts_with("refs/") => continue,
+                RefValue::Direct(hash) => Some(hash),
+                RefValue::Symbolic(_) => resolve_ref(repo, &name)?,
+            };
+            if let Some(hash) = hash {
+                refs.insert(name, hash);
+            }
+        }
+        return Ok(refs);
+    }
+    let mut refs = read_packed_refs(repo)?;
+    refs.retain(|name, _| name.starts_with(prefix));
+
+    for root in ref_roots(repo) {
+        let mut stack = vec![root.join("refs")];
+        while let Some(dir) = stack.pop() {
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    stack.push(path);
+                    continue;
+                }
+                let name = path
+                    .strip_prefix(root)?
+                    .to_string_lossy()
+                    .replace('\\', "/");
+                // Each worktree only sees its own per-worktree references
+                if !name.starts_with(prefix)
+                    || name.ends_with(".lock")
+                    || ref_root(repo, &name) != root
+                {
+                    continue;
+                }
+                if let Some(hash) = resolve_ref(repo, &name)? {
+                    refs.insert(name, hash);
+                }
+            }
+        }
+    }
+    Ok(refs)
+}
+
+/// Read what `HEAD` currently points to
+pub fn read_head(repo: &Repository) -> Result<Head> {
+    match read_ref(repo, "HEAD")? {
+        Some(RefValue::Symbolic(name)) => Ok(Head::Branch(name)),
+        Some(RefValue::Direct(hash)) => Ok(Head::Detached(hash)),
+        None => bail!("HEAD not found in {}", repo.gitdir().display()),
+    }
+}
+
+/// Point `HEAD` to a branch or detach it at a commit
+pub fn set_head(repo: &Repository, head: &Head) -> Result<()> {
+    match head {
+        Head::Branch(name) => update_symbolic_ref(repo, "HEAD", name),
+        Head::Detached(hash) => update_ref(repo, "HEAD", hash),
+    }
+}
+
+/// Move the branch HEAD points to, or HEAD itself when detached, to a commit
+pub fn update_head(repo: &Repository, hash: &ObjectHash) -> Result<()> {
+    match read_head(repo)? {
+        Head::Branch(name) => update_ref(repo, &name, hash),
+        Head::Detached(_) => update_ref(repo, "HEAD", hash),
+    }
+}
+
+/// ReflogEntry is one line of a reference's reflog
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReflogEntry {
+    pub old: ObjectHash,
+    pub new: ObjectHash,
+    /// The identity and time of the update, formatted like a commit's committer
+    pub committer: String,
+    pub message: String,
+}
+
+impl ReflogEntry {
+    fn parse(line: &str, algorithm: HashAlgorithm) -> Option<ReflogEntry> {
+        let (fields, message) = line.split_once('\t').unwrap_or((line, ""));
+        let mut fields = fields.splitn(3, ' ');
+        let old = ObjectHash::from_hex(fields.next()?, algorithm).ok()?;
+        let new = ObjectHash::from_hex(fields.next()?, algorithm).ok()?;
+        Some(ReflogEntry {
+            old,
+            new,
+            committer: fields.next().unwrap_or_default().to_string(),
+            message: message.to_string(),
+        })
+    }
+
+    fn format(&self) -> String {
+        format!(
+            "{} {} {}\t{}\n",
+            self.old, self.new, self.committer, self.message
+        )
+    }
+}
+
+fn reflog_path(repo: &Repository, name: &str) -> PathBuf {
+    ref_root(repo, name).join("logs").join(name)
+}
+
+/// Return every entry of a reference's reflog, oldest first
+///
+/// legit only writes the reflog of `refs/stash`; the others come from git.
+pub fn read_reflog(repo: &Repository, name: &str) -> Result<Vec<ReflogEntry>> {
+    if in_reftable(repo, name) {
+        return reftable::read_reflog(repo, name);
+    }
+    let Ok(content) = fs::read_to_string(reflog_path(repo, name)) else {
+        return Ok(Vec::new());
+    };
+    content
+        .lines()
+        .map(|line| {
+            ReflogEntry::parse(line, repo.hash_algorithm())
+                .ok_or_else(|| anyhow::anyhow!("Invalid reflog entry of {}: {}", name, line))
+        })
+        .collect()
+}
+
+/// Add an entry at the end of a reference's reflog
+pub fn append_reflog(repo: &Repository, name: &str, entry: &ReflogEntry) -> Result<()> {
+    use std::io::Write;
+    if in_reftable(repo, name) {
+        return reftable::append_reflog(repo, name, entry);
+    }
+    let path = reflog_path(repo, name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(entry.format().as_bytes()))
+        .with_context(|| format!("Failed to write the reflog of {}", name))
+}
+
+/// Replace a reference's reflog, removing it when there are no entries
+pub fn write_reflog(repo: &Repository, name: &str, entries: &[ReflogEntry]) -> Result<()> {
+    if in_reftable(repo, name) {
+        return reftable::write_reflog(repo, name, entries);
+    }
+    let path = reflog_path(repo, name);
+    if entries.is_empty() {
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        return Ok(());
+    }
+    let content = entries.iter().map(ReflogEntry::format).collect::<String>();
+    write_atomic(&path, content.as_bytes())
+        .with_context(|| format!("Failed to write the reflog of {}", name))
+}
+
+/// Strip the `refs/heads/`, `refs/tags/` or `refs/remotes/` prefix of a name
+pub fn short_name(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
+
+/// Write a file by renaming a temporary lock file into place
+///
+/// Like git, the lock file is created exclusively, so a concurrent writer
+/// holding it makes this fail instead of being overwritten.
+pub(crate) fn write_atomic(path: &std::path::Path, content: &[u8]) -> Result<()> {
+    use std::io::Write;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut lock = path.as_os_str().to_owned();
+    lock.push(".lock");
+    let lock = PathBuf::from(lock);
+    let mut file = match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            bail!("Unable to create '{}': File exists", lock.display())
+        }
+        Err(e) => return Err(e).with_context(|| format!("Unable to create '{}'", lock.display())),
+    };
+    let result = file
+        .write_all(content)
+        .with_context(|| format!("Failed to write {}", lock.display()))
+        .and_then(|_| {
+            fs::rename(&lock, path).with_context(|| format!("Failed to rename {}", lock.display()))
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(&lock);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_write_atomic_respects_lock() {
+        let tempdir = TempDir::new().unwrap();
+        let path = tempdir.path().join("refs/heads/main");
+        write_atomic(&path, b"one\n").unwrap();
+
+        // Another writer holds the lock
+        let lock = tempdir.path().join("refs/heads/main.lock");
+        fs::write(&lock, "theirs\n").unwrap();
+        let error = write_atomic(&path, b"two\n").unwrap_err();
+        assert!(error.to_string().starts_with("Unable to create '"));
+        assert!(error.to_string().ends_with("main.lock': File exists"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one\n");
+        assert_eq!(fs::read_to_string(&lock).unwrap(), "theirs\n");
+
+        fs::remove_file(&lock).unwrap();
+        write_atomic(&path, b"two\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "two\n");
+        assert!(!lock.exists());
+    }
+
+    #[test]
+    fn test_read_head_new_repository() {
+        let tempdir = TempDir::new().unwrap();
+        let repo = Repository::new(tempdir.path()).unwrap();
+        let head = read_head(&repo).unwrap();
+        assert_eq!(head, Head::Branch("refs/heads/master".to_string()));
+        assert_eq!(head.branch(), Some("master"));
+        assert_eq!(resolve_ref(&repo, "HEAD").unwrap(), None);
+    }
+
+    #[test]
+    fn test_update_and_resolve_ref() {
+        let tempdir = TempDir::new().unwrap();
+        let repo = Repository::new(tempdir.path()).unwrap();
+        let hash = HashAlgorithm::Sha1.digest("commit".as_bytes());
+        update_ref(&repo, "refs/heads/master", &hash).unwrap();
+        assert_eq!(resolve_ref(&repo, "HEAD").unwrap(), Some(hash.clone()));
+
+        set_head(&repo, &Head::Detached(hash.clone())).unwrap();
+        assert_eq!(read_head(&repo).unwrap(), Head::Detached(hash));
+    }
+
+    #[test]
+    fn test_packed_refs() {
+        let tempdir = TempDir::new().unwrap();
+        let repo = Repository::new(tempdir.path()).unwrap();
+        let hash = HashAlgorithm::Sha1.digest("commit".as_bytes());
+        let mut packed = BTreeMap::new();
+        packed.insert("refs/tags/v1".to_string(), hash.clone());
+        write_packed_refs(&repo, &packed).unwrap();
+        update_ref(&repo, "refs/heads/master", &hash).unwrap();
+
+        let refs = list_refs(&repo, "refs/").unwrap();
+        assert_eq!(refs.len(), 2);
+        assert_eq!(resolve_ref(&repo, "refs/tags/v1").unwrap(), Some(hash));
+
+        delete_ref(&repo, "refs/tags/v1").unwrap();
+        assert!(read_packed_refs(&repo).unwrap().is_empty());
+        assert!(delete_ref(&repo, "refs/tags/v1").is_err());
+    }
+
+    #[test]
+    fn test_check_ref_name() {
+        assert!(check_ref_name("refs/heads/feature/x").is_ok());
+        for name in ["refs/heads/a..b", "refs/heads/a b", "refs/heads/x.lock", ""] {
+            assert!(check_ref_name(name).is_err(), "{} should be invalid", name);
+        }
+    }
+}
@@ -0,0 +1,1464 @@
+use crate::commits::Signature;
+use crate::objects::{HashAlgorithm, ObjectHash};
+use crate::pack::read_offset_delta;
+use crate::pack_writer::{deflate, offset_encoding};
+use crate::refs::{ref_root, ref_roots, RefValue, ReflogEntry};
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use flate2::{Decompress, FlushDecompress, Status};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+const MAGIC: &[u8; 4] = b"REFT";
+
+/// Size of the blocks of the tables legit writes, git's default
+const BLOCK_SIZE: usize = 4096;
+
+/// Every this many records, a block stores a full key as a restart point
+const RESTART_INTERVAL: usize = 16;
+
+/// A section with more blocks than this gets an index, as in git
+const INDEX_THRESHOLD: usize = 3;
+
+const BLOCK_REF: u8 = b'r';
+const BLOCK_LOG: u8 = b'g';
+const BLOCK_INDEX: u8 = b'i';
+
+const REF_DELETION: u8 = 0;
+const REF_VALUE: u8 = 1;
+/// A value followed by the object an annotated tag peels to
+const REF_PEELED: u8 = 2;
+const REF_SYMBOLIC: u8 = 3;
+
+const LOG_DELETION: u8 = 0;
+const LOG_UPDATE: u8 = 1;
+
+/// RefRecord is a reference as stored in a table; a `None` value deletes
+/// it from the older tables
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RefRecord {
+    pub name: String,
+    pub update_index: u64,
+    pub value: Option<RefValue>,
+}
+
+/// LogRecord is a reflog entry as stored in a table, where the update
+/// index orders the entries of a reference; a `None` entry deletes it
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LogRecord {
+    pub name: String,
+    pub update_index: u64,
+    pub entry: Option<ReflogEntry>,
+}
+
+impl LogRecord {
+    /// Sort newest first within a reference, as the update index is stored
+    /// reversed after the name
+    fn key(&self) -> Vec<u8> {
+        let mut key = self.name.as_bytes().to_vec();
+        key.push(0);
+        key.extend((u64::MAX - self.update_index).to_be_bytes());
+        key
+    }
+}
+
+/// A record ready to be written: its key, value type and the bytes after
+/// the key
+type Entry = (Vec<u8>, u8, Vec<u8>);
+
+/// A decoded record
+#[derive(Debug)]
+enum Record {
+    Ref(RefRecord),
+    Log(LogRecord),
+    /// An index entry: the offset of the block whose last key it holds
+    Index(usize),
+}
+
+fn header_len(version: u8) -> usize {
+    match version {
+        1 => 24,
+        _ => 28,
+    }
+}
+
+/// The footer repeats the header and adds five offsets and a CRC-32
+fn footer_len(version: u8) -> usize {
+    header_len(version) + 5 * 8 + 4
+}
+
+fn u24(bytes: &[u8]) -> usize {
+    (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if input.len() < len {
+        bail!("Truncated reftable record");
+    }
+    let (taken, rest) = input.split_at(len);
+    *input = rest;
+    Ok(taken)
+}
+
+fn take_string(input: &mut &[u8]) -> Result<String> {
+    let len = read_offset_delta(input)? as usize;
+    Ok(String::from_utf8(take(input, len)?.to_vec())?)
+}
+
+fn put_string(out: &mut Vec<u8>, value: &str) {
+    out.extend(offset_encoding(value.len() as u64));
+    out.extend(value.as_bytes());
+}
+
+/// Convert a `+HHMM` timezone into the minutes stored in log records
+fn parse_tz(offset: &str) -> Result<i16> {
+    let invalid = || anyhow::anyhow!("Invalid timezone offset: {}", offset);
+    let (sign, digits) = match offset.split_at_checked(1) {
+        Some(("-", digits)) => (-1, digits),
+        Some(("+", digits)) => (1, digits),
+        _ => return Err(invalid()),
+    };
+    if digits.len() != 4 {
+        return Err(invalid());
+    }
+    let hours = digits[..2].parse::<i16>().map_err(|_| invalid())?;
+    let minutes = digits[2..].parse::<i16>().map_err(|_| invalid())?;
+    Ok(sign * (hours * 60 + minutes))
+}
+
+fn format_tz(minutes: i16) -> String {
+    let sign = if minutes < 0 { '-' } else { '+' };
+    let minutes = minutes.unsigned_abs();
+    format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60)
+}
+
+fn encode_ref(record: &RefRecord, min_update_index: u64) -> Entry {
+    let mut value = offset_encoding(record.update_index - min_update_index);
+    let value_type = match &record.value {
+        None => REF_DELETION,
+        Some(RefValue::Direct(hash)) => {
+            value.extend(hash.as_bytes());
+            REF_VALUE
+        }
+        Some(RefValue::Symbolic(target)) => {
+            put_string(&mut value, target);
+            REF_SYMBOLIC
+        }
+    };
+    (record.name.as_bytes().to_vec(), value_type, value)
+}
+
+fn encode_log(record: &LogRecord) -> Result<Entry> {
+    let Some(entry) = &record.entry else {
+        return Ok((record.key(), LOG_DELETION, Vec::new()));
+    };
+    let signature = Signature::parse(&entry.committer)
+        .with_context(|| format!("Invalid reflog entry of {}", record.name))?;
+    let mut value = entry.old.as_bytes().to_vec();
+    value.extend(entry.new.as_bytes());
+    put_string(&mut value, &signature.name);
+    put_string(&mut value, &signature.email);
+    value.extend(offset_encoding(signature.time.max(0) as u64));
+    value.extend(parse_tz(&signature.offset)?.to_be_bytes());
+    // Like git, messages end with a newline inside tables
+    put_string(&mut value, &format!("{}\n", entry.message));
+    Ok((record.key(), LOG_UPDATE, value))
+}
+
+/// BlockWriter collects the prefix-compressed records of one block
+struct BlockWriter {
+    block_type: u8,
+    /// Offset of the block in the table
+    offset: u64,
+    /// Size of the file header preceding the block header in the first block
+    header_off: usize,
+    /// The block from its start, the file header space included
+    data: Vec<u8>,
+    restarts: Vec<usize>,
+    last_key: Vec<u8>,
+    count: usize,
+}
+
+impl BlockWriter {
+    /// Add a record, or return false if it does not fit in the block
+    fn add(&mut self, key: &[u8], value_type: u8, value: &[u8]) -> bool {
+        let restart = self.count.is_multiple_of(RESTART_INTERVAL);
+        let prefix = match restart {
+            true => 0,
+            false => key
+                .iter()
+                .zip(&self.last_key)
+                .take_while(|(a, b)| a == b)
+                .count(),
+        };
+        let mut record = offset_encoding(prefix as u64);
+        record.extend(offset_encoding(
+            ((key.len() - prefix) << 3 | value_type as usize) as u64,
+        ));
+        record.extend(&key[prefix..]);
+        record.extend(value);
+
+        let restarts = self.restarts.len() + restart as usize;
+        let size = self.data.len() + record.len() + 3 * restarts + 2;
+        // A log block is compressed, so one large record may exceed the size
+        let oversized_log = self.block_type == BLOCK_LOG && self.count == 0;
+        if size > BLOCK_SIZE && !oversized_log {
+            return false;
+        }
+        if restart {
+            self.restarts.push(self.data.len());
+        }
+        self.data.extend(record);
+        self.last_key = key.to_vec();
+        self.count += 1;
+        true
+    }
+
+    /// Return the block as written in the table, without the file header
+    fn finish(mut self) -> Result<Vec<u8>> {
+        for restart in &self.restarts {
+            self.data.extend(&(*restart as u32).to_be_bytes()[1..]);
+        }
+        self.data.extend((self.restarts.len() as u16).to_be_bytes());
+        let header = self.header_off;
+        self.data[header] = self.block_type;
+        let len = (self.data.len() as u32).to_be_bytes();
+        self.data[header + 1..header + 4].copy_from_slice(&len[1..]);
+        if self.block_type != BLOCK_LOG {
+            return Ok(self.data.split_off(header));
+        }
+        // Log blocks store their uncompressed size but deflate the records
+        let mut block = self.data[header..header + 4].to_vec();
+        block.extend(deflate(&self.data[header + 4..])?);
+        Ok(block)
+    }
+}
+
+/// TableWriter lays out the blocks of a table
+struct TableWriter {
+    out: Vec<u8>,
+    header_len: usize,
+    /// Padding of the last block, only written if another block follows
+    padding: usize,
+}
+
+impl TableWriter {
+    fn block(&self, block_type: u8) -> BlockWriter {
+        let first = self.out.len() == self.header_len;
+        let header_off = if first { self.header_len } else { 0 };
+        BlockWriter {
+            block_type,
+            offset: if first {
+                0
+            } else {
+                (self.out.len() + self.padding) as u64
+            },
+            header_off,
+            data: vec![0; header_off + 4],
+            restarts: Vec::new(),
+            last_key: Vec::new(),
+            count: 0,
+        }
+    }
+
+    /// Write a block, returning its last key and offset for the index
+    fn flush(&mut self, block: BlockWriter) -> Result<(Vec<u8>, u64)> {
+        self.out.resize(self.out.len() + self.padding, 0);
+        let block_type = block.block_type;
+        let (key, offset, header_off) = (block.last_key.clone(), block.offset, block.header_off);
+        let data = block.finish()?;
+        self.padding = match block_type {
+            BLOCK_LOG => 0,
+            _ => BLOCK_SIZE - (header_off + data.len()),
+        };
+        self.out.extend(data);
+        Ok((key, offset))
+    }
+
+    fn write_blocks(&mut self, block_type: u8, entries: &[Entry]) -> Result<Vec<(Vec<u8>, u64)>> {
+        let mut blocks = Vec::new();
+        let mut block = self.block(block_type);
+        for (key, value_type, value) in entries {
+            if block.add(key, *value_type, value) {
+                continue;
+            }
+            if block.count > 0 {
+                blocks.push(self.flush(block)?);
+                block = self.block(block_type);
+            }
+            if !block.add(key, *value_type, value) {
+                bail!(
+                    "Reftable record too large: {}",
+                    String::from_utf8_lossy(key)
+                );
+            }
+        }
+        if block.count > 0 {
+            blocks.push(self.flush(block)?);
+        }
+        Ok(blocks)
+    }
+
+    /// Write the blocks of a section, then index them level by level while
+    /// there are many. Returns the offset of the top index level, or 0.
+    fn write_section(&mut self, block_type: u8, entries: &[Entry]) -> Result<u64> {
+        let mut blocks = self.write_blocks(block_type, entries)?;
+        let mut index = 0;
+        while blocks.len() > INDEX_THRESHOLD {
+            index = (self.out.len() + self.padding) as u64;
+            let entries = blocks
+                .into_iter()
+                .map(|(key, offset)| (key, 0, offset_encoding(offset)))
+                .collect::<Vec<_>>();
+            blocks = self.write_blocks(BLOCK_INDEX, &entries)?;
+        }
+        Ok(index)
+    }
+}
+
+/// Serialize a table of the update indexes `min..=max` holding references
+/// sorted by name and log records sorted by key
+fn write_table(
+    algorithm: HashAlgorithm,
+    min: u64,
+    max: u64,
+    refs: &[RefRecord],
+    logs: &[LogRecord],
+) -> Result<Vec<u8>> {
+    // Version 1 implies SHA-1, version 2 names its hash function
+    let version = match algorithm {
+        HashAlgorithm::Sha1 => 1,
+        HashAlgorithm::Sha256 => 2,
+    };
+    let mut header = MAGIC.to_vec();
+    header.push(version);
+    header.extend(&(BLOCK_SIZE as u32).to_be_bytes()[1..]);
+    header.extend(min.to_be_bytes());
+    header.extend(max.to_be_bytes());
+    if version == 2 {
+        header.extend(b"s256");
+    }
+
+    let mut writer = TableWriter {
+        out: header.clone(),
+        header_len: header.len(),
+        padding: 0,
+    };
+    let refs = refs
+        .iter()
+        .map(|record| encode_ref(record, min))
+        .collect::<Vec<_>>();
+    let ref_index = writer.write_section(BLOCK_REF, &refs)?;
+    // Logs in the first block are found by its type, position 0 means none
+    let log_position = match refs.is_empty() || logs.is_empty() {
+        true => 0,
+        false => (writer.out.len() + writer.padding) as u64,
+    };
+    let logs = logs.iter().map(encode_log).collect::<Result<Vec<_>>>()?;
+    let log_index = writer.write_section(BLOCK_LOG, &logs)?;
+
+    let mut footer = header;
+    // No object blocks mapping hashes back to references are written
+    for offset in [ref_index, 0, 0, log_position, log_index] {
+        footer.extend(offset.to_be_bytes());
+    }
+    footer.extend(crc32fast::hash(&footer).to_be_bytes());
+    let mut out = writer.out;
+    out.extend(footer);
+    Ok(out)
+}
+
+/// Block is a block of a table, inflated if it holds logs
+struct Block {
+    block_type: u8,
+    /// The block from its start, so that restart offsets index it
+    data: Vec<u8>,
+    records: usize,
+    restarts: Vec<usize>,
+    /// End of the records, where the restart offsets start
+    records_end: usize,
+    /// Offset of the following block in the table
+    next: usize,
+}
+
+/// Table is a parsed reftable file
+#[derive(Debug)]
+pub(crate) struct Table {
+    data: Vec<u8>,
+    header_len: usize,
+    /// Where the footer starts, after the last block
+    end: usize,
+    block_size: usize,
+    algorithm: HashAlgorithm,
+    min_update_index: u64,
+    max_update_index: u64,
+    has_refs: bool,
+    ref_index: usize,
+    log_position: Option<usize>,
+    log_index: usize,
+}
+
+impl Table {
+    fn parse(data: Vec<u8>, algorithm: HashAlgorithm) -> Result<Table> {
+        if data.len() < 24 || &data[..4] != MAGIC {
+            bail!("Not a reftable file");
+        }
+        let version = data[4];
+        let table_algorithm = match (version, data.get(24..28)) {
+            (1, _) => HashAlgorithm::Sha1,
+            (2, Some(b"sha1")) => HashAlgorithm::Sha1,
+            (2, Some(b"s256")) => HashAlgorithm::Sha256,
+            _ => bail!("Unsupported reftable version {}", version),
+        };
+        if table_algorithm != algorithm {
+            bail!(
+                "Reftable uses {} but the repository uses {}",
+                table_algorithm,
+                algorithm
+            );
+        }
+        let (header_len, footer_len) = (header_len(version), footer_len(version));
+        if data.len() < header_len + footer_len {
+            bail!("Truncated reftable file");
+        }
+        let end = data.len() - footer_len;
+        let footer = &data[end..];
+        if footer[..header_len] != data[..header_len] {
+            bail!("Reftable footer does not match its header");
+        }
+        let (content, crc) = footer.split_at(footer_len - 4);
+        if crc32fast::hash(content).to_be_bytes() != crc {
+            bail!("Reftable footer checksum mismatch");
+        }
+        let u64_at = |bytes: &[u8], i: usize| {
+            u64::from_be_bytes(bytes[i..i + 8].try_into().unwrap()) as usize
+        };
+        let offset = |i: usize| u64_at(footer, header_len + 8 * i);
+        let first_block = (end > header_len).then(|| data[header_len]);
+        let log_position = match (first_block, offset(3)) {
+            (Some(BLOCK_LOG), _) => Some(0),
+            (_, 0) => None,
+            (_, position) => Some(position),
+        };
+        Ok(Table {
+            header_len,
+            end,
+            block_size: u24(&data[5..8]),
+            algorithm,
+            min_update_index: u64_at(&data, 8) as u64,
+            max_update_index: u64_at(&data, 16) as u64,
+            has_refs: first_block == Some(BLOCK_REF),
+            ref_index: offset(0),
+            log_position,
+            log_index: offset(4),
+            data,
+        })
+    }
+
+    /// The type of the block at an offset, if there is one
+    fn block_type_at(&self, offset: usize) -> Option<u8> {
+        let start = offset + if offset == 0 { self.header_len } else { 0 };
+        (start < self.end).then(|| self.data[start])
+    }
+
+    fn block(&self, offset: usize) -> Result<Block> {
+        let header_off = if offset == 0 { self.header_len } else { 0 };
+        let start = offset + header_off;
+        if start + 4 > self.end {
+            bail!("Reftable block at {} is out of bounds", offset);
+        }
+        let block_type = self.data[start];
+        let len = u24(&self.data[start + 1..start + 4]);
+        let (data, next) = if block_type == BLOCK_LOG {
+            let mut data = Vec::with_capacity(len);
+            data.extend(&self.data[offset..start + 4]);
+            let mut inflater = Decompress::new(true);
+            let status = inflater.decompress_vec(
+                &self.data[start + 4..self.end],
+                &mut data,
+                FlushDecompress::Finish,
+            )?;
+            if status != Status::StreamEnd || data.len() != len {
+                bail!("Corrupt reftable log block at {}", offset);
+            }
+            (data, start + 4 + inflater.total_in() as usize)
+        } else {
+            if offset + len > self.end {
+                bail!("Reftable block at {} is out of bounds", offset);
+            }
+            // Blocks are padded to the block size unless the next one
+            // follows right away
+            let unaligned = self.block_size == 0
+                || (len < self.block_size
+                    && offset + len < self.end
+                    && self.data[offset + len] != 0);
+            let size = if unaligned { len } else { self.block_size };
+            (self.data[offset..offset + len].to_vec(), offset + size)
+        };
+        let restarts_end = len.checked_sub(2).filter(|&n| n >= header_off + 4);
+        let Some(restarts_end) = restarts_end else {
+            bail!("Corrupt reftable block at {}", offset);
+        };
+        let count = u16::from_be_bytes([data[restarts_end], data[restarts_end + 1]]) as usize;
+        let Some(records_end) = restarts_end
+            .checked_sub(3 * count)
+            .filter(|&n| n >= header_off + 4)
+        else {
+            bail!("Corrupt reftable block at {}", offset);
+        };
+        let restarts = (0..count)
+            .map(|i| u24(&data[records_end + 3 * i..]))
+            .collect();
+        Ok(Block {
+            block_type,
+            data,
+            records: header_off + 4,
+            restarts,
+            records_end,
+            next: next.min(self.end),
+        })
+    }
+
+    fn hash(&self, input: &mut &[u8]) -> Result<ObjectHash> {
+        ObjectHash::from_bytes(take(input, self.algorithm.size())?, self.algorithm)
+    }
+
+    /// Decode the record at the start of `input`, whose key shares a prefix
+    /// with `key`, the previous one, and becomes the new `key`
+    fn decode(&self, block_type: u8, input: &mut &[u8], key: &mut Vec<u8>) -> Result<Record> {
+        let prefix = read_offset_delta(input)? as usize;
+        let suffix_and_type = read_offset_delta(input)? as usize;
+        let suffix = take(input, suffix_and_type >> 3)?;
+        let value_type = (suffix_and_type & 7) as u8;
+        if prefix > key.len() {
+            bail!("Corrupt reftable record key");
+        }
+        key.truncate(prefix);
+        key.extend(suffix);
+        match block_type {
+            BLOCK_REF => {
+                let update_index = self.min_update_index + read_offset_delta(input)?;
+                let value = match value_type {
+                    REF_DELETION => None,
+                    REF_VALUE | REF_PEELED => {
+                        let hash = self.hash(input)?;
+                        if value_type == REF_PEELED {
+                            self.hash(input)?;
+                        }
+                        Some(RefValue::Direct(hash))
+                    }
+                    REF_SYMBOLIC => Some(RefValue::Symbolic(take_string(input)?)),
+                    _ => bail!("Unknown reftable value type {}", value_type),
+                };
+                Ok(Record::Ref(RefRecord {
+                    name: String::from_utf8(key.clone())?,
+                    update_index,
+                    value,
+                }))
+            }
+            BLOCK_LOG => {
+                let len = key.len();
+                if len < 9 || key[len - 9] != 0 {
+                    bail!("Corrupt reftable log key");
+                }
+                let update_index = u64::MAX - u64::from_be_bytes(key[len - 8..].try_into()?);
+                let entry = match value_type {
+                    LOG_DELETION => None,
+                    LOG_UPDATE => {
+                        let old = self.hash(input)?;
+                        let new = self.hash(input)?;
+                        let name = take_string(input)?;
+                        let email = take_string(input)?;
+                        let time = read_offset_delta(input)? as i64;
+                        let tz = i16::from_be_bytes(take(input, 2)?.try_into()?);
+                        let message = take_string(input)?;
+                        let signature = Signature {
+                            name,
+                            email,
+                            time,
+                            offset: format_tz(tz),
+                        };
+                        Some(ReflogEntry {
+                            old,
+                            new,
+                            committer: signature.to_string(),
+                            message: message.strip_suffix('\n').unwrap_or(&message).to_string(),
+                        })
+                    }
+                    _ => bail!("Unknown reftable log type {}", value_type),
+                };
+                Ok(Record::Log(LogRecord {
+                    name: String::from_utf8(key[..len - 9].to_vec())?,
+                    update_index,
+                    entry,
+                }))
+            }
+            BLOCK_INDEX => Ok(Record::Index(read_offset_delta(input)? as usize)),
+            _ => bail!("Unexpected reftable block type {}", block_type),
+        }
+    }
+
+    /// Iterate over the records of the block at `offset` and the following
+    /// blocks of its type, from the first key not below `want`
+    fn seek_block(&self, offset: usize, want: &[u8]) -> Result<Records<'_>> {
+        let block = self.block(offset)?;
+        // Restart points hold full keys: binary search the last one below
+        let restart_key = |restart: usize| -> Result<Vec<u8>> {
+            let mut input = &block.data[restart..block.records_end];
+            read_offset_delta(&mut input)?;
+            let suffix = read_offset_delta(&mut input)? as usize >> 3;
+            Ok(take(&mut input, suffix)?.to_vec())
+        };
+        let (mut low, mut high) = (0, block.restarts.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            match restart_key(block.restarts[middle])?.as_slice() < want {
+                true => low = middle + 1,
+                false => high = middle,
+            }
+        }
+        let start = match low {
+            0 => block.records,
+            _ => block.restarts[low - 1],
+        };
+        let mut records = Records {
+            table: self,
+            pos: start,
+            block: Some(block),
+            key: Vec::new(),
+            peeked: None,
+        };
+        while let Some((key, record)) = records.next_record()? {
+            if key.as_slice() >= want {
+                records.peeked = Some((key, record));
+                break;
+            }
+        }
+        Ok(records)
+    }
+
+    /// Iterate over the ref or log section from the first key not below
+    /// `want`, going down the index when there is one
+    fn seek(&self, block_type: u8, want: &[u8]) -> Result<Records<'_>> {
+        let (start, index) = match block_type {
+            BLOCK_REF => (self.has_refs.then_some(0), self.ref_index),
+            _ => (self.log_position, self.log_index),
+        };
+        let Some(start) = start else {
+            return Ok(Records::empty(self));
+        };
+        if index == 0 {
+            return self.seek_block(start, want);
+        }
+        let mut offset = index;
+        loop {
+            // The first index key not below `want` is the last key of the
+            // block that would hold it
+            let Some((_, record)) = self.seek_block(offset, want)?.next().transpose()? else {
+                return Ok(Records::empty(self));
+            };
+            let Record::Index(position) = record else {
+                bail!("Corrupt reftable index at {}", offset);
+            };
+            match self.block_type_at(position) {
+                Some(BLOCK_INDEX) => offset = position,
+                _ => return self.seek_block(position, want),
+            }
+        }
+    }
+
+    /// Return the record of a reference, which may be a deletion
+    fn find_ref(&self, name: &str) -> Result<Option<RefRecord>> {
+        match self.seek(BLOCK_REF, name.as_bytes())?.next().transpose()? {
+            Some((_, Record::Ref(record))) if record.name == name => Ok(Some(record)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Return the ref records whose name starts with `prefix`
+    fn refs(&self, prefix: &str) -> Result<Vec<RefRecord>> {
+        let mut refs = Vec::new();
+        for item in self.seek(BLOCK_REF, prefix.as_bytes())? {
+            match item? {
+                (_, Record::Ref(record)) if record.name.starts_with(prefix) => refs.push(record),
+                _ => break,
+            }
+        }
+        Ok(refs)
+    }
+
+    /// Return the log records of a reference, or of every one, newest first
+    fn logs(&self, name: Option<&str>) -> Result<Vec<LogRecord>> {
+        let want = name.map_or(Vec::new(), |name| format!("{}\0", name).into_bytes());
+        let mut logs = Vec::new();
+        for item in self.seek(BLOCK_LOG, &want)? {
+            match item? {
+                (_, Record::Log(record)) if name.is_none_or(|name| record.name == name) => {
+                    logs.push(record)
+                }
+                _ => break,
+            }
+        }
+        Ok(logs)
+    }
+}
+
+/// Records iterates over the records of consecutive blocks of one type
+struct Records<'a> {
+    table: &'a Table,
+    block: Option<Block>,
+    pos: usize,
+    key: Vec<u8>,
+    peeked: Option<(Vec<u8>, Record)>,
+}
+
+impl<'a> Records<'a> {
+    fn empty(table: &'a Table) -> Records<'a> {
+        Records {
+            table,
+            block: None,
+            pos: 0,
+            key: Vec::new(),
+            peeked: None,
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<(Vec<u8>, Record)>> {
+        if let Some(peeked) = self.peeked.take() {
+            return Ok(Some(peeked));
+        }
+        while let Some(block) = &self.block {
+            if self.pos < block.records_end {
+                let mut input = &block.data[self.pos..block.records_end];
+                let record = self
+                    .table
+                    .decode(block.block_type, &mut input, &mut self.key)?;
+                self.pos = block.records_end - input.len();
+                return Ok(Some((self.key.clone(), record)));
+            }
+            let (next, block_type) = (block.next, block.block_type);
+            self.block = None;
+            if self.table.block_type_at(next) == Some(block_type) {
+                let block = self.table.block(next)?;
+                self.pos = block.records;
+                self.key.clear();
+                self.block = Some(block);
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for Records<'_> {
+    type Item = Result<(Vec<u8>, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+/// Parsed tables by path; a table file never changes once written, so an
+/// entry only goes once its table leaves the stack
+fn table_cache() -> &'static Mutex<HashMap<PathBuf, Arc<Table>>> {
+    static CACHE: OnceLock<Mutex<HashMap<PathBuf, Arc<Table>>>> = OnceLock::new();
+    CACHE.get_or_init(Default::default)
+}
+
+/// Return the directory of the stack under a reference root: the common
+/// directory, or the git directory of a linked worktree for its own
+/// references
+fn reftable_dir(root: &Path) -> PathBuf {
+    root.join("reftable")
+}
+
+/// Stack is the tables of `reftable/tables.list`, oldest first, each one
+/// overriding the references and reflog entries of the previous ones
+struct Stack {
+    names: Vec<String>,
+    tables: Vec<Arc<Table>>,
+}
+
+impl Stack {
+    fn open(repo: &Repository, root: &Path) -> Result<Stack> {
+        let dir = reftable_dir(root);
+        let list = match fs::read_to_string(dir.join("tables.list")) {
+            Ok(list) => list,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).context("Failed to read reftable/tables.list"),
+        };
+        let names = list
+            .lines()
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        // Forget the tables another writer compacted away
+        table_cache().lock().unwrap().retain(|path, _| {
+            path.parent() != Some(dir.as_path())
+                || path
+                    .file_name()
+                    .is_some_and(|name| names.iter().any(|n| name == n.as_str()))
+        });
+        let mut tables = Vec::new();
+        for name in &names {
+            let path = dir.join(name);
+            if let Some(table) = table_cache().lock().unwrap().get(&path) {
+                tables.push(table.clone());
+                continue;
+            }
+            let data =
+                fs::read(&path).with_context(|| format!("Failed to read reftable {}", name))?;
+            let table = Arc::new(
+                Table::parse(data, repo.hash_algorithm())
+                    .with_context(|| format!("Invalid reftable {}", name))?,
+            );
+            table_cache().lock().unwrap().insert(path, table.clone());
+            tables.push(table);
+        }
+        Ok(Stack { names, tables })
+    }
+
+    fn next_update_index(&self) -> u64 {
+        self.tables
+            .last()
+            .map_or(1, |table| table.max_update_index + 1)
+    }
+
+    /// Return the live reflog entries of a reference by update index
+    fn logs(&self, name: &str) -> Result<BTreeMap<u64, ReflogEntry>> {
+        let mut logs = BTreeMap::new();
+        for table in &self.tables {
+            for record in table.logs(Some(name))? {
+                match record.entry {
+                    Some(entry) => logs.insert(record.update_index, entry),
+                    None => logs.remove(&record.update_index),
+                };
+            }
+        }
+        Ok(logs)
+    }
+}
+
+/// Merge tables into one covering their update indexes. Deletions are only
+/// needed to hide older tables, so they go when merging from the oldest.
+fn merge_tables(
+    algorithm: HashAlgorithm,
+    tables: &[Arc<Table>],
+    keep_deletions: bool,
+) -> Result<Vec<u8>> {
+    let mut refs = BTreeMap::new();
+    let mut logs = BTreeMap::new();
+    for table in tables {
+        for record in table.refs("")? {
+            refs.insert(record.name.clone(), record);
+        }
+        for record in table.logs(None)? {
+            logs.insert(record.key(), record);
+        }
+    }
+    let refs = refs
+        .into_values()
+        .filter(|record| keep_deletions || record.value.is_some())
+        .collect::<Vec<_>>();
+    let logs = logs
+        .into_values()
+        .filter(|record| keep_deletions || record.entry.is_some())
+        .collect::<Vec<_>>();
+    let min = tables.first().map_or(1, |table| table.min_update_index);
+    let max = tables.last().map_or(1, |table| table.max_update_index);
+    write_table(algorithm, min, max, &refs, &logs)
+}
+
+/// Find the newest tables to merge so that every table is at least twice
+/// as large as the next newer one, which keeps the stack logarithmic
+fn compaction_start(sizes: &[usize]) -> Option<usize> {
+    let mut start = sizes.len().checked_sub(1)?;
+    let mut total = sizes[start];
+    while start > 0 && sizes[start - 1] < 2 * total {
+        start -= 1;
+        total += sizes[start];
+    }
+    (start + 1 < sizes.len()).then_some(start)
+}
+
+fn table_name(min: u64, max: u64) -> String {
+    let random = RandomState::new().build_hasher().finish() as u32;
+    format!("0x{:012x}-0x{:012x}-{:08x}.ref", min, max, random)
+}
+
+/// Update the stack while holding `tables.list.lock`. `update` gets the
+/// current stack and returns the tables to use instead of the ones from
+/// an index on, or `None` to leave the stack as it is.
+fn update_stack(
+    repo: &Repository,
+    root: &Path,
+    update: impl FnOnce(&Stack) -> Result<Option<(usize, Vec<Vec<u8>>)>>,
+) -> Result<()> {
+    let dir = reftable_dir(root);
+    fs::create_dir_all(&dir)?;
+    let lock = dir.join("tables.list.lock");
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock)
+        .with_context(|| format!("Unable to lock {}", lock.display()))?;
+    let result = (|| {
+        let stack = Stack::open(repo, root)?;
+        let Some((start, tables)) = update(&stack)? else {
+            fs::remove_file(&lock)?;
+            return Ok(());
+        };
+        let mut names = stack.names[..start].to_vec();
+        for data in tables {
+            let table = Table::parse(data, repo.hash_algorithm())?;
+            let name = table_name(table.min_update_index, table.max_update_index);
+            fs::write(dir.join(&name), &table.data)
+                .with_context(|| format!("Failed to write reftable {}", name))?;
+            table_cache()
+                .lock()
+                .unwrap()
+                .insert(dir.join(&name), Arc::new(table));
+            names.push(name);
+        }
+        let list = names
+            .iter()
+            .map(|name| format!("{}\n", name))
+            .collect::<String>();
+        fs::write(&lock, list)?;
+        fs::rename(&lock, dir.join("tables.list"))?;
+        for name in &stack.names[start..] {
+            let path = dir.join(name);
+            table_cache().lock().unwrap().remove(&path);
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove reftable {}", name))?;
+        }
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&lock);
+    }
+    result
+}
+
+/// Add a table holding the records `build` makes for the next update
+/// index, then compact the newest tables if they got too large
+fn add_table(
+    repo: &Repository,
+    root: &Path,
+    build: impl FnOnce(&Stack, u64) -> Result<(Vec<RefRecord>, Vec<LogRecord>)>,
+) -> Result<()> {
+    let algorithm = repo.hash_algorithm();
+    update_stack(repo, root, |stack| {
+        let min = stack.next_update_index();
+        let (mut refs, mut logs) = build(stack, min)?;
+        refs.sort_by(|a, b| a.name.cmp(&b.name));
+        logs.sort_by_key(LogRecord::key);
+        let max = refs
+            .iter()
+            .map(|record| record.update_index)
+            .chain(logs.iter().map(|record| record.update_index))
+            .fold(min, u64::max);
+        let table = Arc::new(Table::parse(
+            write_table(algorithm, min, max, &refs, &logs)?,
+            algorithm,
+        )?);
+        let mut tables = stack.tables.clone();
+        tables.push(table);
+        let sizes = tables
+            .iter()
+            .map(|table| table.data.len())
+            .collect::<Vec<_>>();
+        let start = compaction_start(&sizes).unwrap_or(tables.len() - 1);
+        let merged = match tables.len() - start {
+            1 => tables[start].data.clone(),
+            _ => merge_tables(algorithm, &tables[start..], start > 0)?,
+        };
+        Ok(Some((start, vec![merged])))
+    })
+}
+
+/// Read a reference from the newest table that has it
+pub(crate) fn read_ref(repo: &Repository, name: &str) -> Result<Option<RefValue>> {
+    let stack = Stack::open(repo, ref_root(repo, name))?;
+    for table in stack.tables.iter().rev() {
+        if let Some(record) = table.find_ref(name)? {
+            return Ok(record.value);
+        }
+    }
+    Ok(None)
+}
+
+/// List the references whose name starts with `prefix`
+pub(crate) fn list_refs(repo: &Repository, prefix: &str) -> Result<BTreeMap<String, RefValue>> {
+    let mut refs = BTreeMap::new();
+    for root in ref_roots(repo) {
+        for table in Stack::open(repo, root)?.tables {
+            for record in table.refs(prefix)? {
+                match record.value {
+                    _ if ref_root(repo, &record.name) != root => continue,
+                    Some(value) => refs.insert(record.name, value),
+                    None => refs.remove(&record.name),
+                };
+            }
+        }
+    }
+    Ok(refs)
+}
+
+/// Set a reference, or delete it with a `None` value. A reflog entry goes
+/// into the same table, under the same update index.
+pub(crate) fn write_ref(
+    repo: &Repository,
+    name: &str,
+    value: Option<RefValue>,
+    log: Option<&ReflogEntry>,
+) -> Result<()> {
+    add_table(repo, ref_root(repo, name), |_, update_index| {
+        let record = RefRecord {
+            name: name.to_string(),
+            update_index,
+            value,
+        };
+        let logs = log.map(|entry| LogRecord {
+            name: name.to_string(),
+            update_index,
+            entry: Some(entry.clone()),
+        });
+        Ok((vec![record], logs.into_iter().collect()))
+    })
+    .with_context(|| format!("Failed to update reference {}", name))
+}
+
+/// Return every entry of a reference's reflog, oldest first
+pub(crate) fn read_reflog(repo: &Repository, name: &str) -> Result<Vec<ReflogEntry>> {
+    Ok(Stack::open(repo, ref_root(repo, name))?
+        .logs(name)?
+        .into_values()
+        .collect())
+}
+
+/// Add an entry at the end of a reference's reflog
+pub(crate) fn append_reflog(repo: &Repository, name: &str, entry: &ReflogEntry) -> Result<()> {
+    add_table(repo, ref_root(repo, name), |_, update_index| {
+        let record = LogRecord {
+            name: name.to_string(),
+            update_index,
+            entry: Some(entry.clone()),
+        };
+        Ok((Vec::new(), vec![record]))
+    })
+    .with_context(|| format!("Failed to write the reflog of {}", name))
+}
+
+/// Replace a reference's reflog by deleting its entries and adding the new
+/// ones at the next update indexes
+pub(crate) fn write_reflog(repo: &Repository, name: &str, entries: &[ReflogEntry]) -> Result<()> {
+    add_table(repo, ref_root(repo, name), |stack, next| {
+        let mut logs = stack
+            .logs(name)?
+            .into_keys()
+            .map(|update_index| LogRecord {
+                name: name.to_string(),
+                update_index,
+                entry: None,
+            })
+            .collect::<Vec<_>>();
+        logs.extend(
+            entries
+                .iter()
+                .zip(next..)
+                .map(|(entry, update_index)| LogRecord {
+                    name: name.to_string(),
+                    update_index,
+                    entry: Some(entry.clone()),
+                }),
+        );
+        Ok((Vec::new(), logs))
+    })
+    .with_context(|| format!("Failed to write the reflog of {}", name))
+}
+
+/// List the references that have reflog entries
+pub(crate) fn reflog_names(repo: &Repository) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for root in ref_roots(repo) {
+        let mut logs = BTreeMap::new();
+        for table in Stack::open(repo, root)?.tables {
+            for record in table.logs(None)? {
+                logs.insert(record.key(), record);
+            }
+        }
+        let mut found = logs
+            .into_values()
+            .filter(|record| record.entry.is_some() && ref_root(repo, &record.name) == root)
+            .map(|record| record.name)
+            .collect::<Vec<_>>();
+        found.dedup();
+        names.extend(found);
+    }
+    Ok(names)
+}
+
+/// Merge every table into one, like `git pack-refs` does for reftables.
+/// Returns the names of the direct references of the merged tables.
+pub(crate) fn compact(repo: &Repository, dry_run: bool) -> Result<Vec<String>> {
+    let refs = list_refs(repo, "")?;
+    let mut names = Vec::new();
+    for root in ref_roots(repo) {
+        if Stack::open(repo, root)?.tables.len() < 2 {
+            continue;
+        }
+        names.extend(
+            refs.iter()
+                .filter(|(name, value)| {
+                    matches!(value, RefValue::Direct(_)) && ref_root(repo, name) == root
+                })
+                .map(|(name, _)| name.clone()),
+        );
+        if !dry_run {
+            let algorithm = repo.hash_algorithm();
+            update_stack(repo, root, |stack| {
+                Ok(Some((
+                    0,
+                    vec![merge_tables(algorithm, &stack.tables, false)?],
+                )))
+            })?;
+        }
+    }
+    Ok(names)
+}
+
+/// Prepare the git directory of a new repository storing its references
+/// in reftables, with the stubs git leaves so that older versions do not
+/// take it for a repository using files
+pub(crate) fn init(gitdir: &Path) -> Result<()> {
+    fs::create_dir_all(gitdir.join("reftable"))?;
+    fs::write(gitdir.join("reftable").join("tables.list"), "")?;
+    fs::write(gitdir.join("HEAD"), "ref: refs/heads/.invalid\n")?;
+    fs::create_dir_all(gitdir.join("refs"))?;
+    fs::write(
+        gitdir.join("refs").join("heads"),
+        "this repository uses the reftable format\n",
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::refs::{self, RefStorage};
+    use crate::test_utils::commit_files;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_table_roundtrip() {
+        let algorithm = HashAlgorithm::Sha1;
+        let hash = |i: usize| algorithm.digest(i.to_string().as_bytes());
+        let mut refs = (0..3000)
+            .map(|i| RefRecord {
+                name: format!("refs/heads/branch-{:05}", i),
+                update_index: 1 + i as u64 % 3,
+                value: Some(RefValue::Direct(hash(i))),
+            })
+            .collect::<Vec<_>>();
+        refs.push(RefRecord {
+            name: "refs/remotes/origin/HEAD".to_string(),
+            update_index: 3,
+            value: Some(RefValue::Symbolic("refs/remotes/origin/main".to_string())),
+        });
+        refs.push(RefRecord {
+            name: "refs/tags/gone".to_string(),
+            update_index: 3,
+            value: None,
+        });
+        let logs = (1..=3)
+            .rev()
+            .map(|update_index| LogRecord {
+                name: "HEAD".to_string(),
+                update_index,
+                entry: Some(ReflogEntry {
+                    old: algorithm.null_hash(),
+                    new: hash(update_index as usize),
+                    committer: "A U Thor <a@example.com> 1700000000 -0130".to_string(),
+                    message: format!("commit: {}", update_index),
+                }),
+            })
+            .collect::<Vec<_>>();
+
+        let data = write_table(algorithm, 1, 3, &refs, &logs).unwrap();
+        let table = Table::parse(data, algorithm).unwrap();
+        assert_ne!(table.ref_index, 0, "many blocks get an index");
+        assert_eq!(
+            table.block(0).unwrap().next,
+            BLOCK_SIZE,
+            "blocks are padded"
+        );
+        for i in [0, 15, 16, 17, 1234, 2999] {
+            let record = table
+                .find_ref(&format!("refs/heads/branch-{:05}", i))
+                .unwrap();
+            assert_eq!(record.unwrap().value, Some(RefValue::Direct(hash(i))));
+        }
+        assert!(table.find_ref("refs/heads/branch").unwrap().is_none());
+        assert!(table.find_ref("refs/heads/zzz").unwrap().is_none());
+        assert_eq!(table.refs("refs/heads/branch-012").unwrap().len(), 100);
+        assert_eq!(table.refs("").unwrap(), refs);
+        assert_eq!(table.refs("refs/tags/").unwrap()[0].value, None);
+        assert_eq!(table.logs(Some("HEAD")).unwrap(), logs);
+        assert!(table.logs(Some("HEA")).unwrap().is_empty());
+        assert!(Table::parse(
+            write_table(algorithm, 1, 1, &[], &[]).unwrap(),
+            HashAlgorithm::Sha256
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_reftable_repository() {
+        let tempdir = TempDir::new().unwrap();
+        let repo =
+            Repository::new_with_formats(tempdir.path(), HashAlgorithm::Sha1, RefStorage::Reftable)
+                .unwrap();
+        assert_eq!(
+            refs::read_head(&repo).unwrap(),
+            refs::Head::Branch("refs/heads/master".to_string())
+        );
+        let mut commits = Vec::new();
+        for i in 0..40 {
+            let hash = commit_files(&repo, &[("a.txt", &i.to_string())], "commit");
+            refs::update_ref(&repo, &format!("refs/tags/v{}", i), &hash).unwrap();
+            commits.push(hash);
+        }
+        let stack = Stack::open(&repo, repo.gitdir()).unwrap();
+        assert!(stack.tables.len() < 8, "{} tables", stack.tables.len());
+        assert_eq!(stack.next_update_index(), 82);
+        assert_eq!(
+            refs::resolve_ref(&repo, "HEAD").unwrap(),
+            commits.last().cloned()
+        );
+        assert_eq!(refs::list_refs(&repo, "refs/tags/").unwrap().len(), 40);
+
+        refs::delete_ref(&repo, "refs/tags/v3").unwrap();
+        assert!(refs::delete_ref(&repo, "refs/tags/v3").is_err());
+        assert_eq!(refs::resolve_ref(&repo, "refs/tags/v3").unwrap(), None);
+        assert_eq!(refs::list_refs(&repo, "refs/").unwrap().len(), 40);
+
+        let entry = |i: usize| ReflogEntry {
+            old: commits[i].clone(),
+            new: commits[i + 1].clone(),
+            committer: "A <a@x> 1700000000 +0000".to_string(),
+            message: format!("move {}", i),
+        };
+        refs::append_reflog(&repo, "refs/heads/master", &entry(0)).unwrap();
+        refs::append_reflog(&repo, "refs/heads/master", &entry(1)).unwrap();
+        assert_eq!(
+            refs::read_reflog(&repo, "refs/heads/master").unwrap(),
+            [entry(0), entry(1)]
+        );
+        refs::write_reflog(&repo, "refs/heads/master", &[entry(1)]).unwrap();
+        assert_eq!(
+            refs::read_reflog(&repo, "refs/heads/master").unwrap(),
+            [entry(1)]
+        );
+        assert_eq!(reflog_names(&repo).unwrap(), ["refs/heads/master"]);
+
+        let packed = compact(&repo, false).unwrap();
+        assert_eq!(packed.len(), 40);
+        let stack = Stack::open(&repo, repo.gitdir()).unwrap();
+        assert_eq!(stack.tables.len(), 1);
+        assert_eq!(
+            fs::read_dir(reftable_dir(repo.gitdir())).unwrap().count(),
+            2
+        );
+        assert_eq!(refs::resolve_ref(&repo, "refs/tags/v3").unwrap(), None);
+        assert_eq!(
+            refs::read_reflog(&repo, "refs/heads/master").unwrap(),
+            [entry(1)]
+        );
+    }
+
+    #[test]
+    fn test_ref_and_reflog_share_a_table() {
+        let tempdir = TempDir::new().unwrap();
+        let repo =
+            Repository::new_with_formats(tempdir.path(), HashAlgorithm::Sha1, RefStorage::Reftable)
+                .unwrap();
+        let one = commit_files(&repo, &[("a.txt", "1")], "one");
+        let two = commit_files(&repo, &[("a.txt", "2")], "two");
+        let next = Stack::open(&repo, repo.gitdir())
+            .unwrap()
+            .next_update_index();
+        let entry = ReflogEntry {
+            old: one,
+            new: two.clone(),
+            committer: "A <a@x> 1700000000 +0000".to_string(),
+            message: "move".to_string(),
+        };
+        refs::update_ref_with_reflog(&repo, "refs/stash", &two, &entry).unwrap();
+
+        // One update index and one table for the ref and its log together
+        let stack = Stack::open(&repo, repo.gitdir()).unwrap();
+        assert_eq!(stack.next_update_index(), next + 1);
+        let table = stack.tables.last().unwrap();
+        let record = table.find_ref("refs/stash").unwrap().unwrap();
+        assert_eq!(record.update_index, next);
+        let logs = table.logs(Some("refs/stash")).unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].update_index, next);
+        assert_eq!(refs::read_reflog(&repo, "refs/stash").unwrap(), [entry]);
+    }
+
+    fn reftable_repo() -> (TempDir, Repository) {
+        let tempdir = TempDir::new().unwrap();
+        let repo =
+            Repository::new_with_formats(tempdir.path(), HashAlgorithm::Sha1, RefStorage::Reftable)
+                .unwrap();
+        (tempdir, repo)
+    }
+
+    fn entry(old: &ObjectHash, new: &ObjectHash, message: &str) -> ReflogEntry {
+        ReflogEntry {
+            old: old.clone(),
+            new: new.clone(),
+            committer: "A <a@x> 1700000000 +0000".to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_locked_stack_is_left_alone() {
+        let (_dir, repo) = reftable_repo();
+        let one = commit_files(&repo, &[("a.txt", "1")], "one");
+        let two = commit_files(&repo, &[("a.txt", "2")], "two");
+        refs::update_ref(&repo, "refs/tags/v1", &one).unwrap();
+        let dir = reftable_dir(repo.gitdir());
+        let list = fs::read_to_string(dir.join("tables.list")).unwrap();
+
+        // Another writer holds the lock: neither the reference nor its
+        // reflog entry may be written, and the lock stays
+        let lock = dir.join("tables.list.lock");
+        fs::write(&lock, "").unwrap();
+        let update = entry(&one, &two, "move");
+        let err = refs::update_ref_with_reflog(&repo, "refs/tags/v1", &two, &update).unwrap_err();
+        assert!(format!("{:#}", err).contains("Unable to lock"), "{:#}", err);
+        assert!(lock.exists());
+        assert_eq!(fs::read_to_string(dir.join("tables.list")).unwrap(), list);
+        assert_eq!(
+            fs::read_dir(&dir).unwrap().count(),
+            list.lines().count() + 2
+        );
+        assert_eq!(
+            refs::resolve_ref(&repo, "refs/tags/v1").unwrap(),
+            Some(one.clone())
+        );
+        assert!(refs::read_reflog(&repo, "refs/tags/v1").unwrap().is_empty());
+
+        fs::remove_file(&lock).unwrap();
+        refs::update_ref_with_reflog(&repo, "refs/tags/v1", &two, &update).unwrap();
+        assert_eq!(refs::resolve_ref(&repo, "refs/tags/v1").unwrap(), Some(two));
+        assert_eq!(refs::read_reflog(&repo, "refs/tags/v1").unwrap(), [update]);
+    }
+
+    #[test]
+    fn test_compaction_drops_deletions() {
+        let (_dir, repo) = reftable_repo();
+        let one = commit_files(&repo, &[("a.txt", "1")], "one");
+        let two = commit_files(&repo, &[("a.txt", "2")], "two");
+        for i in 0..40 {
+            refs::update_ref(&repo, &format!("refs/tags/v{}", i), &one).unwrap();
+        }
+        compact(&repo, false).unwrap();
+        let name = "refs/heads/master";
+        let old = entry(&one, &two, "old");
+        let kept = entry(&two, &one, "kept");
+        refs::append_reflog(&repo, name, &old).unwrap();
+        refs::append_reflog(&repo, name, &kept).unwrap();
+        refs::write_reflog(&repo, name, std::slice::from_ref(&kept)).unwrap();
+        refs::delete_ref(&repo, "refs/tags/v3").unwrap();
+
+        // The small newer tables are merged among themselves only, so they
+        // keep the deletions hiding what the oldest table holds
+        let stack = Stack::open(&repo, repo.gitdir()).unwrap();
+        assert!(stack.tables.len() > 1);
+        let newer = &stack.tables[1..];
+        assert!(newer
+            .iter()
+            .any(|t| t.refs("").unwrap().iter().any(|r| r.value.is_none())));
+        assert!(newer
+            .iter()
+            .any(|t| t.logs(None).unwrap().iter().any(|r| r.entry.is_none())));
+
+        compact(&repo, false).unwrap();
+        let stack = Stack::open(&repo, repo.gitdir()).unwrap();
+        assert_eq!(stack.tables.len(), 1);
+        let table = &stack.tables[0];
+        let refs = table.refs("").unwrap();
+        assert!(refs.iter().all(|r| r.value.is_some()));
+        assert!(!refs.iter().any(|r| r.name == "refs/tags/v3"));
+        assert_eq!(refs.len(), 2 + 39, "HEAD, master and the tags");
+        let logs = table.logs(None).unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].entry, Some(kept.clone()));
+        assert_eq!(refs::read_reflog(&repo, name).unwrap(), [kept]);
+        assert_eq!(refs::resolve_ref(&repo, "refs/tags/v3").unwrap(), None);
+    }
+
+    #[test]
+    fn test_stack_sees_tables_of_other_writers() {
+        let (_dir, repo) = reftable_repo();
+        let one = commit_files(&repo, &[("a.txt", "1")], "one");
+        let two = commit_files(&repo, &[("a.txt", "2")], "two");
+        refs::update_ref(&repo, "refs/tags/v1", &one).unwrap();
+        let stack = Stack::open(&repo, repo.gitdir()).unwrap();
+        let next = stack.next_update_index();
+
+        // Another process appends a table without going through the cache
+        let record = RefRecord {
+            name: "refs/tags/v1".to_string(),
+            update_index: next,
+            value: Some(RefValue::Direct(two.clone())),
+        };
+        let data = write_table(HashAlgorithm::Sha1, next, next, &[record], &[]).unwrap();
+        let dir = reftable_dir(repo.gitdir());
+        let name = table_name(next, next);
+        fs::write(dir.join(&name), data).unwrap();
+        let mut list = fs::read_to_string(dir.join("tables.list")).unwrap();
+        list.push_str(&format!("{}\n", name));
+        fs::write(dir.join("tables.list"), &list).unwrap();
+        assert_eq!(
+            refs::resolve_ref(&repo, "refs/tags/v1").unwrap(),
+            Some(two.clone())
+        );
+        assert_eq!(
+            Stack::open(&repo, repo.gitdir())
+                .unwrap()
+                .next_update_index(),
+            next + 1
+        );
+
+        // And then replaces the whole stack with a table of its own
+        let record = RefRecord {
+            name: "refs/tags/v2".to_string(),
+            update_index: 1,
+            value: Some(RefValue::Direct(one.clone())),
+        };
+        let data = write_table(HashAlgorithm::Sha1, 1, next + 1, &[record], &[]).unwrap();
+        let name = table_name(1, next + 1);
+        fs::write(dir.join(&name), data).unwrap();
+        fs::write(dir.join("tables.list"), format!("{}\n", name)).unwrap();
+        for old in list.lines() {
+            fs::remove_file(dir.join(old)).unwrap();
+        }
+        assert_eq!(refs::resolve_ref(&repo, "refs/tags/v1").unwrap(), None);
+        assert_eq!(refs::resolve_ref(&repo, "refs/tags/v2").unwrap(), Some(one));
+        let cache = table_cache().lock().unwrap();
+        assert!(!list.lines().any(|old| cache.contains_key(&dir.join(old))));
+        drop(cache);
+        refs::update_ref(&repo, "refs/tags/v3", &two).unwrap();
+        assert_eq!(refs::list_refs(&repo, "refs/tags/").unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,192 @@
+use crate::refs::check_ref_name;
+use crate::Repository;
+use anyhow::{bail, Result};
+use std::fmt::Display;
+
+/// RefSpec maps remote references to local ones, e.g.
+/// `+refs/heads/*:refs/remotes/origin/*`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefSpec {
+    /// Update the destination even when it is not a fast-forward
+    pub force: bool,
+    pub src: String,
+    /// Where to store the fetched reference; `None` only fetches it
+    pub dst: Option<String>,
+}
+
+impl RefSpec {
+    /// Parse a refspec; `src` and `dst` may each hold a single `*`
+    pub fn parse(spec: &str) -> Result<RefSpec> {
+        let (force, rest) = match spec.strip_prefix('+') {
+            Some(rest) => (true, rest),
+            None => (false, spec),
+        };
+        let (src, dst) = match rest.split_once(':') {
+            Some((src, "")) => (src, None),
+            Some((src, dst)) => (src, Some(dst.to_string())),
+            None => (rest, None),
+        };
+        let wildcards = |s: &str| s.matches('*').count();
+        let dst_wildcards = dst.as_deref().map_or(0, wildcards);
+        if src.is_empty()
+            || wildcards(src) > 1
+            || (dst.is_some() && wildcards(src) != dst_wildcards)
+        {
+            bail!("Invalid refspec '{}'", spec);
+        }
+        Ok(RefSpec {
+            force,
+            src: src.to_string(),
+            dst,
+        })
+    }
+
+    /// Return true if the source is a pattern such as `refs/heads/*`
+    pub fn is_pattern(&self) -> bool {
+        self.src.contains('*')
+    }
+
+    /// Return true if a remote reference name matches the source
+    pub fn matches(&self, name: &str) -> bool {
+        match self.src.split_once('*') {
+            Some((prefix, suffix)) => {
+                name.len() >= prefix.len() + suffix.len()
+                    && name.starts_with(prefix)
+                    && name.ends_with(suffix)
+            }
+            None => name == self.src,
+        }
+    }
+
+    /// Map a remote reference to its local name, if the refspec matches it
+    /// and has a destination
+    pub fn map(&self, name: &str) -> Option<String> {
+        if !self.matches(name) {
+            return None;
+        }
+        let dst = self.dst.as_ref()?;
+        match self.src.split_once('*') {
+            Some((prefix, suffix)) => {
+                let matched = &name[prefix.len()..name.len() - suffix.len()];
+                Some(dst.replacen('*', matched, 1))
+            }
+            None => Some(dst.clone()),
+        }
+    }
+}
+
+impl Display for RefSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.force {
+            write!(f, "+")?;
+        }
+        write!(f, "{}", self.src)?;
+        match &self.dst {
+            Some(dst) => write!(f, ":{}", dst),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Remote is a repository we fetch from, configured as `remote.<name>.*`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Remote {
+    pub name: String,
+    pub url: String,
+    pub fetch: Vec<RefSpec>,
+}
+
+impl Remote {
+    /// Read a remote from the repository config
+    pub fn load(repo: &Repository, name: &str) -> Result<Option<Remote>> {
+        let config = repo.config()?;
+        let Some(url) = config.get(&format!("remote.{}.url", name)) else {
+            return Ok(None);
+        };
+        let fetch = config
+            .get_all(&format!("remote.{}.fetch", name))
+            .into_iter()
+            .map(RefSpec::parse)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(Remote {
+            name: name.to_string(),
+            url: url.to_string(),
+            fetch,
+        }))
+    }
+
+    /// Find a configured remote by name, or treat `name` as the URL of an
+    /// unnamed remote that fetches nothing by default
+    pub fn resolve(repo: &Repository, name: &str) -> Result<Remote> {
+        if let Some(remote) = Remote::load(repo, name)? {
+            return Ok(remote);
+        }
+        Ok(Remote {
+            name: name.to_string(),
+            url: name.to_string(),
+            fetch: Vec::new(),
+        })
+    }
+
+    /// Add a remote fetching every branch into `refs/remotes/<name>/`
+    pub fn add(repo: &Repository, name: &str, url: &str) -> Result<Remote> {
+        check_ref_name(&format!("refs/remotes/{}", name))
+            .map_err(|_| anyhow::anyhow!("'{}' is not a valid remote name", name))?;
+        let mut config = repo.config()?;
+        if config.get(&format!("remote.{}.url", name)).is_some() {
+            bail!("remote {} already exists.", name);
+        }
+        let refspec = RefSpec::parse(&format!("+refs/heads/*:refs/remotes/{}/*", name))?;
+        config.set(&format!("remote.{}.url", name), url)?;
+        config.add(&format!("remote.{}.fetch", name), &refspec.to_string())?;
+        repo.write_config(&config)?;
+        Ok(Remote {
+            name: name.to_string(),
+            url: url.to_string(),
+            fetch: vec![refspec],
+        })
+    }
+
+    /// Return true if this remote is configured rather than a bare URL
+    pub fn is_configured(&self) -> bool {
+        self.name != self.url
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::init_repo;
+
+    #[test]
+    fn test_refspec_mapping() {
+        let spec = RefSpec::parse("+refs/heads/*:refs/remotes/origin/*").unwrap();
+        assert!(spec.force && spec.is_pattern());
+        assert_eq!(
+            spec.map("refs/heads/feature/x").as_deref(),
+            Some("refs/remotes/origin/feature/x")
+        );
+        assert_eq!(spec.map("refs/tags/v1"), None);
+        assert_eq!(spec.to_string(), "+refs/heads/*:refs/remotes/origin/*");
+
+        let spec = RefSpec::parse("refs/heads/main").unwrap();
+        assert!(!spec.force && spec.dst.is_none());
+        assert!(spec.matches("refs/heads/main"));
+        assert_eq!(spec.map("refs/heads/main"), None);
+
+        assert!(RefSpec::parse("refs/heads/*:refs/remotes/origin/main").is_err());
+        assert!(RefSpec::parse("refs/*/*:refs/x/*").is_err());
+    }
+
+    #[test]
+    fn test_add_and_load_remote() {
+        let (_dir, repo) = init_repo();
+        let added = Remote::add(&repo, "origin", "/srv/repo.git").unwrap();
+        assert_eq!(Remote::load(&repo, "origin").unwrap(), Some(added));
+        assert!(Remote::add(&repo, "origin", "/elsewhere").is_err());
+        assert!(Remote::add(&repo, "bad name", "/x").is_err());
+
+        let unnamed = Remote::resolve(&repo, "../other").unwrap();
+        assert!(!unnamed.is_configured() && unnamed.fetch.is_empty());
+    }
+}
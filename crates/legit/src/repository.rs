@@ -1,13 +1,125 @@
+use crate::gitconfig::GitConfig;
+use crate::objects::HashAlgorithm;
+use crate::refs::{update_symbolic_ref, RefStorage};
+use crate::reftable;
 use crate::settings::Settings;
-use anyhow::Result;
+use anyhow::{Context, Result};
+use std::fmt::Display;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Newest `core.repositoryformatversion` legit understands
+const MAX_FORMAT_VERSION: i64 = 1;
+
+/// Extensions git honors even in version 0 repositories, which older
+/// versions of git wrote without bumping the format version
+const V0_EXTENSIONS: [&str; 3] = ["preciousobjects", "partialclone", "worktreeconfig"];
+
+/// Extensions only valid in version 1 repositories
+const V1_EXTENSIONS: [&str; 2] = ["objectformat", "refstorage"];
+
+/// FormatError tells why the format of a repository keeps legit from
+/// using it, so that callers can tell it apart from other failures
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormatError {
+    /// `core.repositoryformatversion` is newer than legit understands
+    UnsupportedVersion(i64),
+    /// A version 1 repository needs an extension legit does not know
+    UnknownExtension(String),
+    /// A version 0 repository sets an extension that needs version 1
+    NeedsVersion1(String),
+    /// A known extension is set to a value legit does not support
+    UnsupportedValue { extension: String, value: String },
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormatError::UnsupportedVersion(version) => write!(
+                f,
+                "Expected git repo version <= {}, found {}",
+                MAX_FORMAT_VERSION, version
+            ),
+            FormatError::UnknownExtension(name) => {
+                write!(f, "Unknown repository extension found: {}", name)
+            }
+            FormatError::NeedsVersion1(name) => {
+                write!(
+                    f,
+                    "Repo version is 0, but v1-only extension found: {}",
+                    name
+                )
+            }
+            FormatError::UnsupportedValue { extension, value } => write!(
+                f,
+                "Unsupported value for extensions.{}: {}",
+                extension, value
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+/// Check that legit understands the format version and extensions of the
+/// repository whose config is `config`, like git does before using it
+fn check_format(config: &GitConfig) -> Result<()> {
+    let version = config.get_int("core.repositoryformatversion")?.unwrap_or(0);
+    if !(0..=MAX_FORMAT_VERSION).contains(&version) {
+        return Err(FormatError::UnsupportedVersion(version).into());
+    }
+    for extension in config.keys("extensions") {
+        let known = V0_EXTENSIONS.contains(&extension.as_str());
+        let v1_only = V1_EXTENSIONS.contains(&extension.as_str());
+        // Version 0 predates extensions: unknown ones are ignored
+        match (version, known || v1_only) {
+            (0, _) if v1_only => return Err(FormatError::NeedsVersion1(extension).into()),
+            (0, _) | (_, true) => {}
+            _ => return Err(FormatError::UnknownExtension(extension).into()),
+        }
+        let value = config
+            .get(&format!("extensions.{}", extension))
+            .unwrap_or("");
+        let supported = match extension.as_str() {
+            "objectformat" => HashAlgorithm::from_str(value).is_ok(),
+            "refstorage" => RefStorage::from_str(value).is_ok(),
+            _ => true,
+        };
+        if !supported {
+            let value = value.to_string();
+            return Err(FormatError::UnsupportedValue { extension, value }.into());
+        }
+    }
+    Ok(())
+}
+
+/// Return the git directory of the working tree at `path`: its `.git`
+/// directory, or the one a `.git` file points to with a `gitdir:` line
+fn resolve_gitdir(path: &Path) -> Result<PathBuf> {
+    let dotgit = path.join(".git");
+    if dotgit.is_dir() {
+        return Ok(dotgit);
+    }
+    let content = fs::read_to_string(&dotgit)
+        .with_context(|| format!("Failed to read {}", dotgit.display()))?;
+    let Some(gitdir) = content.trim_end().strip_prefix("gitdir: ") else {
+        anyhow::bail!("Invalid gitfile format: {}", dotgit.display());
+    };
+    let gitdir = path.join(gitdir);
+    if !gitdir.is_dir() {
+        anyhow::bail!("Not a git repository: {}", gitdir.display());
+    }
+    // Submodules point to their git directory with a relative path
+    Ok(fs::canonicalize(&gitdir)?)
+}
 
 // Repository represents a git repository
 #[derive(Debug)]
 pub struct Repository {
     worktree: PathBuf,
     gitdir: PathBuf,
+    commondir: PathBuf,
     settings: Settings,
 }
 
@@ -18,19 +130,60 @@ impl Repository {
     }
 
     /// Return the git directory path
+    ///
+    /// In a linked worktree this is its directory under `worktrees/`, which
+    /// only holds what is particular to the worktree, like HEAD and the
+    /// index.
     pub fn gitdir(&self) -> &Path {
         &self.gitdir
     }
 
+    /// Return the directory holding what every worktree of the repository
+    /// shares: objects, config and references other than HEAD
+    pub fn commondir(&self) -> &Path {
+        &self.commondir
+    }
+
+    /// Return true if this is a linked worktree, not the main one
+    pub fn is_linked_worktree(&self) -> bool {
+        self.gitdir != self.commondir
+    }
+
+    /// Return true if the repository has no working tree
+    pub fn is_bare(&self) -> bool {
+        self.worktree == self.gitdir
+    }
+
     /// Return the settings of the repository
     pub fn settings(&self) -> &Settings {
         &self.settings
     }
 
+    /// Return the hash algorithm naming the objects of the repository
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.settings.extensions.objectformat.unwrap_or_default()
+    }
+
+    /// Return the backend storing the references of the repository
+    pub fn ref_storage(&self) -> RefStorage {
+        self.settings.extensions.refstorage.unwrap_or_default()
+    }
+
+    /// Read the repository config file
+    pub fn config(&self) -> Result<GitConfig> {
+        GitConfig::read(&self.commondir.join("config"))
+    }
+
+    /// Write the repository config file
+    pub fn write_config(&self, config: &GitConfig) -> Result<()> {
+        config.write(&self.commondir.join("config"))
+    }
+
     /// Find a git repository by traversing up the directory tree
     ///
-    /// /// This function looks for a `.git` directory in the specified path or its parent
-    /// directories.
+    /// This function looks for a `.git` directory in the specified path or its parent
+    /// directories, or a `.git` file pointing to the git directory of a
+    /// linked worktree.
     pub fn find(path: &Path) -> Result<Repository> {
         let gitdir = path.join(".git");
         if !gitdir.exists() {
@@ -39,10 +192,42 @@ impl Repository {
                 .ok_or_else(|| anyhow::anyhow!("No parent directory"))?;
             return Repository::find(parent);
         }
-        let settings = Settings::new()?;
+        Repository::load(path, resolve_gitdir(path)?)
+    }
+
+    /// Open the repository at exactly `path`, without looking at its parents
+    ///
+    /// The path is either a working tree holding a `.git` directory or file,
+    /// or a bare git directory. A bare repository has no working tree, so its
+    /// worktree is the git directory itself and must not be checked out.
+    pub fn open(path: &Path) -> Result<Repository> {
+        let gitdir = match path.join(".git") {
+            gitdir if gitdir.exists() => resolve_gitdir(path)?,
+            _ if path.join("HEAD").is_file() && path.join("objects").is_dir() => path.to_owned(),
+            _ => anyhow::bail!("Not a git repository: {}", path.display()),
+        };
+        Repository::load(path, gitdir)
+    }
+
+    /// Load the settings of a found repository, once its format is known
+    /// to be supported
+    ///
+    /// A `commondir` file in the git directory points to the directory
+    /// shared with the main worktree.
+    pub(crate) fn load(worktree: &Path, gitdir: PathBuf) -> Result<Repository> {
+        let commondir = match fs::read_to_string(gitdir.join("commondir")) {
+            Ok(content) => {
+                let commondir = gitdir.join(content.trim_end_matches(['\n', '\r']));
+                fs::canonicalize(&commondir).unwrap_or(commondir)
+            }
+            Err(_) => gitdir.clone(),
+        };
+        check_format(&GitConfig::read(&commondir.join("config"))?)?;
+        let settings = Settings::load_linked(&commondir, &gitdir)?;
         Ok(Repository {
-            worktree: path.to_owned(),
+            worktree: worktree.to_owned(),
             gitdir,
+            commondir,
             settings,
         })
     }
@@ -52,24 +237,57 @@ impl Repository {
     /// This function initializes a new git repository at the specified path.
     /// It creates the necessary directories and files for a git repository.
     pub fn new(path: &Path) -> Result<Repository> {
+        Repository::new_with_object_format(path, HashAlgorithm::Sha1)
+    }
+
+    /// Create a new Repository whose objects are named with `algorithm`
+    pub fn new_with_object_format(path: &Path, algorithm: HashAlgorithm) -> Result<Repository> {
+        Repository::new_with_formats(path, algorithm, RefStorage::Files)
+    }
+
+    /// Create a new Repository whose objects are named with `algorithm` and
+    /// whose references are stored in `ref_storage`
+    ///
+    /// Any algorithm but SHA-1 is recorded in `extensions.objectFormat` and
+    /// reftables in `extensions.refStorage`, which need
+    /// `repositoryformatversion = 1`.
+    pub fn new_with_formats(
+        path: &Path,
+        algorithm: HashAlgorithm,
+        ref_storage: RefStorage,
+    ) -> Result<Repository> {
         let worktree = path.to_owned();
         let gitdir = worktree.join(".git");
-        let settings = Settings::new()?;
+        // Nothing is inherited from whatever repository we are run in
+        let mut settings = Settings::default();
+        if algorithm != HashAlgorithm::Sha1 {
+            settings.core.repositoryformatversion = 1;
+            settings.extensions.objectformat = Some(algorithm);
+        }
+        if ref_storage != RefStorage::Files {
+            settings.core.repositoryformatversion = 1;
+            settings.extensions.refstorage = Some(ref_storage);
+        }
 
         Repository::create(&worktree, &gitdir, &settings)?;
 
-        Ok(Repository {
+        let repo = Repository {
             worktree,
+            commondir: gitdir.clone(),
             gitdir,
             settings,
-        })
+        };
+        if ref_storage == RefStorage::Reftable {
+            update_symbolic_ref(&repo, "HEAD", "refs/heads/master")?;
+        }
+        Ok(repo)
     }
 
     /// Populate the git directory with the necessary files and directories
     fn create(worktree: &Path, gitdir: &Path, settings: &Settings) -> Result<()> {
-        let version = settings.core.repositoryformatversion;
-        if version != 0 {
-            anyhow::bail!("Unsupported repositoryformatversion: {}", version);
+        let version = i64::from(settings.core.repositoryformatversion);
+        if !(0..=MAX_FORMAT_VERSION).contains(&version) {
+            return Err(FormatError::UnsupportedVersion(version).into());
         }
 
         if gitdir.exists() {
@@ -82,7 +300,7 @@ impl Repository {
 
         fs::create_dir_all(gitdir)?;
 
-        let dirs = ["branches", "objects", "refs/tags", "refs/heads"];
+        let dirs = ["branches", "objects"];
         for dir in dirs.iter() {
             fs::create_dir_all(gitdir.join(dir))?;
         }
@@ -93,8 +311,15 @@ impl Repository {
             "Unnamed repository; edit this file 'description' to name the repository.",
         )?;
 
-        let head = gitdir.join("HEAD");
-        fs::write(head, "ref: refs/heads/master\n")?;
+        if settings.extensions.refstorage == Some(RefStorage::Reftable) {
+            reftable::init(gitdir)?;
+        } else {
+            for dir in ["refs/tags", "refs/heads"] {
+                fs::create_dir_all(gitdir.join(dir))?;
+            }
+            let head = gitdir.join("HEAD");
+            fs::write(head, "ref: refs/heads/master\n")?;
+        }
 
         let config = gitdir.join("config");
         let config_content = toml::to_string(settings)?;
@@ -107,6 +332,12 @@ impl Repository {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::commits::Commit;
+    use crate::index::Index;
+    use crate::pack::store_pack;
+    use crate::pack_writer::{list_objects, write_pack};
+    use crate::test_utils::commit_files;
+    use crate::tree::flatten_tree;
     use tempfile::TempDir;
 
     #[test]
@@ -138,6 +369,55 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_new_with_object_format() {
+        let tempdir = TempDir::new().unwrap();
+        Repository::new_with_object_format(tempdir.path(), HashAlgorithm::Sha256).unwrap();
+        let repo = Repository::open(tempdir.path()).unwrap();
+        assert_eq!(repo.settings().core.repositoryformatversion, 1);
+        assert_eq!(repo.hash_algorithm(), HashAlgorithm::Sha256);
+
+        let commit = commit_files(&repo, &[("a.txt", "a"), ("dir/b.txt", "b")], "first");
+        assert_eq!(commit.to_hex().len(), 64);
+        let tree = Commit::read(&repo, &commit).unwrap().tree;
+        Index::from_tree(&repo, &tree)
+            .unwrap()
+            .write(&repo)
+            .unwrap();
+        assert_eq!(Index::read(&repo).unwrap().write_tree(&repo).unwrap(), tree);
+
+        let server = TempDir::new().unwrap();
+        let server =
+            Repository::new_with_object_format(server.path(), HashAlgorithm::Sha256).unwrap();
+        let objects = list_objects(&repo, std::slice::from_ref(&commit), &[]).unwrap();
+        store_pack(&server, &write_pack(&repo, &objects, false).unwrap()).unwrap();
+        assert_eq!(flatten_tree(&server, &tree).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_new_ignores_current_repository() {
+        let parent = TempDir::new().unwrap();
+        Repository::new(parent.path()).unwrap();
+        fs::write(
+            parent.path().join(".git/config"),
+            "[core]\n\trepositoryformatversion = 1\n\tfilemode = true\n\
+             [extensions]\n\tpreciousObjects = true\n\tpartialClone = origin\n",
+        )
+        .unwrap();
+        let previous = std::env::current_dir().unwrap();
+        std::env::set_current_dir(parent.path()).unwrap();
+        let tempdir = TempDir::new().unwrap();
+        let created = Repository::new(tempdir.path());
+        std::env::set_current_dir(previous).unwrap();
+        created.unwrap();
+
+        let repo = Repository::open(tempdir.path()).unwrap();
+        let settings = repo.settings();
+        assert_eq!(settings.core.repositoryformatversion, 0);
+        assert!(!settings.core.filemode);
+        assert!(settings.extensions.is_empty());
+    }
+
     #[test]
     fn test_find() {
         let tempdir = TempDir::new().unwrap();
@@ -158,4 +438,54 @@ mod tests {
         let repo = Repository::find(&subdir).unwrap();
         assert_eq!(repo.worktree, tempdir.path());
     }
+
+    #[test]
+    fn test_check_format() {
+        let tempdir = TempDir::new().unwrap();
+        let repo = Repository::new(tempdir.path()).unwrap();
+        let format_error = |config: &str| {
+            fs::write(repo.gitdir().join("config"), config).unwrap();
+            Repository::find(tempdir.path())
+                .unwrap_err()
+                .downcast::<FormatError>()
+                .unwrap()
+        };
+
+        assert_eq!(
+            format_error("[core]\n\trepositoryformatversion = 2\n"),
+            FormatError::UnsupportedVersion(2)
+        );
+        assert_eq!(
+            format_error("[core]\n\trepositoryformatversion = 1\n[extensions]\n\tfoo = bar\n"),
+            FormatError::UnknownExtension("foo".to_string())
+        );
+        assert_eq!(
+            format_error("[extensions]\n\tobjectFormat = sha256\n"),
+            FormatError::NeedsVersion1("objectformat".to_string())
+        );
+        assert_eq!(
+            format_error(
+                "[core]\n\trepositoryformatversion = 1\n[extensions]\n\trefStorage = foo\n"
+            ),
+            FormatError::UnsupportedValue {
+                extension: "refstorage".to_string(),
+                value: "foo".to_string()
+            }
+        );
+
+        // Version 0 ignores unknown extensions but honors the old ones
+        fs::write(
+            repo.gitdir().join("config"),
+            "[core]\n\tbare = false\n[extensions]\n\tfoo = bar\n\tpreciousObjects = true\n",
+        )
+        .unwrap();
+        let repo = Repository::find(tempdir.path()).unwrap();
+        assert!(repo.settings().extensions.preciousobjects);
+        fs::write(
+            repo.gitdir().join("config"),
+            "[core]\n\trepositoryformatversion = 1\n[extensions]\n\trefStorage = files\n\tpartialClone = origin\n",
+        )
+        .unwrap();
+        assert!(Repository::open(tempdir.path()).is_ok());
+    }
 }
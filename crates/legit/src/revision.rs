@@ -0,0 +1,492 @@
+use crate::commit_graph::CommitGraph;
+use crate::commits::{parse_key_values, split_headers, Commit, Signature};
+use crate::objects::{object_exists, read_object, HashAlgorithm, ObjectHash, ObjectType};
+use crate::refs::{read_head, read_reflog, resolve_ref, Head};
+use crate::shallow::{graft_parents, read_shallow, Grafts};
+use crate::tree::Tree;
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::fs;
+
+/// Minimum number of hex characters accepted as an abbreviated hash
+const MIN_ABBREV: usize = 4;
+
+/// Resolve a revision such as `HEAD~2`, `master^2`, `v1.0^{tree}`,
+/// `stash@{1}` or an abbreviated hash to an object hash.
+pub fn rev_parse(repo: &Repository, spec: &str) -> Result<ObjectHash> {
+    let (base, suffix) = match spec.find(['~', '^']) {
+        Some(index) => spec.split_at(index),
+        None => (spec, ""),
+    };
+    let mut hash =
+        resolve_name(repo, base)?.ok_or_else(|| anyhow::anyhow!("Unknown revision: {}", spec))?;
+
+    let mut rest = suffix;
+    while !rest.is_empty() {
+        if let Some(peel) = rest.strip_prefix("^{") {
+            let (target, after) = peel
+                .split_once('}')
+                .ok_or_else(|| anyhow::anyhow!("Invalid revision: {}", spec))?;
+            hash = match target {
+                "" => peel_tags(repo, &hash)?,
+                "commit" => peel_to_commit(repo, &hash)?,
+                "tree" => peel_to_tree(repo, &hash)?,
+                _ => bail!("Unsupported peel target in {}", spec),
+            };
+            rest = after;
+            continue;
+        }
+
+        let operator = rest.as_bytes()[0];
+        rest = &rest[1..];
+        let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let count = match digits {
+            0 => 1,
+            _ => rest[..digits].parse::<usize>()?,
+        };
+        rest = &rest[digits..];
+
+        let commit_hash = peel_to_commit(repo, &hash)?;
+        hash = match operator {
+            b'~' => nth_ancestor(repo, &commit_hash, count)?,
+            _ if count == 0 => commit_hash,
+            _ => Commit::read(repo, &commit_hash)?
+                .parents
+                .get(count - 1)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("Revision {} has no parent {}", spec, count))?,
+        };
+    }
+    Ok(hash)
+}
+
+/// Resolve a reference name, special file or (abbreviated) hash
+fn resolve_name(repo: &Repository, name: &str) -> Result<Option<ObjectHash>> {
+    if let Some((base, selector)) = name.strip_suffix('}').and_then(|n| n.split_once("@{")) {
+        return resolve_reflog_entry(repo, base, selector).map(Some);
+    }
+    let name = if name == "@" || name.is_empty() {
+        "HEAD"
+    } else {
+        name
+    };
+
+    for candidate in ref_candidates(name).iter() {
+        let is_ref = candidate == "HEAD"
+            || candidate.starts_with("refs/")
+            || candidate
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c == '_');
+        if !is_ref {
+            continue;
+        }
+        let hash = match candidate.as_str() {
+            // FETCH_HEAD holds one line per fetched ref; the first one wins
+            "FETCH_HEAD" => read_fetch_head(repo)?,
+            _ => resolve_ref(repo, candidate)?,
+        };
+        if hash.is_some() {
+            return Ok(hash);
+        }
+    }
+
+    if name.len() == repo.hash_algorithm().hex_len() {
+        if let Ok(hash) = ObjectHash::from_hex(name, repo.hash_algorithm()) {
+            return Ok(Some(hash));
+        }
+    }
+    resolve_abbreviated(repo, name)
+}
+
+/// The reference names a short name can stand for, in lookup order
+fn ref_candidates(name: &str) -> [String; 6] {
+    [
+        name.to_string(),
+        format!("refs/{}", name),
+        format!("refs/tags/{}", name),
+        format!("refs/heads/{}", name),
+        format!("refs/remotes/{}", name),
+        format!("refs/remotes/{}/HEAD", name),
+    ]
+}
+
+/// Expand a short reference name like `main` or `origin/main` to the full
+/// name of the existing reference it stands for
+pub fn expand_ref_name(repo: &Repository, name: &str) -> Result<Option<String>> {
+    for candidate in ref_candidates(name) {
+        if (candidate == "HEAD" || candidate.starts_with("refs/"))
+            && resolve_ref(repo, &candidate)?.is_some()
+        {
+            return Ok(Some(candidate));
+        }
+    }
+    Ok(None)
+}
+
+/// Resolve `<ref>@{<n>}` to the value a reference had `n` updates ago,
+/// according to its reflog
+fn resolve_reflog_entry(repo: &Repository, base: &str, selector: &str) -> Result<ObjectHash> {
+    let spec = format!("{}@{{{}}}", base, selector);
+    let count = selector
+        .parse::<usize>()
+        .with_context(|| format!("Unsupported reflog selector: {}", spec))?;
+    // An empty reference stands for the current branch
+    let name = match (base, read_head(repo)?) {
+        ("", Head::Branch(name)) => name,
+        ("", Head::Detached(_)) => "HEAD".to_string(),
+        _ => expand_ref_name(repo, base)?
+            .ok_or_else(|| anyhow::anyhow!("Unknown revision: {}", spec))?,
+    };
+    let entries = read_reflog(repo, &name)?;
+    if entries.is_empty() && count == 0 {
+        return resolve_ref(repo, &name)?
+            .ok_or_else(|| anyhow::anyhow!("Unknown revision: {}", spec));
+    }
+    entries
+        .iter()
+        .rev()
+        .nth(count)
+        .map(|entry| entry.new.clone())
+        .ok_or_else(|| anyhow::anyhow!("Log for '{}' only has {} entries.", base, entries.len()))
+}
+
+fn read_fetch_head(repo: &Repository) -> Result<Option<ObjectHash>> {
+    let path = repo.gitdir().join("FETCH_HEAD");
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(path)?;
+    content
+        .split_whitespace()
+        .next()
+        .map(|hex| ObjectHash::from_hex(hex, repo.hash_algorithm()))
+        .transpose()
+}
+
+/// Find the unique object whose hash starts with the given hex prefix
+fn resolve_abbreviated(repo: &Repository, prefix: &str) -> Result<Option<ObjectHash>> {
+    if prefix.len() < MIN_ABBREV
+        || prefix.len() > repo.hash_algorithm().hex_len()
+        || !prefix.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return Ok(None);
+    }
+    let prefix = prefix.to_lowercase();
+    let dir = repo.commondir().join("objects").join(&prefix[..2]);
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+    let mut matches = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let file = entry?.file_name().to_string_lossy().into_owned();
+        let hex = format!("{}{}", &prefix[..2], file);
+        if hex.starts_with(&prefix) {
+            matches.push(hex);
+        }
+    }
+    match matches.as_slice() {
+        [] => Ok(None),
+        [hex] => Ok(Some(ObjectHash::from_hex(hex, repo.hash_algorithm())?)),
+        _ => bail!("Ambiguous revision: {}", prefix),
+    }
+}
+
+/// Follow the first parent of a commit `count` times
+fn nth_ancestor(repo: &Repository, hash: &ObjectHash, count: usize) -> Result<ObjectHash> {
+    let mut hash = hash.clone();
+    for _ in 0..count {
+        hash = Commit::read(repo, &hash)?
+            .parents
+            .first()
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Commit {} has no parent", hash))?;
+    }
+    Ok(hash)
+}
+
+/// Follow annotated tags until reaching an object that is not a tag
+pub fn peel_tags(repo: &Repository, hash: &ObjectHash) -> Result<ObjectHash> {
+    let mut hash = hash.clone();
+    loop {
+        let object = read_object(repo, &hash)?;
+        if object.object_type != ObjectType::Tag {
+            return Ok(hash);
+        }
+        hash = tag_target(&object.data, repo.hash_algorithm())
+            .with_context(|| format!("Invalid tag {}", hash))?;
+    }
+}
+
+/// Return the object a tag points to, from the tag's data
+pub fn tag_target(data: &[u8], algorithm: HashAlgorithm) -> Result<ObjectHash> {
+    let text = String::from_utf8_lossy(data);
+    let (headers, _) = split_headers(&text);
+    let target = parse_key_values(headers)?
+        .into_iter()
+        .find(|(key, _)| key == "object")
+        .ok_or_else(|| anyhow::anyhow!("Tag has no object"))?
+        .1;
+    ObjectHash::from_hex(&target, algorithm).context("Invalid tag target")
+}
+
+/// Peel an object to the commit it refers to
+pub fn peel_to_commit(repo: &Repository, hash: &ObjectHash) -> Result<ObjectHash> {
+    let hash = peel_tags(repo, hash)?;
+    if !object_exists(repo, &hash) {
+        bail!("Object {} not found", hash);
+    }
+    let object = read_object(repo, &hash)?;
+    if object.object_type != ObjectType::Commit {
+        bail!("Object {} is a {}, not a commit", hash, object.object_type);
+    }
+    Ok(hash)
+}
+
+/// Peel an object to the tree it refers to
+pub fn peel_to_tree(repo: &Repository, hash: &ObjectHash) -> Result<ObjectHash> {
+    let hash = peel_tags(repo, hash)?;
+    let object = read_object(repo, &hash)?;
+    match object.object_type {
+        ObjectType::Tree => Ok(hash),
+        ObjectType::Commit => Ok(Commit::parse(&object.data, repo.hash_algorithm())?.tree),
+        other => bail!("Object {} is a {}, not a tree", hash, other),
+    }
+}
+
+/// Return every commit reachable from `start`, including `start` itself
+pub fn ancestors(repo: &Repository, start: &ObjectHash) -> Result<HashSet<ObjectHash>> {
+    let graph = CommitGraph::open(repo).ok().flatten();
+    ancestors_grafted(repo, graph.as_ref(), start, &read_shallow(repo)?)
+}
+
+/// Return the ancestors of `start`, not walking past the parents of `grafts`
+fn ancestors_grafted(
+    repo: &Repository,
+    graph: Option<&CommitGraph>,
+    start: &ObjectHash,
+    grafts: &HashSet<ObjectHash>,
+) -> Result<HashSet<ObjectHash>> {
+    let mut seen = HashSet::new();
+    let mut stack = vec![start.clone()];
+    while let Some(hash) = stack.pop() {
+        if seen.insert(hash.clone()) {
+            stack.extend(parents_and_date(repo, graph, grafts, &hash)?.0);
+        }
+    }
+    Ok(seen)
+}
+
+/// Read the parents and commit date of a commit, from the commit-graph
+/// when it has the commit; grafted commits have no parents
+fn parents_and_date(
+    repo: &Repository,
+    graph: Option<&CommitGraph>,
+    grafts: &HashSet<ObjectHash>,
+    hash: &ObjectHash,
+) -> Result<(Vec<ObjectHash>, i64)> {
+    if let Some(commit) = graph.map(|g| g.lookup(hash)).transpose()?.flatten() {
+        return Ok((
+            graft_parents(grafts, hash, commit.parents),
+            commit.commit_time,
+        ));
+    }
+    let commit = Commit::read(repo, hash)?;
+    let date = Signature::parse(&commit.committer)
+        .map(|s| s.time)
+        .unwrap_or_default();
+    Ok((graft_parents(grafts, hash, commit.parents), date))
+}
+
+/// List the commits reachable from `include` but not from `exclude`, newest
+/// first, like `git rev-list include ^exclude`
+///
+/// Commits with the same date are listed in the order they were reached.
+/// The history of a shallow repository ends at its shallow commits.
+pub fn rev_list(
+    repo: &Repository,
+    include: &[ObjectHash],
+    exclude: &[ObjectHash],
+) -> Result<Vec<ObjectHash>> {
+    rev_list_grafted(repo, include, exclude, &Grafts::of(repo)?)
+}
+
+/// List the commits of `rev_list`, ignoring the parents of the commits
+/// `grafts` cuts each side at
+pub fn rev_list_grafted(
+    repo: &Repository,
+    include: &[ObjectHash],
+    exclude: &[ObjectHash],
+    grafts: &Grafts,
+) -> Result<Vec<ObjectHash>> {
+    let graph = CommitGraph::open(repo).ok().flatten();
+    let mut excluded = HashSet::new();
+    for hash in exclude {
+        excluded.extend(ancestors_grafted(
+            repo,
+            graph.as_ref(),
+            hash,
+            &grafts.exclude,
+        )?);
+    }
+
+    let mut queue = BinaryHeap::new();
+    let mut seen = HashSet::new();
+    let mut sequence = 0usize;
+    let mut push = |queue: &mut BinaryHeap<_>, hash: ObjectHash| -> Result<()> {
+        let (parents, date) = parents_and_date(repo, graph.as_ref(), &grafts.include, &hash)?;
+        queue.push((date, Reverse(sequence), hash, parents));
+        sequence += 1;
+        Ok(())
+    };
+    for hash in include {
+        if !excluded.contains(hash) && seen.insert(hash.clone()) {
+            push(&mut queue, hash.clone())?;
+        }
+    }
+
+    let mut commits = Vec::new();
+    while let Some((_, _, hash, parents)) = queue.pop() {
+        commits.push(hash);
+        for parent in parents {
+            if !excluded.contains(&parent) && seen.insert(parent.clone()) {
+                push(&mut queue, parent)?;
+            }
+        }
+    }
+    Ok(commits)
+}
+
+/// List the commits of `rev_list` that change one of `paths` compared to
+/// their first parent, like `git rev-list include ^exclude -- paths`
+///
+/// The changed-path filters of the commit-graph rule out most commits
+/// without reading their trees.
+pub fn rev_list_paths(
+    repo: &Repository,
+    include: &[ObjectHash],
+    exclude: &[ObjectHash],
+    paths: &[String],
+) -> Result<Vec<ObjectHash>> {
+    let commits = rev_list(repo, include, exclude)?;
+    if paths.is_empty() {
+        return Ok(commits);
+    }
+    let graph = CommitGraph::open(repo).ok().flatten();
+    let shallow = read_shallow(repo)?;
+    let mut result = Vec::new();
+    for hash in commits {
+        let candidates = paths
+            .iter()
+            .filter(|path| graph.as_ref().and_then(|g| g.maybe_changed(&hash, path)) != Some(false))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            continue;
+        }
+        let commit = Commit::read(repo, &hash)?;
+        let parents = graft_parents(&shallow, &hash, commit.parents);
+        let parent_tree = match parents.first() {
+            Some(parent) => Some(Commit::read(repo, parent)?.tree),
+            None => None,
+        };
+        for path in candidates {
+            let old = match &parent_tree {
+                Some(tree) => tree_entry(repo, tree, path)?,
+                None => None,
+            };
+            if tree_entry(repo, &commit.tree, path)? != old {
+                result.push(hash);
+                break;
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Find the mode and hash of the entry at a path of a tree
+fn tree_entry(
+    repo: &Repository,
+    tree: &ObjectHash,
+    path: &str,
+) -> Result<Option<(u32, ObjectHash)>> {
+    let mut entry = (0o40000, tree.clone());
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        if entry.0 != 0o40000 {
+            return Ok(None);
+        }
+        match Tree::read(repo, &entry.1)?
+            .entries
+            .into_iter()
+            .find(|e| e.name == name)
+        {
+            Some(found) => entry = (found.mode.bits(), found.hash),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(entry))
+}
+
+/// Count the commits only reachable from `local` (ahead) and only reachable
+/// from `upstream` (behind)
+pub fn ahead_behind(
+    repo: &Repository,
+    local: &ObjectHash,
+    upstream: &ObjectHash,
+) -> Result<(usize, usize)> {
+    let local = ancestors(repo, local)?;
+    let upstream = ancestors(repo, upstream)?;
+    Ok((
+        local.difference(&upstream).count(),
+        upstream.difference(&local).count(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{commit_files, init_repo, write_commit};
+
+    #[test]
+    fn test_rev_parse_refs_and_ancestors() {
+        let (_dir, repo) = init_repo();
+        let first = commit_files(&repo, &[("a.txt", "one")], "first");
+        let second = commit_files(&repo, &[("a.txt", "two")], "second");
+
+        assert_eq!(rev_parse(&repo, "HEAD").unwrap(), second);
+        assert_eq!(rev_parse(&repo, "master").unwrap(), second);
+        assert_eq!(rev_parse(&repo, "@~1").unwrap(), first);
+        assert_eq!(rev_parse(&repo, "HEAD^").unwrap(), first);
+        assert_eq!(rev_parse(&repo, "HEAD^0").unwrap(), second);
+        assert!(rev_parse(&repo, "HEAD~2").is_err());
+    }
+
+    #[test]
+    fn test_rev_parse_hashes() {
+        let (_dir, repo) = init_repo();
+        let commit = commit_files(&repo, &[("a.txt", "one")], "first");
+        let hex = commit.to_hex();
+        assert_eq!(rev_parse(&repo, &hex).unwrap(), commit);
+        assert_eq!(rev_parse(&repo, &hex[..7]).unwrap(), commit);
+
+        let tree = Commit::read(&repo, &commit).unwrap().tree;
+        assert_eq!(rev_parse(&repo, "HEAD^{tree}").unwrap(), tree);
+        assert!(rev_parse(&repo, "nope").is_err());
+    }
+
+    #[test]
+    fn test_ahead_behind() {
+        let (_dir, repo) = init_repo();
+        let base = commit_files(&repo, &[("a.txt", "one")], "base");
+        let local = commit_files(&repo, &[("a.txt", "two")], "local");
+        let local = write_commit(&repo, &[("a.txt", "three")], &[local], "local 2");
+        let upstream = write_commit(
+            &repo,
+            &[("b.txt", "b")],
+            std::slice::from_ref(&base),
+            "upstream",
+        );
+        assert_eq!(ahead_behind(&repo, &local, &upstream).unwrap(), (2, 1));
+        assert_eq!(ahead_behind(&repo, &base, &base).unwrap(), (0, 0));
+    }
+}
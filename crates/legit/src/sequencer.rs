@@ -0,0 +1,638 @@
+use crate::checkout::{head_tree, FileMap};
+use crate::commits::Commit;
+use crate::gitconfig::GitConfig;
+use crate::index::Index;
+use crate::merge::{
+    check_index_matches_head, merge_in_progress, merge_trees, record_conflicts, reset_merge,
+    strip_comments, update_files, ConflictLabels, ConflictStyle, TreeMerge,
+};
+use crate::objects::ObjectHash;
+use crate::refs::{resolve_ref, update_head, write_atomic};
+use crate::revision::{peel_to_commit, rev_list, rev_parse};
+use crate::tree::{flatten_tree, Tree};
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::PathBuf;
+use strum::{Display, EnumString};
+
+const SEQUENCER_DIR: &str = "sequencer";
+const CHERRY_PICK_HEAD: &str = "CHERRY_PICK_HEAD";
+const REVERT_HEAD: &str = "REVERT_HEAD";
+const MERGE_MSG: &str = "MERGE_MSG";
+
+/// Action is what the sequencer does with a commit, as written in its todo list
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum Action {
+    /// Apply the changes a commit introduced, like `git cherry-pick`
+    Pick,
+    /// Apply the inverse of the changes a commit introduced, like `git revert`
+    Revert,
+}
+
+impl Action {
+    /// Return the name of the command running the action
+    pub fn command(&self) -> &'static str {
+        match self {
+            Action::Pick => "cherry-pick",
+            Action::Revert => "revert",
+        }
+    }
+
+    fn head_file(&self) -> &'static str {
+        match self {
+            Action::Pick => CHERRY_PICK_HEAD,
+            Action::Revert => REVERT_HEAD,
+        }
+    }
+}
+
+/// SequencerOptions holds the options of `cherry-pick` and `revert`
+#[derive(Debug, Clone, Default)]
+pub struct SequencerOptions {
+    /// Parent number, starting at 1, to diff merge commits against
+    pub mainline: Option<usize>,
+    /// Append a `(cherry picked from commit ...)` line to picked messages
+    pub record_origin: bool,
+}
+
+/// StopReason tells why the sequencer stopped before the end of its todo list
+#[derive(Debug)]
+pub enum StopReason {
+    /// The commit did not apply cleanly; the conflicts are left in the index
+    Conflicted(TreeMerge),
+    /// Applying the commit left HEAD unchanged
+    Empty,
+}
+
+/// Stop describes the commit the sequencer stopped at
+#[derive(Debug)]
+pub struct Stop {
+    pub action: Action,
+    pub commit: ObjectHash,
+    pub summary: String,
+    pub reason: StopReason,
+}
+
+/// SequencerReport lists the commits created by a sequencer run
+#[derive(Debug, Default)]
+pub struct SequencerReport {
+    pub created: Vec<ObjectHash>,
+    pub stopped: Option<Stop>,
+}
+
+/// Apply the changes introduced by each revision on top of HEAD, like
+/// `git cherry-pick`
+///
+/// Revisions are single commits or `A..B` ranges. When a commit does not
+/// apply cleanly the sequencer stops, leaving its state in `.git/sequencer`
+/// so that it can be resumed with `sequencer_continue` or `sequencer_skip`,
+/// or abandoned with `sequencer_abort`.
+pub fn cherry_pick(
+    repo: &Repository,
+    revisions: &[String],
+    options: &SequencerOptions,
+) -> Result<SequencerReport> {
+    start(repo, Action::Pick, revisions, options)
+}
+
+/// Create commits reverting the changes introduced by each revision, like
+/// `git revert`
+pub fn revert(
+    repo: &Repository,
+    revisions: &[String],
+    options: &SequencerOptions,
+) -> Result<SequencerReport> {
+    start(repo, Action::Revert, revisions, options)
+}
+
+/// Return true if a cherry-pick or revert stopped and was not concluded
+pub fn sequencer_in_progress(repo: &Repository) -> bool {
+    sequencer_dir(repo).exists()
+}
+
+/// Commit the resolved commit the sequencer stopped at and apply the rest of
+/// the todo list
+pub fn sequencer_continue(repo: &Repository) -> Result<SequencerReport> {
+    let (mut todo, options) = read_state(repo)?;
+    let mut report = SequencerReport::default();
+    let gitdir = repo.gitdir();
+    if let Some(&(action, ref commit)) = todo.first() {
+        if gitdir.join(action.head_file()).exists() {
+            let index = Index::read(repo)?;
+            if index.has_conflicts() {
+                bail!("Committing is not possible because you have unmerged files.");
+            }
+            let message = fs::read_to_string(gitdir.join(MERGE_MSG)).unwrap_or_default();
+            let message = strip_comments(&message);
+            if message.is_empty() {
+                bail!("Aborting commit due to empty commit message.");
+            }
+            let picked = Commit::read(repo, commit)?;
+            let created = write_commit(repo, action, &picked, index.write_tree(repo)?, &message)?;
+            report.created.push(created);
+            remove_files(repo, &[action.head_file(), MERGE_MSG])?;
+        }
+        // Without the head file the user already committed the step
+        todo.remove(0);
+    }
+    run(repo, todo, &options, report)
+}
+
+/// Drop the commit the sequencer stopped at and apply the rest of the todo list
+pub fn sequencer_skip(repo: &Repository) -> Result<SequencerReport> {
+    let (mut todo, options) = read_state(repo)?;
+    let head = match head_tree(repo)? {
+        Some(tree) => flatten_tree(repo, &tree)?,
+        None => FileMap::new(),
+    };
+    reset_merge(repo, &mut Index::read(repo)?, &head)?;
+    remove_files(repo, &[CHERRY_PICK_HEAD, REVERT_HEAD, MERGE_MSG])?;
+    if !todo.is_empty() {
+        todo.remove(0);
+    }
+    run(repo, todo, &options, SequencerReport::default())
+}
+
+/// Abandon the sequence, moving HEAD, the index and the working tree back to
+/// the commit HEAD was at when it started
+pub fn sequencer_abort(repo: &Repository) -> Result<()> {
+    read_state(repo)?;
+    let dir = sequencer_dir(repo);
+    let original = read_hash(repo, &dir.join("head"))?;
+    let safety = read_hash(repo, &dir.join("abort-safety"))?;
+    if resolve_ref(repo, "HEAD")?.as_ref() != Some(&safety) {
+        remove_state(repo)?;
+        bail!("You seem to have moved HEAD. Not rewinding, check your HEAD!");
+    }
+
+    let target = flatten_tree(repo, &Commit::read(repo, &original)?.tree)?;
+    reset_merge(repo, &mut Index::read(repo)?, &target)?;
+    update_head(repo, &original)?;
+    remove_state(repo)
+}
+
+fn start(
+    repo: &Repository,
+    action: Action,
+    revisions: &[String],
+    options: &SequencerOptions,
+) -> Result<SequencerReport> {
+    if sequencer_in_progress(repo) {
+        bail!("{} is already in progress", action.command());
+    }
+    if merge_in_progress(repo) {
+        bail!("You have not concluded your merge (MERGE_HEAD exists).");
+    }
+    let Some(head) = resolve_ref(repo, "HEAD")? else {
+        bail!("Cannot {} onto an unborn branch", action.command());
+    };
+
+    let mut todo = Vec::new();
+    for revision in revisions {
+        for commit in resolve_revision(repo, revision)? {
+            check_mainline(repo, &commit, options)?;
+            todo.push((action, commit));
+        }
+    }
+    if todo.is_empty() {
+        bail!("empty commit set passed");
+    }
+
+    let dir = sequencer_dir(repo);
+    fs::create_dir_all(&dir)?;
+    write_atomic(&dir.join("head"), format!("{}\n", head).as_bytes())?;
+    write_atomic(&dir.join("abort-safety"), format!("{}\n", head).as_bytes())?;
+    let mut opts = GitConfig::default();
+    if let Some(mainline) = options.mainline {
+        opts.set("options.mainline", &mainline.to_string())?;
+    }
+    if options.record_origin {
+        opts.set("options.record-origin", "true")?;
+    }
+    opts.write(&dir.join("opts"))?;
+    run(repo, todo, options, SequencerReport::default())
+}
+
+/// Resolve a single revision or an `A..B` range to the commits to apply,
+/// oldest first
+fn resolve_revision(repo: &Repository, revision: &str) -> Result<Vec<ObjectHash>> {
+    let resolve = |spec: &str| {
+        let spec = if spec.is_empty() { "HEAD" } else { spec };
+        peel_to_commit(repo, &rev_parse(repo, spec)?)
+            .with_context(|| format!("bad revision '{}'", spec))
+    };
+    match revision.split_once("..") {
+        Some((from, to)) => {
+            let mut commits = rev_list(repo, &[resolve(to)?], &[resolve(from)?])?;
+            commits.reverse();
+            Ok(commits)
+        }
+        None => Ok(vec![resolve(revision)?]),
+    }
+}
+
+fn check_mainline(
+    repo: &Repository,
+    commit: &ObjectHash,
+    options: &SequencerOptions,
+) -> Result<()> {
+    let parents = Commit::read(repo, commit)?.parents.len();
+    match options.mainline {
+        None if parents > 1 => {
+            bail!("commit {} is a merge but no -m option was given.", commit)
+        }
+        Some(_) if parents <= 1 => {
+            bail!(
+                "mainline was specified but commit {} is not a merge.",
+                commit
+            )
+        }
+        Some(mainline) if mainline == 0 || mainline > parents => {
+            bail!("commit {} does not have parent {}", commit, mainline)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Apply the todo list until it is empty or a commit stops the sequencer
+fn run(
+    repo: &Repository,
+    mut todo: Vec<(Action, ObjectHash)>,
+    options: &SequencerOptions,
+    mut report: SequencerReport,
+) -> Result<SequencerReport> {
+    while let Some((action, commit)) = todo.first().cloned() {
+        write_todo(repo, &todo)?;
+        let picked = Commit::read(repo, &commit)?;
+        match apply(repo, action, &commit, &picked, options)? {
+            Ok(created) => {
+                report.created.push(created.clone());
+                write_atomic(
+                    &sequencer_dir(repo).join("abort-safety"),
+                    format!("{}\n", created).as_bytes(),
+                )?;
+                todo.remove(0);
+            }
+            Err(reason) => {
+                report.stopped = Some(Stop {
+                    action,
+                    commit,
+                    summary: picked.summary().to_string(),
+                    reason,
+                });
+                return Ok(report);
+            }
+        }
+    }
+    remove_state(repo)?;
+    Ok(report)
+}
+
+/// Apply one commit on top of HEAD, returning the created commit or the
+/// reason the sequencer has to stop
+fn apply(
+    repo: &Repository,
+    action: Action,
+    hash: &ObjectHash,
+    picked: &Commit,
+    options: &SequencerOptions,
+) -> Result<std::result::Result<ObjectHash, StopReason>> {
+    let result = apply_commit(
+        repo,
+        action,
+        hash,
+        picked,
+        options.mainline,
+        action.command(),
+    )?;
+    let parent = picked.parents.get(options.mainline.unwrap_or(1) - 1);
+
+    let message = match action {
+        Action::Pick if options.record_origin => format!(
+            "{}\n\n(cherry picked from commit {})\n",
+            picked.message.trim_end(),
+            hash
+        ),
+        Action::Pick => picked.message.clone(),
+        Action::Revert => {
+            let mut message = format!(
+                "Revert \"{}\"\n\nThis reverts commit {}",
+                picked.summary(),
+                hash
+            );
+            match parent {
+                Some(parent) if picked.parents.len() > 1 => {
+                    message.push_str(&format!(", reversing\nchanges made to {}.\n", parent))
+                }
+                _ => message.push_str(".\n"),
+            }
+            message
+        }
+    };
+
+    let gitdir = repo.gitdir();
+    if !result.conflicts.is_empty() {
+        let mut merge_msg = format!("{}\n# Conflicts:\n", message);
+        for conflict in &result.conflicts {
+            merge_msg.push_str(&format!("#\t{}\n", conflict.path));
+        }
+        write_atomic(
+            &gitdir.join(action.head_file()),
+            format!("{}\n", hash).as_bytes(),
+        )?;
+        write_atomic(&gitdir.join(MERGE_MSG), merge_msg.as_bytes())?;
+        return Ok(Err(StopReason::Conflicted(result)));
+    }
+
+    let tree = result.write_tree(repo)?;
+    if Some(&tree) == head_tree(repo)?.as_ref() {
+        write_atomic(
+            &gitdir.join(action.head_file()),
+            format!("{}\n", hash).as_bytes(),
+        )?;
+        write_atomic(&gitdir.join(MERGE_MSG), message.as_bytes())?;
+        return Ok(Err(StopReason::Empty));
+    }
+    Ok(Ok(write_commit(repo, action, picked, tree, &message)?))
+}
+
+/// Three-way merge the changes of `picked`, or their inverse for a revert,
+/// into HEAD and update the index and working tree
+///
+/// Conflicts are recorded in the index; the caller decides what to do with
+/// them. `command` names the operation in error messages.
+pub(crate) fn apply_commit(
+    repo: &Repository,
+    action: Action,
+    hash: &ObjectHash,
+    picked: &Commit,
+    mainline: Option<usize>,
+    command: &str,
+) -> Result<TreeMerge> {
+    let Some(head) = resolve_ref(repo, "HEAD")? else {
+        bail!("Cannot {} onto an unborn branch", command);
+    };
+    let head_tree = Commit::read(repo, &head)?.tree;
+    let current = flatten_tree(repo, &head_tree)?;
+    let mut index = Index::read(repo)?;
+    if index.has_conflicts() {
+        bail!(
+            "{} is not possible because you have unmerged files.",
+            command
+        );
+    }
+    check_index_matches_head(&index, &current, command)?;
+
+    let parent_tree = match picked.parents.get(mainline.unwrap_or(1) - 1) {
+        Some(parent) => Commit::read(repo, parent)?.tree,
+        None => Tree::default().write(repo)?,
+    };
+    let label = format!("{} ({})", &hash.to_hex()[..7], picked.summary());
+    let parent_label = format!("parent of {}", label);
+    let (base, theirs, labels) = match action {
+        Action::Pick => (parent_tree, picked.tree.clone(), (parent_label, label)),
+        Action::Revert => (picked.tree.clone(), parent_tree, (label, parent_label)),
+    };
+    let labels = ConflictLabels {
+        ours: "HEAD".to_string(),
+        base: labels.0,
+        theirs: labels.1,
+    };
+    let style = ConflictStyle::from_config(repo)?;
+    let result = merge_trees(repo, Some(&base), &head_tree, &theirs, &labels, style)?;
+    update_files(repo, &mut index, &current, &result.files, command)?;
+    if !result.conflicts.is_empty() {
+        record_conflicts(&mut index, &result.conflicts)?;
+        index.write(repo)?;
+    }
+    Ok(result)
+}
+
+/// Commit `tree` on top of HEAD and advance HEAD
+///
+/// Picked commits keep their original author; reverts are authored by the
+/// current user.
+fn write_commit(
+    repo: &Repository,
+    action: Action,
+    picked: &Commit,
+    tree: ObjectHash,
+    message: &str,
+) -> Result<ObjectHash> {
+    let parents = resolve_ref(repo, "HEAD")?.into_iter().collect();
+    let mut commit = Commit::new(repo, tree, parents, message)?;
+    if action == Action::Pick {
+        commit.author = picked.author.clone();
+        commit.extra_headers = picked
+            .extra_headers
+            .iter()
+            .filter(|(key, _)| key == "encoding")
+            .cloned()
+            .collect();
+    }
+    let hash = commit.write(repo)?;
+    update_head(repo, &hash)?;
+    Ok(hash)
+}
+
+fn sequencer_dir(repo: &Repository) -> PathBuf {
+    repo.gitdir().join(SEQUENCER_DIR)
+}
+
+fn read_hash(repo: &Repository, path: &std::path::Path) -> Result<ObjectHash> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    ObjectHash::from_hex(text.trim(), repo.hash_algorithm())
+        .with_context(|| format!("Invalid hash in {}", path.display()))
+}
+
+/// Write the todo list in git's `<action> <hash> <summary>` format
+fn write_todo(repo: &Repository, todo: &[(Action, ObjectHash)]) -> Result<()> {
+    let mut text = String::new();
+    for (action, hash) in todo {
+        let commit = Commit::read(repo, hash)?;
+        text.push_str(&format!("{} {} {}\n", action, hash, commit.summary()));
+    }
+    write_atomic(&sequencer_dir(repo).join("todo"), text.as_bytes())
+}
+
+fn read_state(repo: &Repository) -> Result<(Vec<(Action, ObjectHash)>, SequencerOptions)> {
+    let dir = sequencer_dir(repo);
+    if !dir.exists() {
+        bail!("no cherry-pick or revert in progress");
+    }
+    let text = fs::read_to_string(dir.join("todo")).unwrap_or_default();
+    let mut todo = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let (Some(action), Some(commit)) = (words.next(), words.next()) else {
+            bail!("Invalid line in sequencer todo: {}", line);
+        };
+        let action = action
+            .parse::<Action>()
+            .with_context(|| format!("Invalid action in sequencer todo: {}", line))?;
+        todo.push((action, peel_to_commit(repo, &rev_parse(repo, commit)?)?));
+    }
+
+    let opts = GitConfig::read(&dir.join("opts"))?;
+    let options = SequencerOptions {
+        mainline: opts.get_int("options.mainline")?.map(|n| n as usize),
+        record_origin: opts.get_bool("options.record-origin")?.unwrap_or(false),
+    };
+    Ok((todo, options))
+}
+
+fn remove_files(repo: &Repository, names: &[&str]) -> Result<()> {
+    for name in names {
+        let path = repo.gitdir().join(name);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_state(repo: &Repository) -> Result<()> {
+    let dir = sequencer_dir(repo);
+    if dir.exists() {
+        fs::remove_dir_all(dir)?;
+    }
+    remove_files(repo, &[CHERRY_PICK_HEAD, REVERT_HEAD, MERGE_MSG])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkout::checkout_tree;
+    use crate::refs::update_ref;
+    use crate::test_utils::{commit_files, init_repo, set_identity, write_commit as commit_on};
+    use tempfile::TempDir;
+
+    /// Build `master: base - main` and `topic: base - one - two`, with the
+    /// working tree checked out at master
+    fn setup(main: &str, change: &str) -> (TempDir, Repository, ObjectHash, ObjectHash) {
+        let (dir, repo) = init_repo();
+        set_identity(&repo);
+        let base = commit_files(&repo, &[("file", "a\nb\nc\n")], "base");
+        let one = commit_on(
+            &repo,
+            &[("file", change)],
+            std::slice::from_ref(&base),
+            "one",
+        );
+        let two = commit_on(
+            &repo,
+            &[("file", change), ("new", "n\n")],
+            std::slice::from_ref(&one),
+            "two",
+        );
+        update_ref(&repo, "refs/heads/topic", &two).unwrap();
+        let head = commit_files(&repo, &[("file", main)], "main");
+        checkout_tree(&repo, &Commit::read(&repo, &head).unwrap().tree, true).unwrap();
+        (dir, repo, one, two)
+    }
+
+    #[test]
+    fn test_cherry_pick_range() {
+        let (_dir, repo, one, two) = setup("A\nb\nc\n", "a\nb\nC\n");
+        let report =
+            cherry_pick(&repo, &["master..topic".to_string()], &Default::default()).unwrap();
+        assert!(report.stopped.is_none());
+        assert_eq!(report.created.len(), 2);
+
+        let head = Commit::read(&repo, &resolve_ref(&repo, "HEAD").unwrap().unwrap()).unwrap();
+        assert_eq!(head.message, "two\n");
+        assert_eq!(head.author, Commit::read(&repo, &two).unwrap().author);
+        assert_eq!(
+            Commit::read(&repo, &head.parents[0]).unwrap().message,
+            "one\n"
+        );
+        assert_ne!(head.parents[0], one);
+        let content = fs::read_to_string(repo.worktree().join("file")).unwrap();
+        assert_eq!(content, "A\nb\nC\n");
+        assert!(repo.worktree().join("new").exists());
+        assert!(!sequencer_in_progress(&repo));
+    }
+
+    #[test]
+    fn test_cherry_pick_conflict_and_continue() {
+        let (_dir, repo, _, _) = setup("ours\nb\nc\n", "theirs\nb\nc\n");
+        let report =
+            cherry_pick(&repo, &["master..topic".to_string()], &Default::default()).unwrap();
+        let stop = report.stopped.unwrap();
+        assert!(matches!(stop.reason, StopReason::Conflicted(_)));
+        assert_eq!(stop.summary, "one");
+        assert!(sequencer_in_progress(&repo));
+        assert!(repo.gitdir().join(CHERRY_PICK_HEAD).exists());
+        let todo = fs::read_to_string(sequencer_dir(&repo).join("todo")).unwrap();
+        assert_eq!(todo.lines().count(), 2);
+        assert!(cherry_pick(&repo, &["topic".to_string()], &Default::default()).is_err());
+        assert!(sequencer_continue(&repo).is_err());
+
+        fs::write(repo.worktree().join("file"), "resolved\n").unwrap();
+        crate::add::add(&repo, &["file".to_string()], &Default::default()).unwrap();
+        let report = sequencer_continue(&repo).unwrap();
+        assert!(report.stopped.is_none());
+        assert_eq!(report.created.len(), 2);
+        let head = Commit::read(&repo, &resolve_ref(&repo, "HEAD").unwrap().unwrap()).unwrap();
+        assert_eq!(head.message, "two\n");
+        assert_eq!(
+            Commit::read(&repo, &head.parents[0]).unwrap().message,
+            "one\n"
+        );
+        assert!(!sequencer_in_progress(&repo));
+        assert!(!repo.gitdir().join(CHERRY_PICK_HEAD).exists());
+    }
+
+    #[test]
+    fn test_cherry_pick_abort() {
+        let (_dir, repo, _, _) = setup("ours\nb\nc\n", "theirs\nb\nc\n");
+        let head = resolve_ref(&repo, "HEAD").unwrap();
+        cherry_pick(
+            &repo,
+            &["topic~1".to_string(), "topic".to_string()],
+            &Default::default(),
+        )
+        .unwrap();
+        sequencer_abort(&repo).unwrap();
+        assert_eq!(resolve_ref(&repo, "HEAD").unwrap(), head);
+        let content = fs::read_to_string(repo.worktree().join("file")).unwrap();
+        assert_eq!(content, "ours\nb\nc\n");
+        assert!(!Index::read(&repo).unwrap().has_conflicts());
+        assert!(!sequencer_in_progress(&repo));
+        assert!(sequencer_abort(&repo).is_err());
+    }
+
+    #[test]
+    fn test_revert() {
+        let (_dir, repo) = init_repo();
+        set_identity(&repo);
+        commit_files(&repo, &[("file", "a\n")], "first");
+        let second = commit_files(&repo, &[("file", "a\n"), ("other", "o\n")], "second");
+        commit_files(&repo, &[("file", "b\n"), ("other", "o\n")], "third");
+        let head = resolve_ref(&repo, "HEAD").unwrap().unwrap();
+        checkout_tree(&repo, &Commit::read(&repo, &head).unwrap().tree, true).unwrap();
+
+        let report = revert(&repo, &["HEAD~1".to_string()], &Default::default()).unwrap();
+        assert!(report.stopped.is_none());
+        let commit = Commit::read(&repo, &report.created[0]).unwrap();
+        assert_eq!(
+            commit.message,
+            format!("Revert \"second\"\n\nThis reverts commit {}.\n", second)
+        );
+        assert!(!repo.worktree().join("other").exists());
+        let content = fs::read_to_string(repo.worktree().join("file")).unwrap();
+        assert_eq!(content, "b\n");
+
+        let options = SequencerOptions {
+            mainline: Some(1),
+            ..Default::default()
+        };
+        assert!(revert(&repo, &["HEAD".to_string()], &options).is_err());
+    }
+}
@@ -1,26 +1,104 @@
+use crate::objects::HashAlgorithm;
+use crate::refs::RefStorage;
 use config::{Config, ConfigError, Environment, File};
 use serde::Deserialize;
 use serde::Serialize;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Core {
     pub repositoryformatversion: i32,
     pub filemode: bool,
     pub bare: bool,
+    pub symlinks: bool,
+}
+
+/// Extensions are the `extensions.*` settings a repository needs its
+/// readers to understand. Git writes the keys in lowercase, but documents
+/// them in camel case.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Extensions {
+    #[serde(alias = "objectFormat", skip_serializing_if = "Option::is_none")]
+    pub objectformat: Option<HashAlgorithm>,
+    /// Read `config.worktree` on top of `config`
+    #[serde(alias = "worktreeConfig", default, skip_serializing_if = "is_false")]
+    pub worktreeconfig: bool,
+    /// Never delete objects, e.g. because other repositories borrow them
+    #[serde(alias = "preciousObjects", default, skip_serializing_if = "is_false")]
+    pub preciousobjects: bool,
+    /// The remote promising the objects missing from a partial clone
+    #[serde(alias = "partialClone", skip_serializing_if = "Option::is_none")]
+    pub partialclone: Option<String>,
+    #[serde(alias = "refStorage", skip_serializing_if = "Option::is_none")]
+    pub refstorage: Option<RefStorage>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+impl Extensions {
+    /// Return true if no extension is set
+    pub fn is_empty(&self) -> bool {
+        self.objectformat.is_none()
+            && !self.worktreeconfig
+            && !self.preciousobjects
+            && self.partialclone.is_none()
+            && self.refstorage.is_none()
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Settings {
     pub core: Core,
+    #[serde(default, skip_serializing_if = "Extensions::is_empty")]
+    pub extensions: Extensions,
 }
 
-impl Settings {
-    /// Create a new Settings instance
-    pub fn new() -> Result<Settings, ConfigError> {
-        let default_config = include_str!("config/default.ini");
+/// The settings every repository starts from
+const DEFAULT_CONFIG: &str = include_str!("config/default.ini");
+
+impl Default for Settings {
+    /// Return the settings of a new repository, from the bundled defaults
+    /// alone rather than any existing config
+    fn default() -> Settings {
         Config::builder()
-            .add_source(File::from_str(default_config, config::FileFormat::Ini))
-            .add_source(File::new(".git/config", config::FileFormat::Ini).required(false))
+            .add_source(File::from_str(DEFAULT_CONFIG, config::FileFormat::Ini))
+            .build()
+            .and_then(Config::try_deserialize)
+            .expect("the bundled default settings are valid")
+    }
+}
+
+impl Settings {
+    /// Load the settings of the repository whose git directory is `gitdir`
+    ///
+    /// With `extensions.worktreeConfig`, `config.worktree` overrides the
+    /// settings of `config`.
+    pub fn load(gitdir: &Path) -> Result<Settings, ConfigError> {
+        Settings::load_linked(gitdir, gitdir)
+    }
+
+    /// Load the settings of a worktree whose git directory is `gitdir`,
+    /// reading `config` from the `commondir` every worktree shares
+    pub fn load_linked(commondir: &Path, gitdir: &Path) -> Result<Settings, ConfigError> {
+        let config = commondir.join("config");
+        let settings = Settings::load_files(std::slice::from_ref(&config))?;
+        if !settings.extensions.worktreeconfig {
+            return Ok(settings);
+        }
+        Settings::load_files(&[config, gitdir.join("config.worktree")])
+    }
+
+    fn load_files(paths: &[PathBuf]) -> Result<Settings, ConfigError> {
+        let mut builder =
+            Config::builder().add_source(File::from_str(DEFAULT_CONFIG, config::FileFormat::Ini));
+        for path in paths {
+            builder = builder.add_source(
+                File::new(&path.to_string_lossy(), config::FileFormat::Ini).required(false),
+            );
+        }
+        builder
             .add_source(Environment::with_prefix("LEGIT").separator("_"))
             .build()?
             .try_deserialize()
@@ -32,8 +110,52 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_settings_new() {
-        let settings = Settings::new().unwrap();
+    fn test_settings_default() {
+        let settings = Settings::default();
         assert_eq!(settings.core.repositoryformatversion, 0);
+        assert!(settings.core.symlinks);
+        assert!(settings.extensions.is_empty());
+    }
+
+    #[test]
+    fn test_settings_load() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        std::fs::write(
+            tempdir.path().join("config"),
+            "[core]\nfilemode = true\nsymlinks = false\n",
+        )
+        .unwrap();
+        let settings = Settings::load(tempdir.path()).unwrap();
+        assert!(settings.core.filemode);
+        assert!(!settings.core.symlinks);
+        assert!(!settings.core.bare);
+        assert_eq!(settings.extensions.objectformat, None);
+
+        std::fs::write(
+            tempdir.path().join("config"),
+            "[core]\n\trepositoryformatversion = 1\n[extensions]\n\tobjectFormat = sha256\n",
+        )
+        .unwrap();
+        let settings = Settings::load(tempdir.path()).unwrap();
+        assert_eq!(
+            settings.extensions.objectformat,
+            Some(HashAlgorithm::Sha256)
+        );
+
+        std::fs::write(
+            tempdir.path().join("config"),
+            "[extensions]\n\tworktreeConfig = true\n\tpartialclone = origin\n",
+        )
+        .unwrap();
+        std::fs::write(
+            tempdir.path().join("config.worktree"),
+            "[core]\nbare = true\n",
+        )
+        .unwrap();
+        let settings = Settings::load(tempdir.path()).unwrap();
+        assert!(settings.extensions.worktreeconfig);
+        assert!(!settings.extensions.preciousobjects);
+        assert_eq!(settings.extensions.partialclone.as_deref(), Some("origin"));
+        assert!(settings.core.bare);
     }
 }
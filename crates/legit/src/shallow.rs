@@ -0,0 +1,305 @@
+use crate::commits::{Commit, Signature};
+use crate::objects::ObjectHash;
+use crate::refs::write_atomic;
+use crate::revision::peel_to_commit;
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+/// Depth a client asks for to get the whole history, as `fetch --unshallow`
+/// does
+pub const INFINITE_DEPTH: u32 = 0x7fff_ffff;
+
+/// Grafts are the commits whose parents a walk of `include ^exclude`
+/// ignores on each side, e.g. the shallow commits of a repository
+///
+/// A server deepening a shallow client cuts the commits it sends at the new
+/// boundary, and what the client has at its current one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Grafts {
+    pub include: HashSet<ObjectHash>,
+    pub exclude: HashSet<ObjectHash>,
+}
+
+impl Grafts {
+    /// Return the grafts of a repository: its shallow commits on both sides
+    pub fn of(repo: &Repository) -> Result<Grafts> {
+        let shallow = read_shallow(repo)?;
+        Ok(Grafts {
+            include: shallow.clone(),
+            exclude: shallow,
+        })
+    }
+}
+
+/// Read `.git/shallow`, the commits whose parents the repository lacks
+pub fn read_shallow(repo: &Repository) -> Result<HashSet<ObjectHash>> {
+    let path = repo.commondir().join("shallow");
+    let Ok(text) = fs::read_to_string(&path) else {
+        return Ok(HashSet::new());
+    };
+    text.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            ObjectHash::from_hex(line, repo.hash_algorithm())
+                .with_context(|| format!("Invalid shallow line {}", line))
+        })
+        .collect()
+}
+
+/// Return true if the repository lacks part of its history
+pub fn is_shallow(repo: &Repository) -> bool {
+    repo.commondir().join("shallow").is_file()
+}
+
+/// Write `.git/shallow`, removing it once the history is complete
+pub fn write_shallow(repo: &Repository, shallow: &HashSet<ObjectHash>) -> Result<()> {
+    let path = repo.commondir().join("shallow");
+    if shallow.is_empty() {
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        return Ok(());
+    }
+    let mut hashes = shallow.iter().map(|hash| hash.to_hex()).collect::<Vec<_>>();
+    hashes.sort();
+    write_atomic(&path, format!("{}\n", hashes.join("\n")).as_bytes())
+}
+
+/// Record the shallow boundary a fetch moved: `shallow` commits lost their
+/// parents and `unshallow` ones got them back
+pub fn update_shallow(
+    repo: &Repository,
+    shallow: &[ObjectHash],
+    unshallow: &[ObjectHash],
+) -> Result<()> {
+    if shallow.is_empty() && unshallow.is_empty() {
+        return Ok(());
+    }
+    let mut current = read_shallow(repo)?;
+    current.extend(shallow.iter().cloned());
+    for hash in unshallow {
+        current.remove(hash);
+    }
+    write_shallow(repo, &current)
+}
+
+/// Return the parents of a commit as a walk sees them: none for a graft
+pub(crate) fn graft_parents(
+    grafts: &HashSet<ObjectHash>,
+    hash: &ObjectHash,
+    parents: Vec<ObjectHash>,
+) -> Vec<ObjectHash> {
+    match grafts.contains(hash) {
+        true => Vec::new(),
+        false => parents,
+    }
+}
+
+/// Deepen is how much history a fetch asks for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Deepen {
+    /// This many commits from each wanted one, like `--depth`
+    Depth(u32),
+    /// The commits made at or after this time, like `--shallow-since`
+    Since(i64),
+}
+
+/// ShallowInfo is where a deepening fetch leaves the history of a client
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShallowInfo {
+    /// Commits whose parents are not sent
+    pub shallow: Vec<ObjectHash>,
+    /// Commits of the client's boundary whose parents are now sent
+    pub unshallow: Vec<ObjectHash>,
+    /// The grafts to list the objects to send with
+    pub grafts: Grafts,
+}
+
+/// Find the shallow boundary of the history of `wants` a fetch sends, given
+/// the `client` commits the fetching repository is already shallow at
+///
+/// Like git, a client's shallow commit is only reported again when it
+/// becomes complete, and the depth counts from the wanted commits.
+pub fn shallow_boundary(
+    repo: &Repository,
+    wants: &[ObjectHash],
+    deepen: Deepen,
+    client: &HashSet<ObjectHash>,
+) -> Result<ShallowInfo> {
+    let own = read_shallow(repo)?;
+    // Wanted trees and blobs have no history
+    let wants = wants
+        .iter()
+        .filter_map(|hash| peel_to_commit(repo, hash).ok())
+        .collect::<Vec<_>>();
+    let mut depths = HashMap::new();
+    let mut boundary = HashSet::new();
+    let mut stack = wants
+        .iter()
+        .map(|hash| (hash.clone(), 1))
+        .collect::<Vec<_>>();
+    while let Some((hash, depth)) = stack.pop() {
+        if depths.get(&hash).is_some_and(|&seen| seen <= depth) {
+            continue;
+        }
+        let commit = Commit::read(repo, &hash)?;
+        if let Deepen::Since(since) = deepen {
+            if commit_time(&commit) < since {
+                if wants.contains(&hash) {
+                    bail!("no commits selected for shallow requests");
+                }
+                continue;
+            }
+        }
+        depths.insert(hash.clone(), depth);
+        let parents = graft_parents(&own, &hash, commit.parents);
+        let cut = match deepen {
+            Deepen::Depth(limit) => depth >= limit && !parents.is_empty(),
+            Deepen::Since(since) => parents
+                .iter()
+                .any(|parent| Commit::read(repo, parent).map_or(true, |p| commit_time(&p) < since)),
+        };
+        if cut {
+            boundary.insert(hash.clone());
+        } else {
+            boundary.remove(&hash);
+        }
+        if !(cut && matches!(deepen, Deepen::Depth(_))) {
+            stack.extend(parents.into_iter().map(|parent| (parent, depth + 1)));
+        }
+    }
+
+    let mut shallow = boundary
+        .iter()
+        .filter(|hash| !client.contains(*hash))
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut unshallow = client
+        .iter()
+        .filter(|hash| depths.contains_key(*hash) && !boundary.contains(*hash))
+        .cloned()
+        .collect::<Vec<_>>();
+    shallow.sort();
+    unshallow.sort();
+    let include = client
+        .iter()
+        .filter(|hash| !unshallow.contains(hash))
+        .chain(&boundary)
+        .chain(&own)
+        .cloned()
+        .collect();
+    let exclude = client.iter().chain(&own).cloned().collect();
+    Ok(ShallowInfo {
+        shallow,
+        unshallow,
+        grafts: Grafts { include, exclude },
+    })
+}
+
+fn commit_time(commit: &Commit) -> i64 {
+    Signature::parse(&commit.committer)
+        .map(|s| s.time)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::{clone, fetch, CloneOptions, FetchOptions};
+    use crate::objects::object_exists;
+    use crate::test_utils::{commit_files, init_repo, write_commit};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_shallow_boundary() {
+        let (_dir, repo) = init_repo();
+        let mut commits = vec![write_commit(&repo, &[("a", "0")], &[], "0")];
+        for i in 1..5 {
+            let content = i.to_string();
+            let parent = commits[i - 1].clone();
+            commits.push(write_commit(&repo, &[("a", &content)], &[parent], &content));
+        }
+        let tips = [commits[4].clone()];
+
+        let info = shallow_boundary(&repo, &tips, Deepen::Depth(2), &HashSet::new()).unwrap();
+        assert_eq!(info.shallow, vec![commits[3].clone()]);
+        assert!(info.unshallow.is_empty());
+
+        // Deepening past the client's boundary completes it
+        let client = HashSet::from([commits[3].clone()]);
+        let info = shallow_boundary(&repo, &tips, Deepen::Depth(3), &client).unwrap();
+        assert_eq!(info.shallow, vec![commits[2].clone()]);
+        assert_eq!(info.unshallow, vec![commits[3].clone()]);
+        let info = shallow_boundary(&repo, &tips, Deepen::Depth(INFINITE_DEPTH), &client).unwrap();
+        assert!(info.shallow.is_empty());
+        assert!(info.grafts.include.is_empty());
+
+        update_shallow(&repo, &[commits[3].clone()], &[]).unwrap();
+        assert!(is_shallow(&repo));
+        assert_eq!(read_shallow(&repo).unwrap(), client);
+        update_shallow(&repo, &[], &[commits[3].clone()]).unwrap();
+        assert!(!is_shallow(&repo));
+    }
+
+    #[test]
+    fn test_fetch_moves_shallow_file() {
+        let (source_dir, source) = init_repo();
+        let commits = (0..5)
+            .map(|i| commit_files(&source, &[("a.txt", &i.to_string())], "commit"))
+            .collect::<Vec<_>>();
+        let target = TempDir::new().unwrap();
+        let options = CloneOptions {
+            depth: Some(1),
+            no_checkout: true,
+            ..CloneOptions::default()
+        };
+        let url = source_dir.path().to_str().unwrap();
+        let repo = clone(url, &target.path().join("clone"), &options).unwrap();
+        let shallow_file = || fs::read_to_string(repo.commondir().join("shallow")).unwrap();
+        assert_eq!(shallow_file(), format!("{}\n", commits[4]));
+
+        // Deepening replaces the boundary
+        let options = FetchOptions {
+            depth: Some(3),
+            ..FetchOptions::default()
+        };
+        fetch(&repo, "origin", &options).unwrap();
+        assert_eq!(shallow_file(), format!("{}\n", commits[2]));
+        assert!(object_exists(&repo, &commits[2]));
+        assert!(!object_exists(&repo, &commits[1]));
+
+        // A new shallow history adds its own boundary next to the old one
+        let (other_dir, other) = init_repo();
+        commit_files(&other, &[("b.txt", "b")], "first");
+        let unrelated = commit_files(&other, &[("b.txt", "c")], "second");
+        let other_url = other_dir.path().to_str().unwrap();
+        let options = FetchOptions {
+            refspecs: vec!["refs/heads/master:refs/remotes/other/master".to_string()],
+            depth: Some(1),
+            ..FetchOptions::default()
+        };
+        fetch(&repo, other_url, &options).unwrap();
+        let mut expected = [commits[2].to_hex(), unrelated.to_hex()];
+        expected.sort();
+        assert_eq!(shallow_file(), format!("{}\n", expected.join("\n")));
+
+        // Unshallowing drops the boundary of one history, then the file
+        let unshallow = FetchOptions {
+            unshallow: true,
+            ..FetchOptions::default()
+        };
+        fetch(&repo, "origin", &unshallow).unwrap();
+        assert_eq!(shallow_file(), format!("{}\n", unrelated));
+        assert!(object_exists(&repo, &commits[0]));
+        let unshallow = FetchOptions {
+            depth: None,
+            unshallow: true,
+            ..options
+        };
+        fetch(&repo, other_url, &unshallow).unwrap();
+        assert!(!is_shallow(&repo));
+        assert!(read_shallow(&repo).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,681 @@
+use crate::checkout::{
+    self, apply_changes, checkout_tree, remove_file, store_file, write_file, CheckoutOptions,
+    FileMap,
+};
+use crate::commits::{Commit, Signature};
+use crate::diff::index_files;
+use crate::index::{Index, IndexEntry};
+use crate::merge::{
+    merge_trees, record_conflicts, update_files, ConflictLabels, ConflictStyle, TreeMerge,
+};
+use crate::objects::ObjectHash;
+use crate::refs::{
+    delete_ref, read_head, read_reflog, resolve_ref, update_ref, update_ref_with_reflog,
+    write_reflog, Head, ReflogEntry,
+};
+use crate::revision::{peel_to_commit, rev_parse};
+use crate::status::{self, StatusOptions, UntrackedFiles};
+use crate::tree::{build_tree, flatten_tree};
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeSet;
+use std::fs;
+
+const STASH_REF: &str = "refs/stash";
+
+/// StashOptions controls what `stash_push` saves
+#[derive(Debug, Clone, Default)]
+pub struct StashOptions {
+    /// Describe the stash with this message instead of HEAD's summary
+    pub message: Option<String>,
+    /// Also save and remove untracked files
+    pub include_untracked: bool,
+    /// Also save and remove untracked and ignored files
+    pub all: bool,
+    /// Leave the changes added to the index in place
+    pub keep_index: bool,
+}
+
+/// Stash is an entry of the stash, or any commit used as one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stash {
+    /// How the stash was named, e.g. `refs/stash@{0}` or `stash@{2}`
+    pub name: String,
+    /// Position in the stash reflog, newest first; `None` for other commits
+    pub position: Option<usize>,
+    pub commit: ObjectHash,
+}
+
+/// StashEntry is one line of `stash list`
+#[derive(Debug, Clone, PartialEq)]
+pub struct StashEntry {
+    pub commit: ObjectHash,
+    pub message: String,
+}
+
+/// The commits a stash is made of
+struct StashCommits {
+    /// The commit the stash was created on
+    base: Commit,
+    /// The tree of the index
+    index: ObjectHash,
+    /// The tree of the working tree
+    worktree: ObjectHash,
+    /// The tree of the untracked files, if they were saved
+    untracked: Option<ObjectHash>,
+}
+
+impl StashCommits {
+    fn read(repo: &Repository, stash: &Stash) -> Result<StashCommits> {
+        let commit = Commit::read(repo, &stash.commit)?;
+        let [base, index, rest @ ..] = commit.parents.as_slice() else {
+            bail!("'{}' is not a stash-like commit", stash.name);
+        };
+        let untracked = match rest {
+            [] => None,
+            [untracked] => Some(Commit::read(repo, untracked)?.tree),
+            _ => bail!("'{}' is not a stash-like commit", stash.name),
+        };
+        Ok(StashCommits {
+            base: Commit::read(repo, base)?,
+            index: Commit::read(repo, index)?.tree,
+            worktree: commit.tree,
+            untracked,
+        })
+    }
+}
+
+/// Save the local changes as a stash and reset the working tree to HEAD,
+/// like `git stash push`
+///
+/// The stash is a commit of the working tree whose parents are HEAD, a
+/// commit of the index and, when untracked files are saved, a commit of
+/// those files. Returns `None` when there is nothing to save.
+pub fn stash_push(repo: &Repository, options: &StashOptions) -> Result<Option<ObjectHash>> {
+    let Some(head) = resolve_ref(repo, "HEAD")? else {
+        bail!("You do not have the initial commit yet");
+    };
+    let head_commit = Commit::read(repo, &head)?;
+    let mut index = Index::read(repo)?;
+    if let Some(path) = index.conflicted_paths().first() {
+        bail!("{}: needs merge\nCannot save the current index state", path);
+    }
+
+    let index_tree = index.write_tree(repo)?;
+    let worktree_tree = write_worktree_tree(repo, &index)?;
+    let untracked = match options.include_untracked || options.all {
+        true => untracked_files(repo, options.all)?,
+        false => Vec::new(),
+    };
+    if index_tree == head_commit.tree && worktree_tree == head_commit.tree && untracked.is_empty() {
+        return Ok(None);
+    }
+
+    let branch = match read_head(repo)? {
+        Head::Branch(name) => name.trim_start_matches("refs/heads/").to_string(),
+        Head::Detached(_) => "(no branch)".to_string(),
+    };
+    let description = format!(
+        "{}: {} {}",
+        branch,
+        &head.to_hex()[..7],
+        head_commit.summary()
+    );
+    let index_commit = Commit::new(
+        repo,
+        index_tree,
+        vec![head.clone()],
+        &format!("index on {}\n", description),
+    )?
+    .write(repo)?;
+    let mut parents = vec![head.clone(), index_commit];
+    if !untracked.is_empty() {
+        let files = untracked
+            .iter()
+            .map(|path| Ok((path.clone(), store_file(repo, path, None)?)))
+            .collect::<Result<FileMap>>()?;
+        let tree = build_tree(
+            repo,
+            files
+                .iter()
+                .map(|(path, (mode, hash))| (path.as_str(), *mode, hash)),
+        )?;
+        let message = format!("untracked files on {}\n", description);
+        let commit = Commit::new(repo, tree, Vec::new(), &message)?;
+        parents.push(commit.write(repo)?);
+    }
+    let message = match &options.message {
+        Some(message) => format!("On {}: {}", branch, message.trim_end()),
+        None => format!("WIP on {}", description),
+    };
+    // Unlike the other two, git writes this message without a final newline
+    let stash = Commit::new(repo, worktree_tree, parents, &message)?.write(repo)?;
+
+    let old = resolve_ref(repo, STASH_REF)?.unwrap_or_default();
+    update_ref_with_reflog(
+        repo,
+        STASH_REF,
+        &stash,
+        &ReflogEntry {
+            old,
+            new: stash.clone(),
+            committer: Signature::identity(repo, "COMMITTER")?.to_string(),
+            message,
+        },
+    )?;
+
+    if options.keep_index {
+        let target = index_files(&index)?;
+        let paths = target.keys().cloned().collect();
+        apply_changes(repo, &mut index, &paths, &target)?;
+        index.write(repo)?;
+    } else {
+        checkout_tree(repo, &head_commit.tree, true)?;
+    }
+    for path in &untracked {
+        remove_file(repo, path)?;
+    }
+    Ok(Some(stash))
+}
+
+/// Write the tree of the tracked files as they are in the working tree
+fn write_worktree_tree(repo: &Repository, index: &Index) -> Result<ObjectHash> {
+    let mut files = FileMap::new();
+    for entry in &index.entries {
+        let Ok(metadata) = fs::symlink_metadata(repo.worktree().join(&entry.path)) else {
+            continue;
+        };
+        let file = match entry.stat_matches(&metadata) || metadata.is_dir() {
+            true => (entry.entry_mode()?, entry.hash.clone()),
+            false => store_file(repo, &entry.path, Some(entry))?,
+        };
+        files.insert(entry.path.clone(), file);
+    }
+    build_tree(
+        repo,
+        files
+            .iter()
+            .map(|(path, (mode, hash))| (path.as_str(), *mode, hash)),
+    )
+}
+
+/// List the untracked files, and the ignored ones too with `ignored`
+fn untracked_files(repo: &Repository, ignored: bool) -> Result<Vec<String>> {
+    let options = StatusOptions {
+        untracked: UntrackedFiles::All,
+        ignored,
+        no_renames: true,
+    };
+    let status = status::status(repo, &options)?;
+    let mut files = status.untracked;
+    for path in status.ignored {
+        // Ignored directories are reported as a whole
+        match path.strip_suffix('/') {
+            Some(dir) => list_files(repo, dir, &mut files)?,
+            None => files.push(path),
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn list_files(repo: &Repository, dir: &str, files: &mut Vec<String>) -> Result<()> {
+    for entry in fs::read_dir(repo.worktree().join(dir))? {
+        let entry = entry?;
+        let path = format!("{}/{}", dir, entry.file_name().to_string_lossy());
+        match entry.file_type()?.is_dir() {
+            true => list_files(repo, &path, files)?,
+            false => files.push(path),
+        }
+    }
+    Ok(())
+}
+
+/// List the stashes, newest first
+pub fn stash_list(repo: &Repository) -> Result<Vec<StashEntry>> {
+    Ok(read_reflog(repo, STASH_REF)?
+        .into_iter()
+        .rev()
+        .map(|entry| StashEntry {
+            commit: entry.new,
+            message: entry.message,
+        })
+        .collect())
+}
+
+/// Find the stash named by `spec`: `stash@{n}`, a bare position `n`, or
+/// any stash-like commit. Without a spec, the latest stash is used.
+pub fn resolve_stash(repo: &Repository, spec: Option<&str>) -> Result<Stash> {
+    let entries = stash_list(repo)?;
+    if entries.is_empty() && spec.is_none() {
+        bail!("No stash entries found.");
+    }
+    let name = spec.unwrap_or("refs/stash@{0}");
+    let position = match name.parse::<usize>() {
+        Ok(position) => Some(position),
+        Err(_) => ["stash@{", "refs/stash@{"]
+            .iter()
+            .find_map(|prefix| name.strip_prefix(prefix)?.strip_suffix('}'))
+            .and_then(|n| n.parse::<usize>().ok()),
+    };
+    if let Some(position) = position {
+        let name = match name.parse::<usize>() {
+            Ok(_) => format!("stash@{{{}}}", position),
+            Err(_) => name.to_string(),
+        };
+        let Some(entry) = entries.get(position) else {
+            bail!("{} is not a valid reference", name);
+        };
+        return Ok(Stash {
+            name,
+            position: Some(position),
+            commit: entry.commit.clone(),
+        });
+    }
+    let commit = rev_parse(repo, name)
+        .and_then(|hash| peel_to_commit(repo, &hash))
+        .with_context(|| format!("{} is not a valid reference", name))?;
+    Ok(Stash {
+        name: name.to_string(),
+        position: None,
+        commit,
+    })
+}
+
+/// Apply the changes of a stash to the working tree, like `git stash apply`
+///
+/// The stash is merged into the current index as a three-way merge with
+/// the stash's base. With `restore_index`, the changes that were staged are
+/// staged again; otherwise only files the stash added are staged. Untracked
+/// files saved in the stash are restored. Conflicts are left in the index
+/// and working tree and reported in the returned merge.
+pub fn stash_apply(
+    repo: &Repository,
+    spec: Option<&str>,
+    restore_index: bool,
+) -> Result<TreeMerge> {
+    let stash = resolve_stash(repo, spec)?;
+    apply(repo, &stash, restore_index)
+}
+
+fn apply(repo: &Repository, stash: &Stash, restore_index: bool) -> Result<TreeMerge> {
+    let commits = StashCommits::read(repo, stash)?;
+    let mut index = Index::read(repo)?;
+    if index.has_conflicts() {
+        bail!("cannot apply a stash in the middle of a merge");
+    }
+    let current_tree = index.write_tree(repo)?;
+    let current = index_files(&index)?;
+    let style = ConflictStyle::from_config(repo)?;
+
+    // The staged changes are replayed onto the index before the merge
+    let base_tree = &commits.base.tree;
+    let staged = match restore_index && commits.index != *base_tree && commits.index != current_tree
+    {
+        true => {
+            let result = merge_trees(
+                repo,
+                Some(base_tree),
+                &current_tree,
+                &commits.index,
+                &labels(),
+                style,
+            )?;
+            if !result.conflicts.is_empty() {
+                bail!("Conflicts in index. Try without --index.");
+            }
+            Some(result.files)
+        }
+        false => None,
+    };
+
+    let untracked = match &commits.untracked {
+        Some(tree) => flatten_tree(repo, tree)?,
+        None => FileMap::new(),
+    };
+    let existing = untracked
+        .keys()
+        .filter(|path| fs::symlink_metadata(repo.worktree().join(path)).is_ok())
+        .map(|path| format!("{} already exists, no checkout", path))
+        .collect::<Vec<_>>();
+    if !existing.is_empty() {
+        bail!(
+            "{}\ncould not restore untracked files from stash",
+            existing.join("\n")
+        );
+    }
+
+    let result = merge_trees(
+        repo,
+        Some(base_tree),
+        &current_tree,
+        &commits.worktree,
+        &labels(),
+        style,
+    )?;
+    update_files(repo, &mut index, &current, &result.files, "merge")?;
+
+    if !result.conflicts.is_empty() {
+        record_conflicts(&mut index, &result.conflicts)?;
+    } else if let Some(staged) = &staged {
+        stage_files(&mut index, staged)?;
+    } else {
+        // Like git, only files the stash added stay staged
+        let mut files = current.clone();
+        for (path, file) in &result.files {
+            if !current.contains_key(path) {
+                files.insert(path.clone(), file.clone());
+            }
+        }
+        stage_files(&mut index, &files)?;
+    }
+    index.write(repo)?;
+
+    for (path, (mode, hash)) in &untracked {
+        write_file(repo, path, *mode, hash)?;
+    }
+    Ok(result)
+}
+
+/// The conflict labels git uses when applying a stash
+fn labels() -> ConflictLabels {
+    ConflictLabels {
+        ours: "Updated upstream".to_string(),
+        base: "Version stash was based on".to_string(),
+        theirs: "Stashed changes".to_string(),
+    }
+}
+
+/// Make the index hold exactly `files`, without touching the working tree
+///
+/// Entries that do not change keep their stat data.
+fn stage_files(index: &mut Index, files: &FileMap) -> Result<()> {
+    let paths = index
+        .entries
+        .iter()
+        .map(|e| e.path.clone())
+        .chain(files.keys().cloned())
+        .collect::<BTreeSet<_>>();
+    for path in paths {
+        match files.get(&path) {
+            Some((mode, hash)) => {
+                let unchanged = index
+                    .get(&path)
+                    .is_some_and(|e| e.hash == *hash && e.mode == mode.bits());
+                if !unchanged {
+                    index.add(IndexEntry::new(&path, *mode, hash.clone(), None))?;
+                }
+            }
+            None => {
+                index.remove(&path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Apply a stash and drop it unless it conflicted, like `git stash pop`
+///
+/// Returns the merge and the stash that was dropped.
+pub fn stash_pop(
+    repo: &Repository,
+    spec: Option<&str>,
+    restore_index: bool,
+) -> Result<(TreeMerge, Option<Stash>)> {
+    let stash = resolve_stash(repo, spec)?;
+    if stash.position.is_none() {
+        bail!("'{}' is not a stash reference", stash.name);
+    }
+    let result = apply(repo, &stash, restore_index)?;
+    if !result.conflicts.is_empty() {
+        return Ok((result, None));
+    }
+    drop_stash(repo, &stash)?;
+    Ok((result, Some(stash)))
+}
+
+/// Remove a stash from the stash list, like `git stash drop`
+pub fn stash_drop(repo: &Repository, spec: Option<&str>) -> Result<Stash> {
+    let stash = resolve_stash(repo, spec)?;
+    drop_stash(repo, &stash)?;
+    Ok(stash)
+}
+
+fn drop_stash(repo: &Repository, stash: &Stash) -> Result<()> {
+    let Some(position) = stash.position else {
+        bail!("'{}' is not a stash reference", stash.name);
+    };
+    let mut entries = read_reflog(repo, STASH_REF)?;
+    let index = entries.len() - 1 - position;
+    let removed = entries.remove(index);
+    // Keep the chain of old and new values intact
+    if let Some(next) = entries.get_mut(index) {
+        next.old = removed.old;
+    }
+    match entries.last() {
+        Some(latest) => update_ref(repo, STASH_REF, &latest.new)?,
+        None => delete_ref(repo, STASH_REF)?,
+    }
+    write_reflog(repo, STASH_REF, &entries)
+}
+
+/// Remove every stash, like `git stash clear`
+pub fn stash_clear(repo: &Repository) -> Result<()> {
+    if resolve_ref(repo, STASH_REF)?.is_some() {
+        delete_ref(repo, STASH_REF)?;
+    }
+    write_reflog(repo, STASH_REF, &[])
+}
+
+/// Create a branch at the commit a stash was created on, switch to it and
+/// pop the stash there, like `git stash branch`
+pub fn stash_branch(
+    repo: &Repository,
+    branch: &str,
+    spec: Option<&str>,
+) -> Result<(TreeMerge, Option<Stash>)> {
+    let stash = resolve_stash(repo, spec)?;
+    let base = Commit::read(repo, &stash.commit)?
+        .parents
+        .first()
+        .cloned()
+        .with_context(|| format!("'{}' is not a stash-like commit", stash.name))?;
+    let options = CheckoutOptions {
+        new_branch: Some(branch.to_string()),
+        ..Default::default()
+    };
+    checkout::checkout(repo, &base.to_hex(), &options)?;
+    let result = apply(repo, &stash, true)?;
+    if !result.conflicts.is_empty() || stash.position.is_none() {
+        return Ok((result, None));
+    }
+    drop_stash(repo, &stash)?;
+    Ok((result, Some(stash)))
+}
+
+/// Return the trees a stash compares, its base and its working tree, as
+/// shown by `git stash show`
+pub fn stash_trees(repo: &Repository, spec: Option<&str>) -> Result<(ObjectHash, ObjectHash)> {
+    let stash = resolve_stash(repo, spec)?;
+    let commits = StashCommits::read(repo, &stash)?;
+    Ok((commits.base.tree, commits.worktree))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{commit_files, init_repo, set_identity};
+    use tempfile::TempDir;
+
+    /// Commit `f` and `g`, then stage a change to `f` and a new file `n`,
+    /// change `f` again, delete `g` and leave an untracked file
+    fn setup() -> (TempDir, Repository) {
+        let (dir, repo) = init_repo();
+        set_identity(&repo);
+        let head = commit_files(&repo, &[("f", "a\nb\nc\n"), ("g", "x\n")], "base");
+        checkout_tree(&repo, &Commit::read(&repo, &head).unwrap().tree, true).unwrap();
+        write(&repo, "f", "A\nb\nc\n");
+        write(&repo, "n", "new\n");
+        add(&repo, &["f", "n"]);
+        write(&repo, "f", "A\nb\nC\n");
+        fs::remove_file(repo.worktree().join("g")).unwrap();
+        write(&repo, "untracked", "u\n");
+        (dir, repo)
+    }
+
+    fn write(repo: &Repository, path: &str, content: &str) {
+        fs::write(repo.worktree().join(path), content).unwrap();
+    }
+
+    fn read(repo: &Repository, path: &str) -> Option<String> {
+        fs::read_to_string(repo.worktree().join(path)).ok()
+    }
+
+    fn add(repo: &Repository, paths: &[&str]) {
+        let paths = paths.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        crate::add::add(repo, &paths, &Default::default()).unwrap();
+    }
+
+    fn short_status(repo: &Repository) -> String {
+        let status = status::status(repo, &StatusOptions::default()).unwrap();
+        status::format_short(&status, false, false)
+    }
+
+    #[test]
+    fn test_push_records_index_worktree_and_untracked() {
+        let (_dir, repo) = setup();
+        let options = StashOptions {
+            include_untracked: true,
+            ..Default::default()
+        };
+        let stash = stash_push(&repo, &options).unwrap().unwrap();
+        assert_eq!(short_status(&repo), "");
+        assert_eq!(read(&repo, "g").as_deref(), Some("x\n"));
+        assert_eq!(read(&repo, "untracked"), None);
+
+        let commit = Commit::read(&repo, &stash).unwrap();
+        assert_eq!(commit.parents.len(), 3);
+        assert!(commit.message.starts_with("WIP on master: "));
+        let files = flatten_tree(&repo, &commit.tree).unwrap();
+        assert_eq!(files.keys().collect::<Vec<_>>(), vec!["f", "n"]);
+        let index = Commit::read(&repo, &commit.parents[1]).unwrap();
+        assert!(index.message.starts_with("index on master: "));
+        let untracked = Commit::read(&repo, &commit.parents[2]).unwrap();
+        assert!(untracked.parents.is_empty());
+        assert!(flatten_tree(&repo, &untracked.tree)
+            .unwrap()
+            .contains_key("untracked"));
+
+        assert_eq!(stash_push(&repo, &options).unwrap(), None);
+        assert_eq!(resolve_stash(&repo, None).unwrap().commit, stash);
+        assert_eq!(rev_parse(&repo, "stash@{0}").unwrap(), stash);
+    }
+
+    #[test]
+    fn test_pop_restores_changes() {
+        let (_dir, repo) = setup();
+        let before = short_status(&repo);
+        let options = StashOptions {
+            include_untracked: true,
+            ..Default::default()
+        };
+        stash_push(&repo, &options).unwrap();
+
+        let (result, dropped) = stash_pop(&repo, None, true).unwrap();
+        assert!(result.conflicts.is_empty());
+        assert_eq!(dropped.unwrap().name, "refs/stash@{0}");
+        assert_eq!(short_status(&repo), before);
+        assert_eq!(read(&repo, "f").as_deref(), Some("A\nb\nC\n"));
+        assert!(stash_list(&repo).unwrap().is_empty());
+        assert!(resolve_ref(&repo, STASH_REF).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_apply_without_index_stages_only_new_files() {
+        let (_dir, repo) = setup();
+        stash_push(&repo, &StashOptions::default()).unwrap();
+        stash_apply(&repo, None, false).unwrap();
+        assert_eq!(short_status(&repo), " M f\n D g\nA  n\n?? untracked\n");
+        assert_eq!(stash_list(&repo).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_keep_index() {
+        let (_dir, repo) = setup();
+        let options = StashOptions {
+            keep_index: true,
+            ..Default::default()
+        };
+        stash_push(&repo, &options).unwrap();
+        assert_eq!(short_status(&repo), "M  f\nA  n\n?? untracked\n");
+        assert_eq!(read(&repo, "f").as_deref(), Some("A\nb\nc\n"));
+    }
+
+    #[test]
+    fn test_list_and_drop() {
+        let (_dir, repo) = init_repo();
+        set_identity(&repo);
+        let head = commit_files(&repo, &[("f", "a\n")], "base");
+        checkout_tree(&repo, &Commit::read(&repo, &head).unwrap().tree, true).unwrap();
+        let mut stashes = Vec::new();
+        for i in 1..=3 {
+            write(&repo, "f", &format!("{}\n", i));
+            let options = StashOptions {
+                message: Some(format!("s{}", i)),
+                ..Default::default()
+            };
+            stashes.push(stash_push(&repo, &options).unwrap().unwrap());
+        }
+        let messages = |repo: &Repository| {
+            stash_list(repo)
+                .unwrap()
+                .into_iter()
+                .map(|e| e.message)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            messages(&repo),
+            vec!["On master: s3", "On master: s2", "On master: s1"]
+        );
+
+        let dropped = stash_drop(&repo, Some("stash@{1}")).unwrap();
+        assert_eq!(dropped.commit, stashes[1]);
+        assert_eq!(messages(&repo), vec!["On master: s3", "On master: s1"]);
+        let log = read_reflog(&repo, STASH_REF).unwrap();
+        assert_eq!(log[1].old, stashes[0]);
+
+        stash_drop(&repo, Some("0")).unwrap();
+        assert_eq!(
+            resolve_ref(&repo, STASH_REF).unwrap(),
+            Some(stashes[0].clone())
+        );
+        assert!(stash_drop(&repo, Some("stash@{1}")).is_err());
+        stash_clear(&repo).unwrap();
+        assert!(resolve_stash(&repo, None).is_err());
+    }
+
+    #[test]
+    fn test_pop_conflict_keeps_stash() {
+        let (_dir, repo) = init_repo();
+        set_identity(&repo);
+        let head = commit_files(&repo, &[("f", "a\n")], "base");
+        checkout_tree(&repo, &Commit::read(&repo, &head).unwrap().tree, true).unwrap();
+        write(&repo, "f", "stashed\n");
+        stash_push(&repo, &StashOptions::default()).unwrap();
+        write(&repo, "f", "committed\n");
+        add(&repo, &["f"]);
+        let index = Index::read(&repo).unwrap();
+        let tree = index.write_tree(&repo).unwrap();
+        let commit = Commit::new(&repo, tree, vec![head], "two\n").unwrap();
+        crate::refs::update_head(&repo, &commit.write(&repo).unwrap()).unwrap();
+
+        let (result, dropped) = stash_pop(&repo, None, false).unwrap();
+        assert_eq!(result.conflicts.len(), 1);
+        assert!(dropped.is_none());
+        assert!(Index::read(&repo).unwrap().has_conflicts());
+        assert!(read(&repo, "f")
+            .unwrap()
+            .contains(">>>>>>> Stashed changes"));
+        assert_eq!(stash_list(&repo).unwrap().len(), 1);
+    }
+}
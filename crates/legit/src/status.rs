@@ -0,0 +1,1357 @@
+use crate::checkout::{hash_file, head_tree, FileMap};
+use crate::diff::{rename_candidates, DiffEntry};
+use crate::ignore::IgnoreRules;
+use crate::index::{Index, IndexEntry};
+use crate::objects::{HashAlgorithm, ObjectHash};
+use crate::refs::{read_head, resolve_ref, short_name, Head};
+use crate::revision::ahead_behind;
+use crate::submodule::{submodule_state, SubmoduleState};
+use crate::tree::{flatten_tree, EntryMode};
+use crate::Repository;
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+/// Minimum similarity for a staged deletion and addition to be a rename
+const RENAME_THRESHOLD: u32 = 50;
+
+/// StatusCode is the state of a path on one side of a comparison
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusCode {
+    Unmodified,
+    Modified,
+    TypeChanged,
+    Added,
+    Deleted,
+    Renamed,
+    Copied,
+    Unmerged,
+}
+
+impl StatusCode {
+    /// The letter used by the short and porcelain formats
+    pub fn as_char(&self) -> char {
+        match self {
+            StatusCode::Unmodified => '.',
+            StatusCode::Modified => 'M',
+            StatusCode::TypeChanged => 'T',
+            StatusCode::Added => 'A',
+            StatusCode::Deleted => 'D',
+            StatusCode::Renamed => 'R',
+            StatusCode::Copied => 'C',
+            StatusCode::Unmerged => 'U',
+        }
+    }
+
+    /// The letter used by the short format, where unmodified is a space
+    fn short_char(&self) -> char {
+        match self {
+            StatusCode::Unmodified => ' ',
+            other => other.as_char(),
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            StatusCode::Modified => "modified:",
+            StatusCode::TypeChanged => "typechange:",
+            StatusCode::Added => "new file:",
+            StatusCode::Deleted => "deleted:",
+            StatusCode::Renamed => "renamed:",
+            StatusCode::Copied => "copied:",
+            StatusCode::Unmodified | StatusCode::Unmerged => "unmerged:",
+        }
+    }
+}
+
+/// FileStatus is a tracked path with staged and/or unstaged changes
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileStatus {
+    pub path: String,
+    /// The path in HEAD when the staged change is a rename or copy
+    pub orig_path: Option<String>,
+    /// Change between the HEAD tree and the index (X)
+    pub staged: StatusCode,
+    /// Change between the index and the working tree (Y)
+    pub unstaged: StatusCode,
+    /// Similarity percentage of a rename or copy
+    pub score: Option<u8>,
+    pub head: Option<(EntryMode, ObjectHash)>,
+    pub index: Option<(EntryMode, ObjectHash)>,
+    pub worktree: Option<EntryMode>,
+    /// How a checked out submodule differs from the recorded commit
+    pub submodule: Option<SubmoduleState>,
+}
+
+impl FileStatus {
+    /// The unstaged letter of the short format, where a submodule without
+    /// new commits shows `m` for modified content and `?` for untracked
+    /// content only
+    fn short_unstaged_char(&self) -> char {
+        match self.submodule {
+            Some(state) if !state.new_commits && state.modified => 'm',
+            Some(state) if !state.new_commits && state.untracked => '?',
+            _ => self.unstaged.short_char(),
+        }
+    }
+}
+
+/// Conflict is a path with unmerged index stages
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub path: String,
+    /// The base, ours and theirs stages
+    pub stages: [Option<(EntryMode, ObjectHash)>; 3],
+    pub worktree: Option<EntryMode>,
+}
+
+impl Conflict {
+    /// The two letter code of the conflict, e.g. `UU` for both modified
+    pub fn code(&self) -> &'static str {
+        match self.stages.each_ref().map(Option::is_some) {
+            [true, false, false] => "DD",
+            [false, true, false] => "AU",
+            [true, true, false] => "UD",
+            [false, false, true] => "UA",
+            [true, false, true] => "DU",
+            [false, true, true] => "AA",
+            _ => "UU",
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self.code() {
+            "DD" => "both deleted:",
+            "AU" => "added by us:",
+            "UD" => "deleted by them:",
+            "UA" => "added by them:",
+            "DU" => "deleted by us:",
+            "AA" => "both added:",
+            _ => "both modified:",
+        }
+    }
+}
+
+/// BranchStatus describes HEAD and its upstream
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BranchStatus {
+    /// The branch name, or `None` when HEAD is detached
+    pub head: Option<String>,
+    /// The commit HEAD points to, or `None` before the first commit
+    pub oid: Option<ObjectHash>,
+    /// The short name of the upstream branch, e.g. `origin/master`
+    pub upstream: Option<String>,
+    /// Commits ahead of and behind the upstream, if it exists
+    pub ahead_behind: Option<(usize, usize)>,
+}
+
+/// Status is the result of comparing HEAD, the index and the working tree
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Status {
+    pub branch: BranchStatus,
+    pub changes: Vec<FileStatus>,
+    pub conflicts: Vec<Conflict>,
+    pub untracked: Vec<String>,
+    pub ignored: Vec<String>,
+    /// A merge or cherry-pick is waiting to be committed
+    pub merging: bool,
+}
+
+/// UntrackedFiles selects how untracked files are reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UntrackedFiles {
+    /// Do not look for untracked files
+    No,
+    /// Show untracked directories without listing their content
+    #[default]
+    Normal,
+    /// Show every untracked file
+    All,
+}
+
+/// StatusOptions controls what `status` looks for
+#[derive(Debug, Clone, Default)]
+pub struct StatusOptions {
+    pub untracked: UntrackedFiles,
+    /// Also report ignored files
+    pub ignored: bool,
+    /// Do not pair deleted and added paths into renames
+    pub no_renames: bool,
+}
+
+/// Compute the status of the repository
+pub fn status(repo: &Repository, options: &StatusOptions) -> Result<Status> {
+    let index = Index::read(repo)?;
+    let head = match head_tree(repo)? {
+        Some(tree) => flatten_tree(repo, &tree)?,
+        None => FileMap::new(),
+    };
+
+    let conflicted = index.conflicted_paths();
+    let conflicts = conflicted
+        .iter()
+        .map(|path| {
+            let stage = |n| {
+                index
+                    .get_stage(path, n)
+                    .and_then(|e| Some((e.entry_mode().ok()?, e.hash.clone())))
+            };
+            Ok(Conflict {
+                path: path.to_string(),
+                stages: [stage(1), stage(2), stage(3)],
+                worktree: worktree_mode(repo, path, None),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let staged = index
+        .entries
+        .iter()
+        .filter(|e| e.stage == 0)
+        .map(|e| Ok((e.path.clone(), (e.entry_mode()?, e.hash.clone()))))
+        .collect::<Result<FileMap>>()?;
+
+    let mut changes = BTreeMap::new();
+    let paths = head.keys().chain(staged.keys()).collect::<BTreeSet<_>>();
+    for path in paths {
+        if conflicted.contains(&path.as_str()) {
+            continue;
+        }
+        let head_entry = head.get(path).cloned();
+        let index_entry = staged.get(path).cloned();
+        let staged_code = compare(head_entry.as_ref(), index_entry.as_ref());
+        let (unstaged_code, worktree, submodule) = match index.get(path) {
+            Some(entry) => worktree_change(repo, entry)?,
+            None => (StatusCode::Unmodified, None, None),
+        };
+        if staged_code == StatusCode::Unmodified && unstaged_code == StatusCode::Unmodified {
+            continue;
+        }
+        changes.insert(
+            path.clone(),
+            FileStatus {
+                path: path.clone(),
+                orig_path: None,
+                staged: staged_code,
+                unstaged: unstaged_code,
+                score: None,
+                head: head_entry,
+                index: index_entry,
+                worktree,
+                submodule,
+            },
+        );
+    }
+    if !options.no_renames {
+        detect_renames(repo, &mut changes)?;
+    }
+
+    let mut untracked = Vec::new();
+    let mut ignored = Vec::new();
+    if options.untracked != UntrackedFiles::No {
+        let rules = IgnoreRules::load(repo)?;
+        let walker = Walker {
+            repo,
+            index: &index,
+            tracked_dirs: tracked_dirs(&index),
+            options,
+            rules: &rules,
+        };
+        walker.walk("", &mut untracked, &mut ignored)?;
+    }
+
+    Ok(Status {
+        branch: branch_status(repo)?,
+        changes: changes.into_values().collect(),
+        conflicts,
+        untracked,
+        ignored: if options.ignored { ignored } else { Vec::new() },
+        merging: ["MERGE_HEAD", "CHERRY_PICK_HEAD"]
+            .iter()
+            .any(|name| repo.gitdir().join(name).exists()),
+    })
+}
+
+/// Classify the difference between two versions of a path
+fn compare(
+    old: Option<&(EntryMode, ObjectHash)>,
+    new: Option<&(EntryMode, ObjectHash)>,
+) -> StatusCode {
+    match (old, new) {
+        (None, None) => StatusCode::Unmodified,
+        (None, Some(_)) => StatusCode::Added,
+        (Some(_), None) => StatusCode::Deleted,
+        (Some(old), Some(new)) if old == new => StatusCode::Unmodified,
+        (Some((old_mode, _)), Some((new_mode, _))) if is_type_change(*old_mode, *new_mode) => {
+            StatusCode::TypeChanged
+        }
+        _ => StatusCode::Modified,
+    }
+}
+
+/// A type change is a switch between a file, a symlink and a submodule
+fn is_type_change(old: EntryMode, new: EntryMode) -> bool {
+    let kind = |mode| match mode {
+        EntryMode::Blob | EntryMode::BlobExecutable => 0,
+        EntryMode::Symlink => 1,
+        EntryMode::Gitlink => 2,
+        EntryMode::Tree => 3,
+    };
+    kind(old) != kind(new)
+}
+
+/// Return the mode of a working tree file, or `None` if it does not exist
+fn worktree_mode(repo: &Repository, path: &str, entry: Option<&IndexEntry>) -> Option<EntryMode> {
+    fs::symlink_metadata(repo.worktree().join(path)).ok()?;
+    hash_file(repo, path, entry).ok().map(|(mode, _)| mode)
+}
+
+/// Compare an index entry with the working tree file, or with the checked
+/// out submodule for a gitlink
+fn worktree_change(
+    repo: &Repository,
+    entry: &IndexEntry,
+) -> Result<(StatusCode, Option<EntryMode>, Option<SubmoduleState>)> {
+    let indexed_mode = entry.entry_mode()?;
+    let metadata = match fs::symlink_metadata(repo.worktree().join(&entry.path)) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok((StatusCode::Deleted, None, None)),
+    };
+    if indexed_mode == EntryMode::Gitlink {
+        let state = submodule_state(repo, &entry.path, &entry.hash)?;
+        let code = match state {
+            Some(state) if state.is_changed() => StatusCode::Modified,
+            _ => StatusCode::Unmodified,
+        };
+        return Ok((code, Some(EntryMode::Gitlink), state));
+    }
+    if metadata.is_dir() {
+        // A directory replaced the file: the file is gone
+        return Ok((StatusCode::Deleted, None, None));
+    }
+    if entry.stat_matches(&metadata) {
+        return Ok((StatusCode::Unmodified, Some(indexed_mode), None));
+    }
+    let (mode, hash) = hash_file(repo, &entry.path, Some(entry))?;
+    let code = compare(
+        Some(&(indexed_mode, entry.hash.clone())),
+        Some(&(mode, hash)),
+    );
+    Ok((code, Some(mode), None))
+}
+
+/// Pair staged deletions and additions of similar content into renames
+fn detect_renames(repo: &Repository, changes: &mut BTreeMap<String, FileStatus>) -> Result<()> {
+    let side = |path: &String, (mode, hash): &(EntryMode, ObjectHash)| DiffEntry {
+        path: path.clone(),
+        mode: *mode,
+        hash: hash.clone(),
+        worktree: false,
+    };
+    let deleted = changes
+        .values()
+        .filter(|c| c.staged == StatusCode::Deleted)
+        .filter_map(|c| Some(side(&c.path, c.head.as_ref()?)))
+        .collect::<Vec<_>>();
+    let added = changes
+        .values()
+        .filter(|c| c.staged == StatusCode::Added)
+        .filter_map(|c| Some(side(&c.path, c.index.as_ref()?)))
+        .collect::<Vec<_>>();
+    if deleted.is_empty() || added.is_empty() {
+        return Ok(());
+    }
+
+    let mut used = vec![false; deleted.len()];
+    let mut paired = vec![false; added.len()];
+    for (score, s, t) in rename_candidates(repo, &deleted, &added, RENAME_THRESHOLD)? {
+        if used[s] || paired[t] {
+            continue;
+        }
+        used[s] = true;
+        paired[t] = true;
+        let Some(old) = changes.remove(&deleted[s].path) else {
+            continue;
+        };
+        if let Some(change) = changes.get_mut(&added[t].path) {
+            change.staged = StatusCode::Renamed;
+            change.orig_path = Some(old.path);
+            change.score = Some(score as u8);
+            change.head = old.head;
+        }
+    }
+    Ok(())
+}
+
+/// Every directory containing a tracked file
+fn tracked_dirs(index: &Index) -> BTreeSet<String> {
+    let mut dirs = BTreeSet::new();
+    for entry in &index.entries {
+        let mut path = entry.path.as_str();
+        while let Some((dir, _)) = path.rsplit_once('/') {
+            if !dirs.insert(dir.to_string()) {
+                break;
+            }
+            path = dir;
+        }
+    }
+    dirs
+}
+
+/// Walker looks for untracked and ignored files in the working tree
+struct Walker<'a> {
+    repo: &'a Repository,
+    index: &'a Index,
+    tracked_dirs: BTreeSet<String>,
+    options: &'a StatusOptions,
+    rules: &'a IgnoreRules,
+}
+
+impl Walker<'_> {
+    fn walk(
+        &self,
+        dir: &str,
+        untracked: &mut Vec<String>,
+        ignored: &mut Vec<String>,
+    ) -> Result<()> {
+        let mut entries = fs::read_dir(self.repo.worktree().join(dir))?
+            .map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort();
+
+        for name in entries {
+            if name == ".git" {
+                continue;
+            }
+            let path = match dir {
+                "" => name,
+                _ => format!("{}/{}", dir, name),
+            };
+            let full_path = self.repo.worktree().join(&path);
+            let is_dir = fs::symlink_metadata(&full_path)
+                .map(|m| m.is_dir())
+                .unwrap_or(false);
+
+            if !is_dir {
+                if self.index.contains(&path) {
+                    continue;
+                }
+                if self.rules.is_ignored(&path, false) {
+                    ignored.push(path);
+                } else {
+                    untracked.push(path);
+                }
+                continue;
+            }
+
+            if self.tracked_dirs.contains(&path) {
+                self.walk(&path, untracked, ignored)?;
+            } else if self.index.contains(&path) {
+                // A submodule checkout
+                continue;
+            } else if self.rules.is_ignored(&path, true) {
+                match self.options.untracked {
+                    UntrackedFiles::All => self.walk_ignored(&path, ignored)?,
+                    _ => ignored.push(format!("{}/", path)),
+                }
+            } else if is_nested_repository(&full_path) {
+                untracked.push(format!("{}/", path));
+            } else {
+                let mut dir_untracked = Vec::new();
+                let mut dir_ignored = Vec::new();
+                self.walk(&path, &mut dir_untracked, &mut dir_ignored)?;
+                let collapse = self.options.untracked == UntrackedFiles::Normal;
+                if collapse && !dir_untracked.is_empty() {
+                    untracked.push(format!("{}/", path));
+                } else {
+                    untracked.extend(dir_untracked);
+                }
+                // A directory holding nothing but ignored files is shown as a whole
+                if collapse
+                    && untracked.last() != Some(&format!("{}/", path))
+                    && !dir_ignored.is_empty()
+                {
+                    ignored.push(format!("{}/", path));
+                } else {
+                    ignored.extend(dir_ignored);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// List every file below an ignored directory, as `--untracked-files=all`
+    /// shows them
+    fn walk_ignored(&self, dir: &str, ignored: &mut Vec<String>) -> Result<()> {
+        let mut entries = fs::read_dir(self.repo.worktree().join(dir))?
+            .map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort();
+
+        for name in entries {
+            let path = format!("{}/{}", dir, name);
+            let full_path = self.repo.worktree().join(&path);
+            let is_dir = fs::symlink_metadata(&full_path).is_ok_and(|m| m.is_dir());
+            if !is_dir {
+                ignored.push(path);
+            } else if is_nested_repository(&full_path) {
+                ignored.push(format!("{}/", path));
+            } else {
+                self.walk_ignored(&path, ignored)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_nested_repository(dir: &Path) -> bool {
+    dir.join(".git").exists()
+}
+
+/// Describe HEAD and how it relates to its upstream
+fn branch_status(repo: &Repository) -> Result<BranchStatus> {
+    let oid = resolve_ref(repo, "HEAD")?;
+    let head = match read_head(repo)? {
+        Head::Branch(name) => Some(short_name(&name).to_string()),
+        Head::Detached(_) => None,
+    };
+    let mut status = BranchStatus {
+        head,
+        oid,
+        ..Default::default()
+    };
+    let Some((upstream_ref, upstream)) = status
+        .head
+        .as_deref()
+        .map(|branch| upstream_of(repo, branch))
+        .transpose()?
+        .flatten()
+    else {
+        return Ok(status);
+    };
+    status.upstream = Some(upstream);
+    if let (Some(local), Some(remote)) = (&status.oid, resolve_ref(repo, &upstream_ref)?) {
+        status.ahead_behind = Some(ahead_behind(repo, local, &remote)?);
+    }
+    Ok(status)
+}
+
+/// Return the full and short name of the upstream of a branch, read from
+/// `branch.<name>.remote` and `branch.<name>.merge`
+pub fn upstream_of(repo: &Repository, branch: &str) -> Result<Option<(String, String)>> {
+    let config = repo.config()?;
+    let remote = config.get(&format!("branch.{}.remote", branch));
+    let merge = config.get(&format!("branch.{}.merge", branch));
+    let (Some(remote), Some(merge)) = (remote, merge) else {
+        return Ok(None);
+    };
+    let merge = short_name(merge);
+    if remote == "." {
+        return Ok(Some((format!("refs/heads/{}", merge), merge.to_string())));
+    }
+    let short = format!("{}/{}", remote, merge);
+    Ok(Some((format!("refs/remotes/{}", short), short)))
+}
+
+/// Quote a path the way git does when it contains special characters
+pub fn quote_path(path: &str) -> String {
+    quote(path, false)
+}
+
+/// Quote a path, also quoting paths with spaces as the short format does
+fn quote(path: &str, quote_space: bool) -> String {
+    let needs_quotes = path.bytes().any(|b| {
+        b == b'"' || b == b'\\' || !(0x20..0x7f).contains(&b) || (quote_space && b == b' ')
+    });
+    if !needs_quotes {
+        return path.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for b in path.bytes() {
+        match b {
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            b'\n' => quoted.push_str("\\n"),
+            b'\t' => quoted.push_str("\\t"),
+            b if !(0x20..0x7f).contains(&b) => {
+                let _ = write!(quoted, "\\{:03o}", b);
+            }
+            b => quoted.push(b as char),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Quote a path unless NUL terminated output was requested
+fn format_path(path: &str, nul: bool) -> String {
+    if nul {
+        path.to_string()
+    } else {
+        quote_path(path)
+    }
+}
+
+/// Quote a path for the short format unless NUL terminated output was requested
+fn format_short_path(path: &str, nul: bool) -> String {
+    if nul {
+        path.to_string()
+    } else {
+        quote(path, true)
+    }
+}
+
+/// A status line sorted by path: tracked changes and conflicts are mixed
+enum Line<'a> {
+    Change(&'a FileStatus),
+    Conflict(&'a Conflict),
+}
+
+fn sorted_lines(status: &Status) -> Vec<Line<'_>> {
+    let mut lines = status
+        .changes
+        .iter()
+        .map(|c| (c.path.as_str(), Line::Change(c)))
+        .chain(
+            status
+                .conflicts
+                .iter()
+                .map(|c| (c.path.as_str(), Line::Conflict(c))),
+        )
+        .collect::<Vec<_>>();
+    lines.sort_by(|a, b| a.0.cmp(b.0));
+    lines.into_iter().map(|(_, line)| line).collect()
+}
+
+/// Format the `## branch...upstream [ahead N, behind M]` header
+fn short_branch_header(branch: &BranchStatus) -> String {
+    let mut header = String::from("## ");
+    match (&branch.head, &branch.oid) {
+        (None, _) => header.push_str("HEAD (no branch)"),
+        (Some(name), None) => {
+            let _ = write!(header, "No commits yet on {}", name);
+        }
+        (Some(name), Some(_)) => header.push_str(name),
+    }
+    if let Some(upstream) = &branch.upstream {
+        let _ = write!(header, "...{}", upstream);
+        match branch.ahead_behind {
+            None => header.push_str(" [gone]"),
+            Some((0, 0)) => {}
+            Some((ahead, 0)) => {
+                let _ = write!(header, " [ahead {}]", ahead);
+            }
+            Some((0, behind)) => {
+                let _ = write!(header, " [behind {}]", behind);
+            }
+            Some((ahead, behind)) => {
+                let _ = write!(header, " [ahead {}, behind {}]", ahead, behind);
+            }
+        }
+    }
+    header
+}
+
+/// Format the status in the `--short` / `--porcelain=v1` format
+///
+/// With `nul`, records are terminated by NUL, paths are not quoted and the
+/// original path of a rename follows the new path as a separate record.
+pub fn format_short(status: &Status, show_branch: bool, nul: bool) -> String {
+    let end = if nul { '\0' } else { '\n' };
+    let mut out = String::new();
+    if show_branch {
+        out.push_str(&short_branch_header(&status.branch));
+        out.push(end);
+    }
+    for line in sorted_lines(status) {
+        match line {
+            Line::Conflict(conflict) => {
+                let _ = write!(
+                    out,
+                    "{} {}",
+                    conflict.code(),
+                    format_short_path(&conflict.path, nul)
+                );
+            }
+            Line::Change(change) => {
+                let _ = write!(
+                    out,
+                    "{}{} ",
+                    change.staged.short_char(),
+                    change.short_unstaged_char()
+                );
+                match (&change.orig_path, nul) {
+                    (Some(orig), true) => {
+                        let _ = write!(out, "{}\0{}", change.path, orig);
+                    }
+                    (Some(orig), false) => {
+                        let _ = write!(
+                            out,
+                            "{} -> {}",
+                            quote(orig, true),
+                            quote(&change.path, true)
+                        );
+                    }
+                    (None, _) => out.push_str(&format_short_path(&change.path, nul)),
+                }
+            }
+        }
+        out.push(end);
+    }
+    for path in &status.untracked {
+        let _ = write!(out, "?? {}{}", format_short_path(path, nul), end);
+    }
+    for path in &status.ignored {
+        let _ = write!(out, "!! {}{}", format_short_path(path, nul), end);
+    }
+    out
+}
+
+fn mode_octal(mode: Option<EntryMode>) -> String {
+    format!("{:06o}", mode.map(|m| m.bits()).unwrap_or_default())
+}
+
+/// Format the hashes of entries, writing missing ones as the null hash of the
+/// algorithm of the others
+fn hashes_hex(entries: &[&Option<(EntryMode, ObjectHash)>]) -> String {
+    let algorithm = entries
+        .iter()
+        .find_map(|entry| entry.as_ref())
+        .map_or(HashAlgorithm::Sha1, |(_, hash)| hash.algorithm());
+    entries
+        .iter()
+        .map(|entry| match entry {
+            Some((_, hash)) => hash.to_hex(),
+            None => algorithm.null_hash().to_hex(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The `S<c><m><u>` field of a submodule, or `N...` for other paths
+fn submodule_flags(change: &FileStatus) -> String {
+    let is_gitlink = [&change.head, &change.index]
+        .iter()
+        .any(|entry| matches!(entry, Some((EntryMode::Gitlink, _))));
+    if !is_gitlink {
+        return "N...".to_string();
+    }
+    let state = change.submodule.unwrap_or_default();
+    let flag = |set: bool, c: char| if set { c } else { '.' };
+    format!(
+        "S{}{}{}",
+        flag(state.new_commits, 'C'),
+        flag(state.modified, 'M'),
+        flag(state.untracked, 'U')
+    )
+}
+
+/// Format the status in the `--porcelain=v2` format
+pub fn format_porcelain_v2(status: &Status, show_branch: bool, nul: bool) -> String {
+    let end = if nul { '\0' } else { '\n' };
+    let mut out = String::new();
+    if show_branch {
+        let branch = &status.branch;
+        let oid = branch
+            .oid
+            .as_ref()
+            .map(|h| h.to_hex())
+            .unwrap_or_else(|| "(initial)".to_string());
+        let _ = write!(out, "# branch.oid {}{}", oid, end);
+        let head = branch.head.as_deref().unwrap_or("(detached)");
+        let _ = write!(out, "# branch.head {}{}", head, end);
+        if let Some(upstream) = &branch.upstream {
+            let _ = write!(out, "# branch.upstream {}{}", upstream, end);
+        }
+        if let Some((ahead, behind)) = branch.ahead_behind {
+            let _ = write!(out, "# branch.ab +{} -{}{}", ahead, behind, end);
+        }
+    }
+
+    for line in sorted_lines(status) {
+        match line {
+            Line::Change(change) => {
+                let xy = format!("{}{}", change.staged.as_char(), change.unstaged.as_char());
+                let sub = submodule_flags(change);
+                let modes = format!(
+                    "{} {} {}",
+                    mode_octal(change.head.as_ref().map(|(m, _)| *m)),
+                    mode_octal(change.index.as_ref().map(|(m, _)| *m)),
+                    mode_octal(change.worktree)
+                );
+                let hashes = hashes_hex(&[&change.head, &change.index]);
+                match &change.orig_path {
+                    Some(orig) => {
+                        let separator = if nul { '\0' } else { '\t' };
+                        let _ = write!(
+                            out,
+                            "2 {} {} {} {} {}{} {}{}{}",
+                            xy,
+                            sub,
+                            modes,
+                            hashes,
+                            change.staged.as_char(),
+                            change.score.unwrap_or(100),
+                            format_path(&change.path, nul),
+                            separator,
+                            format_path(orig, nul)
+                        );
+                    }
+                    None => {
+                        let _ = write!(
+                            out,
+                            "1 {} {} {} {} {}",
+                            xy,
+                            sub,
+                            modes,
+                            hashes,
+                            format_path(&change.path, nul)
+                        );
+                    }
+                }
+            }
+            Line::Conflict(conflict) => {
+                let [base, ours, theirs] = &conflict.stages;
+                let _ = write!(
+                    out,
+                    "u {} N... {} {} {} {} {} {}",
+                    conflict.code(),
+                    mode_octal(base.as_ref().map(|(m, _)| *m)),
+                    mode_octal(ours.as_ref().map(|(m, _)| *m)),
+                    mode_octal(theirs.as_ref().map(|(m, _)| *m)),
+                    mode_octal(conflict.worktree),
+                    hashes_hex(&[base, ours, theirs]),
+                    format_path(&conflict.path, nul)
+                );
+            }
+        }
+        out.push(end);
+    }
+    for path in &status.untracked {
+        let _ = write!(out, "? {}{}", format_path(path, nul), end);
+    }
+    for path in &status.ignored {
+        let _ = write!(out, "! {}{}", format_path(path, nul), end);
+    }
+    out
+}
+
+/// Return true if the long format shows hints, as `advice.statusHints` asks
+pub fn status_hints(repo: &Repository) -> Result<bool> {
+    Ok(repo
+        .config()?
+        .get_bool("advice.statusHints")?
+        .unwrap_or(true))
+}
+
+/// Format the status for humans, like `git status` without options
+///
+/// With `hints`, each section says which commands act on its paths.
+pub fn format_long(status: &Status, hints: bool) -> String {
+    let mut out = String::new();
+    let branch = &status.branch;
+    let initial = branch.oid.is_none();
+    match &branch.head {
+        Some(name) => {
+            let _ = writeln!(out, "On branch {}", name);
+        }
+        None => {
+            let oid = branch.oid.as_ref().map(|h| h.to_hex()).unwrap_or_default();
+            let _ = writeln!(out, "HEAD detached at {}", &oid[..oid.len().min(7)]);
+        }
+    }
+    if let Some(upstream) = branch.upstream.as_ref().filter(|_| !initial) {
+        let plural = |n: usize| if n == 1 { "commit" } else { "commits" };
+        let (tracking, hint) = match branch.ahead_behind {
+            None => (
+                format!("Your branch is based on '{}', but the upstream is gone.", upstream),
+                Some("git branch --unset-upstream\" to fixup"),
+            ),
+            Some((0, 0)) => (
+                format!("Your branch is up to date with '{}'.", upstream),
+                None,
+            ),
+            Some((ahead, 0)) => (
+                format!(
+                    "Your branch is ahead of '{}' by {} {}.",
+                    upstream,
+                    ahead,
+                    plural(ahead)
+                ),
+                Some("git push\" to publish your local commits"),
+            ),
+            Some((0, behind)) => (
+                format!(
+                    "Your branch is behind '{}' by {} {}, and can be fast-forwarded.",
+                    upstream,
+                    behind,
+                    plural(behind)
+                ),
+                Some("git pull\" to update your local branch"),
+            ),
+            Some((ahead, behind)) => (
+                format!(
+                    "Your branch and '{}' have diverged,\nand have {} and {} different commits each, respectively.",
+                    upstream, ahead, behind
+                ),
+                Some("git pull\" to merge the remote branch into yours"),
+            ),
+        };
+        let _ = writeln!(out, "{}", tracking);
+        if let Some(hint) = hint.filter(|_| hints) {
+            let _ = writeln!(out, "  (use \"{})", hint);
+        }
+        out.push('\n');
+    }
+    if initial {
+        out.push_str("\nNo commits yet\n\n");
+    }
+    // Unstaging would lose the resolution of a merge, so it is not offered
+    let unstage = match (status.merging, initial) {
+        (true, _) => "",
+        (false, true) => "  (use \"git rm --cached <file>...\" to unstage)\n",
+        (false, false) => "  (use \"git restore --staged <file>...\" to unstage)\n",
+    };
+
+    let staged = status
+        .changes
+        .iter()
+        .filter(|c| c.staged != StatusCode::Unmodified)
+        .collect::<Vec<_>>();
+    if !staged.is_empty() {
+        out.push_str("Changes to be committed:\n");
+        if hints {
+            out.push_str(unstage);
+        }
+        for change in &staged {
+            let path = match &change.orig_path {
+                Some(orig) => format!("{} -> {}", quote_path(orig), quote_path(&change.path)),
+                None => quote_path(&change.path),
+            };
+            let _ = writeln!(out, "\t{:<12}{}", change.staged.label(), path);
+        }
+        out.push('\n');
+    }
+
+    if !status.conflicts.is_empty() {
+        out.push_str("Unmerged paths:\n");
+        if hints {
+            let codes = status.conflicts.iter().map(Conflict::code);
+            let both_deleted = codes.clone().any(|code| code == "DD");
+            let deleted_by_one = codes.clone().any(|code| code == "DU" || code == "UD");
+            let not_deleted = codes.clone().any(|code| !code.contains('D'));
+            out.push_str(unstage);
+            out.push_str(match (both_deleted, deleted_by_one, not_deleted) {
+                (false, false, _) => "  (use \"git add <file>...\" to mark resolution)\n",
+                (true, false, false) => "  (use \"git rm <file>...\" to mark resolution)\n",
+                _ => "  (use \"git add/rm <file>...\" as appropriate to mark resolution)\n",
+            });
+        }
+        for conflict in &status.conflicts {
+            let _ = writeln!(
+                out,
+                "\t{:<17}{}",
+                conflict.label(),
+                quote_path(&conflict.path)
+            );
+        }
+        out.push('\n');
+    }
+
+    let unstaged = status
+        .changes
+        .iter()
+        .filter(|c| c.unstaged != StatusCode::Unmodified)
+        .collect::<Vec<_>>();
+    if !unstaged.is_empty() {
+        out.push_str("Changes not staged for commit:\n");
+        if hints {
+            match unstaged.iter().any(|c| c.unstaged == StatusCode::Deleted) {
+                true => out.push_str(
+                    "  (use \"git add/rm <file>...\" to update what will be committed)\n",
+                ),
+                false => {
+                    out.push_str("  (use \"git add <file>...\" to update what will be committed)\n")
+                }
+            }
+            out.push_str(
+                "  (use \"git restore <file>...\" to discard changes in working directory)\n",
+            );
+            if unstaged
+                .iter()
+                .any(|c| c.submodule.is_some_and(|s| s.modified || s.untracked))
+            {
+                out.push_str(
+                    "  (commit or discard the untracked or modified content in submodules)\n",
+                );
+            }
+        }
+        for change in &unstaged {
+            let _ = write!(
+                out,
+                "\t{:<12}{}",
+                change.unstaged.label(),
+                quote_path(&change.path)
+            );
+            match change.submodule {
+                Some(state) if state.is_changed() => {
+                    let _ = writeln!(out, " ({})", state.describe());
+                }
+                _ => out.push('\n'),
+            }
+        }
+        out.push('\n');
+    }
+
+    for (title, paths, command) in [
+        ("Untracked files", &status.untracked, "add"),
+        ("Ignored files", &status.ignored, "add -f"),
+    ] {
+        if !paths.is_empty() {
+            let _ = writeln!(out, "{}:", title);
+            if hints {
+                let _ = writeln!(
+                    out,
+                    "  (use \"git {} <file>...\" to include in what will be committed)",
+                    command
+                );
+            }
+            for path in paths {
+                let _ = writeln!(out, "\t{}", quote_path(path));
+            }
+            out.push('\n');
+        }
+    }
+
+    if staged.is_empty() {
+        let (message, hint) = if !unstaged.is_empty() || !status.conflicts.is_empty() {
+            (
+                "no changes added to commit",
+                " (use \"git add\" and/or \"git commit -a\")",
+            )
+        } else if !status.untracked.is_empty() {
+            (
+                "nothing added to commit but untracked files present",
+                " (use \"git add\" to track)",
+            )
+        } else if initial {
+            (
+                "nothing to commit",
+                " (create/copy files and use \"git add\" to track)",
+            )
+        } else {
+            ("nothing to commit, working tree clean", "")
+        };
+        let _ = writeln!(out, "{}{}", message, if hints { hint } else { "" });
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkout::checkout_tree;
+    use crate::commits::Commit;
+    use crate::index::IndexEntry;
+    use crate::refs::update_ref;
+    use crate::test_utils::{commit_files, init_repo, write_blob, write_commit};
+    use tempfile::TempDir;
+
+    fn setup() -> (TempDir, Repository) {
+        let (dir, repo) = init_repo();
+        let commit = commit_files(
+            &repo,
+            &[("a.txt", "a"), ("b.txt", "b"), ("old.txt", "rename me")],
+            "first",
+        );
+        let tree = Commit::read(&repo, &commit).unwrap().tree;
+        checkout_tree(&repo, &tree, true).unwrap();
+        (dir, repo)
+    }
+
+    fn stage(repo: &Repository, path: &str, content: &str) {
+        let hash = write_blob(repo, content.as_bytes());
+        let mut index = Index::read(repo).unwrap();
+        index
+            .add(IndexEntry::new(path, EntryMode::Blob, hash, None))
+            .unwrap();
+        index.write(repo).unwrap();
+    }
+
+    fn write(repo: &Repository, path: &str, content: &str) {
+        let path = repo.worktree().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn test_clean_status() {
+        let (_dir, repo) = setup();
+        let status = status(&repo, &StatusOptions::default()).unwrap();
+        assert!(status.changes.is_empty());
+        assert!(status.untracked.is_empty());
+        assert_eq!(status.branch.head.as_deref(), Some("master"));
+        assert_eq!(
+            format_long(&status, true),
+            "On branch master\nnothing to commit, working tree clean\n"
+        );
+    }
+
+    #[test]
+    fn test_staged_unstaged_and_untracked() {
+        let (_dir, repo) = setup();
+        stage(&repo, "a.txt", "staged");
+        write(&repo, "a.txt", "unstaged");
+        fs::remove_file(repo.worktree().join("b.txt")).unwrap();
+        stage(&repo, "new.txt", "new");
+        write(&repo, "new.txt", "new");
+        write(&repo, "untracked/dir/file", "x");
+        write(&repo, "z.txt", "x");
+
+        let status = status(&repo, &StatusOptions::default()).unwrap();
+        let short = format_short(&status, false, false);
+        assert_eq!(
+            short,
+            "MM a.txt\n D b.txt\nA  new.txt\n?? untracked/\n?? z.txt\n"
+        );
+
+        let options = StatusOptions {
+            untracked: UntrackedFiles::All,
+            ..Default::default()
+        };
+        let all = status_untracked(&repo, &options);
+        assert_eq!(all, vec!["untracked/dir/file", "z.txt"]);
+    }
+
+    #[test]
+    fn test_long_format() {
+        let (_dir, repo) = setup();
+        stage(&repo, "a.txt", "staged");
+        write(&repo, "a.txt", "unstaged");
+        fs::remove_file(repo.worktree().join("b.txt")).unwrap();
+        stage(&repo, "new.txt", "new");
+        write(&repo, "new.txt", "new");
+        write(&repo, "untracked/dir/file", "x");
+        write(&repo, "z.txt", "x");
+
+        // The output of git 2.39 for the same changes
+        let status = status(&repo, &StatusOptions::default()).unwrap();
+        assert!(status_hints(&repo).unwrap());
+        assert_eq!(
+            format_long(&status, true),
+            "On branch master\n\
+             Changes to be committed:\n\
+             \x20 (use \"git restore --staged <file>...\" to unstage)\n\
+             \tmodified:   a.txt\n\
+             \tnew file:   new.txt\n\n\
+             Changes not staged for commit:\n\
+             \x20 (use \"git add/rm <file>...\" to update what will be committed)\n\
+             \x20 (use \"git restore <file>...\" to discard changes in working directory)\n\
+             \tmodified:   a.txt\n\
+             \tdeleted:    b.txt\n\n\
+             Untracked files:\n\
+             \x20 (use \"git add <file>...\" to include in what will be committed)\n\
+             \tuntracked/\n\
+             \tz.txt\n\n"
+        );
+
+        let mut config = repo.config().unwrap();
+        config.set("advice.statusHints", "false").unwrap();
+        repo.write_config(&config).unwrap();
+        assert!(!status_hints(&repo).unwrap());
+        assert_eq!(
+            format_long(&status, false),
+            "On branch master\n\
+             Changes to be committed:\n\
+             \tmodified:   a.txt\n\
+             \tnew file:   new.txt\n\n\
+             Changes not staged for commit:\n\
+             \tmodified:   a.txt\n\
+             \tdeleted:    b.txt\n\n\
+             Untracked files:\n\
+             \tuntracked/\n\
+             \tz.txt\n\n"
+        );
+    }
+
+    #[test]
+    fn test_long_format_before_first_commit() {
+        let (_dir, repo) = init_repo();
+        write(&repo, "f", "x");
+        let status = status(&repo, &StatusOptions::default()).unwrap();
+        assert_eq!(
+            format_long(&status, true),
+            "On branch master\n\nNo commits yet\n\n\
+             Untracked files:\n\
+             \x20 (use \"git add <file>...\" to include in what will be committed)\n\
+             \tf\n\n\
+             nothing added to commit but untracked files present (use \"git add\" to track)\n"
+        );
+
+        stage(&repo, "f", "x");
+        let status = super::status(&repo, &StatusOptions::default()).unwrap();
+        assert_eq!(
+            format_long(&status, true),
+            "On branch master\n\nNo commits yet\n\n\
+             Changes to be committed:\n\
+             \x20 (use \"git rm --cached <file>...\" to unstage)\n\
+             \tnew file:   f\n\n"
+        );
+    }
+
+    #[test]
+    fn test_ignored_files() {
+        let (_dir, repo) = setup();
+        write(&repo, ".gitignore", "*.log\nbuild/\n");
+        write(&repo, "debug.log", "x");
+        write(&repo, "build/out.bin", "x");
+        write(&repo, "notes.txt", "x");
+
+        let result = status(&repo, &StatusOptions::default()).unwrap();
+        assert_eq!(result.untracked, vec![".gitignore", "notes.txt"]);
+        assert!(result.ignored.is_empty());
+
+        let options = StatusOptions {
+            ignored: true,
+            ..Default::default()
+        };
+        let result = status(&repo, &options).unwrap();
+        assert_eq!(result.ignored, vec!["build/", "debug.log"]);
+
+        write(&repo, "build/sub/deep.bin", "x");
+        let options = StatusOptions {
+            untracked: UntrackedFiles::All,
+            ignored: true,
+            ..Default::default()
+        };
+        let result = status(&repo, &options).unwrap();
+        assert_eq!(
+            result.ignored,
+            vec!["build/out.bin", "build/sub/deep.bin", "debug.log"]
+        );
+    }
+
+    fn status_untracked(repo: &Repository, options: &StatusOptions) -> Vec<String> {
+        status(repo, options).unwrap().untracked
+    }
+
+    #[test]
+    fn test_rename_detection() {
+        let (_dir, repo) = setup();
+        let mut index = Index::read(&repo).unwrap();
+        let entry = index.get("old.txt").unwrap().clone();
+        index.remove("old.txt");
+        index
+            .add(IndexEntry {
+                path: "new.txt".to_string(),
+                ..entry
+            })
+            .unwrap();
+        index.write(&repo).unwrap();
+        fs::rename(
+            repo.worktree().join("old.txt"),
+            repo.worktree().join("new.txt"),
+        )
+        .unwrap();
+
+        let status = status(&repo, &StatusOptions::default()).unwrap();
+        assert_eq!(
+            format_short(&status, false, false),
+            "R  old.txt -> new.txt\n"
+        );
+        assert_eq!(format_short(&status, false, true), "R  new.txt\0old.txt\0");
+        let v2 = format_porcelain_v2(&status, false, false);
+        assert!(v2.starts_with("2 R. N... 100644 100644 100644 "));
+        assert!(v2.ends_with(" R100 new.txt\told.txt\n"));
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let (_dir, repo) = setup();
+        let mut index = Index::read(&repo).unwrap();
+        let entry = index.get("a.txt").unwrap().clone();
+        index.remove("a.txt");
+        for stage in 1..=3 {
+            index
+                .add(IndexEntry {
+                    stage,
+                    ..entry.clone()
+                })
+                .unwrap();
+        }
+        index.write(&repo).unwrap();
+
+        let status = status(&repo, &StatusOptions::default()).unwrap();
+        assert_eq!(status.conflicts.len(), 1);
+        assert_eq!(format_short(&status, false, false), "UU a.txt\n");
+        assert!(format_porcelain_v2(&status, false, false).starts_with("u UU N... 100644"));
+        assert_eq!(
+            format_long(&status, true),
+            "On branch master\n\
+             Unmerged paths:\n\
+             \x20 (use \"git restore --staged <file>...\" to unstage)\n\
+             \x20 (use \"git add <file>...\" to mark resolution)\n\
+             \tboth modified:   a.txt\n\n\
+             no changes added to commit (use \"git add\" and/or \"git commit -a\")\n"
+        );
+
+        // Unstaging would drop the resolution of a merge
+        let head = resolve_ref(&repo, "HEAD").unwrap().unwrap();
+        fs::write(repo.gitdir().join("MERGE_HEAD"), format!("{}\n", head)).unwrap();
+        let status = super::status(&repo, &StatusOptions::default()).unwrap();
+        assert!(format_long(&status, true)
+            .contains("Unmerged paths:\n  (use \"git add <file>...\" to mark resolution)\n\tboth"));
+    }
+
+    #[test]
+    fn test_branch_headers() {
+        let (_dir, repo) = setup();
+        let head = resolve_ref(&repo, "HEAD").unwrap().unwrap();
+        let upstream = write_commit(
+            &repo,
+            &[("c.txt", "c")],
+            std::slice::from_ref(&head),
+            "upstream",
+        );
+        update_ref(&repo, "refs/remotes/origin/master", &upstream).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set("branch.master.remote", "origin").unwrap();
+        config
+            .set("branch.master.merge", "refs/heads/master")
+            .unwrap();
+        repo.write_config(&config).unwrap();
+
+        let status = status(&repo, &StatusOptions::default()).unwrap();
+        assert_eq!(status.branch.ahead_behind, Some((0, 1)));
+        assert_eq!(
+            format_short(&status, true, false),
+            "## master...origin/master [behind 1]\n"
+        );
+        let v2 = format_porcelain_v2(&status, true, false);
+        assert_eq!(
+            v2,
+            format!(
+                "# branch.oid {}\n# branch.head master\n# branch.upstream origin/master\n# branch.ab +0 -1\n",
+                head
+            )
+        );
+        assert_eq!(
+            format_long(&status, true),
+            "On branch master\n\
+             Your branch is behind 'origin/master' by 1 commit, and can be fast-forwarded.\n\
+             \x20 (use \"git pull\" to update your local branch)\n\n\
+             nothing to commit, working tree clean\n"
+        );
+    }
+
+    #[test]
+    fn test_quote_path() {
+        assert_eq!(quote_path("plain.txt"), "plain.txt");
+        assert_eq!(quote_path("with space"), "with space");
+        assert_eq!(format_short_path("with space", false), "\"with space\"");
+        assert_eq!(quote_path("tab\there"), "\"tab\\there\"");
+        assert_eq!(quote_path("caf\u{e9}"), "\"caf\\303\\251\"");
+    }
+}
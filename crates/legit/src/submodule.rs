@@ -0,0 +1,787 @@
+use crate::checkout::{checkout, checkout_tree, CheckoutOptions};
+use crate::commits::Commit;
+use crate::fetch::{clone, fetch, CloneOptions, FetchOptions};
+use crate::gitconfig::GitConfig;
+use crate::index::Index;
+use crate::objects::{object_exists, read_object, ObjectHash};
+use crate::refs::{list_refs, read_head, resolve_ref, set_head, Head};
+use crate::status::{status, StatusOptions};
+use crate::tree::EntryMode;
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+const GITMODULES: &str = ".gitmodules";
+
+/// Submodule is a repository checked out inside the working tree, as
+/// described by `.gitmodules`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Submodule {
+    /// The name identifying the submodule in the config and under `modules/`
+    pub name: String,
+    /// The path of the submodule in the working tree
+    pub path: String,
+    /// The URL to clone from, which may be relative to the superproject's
+    /// remote
+    pub url: Option<String>,
+}
+
+/// Gitmodules is what `.gitmodules` describes
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Gitmodules {
+    pub submodules: Vec<Submodule>,
+    /// Names refused by `check_submodule_name`, for callers to warn about
+    pub skipped: Vec<String>,
+}
+
+/// SubmoduleState is how a checked out submodule differs from the commit
+/// the superproject records for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SubmoduleState {
+    /// HEAD of the submodule is another commit
+    pub new_commits: bool,
+    /// The submodule has changes to tracked files
+    pub modified: bool,
+    /// The submodule has untracked files
+    pub untracked: bool,
+}
+
+impl SubmoduleState {
+    /// Return true if the submodule differs in any way
+    pub fn is_changed(&self) -> bool {
+        self.new_commits || self.modified || self.untracked
+    }
+
+    /// Describe the changes like `git status` does, e.g. `new commits,
+    /// modified content`
+    pub fn describe(&self) -> String {
+        [
+            (self.new_commits, "new commits"),
+            (self.modified, "modified content"),
+            (self.untracked, "untracked content"),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, text)| *text)
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+}
+
+/// SubmoduleStatus is a line of `submodule status`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubmoduleStatus {
+    /// The path relative to the top superproject
+    pub path: String,
+    /// `-` when not initialized or checked out, `+` when HEAD is not the
+    /// recorded commit, `U` when conflicted and a space otherwise
+    pub prefix: char,
+    /// The commit checked out, or the recorded one if none is
+    pub hash: ObjectHash,
+    /// A reference naming the commit, like `heads/master`
+    pub name: Option<String>,
+}
+
+/// Read the submodules described by `.gitmodules` in the working tree, or
+/// in the index when the file is not checked out
+///
+/// Submodules with unsafe names are left out and listed in `skipped`.
+pub fn read_gitmodules(repo: &Repository) -> Result<Gitmodules> {
+    let path = repo.worktree().join(GITMODULES);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(_) => match Index::read(repo)?.get(GITMODULES) {
+            Some(entry) => String::from_utf8(read_object(repo, &entry.hash)?.data)?,
+            None => return Ok(Gitmodules::default()),
+        },
+    };
+    let config = GitConfig::parse(&text).context("Invalid .gitmodules")?;
+    let (names, skipped) = config
+        .subsections("submodule")
+        .into_iter()
+        .partition::<Vec<_>, _>(|name| check_submodule_name(name).is_ok());
+    let submodules = names
+        .into_iter()
+        .filter_map(|name| {
+            let key = |key: &str| config.get(&format!("submodule.{}.{}", name, key));
+            Some(Submodule {
+                path: key("path")?.trim_end_matches('/').to_string(),
+                url: key("url").map(str::to_string),
+                name,
+            })
+        })
+        .collect();
+    Ok(Gitmodules {
+        submodules,
+        skipped,
+    })
+}
+
+/// List the submodules the index records a commit for, with that commit;
+/// a conflicted submodule has no commit
+fn recorded_submodules(repo: &Repository) -> Result<Vec<(Submodule, Option<ObjectHash>)>> {
+    let index = Index::read(repo)?;
+    let modules = read_gitmodules(repo)?.submodules;
+    let mut submodules = Vec::new();
+    for entry in &index.entries {
+        if entry.entry_mode()? != EntryMode::Gitlink
+            || submodules
+                .iter()
+                .any(|(s, _): &(Submodule, _)| s.path == entry.path)
+        {
+            continue;
+        }
+        let Some(submodule) = modules.iter().find(|s| s.path == entry.path) else {
+            bail!(
+                "No url found for submodule path '{}' in .gitmodules",
+                entry.path
+            );
+        };
+        let hash = (entry.stage == 0).then(|| entry.hash.clone());
+        submodules.push((submodule.clone(), hash));
+    }
+    Ok(submodules)
+}
+
+/// Keep the submodules matching any of `paths`, or all of them
+fn select<T>(submodules: Vec<(Submodule, T)>, paths: &[String]) -> Vec<(Submodule, T)> {
+    submodules
+        .into_iter()
+        .filter(|(submodule, _)| {
+            paths.is_empty()
+                || paths.iter().any(|path| {
+                    let path = path.trim_end_matches('/');
+                    submodule.path == path || submodule.path.starts_with(&format!("{}/", path))
+                })
+        })
+        .collect()
+}
+
+/// Check that a submodule name cannot leave `modules/` once used as a
+/// path, like git's `check_submodule_name`
+///
+/// A name such as `../../hooks` from a hostile `.gitmodules` would
+/// otherwise place the clone's git directory anywhere (CVE-2018-11235).
+pub fn check_submodule_name(name: &str) -> Result<()> {
+    // An absolute name would replace `modules/` when joined to it
+    let absolute = name.starts_with(['/', '\\']);
+    if name.is_empty() || absolute || name.split(['/', '\\']).any(|component| component == "..") {
+        bail!("Invalid submodule name '{}'", name);
+    }
+    Ok(())
+}
+
+/// Return the git directory of a submodule inside the superproject's
+pub fn module_gitdir(repo: &Repository, name: &str) -> Result<PathBuf> {
+    check_submodule_name(name)?;
+    Ok(repo.commondir().join("modules").join(name))
+}
+
+/// Open the repository of a submodule if it is checked out
+pub fn open_submodule(repo: &Repository, path: &str) -> Option<Repository> {
+    let worktree = repo.worktree().join(path);
+    if !worktree.join(".git").exists() {
+        return None;
+    }
+    Repository::open(&worktree).ok()
+}
+
+/// Return the commit HEAD of a checked out submodule points to
+pub fn submodule_head(repo: &Repository, path: &str) -> Result<Option<ObjectHash>> {
+    match open_submodule(repo, path) {
+        Some(submodule) => resolve_ref(&submodule, "HEAD"),
+        None => Ok(None),
+    }
+}
+
+/// Compare a checked out submodule with the commit recorded for it;
+/// `None` if it is not checked out
+pub fn submodule_state(
+    repo: &Repository,
+    path: &str,
+    recorded: &ObjectHash,
+) -> Result<Option<SubmoduleState>> {
+    let Some(submodule) = open_submodule(repo, path) else {
+        return Ok(None);
+    };
+    let status = status(&submodule, &StatusOptions::default())?;
+    Ok(Some(SubmoduleState {
+        new_commits: resolve_ref(&submodule, "HEAD")?.as_ref() != Some(recorded),
+        modified: !status.changes.is_empty() || !status.conflicts.is_empty(),
+        untracked: !status.untracked.is_empty(),
+    }))
+}
+
+/// Return true if `submodule init` registered the submodule
+fn is_active(config: &GitConfig, name: &str) -> Result<bool> {
+    match config.get_bool(&format!("submodule.{}.active", name))? {
+        Some(active) => Ok(active),
+        None => Ok(config.get(&format!("submodule.{}.url", name)).is_some()),
+    }
+}
+
+/// Resolve a submodule URL starting with `./` or `../` against the URL of
+/// the superproject's remote, or its working tree when it has none
+pub fn resolve_url(repo: &Repository, url: &str) -> Result<String> {
+    if !url.starts_with("./") && !url.starts_with("../") {
+        return Ok(url.to_string());
+    }
+    let config = repo.config()?;
+    let remote = read_head(repo)?
+        .branch()
+        .and_then(|branch| config.get(&format!("branch.{}.remote", branch)))
+        .unwrap_or("origin")
+        .to_string();
+    let mut base = match config.get(&format!("remote.{}.url", remote)) {
+        Some(url) => url.trim_end_matches('/').to_string(),
+        None => repo.worktree().to_string_lossy().into_owned(),
+    };
+    let mut rest = url;
+    loop {
+        if let Some(stripped) = rest.strip_prefix("./") {
+            rest = stripped;
+        } else if let Some(stripped) = rest.strip_prefix("../") {
+            rest = stripped;
+            match base.rfind(['/', ':']) {
+                Some(i) => base.truncate(i),
+                None => bail!("cannot strip one component off url '{}'", base),
+            }
+        } else {
+            break;
+        }
+    }
+    Ok(format!("{}/{}", base, rest))
+}
+
+/// Register the submodules in the config, copying their resolved URL from
+/// `.gitmodules`, like `git submodule init`
+///
+/// Returns the submodules registered by this call with their URL.
+pub fn init(repo: &Repository, paths: &[String]) -> Result<Vec<(Submodule, String)>> {
+    let mut config = repo.config()?;
+    let mut registered = Vec::new();
+    for (submodule, _) in select(recorded_submodules(repo)?, paths) {
+        let key = format!("submodule.{}.url", submodule.name);
+        if config.get(&key).is_some() {
+            continue;
+        }
+        let Some(url) = &submodule.url else {
+            bail!(
+                "No url found for submodule path '{}' in .gitmodules",
+                submodule.path
+            );
+        };
+        let url = resolve_url(repo, url)?;
+        config.set(&format!("submodule.{}.active", submodule.name), "true")?;
+        config.set(&key, &url)?;
+        registered.push((submodule, url));
+    }
+    repo.write_config(&config)?;
+    Ok(registered)
+}
+
+/// UpdateOptions controls `submodule update`
+#[derive(Debug, Clone, Default)]
+pub struct UpdateOptions {
+    /// Initialize the submodules that are not yet
+    pub init: bool,
+    /// Also update the submodules of the submodules
+    pub recursive: bool,
+}
+
+/// Clone the missing submodules and check out the commit the superproject
+/// records in each, like `git submodule update`
+///
+/// Clones keep their git directory under `modules/` of the superproject's.
+/// Returns the paths, relative to `repo`, of the submodules that moved
+/// with the commit they are now at.
+pub fn update(
+    repo: &Repository,
+    paths: &[String],
+    options: &UpdateOptions,
+) -> Result<Vec<(String, ObjectHash)>> {
+    if options.init {
+        init(repo, paths)?;
+    }
+    let config = repo.config()?;
+    let mut updated = Vec::new();
+    for (submodule, hash) in select(recorded_submodules(repo)?, paths) {
+        let Some(hash) = hash else {
+            bail!("Skipping unmerged submodule {}", submodule.path);
+        };
+        check_submodule_name(&submodule.name)?;
+        if !is_active(&config, &submodule.name)? {
+            continue;
+        }
+        let (sub, fresh) = match open_submodule(repo, &submodule.path) {
+            Some(sub) => (sub, false),
+            None => (clone_submodule(repo, &submodule, &config)?, true),
+        };
+        if fresh || resolve_ref(&sub, "HEAD")?.as_ref() != Some(&hash) {
+            if !object_exists(&sub, &hash) {
+                fetch(&sub, "origin", &FetchOptions::default()).with_context(|| {
+                    format!("Failed to fetch submodule path '{}'", submodule.path)
+                })?;
+            }
+            if !object_exists(&sub, &hash) {
+                bail!(
+                    "Fetched in submodule path '{}', but it did not contain {}",
+                    submodule.path,
+                    hash
+                );
+            }
+            if fresh {
+                // Nothing is checked out yet, so the recorded commit is
+                // written out as is
+                checkout_tree(&sub, &Commit::read(&sub, &hash)?.tree, true)?;
+                set_head(&sub, &Head::Detached(hash.clone()))?;
+            } else {
+                let options = CheckoutOptions {
+                    detach: true,
+                    ..CheckoutOptions::default()
+                };
+                checkout(&sub, &hash.to_hex(), &options).with_context(|| {
+                    format!(
+                        "Unable to checkout '{}' in submodule path '{}'",
+                        hash, submodule.path
+                    )
+                })?;
+            }
+            updated.push((submodule.path.clone(), hash));
+        }
+        if options.recursive {
+            let nested = update(&sub, &[], options)?;
+            updated.extend(
+                nested
+                    .into_iter()
+                    .map(|(path, hash)| (format!("{}/{}", submodule.path, path), hash)),
+            );
+        }
+    }
+    Ok(updated)
+}
+
+/// Clone a submodule without checking it out, and move its git directory
+/// under `modules/`
+fn clone_submodule(
+    repo: &Repository,
+    submodule: &Submodule,
+    config: &GitConfig,
+) -> Result<Repository> {
+    let path = repo.worktree().join(&submodule.path);
+    let gitdir = module_gitdir(repo, &submodule.name)?;
+    // The git directory of a submodule that was removed from the working
+    // tree is reused
+    if gitdir.is_dir() {
+        fs::create_dir_all(&path)?;
+        link_gitdir(&path, &gitdir)?;
+        let sub = Repository::open(&path)?;
+        // Nothing is checked out yet
+        let _ = fs::remove_file(sub.gitdir().join("index"));
+        return Ok(sub);
+    }
+    let url = config
+        .get(&format!("submodule.{}.url", submodule.name))
+        .context("Submodule is not initialized")?;
+    let options = CloneOptions {
+        no_checkout: true,
+        ..CloneOptions::default()
+    };
+    clone(url, &path, &options).with_context(|| {
+        format!(
+            "Clone of '{}' into submodule path '{}' failed",
+            url, submodule.path
+        )
+    })?;
+    absorb(repo, submodule)?;
+    Repository::open(&path)
+}
+
+/// Return `to` relative to the directory `from`
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let canonical = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+    let (from, to) = (canonical(from), canonical(to));
+    let from = from.components().collect::<Vec<_>>();
+    let to = to.components().collect::<Vec<_>>();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut path = PathBuf::new();
+    for _ in common..from.len() {
+        path.push(Component::ParentDir);
+    }
+    for component in &to[common..] {
+        path.push(component);
+    }
+    path
+}
+
+/// Point the working tree of a submodule at its git directory, both ways
+fn link_gitdir(worktree: &Path, gitdir: &Path) -> Result<()> {
+    fs::write(
+        worktree.join(".git"),
+        format!("gitdir: {}\n", relative_path(worktree, gitdir).display()),
+    )?;
+    let mut config = GitConfig::read(&gitdir.join("config"))?;
+    config.set(
+        "core.worktree",
+        &relative_path(gitdir, worktree).to_string_lossy(),
+    )?;
+    config.write(&gitdir.join("config"))
+}
+
+/// Move the `.git` directory of a checked out submodule under `modules/`
+/// of the superproject, returning its new path; `None` if it already is
+fn absorb(repo: &Repository, submodule: &Submodule) -> Result<Option<PathBuf>> {
+    let worktree = repo.worktree().join(&submodule.path);
+    let dotgit = worktree.join(".git");
+    if !dotgit.is_dir() {
+        return Ok(None);
+    }
+    let gitdir = module_gitdir(repo, &submodule.name)?;
+    if gitdir.exists() {
+        bail!(
+            "refusing to move '{}' into an existing git dir",
+            dotgit.display()
+        );
+    }
+    if let Some(parent) = gitdir.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(&dotgit, &gitdir).with_context(|| format!("Failed to move {}", dotgit.display()))?;
+    link_gitdir(&worktree, &gitdir)?;
+    Ok(Some(fs::canonicalize(gitdir)?))
+}
+
+/// Move the git directories of checked out submodules under `modules/` of
+/// the superproject, like `git submodule absorbgitdirs`
+///
+/// Returns the path of each moved submodule with its new git directory.
+pub fn absorb_git_dirs(repo: &Repository, paths: &[String]) -> Result<Vec<(String, PathBuf)>> {
+    let mut moved = Vec::new();
+    for (submodule, _) in select(recorded_submodules(repo)?, paths) {
+        if let Some(gitdir) = absorb(repo, &submodule)? {
+            moved.push((submodule.path, gitdir));
+        }
+    }
+    Ok(moved)
+}
+
+/// Name a commit of a submodule by a reference pointing to it: a tag,
+/// then a branch, then a remote-tracking branch
+fn describe(sub: &Repository, hash: &ObjectHash) -> Result<Option<String>> {
+    for prefix in ["refs/tags/", "refs/heads/", "refs/remotes/"] {
+        let found = list_refs(sub, prefix)?.into_iter().find(|(_, target)| {
+            crate::revision::peel_tags(sub, target).is_ok_and(|peeled| peeled == *hash)
+        });
+        if let Some((name, _)) = found {
+            let name = name.trim_start_matches("refs/");
+            return Ok(Some(match prefix {
+                "refs/tags/" => name.trim_start_matches("tags/").to_string(),
+                _ => name.to_string(),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Report the commit checked out in each submodule, like `git submodule
+/// status`
+pub fn submodule_status(
+    repo: &Repository,
+    paths: &[String],
+    recursive: bool,
+) -> Result<Vec<SubmoduleStatus>> {
+    let config = repo.config()?;
+    let mut statuses = Vec::new();
+    for (submodule, hash) in select(recorded_submodules(repo)?, paths) {
+        let Some(hash) = hash else {
+            statuses.push(SubmoduleStatus {
+                path: submodule.path,
+                prefix: 'U',
+                hash: repo.hash_algorithm().null_hash(),
+                name: None,
+            });
+            continue;
+        };
+        let sub = match open_submodule(repo, &submodule.path) {
+            Some(sub) if is_active(&config, &submodule.name)? => sub,
+            _ => {
+                statuses.push(SubmoduleStatus {
+                    path: submodule.path,
+                    prefix: '-',
+                    hash,
+                    name: None,
+                });
+                continue;
+            }
+        };
+        let head = resolve_ref(&sub, "HEAD")?.unwrap_or_else(|| hash.clone());
+        statuses.push(SubmoduleStatus {
+            path: submodule.path.clone(),
+            prefix: if head == hash { ' ' } else { '+' },
+            name: describe(&sub, &head)?,
+            hash: head,
+        });
+        if recursive {
+            statuses.extend(
+                submodule_status(&sub, &[], true)?
+                    .into_iter()
+                    .map(|mut status| {
+                        status.path = format!("{}/{}", submodule.path, status.path);
+                        status
+                    }),
+            );
+        }
+    }
+    Ok(statuses)
+}
+
+/// Copy the URLs of `.gitmodules` to the config of the superproject and to
+/// the `origin` remote of each checked out submodule, like `git submodule
+/// sync`
+///
+/// Returns the paths of the submodules synchronized.
+pub fn sync(repo: &Repository, paths: &[String], recursive: bool) -> Result<Vec<String>> {
+    let mut config = repo.config()?;
+    let mut synced = Vec::new();
+    for (submodule, _) in select(recorded_submodules(repo)?, paths) {
+        let Some(url) = &submodule.url else {
+            continue;
+        };
+        let url = resolve_url(repo, url)?;
+        let key = format!("submodule.{}.url", submodule.name);
+        if config.get(&key).is_some() {
+            config.set(&key, &url)?;
+        }
+        if let Some(sub) = open_submodule(repo, &submodule.path) {
+            let mut sub_config = sub.config()?;
+            sub_config.set("remote.origin.url", &url)?;
+            sub.write_config(&sub_config)?;
+            if recursive {
+                synced.extend(
+                    sync(&sub, &[], true)?
+                        .into_iter()
+                        .map(|path| format!("{}/{}", submodule.path, path)),
+                );
+            }
+        }
+        synced.push(submodule.path);
+    }
+    repo.write_config(&config)?;
+    Ok(synced)
+}
+
+/// Run a shell command in each checked out submodule, like `git submodule
+/// foreach`, calling `entering` with the path of each one first
+///
+/// The command sees `$name`, `$sm_path`, `$displaypath`, `$sha1` and
+/// `$toplevel`, and stops the walk when it fails.
+pub fn foreach(
+    repo: &Repository,
+    command: &str,
+    recursive: bool,
+    entering: &mut dyn FnMut(&str),
+) -> Result<()> {
+    foreach_in(repo, command, recursive, "", entering)
+}
+
+fn foreach_in(
+    repo: &Repository,
+    command: &str,
+    recursive: bool,
+    prefix: &str,
+    entering: &mut dyn FnMut(&str),
+) -> Result<()> {
+    for (submodule, _) in recorded_submodules(repo)? {
+        let Some(sub) = open_submodule(repo, &submodule.path) else {
+            continue;
+        };
+        let displaypath = format!("{}{}", prefix, submodule.path);
+        entering(&displaypath);
+        let head = resolve_ref(&sub, "HEAD")?
+            .map(|hash| hash.to_hex())
+            .unwrap_or_default();
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(sub.worktree())
+            .env("name", &submodule.name)
+            .env("sm_path", &submodule.path)
+            .env("displaypath", &displaypath)
+            .env("sha1", head)
+            .env("toplevel", fs::canonicalize(repo.worktree())?)
+            .status()
+            .context("Failed to run the command")?;
+        if !status.success() {
+            bail!(
+                "Stopping at '{}'; script returned non-zero status.",
+                displaypath
+            );
+        }
+        if recursive {
+            let prefix = format!("{}/", displaypath);
+            foreach_in(&sub, command, true, &prefix, entering)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::add::{add, AddOptions};
+    use crate::index::IndexEntry;
+    use crate::refs::update_ref;
+    use crate::test_utils::{commit_files, init_repo, SIGNATURE};
+
+    /// Make a superproject recording the repository at `url` as `lib/sub`
+    fn superproject(name: &str, url: &str, commit: &ObjectHash) -> (tempfile::TempDir, Repository) {
+        let (dir, repo) = init_repo();
+        let gitmodules = format!(
+            "[submodule \"{}\"]\n\tpath = lib/sub\n\turl = {}\n",
+            name, url
+        );
+        fs::write(repo.worktree().join(GITMODULES), gitmodules).unwrap();
+        add(&repo, &[GITMODULES.to_string()], &AddOptions::default()).unwrap();
+        let mut index = Index::read(&repo).unwrap();
+        index
+            .add(IndexEntry::new(
+                "lib/sub",
+                EntryMode::Gitlink,
+                commit.clone(),
+                None,
+            ))
+            .unwrap();
+        index.write(&repo).unwrap();
+        let commit = Commit {
+            tree: index.write_tree(&repo).unwrap(),
+            parents: Vec::new(),
+            author: SIGNATURE.to_string(),
+            committer: SIGNATURE.to_string(),
+            gpgsig: None,
+            extra_headers: Vec::new(),
+            message: "add sub\n".to_string(),
+        };
+        update_ref(&repo, "refs/heads/master", &commit.write(&repo).unwrap()).unwrap();
+        (dir, repo)
+    }
+
+    #[test]
+    fn test_update_clones_into_modules() {
+        let (sub_dir, sub_source) = init_repo();
+        let first = commit_files(&sub_source, &[("a.txt", "one\n")], "first");
+        let second = commit_files(&sub_source, &[("a.txt", "two\n")], "second");
+        let (_dir, repo) = superproject("sub", sub_dir.path().to_str().unwrap(), &first);
+
+        let statuses = submodule_status(&repo, &[], false).unwrap();
+        assert_eq!(statuses[0].prefix, '-');
+        let options = UpdateOptions {
+            init: true,
+            ..UpdateOptions::default()
+        };
+        let updated = update(&repo, &[], &options).unwrap();
+        assert_eq!(updated, vec![("lib/sub".to_string(), first.clone())]);
+        let path = repo.worktree().join("lib/sub");
+        assert_eq!(fs::read_to_string(path.join("a.txt")).unwrap(), "one\n");
+        assert!(path.join(".git").is_file());
+        assert!(module_gitdir(&repo, "sub").unwrap().join("HEAD").exists());
+        assert_eq!(
+            submodule_status(&repo, &[], false).unwrap()[0],
+            SubmoduleStatus {
+                path: "lib/sub".to_string(),
+                prefix: ' ',
+                hash: first.clone(),
+                name: None,
+            }
+        );
+
+        // Moving the submodule shows up in its status and the superproject's
+        let sub = open_submodule(&repo, "lib/sub").unwrap();
+        checkout(&sub, "master", &CheckoutOptions::default()).unwrap();
+        let statuses = submodule_status(&repo, &[], false).unwrap();
+        assert_eq!(statuses[0].prefix, '+');
+        assert_eq!(statuses[0].name.as_deref(), Some("heads/master"));
+        fs::write(path.join("new.txt"), "new\n").unwrap();
+        let state = submodule_state(&repo, "lib/sub", &first).unwrap().unwrap();
+        assert_eq!(state.describe(), "new commits, untracked content");
+        let change = status(&repo, &StatusOptions::default()).unwrap().changes;
+        assert_eq!(change[0].submodule, Some(state));
+
+        // Staging the submodule records its HEAD
+        add(&repo, &["lib/sub".to_string()], &AddOptions::default()).unwrap();
+        let index = Index::read(&repo).unwrap();
+        assert_eq!(index.get("lib/sub").unwrap().hash, second);
+    }
+
+    #[test]
+    fn test_refuses_names_escaping_modules() {
+        let (sub_dir, sub_source) = init_repo();
+        let first = commit_files(&sub_source, &[("a.txt", "one\n")], "first");
+        // Resolves to the root of the superproject's working tree
+        let name = "../../smescape";
+        let (dir, repo) = superproject(name, sub_dir.path().to_str().unwrap(), &first);
+        let modules = read_gitmodules(&repo).unwrap();
+        assert!(modules.submodules.is_empty());
+        assert_eq!(modules.skipped, [name]);
+        let options = UpdateOptions {
+            init: true,
+            ..UpdateOptions::default()
+        };
+        assert!(update(&repo, &[], &options).is_err());
+        let escaped = dir.path().join("smescape");
+        assert!(!escaped.exists());
+        assert!(!repo.worktree().join("lib/sub/.git").exists());
+
+        let submodule = Submodule {
+            name: name.to_string(),
+            path: "lib/sub".to_string(),
+            url: None,
+        };
+        Repository::new(&repo.worktree().join("lib/sub")).unwrap();
+        assert!(absorb(&repo, &submodule).is_err());
+        assert!(repo.worktree().join("lib/sub/.git").is_dir());
+        assert!(!escaped.exists());
+
+        for name in ["", "..", "a/../b", "a\\..\\b", "../a", "/tmp/a", "\\a"] {
+            assert!(check_submodule_name(name).is_err(), "{:?}", name);
+        }
+        check_submodule_name("lib/sub..x").unwrap();
+    }
+
+    #[test]
+    fn test_read_gitmodules_reports_skipped_names() {
+        let (_dir, repo) = init_repo();
+        let gitmodules = "[submodule \"good\"]\n\tpath = good\n\
+                          [submodule \"../escape\"]\n\tpath = a\n\
+                          [submodule \"/abs\"]\n\tpath = b\n";
+        fs::write(repo.worktree().join(GITMODULES), gitmodules).unwrap();
+        let modules = read_gitmodules(&repo).unwrap();
+        let names = modules
+            .submodules
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["good"]);
+        assert_eq!(modules.skipped, ["../escape", "/abs"]);
+    }
+
+    #[test]
+    fn test_resolve_relative_url() {
+        let (_dir, repo) = init_repo();
+        let mut config = repo.config().unwrap();
+        config
+            .set("remote.origin.url", "https://example.com/group/project.git")
+            .unwrap();
+        repo.write_config(&config).unwrap();
+        assert_eq!(
+            resolve_url(&repo, "../lib.git").unwrap(),
+            "https://example.com/group/lib.git"
+        );
+        assert_eq!(
+            resolve_url(&repo, "./lib").unwrap(),
+            "https://example.com/group/project.git/lib"
+        );
+        assert_eq!(resolve_url(&repo, "git@host:lib").unwrap(), "git@host:lib");
+    }
+}
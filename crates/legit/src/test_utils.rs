@@ -0,0 +1,91 @@
+//! Helpers shared by the unit tests of the library
+
+use crate::commits::Commit;
+use crate::objects::{store_object, Object, ObjectHash, ObjectType};
+use crate::refs::{read_head, resolve_ref, update_ref, Head};
+use crate::tree::{build_tree, EntryMode};
+use crate::Repository;
+use std::process::Command;
+use tempfile::TempDir;
+
+pub const SIGNATURE: &str = "Test User <test@example.com> 1700000000 +0000";
+
+/// Create an empty repository in a temporary directory
+pub fn init_repo() -> (TempDir, Repository) {
+    let tempdir = TempDir::new().unwrap();
+    let repo = Repository::new(tempdir.path()).unwrap();
+    (tempdir, repo)
+}
+
+/// Store a blob and return its hash
+pub fn write_blob(repo: &Repository, data: &[u8]) -> ObjectHash {
+    store_object(
+        &Object::new(repo.hash_algorithm(), ObjectType::Blob, data.to_vec()).unwrap(),
+        repo,
+    )
+    .unwrap()
+}
+
+/// Write a tree holding exactly the given files
+pub fn write_tree(repo: &Repository, files: &[(&str, &str)]) -> ObjectHash {
+    let blobs = files
+        .iter()
+        .map(|(path, content)| (*path, write_blob(repo, content.as_bytes())))
+        .collect::<Vec<_>>();
+    build_tree(
+        repo,
+        blobs
+            .iter()
+            .map(|(path, hash)| (*path, EntryMode::Blob, hash)),
+    )
+    .unwrap()
+}
+
+/// Write a commit with the given parents without touching any reference
+pub fn write_commit(
+    repo: &Repository,
+    files: &[(&str, &str)],
+    parents: &[ObjectHash],
+    message: &str,
+) -> ObjectHash {
+    let commit = Commit {
+        tree: write_tree(repo, files),
+        parents: parents.to_vec(),
+        author: SIGNATURE.to_string(),
+        committer: SIGNATURE.to_string(),
+        gpgsig: None,
+        extra_headers: Vec::new(),
+        message: format!("{}\n", message),
+    };
+    commit.write(repo).unwrap()
+}
+
+/// Commit a snapshot of the given files on top of HEAD and advance HEAD
+pub fn commit_files(repo: &Repository, files: &[(&str, &str)], message: &str) -> ObjectHash {
+    let parents = resolve_ref(repo, "HEAD")
+        .unwrap()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let hash = write_commit(repo, files, &parents, message);
+    match read_head(repo).unwrap() {
+        Head::Branch(name) => update_ref(repo, &name, &hash).unwrap(),
+        Head::Detached(_) => update_ref(repo, "HEAD", &hash).unwrap(),
+    }
+    hash
+}
+
+/// Configure the user identity used by commands that create commits
+pub fn set_identity(repo: &Repository) {
+    let mut config = repo.config().unwrap();
+    config.set("user.name", "Test User").unwrap();
+    config.set("user.email", "test@example.com").unwrap();
+    repo.write_config(&config).unwrap();
+}
+
+/// Fail an interop test up front, rather than deep inside it, when the git
+/// program it talks to is not installed
+pub fn require_program(program: &str) {
+    if let Err(e) = Command::new(program).arg("--help").output() {
+        panic!("this interop test needs `{}` on the PATH: {}", program, e);
+    }
+}
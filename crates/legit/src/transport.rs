@@ -0,0 +1,434 @@
+use crate::http::HttpTransport;
+use crate::objects::{object_exists, read_object, store_object, ObjectHash, ObjectType};
+use crate::promisor::ObjectFilter;
+use crate::protocol::{fetch_request_for, store_response, ProcessTransport};
+use crate::refs::{list_refs, read_ref, resolve_ref, RefValue};
+use crate::revision::{peel_tags, rev_list, tag_target};
+use crate::shallow::{read_shallow, update_shallow, Deepen};
+use crate::tree::{EntryMode, Tree};
+use crate::upload_pack::local_fetch;
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// RemoteRef is a reference advertised by a remote repository
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteRef {
+    pub name: String,
+    pub hash: ObjectHash,
+    /// The reference a symbolic reference such as `HEAD` points to
+    pub symref_target: Option<String>,
+    /// The object an annotated tag ultimately points to
+    pub peeled: Option<ObjectHash>,
+}
+
+/// Transport is a way of talking to a remote repository
+///
+/// Fetching is done in two steps: listing the remote's references, then
+/// asking for the objects reachable from some of them. `haves` are commits
+/// the local repository already has, so that the objects reachable from them
+/// need not be transferred.
+pub trait Transport {
+    /// List the references of the remote repository
+    fn list_refs(&mut self) -> Result<Vec<RemoteRef>>;
+
+    /// Copy into `repo` every object reachable from `wants` and not from `haves`
+    fn fetch(
+        &mut self,
+        repo: &Repository,
+        wants: &[ObjectHash],
+        haves: &[ObjectHash],
+    ) -> Result<()>;
+
+    /// Show the progress messages of the remote on stderr
+    fn set_progress(&mut self, _progress: bool) {}
+
+    /// Narrow the history and the objects later fetches transfer
+    fn set_scope(&mut self, _scope: &FetchScope) -> Result<()> {
+        bail!("The transport does not support shallow or partial fetches")
+    }
+}
+
+/// FetchScope narrows a fetch to part of the history or of the objects
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FetchScope {
+    /// How much history to fetch, all of it when `None`
+    pub deepen: Option<Deepen>,
+    /// Leave out the objects this filter excludes
+    pub filter: Option<ObjectFilter>,
+    /// The remote promises the objects left out: mark its packs as such
+    pub promisor: bool,
+}
+
+/// Url is where a remote repository lives
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Url {
+    /// A plain path, read directly
+    Local(PathBuf),
+    /// A `file://` URL, served by a local `git-upload-pack`
+    File(PathBuf),
+    /// An `http://` or `https://` URL, spoken to with the smart protocol
+    Http(String),
+    /// `ssh://[user@]host[:port]/path` or `[user@]host:path`
+    Ssh {
+        user: Option<String>,
+        host: String,
+        port: Option<u16>,
+        path: String,
+    },
+}
+
+impl Url {
+    /// Parse a repository URL the way git does
+    pub fn parse(url: &str) -> Result<Url> {
+        if let Some(path) = url.strip_prefix("file://") {
+            return Ok(Url::File(PathBuf::from(path)));
+        }
+        if url.starts_with("http://") || url.starts_with("https://") {
+            return Ok(Url::Http(url.to_string()));
+        }
+        let ssh = ["ssh://", "git+ssh://", "ssh+git://"]
+            .iter()
+            .find_map(|scheme| url.strip_prefix(scheme));
+        if let Some(rest) = ssh {
+            let (authority, path) = rest
+                .find('/')
+                .map(|slash| rest.split_at(slash))
+                .with_context(|| format!("No path specified in {}", url))?;
+            let (user, host) = split_user(authority);
+            let (host, port) = match host.rsplit_once(':') {
+                Some((host, port))
+                    if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) =>
+                {
+                    let port = port
+                        .parse()
+                        .with_context(|| format!("Invalid port in {}", url))?;
+                    (host, Some(port))
+                }
+                _ => (host, None),
+            };
+            // `ssh://host/~user/repo` is relative to that user's home
+            let path = match path.strip_prefix("/~") {
+                Some(path) => format!("~{}", path),
+                None => path.to_string(),
+            };
+            return Url::ssh(user, host.trim_matches(['[', ']']), port, path);
+        }
+        if url.contains("://") {
+            bail!("Unsupported URL: {}", url);
+        }
+        // scp-like syntax: a colon before any slash
+        match url.split_once(':') {
+            Some((authority, path)) if !authority.is_empty() && !authority.contains('/') => {
+                let (user, host) = split_user(authority);
+                Url::ssh(user, host, None, path.to_string())
+            }
+            _ => {
+                check_not_option("pathname", url)?;
+                Ok(Url::Local(PathBuf::from(url)))
+            }
+        }
+    }
+
+    /// Make an ssh URL, refusing parts that ssh would take for options
+    fn ssh(user: Option<String>, host: &str, port: Option<u16>, path: String) -> Result<Url> {
+        check_not_option("hostname", host)?;
+        if let Some(user) = &user {
+            check_not_option("username", user)?;
+        }
+        check_not_option("pathname", &path)?;
+        Ok(Url::Ssh {
+            user,
+            host: host.to_string(),
+            port,
+            path,
+        })
+    }
+}
+
+/// Refuse a part of a URL that a command would parse as an option, such as
+/// the host `-oProxyCommand=...` (CVE-2017-1000117)
+pub fn check_not_option(kind: &str, value: &str) -> Result<()> {
+    if value.starts_with('-') {
+        bail!("strange {} '{}' blocked", kind, value);
+    }
+    Ok(())
+}
+
+/// Split `user@host` into its user and host
+fn split_user(authority: &str) -> (Option<String>, &str) {
+    match authority.rsplit_once('@') {
+        Some((user, host)) => (Some(user.to_string()), host),
+        None => (None, authority),
+    }
+}
+
+/// Open a transport to the repository at `url` on behalf of `repo`, whose
+/// config holds the proxy and credential settings
+pub fn open(repo: &Repository, url: &str) -> Result<Box<dyn Transport>> {
+    Ok(match Url::parse(url)? {
+        Url::Local(path) => Box::new(LocalTransport::open(&path)?),
+        Url::Http(url) => Box::new(HttpTransport::open(repo, &url)?),
+        url => Box::new(ProcessTransport::open(&url)?),
+    })
+}
+
+/// LocalTransport reads objects directly from a repository on disk
+#[derive(Debug)]
+pub struct LocalTransport {
+    source: Repository,
+    scope: FetchScope,
+}
+
+impl LocalTransport {
+    /// Open the repository at `path`, a working tree or a bare git directory
+    pub fn open(path: &Path) -> Result<LocalTransport> {
+        let source = Repository::open(path).with_context(|| {
+            format!(
+                "'{}' does not appear to be a git repository",
+                path.display()
+            )
+        })?;
+        Ok(LocalTransport {
+            source,
+            scope: FetchScope::default(),
+        })
+    }
+
+    /// Return the repository being fetched from
+    pub fn source(&self) -> &Repository {
+        &self.source
+    }
+
+    /// Copy the whole object database of the source, hardlinking files when
+    /// possible, as `git clone --local` does
+    pub fn link_objects(&self, repo: &Repository) -> Result<()> {
+        let source = self.source.commondir().join("objects");
+        let target = repo.commondir().join("objects");
+        link_dir(&source, &target)
+    }
+
+    /// Copy one object, as the loose file itself when the source has it loose
+    fn copy_object(&self, repo: &Repository, hash: &ObjectHash) -> Result<()> {
+        let (dir, file) = hash.as_path_parts();
+        let loose = self
+            .source
+            .commondir()
+            .join("objects")
+            .join(&dir)
+            .join(&file);
+        if loose.is_file() {
+            let target = repo.commondir().join("objects").join(&dir);
+            fs::create_dir_all(&target)?;
+            fs::copy(&loose, target.join(&file))
+                .with_context(|| format!("Failed to copy object {}", hash))?;
+            return Ok(());
+        }
+        store_object(&read_object(&self.source, hash)?, repo)?;
+        Ok(())
+    }
+
+    /// Copy a tree and everything below it that the repository lacks
+    fn copy_tree(
+        &self,
+        repo: &Repository,
+        hash: &ObjectHash,
+        seen: &mut HashSet<ObjectHash>,
+    ) -> Result<()> {
+        if !seen.insert(hash.clone()) || object_exists(repo, hash) {
+            return Ok(());
+        }
+        let tree = Tree::read(&self.source, hash)?;
+        for entry in &tree.entries {
+            match entry.mode {
+                EntryMode::Tree => self.copy_tree(repo, &entry.hash, seen)?,
+                // Submodule commits live in another repository
+                EntryMode::Gitlink => {}
+                _ => {
+                    if seen.insert(entry.hash.clone()) && !object_exists(repo, &entry.hash) {
+                        self.copy_object(repo, &entry.hash)?;
+                    }
+                }
+            }
+        }
+        self.copy_object(repo, hash)
+    }
+}
+
+impl Transport for LocalTransport {
+    fn list_refs(&mut self) -> Result<Vec<RemoteRef>> {
+        let source = &self.source;
+        let mut refs = Vec::new();
+        if let Some(hash) = resolve_ref(source, "HEAD")? {
+            let symref_target = match read_ref(source, "HEAD")? {
+                Some(RefValue::Symbolic(target)) => Some(target),
+                _ => None,
+            };
+            refs.push(RemoteRef {
+                name: "HEAD".to_string(),
+                hash,
+                symref_target,
+                peeled: None,
+            });
+        }
+        for (name, hash) in list_refs(source, "refs/")? {
+            let peeled = peel_tags(source, &hash)?;
+            refs.push(RemoteRef {
+                name,
+                peeled: (peeled != hash).then_some(peeled),
+                hash,
+                symref_target: None,
+            });
+        }
+        Ok(refs)
+    }
+
+    fn fetch(
+        &mut self,
+        repo: &Repository,
+        wants: &[ObjectHash],
+        haves: &[ObjectHash],
+    ) -> Result<()> {
+        // A narrowed fetch gets the pack upload-pack would send
+        if self.scope != FetchScope::default() {
+            let request = fetch_request_for(repo, wants, haves, &self.scope, false)?;
+            let response = local_fetch(&self.source, &request)?;
+            return store_response(repo, &response, self.scope.promisor);
+        }
+        let source = &self.source;
+        // Tags are copied as they are and followed to what they point to
+        let mut commits = Vec::new();
+        let mut seen = HashSet::new();
+        for want in wants {
+            let mut hash = want.clone();
+            loop {
+                let object = read_object(source, &hash)
+                    .with_context(|| format!("Remote does not have {}", hash))?;
+                match object.object_type {
+                    ObjectType::Tag => {
+                        if seen.insert(hash.clone()) && !object_exists(repo, &hash) {
+                            self.copy_object(repo, &hash)?;
+                        }
+                        hash = tag_target(&object.data, repo.hash_algorithm())?;
+                    }
+                    ObjectType::Commit => {
+                        commits.push(hash);
+                        break;
+                    }
+                    ObjectType::Tree => {
+                        self.copy_tree(repo, &hash, &mut seen)?;
+                        break;
+                    }
+                    ObjectType::Blob => {
+                        if seen.insert(hash.clone()) && !object_exists(repo, &hash) {
+                            self.copy_object(repo, &hash)?;
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+
+        let haves = haves
+            .iter()
+            .filter(|hash| object_exists(source, hash))
+            .cloned()
+            .collect::<Vec<_>>();
+        // Oldest first, so that a commit is only stored once its history is
+        let mut missing = rev_list(source, &commits, &haves)?;
+        missing.reverse();
+        // The history of a shallow source ends where its own does
+        let source_shallow = read_shallow(source)?;
+        let mut shallow = Vec::new();
+        for hash in missing {
+            if object_exists(repo, &hash) {
+                continue;
+            }
+            let commit = crate::commits::Commit::read(source, &hash)?;
+            self.copy_tree(repo, &commit.tree, &mut seen)?;
+            self.copy_object(repo, &hash)?;
+            if source_shallow.contains(&hash) {
+                shallow.push(hash);
+            }
+        }
+        update_shallow(repo, &shallow, &[])
+    }
+
+    fn set_scope(&mut self, scope: &FetchScope) -> Result<()> {
+        self.scope = scope.clone();
+        Ok(())
+    }
+}
+
+/// Recursively hardlink, or copy when linking fails, the files of a directory
+fn link_dir(source: &Path, target: &Path) -> Result<()> {
+    fs::create_dir_all(target)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let from = entry.path();
+        let to = target.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            link_dir(&from, &to)?;
+        } else if !to.exists() && fs::hard_link(&from, &to).is_err() {
+            fs::copy(&from, &to).with_context(|| format!("Failed to copy {}", from.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        let ssh = |user: Option<&str>, host: &str, port, path: &str| Url::Ssh {
+            user: user.map(str::to_string),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        };
+        assert_eq!(
+            Url::parse("ssh://git@example.com:2222/srv/repo.git").unwrap(),
+            ssh(Some("git"), "example.com", Some(2222), "/srv/repo.git")
+        );
+        assert_eq!(
+            Url::parse("ssh://example.com/~alice/repo").unwrap(),
+            ssh(None, "example.com", None, "~alice/repo")
+        );
+        assert_eq!(
+            Url::parse("git@example.com:team/repo.git").unwrap(),
+            ssh(Some("git"), "example.com", None, "team/repo.git")
+        );
+        assert_eq!(
+            Url::parse("file:///srv/repo").unwrap(),
+            Url::File(PathBuf::from("/srv/repo"))
+        );
+        assert_eq!(
+            Url::parse("./dir:with/colon").unwrap(),
+            Url::Local(PathBuf::from("./dir:with/colon"))
+        );
+        assert_eq!(
+            Url::parse("https://example.com/repo.git").unwrap(),
+            Url::Http("https://example.com/repo.git".to_string())
+        );
+        assert!(Url::parse("ftp://example.com/repo").is_err());
+    }
+
+    #[test]
+    fn test_parse_url_blocks_options() {
+        for url in [
+            "ssh://-oProxyCommand=touch%20pwned/repo",
+            "ssh://-user@example.com/repo",
+            "-oProxyCommand=touch pwnedfile:repo",
+            "-user@example.com:repo",
+            "example.com:-repo",
+            "-repo",
+        ] {
+            let error = Url::parse(url).unwrap_err();
+            assert!(error.to_string().contains("blocked"), "{}: {}", url, error);
+        }
+    }
+}
@@ -0,0 +1,390 @@
+use crate::objects::{read_object, store_object, HashAlgorithm, Object, ObjectHash, ObjectType};
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+/// EntryMode represents the kind of object a tree entry points to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EntryMode {
+    Tree,
+    Blob,
+    BlobExecutable,
+    Symlink,
+    Gitlink,
+}
+
+impl EntryMode {
+    /// Parse the octal mode used in tree objects
+    pub fn from_octal(mode: &str) -> Result<EntryMode> {
+        match mode {
+            "40000" | "040000" => Ok(EntryMode::Tree),
+            "100644" | "100664" => Ok(EntryMode::Blob),
+            "100755" => Ok(EntryMode::BlobExecutable),
+            "120000" => Ok(EntryMode::Symlink),
+            "160000" => Ok(EntryMode::Gitlink),
+            _ => bail!("Invalid tree entry mode: {}", mode),
+        }
+    }
+
+    /// Create a mode from the numeric value used in the index
+    pub fn from_bits(mode: u32) -> Result<EntryMode> {
+        EntryMode::from_octal(&format!("{:o}", mode))
+    }
+
+    /// Return the octal mode as written in tree objects
+    pub fn as_octal(&self) -> &'static str {
+        match self {
+            EntryMode::Tree => "40000",
+            EntryMode::Blob => "100644",
+            EntryMode::BlobExecutable => "100755",
+            EntryMode::Symlink => "120000",
+            EntryMode::Gitlink => "160000",
+        }
+    }
+
+    /// Return the numeric mode as stored in the index
+    pub fn bits(&self) -> u32 {
+        u32::from_str_radix(self.as_octal(), 8).unwrap_or_default()
+    }
+
+    /// Return true if the entry is a subtree
+    pub fn is_tree(&self) -> bool {
+        *self == EntryMode::Tree
+    }
+}
+
+/// TreeEntry is a single named entry of a tree object
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeEntry {
+    pub mode: EntryMode,
+    pub name: String,
+    pub hash: ObjectHash,
+}
+
+/// Tree represents a git tree object
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Tree {
+    pub entries: Vec<TreeEntry>,
+}
+
+impl Tree {
+    /// Parse the binary data of a tree object
+    ///
+    /// Each entry is stored as `<mode> <name>\0<hash>`, with a raw hash of
+    /// the size `algorithm` makes. Names are taken as they are, so that
+    /// history holding unsafe ones can still be walked; `verify_path`
+    /// guards the index and working tree instead.
+    pub fn parse(data: &[u8], algorithm: HashAlgorithm) -> Result<Tree> {
+        let size = algorithm.size();
+        let mut entries = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let space = rest
+                .iter()
+                .position(|&b| b == b' ')
+                .ok_or_else(|| anyhow::anyhow!("Invalid tree entry: missing mode"))?;
+            let mode = std::str::from_utf8(&rest[..space]).context("Invalid tree entry mode")?;
+            let mode = EntryMode::from_octal(mode)?;
+            rest = &rest[space + 1..];
+
+            let nul = rest
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid tree entry: missing null terminator"))?;
+            let name =
+                String::from_utf8(rest[..nul].to_vec()).context("Invalid tree entry name")?;
+            rest = &rest[nul + 1..];
+
+            if rest.len() < size {
+                bail!("Invalid tree entry: truncated hash for {}", name);
+            }
+            let hash = ObjectHash::from_bytes(&rest[..size], algorithm)?;
+            rest = &rest[size..];
+
+            entries.push(TreeEntry { mode, name, hash });
+        }
+        Ok(Tree { entries })
+    }
+
+    /// Serialize the tree, sorting entries the way git expects
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut entries = self.entries.iter().collect::<Vec<_>>();
+        entries.sort_by(|a, b| compare_entries(a, b));
+        let mut data = Vec::new();
+        for entry in entries {
+            data.extend_from_slice(entry.mode.as_octal().as_bytes());
+            data.push(b' ');
+            data.extend_from_slice(entry.name.as_bytes());
+            data.push(0);
+            data.extend_from_slice(entry.hash.as_bytes());
+        }
+        data
+    }
+
+    /// Read and parse a tree from the repository
+    pub fn read(repo: &Repository, hash: &ObjectHash) -> Result<Tree> {
+        let object = read_object(repo, hash)?;
+        if object.object_type != ObjectType::Tree {
+            bail!("Object {} is a {}, not a tree", hash, object.object_type);
+        }
+        Tree::parse(&object.data, hash.algorithm())
+            .with_context(|| format!("Failed to parse tree {}", hash))
+    }
+
+    /// Write the tree to the repository and return its hash
+    pub fn write(&self, repo: &Repository) -> Result<ObjectHash> {
+        let object = Object::new(repo.hash_algorithm(), ObjectType::Tree, self.serialize())?;
+        store_object(&object, repo)
+    }
+}
+
+/// Check that a tree entry name is safe to check out, like git's
+/// `verify_path`: it is not empty, `.` or `..`, holds no `/` or NUL and is
+/// not the git directory under any spelling a filesystem treats as `.git`
+pub fn verify_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        bail!("invalid path '{}'", name);
+    }
+    if is_dot_git(name) {
+        bail!("invalid path '{}': names the git directory", name);
+    }
+    Ok(())
+}
+
+/// Check each component of a `/` separated path with `verify_name`
+pub fn verify_path(path: &str) -> Result<()> {
+    for component in path.split('/') {
+        verify_name(component).with_context(|| format!("invalid path '{}'", path))?;
+    }
+    Ok(())
+}
+
+/// Return true if a name is `.git` once case is folded, the code points HFS+
+/// ignores are dropped, or trailing dots, spaces and alternate data streams
+/// are stripped as NTFS does; `git~1` is its NTFS short name
+pub fn is_dot_git(name: &str) -> bool {
+    // Zero width and direction marks, which HFS+ leaves out of comparisons
+    let ignorable = |c: &char| {
+        matches!(
+            *c,
+            '\u{200c}'..='\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{206a}'..='\u{206f}' | '\u{feff}'
+        )
+    };
+    let folded = name
+        .chars()
+        .filter(|c| !ignorable(c))
+        .collect::<String>()
+        .to_lowercase();
+    // NTFS drops trailing dots and spaces and what follows a `:`
+    [".git", "git~1"].iter().any(|stem| {
+        folded.strip_prefix(stem).is_some_and(|rest| {
+            let rest = rest.split(':').next().unwrap_or_default();
+            rest.chars().all(|c| c == '.' || c == ' ')
+        })
+    })
+}
+
+/// Git sorts tree entries by name, comparing subtrees as if their name ended in `/`
+fn compare_entries(a: &TreeEntry, b: &TreeEntry) -> Ordering {
+    let key = |e: &TreeEntry| {
+        let mut name = e.name.as_bytes().to_vec();
+        if e.mode.is_tree() {
+            name.push(b'/');
+        }
+        name
+    };
+    key(a).cmp(&key(b))
+}
+
+/// Recursively list every non-tree entry of a tree keyed by its full path
+pub fn flatten_tree(
+    repo: &Repository,
+    hash: &ObjectHash,
+) -> Result<BTreeMap<String, (EntryMode, ObjectHash)>> {
+    let mut files = BTreeMap::new();
+    flatten_into(repo, hash, "", &mut files)?;
+    Ok(files)
+}
+
+fn flatten_into(
+    repo: &Repository,
+    hash: &ObjectHash,
+    prefix: &str,
+    files: &mut BTreeMap<String, (EntryMode, ObjectHash)>,
+) -> Result<()> {
+    for entry in Tree::read(repo, hash)?.entries {
+        let path = format!("{}{}", prefix, entry.name);
+        if entry.mode.is_tree() {
+            flatten_into(repo, &entry.hash, &format!("{}/", path), files)?;
+        } else {
+            files.insert(path, (entry.mode, entry.hash));
+        }
+    }
+    Ok(())
+}
+
+/// Write the nested trees for a set of paths and return the root tree hash
+///
+/// This is the inverse of `flatten_tree`: paths are split on `/` and a tree
+/// object is written for every directory.
+pub fn build_tree<'a, I>(repo: &Repository, files: I) -> Result<ObjectHash>
+where
+    I: IntoIterator<Item = (&'a str, EntryMode, &'a ObjectHash)>,
+{
+    #[derive(Default)]
+    struct Dir<'a> {
+        files: Vec<TreeEntry>,
+        dirs: BTreeMap<&'a str, Dir<'a>>,
+    }
+
+    fn write_dir(repo: &Repository, dir: Dir) -> Result<ObjectHash> {
+        let mut tree = Tree { entries: dir.files };
+        for (name, subdir) in dir.dirs {
+            let hash = write_dir(repo, subdir)?;
+            tree.entries.push(TreeEntry {
+                mode: EntryMode::Tree,
+                name: name.to_string(),
+                hash,
+            });
+        }
+        tree.write(repo)
+    }
+
+    let mut root = Dir::default();
+    for (path, mode, hash) in files {
+        let mut components = path.split('/').collect::<Vec<_>>();
+        let name = components
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("Empty path"))?;
+        let mut dir = &mut root;
+        for component in components {
+            dir = dir.dirs.entry(component).or_default();
+        }
+        dir.files.push(TreeEntry {
+            mode,
+            name: name.to_string(),
+            hash: hash.clone(),
+        });
+    }
+    write_dir(repo, root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn blob(repo: &Repository, data: &[u8]) -> ObjectHash {
+        store_object(
+            &Object::new(repo.hash_algorithm(), ObjectType::Blob, data.to_vec()).unwrap(),
+            repo,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_entry_mode() {
+        assert_eq!(EntryMode::from_octal("40000").unwrap(), EntryMode::Tree);
+        assert_eq!(
+            EntryMode::from_bits(0o100755).unwrap(),
+            EntryMode::BlobExecutable
+        );
+        assert_eq!(EntryMode::Symlink.bits(), 0o120000);
+        assert!(EntryMode::from_octal("100000").is_err());
+    }
+
+    #[test]
+    fn test_tree_roundtrip() {
+        for algorithm in [HashAlgorithm::Sha1, HashAlgorithm::Sha256] {
+            let hash = algorithm.digest(b"data");
+            let tree = Tree {
+                entries: vec![
+                    TreeEntry {
+                        mode: EntryMode::Blob,
+                        name: "a.txt".to_string(),
+                        hash: hash.clone(),
+                    },
+                    TreeEntry {
+                        mode: EntryMode::Tree,
+                        name: "b".to_string(),
+                        hash,
+                    },
+                ],
+            };
+            assert_eq!(Tree::parse(&tree.serialize(), algorithm).unwrap(), tree);
+        }
+    }
+
+    #[test]
+    fn test_tree_sort_order() {
+        let hash = HashAlgorithm::Sha1.digest("data".as_bytes());
+        let entry = |mode, name: &str| TreeEntry {
+            mode,
+            name: name.to_string(),
+            hash: hash.clone(),
+        };
+        // "foo/" sorts after "foo.txt" because '.' < '/'
+        let tree = Tree {
+            entries: vec![
+                entry(EntryMode::Tree, "foo"),
+                entry(EntryMode::Blob, "foo.txt"),
+            ],
+        };
+        let parsed = Tree::parse(&tree.serialize(), HashAlgorithm::Sha1).unwrap();
+        assert_eq!(parsed.entries[0].name, "foo.txt");
+        assert_eq!(parsed.entries[1].name, "foo");
+    }
+
+    #[test]
+    fn test_parse_truncated_tree() {
+        let result = Tree::parse(b"100644 a.txt\0abc", HashAlgorithm::Sha1);
+        assert!(result.unwrap_err().to_string().contains("truncated hash"));
+    }
+
+    #[test]
+    fn test_verify_name() {
+        for name in ["a.txt", ".gitignore", ".github", "git~2", "..."] {
+            verify_name(name).unwrap();
+        }
+        for name in [
+            "",
+            ".",
+            "..",
+            "a/b",
+            "a\0b",
+            ".git",
+            ".Git",
+            ".git.",
+            ".git ::$INDEX_ALLOCATION",
+            "GIT~1",
+            ".g\u{200c}it",
+        ] {
+            assert!(verify_name(name).is_err(), "{:?}", name);
+        }
+        assert!(verify_path("dir/../a").is_err());
+
+        // Parsing is permissive; only checkouts refuse such names
+        let mut data = b"40000 .git\0".to_vec();
+        data.extend([0u8; 20]);
+        let tree = Tree::parse(&data, HashAlgorithm::Sha1).unwrap();
+        assert_eq!(tree.entries[0].name, ".git");
+    }
+
+    #[test]
+    fn test_build_and_flatten_tree() {
+        let tempdir = TempDir::new().unwrap();
+        let repo = Repository::new(tempdir.path()).unwrap();
+        let a = blob(&repo, b"a");
+        let b = blob(&repo, b"b");
+        let files = [
+            ("a.txt", EntryMode::Blob, &a),
+            ("dir/sub/b.sh", EntryMode::BlobExecutable, &b),
+        ];
+        let root = build_tree(&repo, files).unwrap();
+        let flat = flatten_tree(&repo, &root).unwrap();
+        assert_eq!(flat.len(), 2);
+        assert_eq!(flat["a.txt"], (EntryMode::Blob, a));
+        assert_eq!(flat["dir/sub/b.sh"], (EntryMode::BlobExecutable, b));
+    }
+}
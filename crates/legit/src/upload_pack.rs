@@ -0,0 +1,667 @@
+use crate::bitmap::bitmap_objects;
+use crate::commits::Commit;
+use crate::objects::{object_exists, read_object, ObjectHash, ObjectType};
+use crate::pack_writer::{list_objects, list_objects_with, write_pack, ListOptions};
+use crate::pktline::{
+    encode, write_delim, write_flush, write_line, Packet, PktReader, MAX_DATA_LEN,
+};
+use crate::promisor::ObjectFilter;
+use crate::protocol::{FetchRequest, FetchResponse};
+use crate::refs::{list_refs, read_ref, resolve_ref, RefValue};
+use crate::revision::{peel_tags, tag_target};
+use crate::shallow::{read_shallow, shallow_boundary, Deepen, Grafts, ShallowInfo, INFINITE_DEPTH};
+use crate::transport::RemoteRef;
+use crate::Repository;
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Read, Write};
+
+/// Largest pkt-line payload of the original `side-band` capability
+const SIDEBAND_DATA_LEN: usize = 996;
+
+/// UploadRequest is what a client asked for in a fetch
+#[derive(Debug, Clone, Default)]
+struct UploadRequest {
+    wants: Vec<ObjectHash>,
+    haves: Vec<ObjectHash>,
+    done: bool,
+    thin: bool,
+    include_tag: bool,
+    /// The payload size of side-band packets, `None` for a bare pack
+    sideband: Option<usize>,
+    /// The commits the client is shallow at
+    shallow: Vec<ObjectHash>,
+    deepen: Option<Deepen>,
+    filter: Option<ObjectFilter>,
+}
+
+/// Serve a fetch or clone over a pair of streams, like `git upload-pack`
+///
+/// Protocol version 2 is spoken when the client asked for it through
+/// `GIT_PROTOCOL`, and the original protocol otherwise.
+pub fn upload_pack(
+    repo: &Repository,
+    input: impl Read,
+    mut output: impl Write,
+    protocol_v2: bool,
+) -> Result<()> {
+    let mut reader = PktReader::new(BufReader::new(input));
+    match protocol_v2 {
+        true => serve_v2(repo, &mut reader, &mut output),
+        false => serve_v0(repo, &mut reader, &mut output),
+    }
+}
+
+/// List the references to advertise: `HEAD` first, then every reference
+pub(crate) fn advertised_refs(repo: &Repository) -> Result<Vec<RemoteRef>> {
+    let mut refs = Vec::new();
+    if let Some(hash) = resolve_ref(repo, "HEAD")? {
+        let symref_target = match read_ref(repo, "HEAD")? {
+            Some(RefValue::Symbolic(target)) => Some(target),
+            _ => None,
+        };
+        refs.push((String::from("HEAD"), hash, symref_target));
+    }
+    refs.extend(
+        list_refs(repo, "refs/")?
+            .into_iter()
+            .map(|(name, hash)| (name, hash, None)),
+    );
+    refs.into_iter()
+        .map(|(name, hash, symref_target)| {
+            let peeled = match read_object(repo, &hash)?.object_type {
+                ObjectType::Tag => Some(peel_tags(repo, &hash)?),
+                _ => None,
+            };
+            Ok(RemoteRef {
+                name,
+                hash,
+                symref_target,
+                peeled,
+            })
+        })
+        .collect()
+}
+
+fn capability_list(repo: &Repository, refs: &[RemoteRef]) -> String {
+    let mut capabilities = vec![
+        "thin-pack".to_string(),
+        "side-band".to_string(),
+        "side-band-64k".to_string(),
+        "ofs-delta".to_string(),
+        "no-progress".to_string(),
+        "include-tag".to_string(),
+        format!("object-format={}", repo.hash_algorithm()),
+    ];
+    if let Some(target) = refs.first().and_then(|r| r.symref_target.as_ref()) {
+        capabilities.push(format!("symref=HEAD:{}", target));
+    }
+    capabilities.push(format!("agent=legit/{}", env!("CARGO_PKG_VERSION")));
+    capabilities.join(" ")
+}
+
+/// Serve the original protocol: advertise, negotiate, send one pack
+fn serve_v0(
+    repo: &Repository,
+    reader: &mut PktReader<impl BufRead>,
+    output: &mut impl Write,
+) -> Result<()> {
+    let refs = advertised_refs(repo)?;
+    let capabilities = capability_list(repo, &refs);
+    if refs.is_empty() {
+        let null = repo.hash_algorithm().null_hash();
+        let line = format!("{} capabilities^{{}}\0{}", null, capabilities);
+        write_line(output, &line)?;
+    }
+    for (i, remote_ref) in refs.iter().enumerate() {
+        let line = match i {
+            0 => format!("{} {}\0{}", remote_ref.hash, remote_ref.name, capabilities),
+            _ => format!("{} {}", remote_ref.hash, remote_ref.name),
+        };
+        write_line(output, &line)?;
+        if let Some(peeled) = &remote_ref.peeled {
+            write_line(output, &format!("{} {}^{{}}", peeled, remote_ref.name))?;
+        }
+    }
+    write_flush(output)?;
+    output.flush()?;
+
+    // A client that wants nothing, like `ls-remote`, hangs up here
+    if reader.get_mut().fill_buf()?.is_empty() {
+        return Ok(());
+    }
+    let mut request = UploadRequest::default();
+    while let Some(line) = reader.read_line()? {
+        let Some(rest) = line.strip_prefix("want ") else {
+            bail!("protocol error: expected want, got '{}'", line);
+        };
+        let (hash, features) = rest.split_once(' ').unwrap_or((rest, ""));
+        request
+            .wants
+            .push(ObjectHash::from_hex(hash, repo.hash_algorithm())?);
+        for feature in features.split(' ') {
+            match feature {
+                "thin-pack" => request.thin = true,
+                "include-tag" => request.include_tag = true,
+                "side-band" => request.sideband = Some(SIDEBAND_DATA_LEN),
+                "side-band-64k" => request.sideband = Some(MAX_DATA_LEN),
+                _ => {}
+            }
+        }
+    }
+    if request.wants.is_empty() {
+        return Ok(());
+    }
+    check_wants(repo, &request.wants, false)?;
+
+    // Without multi_ack, the first common commit is acknowledged at once and
+    // every round of haves before it is answered with NAK
+    loop {
+        match reader.read_packet()? {
+            Packet::Data(data) => {
+                let line = String::from_utf8_lossy(&data);
+                let line = line.trim_end();
+                if line == "done" {
+                    break;
+                }
+                let Some(hash) = line.strip_prefix("have ") else {
+                    bail!("protocol error: expected have, got '{}'", line);
+                };
+                let hash = ObjectHash::from_hex(hash, repo.hash_algorithm())?;
+                if object_exists(repo, &hash) {
+                    request.haves.push(hash.clone());
+                    if request.haves.len() == 1 {
+                        write_line(output, &format!("ACK {}", hash))?;
+                        output.flush()?;
+                    }
+                }
+            }
+            _ => {
+                if request.haves.is_empty() {
+                    write_line(output, "NAK")?;
+                    output.flush()?;
+                }
+            }
+        }
+    }
+    if request.haves.is_empty() {
+        write_line(output, "NAK")?;
+    }
+    let (pack, _) = pack_objects(repo, &request)?;
+    send_pack(&pack, request.sideband, output)
+}
+
+/// Serve protocol version 2: advertise capabilities, then answer commands
+/// until the client hangs up
+fn serve_v2(
+    repo: &Repository,
+    reader: &mut PktReader<impl BufRead>,
+    output: &mut impl Write,
+) -> Result<()> {
+    write_line(output, "version 2")?;
+    write_line(
+        output,
+        &format!("agent=legit/{}", env!("CARGO_PKG_VERSION")),
+    )?;
+    write_line(output, "ls-refs=unborn")?;
+    write_line(output, "fetch=shallow filter")?;
+    write_line(output, "server-option")?;
+    write_line(output, &format!("object-format={}", repo.hash_algorithm()))?;
+    write_flush(output)?;
+    output.flush()?;
+
+    loop {
+        if reader.get_mut().fill_buf()?.is_empty() {
+            return Ok(());
+        }
+        let command = match reader.read_packet()? {
+            Packet::Data(data) => String::from_utf8_lossy(&data).trim_end().to_string(),
+            Packet::Flush => return Ok(()),
+            _ => bail!("protocol error: expected a command"),
+        };
+        let Some(command) = command.strip_prefix("command=") else {
+            bail!("protocol error: expected a command, got '{}'", command);
+        };
+        // Capabilities such as `agent` come before the arguments
+        let mut arguments = Vec::new();
+        let mut in_arguments = false;
+        loop {
+            match reader.read_packet()? {
+                Packet::Data(data) if in_arguments => {
+                    arguments.push(String::from_utf8_lossy(&data).trim_end().to_string())
+                }
+                Packet::Data(_) => {}
+                Packet::Delim => in_arguments = true,
+                _ => break,
+            }
+        }
+        match command {
+            "ls-refs" => ls_refs(repo, &arguments, output)?,
+            "fetch" => fetch(repo, &arguments, output)?,
+            _ => bail!("unknown command '{}'", command),
+        }
+        output.flush()?;
+    }
+}
+
+/// Answer `ls-refs`, honouring `peel`, `symrefs`, `unborn` and
+/// `ref-prefix`
+fn ls_refs(repo: &Repository, arguments: &[String], output: &mut impl Write) -> Result<()> {
+    let has = |name: &str| arguments.iter().any(|a| a == name);
+    let prefixes = arguments
+        .iter()
+        .filter_map(|a| a.strip_prefix("ref-prefix "))
+        .collect::<Vec<_>>();
+    let wanted = |name: &str| prefixes.is_empty() || prefixes.iter().any(|p| name.starts_with(p));
+    let refs = advertised_refs(repo)?;
+    if has("unborn") && wanted("HEAD") && !refs.iter().any(|r| r.name == "HEAD") {
+        if let Some(RefValue::Symbolic(target)) = read_ref(repo, "HEAD")? {
+            write_line(output, &format!("unborn HEAD symref-target:{}", target))?;
+        }
+    }
+    for remote_ref in refs.iter().filter(|r| wanted(&r.name)) {
+        let mut line = format!("{} {}", remote_ref.hash, remote_ref.name);
+        if let (true, Some(target)) = (has("symrefs"), &remote_ref.symref_target) {
+            line.push_str(&format!(" symref-target:{}", target));
+        }
+        if let (true, Some(peeled)) = (has("peel"), &remote_ref.peeled) {
+            line.push_str(&format!(" peeled:{}", peeled));
+        }
+        write_line(output, &line)?;
+    }
+    write_flush(output)
+}
+
+/// Answer `fetch`: acknowledge the common commits, and send the pack once
+/// the client is done or some commit is known to be common
+fn fetch(repo: &Repository, arguments: &[String], output: &mut impl Write) -> Result<()> {
+    let mut request = UploadRequest {
+        sideband: Some(MAX_DATA_LEN),
+        ..UploadRequest::default()
+    };
+    let algorithm = repo.hash_algorithm();
+    for argument in arguments {
+        let (name, value) = argument.split_once(' ').unwrap_or((argument, ""));
+        match name {
+            "want" => request.wants.push(ObjectHash::from_hex(value, algorithm)?),
+            "have" => {
+                let hash = ObjectHash::from_hex(value, algorithm)?;
+                if object_exists(repo, &hash) {
+                    request.haves.push(hash);
+                }
+            }
+            "done" => request.done = true,
+            "thin-pack" => request.thin = true,
+            "include-tag" => request.include_tag = true,
+            "shallow" => request
+                .shallow
+                .push(ObjectHash::from_hex(value, algorithm)?),
+            "deepen" | "deepen-since" => {
+                if request.deepen.is_some() {
+                    bail!("deepen and deepen-since cannot be used together");
+                }
+                request.deepen = Some(match (name, value.parse::<i64>()) {
+                    ("deepen", Ok(depth)) if depth > 0 && depth <= INFINITE_DEPTH as i64 => {
+                        Deepen::Depth(depth as u32)
+                    }
+                    ("deepen-since", Ok(time)) => Deepen::Since(time),
+                    _ => bail!("invalid {} argument '{}'", name, value),
+                })
+            }
+            "deepen-not" | "deepen-relative" => bail!("{} is not supported", name),
+            "filter" => request.filter = Some(value.parse()?),
+            _ => {}
+        }
+    }
+    check_wants(repo, &request.wants, true)?;
+
+    if !request.done {
+        write_line(output, "acknowledgments")?;
+        if request.haves.is_empty() {
+            write_line(output, "NAK")?;
+            return write_flush(output);
+        }
+        for hash in &request.haves {
+            write_line(output, &format!("ACK {}", hash))?;
+        }
+        write_line(output, "ready")?;
+        write_delim(output)?;
+    }
+    let (pack, info) = pack_objects(repo, &request)?;
+    if request.deepen.is_some() || !info.shallow.is_empty() {
+        write_line(output, "shallow-info")?;
+        for hash in &info.shallow {
+            write_line(output, &format!("shallow {}", hash))?;
+        }
+        for hash in &info.unshallow {
+            write_line(output, &format!("unshallow {}", hash))?;
+        }
+        write_delim(output)?;
+    }
+    write_line(output, "packfile")?;
+    send_pack(&pack, request.sideband, output)
+}
+
+/// Answer a fetch request without a server, as a fetch from a repository
+/// on disk does
+pub(crate) fn local_fetch(repo: &Repository, request: &FetchRequest) -> Result<FetchResponse> {
+    let request = UploadRequest {
+        wants: request.wants.clone(),
+        haves: request
+            .haves
+            .iter()
+            .filter(|hash| object_exists(repo, hash))
+            .cloned()
+            .collect(),
+        done: true,
+        include_tag: true,
+        shallow: request.shallow.clone(),
+        deepen: request.deepen,
+        filter: request.filter,
+        ..UploadRequest::default()
+    };
+    check_wants(repo, &request.wants, true)?;
+    let (pack, info) = pack_objects(repo, &request)?;
+    Ok(FetchResponse {
+        pack,
+        shallow: info.shallow,
+        unshallow: info.unshallow,
+    })
+}
+
+/// Find where the pack leaves the client's history, with the grafts to
+/// list its objects with
+///
+/// Without deepening, the client's history still ends at its shallow
+/// commits, and at ours.
+fn shallow_info(repo: &Repository, request: &UploadRequest) -> Result<ShallowInfo> {
+    let client = request
+        .shallow
+        .iter()
+        .filter(|hash| object_exists(repo, hash))
+        .cloned()
+        .collect::<HashSet<_>>();
+    if let Some(deepen) = request.deepen {
+        return shallow_boundary(repo, &request.wants, deepen, &client);
+    }
+    let mut grafts = Grafts::of(repo)?;
+    grafts.include.extend(client.iter().cloned());
+    grafts.exclude.extend(client);
+    Ok(ShallowInfo {
+        grafts,
+        ..ShallowInfo::default()
+    })
+}
+
+/// Refuse requests for objects the references do not let the client see
+///
+/// The advertised tips are always allowed. Other objects must be reachable
+/// from a reference when `reachable` is set, as for the stateless protocol
+/// v2, or `uploadpack.allowReachableSHA1InWant` is on, and may be anything
+/// with `uploadpack.allowAnySHA1InWant`.
+fn check_wants(repo: &Repository, wants: &[ObjectHash], reachable: bool) -> Result<()> {
+    if let Some(hash) = wants.iter().find(|hash| !object_exists(repo, hash)) {
+        bail!("upload-pack: not our ref {}", hash);
+    }
+    let config = repo.config()?;
+    let allow = |key: &str| matches!(config.get_bool(key), Ok(Some(true)));
+    if allow("uploadpack.allowAnySHA1InWant") {
+        return Ok(());
+    }
+    let tips = advertised_refs(repo)?
+        .into_iter()
+        .flat_map(|r| [Some(r.hash), r.peeled])
+        .flatten()
+        .collect::<Vec<_>>();
+    let others = wants
+        .iter()
+        .filter(|hash| !tips.contains(hash))
+        .collect::<Vec<_>>();
+    let Some(first) = others.first() else {
+        return Ok(());
+    };
+    if !reachable && !allow("uploadpack.allowReachableSHA1InWant") {
+        bail!("upload-pack: not our ref {}", first);
+    }
+    let reached = list_objects(repo, &tips, &[])?
+        .objects
+        .into_iter()
+        .map(|(hash, _)| hash)
+        .collect::<HashSet<_>>();
+    match others.into_iter().find(|hash| !reached.contains(*hash)) {
+        Some(hash) => bail!("upload-pack: not our ref {}", hash),
+        None => Ok(()),
+    }
+}
+
+/// Build the pack of what the client wants and does not have, with the
+/// tags pointing into it if asked for, and where it leaves the client's
+/// history
+///
+/// Our own shallow commits the pack holds are shallow for the client too.
+fn pack_objects(repo: &Repository, request: &UploadRequest) -> Result<(Vec<u8>, ShallowInfo)> {
+    let mut info = shallow_info(repo, request)?;
+    let bitmap = match info.grafts == Grafts::default() && request.filter.is_none() {
+        true => bitmap_objects(repo, &request.wants, &request.haves)?,
+        false => None,
+    };
+    let mut list = match bitmap {
+        Some(list) => list,
+        None => {
+            let options = ListOptions {
+                grafts: info.grafts.clone(),
+                filter: request.filter,
+                ..ListOptions::default()
+            };
+            // As in git, the parents of the commits the client gets the
+            // history of are wanted, since its tips already include them
+            let mut wants = request.wants.clone();
+            for hash in &info.unshallow {
+                wants.extend(Commit::read(repo, hash)?.parents);
+            }
+            list_objects_with(repo, &wants, &request.haves, &options)?
+        }
+    };
+    let own = read_shallow(repo)?;
+    info.shallow.extend(
+        list.objects
+            .iter()
+            .map(|(hash, _)| hash)
+            .filter(|hash| own.contains(*hash) && !request.shallow.contains(*hash))
+            .filter(|hash| !info.shallow.contains(*hash))
+            .cloned()
+            .collect::<Vec<_>>(),
+    );
+    if request.include_tag {
+        let mut sent = list
+            .objects
+            .iter()
+            .map(|(hash, _)| hash.clone())
+            .collect::<HashSet<_>>();
+        for hash in list_refs(repo, "refs/tags/")?.into_values() {
+            // Follow chains of tags down to an object being sent
+            let mut chain = Vec::new();
+            let mut current = hash;
+            while !sent.contains(&current) {
+                let object = read_object(repo, &current)?;
+                if object.object_type != ObjectType::Tag {
+                    chain.clear();
+                    break;
+                }
+                chain.push(current);
+                current = tag_target(&object.data, repo.hash_algorithm())?;
+            }
+            for tag in chain {
+                sent.insert(tag.clone());
+                list.objects.push((tag, String::new()));
+            }
+        }
+    }
+    Ok((write_pack(repo, &list, request.thin)?, info))
+}
+
+/// Send a pack, in side-band packets of `sideband` bytes if set
+fn send_pack(pack: &[u8], sideband: Option<usize>, output: &mut impl Write) -> Result<()> {
+    match sideband {
+        Some(size) => {
+            for chunk in pack.chunks(size - 1) {
+                output.write_all(&encode(&[&[1u8][..], chunk].concat())?)?;
+            }
+            write_flush(output)?;
+        }
+        None => output.write_all(pack)?,
+    }
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pack::store_pack;
+    use crate::protocol::{read_fetch_response, Capabilities};
+    use crate::test_utils::{commit_files, init_repo, write_commit};
+
+    /// Run a v2 `fetch` with `arguments` against `source`
+    fn fetch_v2(source: &Repository, arguments: &[String]) -> Result<FetchResponse> {
+        let mut input = Vec::new();
+        write_line(&mut input, "command=fetch")?;
+        write_delim(&mut input)?;
+        for argument in arguments {
+            write_line(&mut input, argument)?;
+        }
+        write_flush(&mut input)?;
+        let mut output = Vec::new();
+        upload_pack(source, &input[..], &mut output, true)?;
+        let mut reader = PktReader::new(&output[..]);
+        Capabilities::read(&mut reader)?;
+        read_fetch_response(&mut reader, source.hash_algorithm(), false)
+    }
+
+    #[test]
+    fn test_serve_ls_refs_and_fetch() {
+        let (_dir, source) = init_repo();
+        commit_files(&source, &[("a.txt", "one\n")], "first");
+        let head = commit_files(&source, &[("a.txt", "two\n")], "second");
+
+        // A v2 session: ls-refs then a fetch that is done at once
+        let mut input = Vec::new();
+        write_line(&mut input, "command=ls-refs").unwrap();
+        write_delim(&mut input).unwrap();
+        write_line(&mut input, "symrefs").unwrap();
+        write_flush(&mut input).unwrap();
+        write_line(&mut input, "command=fetch").unwrap();
+        write_delim(&mut input).unwrap();
+        write_line(&mut input, &format!("want {}", head)).unwrap();
+        write_line(&mut input, "done").unwrap();
+        write_flush(&mut input).unwrap();
+        let mut output = Vec::new();
+        upload_pack(&source, &input[..], &mut output, true).unwrap();
+
+        let mut reader = PktReader::new(&output[..]);
+        let capabilities = Capabilities::read(&mut reader).unwrap();
+        assert!(capabilities.supports("ls-refs", "unborn"));
+        assert_eq!(
+            reader.read_line().unwrap().unwrap(),
+            format!("{} HEAD symref-target:refs/heads/master", head)
+        );
+        assert_eq!(
+            reader.read_line().unwrap().unwrap(),
+            format!("{} refs/heads/master", head)
+        );
+        assert_eq!(reader.read_line().unwrap(), None);
+        let response = read_fetch_response(&mut reader, source.hash_algorithm(), false).unwrap();
+        let (_dir, repo) = init_repo();
+        store_pack(&repo, &response.pack).unwrap();
+        assert!(object_exists(&repo, &head));
+    }
+
+    #[test]
+    fn test_check_wants() {
+        let (_dir, repo) = init_repo();
+        let first = commit_files(&repo, &[("a.txt", "one\n")], "first");
+        let head = commit_files(&repo, &[("a.txt", "two\n")], "second");
+        let dangling = write_commit(&repo, &[("b.txt", "secret\n")], &[], "dangling");
+
+        check_wants(&repo, std::slice::from_ref(&head), false).unwrap();
+        // Reachable objects need protocol v2 or allowReachableSHA1InWant
+        assert!(check_wants(&repo, std::slice::from_ref(&first), false).is_err());
+        check_wants(&repo, std::slice::from_ref(&first), true).unwrap();
+        assert!(check_wants(&repo, std::slice::from_ref(&dangling), true).is_err());
+
+        let mut config = repo.config().unwrap();
+        config
+            .set("uploadpack.allowReachableSHA1InWant", "true")
+            .unwrap();
+        repo.write_config(&config).unwrap();
+        check_wants(&repo, std::slice::from_ref(&first), false).unwrap();
+        assert!(check_wants(&repo, std::slice::from_ref(&dangling), false).is_err());
+        config.set("uploadpack.allowAnySHA1InWant", "true").unwrap();
+        repo.write_config(&config).unwrap();
+        check_wants(&repo, std::slice::from_ref(&dangling), false).unwrap();
+    }
+
+    #[test]
+    fn test_fetch_refuses_unreachable_want() {
+        let (_dir, source) = init_repo();
+        let head = commit_files(&source, &[("a.txt", "one\n")], "first");
+        let dangling = write_commit(&source, &[("b.txt", "secret\n")], &[], "dangling");
+        let error =
+            fetch_v2(&source, &[format!("want {}", dangling), "done".to_string()]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("upload-pack: not our ref {}", dangling)
+        );
+        let arguments = [format!("want {}", head), "done".to_string()];
+        assert!(fetch_v2(&source, &arguments).is_ok());
+    }
+
+    #[test]
+    fn test_fetch_shallow_and_filtered() {
+        let (_dir, source) = init_repo();
+        let first = commit_files(&source, &[("a.txt", "one\n")], "first");
+        let second = commit_files(&source, &[("a.txt", "two\n"), ("d/b.txt", "b\n")], "second");
+        let files = [("a.txt", "three\n"), ("d/b.txt", "b\n")];
+        let head = commit_files(&source, &files, "third");
+        let filter = "filter blob:none".to_string();
+
+        let arguments = [
+            format!("want {}", head),
+            "deepen 1".to_string(),
+            filter.clone(),
+            "done".to_string(),
+        ];
+        let response = fetch_v2(&source, &arguments).unwrap();
+        assert_eq!(response.shallow, std::slice::from_ref(&head));
+        assert!(response.unshallow.is_empty());
+        let (_dir, repo) = init_repo();
+        let pack = store_pack(&repo, &response.pack).unwrap().unwrap();
+        // The commit and its two trees, without blobs or parents
+        assert_eq!(pack.index().len(), 3);
+        assert!(object_exists(&repo, &head));
+        assert!(!object_exists(&repo, &second));
+
+        // A client shallow at the tip deepens by one more commit
+        let arguments = [
+            format!("want {}", head),
+            format!("have {}", head),
+            format!("shallow {}", head),
+            "deepen 2".to_string(),
+            filter,
+            "done".to_string(),
+        ];
+        let response = fetch_v2(&source, &arguments).unwrap();
+        assert_eq!(response.shallow, std::slice::from_ref(&second));
+        assert_eq!(response.unshallow, [head]);
+        store_pack(&repo, &response.pack).unwrap();
+        assert!(object_exists(&repo, &second));
+        assert!(!object_exists(&repo, &first));
+        let tree = crate::commits::Commit::read(&repo, &second).unwrap().tree;
+        for entry in crate::tree::Tree::read(&repo, &tree).unwrap().entries {
+            assert_eq!(
+                entry.mode == crate::tree::EntryMode::Tree,
+                object_exists(&repo, &entry.hash)
+            );
+        }
+    }
+}
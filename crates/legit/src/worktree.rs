@@ -0,0 +1,586 @@
+use crate::checkout::checkout_tree;
+use crate::objects::ObjectHash;
+use crate::refs::{delete_ref, read_head, resolve_ref, set_head, update_ref, Head, RefStorage};
+use crate::reftable;
+use crate::revision::{peel_to_commit, peel_to_tree, rev_parse};
+use crate::status::{status, StatusOptions};
+use crate::Repository;
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Worktree is a working tree of a repository, with its own HEAD and index
+#[derive(Debug, Clone, PartialEq)]
+pub struct Worktree {
+    /// The name of its directory under `worktrees/`, `None` for the main
+    /// worktree
+    pub name: Option<String>,
+    /// The path of the working tree
+    pub path: PathBuf,
+    /// The git directory holding its HEAD and index
+    pub gitdir: PathBuf,
+    /// True for the main worktree of a bare repository, which has no files
+    pub bare: bool,
+    /// Why the worktree is locked against pruning, moving and removal;
+    /// empty when no reason was given
+    pub locked: Option<String>,
+    /// Why the worktree can be pruned, if its working tree is gone
+    pub prunable: Option<String>,
+}
+
+impl Worktree {
+    /// Open the repository as seen from the worktree
+    pub fn open(&self) -> Result<Repository> {
+        Repository::load(&self.path, self.gitdir.clone())
+    }
+
+    /// Return what HEAD of the worktree points to, `None` if it is unborn
+    /// or unreadable
+    pub fn head(&self) -> Option<(Head, Option<ObjectHash>)> {
+        let repo = self.open().ok()?;
+        let head = read_head(&repo).ok()?;
+        Some((head, resolve_ref(&repo, "HEAD").ok()?))
+    }
+}
+
+/// WorktreeAddOptions controls how `add_worktree` sets up the new worktree
+#[derive(Debug, Clone, Default)]
+pub struct WorktreeAddOptions {
+    /// Create a branch with this name at the commit and check it out
+    pub new_branch: Option<String>,
+    /// Detach HEAD at the commit even if it names a branch
+    pub detach: bool,
+    /// Check out a branch even if another worktree has it checked out
+    pub force: bool,
+    /// Do not check out the files of the commit
+    pub no_checkout: bool,
+    /// Lock the worktree, with this reason, which may be empty
+    pub lock: Option<String>,
+}
+
+fn worktrees_dir(repo: &Repository) -> PathBuf {
+    repo.commondir().join("worktrees")
+}
+
+/// Return the path of the main worktree: the parent of the common `.git`
+/// directory, or the directory itself in a bare repository
+fn main_worktree(repo: &Repository) -> (PathBuf, bool) {
+    let commondir = canonical(repo.commondir());
+    match (commondir.file_name(), commondir.parent()) {
+        (Some(name), Some(parent)) if name == ".git" => (parent.to_owned(), false),
+        _ => (commondir.clone(), true),
+    }
+}
+
+/// Read the `gitdir` file of a linked worktree, pointing to the `.git` file
+/// of its working tree; `Err` tells why the worktree can be pruned
+fn read_gitdir_file(admin: &Path) -> Result<PathBuf, String> {
+    if !admin.is_dir() {
+        return Err("not a valid directory".to_string());
+    }
+    let content = fs::read_to_string(admin.join("gitdir"))
+        .map_err(|_| "gitdir file does not exist".to_string())?;
+    let dotgit = content.trim_end_matches(['\n', '\r']);
+    if dotgit.is_empty() {
+        return Err("invalid gitdir file".to_string());
+    }
+    Ok(admin.join(dotgit))
+}
+
+fn read_linked(admin: &Path, name: &str) -> Worktree {
+    let locked = fs::read_to_string(admin.join("locked")).ok();
+    let (path, prunable) = match read_gitdir_file(admin) {
+        Ok(dotgit) => {
+            let prunable = (!dotgit.exists())
+                .then(|| "gitdir file points to non-existent location".to_string());
+            let path = dotgit.parent().map(Path::to_owned).unwrap_or(dotgit);
+            (path, prunable)
+        }
+        Err(reason) => (admin.to_owned(), Some(reason)),
+    };
+    Worktree {
+        name: Some(name.to_string()),
+        path,
+        gitdir: admin.to_owned(),
+        bare: false,
+        // A locked worktree is kept even when its files are out of reach
+        prunable: prunable.filter(|_| locked.is_none()),
+        locked: locked.map(|reason| reason.trim_end().to_string()),
+    }
+}
+
+/// List the worktrees of the repository, the main one first
+pub fn list_worktrees(repo: &Repository) -> Result<Vec<Worktree>> {
+    let (path, bare) = main_worktree(repo);
+    let mut worktrees = vec![Worktree {
+        name: None,
+        path,
+        gitdir: repo.commondir().to_owned(),
+        bare,
+        locked: None,
+        prunable: None,
+    }];
+    let Ok(entries) = fs::read_dir(worktrees_dir(repo)) else {
+        return Ok(worktrees);
+    };
+    let mut linked = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        linked.push(read_linked(&entry.path(), &name));
+    }
+    linked.sort_by(|a, b| a.name.cmp(&b.name));
+    worktrees.extend(linked);
+    Ok(worktrees)
+}
+
+/// List the worktrees other than the one `repo` was opened from
+pub(crate) fn other_worktrees(repo: &Repository) -> Result<Vec<Worktree>> {
+    Ok(list_worktrees(repo)?
+        .into_iter()
+        .filter(|worktree| canonical(&worktree.gitdir) != canonical(repo.gitdir()))
+        .collect())
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
+
+/// Return the path of the other worktree that has a branch checked out
+pub fn checked_out_elsewhere(repo: &Repository, branch: &str) -> Result<Option<PathBuf>> {
+    Ok(other_worktrees(repo)?
+        .into_iter()
+        .find(|worktree| {
+            !worktree.bare
+                && matches!(worktree.head(), Some((Head::Branch(name), _)) if name == branch)
+        })
+        .map(|worktree| worktree.path))
+}
+
+/// Find a linked worktree by its path, or by the last component of its
+/// path when no other worktree shares it
+pub fn find_worktree(repo: &Repository, spec: &str) -> Result<Worktree> {
+    let worktrees = list_worktrees(repo)?;
+    let wanted = canonical(Path::new(spec));
+    let by_path = worktrees
+        .iter()
+        .find(|worktree| canonical(&worktree.path) == wanted);
+    let by_name = || {
+        let mut matches = worktrees
+            .iter()
+            .filter(|worktree| worktree.path.file_name().is_some_and(|name| name == spec));
+        match (matches.next(), matches.next()) {
+            (Some(worktree), None) => Some(worktree),
+            _ => None,
+        }
+    };
+    let Some(worktree) = by_path.or_else(by_name) else {
+        bail!("'{}' is not a working tree", spec);
+    };
+    if worktree.name.is_none() {
+        bail!("'{}' is a main working tree", spec);
+    }
+    Ok(worktree.clone())
+}
+
+/// Pick the name of the directory of a new worktree under `worktrees/`:
+/// the last component of its path, numbered when already taken
+fn admin_name(repo: &Repository, path: &Path) -> String {
+    let base = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "worktree".to_string());
+    let dir = worktrees_dir(repo);
+    let mut name = base.clone();
+    let mut counter = 1;
+    while dir.join(&name).exists() {
+        name = format!("{}{}", base, counter);
+        counter += 1;
+    }
+    name
+}
+
+/// Create a linked worktree at `path` checking out `commitish`, like
+/// `git worktree add`
+///
+/// Without a commit, a branch named after the last component of the path
+/// is checked out, and created at HEAD if it does not exist yet. A branch
+/// is only checked out in one worktree at a time.
+pub fn add_worktree(
+    repo: &Repository,
+    path: &Path,
+    commitish: Option<&str>,
+    options: &WorktreeAddOptions,
+) -> Result<Repository> {
+    if path.exists() && fs::read_dir(path)?.next().is_some() {
+        bail!("'{}' already exists", path.display());
+    }
+    let branch_of = |name: &str| -> Result<Option<String>> {
+        let name = format!("refs/heads/{}", name);
+        Ok(resolve_ref(repo, &name)?.map(|_| name))
+    };
+    let (head, commit, create) = match (&options.new_branch, commitish) {
+        (Some(branch), _) => {
+            let commit = peel_to_commit(repo, &rev_parse(repo, commitish.unwrap_or("HEAD"))?)?;
+            if branch_of(branch)?.is_some() {
+                bail!("A branch named '{}' already exists", branch);
+            }
+            let name = format!("refs/heads/{}", branch);
+            (Head::Branch(name), commit, true)
+        }
+        (None, Some(spec)) => {
+            let commit = peel_to_commit(repo, &rev_parse(repo, spec)?)?;
+            match branch_of(spec)? {
+                Some(name) if !options.detach => (Head::Branch(name), commit, false),
+                _ => (Head::Detached(commit.clone()), commit, false),
+            }
+        }
+        (None, None) => {
+            let commit = peel_to_commit(repo, &rev_parse(repo, "HEAD")?)?;
+            let base = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .context("The worktree path has no name")?;
+            match (options.detach, branch_of(&base)?) {
+                (true, _) => (Head::Detached(commit.clone()), commit, false),
+                (false, Some(name)) => {
+                    let commit = resolve_ref(repo, &name)?.unwrap_or(commit);
+                    (Head::Branch(name), commit, false)
+                }
+                (false, None) => (Head::Branch(format!("refs/heads/{}", base)), commit, true),
+            }
+        }
+    };
+    if let (Head::Branch(name), false, false) = (&head, create, options.force) {
+        if let Some(other) = checked_out_elsewhere(repo, name)? {
+            bail!(
+                "'{}' is already checked out at '{}'",
+                name.trim_start_matches("refs/heads/"),
+                other.display()
+            );
+        }
+    }
+
+    // An existing empty directory is used as it is, and left so on failure
+    let created = !path.exists();
+    fs::create_dir_all(path)?;
+    let path = fs::canonicalize(path)?;
+    let admin = worktrees_dir(repo).join(admin_name(repo, &path));
+    fs::create_dir_all(&admin)?;
+    let admin = fs::canonicalize(&admin)?;
+    let result = (|| {
+        // Lock the worktree while it is set up, so that nothing prunes it
+        fs::write(admin.join("locked"), "initializing")?;
+        fs::write(
+            admin.join("gitdir"),
+            format!("{}\n", path.join(".git").display()),
+        )?;
+        fs::write(admin.join("commondir"), "../..\n")?;
+        if repo.ref_storage() == RefStorage::Reftable {
+            reftable::init(&admin)?;
+        }
+        fs::write(path.join(".git"), format!("gitdir: {}\n", admin.display()))?;
+        let worktree = Repository::load(&path, admin.clone())?;
+        if let (true, Head::Branch(name)) = (create, &head) {
+            update_ref(&worktree, name, &commit)?;
+        }
+        if !options.no_checkout {
+            checkout_tree(&worktree, &peel_to_tree(repo, &commit)?, true)?;
+        }
+        set_head(&worktree, &head)?;
+        match &options.lock {
+            Some(reason) => fs::write(admin.join("locked"), reason)?,
+            None => fs::remove_file(admin.join("locked"))?,
+        }
+        Ok(worktree)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_dir_all(&admin);
+        match created {
+            true => {
+                let _ = fs::remove_dir_all(&path);
+            }
+            false => {
+                for entry in fs::read_dir(&path).into_iter().flatten().flatten() {
+                    let _ = match entry.file_type().is_ok_and(|t| t.is_dir()) {
+                        true => fs::remove_dir_all(entry.path()),
+                        false => fs::remove_file(entry.path()),
+                    };
+                }
+            }
+        }
+        // Nothing else can have the branch created for the worktree yet
+        if let (true, Head::Branch(name)) = (create, &head) {
+            if matches!(resolve_ref(repo, name), Ok(Some(_))) {
+                let _ = delete_ref(repo, name);
+            }
+        }
+    }
+    result
+}
+
+/// Lock a linked worktree against pruning, moving and removal
+pub fn lock_worktree(repo: &Repository, spec: &str, reason: &str) -> Result<()> {
+    let worktree = find_worktree(repo, spec)?;
+    match worktree.locked {
+        Some(reason) if !reason.is_empty() => {
+            bail!("'{}' is already locked, reason: {}", spec, reason)
+        }
+        Some(_) => bail!("'{}' is already locked", spec),
+        None => Ok(fs::write(worktree.gitdir.join("locked"), reason)?),
+    }
+}
+
+/// Unlock a linked worktree
+pub fn unlock_worktree(repo: &Repository, spec: &str) -> Result<()> {
+    let worktree = find_worktree(repo, spec)?;
+    if worktree.locked.is_none() {
+        bail!("'{}' is not locked", spec);
+    }
+    Ok(fs::remove_file(worktree.gitdir.join("locked"))?)
+}
+
+/// Refuse to move or remove a locked worktree unless forced twice
+fn check_unlocked(worktree: &Worktree, action: &str, force: u8) -> Result<()> {
+    match &worktree.locked {
+        Some(_) if force >= 2 => Ok(()),
+        Some(reason) if !reason.is_empty() => bail!(
+            "cannot {} a locked working tree, lock reason: {}\nuse '{} -f -f' to override or unlock first",
+            action,
+            reason,
+            action
+        ),
+        Some(_) => bail!(
+            "cannot {} a locked working tree;\nuse '{} -f -f' to override or unlock first",
+            action,
+            action
+        ),
+        None => Ok(()),
+    }
+}
+
+/// Move a linked worktree to `to`, or into `to` if it is a directory
+///
+/// `force` given twice moves a locked worktree.
+pub fn move_worktree(repo: &Repository, spec: &str, to: &Path, force: u8) -> Result<PathBuf> {
+    let worktree = find_worktree(repo, spec)?;
+    check_unlocked(&worktree, "move", force)?;
+    let target = match (to.is_dir(), worktree.path.file_name()) {
+        (true, Some(name)) => to.join(name),
+        _ => to.to_owned(),
+    };
+    if target.exists() {
+        bail!("target '{}' already exists", target.display());
+    }
+    fs::rename(&worktree.path, &target).with_context(|| {
+        format!(
+            "Failed to move '{}' to '{}'",
+            worktree.path.display(),
+            target.display()
+        )
+    })?;
+    let target = fs::canonicalize(&target)?;
+    fs::write(
+        worktree.gitdir.join("gitdir"),
+        format!("{}\n", target.join(".git").display()),
+    )?;
+    Ok(target)
+}
+
+/// Remove a linked worktree and its files
+///
+/// Unless `force` is set, a worktree with local changes or untracked files
+/// is kept; `force` given twice removes a locked worktree.
+pub fn remove_worktree(repo: &Repository, spec: &str, force: u8) -> Result<()> {
+    let worktree = find_worktree(repo, spec)?;
+    check_unlocked(&worktree, "remove", force)?;
+    if worktree.path.is_dir() {
+        if force == 0 {
+            let status = status(&worktree.open()?, &StatusOptions::default())?;
+            if !status.changes.is_empty()
+                || !status.conflicts.is_empty()
+                || !status.untracked.is_empty()
+            {
+                bail!(
+                    "'{}' contains modified or untracked files, use --force to delete it",
+                    spec
+                );
+            }
+        }
+        fs::remove_dir_all(&worktree.path)
+            .with_context(|| format!("Failed to delete '{}'", worktree.path.display()))?;
+    }
+    fs::remove_dir_all(&worktree.gitdir)?;
+    remove_empty_worktrees_dir(repo);
+    Ok(())
+}
+
+/// Remove the `worktrees/` directory once the last linked worktree is gone
+fn remove_empty_worktrees_dir(repo: &Repository) {
+    let _ = fs::remove_dir(worktrees_dir(repo));
+}
+
+/// Delete the git directories of the worktrees whose working tree is gone,
+/// like `git worktree prune`
+///
+/// Returns the name of every pruned worktree with the reason.
+pub fn prune_worktrees(repo: &Repository, dry_run: bool) -> Result<Vec<(String, String)>> {
+    let mut pruned = Vec::new();
+    for worktree in list_worktrees(repo)? {
+        let (Some(name), Some(reason)) = (worktree.name, worktree.prunable) else {
+            continue;
+        };
+        if !dry_run {
+            fs::remove_dir_all(&worktree.gitdir)?;
+        }
+        pruned.push((name, reason));
+    }
+    if !dry_run {
+        remove_empty_worktrees_dir(repo);
+    }
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkout::{checkout, CheckoutOptions};
+    use crate::commits::Commit;
+    use crate::gc::reachable_objects;
+    use crate::objects::{write_object, HashAlgorithm, Object, ObjectType};
+    use crate::test_utils::{commit_files, init_repo, write_blob, SIGNATURE};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_add_worktree() {
+        let (_dir, repo) = init_repo();
+        let first = commit_files(&repo, &[("a.txt", "one\n")], "first");
+        let target = TempDir::new().unwrap();
+        let path = target.path().join("topic");
+
+        let worktree = add_worktree(&repo, &path, None, &WorktreeAddOptions::default()).unwrap();
+        assert_eq!(fs::read_to_string(path.join("a.txt")).unwrap(), "one\n");
+        assert!(path.join(".git").is_file());
+        assert_eq!(
+            read_head(&worktree).unwrap(),
+            Head::Branch("refs/heads/topic".to_string())
+        );
+        assert_eq!(read_head(&repo).unwrap().branch(), Some("master"));
+
+        // References are shared, HEAD and the index are not
+        let found = Repository::find(&path).unwrap();
+        assert_eq!(found.commondir(), fs::canonicalize(repo.gitdir()).unwrap());
+        let second = commit_files(&found, &[("a.txt", "two\n")], "second");
+        assert_eq!(
+            resolve_ref(&repo, "refs/heads/topic").unwrap(),
+            Some(second.clone())
+        );
+        assert_eq!(resolve_ref(&repo, "HEAD").unwrap(), Some(first));
+        assert!(reachable_objects(&repo)
+            .unwrap()
+            .objects
+            .iter()
+            .any(|(hash, _)| *hash == second));
+
+        let options = WorktreeAddOptions::default();
+        assert!(
+            add_worktree(&repo, &target.path().join("other"), Some("topic"), &options).is_err()
+        );
+        assert!(checkout(&repo, "topic", &CheckoutOptions::default()).is_err());
+
+        let worktrees = list_worktrees(&repo).unwrap();
+        assert_eq!(worktrees.len(), 2);
+        assert_eq!(worktrees[1].name.as_deref(), Some("topic"));
+        assert_eq!(worktrees[1].path, fs::canonicalize(&path).unwrap());
+    }
+
+    #[test]
+    fn test_failed_add_leaves_nothing_behind() {
+        let (_dir, repo) = init_repo();
+        let head = commit_files(&repo, &[("a.txt", "one\n")], "first");
+        // A commit the checkout refuses, with a file in the way of `.git`
+        let blob = write_blob(&repo, b"bad\n");
+        let mut tree = b"100644 .GIT\0".to_vec();
+        tree.extend(blob.as_bytes());
+        let tree = Object::new(HashAlgorithm::Sha1, ObjectType::Tree, tree).unwrap();
+        let tree = write_object(&tree, &repo).unwrap();
+        let bad = Commit {
+            tree,
+            parents: vec![head],
+            author: SIGNATURE.to_string(),
+            committer: SIGNATURE.to_string(),
+            gpgsig: None,
+            extra_headers: Vec::new(),
+            message: "bad\n".to_string(),
+        }
+        .write(&repo)
+        .unwrap();
+
+        let target = TempDir::new().unwrap();
+        let options = WorktreeAddOptions {
+            new_branch: Some("topic".to_string()),
+            ..WorktreeAddOptions::default()
+        };
+        let empty = target.path().join("empty");
+        fs::create_dir(&empty).unwrap();
+        let Err(error) = add_worktree(&repo, &empty, Some(&bad.to_hex()), &options) else {
+            panic!("the checkout of {} succeeded", bad);
+        };
+        assert!(format!("{:#}", error).contains(".GIT"), "{:#}", error);
+        assert!(empty.is_dir());
+        assert_eq!(fs::read_dir(&empty).unwrap().count(), 0);
+        assert_eq!(resolve_ref(&repo, "refs/heads/topic").unwrap(), None);
+        assert_eq!(fs::read_dir(worktrees_dir(&repo)).unwrap().count(), 0);
+
+        let missing = target.path().join("missing");
+        assert!(add_worktree(&repo, &missing, Some(&bad.to_hex()), &options).is_err());
+        assert!(!missing.exists());
+        assert_eq!(resolve_ref(&repo, "refs/heads/topic").unwrap(), None);
+
+        // The empty directory is still a fine target
+        add_worktree(&repo, &empty, None, &options).unwrap();
+        assert_eq!(fs::read_to_string(empty.join("a.txt")).unwrap(), "one\n");
+    }
+
+    #[test]
+    fn test_lock_move_remove_and_prune() {
+        let (_dir, repo) = init_repo();
+        commit_files(&repo, &[("a.txt", "one\n")], "first");
+        let target = TempDir::new().unwrap();
+        let options = WorktreeAddOptions {
+            detach: true,
+            ..WorktreeAddOptions::default()
+        };
+        let path = target.path().join("a");
+        add_worktree(&repo, &path, None, &options).unwrap();
+        let spec = path.to_str().unwrap();
+
+        lock_worktree(&repo, spec, "on a usb drive").unwrap();
+        assert!(lock_worktree(&repo, "a", "").is_err());
+        let moved = target.path().join("b");
+        assert!(move_worktree(&repo, "a", &moved, 0).is_err());
+        unlock_worktree(&repo, "a").unwrap();
+        let moved = move_worktree(&repo, "a", &moved, 0).unwrap();
+        assert!(Repository::find(&moved).is_ok());
+        assert_eq!(list_worktrees(&repo).unwrap()[1].path, moved);
+
+        fs::write(moved.join("new.txt"), "new\n").unwrap();
+        assert!(remove_worktree(&repo, "b", 0).is_err());
+        remove_worktree(&repo, "b", 1).unwrap();
+        assert!(!moved.exists());
+        assert!(!repo.gitdir().join("worktrees").exists());
+
+        let path = target.path().join("c");
+        add_worktree(&repo, &path, None, &options).unwrap();
+        assert!(prune_worktrees(&repo, false).unwrap().is_empty());
+        fs::remove_dir_all(&path).unwrap();
+        assert_eq!(
+            prune_worktrees(&repo, false).unwrap(),
+            vec![(
+                "c".to_string(),
+                "gitdir file points to non-existent location".to_string()
+            )]
+        );
+        assert_eq!(list_worktrees(&repo).unwrap().len(), 1);
+    }
+}